@@ -0,0 +1,152 @@
+//! Compiles the GLSL and WGSL sources of hot's built-in shaders in `src/shaders` to SPIR-V with
+//! naga, and writes them to `$OUT_DIR/shaders.rs` as `&[u32]` constants, which `src/shaders.rs`
+//! includes.
+//!
+//! Each shader becomes a constant named after its file stem in upper case. Shaders writing a
+//! storage image of the caller's format are compiled once per entry of `STORAGE_FORMATS`, with
+//! `DST_FORMAT` defined as its GLSL format qualifier, or replaced with its WGSL texel format,
+//! into an array of variants in that order.
+
+use naga::back::spv;
+use naga::front::{glsl, wgsl};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+/// The storage image formats which need no extended storage format support: their GLSL format
+/// qualifier, their WGSL texel format and the name of their `vk::Format`.
+const STORAGE_FORMATS: &[(&str, &str, &str)] = &[
+    ("rgba32f", "rgba32float", "R32G32B32A32_SFLOAT"),
+    ("rgba16f", "rgba16float", "R16G16B16A16_SFLOAT"),
+    ("r32f", "r32float", "R32_SFLOAT"),
+    ("rgba8", "rgba8unorm", "R8G8B8A8_UNORM"),
+    ("rgba8_snorm", "rgba8snorm", "R8G8B8A8_SNORM"),
+];
+
+/// The shaders compiled once, by file name.
+const SHADERS: &[&str] = &[
+    "debug_draw.vert",
+    "debug_draw.frag",
+    "hiz_reduce.comp",
+    "hiz_cull.comp",
+    "self_test.comp",
+];
+
+/// The shaders compiled once which are only used with a cargo feature, by the feature and file
+/// name.
+const FEATURE_SHADERS: &[(&str, &str)] = &[("imgui", "imgui.vert"), ("imgui", "imgui.frag")];
+
+/// The shaders compiled once per storage format, by file name.
+const STORAGE_FORMAT_SHADERS: &[&str] = &[
+    "mip_chain.comp",
+    "spd_downsample.wgsl",
+    "image_convert.comp",
+];
+
+fn main() {
+    let shader_dir = Path::new(&std::env::var("CARGO_MANIFEST_DIR").unwrap()).join("src/shaders");
+    let out_path = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("shaders.rs");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let mut out = String::new();
+    writeln!(out, "/// The storage image formats the shaders with variants are compiled for, in order.").unwrap();
+    writeln!(out, "pub(crate) const STORAGE_FORMATS: &[vk::Format] = &[").unwrap();
+    for (_, _, format) in STORAGE_FORMATS {
+        writeln!(out, "    vk::Format::{},", format).unwrap();
+    }
+    writeln!(out, "];").unwrap();
+
+    for name in SHADERS {
+        let path = shader_dir.join(name);
+        let words = compile(&path, None);
+        writeln!(out, "\n/// Compiled from `src/shaders/{}`.", name).unwrap();
+        writeln!(out, "pub(crate) const {}: &[u32] = &{:?};", const_name(name), words).unwrap();
+    }
+
+    for (feature, name) in FEATURE_SHADERS {
+        let path = shader_dir.join(name);
+        let words = compile(&path, None);
+        writeln!(out, "\n/// Compiled from `src/shaders/{}`.", name).unwrap();
+        writeln!(out, "#[cfg(feature = \"{}\")]", feature).unwrap();
+        writeln!(out, "pub(crate) const {}: &[u32] = &{:?};", const_name(name), words).unwrap();
+    }
+
+    for name in STORAGE_FORMAT_SHADERS {
+        let path = shader_dir.join(name);
+        writeln!(
+            out,
+            "\n/// Compiled from `src/shaders/{}`, once for each of `STORAGE_FORMATS`.",
+            name
+        )
+        .unwrap();
+        writeln!(out, "pub(crate) const {}: &[&[u32]] = &[", const_name(name)).unwrap();
+        for format in STORAGE_FORMATS {
+            let words = compile(&path, Some(format));
+            writeln!(out, "    &{:?},", words).unwrap();
+        }
+        writeln!(out, "];").unwrap();
+    }
+
+    std::fs::write(&out_path, out).unwrap();
+}
+
+/// Get the name of the constant holding the shader in the file `name`.
+fn const_name(name: &str) -> String {
+    let (stem, extension) = name.split_once('.').unwrap();
+    match extension {
+        "vert" => format!("{}_VERT", stem.to_ascii_uppercase()),
+        "frag" => format!("{}_FRAG", stem.to_ascii_uppercase()),
+        _ => stem.to_ascii_uppercase(),
+    }
+}
+
+/// Compile the GLSL or WGSL shader at `path`, with `DST_FORMAT` standing for the storage format
+/// `dst_format` if given, and panic with the compiler's errors if it fails.
+fn compile(path: &Path, dst_format: Option<&(&str, &str, &str)>) -> Vec<u32> {
+    println!("cargo:rerun-if-changed={}", path.display());
+    let stage = match path.extension().and_then(|extension| extension.to_str()) {
+        Some("vert") => naga::ShaderStage::Vertex,
+        Some("frag") => naga::ShaderStage::Fragment,
+        Some("comp") => naga::ShaderStage::Compute,
+        Some("wgsl") => {
+            // WGSL has no preprocessor, so the format is substituted textually.
+            let mut source = std::fs::read_to_string(path).unwrap();
+            if let Some((_, texel_format, _)) = dst_format {
+                source = source.replace("DST_FORMAT", texel_format);
+            }
+            let module = wgsl::parse_str(&source).unwrap_or_else(|error| {
+                panic!("failed to parse {}:\n{}", path.display(), error.emit_to_string(&source))
+            });
+            return write_spirv(path, &module);
+        }
+        _ => panic!("unknown shader stage of {}", path.display()),
+    };
+    let source = std::fs::read_to_string(path).unwrap();
+
+    let mut options = glsl::Options::from(stage);
+    if let Some((qualifier, _, _)) = dst_format {
+        options.defines.insert(String::from("DST_FORMAT"), String::from(*qualifier));
+    }
+    let module = glsl::Frontend::default()
+        .parse(&options, &source)
+        .unwrap_or_else(|errors| panic!("failed to parse {}: {:?}", path.display(), errors));
+    write_spirv(path, &module)
+}
+
+/// Validate `module`, parsed from the shader at `path`, and write it as SPIR-V.
+fn write_spirv(path: &Path, module: &naga::Module) -> Vec<u32> {
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(module)
+        .unwrap_or_else(|error| panic!("failed to validate {}: {:?}", path.display(), error));
+
+    // The sources are written for Vulkan, so unlike naga's usual WebGPU sources they need no
+    // change of coordinate space, and shared memory is not zeroed.
+    let spv_options = spv::Options {
+        flags: spv::WriterFlags::empty(),
+        zero_initialize_workgroup_memory: spv::ZeroInitializeWorkgroupMemoryMode::None,
+        ..Default::default()
+    };
+    spv::write_vec(module, &info, &spv_options, None)
+        .unwrap_or_else(|error| panic!("failed to compile {}: {:?}", path.display(), error))
+}
@@ -0,0 +1,57 @@
+//! Streams a file into a GPU buffer a chunk per frame on the async transfer queue, then reads
+//! the buffer back to check that it arrived intact.
+
+use hot::ash::vk;
+use hot::examples_support::*;
+use hot::*;
+
+const FILE_SIZE: u64 = 16 << 20;
+const CHUNK_SIZE: u64 = 1 << 20;
+
+fn main() -> ExampleResult {
+    let device = create_device("async_streaming")?;
+
+    // A file with contents which are easy to check, standing in for an asset.
+    let path = output_path("async_streaming.bin");
+    let contents = (0..FILE_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(&path, &contents)?;
+
+    let buffer = device.clone().create_buffer::<()>(
+        BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: FILE_SIZE,
+            usage: vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+            wants_device_address: false,
+        },
+        Some(Tag::Static("streamed asset")),
+        None,
+    )?;
+
+    let mut streamer = FileStreamer::new(device.clone());
+    let file = streamer.open(&path)?;
+
+    // Each chunk's copy is submitted to the transfer queue as the frame goes on, and the next
+    // graphics submission waits for it, so streaming never stalls the frame.
+    let chunks = FILE_SIZE.div_ceil(CHUNK_SIZE);
+    run_frames(&device, chunks, |_, index| {
+        let offset = index * CHUNK_SIZE;
+        let size = CHUNK_SIZE.min(FILE_SIZE - offset);
+        streamer.stream_to_buffer(file, offset, size, buffer, offset)?;
+        println!("frame {}: streaming bytes {}..{}", index, offset, offset + size);
+        Ok(())
+    })?;
+
+    let data = match device.read_buffer(buffer, 0..FILE_SIZE)?.wait(u64::MAX)? {
+        Ok(data) => data,
+        Err(_) => return Err("timed out reading back the streamed buffer".into()),
+    };
+    if data != contents {
+        return Err("the streamed buffer does not match the file".into());
+    }
+    println!("streamed {} bytes in {} chunks", FILE_SIZE, chunks);
+
+    streamer.close(file);
+    device.destroy_buffer(buffer);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -0,0 +1,94 @@
+//! Simulates particles bouncing on a floor with a compute shader over several frames, then
+//! reads them back and prints where they ended up.
+
+use hot::ash::vk;
+use hot::examples_support::*;
+use hot::*;
+
+const PARTICLE_COUNT: usize = 1024;
+const FRAMES: u64 = 120;
+const DT: f32 = 1.0 / 60.0;
+
+/// The position and velocity of a particle, laid out as in `particles.comp`.
+type Particle = [f32; 4];
+
+fn main() -> ExampleResult {
+    let device = create_device("compute_particles")?;
+
+    let mut particles: [Particle; PARTICLE_COUNT] = [[0.0; 4]; PARTICLE_COUNT];
+    for (i, particle) in particles.iter_mut().enumerate() {
+        let t = i as f32 / PARTICLE_COUNT as f32;
+        *particle = [t * 10.0 - 5.0, 1.0 + t * 4.0, (t * 37.0).sin(), 0.0];
+    }
+    let buffer = device.clone().create_buffer(
+        BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: std::mem::size_of_val(&particles) as vk::DeviceSize,
+            usage: vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_SRC,
+            wants_device_address: false,
+        },
+        Some(Tag::Static("particles")),
+        Some(particles),
+    )?;
+
+    let shader = load_shader(&device, "particles.comp")?;
+    let layout = device.create_shader_layout(&[&shader])?;
+    let pipeline = device.create_compute_pipeline(shader.raw(), layout.raw())?;
+
+    let descriptors = DescriptorAllocator::new(&device, 1)?;
+    let set = descriptors.allocate(layout.set_layouts()[0])?;
+    let buffer_info = vk::DescriptorBufferInfo {
+        buffer: device.resources().get_buffer(buffer).expect("particles were destroyed").raw(),
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    };
+    device.write_buffer_descriptors(set, 0, 0, vk::DescriptorType::STORAGE_BUFFER, &[buffer_info]);
+
+    let mut push_constants = Vec::with_capacity(8);
+    push_constants.extend_from_slice(&DT.to_le_bytes());
+    push_constants.extend_from_slice(&(PARTICLE_COUNT as u32).to_le_bytes());
+    let group_count = PARTICLE_COUNT.div_ceil(64) as u32;
+
+    run_frames(&device, FRAMES, |frame, _| {
+        let mut cmd = device.clone().request_command_buffer(frame, QueueType::Graphics)?;
+        cmd.dispatch_with(
+            &pipeline,
+            &[set],
+            &push_constants,
+            &[DispatchResource::StorageBuffer(buffer, ShaderAccess::ReadWrite)],
+            [group_count, 1, 1],
+        );
+        cmd.end()?;
+        device.submit(QueueType::Graphics, &[cmd]).enqueue();
+        Ok(())
+    })?;
+
+    let size = std::mem::size_of_val(&particles) as vk::DeviceSize;
+    let data = match device.read_buffer(buffer, 0..size)?.wait(u64::MAX)? {
+        Ok(data) => data,
+        Err(_) => return Err("timed out reading back the particles".into()),
+    };
+    let floats = data
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect::<Vec<_>>();
+    let particles = floats.chunks_exact(4).collect::<Vec<_>>();
+    let mean_height = particles.iter().map(|particle| particle[1]).sum::<f32>() / particles.len() as f32;
+    let max_height = particles.iter().map(|particle| particle[1]).fold(0.0, f32::max);
+    println!(
+        "after {} frames, {} particles have a mean height of {:.3} and a max height of {:.3}",
+        FRAMES,
+        particles.len(),
+        mean_height,
+        max_height
+    );
+
+    drop(descriptors);
+    unsafe {
+        device.destroy_compute_pipeline(pipeline);
+        device.destroy_shader_layout(layout);
+        device.destroy_shader(shader);
+    }
+    device.destroy_buffer(buffer);
+    Ok(())
+}
@@ -0,0 +1,180 @@
+//! Renders a few shapes with a two pass deferred renderer built on a `RenderGraph`: a G-buffer
+//! pass writes albedo and normals, then a lighting pass shades them into an offscreen target,
+//! which is saved to `target/deferred.ppm`.
+
+use hot::ash::version::DeviceV1_0;
+use hot::ash::vk;
+use hot::examples_support::*;
+use hot::*;
+
+use std::sync::{Arc, OnceLock};
+
+const SIZE: u32 = 512;
+
+/// The offset and scale in clip space, and the albedo, of each object.
+const OBJECTS: [[f32; 8]; 3] = [
+    [-0.45, -0.4, 0.4, 0.4, 0.9, 0.2, 0.2, 1.0],
+    [0.45, -0.4, 0.4, 0.4, 0.2, 0.8, 0.3, 1.0],
+    [0.0, 0.45, 0.7, 0.35, 0.3, 0.4, 0.9, 1.0],
+];
+
+fn main() -> ExampleResult {
+    let device = create_device("deferred")?;
+    let target = OffscreenTarget::new(&device, SIZE, SIZE)?;
+
+    let gbuffer_vert = load_shader(&device, "gbuffer.vert")?;
+    let gbuffer_frag = load_shader(&device, "gbuffer.frag")?;
+    let gbuffer_layout = device.create_shader_layout(&[&gbuffer_vert, &gbuffer_frag])?;
+    let fullscreen_vert = load_shader(&device, "fullscreen.vert")?;
+    let lighting_frag = load_shader(&device, "lighting.frag")?;
+    let lighting_layout = device.create_shader_layout(&[&fullscreen_vert, &lighting_frag])?;
+
+    let sampler_info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::NEAREST)
+        .min_filter(vk::Filter::NEAREST)
+        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+    let sampler = unsafe { device.raw_device().create_sampler(&sampler_info, None)? };
+
+    // A lighting set per frame in flight, since a set can't be rewritten while in use.
+    let descriptors = DescriptorAllocator::new(&device, device.frames_in_flight() as u32)?;
+    let lighting_sets = (0..device.frames_in_flight())
+        .map(|_| descriptors.allocate(lighting_layout.set_layouts()[0]))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let attachment = |format| AttachmentInfo {
+        format,
+        size: AttachmentSize::Absolute {
+            width: SIZE,
+            height: SIZE,
+        },
+        samples: vk::SampleCountFlags::TYPE_1,
+    };
+    let mut graph = RenderGraph::new();
+    let albedo = graph.create_attachment("albedo", attachment(vk::Format::R8G8B8A8_UNORM));
+    let normal = graph.create_attachment("normal", attachment(vk::Format::R16G16B16A16_SFLOAT));
+    let depth = graph.create_attachment("depth", attachment(vk::Format::D32_SFLOAT));
+    let output = graph.import_image("output", target.image());
+
+    // Pipelines need the render passes, which only exist once the graph is compiled.
+    let gbuffer_pipeline = Arc::new(OnceLock::new());
+    let lighting_pipeline = Arc::new(OnceLock::new());
+
+    let clear_color = |color| vk::ClearValue {
+        color: vk::ClearColorValue { float32: color },
+    };
+    let clear_depth = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+    };
+    {
+        let pipeline = gbuffer_pipeline.clone();
+        let object_constants = PushConstants::<[f32; 8]>::new(&gbuffer_layout);
+        let device = device.clone();
+        graph
+            .add_pass("gbuffer")
+            .color_output(albedo, Some(clear_color([0.0; 4])))
+            .color_output(normal, Some(clear_color([0.0; 4])))
+            .depth_stencil_output(depth, Some(clear_depth))
+            .execute(move |cmd, images| {
+                cmd.set_viewport_for(images.image(albedo), false);
+                let raw = device.raw_device();
+                let pipeline = *pipeline.get().expect("pipelines are created after compiling");
+                unsafe { raw.cmd_bind_pipeline(cmd.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline) };
+                for object in &OBJECTS {
+                    cmd.push_constants(&object_constants, object)
+                        .expect("objects match the gbuffer shaders' push constants");
+                    unsafe { raw.cmd_draw(cmd.raw(), 6, 1, 0, 0) };
+                }
+            });
+    }
+    {
+        let pipeline = lighting_pipeline.clone();
+        let layout = lighting_layout.raw();
+        let device = device.clone();
+        graph
+            .add_pass("lighting")
+            .sampled_input(albedo)
+            .sampled_input(normal)
+            .color_output(output, Some(clear_color([0.0, 0.0, 0.0, 1.0])))
+            .execute(move |cmd, images| {
+                let set = lighting_sets[device.current_frame_index()];
+                for (binding, resource) in [albedo, normal].iter().enumerate() {
+                    let image_info = vk::DescriptorImageInfo {
+                        sampler,
+                        image_view: images.view(*resource),
+                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    };
+                    device.write_image_descriptors(
+                        set,
+                        binding as u32,
+                        0,
+                        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                        &[image_info],
+                    );
+                }
+                device.flush_descriptor_writes();
+
+                cmd.set_viewport_for(images.image(output), false);
+                let raw = device.raw_device();
+                let pipeline = *pipeline.get().expect("pipelines are created after compiling");
+                unsafe {
+                    raw.cmd_bind_pipeline(cmd.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline);
+                    raw.cmd_bind_descriptor_sets(
+                        cmd.raw(),
+                        vk::PipelineBindPoint::GRAPHICS,
+                        layout,
+                        0,
+                        &[set],
+                        &[],
+                    );
+                    raw.cmd_draw(cmd.raw(), 3, 1, 0, 0);
+                }
+            });
+    }
+    let mut graph = graph.compile(&device)?;
+
+    let gbuffer = device.request_graphics_pipeline(&GraphicsPipelineCreateInfo {
+        vertex_shader: gbuffer_vert.raw(),
+        fragment_shader: Some(gbuffer_frag.raw()),
+        layout: gbuffer_layout.raw(),
+        render_pass: graph.render_pass("gbuffer").expect("the graph has a gbuffer pass"),
+        depth_test: true,
+        depth_write: true,
+        blend_states: vec![BlendState::Opaque; 2],
+        ..Default::default()
+    })?;
+    let lighting = device.request_graphics_pipeline(&GraphicsPipelineCreateInfo {
+        vertex_shader: fullscreen_vert.raw(),
+        fragment_shader: Some(lighting_frag.raw()),
+        layout: lighting_layout.raw(),
+        render_pass: graph.render_pass("lighting").expect("the graph has a lighting pass"),
+        ..Default::default()
+    })?;
+    gbuffer_pipeline.set(gbuffer).unwrap();
+    lighting_pipeline.set(lighting).unwrap();
+
+    run_frames(&device, 3, |frame, _| {
+        let mut cmd = device.clone().request_command_buffer(frame, QueueType::Graphics)?;
+        graph.execute(&mut cmd)?;
+        cmd.end()?;
+        device.submit(QueueType::Graphics, &[cmd]).enqueue();
+        Ok(())
+    })?;
+
+    let path = output_path("deferred.ppm");
+    target.save_ppm(&path)?;
+    println!("saved {}", path.display());
+
+    drop(graph);
+    drop(descriptors);
+    unsafe {
+        device.raw_device().destroy_sampler(sampler, None);
+        device.destroy_shader_layout(gbuffer_layout);
+        device.destroy_shader_layout(lighting_layout);
+        for shader in [gbuffer_vert, gbuffer_frag, fullscreen_vert, lighting_frag] {
+            device.destroy_shader(shader);
+        }
+    }
+    Ok(())
+}
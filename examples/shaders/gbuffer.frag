@@ -0,0 +1,16 @@
+#version 450
+
+layout(push_constant) uniform Object {
+    vec4 offset_scale;
+    vec4 albedo;
+};
+
+layout(location = 0) in vec3 in_normal;
+
+layout(location = 0) out vec4 out_albedo;
+layout(location = 1) out vec4 out_normal;
+
+void main() {
+    out_albedo = albedo;
+    out_normal = vec4(normalize(in_normal), 0.0);
+}
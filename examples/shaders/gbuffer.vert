@@ -0,0 +1,21 @@
+#version 450
+
+layout(push_constant) uniform Object {
+    vec4 offset_scale;
+    vec4 albedo;
+};
+
+layout(location = 0) out vec3 out_normal;
+
+// A unit quad facing the viewer, bent so that each half faces a different way.
+const vec2 POSITIONS[6] = vec2[](
+    vec2(-1.0, -1.0), vec2(0.0, -1.0), vec2(-1.0, 1.0),
+    vec2(0.0, -1.0), vec2(1.0, -1.0), vec2(1.0, 1.0)
+);
+const vec3 NORMALS[2] = vec3[](normalize(vec3(-0.5, 0.0, 1.0)), normalize(vec3(0.5, 0.3, 1.0)));
+
+void main() {
+    vec2 position = POSITIONS[gl_VertexIndex] * offset_scale.zw + offset_scale.xy;
+    gl_Position = vec4(position, 0.5, 1.0);
+    out_normal = NORMALS[gl_VertexIndex / 3];
+}
@@ -0,0 +1,17 @@
+#version 450
+
+layout(set = 0, binding = 0) uniform sampler2D albedo_texture;
+layout(set = 0, binding = 1) uniform sampler2D normal_texture;
+
+layout(location = 0) in vec2 in_uv;
+layout(location = 0) out vec4 out_color;
+
+const vec3 LIGHT_DIRECTION = normalize(vec3(0.4, 0.6, 1.0));
+const vec3 AMBIENT = vec3(0.1);
+
+void main() {
+    vec4 albedo = texture(albedo_texture, in_uv);
+    vec3 normal = texture(normal_texture, in_uv).xyz;
+    float diffuse = max(dot(normal, LIGHT_DIRECTION), 0.0);
+    out_color = vec4(albedo.rgb * (AMBIENT + diffuse), 1.0);
+}
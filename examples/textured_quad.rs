@@ -0,0 +1,168 @@
+//! Draws a quad textured with a generated checkerboard into an offscreen target and saves it to
+//! `target/textured_quad.ppm`.
+
+use hot::ash::version::DeviceV1_0;
+use hot::ash::vk;
+use hot::examples_support::*;
+use hot::*;
+
+const TEXTURE_SIZE: usize = 64;
+
+/// Two triangles, each vertex being a position followed by a texture coordinate.
+const QUAD: [[f32; 4]; 6] = [
+    [-0.8, -0.8, 0.0, 0.0],
+    [0.8, -0.8, 1.0, 0.0],
+    [0.8, 0.8, 1.0, 1.0],
+    [-0.8, -0.8, 0.0, 0.0],
+    [0.8, 0.8, 1.0, 1.0],
+    [-0.8, 0.8, 0.0, 1.0],
+];
+
+fn checkerboard() -> Vec<u8> {
+    let mut texels = Vec::with_capacity(TEXTURE_SIZE * TEXTURE_SIZE * 4);
+    for y in 0..TEXTURE_SIZE {
+        for x in 0..TEXTURE_SIZE {
+            let light = (x / 8 + y / 8) % 2 == 0;
+            texels.extend_from_slice(if light { &[230, 200, 90, 255] } else { &[40, 60, 120, 255] });
+        }
+    }
+    texels
+}
+
+fn main() -> ExampleResult {
+    let device = create_device("textured_quad")?;
+    let target = OffscreenTarget::new(&device, 512, 512)?;
+
+    let texels = checkerboard();
+    let mut texture_info =
+        ImageCreateInfo::immutable_2d_image(TEXTURE_SIZE, TEXTURE_SIZE, vk::Format::R8G8B8A8_UNORM, false);
+    texture_info.initial_layout = vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL;
+    let initial_data = InitialImageData::new(&texels);
+    let texture = device
+        .clone()
+        .create_image(texture_info, Some(initial_data), Some(Tag::Static("checkerboard")))?;
+    let texture_view = device
+        .resources()
+        .get_image(texture)
+        .and_then(|image| image.view().map(ImageView::raw))
+        .expect("sampled images get a default view");
+
+    let vertices = device.clone().create_buffer(
+        BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: std::mem::size_of_val(&QUAD) as vk::DeviceSize,
+            usage: vk::BufferUsageFlags::VERTEX_BUFFER,
+            wants_device_address: false,
+        },
+        Some(Tag::Static("quad vertices")),
+        Some(QUAD),
+    )?;
+
+    let sampler_info = vk::SamplerCreateInfo::builder()
+        .mag_filter(vk::Filter::NEAREST)
+        .min_filter(vk::Filter::NEAREST)
+        .address_mode_u(vk::SamplerAddressMode::REPEAT)
+        .address_mode_v(vk::SamplerAddressMode::REPEAT)
+        .address_mode_w(vk::SamplerAddressMode::REPEAT);
+    let sampler = unsafe { device.raw_device().create_sampler(&sampler_info, None)? };
+
+    let vertex_shader = load_shader(&device, "textured_quad.vert")?;
+    let fragment_shader = load_shader(&device, "textured_quad.frag")?;
+    let layout = device.create_shader_layout(&[&vertex_shader, &fragment_shader])?;
+    let pipeline = device.request_graphics_pipeline(&GraphicsPipelineCreateInfo {
+        vertex_shader: vertex_shader.raw(),
+        fragment_shader: Some(fragment_shader.raw()),
+        layout: layout.raw(),
+        render_pass: target.render_pass(),
+        vertex_bindings: vec![VertexBinding {
+            binding: 0,
+            stride: std::mem::size_of::<[f32; 4]>() as u32,
+            input_rate: vk::VertexInputRate::VERTEX,
+        }],
+        vertex_attributes: vec![
+            VertexAttribute {
+                location: 0,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 0,
+            },
+            VertexAttribute {
+                location: 1,
+                binding: 0,
+                format: vk::Format::R32G32_SFLOAT,
+                offset: 8,
+            },
+        ],
+        ..Default::default()
+    })?;
+
+    let descriptors = DescriptorAllocator::new(&device, 1)?;
+    let set = descriptors.allocate(layout.set_layouts()[0])?;
+    device.write_image_descriptors(
+        set,
+        0,
+        0,
+        vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+        &[vk::DescriptorImageInfo {
+            sampler,
+            image_view: texture_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }],
+    );
+    device.flush_descriptor_writes();
+
+    run_frames(&device, 1, |frame, _| {
+        let mut cmd = device.clone().request_command_buffer(frame, QueueType::Graphics)?;
+        cmd.transition_image(
+            texture,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        cmd.transition_buffer(
+            vertices,
+            vk::PipelineStageFlags::VERTEX_INPUT,
+            vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+        );
+        let vertex_buffer = device
+            .resources()
+            .get_buffer(vertices)
+            .expect("vertex buffer was destroyed")
+            .raw();
+
+        target.begin(&mut cmd, [0.1, 0.1, 0.1, 1.0]);
+        unsafe {
+            let raw = device.raw_device();
+            raw.cmd_bind_pipeline(cmd.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline);
+            raw.cmd_bind_descriptor_sets(
+                cmd.raw(),
+                vk::PipelineBindPoint::GRAPHICS,
+                layout.raw(),
+                0,
+                &[set],
+                &[],
+            );
+            raw.cmd_bind_vertex_buffers(cmd.raw(), 0, &[vertex_buffer], &[0]);
+            raw.cmd_draw(cmd.raw(), QUAD.len() as u32, 1, 0, 0);
+        }
+        cmd.end_render_pass();
+        cmd.end()?;
+        device.submit(QueueType::Graphics, &[cmd]).enqueue();
+        Ok(())
+    })?;
+
+    let path = output_path("textured_quad.ppm");
+    target.save_ppm(&path)?;
+    println!("saved {}", path.display());
+
+    drop(descriptors);
+    unsafe {
+        device.raw_device().destroy_sampler(sampler, None);
+        device.destroy_shader_layout(layout);
+        device.destroy_shader(vertex_shader);
+        device.destroy_shader(fragment_shader);
+    }
+    device.destroy_buffer(vertices);
+    device.destroy_image(texture);
+    Ok(())
+}
@@ -0,0 +1,46 @@
+//! Draws a single triangle into an offscreen target and saves it to `target/triangle.ppm`.
+
+use hot::ash::version::DeviceV1_0;
+use hot::ash::vk;
+use hot::examples_support::*;
+use hot::*;
+
+fn main() -> ExampleResult {
+    let device = create_device("triangle")?;
+    let target = OffscreenTarget::new(&device, 512, 512)?;
+
+    let vertex_shader = load_shader(&device, "triangle.vert")?;
+    let fragment_shader = load_shader(&device, "triangle.frag")?;
+    let layout = device.create_shader_layout(&[&vertex_shader, &fragment_shader])?;
+    let pipeline = device.request_graphics_pipeline(&GraphicsPipelineCreateInfo {
+        vertex_shader: vertex_shader.raw(),
+        fragment_shader: Some(fragment_shader.raw()),
+        layout: layout.raw(),
+        render_pass: target.render_pass(),
+        ..Default::default()
+    })?;
+
+    run_frames(&device, 1, |frame, _| {
+        let mut cmd = device.clone().request_command_buffer(frame, QueueType::Graphics)?;
+        target.begin(&mut cmd, [0.1, 0.1, 0.1, 1.0]);
+        unsafe {
+            device.raw_device().cmd_bind_pipeline(cmd.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline);
+            device.raw_device().cmd_draw(cmd.raw(), 3, 1, 0, 0);
+        }
+        cmd.end_render_pass();
+        cmd.end()?;
+        device.submit(QueueType::Graphics, &[cmd]).enqueue();
+        Ok(())
+    })?;
+
+    let path = output_path("triangle.ppm");
+    target.save_ppm(&path)?;
+    println!("saved {}", path.display());
+
+    unsafe {
+        device.destroy_shader_layout(layout);
+        device.destroy_shader(vertex_shader);
+        device.destroy_shader(fragment_shader);
+    }
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! Derive macros for hot, enabled with its `derive` feature.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+
+use proc_macro2::TokenStream as TokenStream2;
+
+use quote::quote;
+
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, Lit, Meta, NestedMeta};
+
+/// Derive `hot::Vertex` for a `#[repr(C)]` struct with named fields.
+///
+/// Each field is an attribute, at consecutive shader locations in declaration order, read as
+/// its type's `hot::VertexFormat`. The format of a field can be overridden with
+/// `#[vertex(format = "R8G8B8A8_SRGB")]`, naming a `vk::Format` constant.
+///
+/// `hot::Vertex` requires `bytemuck::Pod`, which must be implemented too, e.g. with
+/// `#[derive(bytemuck::Pod, bytemuck::Zeroable)]`.
+#[proc_macro_derive(Vertex, attributes(vertex))]
+pub fn derive_vertex(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand_vertex(&input) {
+        Ok(expanded) => expanded.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand_vertex(input: &DeriveInput) -> Result<TokenStream2, Error> {
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(input.generics.span(), "Vertex can't be derived for generic types"));
+    }
+    if !is_repr_c(input) {
+        return Err(Error::new(input.ident.span(), "Vertex can only be derived for #[repr(C)] structs"));
+    }
+    let fields = match input.data {
+        Data::Struct(ref data) => match data.fields {
+            Fields::Named(ref fields) => &fields.named,
+            _ => return Err(Error::new(input.ident.span(), "Vertex can only be derived for structs with named fields")),
+        },
+        _ => return Err(Error::new(input.ident.span(), "Vertex can only be derived for structs")),
+    };
+
+    let name = &input.ident;
+    let attributes = fields
+        .iter()
+        .map(|field| {
+            let ident = field.ident.as_ref().unwrap();
+            let ty = &field.ty;
+            let format = match format_override(field)? {
+                Some(format) => quote!(::hot::ash::vk::Format::#format),
+                None => quote!(<#ty as ::hot::VertexFormat>::FORMAT),
+            };
+            Ok(quote! {
+                .attribute(#format, ::core::mem::offset_of!(#name, #ident) as u32)
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    Ok(quote! {
+        impl ::hot::Vertex for #name {
+            fn layout() -> ::hot::VertexLayout {
+                ::hot::VertexLayout::new(::core::mem::size_of::<#name>() as u32)
+                    #(#attributes)*
+            }
+        }
+    })
+}
+
+/// Get whether the struct is `#[repr(C)]`, so that its field offsets are stable.
+fn is_repr_c(input: &DeriveInput) -> bool {
+    input.attrs.iter().any(|attr| match attr.parse_meta() {
+        Ok(Meta::List(list)) if list.path.is_ident("repr") => list.nested.iter().any(|nested| {
+            matches!(nested, NestedMeta::Meta(Meta::Path(path)) if path.is_ident("C"))
+        }),
+        _ => false,
+    })
+}
+
+/// Get the format given with `#[vertex(format = ...)]` on `field`, if any.
+fn format_override(field: &syn::Field) -> Result<Option<syn::Ident>, Error> {
+    for attr in field.attrs.iter().filter(|attr| attr.path.is_ident("vertex")) {
+        let list = match attr.parse_meta()? {
+            Meta::List(list) => list,
+            meta => return Err(Error::new(meta.span(), "expected #[vertex(format = ...)]")),
+        };
+        for nested in list.nested.iter() {
+            match nested {
+                NestedMeta::Meta(Meta::NameValue(value)) if value.path.is_ident("format") => {
+                    let format = match value.lit {
+                        Lit::Str(ref format) => format.value(),
+                        ref lit => return Err(Error::new(lit.span(), "expected the name of a vk::Format")),
+                    };
+                    return Ok(Some(syn::Ident::new(&format, value.lit.span())));
+                }
+                NestedMeta::Meta(Meta::Path(path)) => {
+                    return Err(Error::new(path.span(), "expected #[vertex(format = \"...\")]"));
+                }
+                nested => return Err(Error::new(nested.span(), "unknown vertex attribute")),
+            }
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,231 @@
+//! Images which share one allocation, for chains of passes such as post-processing whose
+//! intermediate images are never used at the same time, without building a render graph.
+//!
+//! `Device::create_aliased_images` binds every image of a group to the same memory, sized for
+//! the largest of them. Only one image of a group holds meaningful contents at a time: the
+//! barrier tracker remembers which image of the group last used the memory, and the first
+//! transition of another image waits for that image's accesses to complete and discards the
+//! contents, as if the new image were in `UNDEFINED`. Images of a group must therefore only be
+//! used in the order they are recorded in, and an image's contents are lost once another image
+//! of its group has been used.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use parking_lot::Mutex;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while creating aliased images.
+#[derive(Error, Debug)]
+pub enum AliasedImageError {
+    /// No image create infos were given.
+    #[error("no images to alias")]
+    Empty,
+    /// No memory type can hold every image of the group.
+    #[error("the images have no memory type in common")]
+    IncompatibleMemory,
+    /// Allocating or binding the shared memory failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// The memory shared by a group of aliased images, owned by the images' `Image`s and freed
+/// along with the last of them.
+pub(crate) struct AliasedMemory {
+    allocation: Allocation,
+    /// The image of the group which most recently used the memory, whose accesses must complete
+    /// before another image of the group may use it.
+    last_user: Mutex<Option<ImageHandle>>,
+}
+
+impl AliasedMemory {
+    /// Free the memory, once no image is bound to it.
+    pub(crate) fn free(self, device: &Device, tag: Option<&Tag>) {
+        device.track_allocation(MemoryCategory::Images, tag, self.allocation.size(), false);
+        if let Err(e) = device.allocator().free_memory(&self.allocation) {
+            device.invariant_failed(tag, format!("Aliased image memory errored on free: {:#?}", e));
+        }
+    }
+}
+
+/// Make `image` the user of its group's memory if it is an aliased image, so that its next
+/// transition waits for the previous user's accesses and discards the memory's contents.
+pub(crate) fn acquire_aliased_memory(resources: &mut ResourceSet, image: ImageHandle) {
+    let memory = match resources.get_image(image).and_then(Image::aliased) {
+        Some(memory) => memory.clone(),
+        None => return,
+    };
+    let previous = match memory.last_user.lock().replace(image) {
+        Some(previous) if previous == image => return,
+        previous => previous,
+    };
+
+    let last_state = previous
+        .and_then(|previous| resources.get_image(previous))
+        .map(Image::state)
+        .unwrap_or_else(ImageState::undefined);
+    if let Some(image) = resources.get_image_mut(image) {
+        image.set_state(ImageState {
+            layout: vk::ImageLayout::UNDEFINED,
+            ..last_state
+        });
+    }
+}
+
+impl Device {
+    /// Create an image for each of `create_infos`, all bound to the same allocation. See the
+    /// `aliasing` module documentation for how the images may be used.
+    ///
+    /// If every image is in the `Transient` domain the memory is lazily allocated where the
+    /// device supports it. Each image must be destroyed with `destroy_image`; the memory is
+    /// freed along with the last of them.
+    pub fn create_aliased_images(
+        self: &Arc<Self>,
+        create_infos: &[ImageCreateInfo],
+        tag: Option<Tag>,
+    ) -> Result<Vec<ImageHandle>, AliasedImageError> {
+        self.check_deterministic_thread("create_aliased_images");
+        if create_infos.is_empty() {
+            return Err(AliasedImageError::Empty);
+        }
+
+        let transient = create_infos
+            .iter()
+            .all(|create_info| create_info.domain == ImageUsageDomain::Transient);
+        let mut queue_family_indices = [0u32; 3];
+        let (sharing_mode, queue_family_index_count) = self.sharing_mode(&mut queue_family_indices);
+
+        let mut images = Vec::with_capacity(create_infos.len());
+        let mut requirements = vk::MemoryRequirements {
+            size: 0,
+            alignment: 1,
+            memory_type_bits: !0,
+        };
+        let destroy_images = |images: &[(vk::Image, ImageCreateInfo)]| {
+            for &(image, _) in images {
+                unsafe { self.device.destroy_image(image, None) };
+            }
+        };
+        for &create_info in create_infos {
+            let mut create_info = create_info;
+            create_info.depth = create_info.depth.max(1);
+            let extent = vk::Extent3D {
+                width: create_info.width as u32,
+                height: create_info.height as u32,
+                depth: create_info.depth as u32,
+            };
+            if create_info.levels == 0 {
+                create_info.levels = mip_levels_from_extent(extent) as usize;
+            }
+            if create_info.domain == ImageUsageDomain::Transient {
+                create_info.usage |= vk::ImageUsageFlags::TRANSIENT_ATTACHMENT;
+            }
+
+            let image_info = vk::ImageCreateInfo::builder()
+                .flags(create_info.create_flags)
+                .image_type(create_info.image_type)
+                .format(create_info.format)
+                .extent(extent)
+                .mip_levels(create_info.levels as u32)
+                .array_layers(create_info.layers as u32)
+                .samples(create_info.sample_count)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(create_info.usage)
+                .sharing_mode(sharing_mode)
+                .queue_family_indices(&queue_family_indices[0..queue_family_index_count])
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            let image = match unsafe { self.device.create_image(&image_info, None) } {
+                Ok(image) => image,
+                Err(e) => {
+                    destroy_images(&images);
+                    return Err(e.into());
+                }
+            };
+            images.push((image, create_info));
+
+            let image_requirements = unsafe { self.device.get_image_memory_requirements(image) };
+            requirements.size = requirements.size.max(image_requirements.size);
+            requirements.alignment = requirements.alignment.max(image_requirements.alignment);
+            requirements.memory_type_bits &= image_requirements.memory_type_bits;
+        }
+        if requirements.memory_type_bits == 0 {
+            destroy_images(&images);
+            return Err(AliasedImageError::IncompatibleMemory);
+        }
+
+        let alloc_desc = AllocationDesc {
+            usage: MemoryUsage::GpuOnly,
+            preferred_flags: if transient {
+                vk::MemoryPropertyFlags::LAZILY_ALLOCATED
+            } else {
+                vk::MemoryPropertyFlags::empty()
+            },
+            ..Default::default()
+        };
+        let allocation = match self.allocator.allocate_memory(&requirements, &alloc_desc) {
+            Ok(allocation) => allocation,
+            Err(e) => {
+                destroy_images(&images);
+                return Err(e.into());
+            }
+        };
+        for &(image, _) in &images {
+            if let Err(e) = self.allocator.bind_image_memory(image, &allocation) {
+                destroy_images(&images);
+                let _ = self.allocator.free_memory(&allocation);
+                return Err(e.into());
+            }
+        }
+        self.track_allocation(MemoryCategory::Images, tag.as_ref(), allocation.size(), true);
+
+        let memory = Arc::new(AliasedMemory {
+            allocation,
+            last_user: Mutex::new(None),
+        });
+        let mut handles = Vec::with_capacity(images.len());
+        for &(image, create_info) in &images {
+            let layout_type = if create_info.initial_layout == vk::ImageLayout::GENERAL {
+                ImageLayoutType::General
+            } else {
+                ImageLayoutType::Optimal
+            };
+            let mut aliased_image = unsafe {
+                Image::new(
+                    self.clone(),
+                    image,
+                    None,
+                    create_info,
+                    None,
+                    layout_type,
+                    vk::PipelineStageFlags::empty(),
+                    vk::AccessFlags::empty(),
+                    vk::ImageLayout::UNDEFINED,
+                    tag.clone(),
+                )
+            };
+            aliased_image.set_aliased(memory.clone());
+            handles.push(ImageHandle::new(self.resources.write().images.insert(aliased_image)));
+        }
+
+        for (&handle, (image, create_info)) in handles.iter().zip(&images) {
+            if let Err(e) = self.create_default_view(handle, *image, create_info) {
+                // The memory is freed along with the last of the images.
+                let mut resources = self.resources.write();
+                for handle in handles {
+                    resources.images.remove(handle.idx);
+                }
+                return Err(e.into());
+            }
+        }
+
+        Ok(handles)
+    }
+}
@@ -0,0 +1,278 @@
+use ash::vk;
+
+use thiserror::Error;
+
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+#[cfg(feature = "vk-mem")]
+mod vma;
+#[cfg(feature = "vk-mem")]
+pub use vma::*;
+
+#[cfg(feature = "gpu-allocator")]
+mod gpu_allocator;
+#[cfg(feature = "gpu-allocator")]
+pub use self::gpu_allocator::*;
+
+/// An error that could occur while allocating or managing device memory.
+#[derive(Error, Debug)]
+pub enum AllocatorError {
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+    /// No memory type satisfies the requirements of an allocation.
+    #[error("no memory type satisfies the allocation's requirements")]
+    NoSuitableMemoryType,
+    /// The Device was built without an allocator backend, i.e. without the `vk-mem` or
+    /// `gpu-allocator` features and without one given to `DeviceBuilder::allocator`.
+    #[error("no allocator backend was provided")]
+    NoBackend,
+    /// A buffer with `BufferCreateInfo::wants_device_address` was requested, but the Device
+    /// doesn't support `VK_KHR_buffer_device_address`.
+    #[error("buffer device addresses are not supported")]
+    DeviceAddressUnsupported,
+    /// The backend failed in some other way.
+    #[error("allocator error: {0}")]
+    Other(String),
+}
+
+/// How the memory of an allocation will be accessed, which the backend uses to pick a memory
+/// type along with the flags of an `AllocationDesc`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub enum MemoryUsage {
+    /// No intended usage; the memory type is picked by the flags alone.
+    #[default]
+    Unknown,
+    /// Memory only accessed by the GPU, preferably device local.
+    GpuOnly,
+    /// Memory mapped and accessed by the host, such as for staging.
+    CpuOnly,
+    /// Memory written by the host and read by the GPU every frame.
+    CpuToGpu,
+    /// Memory written by the GPU and read back by the host.
+    GpuToCpu,
+}
+
+/// The requirements of an allocation beyond those of the resource it is for.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct AllocationDesc {
+    /// How the memory will be accessed.
+    pub usage: MemoryUsage,
+    /// Properties the memory type must have.
+    pub required_flags: vk::MemoryPropertyFlags,
+    /// Properties the memory type should have if possible.
+    pub preferred_flags: vk::MemoryPropertyFlags,
+    /// A mask of the memory types the allocation may be made from, or 0 for any.
+    pub memory_type_bits: u32,
+    /// Whether the memory must be persistently mapped, if it is host visible.
+    pub mapped: bool,
+}
+
+/// The handle a backend identifies an allocation by.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum AllocationHandle {
+    #[cfg(feature = "vk-mem")]
+    Vma(vk_mem::Allocation),
+    /// The key of the allocation in the `GpuAllocator` which made it.
+    #[cfg(feature = "gpu-allocator")]
+    GpuAllocator(u64),
+    Custom(u64),
+    /// Memory allocated by the Device itself for a single resource, outside of the backend.
+    Dedicated,
+}
+
+/// A block of device memory made by a `MemoryAllocator`, along with where it lives.
+#[derive(Clone, Debug)]
+pub struct Allocation {
+    pub(crate) handle: AllocationHandle,
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    memory_type: u32,
+    mapped_data: Option<NonNull<u8>>,
+}
+
+// The mapped pointer points into memory owned by the allocation, which is only written through
+// by the resource owning it.
+unsafe impl Send for Allocation {}
+unsafe impl Sync for Allocation {}
+
+impl Allocation {
+    /// Describe an allocation made by a custom `MemoryAllocator`, which identifies it by `handle`.
+    pub fn new(
+        handle: u64,
+        memory: vk::DeviceMemory,
+        offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+        memory_type: u32,
+        mapped_data: Option<NonNull<u8>>,
+    ) -> Self {
+        Self {
+            handle: AllocationHandle::Custom(handle),
+            memory,
+            offset,
+            size,
+            memory_type,
+            mapped_data,
+        }
+    }
+
+    /// The handle given to `Allocation::new` by a custom `MemoryAllocator`, or `None` for
+    /// allocations made by a built-in backend.
+    pub fn custom_handle(&self) -> Option<u64> {
+        match self.handle {
+            AllocationHandle::Custom(handle) => Some(handle),
+            _ => None,
+        }
+    }
+
+    /// Describe a dedicated allocation of `memory`, which the Device made itself rather than
+    /// through its allocator, and frees itself.
+    pub(crate) fn dedicated(
+        memory: vk::DeviceMemory,
+        size: vk::DeviceSize,
+        memory_type: u32,
+        mapped_data: Option<NonNull<u8>>,
+    ) -> Self {
+        Self {
+            handle: AllocationHandle::Dedicated,
+            memory,
+            offset: 0,
+            size,
+            memory_type,
+            mapped_data,
+        }
+    }
+
+    /// Whether the allocation is a dedicated one the Device made itself, which is always host
+    /// coherent if it's host visible.
+    pub(crate) fn is_dedicated(&self) -> bool {
+        matches!(self.handle, AllocationHandle::Dedicated)
+    }
+
+    /// The `vk::DeviceMemory` the allocation is part of.
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    /// The offset of the allocation within its `vk::DeviceMemory`.
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    /// The size of the allocation.
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// The index of the memory type the allocation was made from.
+    pub fn memory_type(&self) -> u32 {
+        self.memory_type
+    }
+
+    /// The host address the allocation is mapped at, if it is persistently mapped.
+    pub fn mapped_data(&self) -> Option<NonNull<u8>> {
+        self.mapped_data
+    }
+}
+
+/// The memory a `MemoryAllocator` has allocated from one memory heap.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapUsage {
+    /// The bytes of the `vk::DeviceMemory` blocks allocated from the heap.
+    pub block_bytes: vk::DeviceSize,
+    /// The bytes of those blocks used by allocations.
+    pub allocated_bytes: vk::DeviceSize,
+}
+
+/// The interface between the Device and the library allocating its memory.
+///
+/// Every buffer, image and buffer block the Device creates is allocated through the Device's
+/// allocator, which is `VmaAllocator` with the default `vk-mem` feature, `GpuAllocator` with the
+/// `gpu-allocator` feature and without `vk-mem`, or the one given to `DeviceBuilder::allocator`.
+/// Implement this to use a different allocation library.
+pub trait MemoryAllocator: Send + Sync {
+    /// Create a buffer with memory allocated and bound for it.
+    fn create_buffer(
+        &self,
+        buffer_info: &vk::BufferCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Buffer, Allocation), AllocatorError>;
+
+    /// Destroy a buffer created with `create_buffer` and free its memory.
+    fn destroy_buffer(&self, buffer: vk::Buffer, allocation: &Allocation) -> Result<(), AllocatorError>;
+
+    /// Create an image with memory allocated and bound for it.
+    fn create_image(
+        &self,
+        image_info: &vk::ImageCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Image, Allocation), AllocatorError>;
+
+    /// Destroy an image created with `create_image` and free its memory.
+    fn destroy_image(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError>;
+
+    /// Allocate memory meeting `requirements` without binding it to anything.
+    fn allocate_memory(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        desc: &AllocationDesc,
+    ) -> Result<Allocation, AllocatorError>;
+
+    /// Free memory allocated with `allocate_memory`.
+    fn free_memory(&self, allocation: &Allocation) -> Result<(), AllocatorError>;
+
+    /// Bind memory allocated with `allocate_memory` to `image`.
+    fn bind_image_memory(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError>;
+
+    /// Flush host writes to `size` bytes at `offset` of a mapped allocation. Does nothing for
+    /// memory which is host coherent.
+    fn flush_allocation(
+        &self,
+        allocation: &Allocation,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), AllocatorError>;
+
+    /// Make device writes to `size` bytes at `offset` of a mapped allocation visible to the
+    /// host. Does nothing for memory which is host coherent.
+    fn invalidate_allocation(
+        &self,
+        allocation: &Allocation,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), AllocatorError>;
+
+    /// Find the memory type an allocation described by `desc` would be made from, out of those
+    /// in `memory_type_bits`.
+    fn find_memory_type_index(
+        &self,
+        memory_type_bits: u32,
+        desc: &AllocationDesc,
+    ) -> Result<u32, AllocatorError>;
+
+    /// Get the memory allocated from each memory heap, indexed like the heaps of the physical
+    /// device's memory properties.
+    fn heap_usage(&self) -> Result<Vec<HeapUsage>, AllocatorError>;
+}
+
+/// Everything a `MemoryAllocator` needs to be created for a Device, passed to the function
+/// given to `DeviceBuilder::allocator`.
+pub struct AllocatorContext<'a> {
+    /// The Vulkan entry points the instance was created with.
+    pub entry: &'a ash::Entry,
+    /// The Vulkan instance.
+    pub instance: &'a ash::Instance,
+    /// The physical device the Device was created for.
+    pub physical_device: vk::PhysicalDevice,
+    /// The logical device.
+    pub device: &'a ash::Device,
+    /// The number of frames which may be in flight at once.
+    pub frames_in_flight: usize,
+}
+
+/// A function creating the `MemoryAllocator` of a Device, given to `DeviceBuilder::allocator`.
+pub type AllocatorFactory = Arc<
+    dyn Fn(&AllocatorContext<'_>) -> Result<Box<dyn MemoryAllocator>, AllocatorError> + Send + Sync,
+>;
@@ -0,0 +1,406 @@
+use ash::version::{DeviceV1_0, EntryV1_0, InstanceV1_0};
+use ash::vk;
+use ash::vk::Handle;
+
+use ash_gpu_allocator::vk as vk_next;
+use ash_gpu_allocator::vk::Handle as _;
+
+use ::gpu_allocator::vulkan as gpu;
+use ::gpu_allocator::{AllocationError, AllocationSizes, AllocatorDebugSettings, MemoryLocation};
+
+use parking_lot::Mutex;
+
+use std::collections::HashMap;
+use std::ptr::NonNull;
+
+use super::*;
+
+/// The size of the blocks allocations are made from in memory types which aren't host visible.
+const DEVICE_BLOCK_SIZE: vk::DeviceSize = 256 * 1024 * 1024;
+
+/// The size of the blocks allocations are made from in host visible memory types.
+const HOST_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+impl From<AllocationError> for AllocatorError {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => AllocatorError::Vulkan(vk::Result::ERROR_OUT_OF_DEVICE_MEMORY),
+            AllocationError::NoCompatibleMemoryTypeFound => AllocatorError::NoSuitableMemoryType,
+            error => AllocatorError::Other(error.to_string()),
+        }
+    }
+}
+
+/// A pure Rust `MemoryAllocator`, backed by the gpu-allocator crate. Enabled by the
+/// `gpu-allocator` feature, and used by default when the `vk-mem` feature is disabled.
+///
+/// gpu-allocator picks memory types by a `MemoryLocation` alone, so an `AllocationDesc`'s
+/// preferred flags only steer the location it is given. Host visible memory is only allocated
+/// from host coherent memory types, which Vulkan guarantees exist, so flushing and invalidating
+/// allocations does nothing. Host visible allocations are always persistently mapped.
+pub struct GpuAllocator {
+    device: ash::Device,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    state: Mutex<GpuAllocatorState>,
+}
+
+struct GpuAllocatorState {
+    allocator: gpu::Allocator,
+    /// The live allocations, by the handle of the `Allocation` describing them.
+    allocations: HashMap<u64, gpu::Allocation>,
+    next_handle: u64,
+    /// The blocks of memory the live allocations were made from.
+    blocks: HashMap<vk::DeviceMemory, BlockUsage>,
+    /// The bytes of the live allocations in each memory heap.
+    allocated_bytes: Vec<vk::DeviceSize>,
+}
+
+/// A block of memory gpu-allocator has made allocations from.
+struct BlockUsage {
+    heap: usize,
+    size: vk::DeviceSize,
+    /// The number of live allocations made from it.
+    allocations: usize,
+}
+
+impl GpuAllocator {
+    /// Create an allocator for the device in `context`.
+    pub fn new(context: &AllocatorContext<'_>) -> Result<Self, AllocatorError> {
+        // gpu-allocator is built on a newer ash than hot, so the instance and device are loaded
+        // again through it from their raw handles.
+        let (instance, device) = unsafe {
+            let get_instance_proc_addr = context.entry.static_fn().get_instance_proc_addr;
+            let static_fn = ash_gpu_allocator::StaticFn {
+                get_instance_proc_addr: std::mem::transmute::<
+                    vk::PFN_vkGetInstanceProcAddr,
+                    vk_next::PFN_vkGetInstanceProcAddr,
+                >(get_instance_proc_addr),
+            };
+            let instance = ash_gpu_allocator::Instance::load(
+                &static_fn,
+                vk_next::Instance::from_raw(context.instance.handle().as_raw()),
+            );
+            let device = ash_gpu_allocator::Device::load(
+                instance.fp_v1_0(),
+                vk_next::Device::from_raw(context.device.handle().as_raw()),
+            );
+            (instance, device)
+        };
+        let allocator = gpu::Allocator::new(&gpu::AllocatorCreateDesc {
+            instance,
+            device,
+            physical_device: vk_next::PhysicalDevice::from_raw(context.physical_device.as_raw()),
+            debug_settings: AllocatorDebugSettings::default(),
+            // Buffers with device addresses are allocated by the Device itself.
+            buffer_device_address: false,
+            allocation_sizes: AllocationSizes::new(DEVICE_BLOCK_SIZE, HOST_BLOCK_SIZE),
+        })?;
+
+        let memory_properties = unsafe {
+            context
+                .instance
+                .get_physical_device_memory_properties(context.physical_device)
+        };
+        Ok(Self {
+            device: context.device.clone(),
+            memory_properties,
+            state: Mutex::new(GpuAllocatorState {
+                allocator,
+                allocations: HashMap::new(),
+                next_handle: 0,
+                blocks: HashMap::new(),
+                allocated_bytes: vec![0; memory_properties.memory_heap_count as usize],
+            }),
+        })
+    }
+
+    /// Get the memory types out of `memory_type_bits` an allocation described by `desc` may be
+    /// made from.
+    fn allowed_memory_types(&self, memory_type_bits: u32, desc: &AllocationDesc) -> u32 {
+        let mut allowed = memory_type_bits;
+        if desc.memory_type_bits != 0 {
+            allowed &= desc.memory_type_bits;
+        }
+        for (i, memory_type) in self.memory_types().iter().enumerate() {
+            let flags = memory_type.property_flags;
+            let non_coherent = flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+                && !flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT);
+            if !flags.contains(desc.required_flags) || non_coherent {
+                allowed &= !(1 << i);
+            }
+        }
+        allowed
+    }
+
+    fn memory_types(&self) -> &[vk::MemoryType] {
+        &self.memory_properties.memory_types[..self.memory_properties.memory_type_count as usize]
+    }
+
+    /// Allocate memory meeting `requirements` for a resource which is `linear` if it is a buffer
+    /// or a linearly tiled image.
+    fn allocate(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        desc: &AllocationDesc,
+        linear: bool,
+    ) -> Result<Allocation, AllocatorError> {
+        let memory_type_bits = self.allowed_memory_types(requirements.memory_type_bits, desc);
+        if memory_type_bits == 0 {
+            return Err(AllocatorError::NoSuitableMemoryType);
+        }
+
+        let mut state = self.state.lock();
+        let raw = state.allocator.allocate(&gpu::AllocationCreateDesc {
+            name: "hot",
+            requirements: vk_next::MemoryRequirements {
+                size: requirements.size,
+                alignment: requirements.alignment,
+                memory_type_bits,
+            },
+            location: memory_location(desc),
+            linear,
+            allocation_scheme: gpu::AllocationScheme::GpuAllocatorManaged,
+        })?;
+
+        // gpu-allocator takes the first allowed memory type with the flags it looks for, which
+        // is also the first allowed one with exactly the flags of the type it took.
+        let flags = vk::MemoryPropertyFlags::from_raw(raw.memory_properties().as_raw());
+        let memory_type = self
+            .memory_types()
+            .iter()
+            .enumerate()
+            .position(|(i, memory_type)| memory_type_bits & (1 << i) != 0 && memory_type.property_flags == flags)
+            .expect("gpu-allocator allocated from a memory type it wasn't allowed") as u32;
+
+        // Allocations bigger than a block get a block of their own.
+        let heap = self.memory_types()[memory_type as usize].heap_index as usize;
+        let block_size = if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+            HOST_BLOCK_SIZE
+        } else {
+            DEVICE_BLOCK_SIZE
+        };
+        let memory = vk::DeviceMemory::from_raw(unsafe { raw.memory() }.as_raw());
+        state
+            .blocks
+            .entry(memory)
+            .or_insert(BlockUsage {
+                heap,
+                size: block_size.max(requirements.size),
+                allocations: 0,
+            })
+            .allocations += 1;
+        state.allocated_bytes[heap] += raw.size();
+
+        let handle = state.next_handle;
+        state.next_handle += 1;
+        let allocation = Allocation {
+            handle: AllocationHandle::GpuAllocator(handle),
+            memory,
+            offset: raw.offset(),
+            size: raw.size(),
+            memory_type,
+            mapped_data: raw.mapped_ptr().map(NonNull::cast),
+        };
+        state.allocations.insert(handle, raw);
+        Ok(allocation)
+    }
+
+    /// Free an allocation made by `allocate`.
+    fn free(&self, allocation: &Allocation) -> Result<(), AllocatorError> {
+        let handle = match allocation.handle {
+            AllocationHandle::GpuAllocator(handle) => handle,
+            _ => {
+                return Err(AllocatorError::Other(String::from(
+                    "the allocation was not made by the gpu-allocator allocator",
+                )))
+            }
+        };
+
+        let mut state = self.state.lock();
+        let raw = state
+            .allocations
+            .remove(&handle)
+            .ok_or_else(|| AllocatorError::Other(String::from("the allocation was already freed")))?;
+        let heap = {
+            let block = state
+                .blocks
+                .get_mut(&allocation.memory)
+                .expect("gpu-allocator allocation's block was not tracked");
+            block.allocations -= 1;
+            block.heap
+        };
+        if state.blocks[&allocation.memory].allocations == 0 {
+            state.blocks.remove(&allocation.memory);
+        }
+        state.allocated_bytes[heap] -= raw.size();
+        Ok(state.allocator.free(raw)?)
+    }
+}
+
+/// Get the `MemoryLocation` gpu-allocator picks the memory type of an allocation described by
+/// `desc` with.
+fn memory_location(desc: &AllocationDesc) -> MemoryLocation {
+    match desc.usage {
+        MemoryUsage::GpuOnly => MemoryLocation::GpuOnly,
+        MemoryUsage::CpuOnly | MemoryUsage::CpuToGpu => MemoryLocation::CpuToGpu,
+        MemoryUsage::GpuToCpu => MemoryLocation::GpuToCpu,
+        MemoryUsage::Unknown => {
+            let flags = desc.required_flags | desc.preferred_flags;
+            if flags.contains(vk::MemoryPropertyFlags::HOST_CACHED) {
+                MemoryLocation::GpuToCpu
+            } else if flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+                MemoryLocation::CpuToGpu
+            } else if flags.contains(vk::MemoryPropertyFlags::DEVICE_LOCAL) {
+                MemoryLocation::GpuOnly
+            } else {
+                MemoryLocation::Unknown
+            }
+        }
+    }
+}
+
+impl MemoryAllocator for GpuAllocator {
+    fn create_buffer(
+        &self,
+        buffer_info: &vk::BufferCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Buffer, Allocation), AllocatorError> {
+        let buffer = unsafe { self.device.create_buffer(buffer_info, None)? };
+        let requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+        let allocation = match self.allocate(&requirements, desc, true) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                return Err(error);
+            }
+        };
+        if let Err(error) =
+            unsafe { self.device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) }
+        {
+            unsafe { self.device.destroy_buffer(buffer, None) };
+            self.free(&allocation)?;
+            return Err(error.into());
+        }
+        Ok((buffer, allocation))
+    }
+
+    fn destroy_buffer(&self, buffer: vk::Buffer, allocation: &Allocation) -> Result<(), AllocatorError> {
+        unsafe { self.device.destroy_buffer(buffer, None) };
+        self.free(allocation)
+    }
+
+    fn create_image(
+        &self,
+        image_info: &vk::ImageCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Image, Allocation), AllocatorError> {
+        let image = unsafe { self.device.create_image(image_info, None)? };
+        let requirements = unsafe { self.device.get_image_memory_requirements(image) };
+        let linear = image_info.tiling == vk::ImageTiling::LINEAR;
+        let allocation = match self.allocate(&requirements, desc, linear) {
+            Ok(allocation) => allocation,
+            Err(error) => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err(error);
+            }
+        };
+        if let Err(error) = self.bind_image_memory(image, &allocation) {
+            unsafe { self.device.destroy_image(image, None) };
+            self.free(&allocation)?;
+            return Err(error);
+        }
+        Ok((image, allocation))
+    }
+
+    fn destroy_image(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError> {
+        unsafe { self.device.destroy_image(image, None) };
+        self.free(allocation)
+    }
+
+    fn allocate_memory(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        desc: &AllocationDesc,
+    ) -> Result<Allocation, AllocatorError> {
+        // Memory allocated unbound is for images, which are optimally tiled.
+        self.allocate(requirements, desc, false)
+    }
+
+    fn free_memory(&self, allocation: &Allocation) -> Result<(), AllocatorError> {
+        self.free(allocation)
+    }
+
+    fn bind_image_memory(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError> {
+        unsafe {
+            self.device
+                .bind_image_memory(image, allocation.memory, allocation.offset)?
+        };
+        Ok(())
+    }
+
+    fn flush_allocation(&self, _: &Allocation, _: usize, _: usize) -> Result<(), AllocatorError> {
+        // Host visible memory is always host coherent.
+        Ok(())
+    }
+
+    fn invalidate_allocation(&self, _: &Allocation, _: usize, _: usize) -> Result<(), AllocatorError> {
+        Ok(())
+    }
+
+    fn find_memory_type_index(
+        &self,
+        memory_type_bits: u32,
+        desc: &AllocationDesc,
+    ) -> Result<u32, AllocatorError> {
+        // Mirror gpu-allocator's search: the first allowed type with the location's preferred
+        // flags, or failing that its required flags.
+        let allowed = self.allowed_memory_types(memory_type_bits, desc);
+        let (preferred, required) = match memory_location(desc) {
+            MemoryLocation::GpuOnly => (
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            ),
+            MemoryLocation::CpuToGpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE
+                    | vk::MemoryPropertyFlags::HOST_COHERENT
+                    | vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ),
+            MemoryLocation::GpuToCpu => (
+                vk::MemoryPropertyFlags::HOST_VISIBLE
+                    | vk::MemoryPropertyFlags::HOST_COHERENT
+                    | vk::MemoryPropertyFlags::HOST_CACHED,
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ),
+            _ => (vk::MemoryPropertyFlags::empty(), vk::MemoryPropertyFlags::empty()),
+        };
+        let find = |flags: vk::MemoryPropertyFlags| {
+            self.memory_types()
+                .iter()
+                .zip(0..)
+                .find(|(memory_type, i)| allowed & (1 << i) != 0 && memory_type.property_flags.contains(flags))
+                .map(|(_, i)| i)
+        };
+        find(preferred)
+            .or_else(|| find(required))
+            .ok_or(AllocatorError::NoSuitableMemoryType)
+    }
+
+    /// gpu-allocator keeps an empty block of each memory type around for reuse, which isn't
+    /// counted in the blocks' bytes.
+    fn heap_usage(&self) -> Result<Vec<HeapUsage>, AllocatorError> {
+        let state = self.state.lock();
+        let mut usage = state
+            .allocated_bytes
+            .iter()
+            .map(|&allocated_bytes| HeapUsage {
+                block_bytes: 0,
+                allocated_bytes,
+            })
+            .collect::<Vec<_>>();
+        for block in state.blocks.values() {
+            usage[block.heap].block_bytes += block.size;
+        }
+        Ok(usage)
+    }
+}
@@ -0,0 +1,179 @@
+use ash::vk;
+
+use std::ptr::NonNull;
+
+use super::*;
+
+impl From<vk_mem::Error> for AllocatorError {
+    fn from(error: vk_mem::Error) -> Self {
+        match *error.kind() {
+            vk_mem::ErrorKind::Vulkan(result) => AllocatorError::Vulkan(result),
+            _ => AllocatorError::Other(error.to_string()),
+        }
+    }
+}
+
+/// The default `MemoryAllocator`, backed by the Vulkan Memory Allocator library through
+/// `vk_mem`. Enabled by the `vk-mem` feature.
+pub struct VmaAllocator {
+    allocator: vk_mem::Allocator,
+}
+
+impl VmaAllocator {
+    /// Create an allocator for the device in `context`.
+    pub fn new(context: &AllocatorContext<'_>) -> Result<Self, AllocatorError> {
+        let allocator = vk_mem::Allocator::new(&vk_mem::AllocatorCreateInfo {
+            physical_device: context.physical_device,
+            device: context.device.clone(),
+            instance: context.instance.clone(),
+            flags: vk_mem::AllocatorCreateFlags::NONE,
+            preferred_large_heap_block_size: 0,
+            frame_in_use_count: context.frames_in_flight as u32 - 1,
+            heap_size_limits: None,
+        })?;
+        Ok(Self { allocator })
+    }
+
+    /// The raw `vk_mem::Allocator`.
+    pub fn raw(&self) -> &vk_mem::Allocator {
+        &self.allocator
+    }
+
+    /// Get the `vk_mem::Allocation` of an allocation made by this allocator.
+    fn vma_allocation(allocation: &Allocation) -> Result<&vk_mem::Allocation, AllocatorError> {
+        match allocation.handle {
+            AllocationHandle::Vma(ref allocation) => Ok(allocation),
+            _ => Err(AllocatorError::Other(String::from(
+                "the allocation was not made by the VMA allocator",
+            ))),
+        }
+    }
+}
+
+/// Convert a `MemoryUsage` to VMA's equivalent.
+fn vma_usage(usage: MemoryUsage) -> vk_mem::MemoryUsage {
+    match usage {
+        MemoryUsage::Unknown => vk_mem::MemoryUsage::Unknown,
+        MemoryUsage::GpuOnly => vk_mem::MemoryUsage::GpuOnly,
+        MemoryUsage::CpuOnly => vk_mem::MemoryUsage::CpuOnly,
+        MemoryUsage::CpuToGpu => vk_mem::MemoryUsage::CpuToGpu,
+        MemoryUsage::GpuToCpu => vk_mem::MemoryUsage::GpuToCpu,
+    }
+}
+
+/// Convert an `AllocationDesc` to VMA's equivalent.
+fn vma_create_info(desc: &AllocationDesc) -> vk_mem::AllocationCreateInfo {
+    vk_mem::AllocationCreateInfo {
+        usage: vma_usage(desc.usage),
+        flags: if desc.mapped {
+            vk_mem::AllocationCreateFlags::MAPPED
+        } else {
+            vk_mem::AllocationCreateFlags::NONE
+        },
+        required_flags: desc.required_flags,
+        preferred_flags: desc.preferred_flags,
+        memory_type_bits: desc.memory_type_bits,
+        ..Default::default()
+    }
+}
+
+/// Describe a VMA allocation.
+fn allocation(allocation: vk_mem::Allocation, info: &vk_mem::AllocationInfo) -> Allocation {
+    Allocation {
+        handle: AllocationHandle::Vma(allocation),
+        memory: info.get_device_memory(),
+        offset: info.get_offset() as vk::DeviceSize,
+        size: info.get_size() as vk::DeviceSize,
+        memory_type: info.get_memory_type(),
+        mapped_data: NonNull::new(info.get_mapped_data()),
+    }
+}
+
+impl MemoryAllocator for VmaAllocator {
+    fn create_buffer(
+        &self,
+        buffer_info: &vk::BufferCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Buffer, Allocation), AllocatorError> {
+        let (buffer, raw, info) = self.allocator.create_buffer(buffer_info, &vma_create_info(desc))?;
+        Ok((buffer, allocation(raw, &info)))
+    }
+
+    fn destroy_buffer(&self, buffer: vk::Buffer, allocation: &Allocation) -> Result<(), AllocatorError> {
+        Ok(self.allocator.destroy_buffer(buffer, Self::vma_allocation(allocation)?)?)
+    }
+
+    fn create_image(
+        &self,
+        image_info: &vk::ImageCreateInfo,
+        desc: &AllocationDesc,
+    ) -> Result<(vk::Image, Allocation), AllocatorError> {
+        let (image, raw, info) = self.allocator.create_image(image_info, &vma_create_info(desc))?;
+        Ok((image, allocation(raw, &info)))
+    }
+
+    fn destroy_image(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError> {
+        Ok(self.allocator.destroy_image(image, Self::vma_allocation(allocation)?)?)
+    }
+
+    fn allocate_memory(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        desc: &AllocationDesc,
+    ) -> Result<Allocation, AllocatorError> {
+        let (raw, info) = self.allocator.allocate_memory(requirements, &vma_create_info(desc))?;
+        Ok(allocation(raw, &info))
+    }
+
+    fn free_memory(&self, allocation: &Allocation) -> Result<(), AllocatorError> {
+        Ok(self.allocator.free_memory(Self::vma_allocation(allocation)?)?)
+    }
+
+    fn bind_image_memory(&self, image: vk::Image, allocation: &Allocation) -> Result<(), AllocatorError> {
+        Ok(self.allocator.bind_image_memory(image, Self::vma_allocation(allocation)?)?)
+    }
+
+    fn flush_allocation(
+        &self,
+        allocation: &Allocation,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), AllocatorError> {
+        Ok(self
+            .allocator
+            .flush_allocation(Self::vma_allocation(allocation)?, offset, size)?)
+    }
+
+    fn invalidate_allocation(
+        &self,
+        allocation: &Allocation,
+        offset: usize,
+        size: usize,
+    ) -> Result<(), AllocatorError> {
+        Ok(self
+            .allocator
+            .invalidate_allocation(Self::vma_allocation(allocation)?, offset, size)?)
+    }
+
+    fn find_memory_type_index(
+        &self,
+        memory_type_bits: u32,
+        desc: &AllocationDesc,
+    ) -> Result<u32, AllocatorError> {
+        Ok(self
+            .allocator
+            .find_memory_type_index(memory_type_bits, &vma_create_info(desc))?)
+    }
+
+    fn heap_usage(&self) -> Result<Vec<HeapUsage>, AllocatorError> {
+        let stats = self.allocator.calculate_stats()?;
+        let heap_count = self.allocator.get_memory_properties()?.memory_heap_count as usize;
+        Ok(stats.memoryHeap[..heap_count]
+            .iter()
+            .map(|heap| HeapUsage {
+                block_bytes: heap.usedBytes + heap.unusedBytes,
+                allocated_bytes: heap.usedBytes,
+            })
+            .collect())
+    }
+}
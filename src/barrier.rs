@@ -0,0 +1,552 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::*;
+
+/// Get all access flags which represent a write. Only writes need to be made available by a
+/// barrier.
+pub fn write_access_flags() -> vk::AccessFlags {
+    vk::AccessFlags::SHADER_WRITE
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+        | vk::AccessFlags::TRANSFER_WRITE
+        | vk::AccessFlags::HOST_WRITE
+        | vk::AccessFlags::MEMORY_WRITE
+}
+
+/// Get whether a set of `vk::AccessFlags` contains any write access.
+pub fn access_has_writes(access: vk::AccessFlags) -> bool {
+    access.intersects(write_access_flags())
+}
+
+/// The synchronization state of an image: the layout it is in and the pipeline stages
+/// and accesses which last used it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ImageState {
+    /// The layout of the image.
+    pub layout: vk::ImageLayout,
+    /// The pipeline stages which last accessed the image.
+    pub stages: vk::PipelineStageFlags,
+    /// The kinds of access with which the image was last accessed.
+    pub access: vk::AccessFlags,
+}
+
+impl ImageState {
+    /// The state of an image which has never been used.
+    pub fn undefined() -> ImageState {
+        ImageState {
+            layout: vk::ImageLayout::UNDEFINED,
+            stages: vk::PipelineStageFlags::TOP_OF_PIPE,
+            access: vk::AccessFlags::empty(),
+        }
+    }
+}
+
+/// A named transition between two common image states, with the stages and accesses filled in.
+///
+/// Presets can be recorded directly with `CommandBuffer::transition_image_preset`, turned into a
+/// raw barrier with `TransitionPreset::image_transition`, or declared on a render graph pass with
+/// `PassBuilder::transition`. Only the destination state matters to the tracked transitions,
+/// since the source state is already known to the Device.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum TransitionPreset {
+    /// Discard the contents of an image and prepare it to be rendered to as a color attachment.
+    UndefinedToColorAttachment,
+    /// Discard the contents of an image and prepare it to be used as a depth-stencil attachment.
+    UndefinedToDepthStencilAttachment,
+    /// Discard the contents of an image and prepare it to be copied into.
+    UndefinedToTransferDst,
+    /// Make a rendered color attachment readable from fragment shaders.
+    ColorAttachmentToShaderRead,
+    /// Make a rendered depth-stencil attachment readable from fragment shaders.
+    DepthStencilAttachmentToShaderRead,
+    /// Make an image which was copied into readable from fragment shaders.
+    TransferDstToShaderRead,
+    /// Prepare an image read by fragment shaders to be copied from.
+    ShaderReadToTransferSrc,
+    /// Prepare an image read by fragment shaders to be rendered to as a color attachment again.
+    ShaderReadToColorAttachment,
+    /// Prepare a rendered color attachment to be copied from, for example for a readback.
+    ColorAttachmentToTransferSrc,
+    /// Prepare a rendered color attachment to be presented.
+    ColorAttachmentToPresent,
+}
+
+impl TransitionPreset {
+    /// Every preset, in declaration order.
+    pub const ALL: &'static [TransitionPreset] = &[
+        TransitionPreset::UndefinedToColorAttachment,
+        TransitionPreset::UndefinedToDepthStencilAttachment,
+        TransitionPreset::UndefinedToTransferDst,
+        TransitionPreset::ColorAttachmentToShaderRead,
+        TransitionPreset::DepthStencilAttachmentToShaderRead,
+        TransitionPreset::TransferDstToShaderRead,
+        TransitionPreset::ShaderReadToTransferSrc,
+        TransitionPreset::ShaderReadToColorAttachment,
+        TransitionPreset::ColorAttachmentToTransferSrc,
+        TransitionPreset::ColorAttachmentToPresent,
+    ];
+
+    /// The state the image is expected to be in before the transition.
+    pub fn src(self) -> ImageState {
+        use TransitionPreset::*;
+        match self {
+            UndefinedToColorAttachment | UndefinedToDepthStencilAttachment | UndefinedToTransferDst => {
+                ImageState::undefined()
+            }
+            ColorAttachmentToShaderRead | ColorAttachmentToTransferSrc | ColorAttachmentToPresent => {
+                color_attachment_state()
+            }
+            DepthStencilAttachmentToShaderRead => depth_stencil_attachment_state(),
+            TransferDstToShaderRead => transfer_dst_state(),
+            ShaderReadToTransferSrc | ShaderReadToColorAttachment => {
+                shader_read_state(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            }
+        }
+    }
+
+    /// The state the image is in after the transition.
+    pub fn dst(self) -> ImageState {
+        use TransitionPreset::*;
+        match self {
+            UndefinedToColorAttachment | ShaderReadToColorAttachment => color_attachment_state(),
+            UndefinedToDepthStencilAttachment => depth_stencil_attachment_state(),
+            UndefinedToTransferDst => transfer_dst_state(),
+            ColorAttachmentToShaderRead | TransferDstToShaderRead => {
+                shader_read_state(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+            }
+            DepthStencilAttachmentToShaderRead => {
+                shader_read_state(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)
+            }
+            ShaderReadToTransferSrc | ColorAttachmentToTransferSrc => ImageState {
+                layout: vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                stages: vk::PipelineStageFlags::TRANSFER,
+                access: vk::AccessFlags::TRANSFER_READ,
+            },
+            // Presentation is synchronized by the semaphore passed to the present, so no stage
+            // or access needs to wait on the barrier.
+            ColorAttachmentToPresent => ImageState {
+                layout: vk::ImageLayout::PRESENT_SRC_KHR,
+                stages: vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                access: vk::AccessFlags::empty(),
+            },
+        }
+    }
+
+    /// Compute the barrier for this preset on `image` with subresources `range`, for use with
+    /// images whose state isn't tracked by a Device.
+    pub fn image_transition(self, image: vk::Image, range: vk::ImageSubresourceRange) -> Option<ImageTransition> {
+        image_transition(image, range, self.src(), self.dst())
+    }
+}
+
+fn color_attachment_state() -> ImageState {
+    ImageState {
+        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        stages: vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        access: vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+    }
+}
+
+fn depth_stencil_attachment_state() -> ImageState {
+    ImageState {
+        layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        stages: vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+        access: vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+            | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+    }
+}
+
+fn transfer_dst_state() -> ImageState {
+    ImageState {
+        layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+        stages: vk::PipelineStageFlags::TRANSFER,
+        access: vk::AccessFlags::TRANSFER_WRITE,
+    }
+}
+
+fn shader_read_state(layout: vk::ImageLayout) -> ImageState {
+    ImageState {
+        layout,
+        stages: vk::PipelineStageFlags::FRAGMENT_SHADER,
+        access: vk::AccessFlags::SHADER_READ,
+    }
+}
+
+/// A barrier needed to transition an image from one `ImageState` to another.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageTransition {
+    /// The stages which must complete before the barrier.
+    pub src_stages: vk::PipelineStageFlags,
+    /// The stages which must wait on the barrier.
+    pub dst_stages: vk::PipelineStageFlags,
+    /// The image memory barrier itself.
+    pub barrier: vk::ImageMemoryBarrier,
+}
+
+/// Compute the minimal barrier needed to move `image` with subresources `range` from the
+/// `old` state to the `new` state, or `None` if no barrier is needed.
+///
+/// Only `old` is known, so it is taken to be the latest of accesses which may have followed a
+/// write. No barrier is needed when the layout does not change, neither access writes to the
+/// image and `new` is by stages and accesses which `old` already covers.
+pub fn image_transition(
+    image: vk::Image,
+    range: vk::ImageSubresourceRange,
+    old: ImageState,
+    new: ImageState,
+) -> Option<ImageTransition> {
+    let mut history = AccessHistory::last_accessed(old.stages, old.access);
+    tracked_image_transition(image, range, old.layout, &mut history, new)
+}
+
+/// Compute the barrier needed to move `image` with subresources `range` from `old_layout` and
+/// the accesses in `history` to the `new` state, recording the new access in `history`.
+pub(crate) fn tracked_image_transition(
+    image: vk::Image,
+    range: vk::ImageSubresourceRange,
+    old_layout: vk::ImageLayout,
+    history: &mut AccessHistory,
+    new: ImageState,
+) -> Option<ImageTransition> {
+    let scopes = history.record(new.stages, new.access, old_layout != new.layout)?;
+
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .src_access_mask(scopes.src_access)
+        .dst_access_mask(scopes.dst_access)
+        .old_layout(old_layout)
+        .new_layout(new.layout)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(range)
+        .build();
+
+    Some(ImageTransition {
+        src_stages: scopes.src_stages,
+        dst_stages: scopes.dst_stages,
+        barrier,
+    })
+}
+
+/// The synchronization state of a buffer: the pipeline stages and accesses which last used it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct BufferState {
+    /// The pipeline stages which last accessed the buffer.
+    pub stages: vk::PipelineStageFlags,
+    /// The kinds of access with which the buffer was last accessed.
+    pub access: vk::AccessFlags,
+}
+
+/// A barrier needed before a buffer may be accessed in a new `BufferState`.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferTransition {
+    /// The stages which must complete before the barrier.
+    pub src_stages: vk::PipelineStageFlags,
+    /// The stages which must wait on the barrier.
+    pub dst_stages: vk::PipelineStageFlags,
+    /// The buffer memory barrier itself.
+    pub barrier: vk::BufferMemoryBarrier,
+}
+
+/// Compute the minimal barrier needed before `buffer` may be accessed in the `new` state after
+/// being accessed in the `old` state, or `None` if no barrier is needed.
+///
+/// Only `old` is known, so it is taken to be the latest of accesses which may have followed a
+/// write. No barrier is needed when the buffer was never used, or when neither access writes to
+/// the buffer and `new` is by stages and accesses which `old` already covers.
+pub fn buffer_transition(
+    buffer: vk::Buffer,
+    old: BufferState,
+    new: BufferState,
+) -> Option<BufferTransition> {
+    let mut history = AccessHistory::last_accessed(old.stages, old.access);
+    tracked_buffer_transition(buffer, &mut history, new)
+}
+
+/// Compute the barrier needed before `buffer` may be accessed in the `new` state after the
+/// accesses in `history`, recording the new access in `history`.
+pub(crate) fn tracked_buffer_transition(
+    buffer: vk::Buffer,
+    history: &mut AccessHistory,
+    new: BufferState,
+) -> Option<BufferTransition> {
+    let scopes = history.record(new.stages, new.access, false)?;
+
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_access_mask(scopes.src_access)
+        .dst_access_mask(scopes.dst_access)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE)
+        .build();
+
+    Some(BufferTransition {
+        src_stages: scopes.src_stages,
+        dst_stages: scopes.dst_stages,
+        barrier,
+    })
+}
+
+/// The accesses of a tracked resource since it was last written, from which the barrier before
+/// its next access is computed.
+///
+/// Reads are tracked apart from the last write, so that a read by stages or accesses the write
+/// has not yet been made visible to still waits for the write, even if other reads since
+/// already have.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub(crate) struct AccessHistory {
+    /// The stages of the last write, or of the barrier which last transitioned the layout.
+    write_stages: vk::PipelineStageFlags,
+    /// The accesses of the last write.
+    write_access: vk::AccessFlags,
+    /// The stages which have read the resource since the last write, which the next write must
+    /// wait for.
+    read_stages: vk::PipelineStageFlags,
+    /// The stages the last write has been made visible to.
+    visible_stages: vk::PipelineStageFlags,
+    /// The accesses the last write has been made visible to.
+    visible_access: vk::AccessFlags,
+}
+
+/// The scopes of a barrier computed by `AccessHistory::record`.
+pub(crate) struct BarrierScopes {
+    pub(crate) src_stages: vk::PipelineStageFlags,
+    pub(crate) src_access: vk::AccessFlags,
+    pub(crate) dst_stages: vk::PipelineStageFlags,
+    pub(crate) dst_access: vk::AccessFlags,
+}
+
+impl AccessHistory {
+    /// The history of a resource last accessed by `stages` with `access`, after which reads by
+    /// other stages or with other accesses wait for `stages`, in case they followed a write.
+    pub(crate) fn last_accessed(stages: vk::PipelineStageFlags, access: vk::AccessFlags) -> Self {
+        AccessHistory {
+            write_stages: stages,
+            write_access: access & write_access_flags(),
+            read_stages: stages,
+            visible_stages: stages,
+            visible_access: access,
+        }
+    }
+
+    /// The stages which accessed the resource since the last write, including it.
+    pub(crate) fn stages(&self) -> vk::PipelineStageFlags {
+        self.write_stages | self.read_stages
+    }
+
+    /// The accesses of the last write and those it has been made visible to.
+    pub(crate) fn access(&self) -> vk::AccessFlags {
+        self.write_access | self.visible_access
+    }
+
+    /// Record an access by `stages` with `access`, which also transitions the layout of an image
+    /// if `layout_changes`, and return the scopes of the barrier needed before it, if any.
+    pub(crate) fn record(
+        &mut self,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+        layout_changes: bool,
+    ) -> Option<BarrierScopes> {
+        let dst_stages = if stages.is_empty() {
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE
+        } else {
+            stages
+        };
+
+        // Writes and layout transitions wait for every access since the last write, and are
+        // visible to nothing after them until another barrier.
+        if access_has_writes(access) || layout_changes {
+            let src_stages = self.stages();
+            let scopes = (layout_changes || !src_stages.is_empty()).then(|| BarrierScopes {
+                src_stages: if src_stages.is_empty() {
+                    vk::PipelineStageFlags::TOP_OF_PIPE
+                } else {
+                    src_stages
+                },
+                src_access: self.write_access,
+                dst_stages,
+                dst_access: access,
+            });
+            *self = if access_has_writes(access) {
+                AccessHistory {
+                    write_stages: dst_stages,
+                    write_access: access & write_access_flags(),
+                    ..Default::default()
+                }
+            } else {
+                // A layout transition alone is visible to the access the barrier was made for.
+                AccessHistory {
+                    write_stages: dst_stages,
+                    write_access: vk::AccessFlags::empty(),
+                    read_stages: stages,
+                    visible_stages: stages,
+                    visible_access: access,
+                }
+            };
+            return scopes;
+        }
+
+        // Reads only wait for the last write, and only if it hasn't been made visible to them.
+        self.read_stages |= stages;
+        let visible = self.visible_stages.contains(stages) && self.visible_access.contains(access);
+        if self.write_stages.is_empty() || visible {
+            return None;
+        }
+        let scopes = BarrierScopes {
+            src_stages: self.write_stages,
+            src_access: self.write_access,
+            dst_stages,
+            dst_access: access,
+        };
+        self.visible_stages |= stages;
+        self.visible_access |= access;
+        Some(scopes)
+    }
+}
+
+impl Device {
+    /// Prepare `buffer` for access by `new_stages` with `new_access`, recording the minimal
+    /// pipeline barrier needed into `cmd` and updating the buffer's tracked state. Returns
+    /// whether a barrier was recorded.
+    ///
+    /// # Safety
+    ///
+    /// * `cmd` must be a command buffer allocated from this Device in the recording state.
+    /// * The command buffers recorded with tracked transitions must be submitted in the order
+    ///   they were recorded, otherwise the tracked state will not match the real state.
+    pub unsafe fn transition_buffer(
+        &self,
+        cmd: vk::CommandBuffer,
+        buffer: BufferHandle,
+        new_stages: vk::PipelineStageFlags,
+        new_access: vk::AccessFlags,
+    ) -> bool {
+        let transition = match self.resources_mut().get_buffer_mut(buffer) {
+            Some(buffer) => buffer.transition_to(BufferState {
+                stages: new_stages,
+                access: new_access,
+            }),
+            None => return false,
+        };
+
+        match transition {
+            Some(transition) => {
+                self.record_transitions(cmd, &[transition], &[]);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Transition `image` into `new_layout` for access by `new_stages` with `new_access`,
+    /// recording the minimal pipeline barriers needed into `cmd` and updating the image's tracked
+    /// state. Returns whether a barrier was recorded.
+    ///
+    /// If the image uses `ImageLayoutType::General`, the image will be kept in
+    /// `vk::ImageLayout::GENERAL` rather than `new_layout`.
+    ///
+    /// # Safety
+    ///
+    /// * `cmd` must be a command buffer allocated from this Device in the recording state.
+    /// * The command buffers recorded with tracked transitions must be submitted in the order
+    ///   they were recorded, otherwise the tracked state will not match the real state.
+    pub unsafe fn transition_image(
+        &self,
+        cmd: vk::CommandBuffer,
+        image: ImageHandle,
+        new_layout: vk::ImageLayout,
+        new_stages: vk::PipelineStageFlags,
+        new_access: vk::AccessFlags,
+    ) -> bool {
+        self.transition_image_subresources(cmd, image, None, new_layout, new_stages, new_access)
+    }
+
+    /// Like `transition_image`, but only transitions the subresources of `image` in `range`, or
+    /// the whole image if `range` is `None`. The other subresources keep their tracked states.
+    ///
+    /// # Safety
+    ///
+    /// See `transition_image`.
+    pub unsafe fn transition_image_subresources(
+        &self,
+        cmd: vk::CommandBuffer,
+        image: ImageHandle,
+        range: Option<vk::ImageSubresourceRange>,
+        new_layout: vk::ImageLayout,
+        new_stages: vk::PipelineStageFlags,
+        new_access: vk::AccessFlags,
+    ) -> bool {
+        let transitions = {
+            let mut resources = self.resources_mut();
+            acquire_aliased_memory(&mut resources, image);
+            let image = match resources.get_image_mut(image) {
+                Some(image) => image,
+                None => return false,
+            };
+            let range = range.unwrap_or_else(|| image.full_subresource_range());
+            image.transition_subresources_to(
+                range,
+                ImageState {
+                    layout: image.layout(new_layout),
+                    stages: new_stages,
+                    access: new_access,
+                },
+            )
+        };
+
+        if transitions.is_empty() {
+            return false;
+        }
+
+        self.record_transitions(cmd, &[], &transitions);
+        true
+    }
+
+    /// Record `buffer_transitions` and `image_transitions` into `cmd` as one pipeline barrier.
+    ///
+    /// With synchronization2 each barrier keeps its own stages. Without it the barrier waits on
+    /// the union of their source stages before the union of their destination stages.
+    pub(crate) unsafe fn record_transitions(
+        &self,
+        cmd: vk::CommandBuffer,
+        buffer_transitions: &[BufferTransition],
+        image_transitions: &[ImageTransition],
+    ) {
+        if let Some(synchronization2) = &self.synchronization2 {
+            synchronization2.cmd_pipeline_barrier(cmd, buffer_transitions, image_transitions);
+            return;
+        }
+
+        let mut src_stages = vk::PipelineStageFlags::empty();
+        let mut dst_stages = vk::PipelineStageFlags::empty();
+        for (src, dst) in buffer_transitions
+            .iter()
+            .map(|transition| (transition.src_stages, transition.dst_stages))
+            .chain(image_transitions.iter().map(|transition| (transition.src_stages, transition.dst_stages)))
+        {
+            src_stages |= src;
+            dst_stages |= dst;
+        }
+        let buffer_barriers = buffer_transitions
+            .iter()
+            .map(|transition| transition.barrier)
+            .collect::<Vec<_>>();
+        let image_barriers = image_transitions
+            .iter()
+            .map(|transition| transition.barrier)
+            .collect::<Vec<_>>();
+
+        self.device.cmd_pipeline_barrier(
+            cmd,
+            src_stages,
+            dst_stages,
+            vk::DependencyFlags::empty(),
+            &[],
+            &buffer_barriers,
+            &image_barriers,
+        );
+    }
+}
@@ -1,13 +1,17 @@
 pub use ash::vk;
 use ash::version::DeviceV1_0;
 
+use bytemuck::Pod;
+
 use derivative::Derivative;
 
+use thiserror::Error;
 
+use std::ops::{Deref, DerefMut};
 use std::ptr::NonNull;
 use std::sync::Arc;
 
-use crate::{Device, Tag, resource::*};
+use crate::{Allocation, AllocatorError, Device, MemoryCategory, Tag, resource::*, barrier::*};
 
 /// The general memory 'domain' a buffer should be placed in.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
@@ -30,6 +34,30 @@ pub enum BufferUsageDomain {
     Readback,
 }
 
+/// An error that could occur while accessing the mapped memory of a `Buffer`.
+#[derive(Error, Debug)]
+pub enum BufferAccessError {
+    /// The buffer's memory is not mapped on the host.
+    #[error("the buffer is not host mapped")]
+    NotMapped,
+    /// The access extends past the end of the buffer.
+    #[error("access of {size} bytes at offset {offset} is out of bounds of a {buffer_size} byte buffer")]
+    OutOfBounds {
+        /// The offset of the access in bytes.
+        offset: vk::DeviceSize,
+        /// The size of the access in bytes.
+        size: vk::DeviceSize,
+        /// The size of the buffer in bytes.
+        buffer_size: vk::DeviceSize,
+    },
+    /// The mapped memory is not sufficiently aligned to be viewed as the requested type.
+    #[error("the mapped memory is not aligned to {0} bytes")]
+    Misaligned(usize),
+    /// Flushing the written memory failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
 /// Information needed to create a buffer.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct BufferCreateInfo {
@@ -39,6 +67,10 @@ pub struct BufferCreateInfo {
     pub size: vk::DeviceSize,
     /// Usage of the buffer.
     pub usage: vk::BufferUsageFlags,
+    /// Whether the buffer should have a device address, which shaders can access it through
+    /// (see `Buffer::device_address`). Adds `SHADER_DEVICE_ADDRESS` to the usage, and needs
+    /// `Device::supports_buffer_device_address`.
+    pub wants_device_address: bool,
 }
 
 /// An owned `vk::Buffer` and some associated information.
@@ -49,15 +81,25 @@ pub struct BufferCreateInfo {
 #[derivative(Debug)]
 pub struct Buffer {
     pub(crate) buffer: vk::Buffer,
-    pub(crate) allocation: vk_mem::Allocation,
-    pub(crate) allocation_info: vk_mem::AllocationInfo,
+    pub(crate) allocation: Option<Allocation>,
+    pub(crate) imported_memory: vk::DeviceMemory,
     pub(crate) create_info: BufferCreateInfo,
+    /// The category the allocation is tracked under in the Device's memory pools.
+    pub(crate) category: MemoryCategory,
     pub(crate) mapped_data: Option<NonNull<u8>>,
+    pub(crate) device_address: Option<vk::DeviceAddress>,
+    /// The accesses to the buffer since it was last written.
+    pub(crate) history: AccessHistory,
     pub(crate) tag: Option<Tag>,
     #[derivative(Debug = "ignore")]
     pub(crate) device: Arc<Device>,
 }
 
+// The mapped pointer points into memory owned by this Buffer's allocation (or, for imported
+// buffers, memory the creator promised to keep alive), and is only handed out through `&mut self`.
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
 impl Buffer {
     /// Create a new owned Buffer. You probably want `Device::create_buffer` instead.
     ///
@@ -67,18 +109,56 @@ impl Buffer {
     pub(crate) unsafe fn new(
         device: Arc<Device>,
         buffer: vk::Buffer,
-        allocation: vk_mem::Allocation,
-        allocation_info: vk_mem::AllocationInfo,
+        allocation: Allocation,
+        create_info: BufferCreateInfo,
+        category: MemoryCategory,
+        tag: Option<Tag>,
+    ) -> Self {
+        device.set_tag_name(buffer, tag.as_ref());
+        device.track_allocation(category, tag.as_ref(), allocation.size(), true);
+        let device_address = if create_info.wants_device_address {
+            device.raw_buffer_device_address(buffer)
+        } else {
+            None
+        };
+        Self {
+            buffer,
+            category,
+            mapped_data: allocation.mapped_data(),
+            device_address,
+            allocation: Some(allocation),
+            imported_memory: vk::DeviceMemory::null(),
+            create_info,
+            history: AccessHistory::default(),
+            tag,
+            device,
+        }
+    }
+
+    /// Create a new Buffer bound to imported `memory`, which is freed along with the buffer.
+    /// You probably want `Device::import_host_buffer` instead.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be an Arc to the `Device` that `buffer` and `memory` were created from.
+    pub(crate) unsafe fn new_imported(
+        device: Arc<Device>,
+        buffer: vk::Buffer,
+        memory: vk::DeviceMemory,
         create_info: BufferCreateInfo,
         mapped_data: Option<NonNull<u8>>,
         tag: Option<Tag>,
     ) -> Self {
+        device.set_tag_name(buffer, tag.as_ref());
         Self {
             buffer,
-            allocation,
-            allocation_info,
+            category: MemoryCategory::Buffers,
+            allocation: None,
+            imported_memory: memory,
             create_info,
             mapped_data,
+            device_address: None,
+            history: AccessHistory::default(),
             tag,
             device,
         }
@@ -89,14 +169,15 @@ impl Buffer {
         self.buffer
     }
 
-    /// The raw `vk_mem::Allocation`
-    pub fn allocation(&self) -> &vk_mem::Allocation {
-        &self.allocation
+    /// The memory allocation backing this buffer. Buffers backed by imported host memory have
+    /// no allocation.
+    pub fn allocation(&self) -> Option<&Allocation> {
+        self.allocation.as_ref()
     }
 
-    /// The `vk_mem::AllocationInfo` used to create this buffer.
-    pub fn allocation_info(&self) -> &vk_mem::AllocationInfo {
-        &self.allocation_info
+    /// Whether this buffer is backed by host memory imported with `Device::import_host_buffer`.
+    pub fn is_imported(&self) -> bool {
+        self.imported_memory != vk::DeviceMemory::null()
     }
 
     /// The BufferCreateInfo used to create this buffer.
@@ -104,25 +185,159 @@ impl Buffer {
         self.create_info
     }
 
+    /// The device address of the start of this buffer, if it was created with
+    /// `BufferCreateInfo::wants_device_address`.
+    pub fn device_address(&self) -> Option<vk::DeviceAddress> {
+        self.device_address
+    }
+
     /// A NonNull pointer to the CPU mapped data of this buffer, if
     /// it exists.
     pub fn mapped_data(&mut self) -> Option<&mut NonNull<u8>> {
         self.mapped_data.as_mut()
     }
+
+    /// Copy `data` into the mapped memory of this buffer at `offset` bytes, flushing the
+    /// written range if the memory is not host coherent.
+    pub fn write_slice<T: Pod>(&mut self, offset: vk::DeviceSize, data: &[T]) -> Result<(), BufferAccessError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let size = bytes.len() as vk::DeviceSize;
+        let mapped = self.mapped_range(offset, size)?;
+
+        unsafe { std::slice::from_raw_parts_mut(mapped.as_ptr(), bytes.len()) }.copy_from_slice(bytes);
+        self.flush(offset, size)
+    }
+
+    /// View the whole mapped memory of this buffer as a mutable slice of `T`. Any bytes at the
+    /// end which do not make up a whole `T` are not included.
+    ///
+    /// If the memory is not host coherent, it is flushed when the returned guard is dropped.
+    pub fn as_slice_mut<T: Pod>(&mut self) -> Result<MappedSliceMut<'_, T>, BufferAccessError> {
+        let len = self.create_info.size as usize / std::mem::size_of::<T>().max(1);
+        let size = (len * std::mem::size_of::<T>()) as vk::DeviceSize;
+        let mapped = self.mapped_range(0, size)?;
+
+        let bytes = unsafe { std::slice::from_raw_parts_mut(mapped.as_ptr(), size as usize) };
+        let slice = bytemuck::try_cast_slice_mut(bytes)
+            .map_err(|_| BufferAccessError::Misaligned(std::mem::align_of::<T>()))?;
+        Ok(MappedSliceMut {
+            buffer_allocation: self.allocation.as_ref(),
+            device: &self.device,
+            size,
+            slice,
+        })
+    }
+
+    /// Get the mapped pointer to `size` bytes at `offset`, checking they are within the buffer.
+    fn mapped_range(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<NonNull<u8>, BufferAccessError> {
+        let mapped = self.mapped_data.ok_or(BufferAccessError::NotMapped)?;
+        if offset.checked_add(size).map_or(true, |end| end > self.create_info.size) {
+            return Err(BufferAccessError::OutOfBounds {
+                offset,
+                size,
+                buffer_size: self.create_info.size,
+            });
+        }
+        Ok(unsafe { NonNull::new_unchecked(mapped.as_ptr().add(offset as usize)) })
+    }
+
+    /// Flush a written range of the mapped memory. Does nothing for host coherent memory.
+    fn flush(&self, offset: vk::DeviceSize, size: vk::DeviceSize) -> Result<(), BufferAccessError> {
+        flush_mapped(&self.device, self.allocation.as_ref(), offset, size)
+    }
+
+    /// Get the tracked synchronization state of this buffer.
+    pub fn state(&self) -> BufferState {
+        BufferState {
+            stages: self.history.stages(),
+            access: self.history.access(),
+        }
+    }
+
+    /// Compute the barrier needed before this buffer may be accessed in the `new` state, and
+    /// record `new` as the buffer's current state.
+    pub fn transition_to(&mut self, new: BufferState) -> Option<BufferTransition> {
+        tracked_buffer_transition(self.buffer, &mut self.history, new)
+    }
 }
 
 impl Drop for Buffer {
     fn drop(&mut self) {
-        if let Err(e) = self.device.raw_allocator().destroy_buffer(self.buffer, &self.allocation) {
-            if let Some(ref tag) = self.tag {
-                panic!("OwnedBuffer with tag {} errored on destruction: {:#?}", tag, e);
-            } else {
-                panic!("Generic (untagged) Buffer errored on destruction: {:#?}", e);
+        match self.allocation {
+            Some(ref allocation) if allocation.is_dedicated() => unsafe {
+                self.device
+                    .track_allocation(self.category, self.tag.as_ref(), allocation.size(), false);
+                self.device.raw_device().destroy_buffer(self.buffer, None);
+                self.device.raw_device().free_memory(allocation.memory(), None);
+            },
+            Some(ref allocation) => {
+                self.device
+                    .track_allocation(self.category, self.tag.as_ref(), allocation.size(), false);
+                if let Err(e) = self.device.allocator().destroy_buffer(self.buffer, allocation) {
+                    self.device.invariant_failed(
+                        self.tag.as_ref(),
+                        format!("Buffer errored on destruction: {:#?}", e),
+                    );
+                }
             }
+            // Freeing imported memory releases the import but leaves the host memory untouched.
+            None => unsafe {
+                self.device.raw_device().destroy_buffer(self.buffer, None);
+                self.device.raw_device().free_memory(self.imported_memory, None);
+            },
+        }
+    }
+}
+
+/// A mutable view of the mapped memory of a `Buffer` as a slice of `T`, returned by
+/// `Buffer::as_slice_mut`. Flushes the memory on drop if it is not host coherent.
+pub struct MappedSliceMut<'a, T: Pod> {
+    buffer_allocation: Option<&'a Allocation>,
+    device: &'a Device,
+    size: vk::DeviceSize,
+    slice: &'a mut [T],
+}
+
+impl<T: Pod> Deref for MappedSliceMut<'_, T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<T: Pod> DerefMut for MappedSliceMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.slice
+    }
+}
+
+impl<T: Pod> Drop for MappedSliceMut<'_, T> {
+    fn drop(&mut self) {
+        if let Err(e) = flush_mapped(self.device, self.buffer_allocation, 0, self.size) {
+            self.device
+                .invariant_failed(None, format!("MappedSliceMut failed to flush on drop: {}", e));
         }
     }
 }
 
+/// Flush `size` bytes at `offset` of a buffer's allocation. Allocations in host coherent
+/// memory are ignored by the allocator, dedicated allocations are always coherent, and imported
+/// buffers have no allocation and are placed in coherent memory where possible.
+fn flush_mapped(
+    device: &Device,
+    allocation: Option<&Allocation>,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+) -> Result<(), BufferAccessError> {
+    if let Some(allocation) = allocation.filter(|allocation| !allocation.is_dedicated()) {
+        device
+            .allocator()
+            .flush_allocation(allocation, offset as usize, size as usize)?;
+    }
+    Ok(())
+}
+
 /// Information needed to create a BufferView
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct BufferViewCreateInfo {
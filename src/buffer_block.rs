@@ -1,5 +1,7 @@
 use ash::vk;
 
+use bytemuck::Pod;
+
 use generational_arena as ga;
 
 use derivative::Derivative;
@@ -8,88 +10,188 @@ use thiserror::Error;
 
 use crate::*;
 
-use std::sync::atomic::AtomicUsize;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 static BUFFER_BLOCK_POOL_UUID: AtomicUsize = AtomicUsize::new(0);
 
-/// A handle to a GPU Buffer allocated from a linear BufferBlock
+/// A handle to a range of a BufferBlock's buffer, allocated linearly from the block.
+///
+/// Handles are plain data, so they can be copied into draw lists and the like. A handle is only
+/// valid until the frame its block was requested for has completed and the block is recycled.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct TransientBufferHandle {
-    block: BufferBlockHandle,
-    gpu_idx: ga::Index,
-    cpu_idx: Option<ga::Index>,
+    pub(crate) block: BufferBlockHandle,
+    pub(crate) buffer: vk::Buffer,
+    pub(crate) offset: vk::DeviceSize,
+    pub(crate) size: vk::DeviceSize,
+}
+
+impl TransientBufferHandle {
+    /// The block the range was allocated from.
+    pub fn block(&self) -> BufferBlockHandle {
+        self.block
+    }
+
+    /// The raw GPU-side `vk::Buffer` the range is part of.
+    pub fn raw(&self) -> vk::Buffer {
+        self.buffer
+    }
+
+    /// The offset of the range within the buffer, in bytes.
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    /// The size of the range, in bytes.
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+}
+
+/// Types which may be used as the indices of an indexed draw, along with the `vk::IndexType`
+/// they are bound with.
+pub trait IndexElement: Pod {
+    /// The index type of indices of this type.
+    const INDEX_TYPE: vk::IndexType;
+}
+
+impl IndexElement for u16 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT16;
+}
+
+impl IndexElement for u32 {
+    const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT32;
+}
+
+/// Get the size in bytes of an index of `index_type`.
+pub(crate) fn index_size(index_type: vk::IndexType) -> vk::DeviceSize {
+    match index_type {
+        vk::IndexType::UINT16 => 2,
+        vk::IndexType::UINT32 => 4,
+        _ => panic!("unsupported index type {:?}", index_type),
+    }
+}
+
+/// A range of an index block's buffer written with `BufferBlock::allocate_indices`, along with
+/// the type of the indices in it, so that `CommandBuffer::bind_index_block` binds it with the
+/// right `vk::IndexType`. Only valid for as long as the `TransientBufferHandle` it wraps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct IndexBlockAllocation {
+    pub(crate) buffer: TransientBufferHandle,
+    pub(crate) index_type: vk::IndexType,
 }
 
-/// A block of Buffers which are linearly allocated and intended to be basically disposable
+impl IndexBlockAllocation {
+    /// The range the indices were written to.
+    pub fn buffer(&self) -> TransientBufferHandle {
+        self.buffer
+    }
+
+    /// The type of the indices.
+    pub fn index_type(&self) -> vk::IndexType {
+        self.index_type
+    }
+
+    /// The number of indices.
+    pub fn count(&self) -> u32 {
+        (self.buffer.size / index_size(self.index_type)) as u32
+    }
+}
+
+/// A range of a BufferBlock's buffer returned by `BufferBlock::allocate`.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferBlockAllocation {
+    /// The GPU-side buffer the range is part of.
+    pub buffer: vk::Buffer,
+    /// The offset of the range within `buffer`, in bytes.
+    pub offset: vk::DeviceSize,
+    /// The size of the range, in bytes.
+    pub size: vk::DeviceSize,
+    /// A pointer to the CPU-visible memory the range's data should be written to, if the block is mapped.
+    pub mapped_ptr: Option<NonNull<u8>>,
+    /// The device address of the range, if the block's pool gives its buffers device addresses.
+    pub device_address: Option<vk::DeviceAddress>,
+}
+
+/// A block of Buffer memory which is linearly allocated and intended to be basically disposable
 /// and used for only one frame before being recycled. It is meant to provide ease of use for such operations,
 /// and so supports CPU side upload as a first class concern.
 ///
+/// Each block owns a single GPU-side `Buffer` (and a CPU-side staging `Buffer` if the GPU-side one
+/// cannot be mapped) which allocations are carved out of by bumping an atomic offset, so allocating
+/// only needs a shared reference to the block and many threads may allocate from it at once.
+///
 /// Generally you will not need to create your own BufferBlock but will rather want use the
 /// `CommandBuffer::allocate_<kind>_data` methods.
 #[derive(Derivative)]
 #[derivative(Debug)]
 pub struct BufferBlock {
     pub(crate) self_id: Option<BufferBlockHandle>,
-    pub(crate) gpu: vk_mem::AllocatorPool,
-    pub(crate) cpu: Option<vk_mem::AllocatorPool>,
-    pub(crate) allocated_buffers: ga::Arena<Buffer>,
+    pub(crate) gpu: Buffer,
+    pub(crate) cpu: Option<Buffer>,
+    pub(crate) offset: AtomicUsize,
+    /// The number of bytes from the start of the block which have been uploaded from `cpu` to
+    /// `gpu` so far.
+    pub(crate) uploaded: usize,
+    pub(crate) alignment: usize,
     pub(crate) usage: vk::BufferUsageFlags,
     pub(crate) domain: BufferUsageDomain,
     pub(crate) size: usize,
     pub(crate) tag: Option<Tag>,
-    #[derivative(Debug = "ignore")]
-    pub(crate) device: Arc<Device>,
-}
-
-impl Drop for BufferBlock {
-    fn drop(&mut self) {
-        self.reset();
-        if let Err(e) = self.device.raw_allocator().destroy_pool(&self.gpu) {
-            if let Some(ref tag) = self.tag {
-                panic!("BufferBlock with tag {} errored on destruction: {:#?}", tag, e);
-            } else {
-                panic!("Generic (untagged) BufferBlock errored on destruction: {:#?}", e);
-            }
-        }
-        if let Some(ref cpu_pool) = self.cpu {
-            if let Err(e) = self.device.raw_allocator().destroy_pool(cpu_pool) {
-                if let Some(ref tag) = self.tag {
-                    panic!("BufferBlock with tag {} errored on destruction: {:#?}", tag, e);
-                } else {
-                    panic!("Generic (untagged) BufferBlock errored on destruction: {:#?}", e);
-                }
-            }
-        }
-    }
 }
 
 impl BufferBlock {
-    /// Create a new OwnedBufferBlock.
+    /// Create a new BufferBlock.
     ///
-    /// # Safety
+    /// # Parameters
     ///
-    /// `device` must be the Device used to allocate the associated AllocatorPools.
-    pub unsafe fn new(
-        device: Arc<Device>,
+    /// * `gpu`: The buffer which allocations are made from. Must be at least `size` bytes.
+    /// * `cpu`: If `gpu` is not host visible, a host visible buffer of the same size which data is
+    ///   written to and then uploaded to `gpu`.
+    /// * `alignment`: The alignment of every allocation made from the block. Must be a power of two.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         self_id: Option<BufferBlockHandle>,
-        gpu: vk_mem::AllocatorPool,
-        cpu: Option<vk_mem::AllocatorPool>,
-        allocated_buffers: ga::Arena<Buffer>,
+        gpu: Buffer,
+        cpu: Option<Buffer>,
+        alignment: usize,
         usage: vk::BufferUsageFlags,
         domain: BufferUsageDomain,
         size: usize,
         tag: Option<Tag>
     ) -> Self {
+        assert!(alignment.is_power_of_two(), "BufferBlock alignment must be a power of two");
+
         Self {
             self_id,
             gpu,
             cpu,
-            allocated_buffers,
+            offset: AtomicUsize::new(0),
+            uploaded: 0,
+            alignment,
             usage,
             domain,
             size,
             tag,
-            device,
+        }
+    }
+
+    /// Get whether `buffer` was allocated from this block.
+    ///
+    /// A block which is not currently owned by a pool (i.e. it has been recycled) owns no buffers,
+    /// and using it this way is reported as an invariant failure.
+    fn owns(&self, buffer: &TransientBufferHandle) -> bool {
+        match self.self_id {
+            Some(self_id) => buffer.block == self_id,
+            None => {
+                self.gpu.device.invariant_failed(
+                    self.tag.as_ref(),
+                    "BufferBlock was used while not owned by any pool",
+                );
+                false
+            }
         }
     }
 
@@ -98,139 +200,206 @@ impl BufferBlock {
         self.cpu.is_some()
     }
 
-    /// Get a shared reference to the GPU-side buffer referenced by a `TransientBufferHandle` created from this `BufferBlock`.
-    pub fn get_gpu_buffer(&self, buffer: TransientBufferHandle) -> Option<&Buffer> {
-        if buffer.block == self.self_id.unwrap() {
-            return self.allocated_buffers.get(buffer.gpu_idx);
+    /// Get the number of bytes of the block which have been allocated so far, including padding.
+    pub fn used(&self) -> usize {
+        self.offset.load(Ordering::Relaxed).min(self.size)
+    }
+
+    /// Get a shared reference to the GPU-side buffer which a `TransientBufferHandle` created from this `BufferBlock`
+    /// is a range of.
+    pub fn get_gpu_buffer(&self, buffer: &TransientBufferHandle) -> Option<&Buffer> {
+        if self.owns(buffer) {
+            return Some(&self.gpu);
         }
         
         None
     }
 
-    /// Get a mutable reference to the GPU-side buffer referenced by a `TransientBufferHandle` created from this `BufferBlock`.
-    pub fn get_gpu_buffer_mut(&mut self, buffer: TransientBufferHandle) -> Option<&mut Buffer> {
-        if buffer.block == self.self_id.unwrap() {
-            return self.allocated_buffers.get_mut(buffer.gpu_idx);
+    /// Get a mutable reference to the GPU-side buffer which a `TransientBufferHandle` created from this `BufferBlock`
+    /// is a range of.
+    pub fn get_gpu_buffer_mut(&mut self, buffer: &TransientBufferHandle) -> Option<&mut Buffer> {
+        if self.owns(buffer) {
+            return Some(&mut self.gpu);
         }
         
         None
     }
 
-    /// Get a shared reference to the CPU-side buffer referenced by a `TransientBufferHandle` created from this `BufferBlock`,
-    /// if there is one.
-    pub fn get_cpu_buffer(&self, buffer: TransientBufferHandle) -> Option<&Buffer> {
-        if buffer.block == self.self_id.unwrap() {
-            if let Some(cpu_idx) = buffer.cpu_idx {
-                return self.allocated_buffers.get(cpu_idx);
-            }
+    /// Get a shared reference to the CPU-side buffer which a `TransientBufferHandle` created from this `BufferBlock`
+    /// is a range of, if there is one.
+    pub fn get_cpu_buffer(&self, buffer: &TransientBufferHandle) -> Option<&Buffer> {
+        if self.owns(buffer) {
+            return self.cpu.as_ref();
         }
         
         None
     }
 
-    /// Get a mutable reference to the CPU-side buffer referenced by a `TransientBufferHandle` created from this `BufferBlock`,
-    /// if there is one.
-    pub fn get_cpu_buffer_mut(&mut self, buffer: TransientBufferHandle) -> Option<&mut Buffer> {
-        if buffer.block == self.self_id.unwrap() {
-            if let Some(cpu_idx) = buffer.cpu_idx {
-                return self.allocated_buffers.get_mut(cpu_idx);
-            }
+    /// Get a mutable reference to the CPU-side buffer which a `TransientBufferHandle` created from this `BufferBlock`
+    /// is a range of, if there is one.
+    pub fn get_cpu_buffer_mut(&mut self, buffer: &TransientBufferHandle) -> Option<&mut Buffer> {
+        if self.owns(buffer) {
+            return self.cpu.as_mut();
         }
         
         None
     }
 
-    /// Allocate a buffer from the block. The buffer is allocated in a linear fashion, making allocation very fast.
-    pub fn allocate_buffer(
-        &mut self,
-        device: Arc<Device>,
-        size: usize,
-        tag: Option<Tag>,
-    ) -> Result<TransientBufferHandle, vk_mem::Error> {
-        let create_info = 
-            BufferCreateInfo {
-                size: size as _,
-                usage: self.usage | vk::BufferUsageFlags::TRANSFER_DST,
-                domain: self.domain,
-            };
+    /// Get a pointer to the CPU-visible memory backing `buffer`, which data for it should be written to.
+    ///
+    /// Ranges allocated from a block never overlap, so the memory behind each handle may be written
+    /// from a different thread.
+    pub fn mapped_data(&self, buffer: &TransientBufferHandle) -> Option<NonNull<u8>> {
+        if !self.owns(buffer) {
+            return None;
+        }
 
-        let mut queue_family_indices = [0u32; 3];
-        let buffer_info = device.raw_buffer_create_info(create_info, &mut queue_family_indices);
+        let mapped = self.cpu.as_ref().unwrap_or(&self.gpu).mapped_data?;
+        NonNull::new(unsafe { mapped.as_ptr().add(buffer.offset as usize) })
+    }
 
-        let alloc_info = vk_mem::AllocationCreateInfo {
-            flags: vk_mem::AllocationCreateFlags::MAPPED,
-            pool: Some(self.gpu.clone()),
-            ..Default::default()
-        };
+    /// Get the device address of the range of the GPU-side buffer referred to by `buffer`, if
+    /// the block's pool gives its buffers device addresses (see
+    /// `DeviceBuilder::block_device_addresses`).
+    pub fn device_address(&self, buffer: &TransientBufferHandle) -> Option<vk::DeviceAddress> {
+        if !self.owns(buffer) {
+            return None;
+        }
 
-        let (buffer, allocation, allocation_info) =
-            device.raw_allocator().create_buffer(&buffer_info, &alloc_info)?;
+        Some(self.gpu.device_address()? + buffer.offset)
+    }
 
-        let mapped_data = std::ptr::NonNull::new(allocation_info.get_mapped_data());
+    /// Allocate a range of `size` bytes from the block. Allocation simply bumps an atomic offset, making it very fast
+    /// and lock-free, so it only needs a shared reference to the block.
+    pub fn allocate_buffer(&self, size: usize) -> Result<TransientBufferHandle, BlockAllocationError> {
+        self.bump(size, self.alignment)
+    }
 
-        let gpu_idx = self
-            .allocated_buffers
-            .insert(unsafe { Buffer::new(
-                device.clone(),
-                buffer,
-                allocation,
-                allocation_info,
-                create_info,
-                mapped_data,
-                tag.clone(),
-            ) });
+    /// Allocate a range of `size` bytes from the block with an offset aligned to at least `align` bytes (as well as
+    /// the block's own alignment), returning the buffer, offset and mapped pointer needed to bind and write it,
+    /// e.g. with `vkCmdBindVertexBuffers`. `align` must be a power of two.
+    ///
+    /// If the block `requires_upload`, `mapped_ptr` points into the CPU-side buffer at the same offset, and the data
+    /// must be uploaded to `buffer` before use.
+    pub fn allocate(&self, size: usize, align: usize) -> Result<BufferBlockAllocation, BlockAllocationError> {
+        assert!(align.is_power_of_two(), "BufferBlock allocation alignment must be a power of two");
+
+        let handle = self.bump(size, align.max(self.alignment))?;
+        Ok(BufferBlockAllocation {
+            buffer: self.gpu.raw(),
+            offset: handle.offset,
+            size: handle.size,
+            mapped_ptr: self.mapped_data(&handle),
+            device_address: self.device_address(&handle),
+        })
+    }
 
-        
-        let cpu_idx = if self.cpu.is_some() {
-            let create_info = 
-                BufferCreateInfo {
-                    size: size as _,
-                    usage: vk::BufferUsageFlags::TRANSFER_SRC,
-                    domain: BufferUsageDomain::Host,
-                };
-            let buffer_info = device.raw_buffer_create_info(create_info, &mut queue_family_indices);
+    /// Allocate a range from the block and write `indices` to it, remembering their type. The
+    /// block should have been requested with `Device::request_index_block`.
+    ///
+    /// If the block `requires_upload`, the indices are uploaded along with the rest of the block.
+    pub fn allocate_indices<I: IndexElement>(
+        &self,
+        indices: &[I],
+    ) -> Result<IndexBlockAllocation, BlockAllocationError> {
+        let size = std::mem::size_of_val(indices);
+        let buffer = self.bump(size, std::mem::align_of::<I>().max(self.alignment))?;
+
+        let ptr = self
+            .mapped_data(&buffer)
+            .expect("BufferBlock is not mapped")
+            .as_ptr() as *mut I;
+        unsafe { ptr.copy_from_nonoverlapping(indices.as_ptr(), indices.len()) };
+
+        Ok(IndexBlockAllocation {
+            buffer,
+            index_type: I::INDEX_TYPE,
+        })
+    }
 
-            let alloc_info = vk_mem::AllocationCreateInfo {
-                flags: vk_mem::AllocationCreateFlags::MAPPED,
-                pool: self.cpu.clone(),
-                ..Default::default()
-            };
+    /// Allocate a range from the block and write `vertices` to it, to be bound with
+    /// `CommandBuffer::bind_vertex_block` for a pipeline using `V::layout()`. The block should
+    /// have been requested with `Device::request_vertex_block`.
+    ///
+    /// If the block `requires_upload`, the vertices are uploaded along with the rest of the block.
+    pub fn allocate_vertices<V: Vertex>(
+        &self,
+        vertices: &[V],
+    ) -> Result<TransientBufferHandle, BlockAllocationError> {
+        let size = std::mem::size_of_val(vertices);
+        let buffer = self.bump(size, std::mem::align_of::<V>().max(self.alignment))?;
+
+        let ptr = self
+            .mapped_data(&buffer)
+            .expect("BufferBlock is not mapped")
+            .as_ptr() as *mut V;
+        unsafe { ptr.copy_from_nonoverlapping(vertices.as_ptr(), vertices.len()) };
+
+        Ok(buffer)
+    }
 
-            let (buffer, allocation, allocation_info) =
-                device.raw_allocator().create_buffer(&buffer_info, &alloc_info)?;
-
-            let mapped_data = std::ptr::NonNull::new(allocation_info.get_mapped_data());
-
-            Some(self
-                .allocated_buffers
-                .insert(unsafe { Buffer::new(
-                    device.clone(),
-                    buffer,
-                    allocation,
-                    allocation_info,
-                    create_info,
-                    mapped_data,
-                    tag.clone()
-                ) }))
-            
-        } else {
-            None
+    /// Bump the block's offset to allocate `size` bytes at an `alignment` aligned offset.
+    fn bump(&self, size: usize, alignment: usize) -> Result<TransientBufferHandle, BlockAllocationError> {
+        let self_id = match self.self_id {
+            Some(self_id) => self_id,
+            None => {
+                self.gpu.device.invariant_failed(
+                    self.tag.as_ref(),
+                    "Attempted to allocate from a BufferBlock not owned by any pool",
+                );
+                return Err(BlockAllocationError::NotOwned);
+            }
+        };
+
+        let mut current = self.offset.load(Ordering::Relaxed);
+        let offset = loop {
+            let offset = (current + alignment - 1) & !(alignment - 1);
+            let end = offset.saturating_add(size);
+
+            if end > self.size {
+                return Err(BlockAllocationError::OutOfSpace {
+                    requested: size,
+                    remaining: self.size.saturating_sub(offset),
+                });
+            }
+
+            match self.offset.compare_exchange_weak(current, end, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break offset,
+                Err(actual) => current = actual,
+            }
         };
 
         Ok(TransientBufferHandle {
-            block: self.self_id.unwrap(),
-            gpu_idx,
-            cpu_idx
+            block: self_id,
+            buffer: self.gpu.raw(),
+            offset: offset as _,
+            size: size as _,
         })
     }
 
-    /// Resets the block by destroying all buffers that were allocated from the block.
+    /// Resets the block, invalidating all ranges that were allocated from it.
     pub fn reset(&mut self) {
-        // Destroy all current buffers by dropping them.
-        for (_, _owned_buffer) in self.allocated_buffers.drain() {}
+        *self.offset.get_mut() = 0;
+        self.uploaded = 0;
     }
 }
 
+/// An error that could occur when attempting to allocate from a block.
+#[derive(Error, Debug)]
+pub enum BlockAllocationError {
+    /// The block is not owned by any pool, i.e. it has been recycled.
+    #[error("block is not owned by any pool")]
+    NotOwned,
+    /// The block does not have enough space left for the allocation.
+    #[error("block has {remaining} bytes remaining but {requested} were requested")]
+    OutOfSpace {
+        /// The size of the allocation.
+        requested: usize,
+        /// The number of bytes left in the block.
+        remaining: usize,
+    },
+}
+
 /// An untyped handle to a BufferBlock.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BufferBlockHandle {
@@ -238,6 +407,10 @@ pub struct BufferBlockHandle {
     idx: ga::Index,
 }
 
+/// The number of frames a recycled block may go unused before `Device::begin_frame` frees it,
+/// unless changed with `DeviceBuilder::block_trim_frames`.
+pub const DEFAULT_BLOCK_TRIM_FRAMES: usize = 300;
+
 /// A pool of BufferBlocks with the same `vk::BufferUsageFlags`.
 ///
 /// Blocks will attempt to be recycled and reused according to the description in `new`.
@@ -246,14 +419,31 @@ pub struct BufferBlockPool {
     uuid: usize,
 
     owned_blocks: ga::Arena<BufferBlock>,
-    recycled_blocks: Vec<BufferBlock>,
+    /// Blocks ready to be reused, along with the frame they were recycled during. The most
+    /// recently recycled blocks are reused first, so the oldest are at the front.
+    recycled_blocks: Vec<(BufferBlock, u64)>,
+    /// The number of frames begun since the pool was created, used to age recycled blocks.
+    frame: u64,
+
+    /// The number of blocks of the default size currently owned, i.e. in use.
+    blocks_in_use: usize,
+    /// The highest `blocks_in_use` has been.
+    peak_blocks_in_use: usize,
+    /// The number of blocks of the default size which have been allocated.
+    allocated_blocks: usize,
+    /// The total size of the blocks currently allocated, in use or recycled, of any size.
+    allocated_bytes: usize,
+    /// The highest `allocated_bytes` has been.
+    peak_bytes: usize,
 
     gpu_memory_type_index: u32,
     cpu_memory_type_index: Option<u32>,
     device_local: bool,
     block_size: usize,
+    alignment: usize,
     domain: BufferUsageDomain,
     usage: vk::BufferUsageFlags,
+    wants_device_address: bool,
 }
 
 impl BufferBlockPool {
@@ -262,21 +452,23 @@ impl BufferBlockPool {
     /// # Parameters
     ///
     /// * `block_size`: The size that each block in the pool should be allocated as. When blocks are requested from the pool,
-    /// if they are requested as less than this size, they will be allocated as this size and are then able to be returned
-    /// to the pool and recycled without actually allocating more memory on the device. If a block is requested with size larger
-    /// than the pool's `block_size`, then a block will still be allocated, but it will need to be simply deallocated and not
-    /// re-used.
+    ///   if they are requested as less than this size, they will be allocated as this size and are then able to be returned
+    ///   to the pool and recycled without actually allocating more memory on the device. If a block is requested with size larger
+    ///   than the pool's `block_size`, then a block will still be allocated, but it will need to be simply deallocated and not
+    ///   re-used.
     /// * `usage`: The `vk::BufferUsageFlags` that all blocks (and all and all buffers allocated from those blocks) created from
-    /// this pool will have.
+    ///   this pool will have.
     /// * `requires_device_local_memory`: Whether this pool requires its memory to be on the GPU. If so, staging buffers may need
-    /// to be used in order to copy data into the final GPU-side buffer.
+    ///   to be used in order to copy data into the final GPU-side buffer.
+    /// * `wants_device_address`: Whether the GPU-side buffers of the blocks have device addresses.
     pub(crate) fn new(
         device: Arc<Device>,
         block_size: usize,
         usage: vk::BufferUsageFlags,
         requires_device_local_memory: bool,
-    ) -> Result<Self, vk_mem::Error> {
-        let uuid = BUFFER_BLOCK_POOL_UUID.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        wants_device_address: bool,
+    ) -> Result<Self, AllocatorError> {
+        let uuid = BUFFER_BLOCK_POOL_UUID.fetch_add(1, Ordering::SeqCst);
         let device_local = requires_device_local_memory;
 
         let (domain, usage) = if device_local {
@@ -292,6 +484,7 @@ impl BufferBlockPool {
             domain,
             size: block_size as _,
             usage,
+            wants_device_address,
         };
 
         let gpu_memory_type_index = device.find_memory_type_index_for_buffer_info(create_info)?;
@@ -301,6 +494,7 @@ impl BufferBlockPool {
                 domain: BufferUsageDomain::Host,
                 size: block_size as _,
                 usage: vk::BufferUsageFlags::TRANSFER_SRC,
+                wants_device_address: false,
             };
 
             Some(device.find_memory_type_index_for_buffer_info(create_info)?)
@@ -308,17 +502,34 @@ impl BufferBlockPool {
             None
         };
 
+        let limits = &device.device_properties().limits;
+        let mut alignment = 16;
+        if usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) {
+            alignment = alignment.max(limits.min_uniform_buffer_offset_alignment as usize);
+        }
+        if usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+            alignment = alignment.max(limits.min_storage_buffer_offset_alignment as usize);
+        }
+
         Ok(Self {
+            alignment,
             device,
             uuid,
             owned_blocks: ga::Arena::new(),
             recycled_blocks: Vec::new(),
+            frame: 0,
+            blocks_in_use: 0,
+            peak_blocks_in_use: 0,
+            allocated_blocks: 0,
+            allocated_bytes: 0,
+            peak_bytes: 0,
             device_local,
             gpu_memory_type_index,
             cpu_memory_type_index,
             block_size,
             domain,
             usage,
+            wants_device_address,
         })
     }
 
@@ -350,9 +561,10 @@ impl BufferBlockPool {
         &mut self,
         min_size: usize,
         tag: Option<Tag>,
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.device.check_deterministic_thread("BufferBlockPool::request_block");
         if min_size <= self.block_size {
-            if let Some(block) = self.recycled_blocks.pop() {
+            if let Some((block, _)) = self.recycled_blocks.pop() {
                 let block_idx = self.owned_blocks.insert(block);
 
                 let block = BufferBlockHandle {
@@ -361,6 +573,7 @@ impl BufferBlockPool {
                 };
 
                 self.owned_blocks.get_mut(block_idx).unwrap().self_id = Some(block);
+                self.track_block_in_use();
 
                 return Ok(block);
             }
@@ -378,43 +591,51 @@ impl BufferBlockPool {
         &mut self,
         min_size: usize,
         tag: Option<Tag>,
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
+    ) -> Result<BufferBlockHandle, AllocatorError> {
         let block_size = if min_size <= self.block_size {
             self.block_size
         } else {
             min_size
         };
 
-        let mut pool_info = vk_mem::AllocatorPoolCreateInfo {
-            memory_type_index: self.gpu_memory_type_index,
-            flags: vk_mem::AllocatorPoolCreateFlags::LINEAR_ALGORITHM,
-            block_size,
-            min_block_count: 1,
-            max_block_count: 1,
-            ..Default::default()
-        };
-
-        let gpu = self.device.raw_allocator().create_pool(&pool_info)?;
+        let gpu = self.create_block_buffer(
+            BufferCreateInfo {
+                size: block_size as _,
+                usage: self.usage,
+                domain: self.domain,
+                wants_device_address: self.wants_device_address,
+            },
+            self.gpu_memory_type_index,
+            self.cpu_memory_type_index.is_some(),
+            tag.clone(),
+        )?;
 
         let cpu = if let Some(cpu_memory_type_index) = self.cpu_memory_type_index {
-            pool_info.memory_type_index = cpu_memory_type_index;
-
-            Some(self.device.raw_allocator().create_pool(&pool_info)?)
+            Some(self.create_block_buffer(
+                BufferCreateInfo {
+                    size: block_size as _,
+                    usage: vk::BufferUsageFlags::TRANSFER_SRC,
+                    domain: BufferUsageDomain::Host,
+                    wants_device_address: false,
+                },
+                cpu_memory_type_index,
+                false,
+                tag.clone(),
+            )?)
         } else {
             None
         };
 
-        let block_idx = self.owned_blocks.insert(unsafe { BufferBlock::new(
-            self.device.clone(),
+        let block_idx = self.owned_blocks.insert(BufferBlock::new(
             None,
             gpu,
             cpu,
-            ga::Arena::new(),
+            self.alignment,
             self.usage,
             self.domain,
             block_size,
             tag,
-        ) });
+        ));
 
         let block = BufferBlockHandle {
             pool_uuid: self.uuid,
@@ -422,10 +643,125 @@ impl BufferBlockPool {
         };
 
         self.owned_blocks.get_mut(block_idx).unwrap().self_id = Some(block);
+        self.allocated_bytes += block_size;
+        self.peak_bytes = self.peak_bytes.max(self.allocated_bytes);
+        if block_size == self.block_size {
+            self.allocated_blocks += 1;
+            self.track_block_in_use();
+        }
 
         Ok(block)
     }
 
+    /// Allocate blocks of the default size up front until at least `count` recycled blocks are
+    /// ready to be reused, so that requesting that many blocks later on won't need to allocate.
+    ///
+    /// Useful during load screens, with a count taken from `BlockPoolStats::recommended_prewarm_count`
+    /// of a previous run. Like any recycled block, those which aren't reused are eventually freed
+    /// by `trim`.
+    pub fn prewarm(&mut self, count: usize, tag: Option<Tag>) -> Result<(), AllocatorError> {
+        while self.recycled_blocks.len() < count {
+            let block = self.allocate_block(self.block_size, tag.clone())?;
+            let mut owned_block = self.owned_blocks.remove(block.idx).unwrap();
+            owned_block.self_id = None;
+            self.blocks_in_use -= 1;
+            self.recycled_blocks.push((owned_block, self.frame));
+        }
+
+        Ok(())
+    }
+
+    /// Free the recycled blocks which have not been reused during the last `keep_frames`
+    /// frames, returning how many were freed.
+    ///
+    /// `Device::begin_frame` does this automatically with the count set by
+    /// `DeviceBuilder::block_trim_frames`, so that the pool shrinks back down after a spike in
+    /// usage.
+    pub fn trim(&mut self, keep_frames: usize) -> usize {
+        let frame = self.frame;
+        let stale = self
+            .recycled_blocks
+            .iter()
+            .take_while(|&&(_, recycled)| frame - recycled > keep_frames as u64)
+            .count();
+        // Blocks are only recycled once the GPU is done with them, so can be dropped right away.
+        for (block, _) in self.recycled_blocks.drain(..stale) {
+            self.allocated_bytes -= block.size;
+        }
+        stale
+    }
+
+    /// Count the start of a new frame, which ages the recycled blocks.
+    pub(crate) fn advance_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    /// Get statistics about the blocks of the pool.
+    pub fn stats(&self) -> BlockPoolStats {
+        BlockPoolStats {
+            block_size: self.block_size,
+            blocks_in_use: self.blocks_in_use,
+            recycled_blocks: self.recycled_blocks.len(),
+            peak_blocks_in_use: self.peak_blocks_in_use,
+            allocated_blocks: self.allocated_blocks,
+            allocated_bytes: self.allocated_bytes,
+            recycled_bytes: self.recycled_blocks.len() * self.block_size,
+            peak_bytes: self.peak_bytes,
+        }
+    }
+
+    /// Get the buffers of every block of the pool, both in use and recycled.
+    pub(crate) fn buffers(&self) -> impl Iterator<Item = &Buffer> {
+        self.owned_blocks
+            .iter()
+            .map(|(_, block)| block)
+            .chain(self.recycled_blocks.iter().map(|(block, _)| block))
+            .flat_map(|block| std::iter::once(&block.gpu).chain(block.cpu.as_ref()))
+    }
+
+    /// Count a block of the default size as having been taken into use.
+    fn track_block_in_use(&mut self) {
+        self.blocks_in_use += 1;
+        self.peak_blocks_in_use = self.peak_blocks_in_use.max(self.blocks_in_use);
+    }
+
+    /// Create the buffer backing a block, in a specific memory type.
+    ///
+    /// Buffers which are uploaded to are `graphics_exclusive`: they are written on the transfer
+    /// queue and then only used by the graphics queue family, which the upload transfers them to.
+    fn create_block_buffer(
+        &self,
+        create_info: BufferCreateInfo,
+        memory_type_index: u32,
+        graphics_exclusive: bool,
+        tag: Option<Tag>,
+    ) -> Result<Buffer, AllocatorError> {
+        let mut queue_family_indices = [0u32; 3];
+        let mut buffer_info = self.device.raw_buffer_create_info(create_info, &mut queue_family_indices);
+        if graphics_exclusive {
+            buffer_info = buffer_info
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .queue_family_indices(&[]);
+        }
+
+        let alloc_info = AllocationDesc {
+            mapped: true,
+            memory_type_bits: 1 << memory_type_index,
+            ..Default::default()
+        };
+
+        let (buffer, allocation) = self.device.create_raw_buffer(create_info, &buffer_info, &alloc_info)?;
+
+        Ok(unsafe { Buffer::new(
+            self.device.clone(),
+            buffer,
+            allocation,
+            create_info,
+            MemoryCategory::BufferBlocks,
+            tag,
+        ) })
+    }
+
     /// Attempt to recycle a block. 
     ///
     /// `block` must have been allocated from this pool, and must
@@ -448,10 +784,87 @@ impl BufferBlockPool {
         let mut owned_block = self.owned_blocks.remove(block.idx).unwrap();
         owned_block.reset();
         owned_block.self_id = None;
-        self.recycled_blocks.push(owned_block);
+        self.blocks_in_use -= 1;
+        self.recycled_blocks.push((owned_block, self.frame));
 
         Ok(())
     }
+
+    /// Destroy a block, freeing its memory.
+    ///
+    /// `block` must have been allocated from this pool and not already recycled or destroyed.
+    pub fn destroy_block(&mut self, block: BufferBlockHandle) -> Result<(), BlockRecycleError> {
+        if block.pool_uuid != self.uuid {
+            return Err(BlockRecycleError::WrongPool);
+        }
+
+        match self.owned_blocks.remove(block.idx) {
+            Some(owned_block) => {
+                self.allocated_bytes -= owned_block.size;
+                if owned_block.size == self.block_size {
+                    self.blocks_in_use -= 1;
+                }
+                Ok(())
+            }
+            None => Err(BlockRecycleError::AlreadyFreed),
+        }
+    }
+
+    /// Recycle a block if possible, or destroy it if it cannot be recycled because it is not
+    /// the pool's default size.
+    pub fn release_block(&mut self, block: BufferBlockHandle) -> Result<(), BlockRecycleError> {
+        match self.recycle_block(block) {
+            Err(BlockRecycleError::WrongSize) => self.destroy_block(block),
+            result => result,
+        }
+    }
+}
+
+/// The kind of blocks a pool of the Device's `BufferBlockSet` hands out.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PoolKind {
+    /// Blocks requested with `Device::request_vertex_block`.
+    Vertex,
+    /// Blocks requested with `Device::request_index_block`.
+    Index,
+    /// Blocks requested with `Device::request_uniform_block`.
+    Uniform,
+    /// Blocks requested with `Device::request_indirect_block`.
+    Indirect,
+    /// Blocks requested with `Device::request_staging_block`.
+    Staging,
+}
+
+/// Statistics about the blocks of a `BufferBlockPool`. Only blocks of the pool's default size
+/// are counted, since larger ones are never recycled.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BlockPoolStats {
+    /// The default size of the pool's blocks.
+    pub block_size: usize,
+    /// The number of blocks currently in use.
+    pub blocks_in_use: usize,
+    /// The number of recycled blocks ready to be reused.
+    pub recycled_blocks: usize,
+    /// The highest number of blocks which have been in use at once.
+    pub peak_blocks_in_use: usize,
+    /// The number of blocks which have been allocated since the pool was created, including by
+    /// `BufferBlockPool::prewarm`.
+    pub allocated_blocks: usize,
+    /// The total size of the blocks currently allocated, in use or recycled. Unlike the counts
+    /// of blocks, this includes blocks larger than the default size.
+    pub allocated_bytes: usize,
+    /// The total size of the recycled blocks, which `BufferBlockPool::trim` can free.
+    pub recycled_bytes: usize,
+    /// The highest `allocated_bytes` has been.
+    pub peak_bytes: usize,
+}
+
+impl BlockPoolStats {
+    /// The number of blocks to prewarm the pool with in later runs so that none need to be
+    /// allocated while rendering, assuming they use as many blocks at once as this one.
+    pub fn recommended_prewarm_count(&self) -> usize {
+        self.peak_blocks_in_use
+    }
 }
 
 /// An error that could occur when attempting to recycle a block.
@@ -0,0 +1,234 @@
+//! Introspection of the Device's caches, for finding state explosion: many pipelines differing
+//! only in a little state, many identical descriptor set layouts, or caches which keep missing
+//! frame after frame.
+
+use ash::vk;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::*;
+
+/// The hits and misses of one cache.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheCounts {
+    /// Requests which found an existing object.
+    pub hits: u64,
+    /// Requests which created a new object.
+    pub misses: u64,
+}
+
+impl CacheCounts {
+    /// The fraction of requests which were hits, or 1 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 1.0,
+            requests => self.hits as f64 / requests as f64,
+        }
+    }
+}
+
+/// The hits and misses of the Device's caches during one frame, returned by
+/// `Device::cache_stats`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    /// Requests of `Device::request_graphics_pipeline`.
+    pub pipelines: CacheCounts,
+    /// Requests of `Device::request_render_pass`.
+    pub render_passes: CacheCounts,
+    /// Requests of `Device::request_framebuffer`.
+    pub framebuffers: CacheCounts,
+    /// Requests of `Device::request_sampler`.
+    pub samplers: CacheCounts,
+}
+
+/// The counts of the cache requests of the frame being recorded.
+#[derive(Debug, Default)]
+pub(crate) struct CacheCounters {
+    pipeline_hits: AtomicU64,
+    pipeline_misses: AtomicU64,
+    render_pass_hits: AtomicU64,
+    render_pass_misses: AtomicU64,
+    framebuffer_hits: AtomicU64,
+    framebuffer_misses: AtomicU64,
+    sampler_hits: AtomicU64,
+    sampler_misses: AtomicU64,
+}
+
+/// One of the caches counted by `CacheCounters`.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CacheKind {
+    Pipeline,
+    RenderPass,
+    Framebuffer,
+    Sampler,
+}
+
+impl CacheCounters {
+    /// Count a request of the `kind` cache.
+    pub(crate) fn count(&self, kind: CacheKind, hit: bool) {
+        let counter = match (kind, hit) {
+            (CacheKind::Pipeline, true) => &self.pipeline_hits,
+            (CacheKind::Pipeline, false) => &self.pipeline_misses,
+            (CacheKind::RenderPass, true) => &self.render_pass_hits,
+            (CacheKind::RenderPass, false) => &self.render_pass_misses,
+            (CacheKind::Framebuffer, true) => &self.framebuffer_hits,
+            (CacheKind::Framebuffer, false) => &self.framebuffer_misses,
+            (CacheKind::Sampler, true) => &self.sampler_hits,
+            (CacheKind::Sampler, false) => &self.sampler_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Take the counts, starting again from zero.
+    fn take(&self) -> CacheStats {
+        let take = |counter: &AtomicU64| counter.swap(0, Ordering::Relaxed);
+        CacheStats {
+            pipelines: CacheCounts {
+                hits: take(&self.pipeline_hits),
+                misses: take(&self.pipeline_misses),
+            },
+            render_passes: CacheCounts {
+                hits: take(&self.render_pass_hits),
+                misses: take(&self.render_pass_misses),
+            },
+            framebuffers: CacheCounts {
+                hits: take(&self.framebuffer_hits),
+                misses: take(&self.framebuffer_misses),
+            },
+            samplers: CacheCounts {
+                hits: take(&self.sampler_hits),
+                misses: take(&self.sampler_misses),
+            },
+        }
+    }
+}
+
+/// A binding of a descriptor set layout, comparable and hashable unlike
+/// `vk::DescriptorSetLayoutBinding`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct DescriptorBindingSignature {
+    /// The binding number.
+    pub binding: u32,
+    /// The type of the binding's descriptors.
+    pub descriptor_type: i32,
+    /// The number of descriptors.
+    pub count: u32,
+    /// The raw stages the binding is visible to.
+    pub stages: u32,
+}
+
+impl DescriptorBindingSignature {
+    /// Get the signature of `binding`.
+    pub fn new(binding: &vk::DescriptorSetLayoutBinding) -> Self {
+        Self {
+            binding: binding.binding,
+            descriptor_type: binding.descriptor_type.as_raw(),
+            count: binding.descriptor_count,
+            stages: binding.stage_flags.as_raw(),
+        }
+    }
+}
+
+/// The descriptor set layouts alive, counted by their bindings.
+pub(crate) type SetLayoutCounts = HashMap<Vec<DescriptorBindingSignature>, usize>;
+
+impl PipelineCache {
+    /// Count the cached pipelines by their fixed-function state, i.e. their create info without
+    /// the shaders, entry point and layout. Signatures shared by many pipelines come first.
+    pub fn pipelines_by_state(&self) -> Vec<(GraphicsPipelineCreateInfo, usize)> {
+        let mut counts: HashMap<GraphicsPipelineCreateInfo, usize> = HashMap::new();
+        for create_info in self.graphics_keys() {
+            let signature = GraphicsPipelineCreateInfo {
+                vertex_shader: vk::ShaderModule::null(),
+                fragment_shader: None,
+                geometry_shader: None,
+                entry_point: String::new(),
+                layout: vk::PipelineLayout::null(),
+                ..create_info.clone()
+            };
+            *counts.entry(signature).or_default() += 1;
+        }
+        let mut counts = counts.into_iter().collect::<Vec<_>>();
+        counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+        counts
+    }
+}
+
+impl Device {
+    /// Get the cache hits and misses of the last frame, from one `begin_frame` to the next.
+    pub fn cache_stats(&self) -> CacheStats {
+        *self.last_cache_stats.lock()
+    }
+
+    /// Latch the cache counts of the frame which just ended, for `cache_stats`.
+    pub(crate) fn latch_cache_stats(&self) {
+        *self.last_cache_stats.lock() = self.cache_counters.take();
+    }
+
+    /// Count the descriptor set layouts made by `create_shader_layout` which are alive, by
+    /// their bindings. Compositions shared by many layouts come first.
+    pub fn descriptor_layouts_by_bindings(&self) -> Vec<(Vec<DescriptorBindingSignature>, usize)> {
+        let mut counts = self
+            .set_layout_counts
+            .lock()
+            .iter()
+            .map(|(bindings, &count)| (bindings.clone(), count))
+            .collect::<Vec<_>>();
+        counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts
+    }
+
+    /// Count a descriptor set layout with `bindings` as alive, or as destroyed if `!alive`.
+    pub(crate) fn track_set_layout(&self, bindings: &[vk::DescriptorSetLayoutBinding], alive: bool) {
+        let key = bindings.iter().map(DescriptorBindingSignature::new).collect::<Vec<_>>();
+        let mut counts = self.set_layout_counts.lock();
+        if alive {
+            *counts.entry(key).or_default() += 1;
+        } else if let Some(count) = counts.get_mut(&key) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&key);
+            }
+        }
+    }
+
+    /// Describe every key of the Device's caches, one per line, for debugging.
+    pub fn dump_cache_keys(&self) -> String {
+        let mut dump = String::new();
+
+        let pipelines = self.pipelines();
+        let _ = writeln!(dump, "graphics pipelines ({}):", pipelines.len());
+        for create_info in pipelines.graphics_keys() {
+            let _ = writeln!(dump, "  {:?}", create_info);
+        }
+        drop(pipelines);
+
+        let render_passes = self.render_passes();
+        let _ = writeln!(dump, "render passes ({}):", render_passes.render_pass_count());
+        for info in render_passes.render_pass_keys() {
+            let _ = writeln!(dump, "  {:?}", info);
+        }
+        let _ = writeln!(dump, "framebuffers ({}):", render_passes.framebuffer_count());
+        for (render_pass, attachments) in render_passes.framebuffer_keys() {
+            let _ = writeln!(dump, "  {:?} {:?}", render_pass, attachments);
+        }
+        drop(render_passes);
+
+        let samplers = self.samplers();
+        let _ = writeln!(dump, "samplers ({}):", samplers.len());
+        for info in samplers.keys() {
+            let _ = writeln!(dump, "  {:?}", info);
+        }
+        drop(samplers);
+
+        let layouts = self.descriptor_layouts_by_bindings();
+        let _ = writeln!(dump, "descriptor set layouts ({}):", layouts.len());
+        for (bindings, count) in layouts {
+            let _ = writeln!(dump, "  {}x {:?}", count, bindings);
+        }
+
+        dump
+    }
+}
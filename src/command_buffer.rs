@@ -0,0 +1,873 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::collections::hash_map::Entry;
+use std::sync::Arc;
+
+use crate::*;
+
+/// The largest update `CommandBuffer::update_buffer` will record inline with `vkCmdUpdateBuffer`
+/// rather than copying through a staging buffer. This is the limit imposed by Vulkan.
+pub const MAX_INLINE_UPDATE_SIZE: usize = 65536;
+
+/// The type of queue a command buffer will be submitted to.
+///
+/// Every queue type can be used on every device. Those without a queue family of their own share
+/// another queue, which `Device::shares_queue` reports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum QueueType {
+    /// The graphics queue, which supports graphics, compute and transfer work.
+    Graphics,
+    /// The compute queue. May be the same as the graphics queue if the device has no dedicated
+    /// compute queue family.
+    Compute,
+    /// The transfer queue, intended for uploads which run asynchronously to rendering. May be
+    /// the same as another queue if the device has no dedicated transfer queue family.
+    AsyncTransfer,
+}
+
+/// Something which can be rendered to, whose extent can be used to set up the viewport.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ViewportTarget {
+    /// The Device's swapchain.
+    Swapchain,
+    /// An image, at a given mip level.
+    Image(ImageHandle, usize),
+}
+
+impl From<ImageHandle> for ViewportTarget {
+    fn from(image: ImageHandle) -> Self {
+        ViewportTarget::Image(image, 0)
+    }
+}
+
+/// How a shader accesses a resource.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ShaderAccess {
+    /// The resource is only read.
+    Read,
+    /// The resource is only written.
+    Write,
+    /// The resource is both read and written.
+    ReadWrite,
+}
+
+impl ShaderAccess {
+    /// Get the access flags corresponding to this access.
+    pub fn flags(self) -> vk::AccessFlags {
+        match self {
+            ShaderAccess::Read => vk::AccessFlags::SHADER_READ,
+            ShaderAccess::Write => vk::AccessFlags::SHADER_WRITE,
+            ShaderAccess::ReadWrite => vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        }
+    }
+}
+
+/// A resource used by a compute dispatch, which must be synchronized with its other uses.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum DispatchResource {
+    /// A storage buffer.
+    StorageBuffer(BufferHandle, ShaderAccess),
+    /// A storage image, which is used in `vk::ImageLayout::GENERAL`.
+    StorageImage(ImageHandle, ShaderAccess),
+    /// A sampled image, which is used in `vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL`.
+    SampledImage(ImageHandle),
+}
+
+/// A primary command buffer in the recording state, allocated from the current frame.
+///
+/// Methods which take `hot` resource handles resolve them through the Device's `ResourceSet`
+/// and record any barriers or layout transitions needed before the resources are used.
+pub struct CommandBuffer {
+    raw: vk::CommandBuffer,
+    queue_type: QueueType,
+    device: Arc<Device>,
+    pub(crate) in_render_pass: bool,
+    /// Whether the current render pass was begun with `VK_KHR_dynamic_rendering`.
+    pub(crate) in_dynamic_rendering: bool,
+    ended: bool,
+    /// The resource accesses recorded, if a resource event sink was installed when the command
+    /// buffer was requested.
+    accesses: Option<Vec<RecordedAccess>>,
+    /// The frame index and zone of each GPU zone begun and not yet ended, innermost last, or
+    /// `None` for zones which aren't timed.
+    pub(crate) gpu_zones: Vec<Option<(usize, usize)>>,
+    /// The render pass, framebuffer and subpass contents of the render pass begun with
+    /// `begin_render_pass` and not yet ended, which secondary command buffers inherit.
+    pub(crate) render_pass: Option<(vk::RenderPass, vk::Framebuffer, vk::SubpassContents)>,
+    /// Whether this is a secondary command buffer continuing its primary's render pass, handed
+    /// out by `record_secondary_parallel`.
+    pub(crate) secondary: bool,
+    /// The graphics state bound through `hot`, which secondary command buffers start with.
+    pub(crate) inherited: InheritedState,
+}
+
+impl CommandBuffer {
+    /// Wrap a raw command buffer which is already in the recording state.
+    pub(crate) fn new(device: Arc<Device>, raw: vk::CommandBuffer, queue_type: QueueType) -> Self {
+        let accesses = if device.records_resource_events() {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        Self {
+            raw,
+            queue_type,
+            device,
+            in_render_pass: false,
+            in_dynamic_rendering: false,
+            ended: false,
+            accesses,
+            gpu_zones: Vec::new(),
+            render_pass: None,
+            secondary: false,
+            inherited: InheritedState::default(),
+        }
+    }
+
+    /// The raw `vk::CommandBuffer`.
+    pub fn raw(&self) -> vk::CommandBuffer {
+        self.raw
+    }
+
+    /// The type of queue this command buffer must be submitted to.
+    pub fn queue_type(&self) -> QueueType {
+        self.queue_type
+    }
+
+    /// The Device this command buffer was allocated from.
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    /// Finish recording. The command buffer may not be recorded into afterwards. Ending a
+    /// command buffer which has already been ended does nothing.
+    pub fn end(&mut self) -> VkResult<()> {
+        assert!(
+            !self.in_render_pass || self.secondary,
+            "ended a command buffer inside a render pass"
+        );
+        if !self.ended {
+            unsafe { self.device.end_command_buffer(self.raw)? };
+            self.ended = true;
+        }
+        Ok(())
+    }
+
+    /// Whether recording has been finished with `end`.
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// The resource accesses recorded for resource events.
+    pub(crate) fn recorded_accesses(&self) -> &[RecordedAccess] {
+        self.accesses.as_deref().unwrap_or(&[])
+    }
+
+    fn record_access(
+        &mut self,
+        resource: ResourceId,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) {
+        if let Some(ref mut accesses) = self.accesses {
+            accesses.push(RecordedAccess {
+                resource,
+                stages,
+                access,
+            });
+        }
+    }
+
+    /// Transition `image` into `layout` for access by `stages` with `access`, recording a
+    /// barrier if needed.
+    pub fn transition_image(
+        &mut self,
+        image: ImageHandle,
+        layout: vk::ImageLayout,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) {
+        assert!(!self.secondary, "transition_image is not allowed in a secondary command buffer");
+        unsafe { self.device.transition_image(self.raw, image, layout, stages, access) };
+        self.record_access(ResourceId::Image(image), stages, access);
+    }
+
+    /// Transition `image` into the destination state of `preset`, recording a barrier if needed.
+    pub fn transition_image_preset(&mut self, image: ImageHandle, preset: TransitionPreset) {
+        let dst = preset.dst();
+        self.transition_image(image, dst.layout, dst.stages, dst.access);
+    }
+
+    /// Transition the subresources of `image` in `range` into `layout` for access by `stages`
+    /// with `access`, recording a barrier if needed. The image's other subresources are left in
+    /// their current states.
+    pub fn transition_image_subresources(
+        &mut self,
+        image: ImageHandle,
+        range: vk::ImageSubresourceRange,
+        layout: vk::ImageLayout,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) {
+        assert!(
+            !self.secondary,
+            "transition_image_subresources is not allowed in a secondary command buffer"
+        );
+        unsafe {
+            self.device
+                .transition_image_subresources(self.raw, image, Some(range), layout, stages, access)
+        };
+        self.record_access(ResourceId::Image(image), stages, access);
+    }
+
+    /// Prepare `buffer` for access by `stages` with `access`, recording a barrier if needed.
+    pub fn transition_buffer(
+        &mut self,
+        buffer: BufferHandle,
+        stages: vk::PipelineStageFlags,
+        access: vk::AccessFlags,
+    ) {
+        assert!(!self.secondary, "transition_buffer is not allowed in a secondary command buffer");
+        unsafe { self.device.transition_buffer(self.raw, buffer, stages, access) };
+        self.record_access(ResourceId::Buffer(buffer), stages, access);
+    }
+
+    /// Copy `size` bytes from `src` at `src_offset` into `dst` at `dst_offset`.
+    pub fn copy_buffer(
+        &mut self,
+        dst: BufferHandle,
+        dst_offset: vk::DeviceSize,
+        src: BufferHandle,
+        src_offset: vk::DeviceSize,
+        size: vk::DeviceSize,
+    ) {
+        self.transition_buffer(src, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ);
+        self.transition_buffer(dst, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE);
+
+        let (src, dst) = {
+            let resources = self.device.resources();
+            (
+                resources.get_buffer(src).expect("copy_buffer: invalid src").raw(),
+                resources.get_buffer(dst).expect("copy_buffer: invalid dst").raw(),
+            )
+        };
+
+        let region = vk::BufferCopy {
+            src_offset,
+            dst_offset,
+            size,
+        };
+
+        unsafe { self.device.cmd_copy_buffer(self.raw, src, dst, &[region]) };
+    }
+
+    /// Write `data` into `dst` at `offset`.
+    ///
+    /// Small updates (at most `MAX_INLINE_UPDATE_SIZE` bytes, with 4 byte aligned offset and size)
+    /// are recorded inline with `vkCmdUpdateBuffer`, which avoids allocating staging memory.
+    /// Larger updates are copied through a staging block of the current frame.
+    ///
+    /// Must not be called inside a render pass.
+    pub fn update_buffer(
+        &mut self,
+        dst: BufferHandle,
+        offset: vk::DeviceSize,
+        data: &[u8],
+    ) -> Result<(), AllocatorError> {
+        assert!(!self.in_render_pass, "update_buffer is not allowed inside a render pass");
+
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let inline = data.len() <= MAX_INLINE_UPDATE_SIZE && offset % 4 == 0 && data.len() % 4 == 0;
+
+        self.transition_buffer(dst, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE);
+        let dst = self
+            .device
+            .resources()
+            .get_buffer(dst)
+            .expect("update_buffer: invalid dst")
+            .raw();
+
+        if inline {
+            unsafe { self.device.cmd_update_buffer(self.raw, dst, offset, data) };
+            return Ok(());
+        }
+
+        let block = self.device.request_internal_block(PoolKind::Staging, data.len(), None)?;
+        let (src, region) = {
+            let blocks = self.device.buffer_blocks();
+            let block = blocks
+                .get_staging_block(block)
+                .expect("update_buffer: staging block was not created");
+            let staging = block
+                .allocate_buffer(data.len())
+                .map_err(|e| AllocatorError::Other(e.to_string()))?;
+
+            let mapped = block
+                .mapped_data(&staging)
+                .expect("update_buffer: staging block is not mapped");
+            unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), mapped.as_ptr(), data.len()) };
+            self.device.allocator().flush_allocation(
+                block.gpu.allocation().expect("update_buffer: staging block has no allocation"),
+                staging.offset as usize,
+                data.len(),
+            )?;
+
+            (
+                block.gpu.raw(),
+                vk::BufferCopy {
+                    src_offset: staging.offset,
+                    dst_offset: offset,
+                    size: data.len() as vk::DeviceSize,
+                },
+            )
+        };
+
+        unsafe { self.device.cmd_copy_buffer(self.raw, src, dst, &[region]) };
+        Ok(())
+    }
+
+    /// Copy regions of `src` into `dst`, transitioning the regions of `dst` to
+    /// `TRANSFER_DST_OPTIMAL`.
+    pub fn copy_buffer_to_image(
+        &mut self,
+        dst: ImageHandle,
+        src: BufferHandle,
+        regions: &[vk::BufferImageCopy],
+    ) {
+        self.transition_buffer(src, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_READ);
+        for region in regions {
+            self.transition_image_subresources(
+                dst,
+                subresource_layers_range(region.image_subresource),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+        }
+
+        let (src, dst, dst_layout) = {
+            let resources = self.device.resources();
+            let image = resources.get_image(dst).expect("copy_buffer_to_image: invalid dst");
+            (
+                resources.get_buffer(src).expect("copy_buffer_to_image: invalid src").raw(),
+                image.raw(),
+                image.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            )
+        };
+
+        unsafe {
+            self.device
+                .cmd_copy_buffer_to_image(self.raw, src, dst, dst_layout, regions)
+        };
+    }
+
+    /// Copy regions of `src` into `dst`, transitioning the regions of `src` to
+    /// `TRANSFER_SRC_OPTIMAL`.
+    pub fn copy_image_to_buffer(
+        &mut self,
+        dst: BufferHandle,
+        src: ImageHandle,
+        regions: &[vk::BufferImageCopy],
+    ) {
+        for region in regions {
+            self.transition_image_subresources(
+                src,
+                subresource_layers_range(region.image_subresource),
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_READ,
+            );
+        }
+        self.transition_buffer(dst, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE);
+
+        let (src, src_layout, dst) = {
+            let resources = self.device.resources();
+            let image = resources.get_image(src).expect("copy_image_to_buffer: invalid src");
+            (
+                image.raw(),
+                image.layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                resources.get_buffer(dst).expect("copy_image_to_buffer: invalid dst").raw(),
+            )
+        };
+
+        unsafe {
+            self.device
+                .cmd_copy_image_to_buffer(self.raw, src, src_layout, dst, regions)
+        };
+    }
+
+    /// Blit regions of `src` into `dst`, transitioning them to `TRANSFER_SRC_OPTIMAL` and
+    /// `TRANSFER_DST_OPTIMAL` respectively.
+    ///
+    /// Only the subresources named by the regions are transitioned, so `src` and `dst` may be
+    /// the same image as long as the regions use different subresources, e.g. when generating
+    /// mips.
+    pub fn blit_image(
+        &mut self,
+        dst: ImageHandle,
+        src: ImageHandle,
+        regions: &[vk::ImageBlit],
+        filter: vk::Filter,
+    ) {
+        for region in regions {
+            self.transition_image_subresources(
+                src,
+                subresource_layers_range(region.src_subresource),
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_READ,
+            );
+            self.transition_image_subresources(
+                dst,
+                subresource_layers_range(region.dst_subresource),
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+        }
+
+        let (src, src_layout, dst, dst_layout) = {
+            let resources = self.device.resources();
+            let src = resources.get_image(src).expect("blit_image: invalid src");
+            let dst = resources.get_image(dst).expect("blit_image: invalid dst");
+            (
+                src.raw(),
+                src.layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                dst.raw(),
+                dst.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            )
+        };
+
+        unsafe {
+            self.device
+                .cmd_blit_image(self.raw, src, src_layout, dst, dst_layout, regions, filter)
+        };
+    }
+
+    /// Resolve mip level 0 of every layer of the multisampled color image `src` into the single
+    /// sampled `dst`, transitioning them to `TRANSFER_SRC_OPTIMAL` and `TRANSFER_DST_OPTIMAL`
+    /// respectively. The images must have the same extent, format and layer count.
+    pub fn resolve_image(&mut self, dst: ImageHandle, src: ImageHandle) {
+        let (src_raw, src_layout, dst_raw, dst_layout, extent, layers) = {
+            let resources = self.device.resources();
+            let src = resources.get_image(src).expect("resolve_image: invalid src");
+            let dst = resources.get_image(dst).expect("resolve_image: invalid dst");
+            let create_info = src.create_info();
+            assert!(
+                create_info.sample_count != vk::SampleCountFlags::TYPE_1,
+                "resolve_image: src is not multisampled"
+            );
+            assert!(
+                dst.create_info().sample_count == vk::SampleCountFlags::TYPE_1,
+                "resolve_image: dst is multisampled"
+            );
+            (
+                src.raw(),
+                src.layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                dst.raw(),
+                dst.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+                vk::Extent3D {
+                    width: create_info.width as u32,
+                    height: create_info.height as u32,
+                    depth: 1,
+                },
+                create_info.layers as u32,
+            )
+        };
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: layers,
+        };
+        self.transition_image_subresources(
+            src,
+            subresource_layers_range(subresource),
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_READ,
+        );
+        self.transition_image_subresources(
+            dst,
+            subresource_layers_range(subresource),
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+
+        let region = vk::ImageResolve {
+            src_subresource: subresource,
+            src_offset: vk::Offset3D::default(),
+            dst_subresource: subresource,
+            dst_offset: vk::Offset3D::default(),
+            extent,
+        };
+        unsafe {
+            self.device
+                .cmd_resolve_image(self.raw, src_raw, src_layout, dst_raw, dst_layout, &[region])
+        };
+    }
+
+    /// Begin a render pass. Attachments must already be in the initial layouts expected by
+    /// `render_pass`.
+    pub fn begin_render_pass(
+        &mut self,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        render_area: vk::Rect2D,
+        clear_values: &[vk::ClearValue],
+    ) {
+        self.begin_render_pass_with_contents(
+            render_pass,
+            framebuffer,
+            render_area,
+            clear_values,
+            vk::SubpassContents::INLINE,
+        );
+    }
+
+    /// Begin a render pass whose subpass is recorded inline or, with
+    /// `SECONDARY_COMMAND_BUFFERS`, only by secondary command buffers (see
+    /// `record_secondary_parallel`).
+    pub fn begin_render_pass_with_contents(
+        &mut self,
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        render_area: vk::Rect2D,
+        clear_values: &[vk::ClearValue],
+        contents: vk::SubpassContents,
+    ) {
+        assert!(!self.in_render_pass, "render passes cannot be nested");
+
+        let begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(render_area)
+            .clear_values(clear_values);
+
+        unsafe { self.device.cmd_begin_render_pass(self.raw, &begin_info, contents) };
+        self.in_render_pass = true;
+        self.render_pass = Some((render_pass, framebuffer, contents));
+    }
+
+    /// End the current render pass.
+    pub fn end_render_pass(&mut self) {
+        assert!(self.in_render_pass, "no render pass to end");
+        assert!(!self.in_dynamic_rendering, "dynamic rendering must be ended with end_rendering");
+        assert!(!self.secondary, "a secondary command buffer cannot end its primary's render pass");
+        unsafe { self.device.cmd_end_render_pass(self.raw) };
+        self.in_render_pass = false;
+        self.render_pass = None;
+    }
+
+    /// Get the extent of `target` and its pre-transform.
+    pub(crate) fn target_extent(
+        &self,
+        target: ViewportTarget,
+    ) -> (vk::Extent2D, vk::SurfaceTransformFlagsKHR) {
+        match target {
+            ViewportTarget::Swapchain => {
+                let swapchain = self
+                    .device
+                    .swapchain()
+                    .expect("viewport target: the swapchain is not initialized");
+                (swapchain.extent(), swapchain.pre_transform())
+            }
+            ViewportTarget::Image(image, lod) => {
+                let resources = self.device.resources();
+                let image = resources.get_image(image).expect("viewport target: invalid image");
+                (
+                    vk::Extent2D {
+                        width: image.width_lod(lod) as u32,
+                        height: image.height_lod(lod) as u32,
+                    },
+                    vk::SurfaceTransformFlagsKHR::IDENTITY,
+                )
+            }
+        }
+    }
+
+    /// Set the viewport and scissor to cover the whole of `target`, using its actual extent.
+    ///
+    /// If `flip_y` is set, the viewport is flipped vertically so that +Y points up in clip space,
+    /// matching OpenGL and wgpu conventions.
+    ///
+    /// Returns the pre-transform of the target. For a pre-rotated swapchain this is the rotation
+    /// the application must apply to its clip space positions, since the extent used here is the
+    /// rotated one. For images it is always `IDENTITY`.
+    pub fn set_viewport_for<T: Into<ViewportTarget>>(
+        &mut self,
+        target: T,
+        flip_y: bool,
+    ) -> vk::SurfaceTransformFlagsKHR {
+        let (extent, transform) = self.target_extent(target.into());
+
+        let (y, height) = if flip_y {
+            (extent.height as f32, -(extent.height as f32))
+        } else {
+            (0.0, extent.height as f32)
+        };
+
+        let viewport = vk::Viewport {
+            x: 0.0,
+            y,
+            width: extent.width as f32,
+            height,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        };
+
+        let scissor = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent,
+        };
+
+        unsafe {
+            self.device.cmd_set_viewport(self.raw, 0, &[viewport]);
+            self.device.cmd_set_scissor(self.raw, 0, &[scissor]);
+        }
+        self.inherited.set_viewports(0, &[viewport], &[scissor]);
+
+        transform
+    }
+
+    /// Bind a buffer allocated from a vertex block as the vertex buffer at `binding`.
+    pub fn bind_vertex_block(&mut self, binding: u32, buffer: TransientBufferHandle) {
+        let offset = buffer.offset;
+        let raw = {
+            let blocks = self.device.buffer_blocks();
+            blocks
+                .get_vertex_block(buffer.block)
+                .and_then(|block| block.get_gpu_buffer(&buffer))
+                .expect("bind_vertex_block: invalid buffer")
+                .raw()
+        };
+
+        unsafe {
+            self.device
+                .cmd_bind_vertex_buffers(self.raw, binding, &[raw], &[offset])
+        };
+    }
+
+    /// Bind indices written to an index block with `BufferBlock::allocate_indices` as the index
+    /// buffer, with the index type they were written as.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `allocation` wasn't allocated from an index block of the current frame, or
+    /// if its range isn't made up of whole, aligned indices of its type, e.g. because it was
+    /// put together by hand with a mismatched index type.
+    pub fn bind_index_block(&mut self, allocation: IndexBlockAllocation) {
+        let buffer = allocation.buffer;
+        let raw = {
+            let blocks = self.device.buffer_blocks();
+            blocks
+                .get_index_block(buffer.block)
+                .and_then(|block| block.get_gpu_buffer(&buffer))
+                .expect("bind_index_block: invalid buffer")
+                .raw()
+        };
+
+        let index_size = index_size(allocation.index_type);
+        assert!(
+            buffer.offset % index_size == 0 && buffer.size % index_size == 0,
+            "bind_index_block: range of {} bytes at offset {} does not hold {:?} indices",
+            buffer.size,
+            buffer.offset,
+            allocation.index_type,
+        );
+
+        unsafe {
+            self.device
+                .cmd_bind_index_buffer(self.raw, raw, buffer.offset, allocation.index_type)
+        };
+    }
+
+    /// Draw `instance_count` instances of `vertex_count` vertices, binding `vertices` at
+    /// binding 0 and `instances` at binding 1, as laid out by a pipeline created with
+    /// `GraphicsPipelineCreateInfo::vertex_layouts(&[vertex_layout, instance_layout.into()])`.
+    pub fn draw_instanced(
+        &mut self,
+        vertices: TransientBufferHandle,
+        instances: TransientBufferHandle,
+        vertex_count: u32,
+        instance_count: u32,
+    ) {
+        self.bind_vertex_block(0, vertices);
+        self.bind_vertex_block(1, instances);
+        unsafe { self.device.cmd_draw(self.raw, vertex_count, instance_count, 0, 0) };
+    }
+
+    /// Like `draw_instanced`, but draws every index of `indices` for each instance.
+    pub fn draw_indexed_instanced(
+        &mut self,
+        vertices: TransientBufferHandle,
+        indices: IndexBlockAllocation,
+        instances: TransientBufferHandle,
+        instance_count: u32,
+    ) {
+        self.bind_vertex_block(0, vertices);
+        self.bind_vertex_block(1, instances);
+        self.bind_index_block(indices);
+        unsafe {
+            self.device
+                .cmd_draw_indexed(self.raw, indices.count(), instance_count, 0, 0, 0)
+        };
+    }
+
+    /// Bind `pipeline` with `descriptor_sets` and `push_constants` and dispatch `group_counts`
+    /// work groups.
+    ///
+    /// The descriptor sets are bound starting at set 0, and the push constants are written at
+    /// offset 0 for the compute stage. Barriers are recorded before the dispatch for each of the
+    /// `resources` it uses, and their tracked states are updated so that later uses wait on any
+    /// writes made by the dispatch.
+    pub fn dispatch_with(
+        &mut self,
+        pipeline: &ComputePipeline,
+        descriptor_sets: &[vk::DescriptorSet],
+        push_constants: &[u8],
+        resources: &[DispatchResource],
+        group_counts: [u32; 3],
+    ) {
+        let stage = vk::PipelineStageFlags::COMPUTE_SHADER;
+        for resource in resources {
+            match *resource {
+                DispatchResource::StorageBuffer(buffer, access) => {
+                    self.transition_buffer(buffer, stage, access.flags())
+                }
+                DispatchResource::StorageImage(image, access) => {
+                    self.transition_image(image, vk::ImageLayout::GENERAL, stage, access.flags())
+                }
+                DispatchResource::SampledImage(image) => self.transition_image(
+                    image,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    stage,
+                    vk::AccessFlags::SHADER_READ,
+                ),
+            }
+        }
+
+        if !descriptor_sets.is_empty() {
+            // Sets must not be updated after being bound, so batched writes are made now.
+            self.device.flush_descriptor_writes();
+        }
+
+        unsafe {
+            self.device
+                .cmd_bind_pipeline(self.raw, vk::PipelineBindPoint::COMPUTE, pipeline.raw);
+            if !descriptor_sets.is_empty() {
+                self.device.cmd_bind_descriptor_sets(
+                    self.raw,
+                    vk::PipelineBindPoint::COMPUTE,
+                    pipeline.layout,
+                    0,
+                    descriptor_sets,
+                    &[],
+                );
+            }
+            if !push_constants.is_empty() {
+                self.device.cmd_push_constants(
+                    self.raw,
+                    pipeline.layout,
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    push_constants,
+                );
+            }
+        }
+
+        self.dispatch(group_counts[0], group_counts[1], group_counts[2]);
+    }
+
+    /// Dispatch compute work with the currently bound compute pipeline.
+    pub fn dispatch(&mut self, group_count_x: u32, group_count_y: u32, group_count_z: u32) {
+        assert!(!self.in_render_pass, "dispatch is not allowed inside a render pass");
+        unsafe {
+            self.device
+                .cmd_dispatch(self.raw, group_count_x, group_count_y, group_count_z)
+        };
+    }
+}
+
+impl Device {
+    /// Get the queue family index used for a `QueueType`.
+    pub fn queue_family_index(&self, queue_type: QueueType) -> u32 {
+        match queue_type {
+            QueueType::Graphics => self.graphics_queue_family_index,
+            QueueType::Compute => self.compute_queue_family_index,
+            QueueType::AsyncTransfer => self.transfer_queue_family_index,
+        }
+    }
+
+    /// Request a command buffer for the current frame which will be submitted to a queue of
+    /// type `queue_type`. The command buffer is returned in the recording state.
+    ///
+    /// Each thread gets command buffers from its own command pool, which is created the first
+    /// time the thread requests a command buffer for `queue_type` in a frame slot, so several
+    /// threads may record at once without locking. A command buffer must be recorded on the
+    /// thread which requested it, since the pool it came from is only synchronized by being
+    /// used by that thread alone.
+    pub fn request_command_buffer(
+        self: Arc<Self>,
+        frame: &Frame,
+        queue_type: QueueType,
+    ) -> Result<CommandBuffer, vk::Result> {
+        self.check_frame(frame);
+        self.request_internal_command_buffer(queue_type)
+    }
+
+    /// Request a command buffer for the current frame without a `Frame`, for work hot records
+    /// itself, such as uploads, which may happen outside of a frame.
+    pub(crate) fn request_internal_command_buffer(
+        self: Arc<Self>,
+        queue_type: QueueType,
+    ) -> Result<CommandBuffer, vk::Result> {
+        let raw = self.request_raw_command_buffer(queue_type)?;
+        Ok(CommandBuffer::new(self, raw, queue_type))
+    }
+
+    /// Request a raw command buffer from the current thread's pool for the current frame, in the
+    /// recording state, for work hot records itself.
+    pub(crate) fn request_raw_command_buffer(
+        &self,
+        queue_type: QueueType,
+    ) -> Result<vk::CommandBuffer, vk::Result> {
+        let raw = {
+            let mut per_frame = self.current_frame().write();
+            let pools = per_frame.cmd_pools_mut(queue_type);
+
+            let pool = match pools.entry(std::thread::current().id()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(unsafe { CommandPool::new(self, self.queue_family_index(queue_type))? })
+                }
+            };
+
+            unsafe { pool.request_command_buffer(self)? }
+        };
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { self.device.begin_command_buffer(raw, &begin_info)? };
+
+        Ok(raw)
+    }
+}
+
+/// Get the subresource range covering a set of subresource layers.
+pub(crate) fn subresource_layers_range(layers: vk::ImageSubresourceLayers) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: layers.aspect_mask,
+        base_mip_level: layers.mip_level,
+        level_count: 1,
+        base_array_layer: layers.base_array_layer,
+        layer_count: layers.layer_count,
+    }
+}
@@ -2,17 +2,31 @@ use crate::Device;
 
 use ash::{prelude::*, version::DeviceV1_0, vk};
 
+#[derive(Default)]
 struct BuffersAndIndex {
     buffers: Vec<vk::CommandBuffer>,
     idx: usize,
 }
 
-impl Default for BuffersAndIndex {
-    fn default() -> Self {
-        BuffersAndIndex {
-            buffers: Vec::new(),
-            idx: 0,
+impl BuffersAndIndex {
+    /// Hand out the next unused command buffer, allocating a new one if all are in use.
+    unsafe fn request(
+        &mut self,
+        device: &Device,
+        pool: vk::CommandPool,
+        level: vk::CommandBufferLevel,
+    ) -> VkResult<vk::CommandBuffer> {
+        if self.idx == self.buffers.len() {
+            let alloc_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(pool)
+                .level(level)
+                .command_buffer_count(1);
+            self.buffers.extend(device.allocate_command_buffers(&alloc_info)?);
         }
+
+        let buffer = self.buffers[self.idx];
+        self.idx += 1;
+        Ok(buffer)
     }
 }
 
@@ -36,6 +50,7 @@ impl CommandPool {
             .build();
 
         let pool = device.create_command_pool(&create_info, None)?;
+        device.set_object_name(pool, &format!("command pool (queue family {})", queue_family_index));
 
         Ok(Self {
             pool,
@@ -44,18 +59,41 @@ impl CommandPool {
         })
     }
 
+    /// Get a primary command buffer from this pool, reusing one which was handed out before the
+    /// last reset if possible.
+    ///
+    /// # Safety
+    /// * This CommandPool must have been allocated from `device`.
+    pub unsafe fn request_command_buffer(&mut self, device: &Device) -> VkResult<vk::CommandBuffer> {
+        self.buffers.request(device, self.pool, vk::CommandBufferLevel::PRIMARY)
+    }
+
+    /// Get a secondary command buffer from this pool, reusing one which was handed out before
+    /// the last reset if possible.
+    ///
+    /// # Safety
+    /// * This CommandPool must have been allocated from `device`.
+    pub unsafe fn request_secondary_command_buffer(
+        &mut self,
+        device: &Device,
+    ) -> VkResult<vk::CommandBuffer> {
+        self.secondary_buffers.request(device, self.pool, vk::CommandBufferLevel::SECONDARY)
+    }
+
     /// # Safety
     /// * This CommandPool must have been allocated from `device`.
     /// * All command buffers allocated from this pool must not be in use, i.e. not part of a
-    /// pending GPU execution.
+    ///   pending GPU execution.
     pub unsafe fn reset(&mut self, device: &Device) -> VkResult<()> {
+        self.buffers.idx = 0;
+        self.secondary_buffers.idx = 0;
         device.reset_command_pool(self.pool, vk::CommandPoolResetFlags::empty())
     }
 
     /// # Safety
     /// * This CommandPool must have been allocated from `device`.
     /// * All command buffers allocated from this pool must not be in use, i.e. not part of a
-    /// pending GPU execution.
+    ///   pending GPU execution.
     pub unsafe fn destroy(self, device: &Device) {
         device.destroy_command_pool(self.pool, None);
     }
@@ -0,0 +1,362 @@
+//! Immediate-mode debug geometry: lines, boxes and spheres are accumulated on the CPU during a
+//! frame with `DebugDraw::line`, `aabb`, `sphere` and friends, and drawn into the current render
+//! pass with `DebugDraw::flush`.
+//!
+//! The vertices are written to a per-frame vertex block and drawn with two cached pipelines, one
+//! for line lists and one for triangle lists, created from built-in shaders through the Device's
+//! pipeline cache like any other graphics pipeline.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use bytemuck::{Pod, Zeroable};
+
+use thiserror::Error;
+
+use std::mem;
+use std::sync::Arc;
+
+use crate::*;
+
+/// The number of line segments each of the three circles drawn by `DebugDraw::sphere` is made
+/// of.
+pub const DEBUG_SPHERE_SEGMENTS: usize = 24;
+
+/// An error that could occur while creating or flushing a `DebugDraw`.
+#[derive(Error, Debug)]
+pub enum DebugDrawError {
+    /// Reflecting a built-in shader failed.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+    /// The vertex block could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// The vertices did not fit in the vertex block.
+    #[error("block allocation error: {0}")]
+    BlockAllocation(#[from] BlockAllocationError),
+    /// The view projection matrix could not be pushed.
+    #[error("push constant error: {0}")]
+    PushConstant(#[from] PushConstantError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A vertex of debug geometry, as read by the built-in debug draw vertex shader.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+pub struct DebugVertex {
+    /// The world space position.
+    pub position: [f32; 3],
+    /// The linear RGBA color, blended with the attachment by its alpha.
+    pub color: [f32; 4],
+}
+
+impl Vertex for DebugVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::new(mem::size_of::<DebugVertex>() as u32)
+            .attribute(vk::Format::R32G32B32_SFLOAT, 0)
+            .attribute(vk::Format::R32G32B32A32_SFLOAT, mem::size_of::<[f32; 3]>() as u32)
+    }
+}
+
+/// The render pass or dynamic rendering a `DebugDraw` is flushed into, which decides the
+/// pipelines it uses.
+///
+/// The subpass or rendering must have a single color attachment.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugDrawTarget {
+    /// The render pass, or null for `CommandBuffer::begin_rendering`.
+    pub render_pass: vk::RenderPass,
+    /// The subpass of `render_pass`.
+    pub subpass: u32,
+    /// The number of samples of the attachments.
+    pub samples: vk::SampleCountFlags,
+    /// The format of the color attachment, if `render_pass` is null.
+    pub color_format: Option<vk::Format>,
+    /// The format of the depth stencil attachment, if `render_pass` is null.
+    pub depth_stencil_format: Option<vk::Format>,
+    /// Whether the geometry is tested against the depth attachment, without writing to it.
+    pub depth_test: bool,
+    /// Whether nearer depths are larger, which flips the depth comparison.
+    pub reversed_z: bool,
+}
+
+impl DebugDrawTarget {
+    /// Target a subpass of `render_pass` with single sampled attachments and no depth testing.
+    pub fn render_pass(render_pass: vk::RenderPass, subpass: u32) -> Self {
+        Self {
+            render_pass,
+            subpass,
+            samples: vk::SampleCountFlags::TYPE_1,
+            color_format: None,
+            depth_stencil_format: None,
+            depth_test: false,
+            reversed_z: false,
+        }
+    }
+
+    /// Target dynamic rendering into a single sampled color attachment of `color_format` and an
+    /// optional depth attachment, which the geometry is depth tested against if given.
+    pub fn rendering(color_format: vk::Format, depth_format: Option<vk::Format>) -> Self {
+        Self {
+            render_pass: vk::RenderPass::null(),
+            subpass: 0,
+            samples: vk::SampleCountFlags::TYPE_1,
+            color_format: Some(color_format),
+            depth_stencil_format: depth_format,
+            depth_test: depth_format.is_some(),
+            reversed_z: false,
+        }
+    }
+}
+
+/// An immediate-mode drawer of debug lines and shapes.
+///
+/// Shapes are accumulated until `flush`, which draws them into the render pass being recorded
+/// and clears them, so a `DebugDraw` is usually created once and flushed every frame. Lines and
+/// wireframe shapes are drawn as line lists and solid shapes as triangle lists, both alpha
+/// blended and without culling.
+pub struct DebugDraw {
+    device: Arc<Device>,
+    lines: Vec<DebugVertex>,
+    triangles: Vec<DebugVertex>,
+    vertex_shader: Option<Shader>,
+    fragment_shader: Option<Shader>,
+    layout: Option<ShaderLayout>,
+    push_constants: PushConstants<[[f32; 4]; 4]>,
+}
+
+impl DebugDraw {
+    /// Create a drawer, creating its built-in shaders.
+    pub fn new(device: &Arc<Device>) -> Result<Self, DebugDrawError> {
+        let vertex_shader = device.create_shader(shaders::DEBUG_DRAW_VERT)?;
+        let fragment_shader = match device.create_shader(shaders::DEBUG_DRAW_FRAG) {
+            Ok(shader) => shader,
+            Err(e) => {
+                unsafe { device.destroy_shader(vertex_shader) };
+                return Err(e.into());
+            }
+        };
+        let layout = match device.create_shader_layout(&[&vertex_shader, &fragment_shader]) {
+            Ok(layout) => layout,
+            Err(e) => {
+                unsafe {
+                    device.destroy_shader(vertex_shader);
+                    device.destroy_shader(fragment_shader);
+                }
+                return Err(e.into());
+            }
+        };
+
+        Ok(Self {
+            device: device.clone(),
+            lines: Vec::new(),
+            triangles: Vec::new(),
+            push_constants: PushConstants::new(&layout),
+            vertex_shader: Some(vertex_shader),
+            fragment_shader: Some(fragment_shader),
+            layout: Some(layout),
+        })
+    }
+
+    /// Draw a line from `a` to `b`.
+    pub fn line(&mut self, a: [f32; 3], b: [f32; 3], color: [f32; 4]) {
+        self.lines.push(DebugVertex { position: a, color });
+        self.lines.push(DebugVertex { position: b, color });
+    }
+
+    /// Draw the edges of the axis aligned box from `min` to `max`.
+    pub fn aabb(&mut self, min: [f32; 3], max: [f32; 3], color: [f32; 4]) {
+        let corners = box_corners(min, max);
+        for &(a, b) in &BOX_EDGES {
+            self.line(corners[a], corners[b], color);
+        }
+    }
+
+    /// Draw a sphere as three circles around `center`, one in each axis aligned plane.
+    pub fn sphere(&mut self, center: [f32; 3], radius: f32, color: [f32; 4]) {
+        for axes in &[(0, 1), (0, 2), (1, 2)] {
+            let point = |i: usize| {
+                let angle = i as f32 / DEBUG_SPHERE_SEGMENTS as f32 * std::f32::consts::TAU;
+                let mut point = center;
+                point[axes.0] += radius * angle.cos();
+                point[axes.1] += radius * angle.sin();
+                point
+            };
+            for i in 0..DEBUG_SPHERE_SEGMENTS {
+                self.line(point(i), point(i + 1), color);
+            }
+        }
+    }
+
+    /// Draw a solid triangle.
+    pub fn triangle(&mut self, a: [f32; 3], b: [f32; 3], c: [f32; 3], color: [f32; 4]) {
+        for &position in &[a, b, c] {
+            self.triangles.push(DebugVertex { position, color });
+        }
+    }
+
+    /// Draw the axis aligned box from `min` to `max` as a solid.
+    pub fn solid_aabb(&mut self, min: [f32; 3], max: [f32; 3], color: [f32; 4]) {
+        let corners = box_corners(min, max);
+        for face in &BOX_FACES {
+            self.triangle(corners[face[0]], corners[face[1]], corners[face[2]], color);
+            self.triangle(corners[face[0]], corners[face[2]], corners[face[3]], color);
+        }
+    }
+
+    /// Discard the geometry drawn since the last `flush`.
+    pub fn clear(&mut self) {
+        self.lines.clear();
+        self.triangles.clear();
+    }
+
+    /// Whether no geometry has been drawn since the last `flush`.
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty() && self.triangles.is_empty()
+    }
+
+    /// Draw the accumulated geometry into `target`, transformed by the column major
+    /// `view_proj` matrix, and clear it.
+    ///
+    /// `cmd` must be inside the render pass or rendering described by `target`, with the
+    /// viewport and scissor set, e.g. with `CommandBuffer::set_viewport_for`. The vertices are
+    /// written to a vertex block of the current frame, so they're only valid for this frame.
+    pub fn flush(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        target: &DebugDrawTarget,
+        view_proj: [[f32; 4]; 4],
+    ) -> Result<(), DebugDrawError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let vertex_count = self.lines.len() + self.triangles.len();
+        let size = vertex_count * mem::size_of::<DebugVertex>();
+        let block = self
+            .device
+            .request_internal_block(PoolKind::Vertex, size, Some(Tag::Static("debug draw vertices")))?;
+        let buffer = {
+            let blocks = self.device.buffer_blocks();
+            let block = blocks
+                .get_vertex_block(block)
+                .expect("debug draw vertex block was recycled");
+            let buffer = block.allocate_buffer(size)?;
+            let ptr = block
+                .mapped_data(&buffer)
+                .expect("debug draw vertex block is not mapped")
+                .as_ptr() as *mut DebugVertex;
+            unsafe {
+                ptr.copy_from_nonoverlapping(self.lines.as_ptr(), self.lines.len());
+                ptr.add(self.lines.len())
+                    .copy_from_nonoverlapping(self.triangles.as_ptr(), self.triangles.len());
+            }
+            buffer
+        };
+
+        cmd.bind_vertex_block(0, buffer);
+        cmd.push_constants(&self.push_constants, &view_proj)?;
+
+        let draws = [
+            (vk::PrimitiveTopology::LINE_LIST, 0, self.lines.len()),
+            (vk::PrimitiveTopology::TRIANGLE_LIST, self.lines.len(), self.triangles.len()),
+        ];
+        for &(topology, first_vertex, count) in &draws {
+            if count == 0 {
+                continue;
+            }
+            let pipeline = self
+                .device
+                .request_graphics_pipeline(&self.pipeline_info(target, topology))?;
+            unsafe {
+                let device = &self.device.device;
+                device.cmd_bind_pipeline(cmd.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline);
+                device.cmd_draw(cmd.raw(), count as u32, 1, first_vertex as u32, 0);
+            }
+        }
+
+        self.clear();
+        Ok(())
+    }
+
+    fn pipeline_info(
+        &self,
+        target: &DebugDrawTarget,
+        topology: vk::PrimitiveTopology,
+    ) -> GraphicsPipelineCreateInfo {
+        GraphicsPipelineCreateInfo {
+            vertex_shader: self.vertex_shader.as_ref().unwrap().raw(),
+            fragment_shader: Some(self.fragment_shader.as_ref().unwrap().raw()),
+            layout: self.layout.as_ref().unwrap().raw(),
+            render_pass: target.render_pass,
+            subpass: target.subpass,
+            vertex_bindings: vec![DebugVertex::layout().binding(0)],
+            vertex_attributes: DebugVertex::layout().vertex_attributes(0, 0),
+            topology,
+            cull_mode: vk::CullModeFlags::NONE,
+            samples: target.samples,
+            depth_test: target.depth_test,
+            depth_write: false,
+            depth_compare_op: if target.reversed_z {
+                vk::CompareOp::GREATER_OR_EQUAL
+            } else {
+                vk::CompareOp::LESS_OR_EQUAL
+            },
+            blend_states: vec![BlendState::AlphaBlend],
+            color_formats: target.color_format.into_iter().collect(),
+            depth_stencil_format: target.depth_stencil_format,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for DebugDraw {
+    fn drop(&mut self) {
+        unsafe {
+            // The pipelines may still be in use by frames in flight. Destroying the shaders also
+            // destroys the cached pipelines created from them.
+            let _ = self.device.device_wait_idle();
+
+            if let Some(layout) = self.layout.take() {
+                self.device.destroy_shader_layout(layout);
+            }
+            for shader in vec![self.vertex_shader.take(), self.fragment_shader.take()]
+                .into_iter()
+                .flatten()
+            {
+                self.device.destroy_shader(shader);
+            }
+        }
+    }
+}
+
+/// The corners of a box, indexed by a bit per axis which is set for the `max` side.
+fn box_corners(min: [f32; 3], max: [f32; 3]) -> [[f32; 3]; 8] {
+    let mut corners = [[0.0; 3]; 8];
+    for (i, corner) in corners.iter_mut().enumerate() {
+        for axis in 0..3 {
+            corner[axis] = if i & (1 << axis) != 0 { max[axis] } else { min[axis] };
+        }
+    }
+    corners
+}
+
+/// The edges of a box, as pairs of indices into `box_corners`.
+#[rustfmt::skip]
+const BOX_EDGES: [(usize, usize); 12] = [
+    (0, 1), (2, 3), (4, 5), (6, 7),
+    (0, 2), (1, 3), (4, 6), (5, 7),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+/// The faces of a box, as quads of indices into `box_corners`.
+const BOX_FACES: [[usize; 4]; 6] = [
+    [0, 2, 3, 1],
+    [4, 5, 7, 6],
+    [0, 1, 5, 4],
+    [2, 6, 7, 3],
+    [0, 4, 6, 2],
+    [1, 3, 7, 5],
+];
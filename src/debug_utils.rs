@@ -0,0 +1,80 @@
+use ash::vk;
+
+use std::ffi::CString;
+
+use crate::*;
+
+impl Device {
+    /// Get whether `VK_EXT_debug_utils` is enabled, i.e. whether object names and command buffer
+    /// labels are passed on to debugging tools such as RenderDoc and the validation layers.
+    pub fn has_debug_utils(&self) -> bool {
+        self.debug_utils.is_some()
+    }
+
+    /// Give `handle` a name to be shown by debugging tools. Does nothing if `VK_EXT_debug_utils`
+    /// is not enabled.
+    ///
+    /// `hot` already names the buffers, images, views, command pools and swapchain semaphores
+    /// it creates after their `Tag`, so this is only needed for objects created outside of it.
+    pub fn set_object_name<H: vk::Handle>(&self, handle: H, name: &str) {
+        let debug_utils = match self.debug_utils {
+            Some(ref debug_utils) => debug_utils,
+            None => return,
+        };
+        // Names are only a debugging aid, so one which can't be passed to Vulkan is skipped.
+        let name = match CString::new(name) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+
+        let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+            .object_type(H::TYPE)
+            .object_handle(handle.as_raw())
+            .object_name(&name);
+        if let Err(e) = unsafe { debug_utils.debug_utils_set_object_name(self.device.handle(), &name_info) } {
+            log::warn!("hot: failed to set debug name {:?}: {}", name, e);
+        }
+    }
+
+    /// Name `handle` after `tag`, if it has one.
+    pub(crate) fn set_tag_name<H: vk::Handle>(&self, handle: H, tag: Option<&Tag>) {
+        if let Some(tag) = tag {
+            if self.debug_utils.is_some() {
+                self.set_object_name(handle, &tag.to_string());
+            }
+        }
+    }
+}
+
+impl CommandBuffer {
+    /// Open a labelled region of the command buffer, which debugging tools show the commands
+    /// recorded until the matching `end_label` under. Regions may be nested.
+    ///
+    /// Labels are only recorded if `VK_EXT_debug_utils` is enabled.
+    pub fn begin_label(&mut self, name: &str, color: [f32; 4]) {
+        let device = self.device();
+        if let Some(ref debug_utils) = device.debug_utils {
+            let name = CString::new(name).unwrap_or_default();
+            let label = vk::DebugUtilsLabelEXT::builder().label_name(&name).color(color);
+            unsafe { debug_utils.cmd_begin_debug_utils_label(self.raw(), &label) };
+        }
+    }
+
+    /// Close the region opened by the last unmatched `begin_label`.
+    pub fn end_label(&mut self) {
+        let device = self.device();
+        if let Some(ref debug_utils) = device.debug_utils {
+            unsafe { debug_utils.cmd_end_debug_utils_label(self.raw()) };
+        }
+    }
+
+    /// Insert a single label between commands.
+    pub fn insert_label(&mut self, name: &str, color: [f32; 4]) {
+        let device = self.device();
+        if let Some(ref debug_utils) = device.debug_utils {
+            let name = CString::new(name).unwrap_or_default();
+            let label = vk::DebugUtilsLabelEXT::builder().label_name(&name).color(color);
+            unsafe { debug_utils.cmd_insert_debug_utils_label(self.raw(), &label) };
+        }
+    }
+}
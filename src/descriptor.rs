@@ -0,0 +1,209 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::ops::Range;
+
+use crate::*;
+
+/// The descriptor infos a pending write takes its descriptors from, as a range of the batch's
+/// storage for that kind of info.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum WriteInfos {
+    Image(Range<usize>),
+    Buffer(Range<usize>),
+    TexelBuffer(Range<usize>),
+}
+
+impl WriteInfos {
+    fn len(&self) -> usize {
+        match self {
+            WriteInfos::Image(range) | WriteInfos::Buffer(range) | WriteInfos::TexelBuffer(range) => range.len(),
+        }
+    }
+
+    /// Extend the range by `count` infos if `next` directly follows it in the same storage.
+    fn try_extend(&mut self, next: &WriteInfos) -> bool {
+        match (self, next) {
+            (WriteInfos::Image(range), WriteInfos::Image(next))
+            | (WriteInfos::Buffer(range), WriteInfos::Buffer(next))
+            | (WriteInfos::TexelBuffer(range), WriteInfos::TexelBuffer(next))
+                if range.end == next.start =>
+            {
+                range.end = next.end;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A descriptor write which has not been submitted to the driver yet.
+#[derive(Clone, Debug)]
+struct PendingWrite {
+    set: vk::DescriptorSet,
+    binding: u32,
+    array_element: u32,
+    descriptor_type: vk::DescriptorType,
+    infos: WriteInfos,
+}
+
+/// Descriptor writes collected by the Device, which are made in a single
+/// `vkUpdateDescriptorSets` call by `Device::flush_descriptor_writes`.
+#[derive(Debug, Default)]
+pub(crate) struct DescriptorWriteBatch {
+    writes: Vec<PendingWrite>,
+    image_infos: Vec<vk::DescriptorImageInfo>,
+    buffer_infos: Vec<vk::DescriptorBufferInfo>,
+    texel_buffer_views: Vec<vk::BufferView>,
+}
+
+impl DescriptorWriteBatch {
+    /// Add a write, merging it into the previous one if it continues the same binding's array.
+    fn push(&mut self, write: PendingWrite) {
+        if let Some(last) = self.writes.last_mut() {
+            if last.set == write.set
+                && last.binding == write.binding
+                && last.descriptor_type == write.descriptor_type
+                && last.array_element + last.infos.len() as u32 == write.array_element
+                && last.infos.try_extend(&write.infos)
+            {
+                return;
+            }
+        }
+        self.writes.push(write);
+    }
+
+    fn clear(&mut self) {
+        self.writes.clear();
+        self.image_infos.clear();
+        self.buffer_infos.clear();
+        self.texel_buffer_views.clear();
+    }
+}
+
+impl Device {
+    /// Write `image_infos` to consecutive array elements of a binding of `set`, starting at
+    /// `array_element`.
+    ///
+    /// The write is batched with all other descriptor writes until `flush_descriptor_writes`,
+    /// which `CommandBuffer::dispatch_with` calls before binding descriptor sets. Sets bound
+    /// any other way must be flushed before they are bound.
+    pub fn write_image_descriptors(
+        &self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        descriptor_type: vk::DescriptorType,
+        image_infos: &[vk::DescriptorImageInfo],
+    ) {
+        if image_infos.is_empty() {
+            return;
+        }
+
+        let mut batch = self.descriptor_writes.lock();
+        let start = batch.image_infos.len();
+        batch.image_infos.extend_from_slice(image_infos);
+        let infos = WriteInfos::Image(start..batch.image_infos.len());
+        batch.push(PendingWrite {
+            set,
+            binding,
+            array_element,
+            descriptor_type,
+            infos,
+        });
+    }
+
+    /// Write `buffer_infos` to consecutive array elements of a binding of `set`, starting at
+    /// `array_element`. Batched like `write_image_descriptors`.
+    pub fn write_buffer_descriptors(
+        &self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        descriptor_type: vk::DescriptorType,
+        buffer_infos: &[vk::DescriptorBufferInfo],
+    ) {
+        if buffer_infos.is_empty() {
+            return;
+        }
+
+        let mut batch = self.descriptor_writes.lock();
+        let start = batch.buffer_infos.len();
+        batch.buffer_infos.extend_from_slice(buffer_infos);
+        let infos = WriteInfos::Buffer(start..batch.buffer_infos.len());
+        batch.push(PendingWrite {
+            set,
+            binding,
+            array_element,
+            descriptor_type,
+            infos,
+        });
+    }
+
+    /// Write `views` to consecutive array elements of a texel buffer binding of `set`, starting
+    /// at `array_element`. Batched like `write_image_descriptors`.
+    pub fn write_texel_buffer_descriptors(
+        &self,
+        set: vk::DescriptorSet,
+        binding: u32,
+        array_element: u32,
+        descriptor_type: vk::DescriptorType,
+        views: &[vk::BufferView],
+    ) {
+        if views.is_empty() {
+            return;
+        }
+
+        let mut batch = self.descriptor_writes.lock();
+        let start = batch.texel_buffer_views.len();
+        batch.texel_buffer_views.extend_from_slice(views);
+        let infos = WriteInfos::TexelBuffer(start..batch.texel_buffer_views.len());
+        batch.push(PendingWrite {
+            set,
+            binding,
+            array_element,
+            descriptor_type,
+            infos,
+        });
+    }
+
+    /// Get the number of `vk::WriteDescriptorSet`s the next `flush_descriptor_writes` will make,
+    /// after writes to consecutive array elements have been merged.
+    pub fn pending_descriptor_writes(&self) -> usize {
+        self.descriptor_writes.lock().writes.len()
+    }
+
+    /// Make every batched descriptor write in a single `vkUpdateDescriptorSets` call.
+    ///
+    /// Writes are made in the order they were batched, so a later write to a descriptor
+    /// overrides an earlier one.
+    pub fn flush_descriptor_writes(&self) {
+        let mut batch = self.descriptor_writes.lock();
+        if batch.writes.is_empty() {
+            return;
+        }
+
+        let writes = batch
+            .writes
+            .iter()
+            .map(|write| {
+                let info = vk::WriteDescriptorSet::builder()
+                    .dst_set(write.set)
+                    .dst_binding(write.binding)
+                    .dst_array_element(write.array_element)
+                    .descriptor_type(write.descriptor_type);
+                match write.infos {
+                    WriteInfos::Image(ref range) => info.image_info(&batch.image_infos[range.clone()]),
+                    WriteInfos::Buffer(ref range) => info.buffer_info(&batch.buffer_infos[range.clone()]),
+                    WriteInfos::TexelBuffer(ref range) => {
+                        info.texel_buffer_view(&batch.texel_buffer_views[range.clone()])
+                    }
+                }
+                .build()
+            })
+            .collect::<Vec<_>>();
+
+        unsafe { self.device.update_descriptor_sets(&writes, &[]) };
+        batch.clear();
+    }
+}
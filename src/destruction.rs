@@ -0,0 +1,60 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use parking_lot::Mutex;
+
+use std::sync::{Arc, Weak};
+
+use crate::*;
+
+/// Raw handles of resources which were dropped rather than destroyed through the Device, waiting
+/// to be handed to the frame during which they were dropped.
+#[derive(Debug, Default)]
+pub(crate) struct DestructionQueue {
+    image_views: Mutex<Vec<vk::ImageView>>,
+}
+
+/// A weak handle to a Device's `DestructionQueue`, held by resources which can't hold an
+/// `Arc<Device>`, so that dropping them defers their destruction instead of leaking or
+/// panicking. It doesn't keep the Device alive.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct DestructionSender(Weak<DestructionQueue>);
+
+impl DestructionSender {
+    /// Queue `views` to be destroyed once the current frame has completed. Returns `false` if
+    /// the Device is gone, in which case the views are leaked.
+    pub(crate) fn destroy_image_views<I>(&self, views: I) -> bool
+    where
+        I: IntoIterator<Item = vk::ImageView>,
+    {
+        match self.0.upgrade() {
+            Some(queue) => {
+                queue.image_views.lock().extend(views);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Device {
+    /// Get a sender which resources use to queue their raw handles for destruction when they
+    /// are dropped.
+    pub(crate) fn destruction_sender(&self) -> DestructionSender {
+        DestructionSender(Arc::downgrade(&self.destruction_queue))
+    }
+
+    /// Move the handles dropped since the last call into `frame`, the frame during which they
+    /// were dropped, so that they are destroyed once it has completed.
+    pub(crate) fn collect_dropped_resources(&self, frame: &mut PerFrame) {
+        let image_views = std::mem::take(&mut *self.destruction_queue.image_views.lock());
+        frame.dropped_image_views.extend(image_views);
+    }
+
+    /// Destroy the handles which were dropped during a frame which has completed.
+    pub(crate) fn destroy_dropped_resources(&self, frame: &mut PerFrame) {
+        for view in frame.dropped_image_views.drain(..) {
+            unsafe { self.device.destroy_image_view(view, None) };
+        }
+    }
+}
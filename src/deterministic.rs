@@ -0,0 +1,71 @@
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+
+use crate::*;
+
+/// A `HashMap` whose iteration order is reproducible across runs when the Device is in
+/// deterministic mode, for maps whose iteration order decides the order resources are freed or
+/// reported in.
+pub(crate) type ReplayHashMap<K, V> = HashMap<K, V, ReplayHasher>;
+
+/// The hasher of a `ReplayHashMap`: randomly seeded per map as usual, or with fixed keys in
+/// deterministic mode.
+#[derive(Clone, Debug)]
+pub(crate) enum ReplayHasher {
+    Random(RandomState),
+    Fixed,
+}
+
+impl ReplayHasher {
+    pub(crate) fn new(deterministic: bool) -> Self {
+        if deterministic {
+            ReplayHasher::Fixed
+        } else {
+            ReplayHasher::Random(RandomState::new())
+        }
+    }
+}
+
+impl BuildHasher for ReplayHasher {
+    type Hasher = DefaultHasher;
+
+    fn build_hasher(&self) -> DefaultHasher {
+        match self {
+            ReplayHasher::Random(state) => state.build_hasher(),
+            ReplayHasher::Fixed => DefaultHasher::new(),
+        }
+    }
+}
+
+impl Device {
+    /// Whether the Device was built in deterministic mode with `DeviceBuilder::deterministic`.
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic_thread.is_some()
+    }
+
+    /// Create an empty `ReplayHashMap` seeded according to the Device's mode.
+    pub(crate) fn replay_hash_map<K, V>(&self) -> ReplayHashMap<K, V> {
+        HashMap::with_hasher(ReplayHasher::new(self.is_deterministic()))
+    }
+
+    /// Report an invariant failure if the Device is in deterministic mode and `operation`,
+    /// which assigns handles or orders submissions, is called from a thread other than the one
+    /// which built the Device, since the interleaving of threads isn't reproducible.
+    pub(crate) fn check_deterministic_thread(&self, operation: &str) {
+        if let Some(thread) = self.deterministic_thread {
+            let current = std::thread::current();
+            if current.id() != thread {
+                self.invariant_failed(
+                    None,
+                    format!(
+                        "{} called from thread {:?} in deterministic mode, which must only be used \
+                         from the thread which built the Device",
+                        operation,
+                        current.name().unwrap_or("<unnamed>"),
+                    ),
+                );
+            }
+        }
+    }
+}
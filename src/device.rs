@@ -1,52 +1,275 @@
+use ash::extensions::{ext, khr};
+use ash::prelude::VkResult;
+use ash::version::{DeviceV1_0, InstanceV1_0};
 use ash::vk;
 
+use bytemuck::Pod;
+
 use parking_lot::*;
 
+use std::collections::HashMap;
 use std::ops::{Deref};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
 use std::sync::Arc;
+use std::thread::ThreadId;
 
 use crate::*;
+use crate::cache_stats::{CacheCounters, SetLayoutCounts};
+use crate::descriptor::DescriptorWriteBatch;
+use crate::destruction::DestructionQueue;
+use crate::deterministic::ReplayHashMap;
+use crate::device_address::{shader_device_address_usage, BufferDeviceAddressFn};
+use crate::dynamic_rendering::DynamicRenderingFn;
+use crate::gpu_assert::GpuAsserts;
+use crate::memory_stats::{MemoryPoolKey, MemoryWatermarks};
+use crate::profiling::{GpuProfiler, GpuZone};
+use crate::screenshot::ScreenshotQueue;
+use crate::synchronization2::Synchronization2Fn;
+use crate::timeline::TimelineSemaphoreFn;
+#[cfg(feature = "raytracing")]
+use crate::raytracing::RayTracingFn;
+use crate::format::{format_block_dim, format_layer_size, format_to_aspect_mask};
+
+mod builder;
+pub use builder::*;
+
+#[derive(Default)]
+pub(crate) struct PerFrame {
+    /// Command pools of each queue type, one per thread which has requested a command buffer
+    /// for the type during this frame slot.
+    pub(crate) graphics_cmd_pools: HashMap<ThreadId, CommandPool>,
+    pub(crate) compute_cmd_pools: HashMap<ThreadId, CommandPool>,
+    pub(crate) transfer_cmd_pools: HashMap<ThreadId, CommandPool>,
+    /// Command pools of each queue type, indexed like `pending_submits`, one per job of the
+    /// largest `record_secondary_parallel` during this frame slot.
+    pub(crate) secondary_cmd_pools: [Vec<CommandPool>; 3],
+
+    pub(crate) used_vbo_blocks: Vec<BufferBlockHandle>,
+    pub(crate) used_ibo_blocks: Vec<BufferBlockHandle>,
+    pub(crate) used_ubo_blocks: Vec<BufferBlockHandle>,
+    pub(crate) used_indirect_blocks: Vec<BufferBlockHandle>,
+    pub(crate) used_staging_blocks: Vec<BufferBlockHandle>,
+
+    /// Fences which are signalled once all work submitted during this frame has completed.
+    pub(crate) fences: Vec<vk::Fence>,
+    /// How many of `fences` have been submitted and must be waited on before the frame's
+    /// resources can be reused.
+    pub(crate) submitted_fences: usize,
+    /// Semaphores waited on by submissions of this frame, which can be reused once it has
+    /// completed.
+    pub(crate) used_semaphores: Vec<vk::Semaphore>,
+    /// Pooled fences released during this frame, which are reset and reused once it has
+    /// completed.
+    pub(crate) released_fences: Vec<vk::Fence>,
+    /// Semaphores dropped during this frame, which are destroyed once it has completed.
+    pub(crate) destroyed_semaphores: Vec<vk::Semaphore>,
+    /// Scratch storage images handed out during this frame, which return to the pool once it
+    /// has completed.
+    pub(crate) used_scratch_images: Vec<(ScratchImageDesc, ImageHandle)>,
+
+    /// Resources destroyed during this frame, which are freed once the frame has completed.
+    pub(crate) destroyed_buffers: Vec<BufferHandle>,
+    pub(crate) destroyed_buffer_views: Vec<BufferViewHandle>,
+    pub(crate) destroyed_images: Vec<ImageHandle>,
+    pub(crate) destroyed_image_views: Vec<ImageViewHandle>,
+    /// Raw image views of `ImageView`s dropped during this frame, which are destroyed once it
+    /// has completed.
+    pub(crate) dropped_image_views: Vec<vk::ImageView>,
+    /// The old buffers replaced by `orphan_buffer` during this frame, which are dropped once it
+    /// has completed.
+    pub(crate) orphaned_buffers: Vec<Buffer>,
+    #[cfg(feature = "raytracing")]
+    pub(crate) destroyed_acceleration_structures: Vec<AccelerationStructureHandle>,
+
+    /// The labels of the draws given slots of the frame's GPU assertion buffer, by slot.
+    pub(crate) gpu_assert_labels: Vec<String>,
+    /// Pools of the descriptor sets used by image conversions recorded during this frame, which
+    /// are reset once it has completed.
+    pub(crate) conversion_descriptor_pools: Vec<vk::DescriptorPool>,
+    /// Memory unbound from the pages of sparse images during this frame, which is freed once
+    /// it has completed.
+    pub(crate) freed_sparse_pages: Vec<(Allocation, Option<Tag>)>,
+    /// The GPU zones begun during this frame, whose timestamps are read once it has completed.
+    pub(crate) gpu_zones: Vec<GpuZone>,
+}
 
-struct PerFrame {
-    graphics_cmd_pools: Vec<CommandPool>,
-    compute_cmd_pools: Vec<CommandPool>,
-    transfer_cmd_pools: Vec<CommandPool>,
-
-    used_vbo_blocks: Vec<BufferBlockHandle>,
-    used_ibo_blocks: Vec<BufferBlockHandle>,
-    used_ubo_blocks: Vec<BufferBlockHandle>,
-    used_staging_blocks: Vec<BufferBlockHandle>,
+impl PerFrame {
+    /// Get the command pools used for a type of queue.
+    pub(crate) fn cmd_pools_mut(&mut self, queue_type: QueueType) -> &mut HashMap<ThreadId, CommandPool> {
+        match queue_type {
+            QueueType::Graphics => &mut self.graphics_cmd_pools,
+            QueueType::Compute => &mut self.compute_cmd_pools,
+            QueueType::AsyncTransfer => &mut self.transfer_cmd_pools,
+        }
+    }
 }
 
 /// The Device. Owns and manages resources, submission, etc.
+///
+/// Create one with a `DeviceBuilder`.
 pub struct Device {
-    instance: ash::Instance,
-    physical_device: vk::PhysicalDevice,
-    device: ash::Device,
-    allocator: vk_mem::Allocator,
-
-    graphics_queue: vk::Queue,
-    graphics_queue_family_index: u32,
-    compute_queue: vk::Queue,
-    compute_queue_family_index: u32,
-    transfer_queue: vk::Queue,
-    transfer_queue_family_index: u32,
-    multiple_queue_families: bool,
-
-    memory_properties: vk::PhysicalDeviceMemoryProperties,
-    device_properties: vk::PhysicalDeviceProperties,
-
-    resources: RwLock<ResourceSet>,
-    blocks: RwLock<BufferBlockSet>,
-
-    per_frame: Vec<RwLock<PerFrame>>,
-    current_frame_index: usize,
-    vbo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
-    ibo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
-    ubo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
+    pub(crate) entry: ash::Entry,
+    pub(crate) instance: ash::Instance,
+    /// The instance extensions enabled, which surfaces for windows need.
+    #[cfg(feature = "raw-window-handle")]
+    pub(crate) instance_extensions: Vec<&'static std::ffi::CStr>,
+    pub(crate) physical_device: vk::PhysicalDevice,
+    pub(crate) device: ash::Device,
+    pub(crate) allocator: Box<dyn MemoryAllocator>,
+
+    pub(crate) graphics_queue: vk::Queue,
+    pub(crate) graphics_queue_family_index: u32,
+    pub(crate) compute_queue: vk::Queue,
+    pub(crate) compute_queue_family_index: u32,
+    pub(crate) transfer_queue: vk::Queue,
+    pub(crate) transfer_queue_family_index: u32,
+    pub(crate) multiple_queue_families: bool,
+    /// The global priority granted to each queue, indexed like `pending_submits`.
+    pub(crate) queue_priorities: [QueuePriority; 3],
+    /// Locks serializing access to each queue, indexed like `pending_submits`. Queue types which
+    /// share a queue use the lock of the first of them, see `Device::lock_queue`.
+    pub(crate) queue_locks: [Mutex<()>; 3],
+
+    pub(crate) api_version: u32,
+    pub(crate) memory_properties: vk::PhysicalDeviceMemoryProperties,
+    pub(crate) device_properties: vk::PhysicalDeviceProperties,
+    /// The core features enabled for the device.
+    pub(crate) enabled_features: vk::PhysicalDeviceFeatures,
+
+    pub(crate) pipeline_cache: vk::PipelineCache,
+    pub(crate) pipeline_cache_path: Option<PathBuf>,
+    pub(crate) pipelines: RwLock<PipelineCache>,
+    pub(crate) render_passes: RwLock<RenderPassCache>,
+    pub(crate) samplers: RwLock<SamplerCache>,
+
+    pub(crate) surface_loader: khr::Surface,
+    pub(crate) swapchain_loader: khr::Swapchain,
+    pub(crate) swapchain: Mutex<Option<Swapchain>>,
+    /// The create info of the swapchain torn down by `suspend_presentation`, while suspended.
+    pub(crate) suspended_swapchain: Mutex<Option<SwapchainCreateInfo>>,
+    pub(crate) display_timing: Option<vk::GoogleDisplayTimingFn>,
+    /// `VK_EXT_debug_utils`, if supported by the instance.
+    pub(crate) debug_utils: Option<ext::DebugUtils>,
+    /// `VK_EXT_external_memory_host` and its `minImportedHostPointerAlignment`, if supported.
+    pub(crate) external_memory_host: Option<(vk::ExtExternalMemoryHostFn, vk::DeviceSize)>,
+    /// Timeline semaphores, through Vulkan 1.2 or `VK_KHR_timeline_semaphore`, if supported.
+    pub(crate) timeline_semaphore: Option<TimelineSemaphoreFn>,
+    /// Dynamic rendering, through Vulkan 1.3 or `VK_KHR_dynamic_rendering`, if supported.
+    pub(crate) dynamic_rendering: Option<DynamicRenderingFn>,
+    /// Synchronization2, through Vulkan 1.3 or `VK_KHR_synchronization2`, if supported.
+    pub(crate) synchronization2: Option<Synchronization2Fn>,
+    /// `VK_KHR_buffer_device_address`, if supported.
+    pub(crate) buffer_device_address: Option<BufferDeviceAddressFn>,
+    /// `VK_KHR_acceleration_structure`, if ray tracing is supported.
+    #[cfg(feature = "raytracing")]
+    pub(crate) ray_tracing: Option<RayTracingFn>,
+    /// The queue created for video decoding, if any.
+    #[cfg(feature = "video")]
+    pub(crate) video_decode_queue: Option<VideoQueue>,
+    /// The queue created for video encoding, if any.
+    #[cfg(feature = "video")]
+    pub(crate) video_encode_queue: Option<VideoQueue>,
+    /// Whether `VK_EXT_custom_border_color` is enabled.
+    pub(crate) custom_border_color: bool,
+    /// Whether `VK_EXT_shader_viewport_index_layer` is enabled.
+    pub(crate) shader_viewport_index_layer: bool,
+    /// Whether `VK_EXT_memory_budget` is enabled.
+    pub(crate) memory_budget: bool,
+    /// The features of `VK_KHR_portability_subset`, if the device is a portability implementation.
+    pub(crate) portability_subset: Option<PortabilitySubset>,
+    /// The optional capabilities the Device's `Tier` is computed from.
+    pub(crate) tier_report: TierReport,
+    /// Whether the Device was built without presentation.
+    pub(crate) headless: bool,
+
+    /// The id the `Frame`s this Device begins carry, unique within the process.
+    pub(crate) id: u64,
+
+    pub(crate) resources: RwLock<ResourceSet>,
+    pub(crate) blocks: RwLock<Option<BufferBlockSet>>,
+
+    pub(crate) per_frame: Vec<RwLock<PerFrame>>,
+    pub(crate) current_frame_index: AtomicUsize,
+    /// Whether a frame has begun and its `Frame` has not yet been ended.
+    pub(crate) frame_active: AtomicBool,
+    pub(crate) vbo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
+    pub(crate) ibo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
+    pub(crate) ubo_upload_queue: RwLock<Vec<BufferBlockHandle>>,
+    pub(crate) indirect_upload_queue: RwLock<Vec<BufferBlockHandle>>,
+    /// Batches waiting to be submitted, indexed by queue type.
+    pub(crate) pending_submits: [Mutex<Vec<PendingSubmit>>; 3],
+    /// Raw handles of resources dropped since the last `begin_frame`.
+    pub(crate) destruction_queue: Arc<DestructionQueue>,
+    /// Semaphores signaled by staged uploads, which the next submission to each queue type waits
+    /// on, indexed by queue type.
+    pub(crate) pending_upload_semaphores: [Mutex<Vec<vk::Semaphore>>; 3],
+    /// Descriptor writes waiting for `flush_descriptor_writes`.
+    pub(crate) descriptor_writes: Mutex<DescriptorWriteBatch>,
+    /// Unsignaled semaphores available for reuse.
+    pub(crate) semaphore_pool: Mutex<Vec<vk::Semaphore>>,
+    /// Unsignaled fences available for reuse.
+    pub(crate) fence_pool: Mutex<Vec<vk::Fence>>,
+    /// Scratch storage images not in use by any frame, by description.
+    pub(crate) scratch_images: Mutex<ReplayHashMap<ScratchImageDesc, Vec<ImageHandle>>>,
+    /// The pipelines of `CommandBuffer::convert_image`, by the format they write.
+    pub(crate) conversion_pipelines: Mutex<HashMap<vk::Format, ConversionPipeline>>,
+    /// The render scale, upscale filter and render target of dynamic resolution scaling.
+    pub(crate) render_scale: Mutex<RenderScaleState>,
+    /// The sink resource access events are passed to, if one is installed.
+    pub(crate) resource_event_sink: RwLock<Option<Arc<dyn ResourceEventSink>>>,
+    /// The id the next batch submitted while a resource event sink is installed gets.
+    pub(crate) next_submission_id: AtomicU64,
+    /// The watermarks memory usage is checked against in `begin_frame`.
+    pub(crate) memory_watermarks: Mutex<MemoryWatermarks>,
+    /// The bytes allocated for resources, by category and tag.
+    pub(crate) memory_pools: Mutex<ReplayHashMap<MemoryPoolKey, vk::DeviceSize>>,
+    /// How many frames recycled buffer blocks are kept unused before being freed, if at all.
+    pub(crate) block_trim_frames: Option<usize>,
+    /// The reserved descriptor set and buffers for GPU assertions, if they are enabled.
+    pub(crate) gpu_asserts: Option<GpuAsserts>,
+    /// The function failed GPU assertions are passed to, if one is installed.
+    pub(crate) gpu_assert_handler: RwLock<Option<GpuAssertHandler>>,
+    /// The timestamp query pools of GPU zones, if profiling is enabled.
+    pub(crate) gpu_profiler: Option<GpuProfiler>,
+    /// The zone timings of the last frame read back, until taken by `collect_gpu_timings`.
+    pub(crate) gpu_timings: Mutex<Option<Vec<GpuZoneTiming>>>,
+    /// The cache hits and misses of the frame being recorded.
+    pub(crate) cache_counters: CacheCounters,
+    /// The cache hits and misses of the last frame, returned by `cache_stats`.
+    pub(crate) last_cache_stats: Mutex<CacheStats>,
+    /// The descriptor set layouts made by `create_shader_layout` which are alive, by bindings.
+    pub(crate) set_layout_counts: Mutex<SetLayoutCounts>,
+    /// The screenshots queued with `queue_screenshot` and the captures in flight.
+    pub(crate) screenshots: Mutex<ScreenshotQueue>,
+    /// The uploads queued with `queue_buffer_upload` and `queue_image_upload`.
+    pub(crate) uploads: Mutex<UploadQueue>,
+    /// The thread which built the Device, if it is in deterministic mode.
+    pub(crate) deterministic_thread: Option<ThreadId>,
+
+    pub(crate) invariant_policy: RwLock<InvariantPolicy>,
 }
 
 impl Device {
+    /// Set the policy used when `hot` detects a recoverable internal invariant failure, such
+    /// as a resource erroring on destruction.
+    pub fn set_invariant_policy(&self, policy: InvariantPolicy) {
+        *self.invariant_policy.write() = policy;
+    }
+
+    /// Get the policy used when `hot` detects a recoverable internal invariant failure.
+    pub fn invariant_policy(&self) -> InvariantPolicy {
+        self.invariant_policy.read().clone()
+    }
+
+    /// Report a recoverable internal invariant failure, which will be handled according to
+    /// the current `InvariantPolicy`.
+    pub(crate) fn invariant_failed<S: Into<String>>(&self, tag: Option<&Tag>, message: S) {
+        // Clone the policy out so that a callback is free to change it.
+        let policy = self.invariant_policy();
+        policy.handle(InvariantFailure::new(tag.cloned(), message));
+    }
+
     /// Acquire a read-only handle to this device's ResourceSet.
     pub fn resources(&self) -> RwLockReadGuard<'_, ResourceSet> {
         self.resources.read()
@@ -58,88 +281,83 @@ impl Device {
     }
 
     /// Acquire a read-only handle to this device's `BufferBlockSet`
-    pub fn buffer_blocks(&self) -> RwLockReadGuard<'_, BufferBlockSet> {
-        self.blocks.read()
+    pub fn buffer_blocks(&self) -> MappedRwLockReadGuard<'_, BufferBlockSet> {
+        RwLockReadGuard::map(self.blocks.read(), |blocks| {
+            blocks.as_ref().expect("Device buffer blocks were not initialized")
+        })
     }
 
     /// Acquire a writable handle to this device's `BufferBlockSet`
-    pub fn buffer_blocks_mut(&self) -> RwLockWriteGuard<'_ , BufferBlockSet> {
-        self.blocks.write()
+    pub fn buffer_blocks_mut(&self) -> MappedRwLockWriteGuard<'_ , BufferBlockSet> {
+        RwLockWriteGuard::map(self.blocks.write(), |blocks| {
+            blocks.as_mut().expect("Device buffer blocks were not initialized")
+        })
     }
 
     /// Request a BufferBlock which will allocate buffers that may be used as vertex buffers.
     ///
     /// The BufferBlock will be automatically recycled or destroyed the next time this frame
-    /// begins, and any data written to it will be automatically uploaded upon the next
-    /// graphics or compute submission.
+    /// begins. If it isn't host visible, the data written to it is automatically uploaded on
+    /// the `AsyncTransfer` queue when the graphics queue is next flushed, e.g. by `end_frame`,
+    /// and the graphics submissions wait for the upload.
     pub fn request_vertex_block(
         &self,
+        frame: &Frame,
         size: usize,
         tag: Option<Tag>
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
-        let pool = &mut self.buffer_blocks_mut().vbo_pool;
-
-        let handle = pool.request_block(size, tag)?;
-
-        self.per_frame[self.current_frame_index].write().used_vbo_blocks.push(handle);
-
-        let block = pool.get_block(handle).unwrap();
-
-        if block.requires_upload() {
-            self.vbo_upload_queue.write().push(handle);
-        }
-
-        Ok(handle)
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_block(PoolKind::Vertex, size, tag)
     }
 
     /// Request a BufferBlock which will allocate buffers that may be used as index buffers.
     ///
     /// The BufferBlock will be automatically recycled or destroyed the next time this frame
-    /// begins, and any data written to it will be automatically uploaded upon the next
-    /// graphics or compute submission.
+    /// begins. If it isn't host visible, the data written to it is automatically uploaded on
+    /// the `AsyncTransfer` queue when the graphics queue is next flushed, e.g. by `end_frame`,
+    /// and the graphics submissions wait for the upload.
     pub fn request_index_block(
         &self,
+        frame: &Frame,
         size: usize,
         tag: Option<Tag>
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
-        let pool = &mut self.buffer_blocks_mut().ibo_pool;
-
-        let handle = pool.request_block(size, tag)?;
-
-        self.per_frame[self.current_frame_index].write().used_ibo_blocks.push(handle);
-
-        let block = pool.get_block(handle).unwrap();
-
-        if block.requires_upload() {
-            self.ibo_upload_queue.write().push(handle);
-        }
-
-        Ok(handle)
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_block(PoolKind::Index, size, tag)
     }
 
     /// Request a BufferBlock which will allocate buffers that may be used as uniform buffers.
     ///
     /// The BufferBlock will be automatically recycled or destroyed the next time this frame
-    /// begins, and any data written to it will be automatically uploaded upon the next
-    /// graphics or compute submission.
+    /// begins. If it isn't host visible, the data written to it is automatically uploaded on
+    /// the `AsyncTransfer` queue when the graphics queue is next flushed, e.g. by `end_frame`,
+    /// and the graphics submissions wait for the upload.
     pub fn request_uniform_block(
         &self,
+        frame: &Frame,
         size: usize,
         tag: Option<Tag>
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
-        let pool = &mut self.buffer_blocks_mut().ubo_pool;
-
-        let handle = pool.request_block(size, tag)?;
-
-        self.per_frame[self.current_frame_index].write().used_ubo_blocks.push(handle);
-
-        let block = pool.get_block(handle).unwrap();
-
-        if block.requires_upload() {
-            self.ubo_upload_queue.write().push(handle);
-        }
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_block(PoolKind::Uniform, size, tag)
+    }
 
-        Ok(handle)
+    /// Request a BufferBlock which will allocate buffers that may be used as indirect buffers,
+    /// e.g. for `CommandBuffer::dispatch_indirect`. They may also be used as storage buffers,
+    /// so that compute shaders can write the commands.
+    ///
+    /// The BufferBlock will be automatically recycled or destroyed the next time this frame
+    /// begins. If it isn't host visible, the data written to it is automatically uploaded on
+    /// the `AsyncTransfer` queue when the graphics queue is next flushed, e.g. by `end_frame`,
+    /// and the graphics submissions wait for the upload.
+    pub fn request_indirect_block(
+        &self,
+        frame: &Frame,
+        size: usize,
+        tag: Option<Tag>
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_block(PoolKind::Indirect, size, tag)
     }
 
     /// Request a BufferBlock which will allocate buffers that may be used as staging buffers,
@@ -147,22 +365,87 @@ impl Device {
     /// to a persistent GPU side buffer or image.
     ///
     /// The BufferBlock will be automatically recycled or destroyed the next time this frame
-    /// begins, but it **will not** automatically be synchronized. Use the `Device::submit_staging`
-    /// method to aid in this regard.
+    /// begins, but it **will not** automatically be synchronized. Record copies out of it on the
+    /// `AsyncTransfer` queue and submit them with `Device::submit_staging`.
     pub fn request_staging_block(
         &self,
+        frame: &Frame,
         size: usize,
         tag: Option<Tag>
-    ) -> Result<BufferBlockHandle, vk_mem::Error> {
-        let handle = self.buffer_blocks_mut().staging_pool.request_block(size, tag)?;
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_block(PoolKind::Staging, size, tag)
+    }
+
+    /// Request a BufferBlock from the pool of `kind` for the current frame without a `Frame`,
+    /// for work hot records itself, such as uploads, which may happen outside of a frame.
+    pub(crate) fn request_internal_block(
+        &self,
+        kind: PoolKind,
+        size: usize,
+        tag: Option<Tag>
+    ) -> Result<BufferBlockHandle, AllocatorError> {
+        let mut blocks = self.buffer_blocks_mut();
+        let pool = blocks.pool_mut(kind);
+
+        let handle = pool.request_block(size, tag)?;
+
+        let mut frame = self.current_frame().write();
+        let (used_blocks, upload_queue) = match kind {
+            PoolKind::Vertex => (&mut frame.used_vbo_blocks, Some(&self.vbo_upload_queue)),
+            PoolKind::Index => (&mut frame.used_ibo_blocks, Some(&self.ibo_upload_queue)),
+            PoolKind::Uniform => (&mut frame.used_ubo_blocks, Some(&self.ubo_upload_queue)),
+            PoolKind::Indirect => (&mut frame.used_indirect_blocks, Some(&self.indirect_upload_queue)),
+            PoolKind::Staging => (&mut frame.used_staging_blocks, None),
+        };
+        used_blocks.push(handle);
+
+        let block = pool.get_block(handle).unwrap();
+
+        if let Some(upload_queue) = upload_queue {
+            if block.requires_upload() {
+                upload_queue.write().push(handle);
+            }
+        }
 
-        self.per_frame[self.current_frame_index].write().used_staging_blocks.push(handle);
         Ok(handle)
     }
 
-    /// Get the raw `vk_mem::Allocator`.
-    pub fn raw_allocator(&self) -> &vk_mem::Allocator {
-        &self.allocator
+    /// Allocate blocks for the pool of `kind` up front, until at least `count` recycled blocks
+    /// are ready to be reused, so that the first frames requesting them don't stall on
+    /// allocating memory. Meant to be called during load screens.
+    ///
+    /// A good `count` is the `BlockPoolStats::recommended_prewarm_count` of the stats returned
+    /// by `block_pool_stats` at the end of a previous run.
+    pub fn prewarm_block_pools(&self, kind: PoolKind, count: usize) -> Result<(), AllocatorError> {
+        self.buffer_blocks_mut()
+            .pool_mut(kind)
+            .prewarm(count, Some(Tag::Static("prewarmed block")))
+    }
+
+    /// Get statistics about the blocks of the pool of `kind`.
+    pub fn block_pool_stats(&self, kind: PoolKind) -> BlockPoolStats {
+        self.buffer_blocks().pool(kind).stats()
+    }
+
+    /// Get the raw `ash::Entry`.
+    pub fn raw_entry(&self) -> &ash::Entry {
+        &self.entry
+    }
+
+    /// Get the raw `ash::Instance`.
+    pub fn raw_instance(&self) -> &ash::Instance {
+        &self.instance
+    }
+
+    /// Get the raw `vk::PhysicalDevice`.
+    pub fn raw_physical_device(&self) -> vk::PhysicalDevice {
+        self.physical_device
+    }
+
+    /// Get the allocator all of the Device's memory is allocated with.
+    pub fn allocator(&self) -> &dyn MemoryAllocator {
+        &*self.allocator
     }
 
     /// Get the raw `ash::Device`.
@@ -170,6 +453,22 @@ impl Device {
         &self.device
     }
 
+    /// Get the Vulkan version this Device uses, as made by `ash::vk_make_version!` with a patch
+    /// version of 0: the lowest of the loader's, the physical device's and `MAX_API_VERSION`.
+    ///
+    /// Features which need a newer version are reported as unsupported instead of being used,
+    /// e.g. `host_import_alignment` is `None` before 1.1. Those promoted to core, like timeline
+    /// semaphores in 1.2 and dynamic rendering and synchronization2 in 1.3, are used through the
+    /// core version when it is available and through their extensions otherwise.
+    pub fn api_version(&self) -> u32 {
+        self.api_version
+    }
+
+    /// Get whether the Device was built `headless`, i.e. without presentation.
+    pub fn is_headless(&self) -> bool {
+        self.headless
+    }
+
     /// Get the `vk::PhysicalDeviceMemoryProperties` for the physical device of this Device.
     pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
         &self.memory_properties
@@ -180,6 +479,47 @@ impl Device {
         &self.device_properties
     }
 
+    /// Get the `vk::FormatProperties` of `format` for the physical device of this Device.
+    pub fn format_properties(&self, format: vk::Format) -> vk::FormatProperties {
+        unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
+        }
+    }
+
+    /// Get whether images of `format` with `tiling` support all of `features`.
+    ///
+    /// The features an image's usage needs are given by `image_usage_to_features`, and e.g.
+    /// `BLIT_SRC | BLIT_DST` tells whether `MiscImageFlags::GENERATE_MIPS` can blit the image's
+    /// levels.
+    pub fn is_format_supported(
+        &self,
+        format: vk::Format,
+        tiling: vk::ImageTiling,
+        features: vk::FormatFeatureFlags,
+    ) -> bool {
+        let properties = self.format_properties(format);
+        let supported = match tiling {
+            vk::ImageTiling::LINEAR => properties.linear_tiling_features,
+            _ => properties.optimal_tiling_features,
+        };
+        supported.contains(features)
+    }
+
+    /// Get the first of `candidates`, in order of preference, whose `tiling` supports all of
+    /// `features`, e.g. to choose a depth format or the compressed format to transcode into.
+    pub fn find_supported_format(
+        &self,
+        candidates: &[vk::Format],
+        tiling: vk::ImageTiling,
+        features: vk::FormatFeatureFlags,
+    ) -> Option<vk::Format> {
+        candidates
+            .iter()
+            .copied()
+            .find(|&format| self.is_format_supported(format, tiling, features))
+    }
+
     /// Find whether a certain memory type index is visible to the cpu, i.e. able to be mapped.
     pub fn is_memory_type_host_visible(&self, type_index: u32) -> bool {
         let ty = self.memory_properties.memory_types[type_index as usize];
@@ -195,18 +535,34 @@ impl Device {
     }
 
     /// Destroy the buffer referred to by `buffer`.
+    ///
+    /// The handle becomes invalid immediately for the purposes of recording, but the buffer is
+    /// only actually freed once the GPU has finished the current frame, since work which uses it
+    /// may still be in flight.
     pub fn destroy_buffer(&self, buffer: BufferHandle) {
-        self.resources.write().buffers.remove(buffer.idx);
+        self.current_frame().write().destroyed_buffers.push(buffer);
     }
 
     /// Destroy the buffer view referred to by `buffer_view`.
+    ///
+    /// As with `destroy_buffer`, the view is freed once the current frame has completed.
     pub fn destroy_buffer_view(&self, buffer_view: BufferViewHandle) {
-        self.resources.write().buffers.remove(buffer_view.idx);
+        self.current_frame().write().destroyed_buffer_views.push(buffer_view);
     }
 
     /// Destroy the image referred to by `image`.
+    ///
+    /// As with `destroy_buffer`, the image is freed once the current frame has completed.
     pub fn destroy_image(&self, image: ImageHandle) {
-        self.resources.write().images.remove(image.idx);
+        self.current_frame().write().destroyed_images.push(image);
+    }
+
+    /// Destroy the image view referred to by `image_view`.
+    ///
+    /// As with `destroy_buffer`, the view is freed once the current frame has completed, along
+    /// with any framebuffers cached by `request_framebuffer` which use it.
+    pub fn destroy_image_view(&self, image_view: ImageViewHandle) {
+        self.current_frame().write().destroyed_image_views.push(image_view);
     }
 
     /// Create a Buffer from a BufferCreateInfo and, optionally, upload some
@@ -216,13 +572,15 @@ impl Device {
     /// the initial data will either be directly copied into the cpu-mappable
     /// buffer, or will be uploaded automatically via a staging buffer.
     ///
-    /// If `initial_data` exists, `size_of::<T>` must be <= to `create_info.size`.
-    pub fn create_buffer<T>(
+    /// If `initial_data` exists, `size_of::<T>` must be <= to `create_info.size`. If uploading
+    /// it fails, the buffer is destroyed again.
+    pub fn create_buffer<T: Pod>(
         self: Arc<Self>,
         mut create_info: BufferCreateInfo,
         tag: Option<Tag>,
         initial_data: Option<T>
-    ) -> Result<BufferHandle, vk_mem::Error> {
+    ) -> Result<BufferHandle, AllocatorError> {
+        self.check_deterministic_thread("create_buffer");
         if initial_data.is_some() {
             assert!(core::mem::size_of::<T>() as vk::DeviceSize <= create_info.size);
         }
@@ -232,12 +590,10 @@ impl Device {
         }
         let mut queue_family_indices = [0u32; 3];
         let buffer_info = self.raw_buffer_create_info(create_info, &mut queue_family_indices);
-        let alloc_info = self.allocation_info_from_buffer_create_info(create_info);
+        let alloc_desc = self.allocation_desc_from_buffer_create_info(create_info);
 
-        let (buffer, allocation, allocation_info) =
-            self.allocator.create_buffer(&buffer_info, &alloc_info)?;
-
-        let mapped_data = std::ptr::NonNull::new(allocation_info.get_mapped_data());
+        let (buffer, allocation) = self.create_raw_buffer(create_info, &buffer_info, &alloc_desc)?;
+        let mapped_data = allocation.mapped_data();
 
         let handle = BufferHandle {
             idx: self
@@ -248,76 +604,591 @@ impl Device {
                     self.clone(),
                     buffer,
                     allocation,
-                    allocation_info,
                     create_info,
-                    mapped_data,
+                    MemoryCategory::Buffers,
                     tag.clone(),
                 ) }),
         };
 
         if let Some(initial_data) = initial_data {
+            let data = bytemuck::bytes_of(&initial_data);
             if let Some(mapped) = mapped_data {
-                let mut mapped = mapped.cast::<T>();
                 unsafe {
-                    *mapped.as_mut() = initial_data;
+                    std::ptr::copy_nonoverlapping(data.as_ptr(), mapped.as_ptr(), data.len());
+                }
+            } else {
+                let uploaded = self.upload_via_staging(data, tag, |cmd, src, src_offset| {
+                    cmd.transition_buffer(
+                        handle,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::AccessFlags::TRANSFER_WRITE,
+                    );
+                    let dst = cmd
+                        .device()
+                        .resources()
+                        .get_buffer(handle)
+                        .expect("create_buffer: buffer was destroyed during upload")
+                        .raw();
+                    let region = vk::BufferCopy {
+                        src_offset,
+                        dst_offset: 0,
+                        size: data.len() as vk::DeviceSize,
+                    };
+                    unsafe { cmd.device().cmd_copy_buffer(cmd.raw(), src, dst, &[region]) };
+                });
+                if let Err(e) = uploaded {
+                    self.destroy_buffer(handle);
+                    return Err(e);
                 }
             }
+        }
+
+        Ok(handle)
+    }
+
+    /// Give the image `handle`, whose raw image `image` was created from `create_info`, a
+    /// default `ImageView` of all of its levels and layers if its usage allows it to be viewed.
+    pub(crate) fn create_default_view(
+        &self,
+        handle: ImageHandle,
+        image: vk::Image,
+        create_info: &ImageCreateInfo,
+    ) -> VkResult<()> {
+        let view_usage = vk::ImageUsageFlags::SAMPLED
+            | vk::ImageUsageFlags::STORAGE
+            | vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+            | vk::ImageUsageFlags::INPUT_ATTACHMENT;
+        if !create_info.usage.intersects(view_usage) {
+            return Ok(());
+        }
+
+        let view_info = ImageViewCreateInfo {
+            image: handle,
+            format: create_info.format,
+            base_mip_level: 0,
+            mip_levels: create_info.levels,
+            base_array_layer: 0,
+            array_layers: create_info.layers,
+            view_type: default_view_type(create_info),
+            swizzle: create_info.swizzle,
+        };
+        let view = unsafe {
+            ImageView::new(&self.device, self.destruction_sender(), image, create_info, view_info)?
+        };
+        self.resources.write().get_image_mut(handle).unwrap().set_view(view);
+        Ok(())
+    }
+
+    /// Create an Image from an ImageCreateInfo and, optionally, upload some initial data to it.
+    ///
+    /// A `levels` of 0 creates a full mip chain. If its usage allows the image to be viewed, it
+    /// gets a default `ImageView` of all of its levels and layers.
+    ///
+    /// `initial_data` fills mip level 0 of every layer, either with each layer following the last
+    /// or from a slice per layer (see `InitialImageData::layers`). It is copied through a
+    /// staging block on the async transfer queue, after which the image is transitioned to
+    /// `create_info.initial_layout`, and the next graphics submission waits for the upload. Without initial data, the image's contents are undefined, and a transition
+    /// from `UNDEFINED` to `create_info.initial_layout` is enqueued in the same way, so that the
+    /// image is in that layout by the time the next graphics submission uses it.
+    ///
+    /// With `MiscImageFlags::GENERATE_MIPS` and initial data, the other mip levels are generated
+    /// from level 0 on the graphics queue, by blitting each level from the one above it. If the
+    /// format can't be blitted, a single layer 2D image is downsampled by `MipChainPasses`
+    /// instead, which flushes the graphics queue and waits for it to complete.
+    pub fn create_image(
+        self: Arc<Self>,
+        mut create_info: ImageCreateInfo,
+        initial_data: Option<InitialImageData<'_>>,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, AllocatorError> {
+        self.check_deterministic_thread("create_image");
+        create_info.depth = create_info.depth.max(1);
+        let extent = vk::Extent3D {
+            width: create_info.width as u32,
+            height: create_info.height as u32,
+            depth: create_info.depth as u32,
+        };
+        if create_info.levels == 0 {
+            create_info.levels = mip_levels_from_extent(extent) as usize;
+        }
+        if initial_data.is_some() {
+            create_info.usage |= vk::ImageUsageFlags::TRANSFER_DST;
+        }
+
+        let mip_generation = if create_info.misc_flags.contains(MiscImageFlags::GENERATE_MIPS)
+            && initial_data.is_some()
+            && create_info.levels > 1
+        {
+            let method = self
+                .mip_generation_method(&create_info)
+                .ok_or(AllocatorError::Vulkan(vk::Result::ERROR_FORMAT_NOT_SUPPORTED))?;
+            create_info.usage |= match method {
+                MipGeneration::Blit(_) => vk::ImageUsageFlags::TRANSFER_SRC,
+                MipGeneration::Compute => vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE,
+            };
+            Some(method)
+        } else {
+            None
+        };
+
+        let transient = create_info.domain == ImageUsageDomain::Transient;
+        if transient {
+            create_info.usage |= vk::ImageUsageFlags::TRANSIENT_ATTACHMENT;
+        }
+
+        let mut queue_family_indices = [0u32; 3];
+        let (sharing_mode, queue_family_index_count) = self.sharing_mode(&mut queue_family_indices);
+        let image_info = vk::ImageCreateInfo::builder()
+            .flags(create_info.create_flags)
+            .image_type(create_info.image_type)
+            .format(create_info.format)
+            .extent(extent)
+            .mip_levels(create_info.levels as u32)
+            .array_layers(create_info.layers as u32)
+            .samples(create_info.sample_count)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(create_info.usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices[0..queue_family_index_count])
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let alloc_desc = if transient {
+            AllocationDesc {
+                required_flags: vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                preferred_flags: vk::MemoryPropertyFlags::LAZILY_ALLOCATED,
+                ..Default::default()
+            }
         } else {
-            let mut staging_info = create_info;
-            staging_info.domain = BufferUsageDomain::Host;
-            staging_info.usage &= !vk::BufferUsageFlags::TRANSFER_DST;
-            staging_info.usage |= vk::BufferUsageFlags::TRANSFER_SRC;
+            AllocationDesc {
+                usage: MemoryUsage::GpuOnly,
+                ..Default::default()
+            }
+        };
 
-            let staging_buffer = self.create_buffer(staging_info, tag.clone(), initial_data);
+        let (image, allocation) = self.allocator.create_image(&image_info, &alloc_desc)?;
 
-            // TODO
-            // let cmd_buf = self.request_commad_buffer(CommandBuffer::Type::AsyncTransfer);
-            // cmd_buf.copy_buffer(staging_buffer, handle);
+        let layout_type = if create_info.initial_layout == vk::ImageLayout::GENERAL {
+            ImageLayoutType::General
+        } else {
+            ImageLayoutType::Optimal
+        };
+        let handle = ImageHandle::new(self.resources.write().images.insert(unsafe {
+            Image::new(
+                self.clone(),
+                image,
+                Some(allocation),
+                create_info,
+                None,
+                layout_type,
+                vk::PipelineStageFlags::empty(),
+                vk::AccessFlags::empty(),
+                vk::ImageLayout::UNDEFINED,
+                tag.clone(),
+            )
+        }));
+
+        if let Err(e) = self.create_default_view(handle, image, &create_info) {
+            self.resources.write().images.remove(handle.idx);
+            return Err(AllocatorError::Vulkan(e));
+        }
 
-            // self.submit_staging(cmd_buf, staging_info.usage, true);
-            // self.used_staging_buffer(staging_buffer);
+        if let Some(initial_data) = initial_data {
+            let format = create_info.format;
+            let row_length = match initial_data.row_length {
+                0 => create_info.width,
+                row_length => row_length,
+            };
+            let image_height = match initial_data.image_height {
+                0 => create_info.height,
+                image_height => image_height,
+            };
+            assert!(
+                row_length >= create_info.width && image_height >= create_info.height,
+                "create_image: initial data rows are smaller than the image"
+            );
+            let (block_width, block_height) = format_block_dim(format);
+            assert!(
+                row_length % block_width as usize == 0 && image_height % block_height as usize == 0,
+                "create_image: initial data rows are not a whole number of blocks"
+            );
+            let layer_size = format_layer_size(
+                format,
+                row_length as u32,
+                image_height as u32,
+                create_info.depth as u32,
+            )
+            .expect("create_image: cannot upload initial data for this format");
+            let size = (layer_size * create_info.layers as u64) as usize;
+            let packed_layers;
+            let data = if initial_data.layers.is_empty() {
+                initial_data.data
+            } else {
+                assert!(
+                    initial_data.layers.len() == create_info.layers,
+                    "create_image: initial data has {} layers but the image has {}",
+                    initial_data.layers.len(),
+                    create_info.layers,
+                );
+                let mut packed = Vec::with_capacity(size);
+                for layer in initial_data.layers {
+                    assert!(
+                        layer.len() as u64 >= layer_size,
+                        "create_image: initial data layer is smaller than level 0 of the image"
+                    );
+                    packed.extend_from_slice(&layer[..layer_size as usize]);
+                }
+                packed_layers = packed;
+                &packed_layers[..]
+            };
+            assert!(
+                data.len() >= size,
+                "create_image: initial data is smaller than level 0 of the image"
+            );
+
+            let final_layout = create_info.initial_layout;
+            let copy_layout = layout_type.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL);
+            self.upload_via_staging(&data[..size], tag, |cmd, src, src_offset| {
+                cmd.transition_image(
+                    handle,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                );
+                let region = vk::BufferImageCopy {
+                    buffer_offset: src_offset,
+                    buffer_row_length: row_length as u32,
+                    buffer_image_height: image_height as u32,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask: format_to_aspect_mask(format),
+                        mip_level: 0,
+                        base_array_layer: 0,
+                        layer_count: create_info.layers as u32,
+                    },
+                    image_offset: vk::Offset3D::default(),
+                    image_extent: extent,
+                };
+                unsafe {
+                    cmd.device().cmd_copy_buffer_to_image(
+                        cmd.raw(),
+                        src,
+                        image,
+                        copy_layout,
+                        &[region],
+                    )
+                };
+
+                // The transfer queue can't wait on later stages, so the transition only needs
+                // to complete before the semaphore which later submissions wait on is signaled.
+                if final_layout != vk::ImageLayout::UNDEFINED && mip_generation.is_none() {
+                    cmd.transition_image(
+                        handle,
+                        final_layout,
+                        vk::PipelineStageFlags::empty(),
+                        vk::AccessFlags::empty(),
+                    );
+                }
+            })?;
+
+            if let Some(method) = mip_generation {
+                self.generate_mips(handle, &create_info, method)?;
+            }
+        } else if create_info.initial_layout != vk::ImageLayout::UNDEFINED {
+            if let Err(e) = self.transition_to_initial_layout(handle, create_info.initial_layout) {
+                self.resources.write().images.remove(handle.idx);
+                return Err(AllocatorError::Vulkan(e));
+            }
         }
 
         Ok(handle)
     }
 
-    // pub fn used_staging_buffer(&mut self, buffer: Buffer) {
+    /// Enqueue a transition of a newly created image from `UNDEFINED` into `layout`. Vulkan only
+    /// allows images to be created in `UNDEFINED` or `PREINITIALIZED`, so this is how
+    /// `ImageCreateInfo::initial_layout` takes effect for images without initial data.
+    ///
+    /// The transition is recorded on the async transfer queue, like an upload, so that it is
+    /// ordered before any upload into the image and before the next graphics submission.
+    fn transition_to_initial_layout(
+        self: &Arc<Self>,
+        image: ImageHandle,
+        layout: vk::ImageLayout,
+    ) -> Result<(), vk::Result> {
+        // As with the transition after an upload, the transfer queue can't wait on later stages.
+        self.submit_upload(|cmd| {
+            cmd.transition_image(
+                image,
+                layout,
+                vk::PipelineStageFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+        })
+    }
+
+    /// Create a multisampled render target with `samples` samples, as described by
+    /// `ImageCreateInfo::multisampled_render_target`, along with the single sampled image of
+    /// the same extent and format it is resolved to, which may also be sampled.
+    ///
+    /// Resolve the image at the end of a render pass by giving the resolve image as a
+    /// `RenderPassInfo::resolve_attachments`, or afterwards with `CommandBuffer::resolve_image`.
+    pub fn create_multisampled_render_target(
+        self: &Arc<Self>,
+        width: usize,
+        height: usize,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+        tag: Option<Tag>,
+    ) -> Result<MultisampledRenderTarget, AllocatorError> {
+        let create_info = ImageCreateInfo::multisampled_render_target(width, height, format, samples);
+        let image = self.clone().create_image(create_info, None, tag.clone())?;
+
+        let mut resolve_info = ImageCreateInfo::render_target(width, height, format, false);
+        resolve_info.usage |= vk::ImageUsageFlags::SAMPLED;
+        let resolve = match self.clone().create_image(resolve_info, None, tag) {
+            Ok(resolve) => resolve,
+            Err(e) => {
+                self.destroy_image(image);
+                return Err(e);
+            }
+        };
+
+        Ok(MultisampledRenderTarget { image, resolve })
+    }
+
+    /// Create an `ImageView` of a subresource range of an image.
+    ///
+    /// As with the default view made by `create_image`, a depth stencil image also gets a view
+    /// of each aspect, a layered attachment a view of each layer, and a `MUTABLE_FORMAT` 8-bit
+    /// RGBA image UNORM and SRGB views. The view must be destroyed with `destroy_image_view`
+    /// before the image is.
+    pub fn create_image_view(
+        &self,
+        create_info: ImageViewCreateInfo,
+        tag: Option<Tag>,
+    ) -> Result<ImageViewHandle, ImageViewCreationError> {
+        self.check_deterministic_thread("create_image_view");
+        let mut resources = self.resources.write();
+        let (image, image_info) = match resources.get_image(create_info.image) {
+            Some(image) => (image.raw(), image.create_info()),
+            None => return Err(ImageViewCreationError::InvalidImage),
+        };
+
+        let view = unsafe {
+            ImageView::new(&self.device, self.destruction_sender(), image, &image_info, create_info)?
+        };
+        for raw in view.raw_views() {
+            self.set_tag_name(raw, tag.as_ref());
+        }
 
-    // }
+        Ok(ImageViewHandle::new(resources.image_views.insert(view)))
+    }
+
+    /// Choose how to generate the mips of an image, or `None` if its format supports neither
+    /// blitting nor `MipChainPasses`.
+    fn mip_generation_method(&self, create_info: &ImageCreateInfo) -> Option<MipGeneration> {
+        let features = self.format_properties(create_info.format).optimal_tiling_features;
+
+        if features.contains(vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST) {
+            let filter = if features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR) {
+                vk::Filter::LINEAR
+            } else {
+                vk::Filter::NEAREST
+            };
+            Some(MipGeneration::Blit(filter))
+        } else if features.contains(vk::FormatFeatureFlags::STORAGE_IMAGE)
+            && mip_chain_supports_format(create_info.format)
+            && create_info.image_type == vk::ImageType::TYPE_2D
+            && create_info.layers == 1
+        {
+            Some(MipGeneration::Compute)
+        } else {
+            None
+        }
+    }
+
+    /// Fill every mip level below the first from level 0, then transition the image to its
+    /// final layout.
+    fn generate_mips(
+        self: &Arc<Self>,
+        image: ImageHandle,
+        create_info: &ImageCreateInfo,
+        method: MipGeneration,
+    ) -> Result<(), AllocatorError> {
+        let mut cmd = self
+            .clone()
+            .request_internal_command_buffer(QueueType::Graphics)
+            .map_err(AllocatorError::Vulkan)?;
+
+        let mut passes = None;
+        match method {
+            MipGeneration::Blit(filter) => {
+                let aspect_mask = format_to_aspect_mask(create_info.format);
+                let offset = |level: usize| vk::Offset3D {
+                    x: (create_info.width >> level).max(1) as i32,
+                    y: (create_info.height >> level).max(1) as i32,
+                    z: (create_info.depth >> level).max(1) as i32,
+                };
+                let subresource = |level: usize| vk::ImageSubresourceLayers {
+                    aspect_mask,
+                    mip_level: level as u32,
+                    base_array_layer: 0,
+                    layer_count: create_info.layers as u32,
+                };
+                for level in 1..create_info.levels {
+                    let region = vk::ImageBlit {
+                        src_subresource: subresource(level - 1),
+                        src_offsets: [vk::Offset3D::default(), offset(level - 1)],
+                        dst_subresource: subresource(level),
+                        dst_offsets: [vk::Offset3D::default(), offset(level)],
+                    };
+                    cmd.blit_image(image, image, &[region], filter);
+                }
+            }
+            MipGeneration::Compute => {
+                let mip_chain = MipChainPasses::new(self, image).map_err(|e| match e {
+                    MipChainError::Vulkan(e) => AllocatorError::Vulkan(e),
+                    e => AllocatorError::Other(e.to_string()),
+                })?;
+                mip_chain.downsample(&mut cmd);
+                passes = Some(mip_chain);
+            }
+        }
+
+        if create_info.initial_layout != vk::ImageLayout::UNDEFINED {
+            cmd.transition_image(
+                image,
+                create_info.initial_layout,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                image_layout_to_possible_access(create_info.initial_layout),
+            );
+        }
+        cmd.end().map_err(AllocatorError::Vulkan)?;
+
+        let submit = self.submit(QueueType::Graphics, &[cmd]);
+        if passes.is_some() {
+            // The passes can only be destroyed once the GPU has finished with them.
+            let fence = submit.flush().map_err(AllocatorError::Vulkan)?;
+            unsafe { self.device.wait_for_fences(&[fence], true, u64::MAX) }.map_err(AllocatorError::Vulkan)?;
+        } else {
+            submit.enqueue();
+        }
+
+        Ok(())
+    }
+
+    /// Copy `data` into a staging block and record a copy out of it with `record` on the async
+    /// transfer queue.
+    ///
+    /// `record` is given the command buffer, the staging buffer and the offset of the data within
+    /// it. The transfer is enqueued to signal a semaphore which the next graphics submission waits
+    /// on, so the upload is complete before anything on the graphics queue can use it.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub(crate) fn upload_via_staging<F>(
+        self: &Arc<Self>,
+        data: &[u8],
+        tag: Option<Tag>,
+        record: F,
+    ) -> Result<(), AllocatorError>
+    where
+        F: FnOnce(&mut CommandBuffer, vk::Buffer, vk::DeviceSize),
+    {
+        let (src, src_offset) = self.write_staging(data, tag)?;
+
+        self.submit_upload(|cmd| record(cmd, src, src_offset))
+            .map_err(AllocatorError::Vulkan)
+    }
+
+    /// Copy `data` into a newly requested staging block, returning the staging buffer and the
+    /// offset of the data within it.
+    pub(crate) fn write_staging(
+        &self,
+        data: &[u8],
+        tag: Option<Tag>,
+    ) -> Result<(vk::Buffer, vk::DeviceSize), AllocatorError> {
+        let block = self.request_internal_block(PoolKind::Staging, data.len(), tag)?;
+        let blocks = self.buffer_blocks();
+        let block = blocks
+            .get_staging_block(block)
+            .expect("write_staging: staging block was not created");
+        let staging = block
+            .allocate_buffer(data.len())
+            .map_err(|e| AllocatorError::Other(e.to_string()))?;
+
+        let mapped = block
+            .mapped_data(&staging)
+            .expect("write_staging: staging block is not mapped");
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), mapped.as_ptr(), data.len()) };
+        let allocation = block
+            .gpu
+            .allocation()
+            .expect("write_staging: staging block has no allocation");
+        self.allocator
+            .flush_allocation(allocation, staging.offset as usize, data.len())?;
+
+        Ok((block.gpu.raw(), staging.offset))
+    }
+
+    /// Record an upload with `record` on the async transfer queue and enqueue it to signal a
+    /// semaphore which the next graphics submission waits on.
+    pub(crate) fn submit_upload<F>(self: &Arc<Self>, record: F) -> Result<(), vk::Result>
+    where
+        F: FnOnce(&mut CommandBuffer),
+    {
+        let mut cmd = self.clone().request_internal_command_buffer(QueueType::AsyncTransfer)?;
+        record(&mut cmd);
+        cmd.end()?;
+
+        let semaphore = self.request_raw_semaphore()?;
+        self.submit(QueueType::AsyncTransfer, &[cmd])
+            .signal(semaphore)
+            .enqueue();
+        self.pending_upload_semaphores[queue_index(QueueType::Graphics)]
+            .lock()
+            .push(semaphore);
+
+        Ok(())
+    }
 
     /// A helper function to find a usable memory type index given an example BufferInfo for
     /// a buffer to be allocated.
     pub fn find_memory_type_index_for_buffer_info(
         &self,
         create_info: BufferCreateInfo,
-    ) -> Result<u32, vk_mem::Error> {
+    ) -> Result<u32, AllocatorError> {
         let mut queue_family_indices = [0u32; 3];
         let buffer_info = self.raw_buffer_create_info(create_info, &mut queue_family_indices);
-        let alloc_info = self.allocation_info_from_buffer_create_info(create_info);
-
-        self.allocator.find_memory_type_index_for_buffer_info(&buffer_info, &alloc_info)
+        let alloc_desc = self.allocation_desc_from_buffer_create_info(create_info);
+
+        // The memory types a buffer can use only depend on its create info, so a temporary one
+        // is created to ask for them.
+        let requirements = unsafe {
+            let buffer = self.device.create_buffer(&buffer_info, None)?;
+            let requirements = self.device.get_buffer_memory_requirements(buffer);
+            self.device.destroy_buffer(buffer, None);
+            requirements
+        };
+        self.allocator
+            .find_memory_type_index(requirements.memory_type_bits, &alloc_desc)
     }
 
-    /// Create a Buffer from a BufferCreateInfo into a specific pool
+    /// Create a Buffer from a BufferCreateInfo in a specific memory type, such as one found
+    /// with `find_memory_type_index_for_buffer_info`.
     pub fn create_buffer_in(
         self: Arc<Self>,
         create_info: BufferCreateInfo,
-        pool: vk_mem::AllocatorPool,
+        memory_type_index: u32,
         tag: Option<Tag>,
-    ) -> Result<BufferHandle, vk_mem::Error> {
+    ) -> Result<BufferHandle, AllocatorError> {
+        self.check_deterministic_thread("create_buffer_in");
         let mut queue_family_indices = [0u32; 3];
         let buffer_info = self.raw_buffer_create_info(create_info, &mut queue_family_indices);
 
-        let alloc_info = vk_mem::AllocationCreateInfo {
-            flags: vk_mem::AllocationCreateFlags::MAPPED,
-            pool: Some(pool),
+        let alloc_desc = AllocationDesc {
+            mapped: true,
+            memory_type_bits: 1 << memory_type_index,
             ..Default::default()
         };
 
-        let (buffer, allocation, allocation_info) =
-            self.allocator.create_buffer(&buffer_info, &alloc_info)?;
-
-        let mapped_data = std::ptr::NonNull::new(allocation_info.get_mapped_data());
+        let (buffer, allocation) = self.create_raw_buffer(create_info, &buffer_info, &alloc_desc)?;
 
         Ok(BufferHandle {
             idx: self
@@ -328,22 +1199,21 @@ impl Device {
                     self.clone(),
                     buffer,
                     allocation,
-                    allocation_info,
                     create_info,
-                    mapped_data,
+                    MemoryCategory::Buffers,
                     tag
                 ) }),
         })
     }
 
-    /// Create the corresponding `vk_mem::AllocationCreateInfo` for a specified `BufferCreateInfo`
-    pub fn allocation_info_from_buffer_create_info(
+    /// Create the corresponding `AllocationDesc` for a specified `BufferCreateInfo`
+    pub fn allocation_desc_from_buffer_create_info(
         &self,
         create_info: BufferCreateInfo
-    ) -> vk_mem::AllocationCreateInfo {
-        vk_mem::AllocationCreateInfo {
-            usage: vk_mem::MemoryUsage::Unknown,
-            flags: vk_mem::AllocationCreateFlags::MAPPED,
+    ) -> AllocationDesc {
+        AllocationDesc {
+            usage: MemoryUsage::Unknown,
+            mapped: true,
             required_flags: match create_info.domain {
                 BufferUsageDomain::Device => vk::MemoryPropertyFlags::DEVICE_LOCAL,
                 BufferUsageDomain::DeviceDynamic => vk::MemoryPropertyFlags::DEVICE_LOCAL,
@@ -365,13 +1235,30 @@ impl Device {
     /// # Parameters
     ///
     /// * `queue_family_indices` this array will be filled with the needed queue family indices
-    /// and must live at least as long as the returned `vk::BufferCreateInfoBuilder`
+    ///   and must live at least as long as the returned `vk::BufferCreateInfoBuilder`
     pub fn raw_buffer_create_info<'a>(
         &self,
         create_info: BufferCreateInfo,
         queue_family_indices: &'a mut [u32; 3],
     ) -> vk::BufferCreateInfoBuilder<'a> {
-        let (sharing_mode, queue_family_index_count) = if self.multiple_queue_families {
+        let (sharing_mode, queue_family_index_count) = self.sharing_mode(queue_family_indices);
+
+        let mut usage = create_info.usage;
+        if create_info.wants_device_address {
+            usage |= shader_device_address_usage();
+        }
+
+        vk::BufferCreateInfo::builder()
+            .size(create_info.size)
+            .usage(usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices[0..queue_family_index_count])
+    }
+
+    /// Get the sharing mode of resources which may be used on every queue, filling
+    /// `queue_family_indices` with the distinct queue families and returning how many there are.
+    pub(crate) fn sharing_mode(&self, queue_family_indices: &mut [u32; 3]) -> (vk::SharingMode, usize) {
+        if self.multiple_queue_families {
             let mut count = 1;
             queue_family_indices[0] = self.graphics_queue_family_index;
             if self.graphics_queue_family_index != self.compute_queue_family_index {
@@ -387,13 +1274,7 @@ impl Device {
             (vk::SharingMode::CONCURRENT, count)
         } else {
             (vk::SharingMode::EXCLUSIVE, 0)
-        };
-
-        vk::BufferCreateInfo::builder()
-            .size(create_info.size)
-            .usage(create_info.usage)
-            .sharing_mode(sharing_mode)
-            .queue_family_indices(&queue_family_indices[0..queue_family_index_count])
+        }
     }
 }
 
@@ -405,3 +1286,33 @@ impl Deref for Device {
         &self.device
     }
 }
+
+/// Get the view type of the default view of an image.
+fn default_view_type(create_info: &ImageCreateInfo) -> vk::ImageViewType {
+    let layers = create_info.layers;
+    match create_info.image_type {
+        vk::ImageType::TYPE_1D if layers > 1 => vk::ImageViewType::TYPE_1D_ARRAY,
+        vk::ImageType::TYPE_1D => vk::ImageViewType::TYPE_1D,
+        vk::ImageType::TYPE_3D => vk::ImageViewType::TYPE_3D,
+        _ if create_info.create_flags.contains(vk::ImageCreateFlags::CUBE_COMPATIBLE)
+            && layers % 6 == 0 =>
+        {
+            if layers == 6 {
+                vk::ImageViewType::CUBE
+            } else {
+                vk::ImageViewType::CUBE_ARRAY
+            }
+        }
+        _ if layers > 1 => vk::ImageViewType::TYPE_2D_ARRAY,
+        _ => vk::ImageViewType::TYPE_2D,
+    }
+}
+
+/// How the mips of an image created with `MiscImageFlags::GENERATE_MIPS` are generated.
+#[derive(Clone, Copy, Debug)]
+enum MipGeneration {
+    /// Blit each level from the one above it with the given filter.
+    Blit(vk::Filter),
+    /// Downsample with `MipChainPasses`.
+    Compute,
+}
@@ -0,0 +1,1119 @@
+use ash::extensions::{ext, khr};
+use ash::version::{EntryV1_0, InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use derivative::Derivative;
+
+use parking_lot::*;
+
+#[cfg(feature = "raw-window-handle")]
+use raw_window_handle::HasRawDisplayHandle;
+
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::ffi::{c_void, CStr, CString};
+use std::os::raw::c_char;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::Arc;
+
+use crate::*;
+use crate::descriptor::DescriptorWriteBatch;
+use crate::deterministic::ReplayHasher;
+use crate::device_address::{
+    buffer_device_address_extension_name, supports_buffer_device_address, BufferDeviceAddressFn,
+    PhysicalDeviceBufferDeviceAddressFeatures,
+};
+use crate::cache_stats::CacheCounters;
+use crate::frame::next_device_id;
+use crate::gpu_assert::GpuAsserts;
+use crate::profiling::GpuProfiler;
+use crate::screenshot::ScreenshotQueue;
+use crate::memory_stats::MemoryWatermarks;
+use crate::dynamic_rendering::{
+    dynamic_rendering_extension_name, supports_dynamic_rendering, DynamicRenderingFn,
+    PhysicalDeviceDynamicRenderingFeatures,
+};
+use crate::pipeline_cache::create_pipeline_cache;
+use crate::portability::{
+    instance_create_enumerate_portability, portability_enumeration_extension_name,
+    portability_subset_extension_name, query_portability_subset,
+};
+use crate::sampler::{
+    custom_border_color_extension_name, supports_custom_border_color, PhysicalDeviceCustomBorderColorFeatures,
+};
+#[cfg(feature = "raytracing")]
+use crate::raytracing::{ray_tracing_extension_names, supports_ray_tracing, RayTracingFeatures, RayTracingFn};
+#[cfg(feature = "video")]
+use crate::video::{video_queue_extension_name, VideoQueueFamilies};
+use crate::tier::{bindless_features, supports_bindless, supports_mesh_shaders};
+use crate::synchronization2::{
+    supports_synchronization2, synchronization2_extension_name, PhysicalDeviceSynchronization2Features,
+    Synchronization2Fn,
+};
+use crate::timeline::{
+    supports_timeline_semaphores, timeline_semaphore_extension_name, PhysicalDeviceTimelineSemaphoreFeatures,
+    TimelineSemaphoreFn,
+};
+
+const DEFAULT_VBO_BLOCK_SIZE: usize = 1024 * 1024;
+const DEFAULT_IBO_BLOCK_SIZE: usize = 256 * 1024;
+const DEFAULT_UBO_BLOCK_SIZE: usize = 256 * 1024;
+const DEFAULT_INDIRECT_BLOCK_SIZE: usize = 64 * 1024;
+const DEFAULT_STAGING_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// An error that could occur while creating a `Device`.
+#[derive(Error, Debug)]
+pub enum DeviceCreationError {
+    /// The Vulkan library could not be loaded.
+    #[error("could not load the vulkan library: {0}")]
+    Loading(#[from] ash::LoadingError),
+    /// The Vulkan instance could not be created.
+    #[error("could not create the vulkan instance: {0}")]
+    Instance(#[from] ash::InstanceError),
+    /// No physical device with a graphics queue was found.
+    #[error("no suitable physical device was found")]
+    NoSuitablePhysicalDevice,
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+    /// The memory allocator or one of the default buffer block pools could not be created.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+/// The system-wide priority of a queue relative to queues of other applications, through
+/// `VK_EXT_global_priority`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum QueuePriority {
+    /// Lower than the default priority, for background work.
+    Low,
+    /// The default priority.
+    #[default]
+    Medium,
+    /// Higher than the default priority, for latency critical work.
+    High,
+    /// The highest priority, which usually requires elevated privileges, e.g. for late-latched
+    /// VR reprojection.
+    Realtime,
+}
+
+impl QueuePriority {
+    /// Get the raw `vk::QueueGlobalPriorityEXT`.
+    pub fn raw(self) -> vk::QueueGlobalPriorityEXT {
+        match self {
+            QueuePriority::Low => vk::QueueGlobalPriorityEXT::LOW,
+            QueuePriority::Medium => vk::QueueGlobalPriorityEXT::MEDIUM,
+            QueuePriority::High => vk::QueueGlobalPriorityEXT::HIGH,
+            QueuePriority::Realtime => vk::QueueGlobalPriorityEXT::REALTIME,
+        }
+    }
+
+    /// Get the next lower priority to fall back to, down to the default.
+    fn fallback(self) -> Self {
+        match self {
+            QueuePriority::Realtime => QueuePriority::High,
+            QueuePriority::High | QueuePriority::Medium => QueuePriority::Medium,
+            QueuePriority::Low => QueuePriority::Low,
+        }
+    }
+}
+
+/// The highest Vulkan version hot uses. Older instances and devices are supported, with the
+/// features which need a newer version reported as unsupported, or used through their extensions
+/// where those are available.
+pub const MAX_API_VERSION: u32 = ash::vk_make_version!(1, 3, 0);
+
+/// Create the allocator used when none is given to `DeviceBuilder::allocator`.
+#[cfg(feature = "vk-mem")]
+fn default_allocator(context: &AllocatorContext<'_>) -> Result<Box<dyn MemoryAllocator>, AllocatorError> {
+    Ok(Box::new(VmaAllocator::new(context)?))
+}
+
+/// Create the allocator used when none is given to `DeviceBuilder::allocator`.
+#[cfg(all(feature = "gpu-allocator", not(feature = "vk-mem")))]
+fn default_allocator(context: &AllocatorContext<'_>) -> Result<Box<dyn MemoryAllocator>, AllocatorError> {
+    Ok(Box::new(GpuAllocator::new(context)?))
+}
+
+/// Create the allocator used when none is given to `DeviceBuilder::allocator`.
+#[cfg(not(any(feature = "vk-mem", feature = "gpu-allocator")))]
+fn default_allocator(_: &AllocatorContext<'_>) -> Result<Box<dyn MemoryAllocator>, AllocatorError> {
+    Err(AllocatorError::NoBackend)
+}
+
+/// Builds a `Device`, including the Vulkan instance and logical device it owns.
+#[derive(Derivative, Clone)]
+#[derivative(Debug)]
+pub struct DeviceBuilder {
+    app_name: String,
+    instance_extensions: Vec<&'static CStr>,
+    device_extensions: Vec<&'static CStr>,
+    validation: bool,
+    frames_in_flight: usize,
+    pipeline_cache_path: Option<PathBuf>,
+    queue_priorities: [QueuePriority; 3],
+    gpu_assert_set: Option<u32>,
+    gpu_profiling: bool,
+    block_trim_frames: Option<usize>,
+    deterministic: bool,
+    block_device_addresses: bool,
+    headless: bool,
+    #[derivative(Debug = "ignore")]
+    allocator: Option<AllocatorFactory>,
+}
+
+impl Default for DeviceBuilder {
+    fn default() -> Self {
+        Self {
+            app_name: String::from("hot"),
+            instance_extensions: Vec::new(),
+            device_extensions: Vec::new(),
+            validation: false,
+            frames_in_flight: 2,
+            pipeline_cache_path: None,
+            queue_priorities: [QueuePriority::Medium; 3],
+            gpu_assert_set: None,
+            gpu_profiling: false,
+            block_trim_frames: Some(DEFAULT_BLOCK_TRIM_FRAMES),
+            deterministic: false,
+            block_device_addresses: false,
+            headless: false,
+            allocator: None,
+        }
+    }
+}
+
+impl DeviceBuilder {
+    /// Create a new DeviceBuilder with default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the application name reported to the driver.
+    pub fn app_name<S: Into<String>>(mut self, app_name: S) -> Self {
+        self.app_name = app_name.into();
+        self
+    }
+
+    /// Enable an additional instance extension, for example the platform specific surface
+    /// extension needed to create a `vk::SurfaceKHR` for your window.
+    ///
+    /// `VK_KHR_surface` is always enabled, unless the Device is `headless`.
+    pub fn instance_extension(mut self, name: &'static CStr) -> Self {
+        self.instance_extensions.push(name);
+        self
+    }
+
+    /// Enable the instance extensions `Device::create_surface` needs to create surfaces for
+    /// windows on `display`. Displays of unsupported platforms enable nothing.
+    #[cfg(feature = "raw-window-handle")]
+    pub fn surface_extensions_for<D: HasRawDisplayHandle>(mut self, display: &D) -> Self {
+        if let Ok(names) = surface_extensions(display.raw_display_handle()) {
+            self.instance_extensions.extend(names);
+        }
+        self
+    }
+
+    /// Enable an additional device extension.
+    ///
+    /// `VK_KHR_swapchain` is always enabled, unless the Device is `headless`.
+    pub fn device_extension(mut self, name: &'static CStr) -> Self {
+        self.device_extensions.push(name);
+        self
+    }
+
+    /// Enable the Khronos validation layer.
+    pub fn validation(mut self, validation: bool) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Set the number of frames which may be in flight on the GPU at once.
+    pub fn frames_in_flight(mut self, frames_in_flight: usize) -> Self {
+        assert!(frames_in_flight > 0);
+        self.frames_in_flight = frames_in_flight;
+        self
+    }
+
+    /// Set the file the Vulkan pipeline cache is loaded from at creation and saved to by
+    /// `Device::save_pipeline_cache`.
+    pub fn pipeline_cache_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.pipeline_cache_path = Some(path.into());
+        self
+    }
+
+    /// Request a global priority for the queue used for `queue_type`.
+    ///
+    /// Priorities other than `Medium` need `VK_EXT_global_priority`, which is enabled if it is
+    /// supported. If it isn't, or the driver doesn't permit the priority, the queue falls back
+    /// to a lower one; use `Device::queue_priority` to find out what was granted. Queue types
+    /// which share a queue family get the highest priority requested for any of them.
+    pub fn queue_priority(mut self, queue_type: QueueType, priority: QueuePriority) -> Self {
+        self.queue_priorities[queue_index(queue_type)] = priority;
+        self
+    }
+
+    /// Enable GPU assertions, reserving descriptor set `set` of every layout made by
+    /// `Device::create_shader_layout` for the buffer shaders write failed assertions to. See the
+    /// `gpu_assert` module for how shaders use it.
+    ///
+    /// This is a debugging aid, off by default, which costs a descriptor set bind per draw.
+    pub fn gpu_asserts(mut self, set: u32) -> Self {
+        self.gpu_assert_set = Some(set);
+        self
+    }
+
+    /// Enable timing zones of graphics command buffers with `CommandBuffer::begin_gpu_zone`,
+    /// if the graphics queue supports timestamps. See the `profiling` module.
+    ///
+    /// This is off by default, since it costs a query pool reset per frame.
+    pub fn gpu_profiling(mut self, enabled: bool) -> Self {
+        self.gpu_profiling = enabled;
+        self
+    }
+
+    /// Set how many frames a recycled buffer block may go unused before `Device::begin_frame`
+    /// frees it with `BufferBlockPool::trim`, or `None` to keep recycled blocks forever.
+    ///
+    /// Defaults to `DEFAULT_BLOCK_TRIM_FRAMES`.
+    pub fn block_trim_frames(mut self, frames: Option<usize>) -> Self {
+        self.block_trim_frames = frames;
+        self
+    }
+
+    /// Enable deterministic mode, a debugging aid under which the same sequence of calls
+    /// assigns the same resource handles and buffer blocks, and submits in the same order,
+    /// on every run.
+    ///
+    /// Pools whose iteration order decides the order resources are freed in, and so which
+    /// arena slots are reused, are hashed with fixed keys instead of per-run random ones. The
+    /// Device must then only be used from the thread which built it, since the interleaving of
+    /// threads can't be reproduced; creating resources, requesting blocks, submitting or
+    /// beginning a frame from another thread is reported as an invariant failure. The raw
+    /// Vulkan handles are still chosen by the driver and may differ between runs.
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    /// Give the buffers of the vertex, index and uniform block pools device addresses, so that
+    /// allocations from them can be accessed through `BufferBlockAllocation::device_address`.
+    ///
+    /// Has no effect unless the Device `supports_buffer_device_address`. Off by default, since
+    /// each block is then a dedicated allocation rather than one made by the allocator.
+    pub fn block_device_addresses(mut self, block_device_addresses: bool) -> Self {
+        self.block_device_addresses = block_device_addresses;
+        self
+    }
+
+    /// Build a Device without presentation, for compute and offscreen rendering, e.g. in CI
+    /// where there is no window system.
+    ///
+    /// `VK_KHR_surface` and `VK_KHR_swapchain` are then not enabled, so physical devices
+    /// without them can be used, and `Device::init_swapchain` fails with
+    /// `SwapchainError::Headless`. Render into an `OffscreenRenderTarget` instead.
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.headless = headless;
+        self
+    }
+
+    /// Allocate the Device's memory with the `MemoryAllocator` returned by `factory`, which is
+    /// called once the logical device has been created.
+    ///
+    /// Defaults to `VmaAllocator` with the `vk-mem` feature, or `GpuAllocator` with only the
+    /// `gpu-allocator` feature. Without either an allocator must be given, or building fails
+    /// with `AllocatorError::NoBackend`. With both, pass `GpuAllocator::new` here to use it.
+    pub fn allocator<F>(mut self, factory: F) -> Self
+    where
+        F: Fn(&AllocatorContext<'_>) -> Result<Box<dyn MemoryAllocator>, AllocatorError>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.allocator = Some(Arc::new(factory));
+        self
+    }
+
+    /// Create the `Device`.
+    pub fn build(self) -> Result<Arc<Device>, DeviceCreationError> {
+        let entry = ash::Entry::new()?;
+
+        // A 1.0 loader rejects instances requesting a newer version, and has no
+        // vkEnumerateInstanceVersion to ask with.
+        let instance_version = entry
+            .try_enumerate_instance_version()?
+            .unwrap_or(ash::vk_make_version!(1, 0, 0))
+            .min(MAX_API_VERSION);
+
+        let app_name = CString::new(self.app_name.as_str()).unwrap_or_default();
+        let app_info = vk::ApplicationInfo::builder()
+            .application_name(&app_name)
+            .engine_name(CStr::from_bytes_with_nul(b"hot\0").unwrap())
+            .api_version(instance_version);
+
+        let mut enabled_instance_extensions = if self.headless {
+            Vec::new()
+        } else {
+            vec![khr::Surface::name()]
+        };
+        enabled_instance_extensions.extend(self.instance_extensions.iter().copied());
+
+        let supported_instance_extensions = entry.enumerate_instance_extension_properties()?;
+        let supports_instance_extension = |name: &CStr| {
+            supported_instance_extensions
+                .iter()
+                .any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name)
+        };
+        // Debug names and labels are cheap when no tool is listening, so they are enabled
+        // whenever they are available.
+        let supports_debug_utils = supports_instance_extension(ext::DebugUtils::name());
+        if supports_debug_utils && !self.instance_extensions.contains(&ext::DebugUtils::name()) {
+            enabled_instance_extensions.push(ext::DebugUtils::name());
+        }
+        // Newer loaders only list portability implementations, such as MoltenVK, when asked to.
+        let portability_enumeration = portability_enumeration_extension_name();
+        let enumerate_portability = supports_instance_extension(portability_enumeration);
+        if enumerate_portability && !self.instance_extensions.contains(&portability_enumeration) {
+            enabled_instance_extensions.push(portability_enumeration);
+        }
+        let instance_extensions: Vec<*const c_char> =
+            enabled_instance_extensions.iter().map(|ext| ext.as_ptr()).collect();
+
+        let validation_layer = CStr::from_bytes_with_nul(b"VK_LAYER_KHRONOS_validation\0").unwrap();
+        let layers: Vec<*const c_char> = if self.validation {
+            vec![validation_layer.as_ptr()]
+        } else {
+            Vec::new()
+        };
+
+        let instance_flags = if enumerate_portability {
+            instance_create_enumerate_portability()
+        } else {
+            vk::InstanceCreateFlags::empty()
+        };
+        let instance_info = vk::InstanceCreateInfo::builder()
+            .flags(instance_flags)
+            .application_info(&app_info)
+            .enabled_extension_names(&instance_extensions)
+            .enabled_layer_names(&layers);
+
+        let instance = unsafe { entry.create_instance(&instance_info, None)? };
+
+        let physical_device = Self::pick_physical_device(&instance, self.headless)?;
+
+        let device_properties = unsafe { instance.get_physical_device_properties(physical_device) };
+        let api_version = api_version_of(device_properties.api_version).min(instance_version);
+
+        let families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let queue_families = QueueFamilies::find(&families)
+            .ok_or(DeviceCreationError::NoSuitablePhysicalDevice)?;
+        if queue_families.compute == queue_families.graphics {
+            log::info!("hot: no dedicated compute queue family, compute work shares the graphics queue");
+        }
+        if queue_families.transfer == queue_families.graphics {
+            log::info!("hot: no dedicated transfer queue family, transfers share the graphics queue");
+        }
+
+        let mut device_extensions: Vec<*const c_char> = if self.headless {
+            Vec::new()
+        } else {
+            vec![khr::Swapchain::name().as_ptr()]
+        };
+        device_extensions.extend(self.device_extensions.iter().map(|ext| ext.as_ptr()));
+
+        let supported_extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let supports_extension = |name: &CStr| {
+            supported_extensions
+                .iter()
+                .any(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) } == name)
+        };
+        // Extensions may be needed by several optional capabilities, but are only enabled once.
+        let mut enable_if_supported = |name: &'static CStr| {
+            let supported = supports_extension(name);
+            let enabled = device_extensions
+                .iter()
+                .any(|&ext| unsafe { CStr::from_ptr(ext) } == name);
+            if supported && !enabled {
+                device_extensions.push(name.as_ptr());
+            }
+            supported
+        };
+
+        // Portability implementations must have their subset enabled, and report the features of
+        // full Vulkan they lack through features2. Without 1.1 none of them are assumed.
+        let portability_subset = if enable_if_supported(portability_subset_extension_name()) {
+            let subset = if api_version >= ash::vk_make_version!(1, 1, 0) {
+                query_portability_subset(&instance, physical_device)
+            } else {
+                PortabilitySubset::default()
+            };
+            log::info!("hot: device is a portability implementation: {:?}", subset);
+            Some(subset)
+        } else {
+            None
+        };
+        // The built-in shaders declare storage buffers in the StorageBuffer storage class, which
+        // is core in 1.1.
+        if api_version < ash::vk_make_version!(1, 1, 0) {
+            enable_if_supported(vk::KhrStorageBufferStorageClassFn::name());
+        }
+        // Presentation timing is optional, so it is enabled whenever it is available.
+        let supports_display_timing = enable_if_supported(vk::GoogleDisplayTimingFn::name());
+        // As is importing host memory, which `Device::import_host_buffer` needs. It builds on
+        // external memory and properties2, which are core in 1.1.
+        let supports_host_import = api_version >= ash::vk_make_version!(1, 1, 0)
+            && enable_if_supported(vk::ExtExternalMemoryHostFn::name());
+        // As are timeline semaphores, whose feature is queried through features2. They are core
+        // in 1.2, and need their extension before it.
+        let core_timelines = api_version >= ash::vk_make_version!(1, 2, 0);
+        let supports_timelines = if core_timelines {
+            supports_timeline_semaphores(&instance, physical_device)
+        } else {
+            api_version >= ash::vk_make_version!(1, 1, 0)
+                && supports_extension(timeline_semaphore_extension_name())
+                && supports_timeline_semaphores(&instance, physical_device)
+                && enable_if_supported(timeline_semaphore_extension_name())
+        };
+        // As is dynamic rendering, which is core in 1.3. Before it, it needs its extension, and
+        // before 1.2 also the extensions it builds on.
+        let core_dynamic_rendering = api_version >= ash::vk_make_version!(1, 3, 0);
+        let supports_dynamic_rendering = if core_dynamic_rendering {
+            supports_dynamic_rendering(&instance, physical_device)
+        } else {
+            api_version >= ash::vk_make_version!(1, 1, 0)
+                && supports_extension(dynamic_rendering_extension_name())
+                && (api_version >= ash::vk_make_version!(1, 2, 0)
+                    || (supports_extension(vk::KhrCreateRenderpass2Fn::name())
+                        && supports_extension(vk::KhrDepthStencilResolveFn::name())))
+                && supports_dynamic_rendering(&instance, physical_device)
+        };
+        if supports_dynamic_rendering && !core_dynamic_rendering {
+            if api_version < ash::vk_make_version!(1, 2, 0) {
+                enable_if_supported(vk::KhrCreateRenderpass2Fn::name());
+                enable_if_supported(vk::KhrDepthStencilResolveFn::name());
+            }
+            enable_if_supported(dynamic_rendering_extension_name());
+        }
+        // As is synchronization2, which tracked transitions use to give each barrier its own
+        // stages. It is core in 1.3, and needs its extension before it.
+        let core_synchronization2 = api_version >= ash::vk_make_version!(1, 3, 0);
+        let supports_synchronization2 = if core_synchronization2 {
+            supports_synchronization2(&instance, physical_device)
+        } else {
+            api_version >= ash::vk_make_version!(1, 1, 0)
+                && supports_extension(synchronization2_extension_name())
+                && supports_synchronization2(&instance, physical_device)
+                && enable_if_supported(synchronization2_extension_name())
+        };
+        // Video queues are created in families other than those of hot's queues, which it
+        // looks for through properties2. Their extensions need synchronization2.
+        #[cfg(feature = "video")]
+        let video_families = if supports_synchronization2 && enable_if_supported(video_queue_extension_name()) {
+            let excluded = [queue_families.graphics, queue_families.compute, queue_families.transfer];
+            let mut video_families = VideoQueueFamilies::find(&instance, physical_device, &excluded);
+            video_families.enable_extensions(&mut enable_if_supported);
+            video_families
+        } else {
+            VideoQueueFamilies::default()
+        };
+        // As are buffer device addresses, whose feature is queried through features2.
+        let supports_device_address = api_version >= ash::vk_make_version!(1, 1, 0)
+            && supports_extension(buffer_device_address_extension_name())
+            && supports_buffer_device_address(&instance, physical_device)
+            && enable_if_supported(buffer_device_address_extension_name());
+        // As is ray tracing with the `raytracing` feature, along with the extensions it builds on.
+        #[cfg(feature = "raytracing")]
+        let supports_ray_tracing = supports_device_address
+            && ray_tracing_extension_names().iter().all(|&name| supports_extension(name))
+            && supports_ray_tracing(&instance, physical_device);
+        #[cfg(feature = "raytracing")]
+        {
+            if supports_ray_tracing {
+                for &name in ray_tracing_extension_names().iter() {
+                    enable_if_supported(name);
+                }
+            }
+        }
+        // As are custom sampler border colors, whose features are queried through features2.
+        let supports_custom_border_color = api_version >= ash::vk_make_version!(1, 1, 0)
+            && supports_extension(custom_border_color_extension_name())
+            && supports_custom_border_color(&instance, physical_device)
+            && enable_if_supported(custom_border_color_extension_name());
+        // As is writing the viewport index and layer from vertex shaders.
+        let supports_viewport_index_layer = enable_if_supported(vk::ExtShaderViewportIndexLayerFn::name());
+        // As is reporting the memory budget, which is queried through properties2.
+        let supports_memory_budget = api_version >= ash::vk_make_version!(1, 1, 0)
+            && enable_if_supported(vk::ExtMemoryBudgetFn::name());
+        // As are the capabilities which make up the Device's `Tier`: bindless descriptor arrays
+        // and mesh shaders, whose features are queried through features2, and indirect counts.
+        let supports_bindless_arrays = api_version >= ash::vk_make_version!(1, 1, 0)
+            && supports_extension(vk::ExtDescriptorIndexingFn::name())
+            && supports_bindless(&instance, physical_device)
+            && enable_if_supported(vk::ExtDescriptorIndexingFn::name());
+        let supports_indirect_count = enable_if_supported(vk::KhrDrawIndirectCountFn::name());
+        let supports_mesh = api_version >= ash::vk_make_version!(1, 1, 0)
+            && supports_extension(vk::NvMeshShaderFn::name())
+            && supports_mesh_shaders(&instance, physical_device)
+            && enable_if_supported(vk::NvMeshShaderFn::name());
+        let tier_report = TierReport {
+            bindless: supports_bindless_arrays,
+            draw_indirect_count: supports_indirect_count,
+            #[cfg(feature = "raytracing")]
+            ray_tracing: supports_ray_tracing,
+            #[cfg(not(feature = "raytracing"))]
+            ray_tracing: false,
+            mesh_shaders: supports_mesh,
+        };
+        log::info!("hot: device is {:?}: {:?}", tier_report.tier(), tier_report);
+
+        // Sparse residency for images, which `Device::create_sparse_image` needs, is enabled
+        // whenever the graphics queue can bind sparse memory. Multiple viewports and geometry
+        // shaders, for layered rendering, wide lines and anisotropic filtering are enabled
+        // whenever supported, which portability implementations often don't. No other features
+        // are enabled.
+        let supported_features = unsafe { instance.get_physical_device_features(physical_device) };
+        let graphics_binds_sparse = families[queue_families.graphics as usize]
+            .queue_flags
+            .contains(vk::QueueFlags::SPARSE_BINDING);
+        let mut enabled_features = if graphics_binds_sparse && supported_features.sparse_binding == vk::TRUE {
+            vk::PhysicalDeviceFeatures {
+                sparse_binding: vk::TRUE,
+                sparse_residency_image2_d: supported_features.sparse_residency_image2_d,
+                sparse_residency_image3_d: supported_features.sparse_residency_image3_d,
+                ..Default::default()
+            }
+        } else {
+            vk::PhysicalDeviceFeatures::default()
+        };
+        enabled_features.multi_viewport = supported_features.multi_viewport;
+        enabled_features.geometry_shader = supported_features.geometry_shader;
+        enabled_features.wide_lines = supported_features.wide_lines;
+        enabled_features.sampler_anisotropy = supported_features.sampler_anisotropy;
+
+        // Each distinct queue family and the highest priority requested for it.
+        let queue_types = [QueueType::Graphics, QueueType::Compute, QueueType::AsyncTransfer];
+        let family_of = |queue_type| match queue_type {
+            QueueType::Graphics => queue_families.graphics,
+            QueueType::Compute => queue_families.compute,
+            QueueType::AsyncTransfer => queue_families.transfer,
+        };
+        let mut family_priorities: Vec<(u32, QueuePriority)> = Vec::with_capacity(3);
+        for &queue_type in &queue_types {
+            let family = family_of(queue_type);
+            let priority = self.queue_priorities[queue_index(queue_type)];
+            match family_priorities.iter_mut().find(|(f, _)| *f == family) {
+                Some((_, existing)) => *existing = priority.max(*existing),
+                None => family_priorities.push((family, priority)),
+            }
+        }
+
+        #[cfg(feature = "video")]
+        for &(family, _) in video_families.decode.iter().chain(&video_families.encode) {
+            if !family_priorities.iter().any(|&(f, _)| f == family) {
+                family_priorities.push((family, QueuePriority::Medium));
+            }
+        }
+
+        let wants_global_priority = family_priorities
+            .iter()
+            .any(|&(_, priority)| priority != QueuePriority::Medium);
+        if !wants_global_priority || !enable_if_supported(vk::ExtGlobalPriorityFn::name()) {
+            for (_, priority) in &mut family_priorities {
+                *priority = QueuePriority::Medium;
+            }
+        }
+
+        // Drivers may refuse priorities above the default, in which case the highest requested
+        // priority is lowered until creation succeeds.
+        let priorities = [1.0f32];
+        let device = loop {
+            let mut priority_infos = family_priorities
+                .iter()
+                .map(|&(_, priority)| {
+                    vk::DeviceQueueGlobalPriorityCreateInfoEXT::builder()
+                        .global_priority(priority.raw())
+                        .build()
+                })
+                .collect::<Vec<_>>();
+            let queue_infos = family_priorities
+                .iter()
+                .zip(&mut priority_infos)
+                .map(|(&(family, priority), priority_info)| {
+                    let info = vk::DeviceQueueCreateInfo::builder()
+                        .queue_family_index(family)
+                        .queue_priorities(&priorities);
+                    if priority == QueuePriority::Medium {
+                        info.build()
+                    } else {
+                        info.push_next(priority_info).build()
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let mut timeline_features = PhysicalDeviceTimelineSemaphoreFeatures {
+                timeline_semaphore: vk::TRUE,
+                ..Default::default()
+            };
+            let mut rendering_features = PhysicalDeviceDynamicRenderingFeatures {
+                dynamic_rendering: vk::TRUE,
+                ..Default::default()
+            };
+            let mut synchronization2_features = PhysicalDeviceSynchronization2Features {
+                synchronization2: vk::TRUE,
+                ..Default::default()
+            };
+            let mut address_features = PhysicalDeviceBufferDeviceAddressFeatures {
+                buffer_device_address: vk::TRUE,
+                ..Default::default()
+            };
+            let mut border_color_features = PhysicalDeviceCustomBorderColorFeatures {
+                custom_border_colors: vk::TRUE,
+                custom_border_color_without_format: vk::TRUE,
+                ..Default::default()
+            };
+            let mut subset_features = portability_subset.map(|subset| subset.features());
+            let mut indexing_features = bindless_features();
+            let mut mesh_features = vk::PhysicalDeviceMeshShaderFeaturesNV {
+                task_shader: vk::TRUE,
+                mesh_shader: vk::TRUE,
+                ..Default::default()
+            };
+            let mut device_info = vk::DeviceCreateInfo::builder()
+                .queue_create_infos(&queue_infos)
+                .enabled_extension_names(&device_extensions)
+                .enabled_features(&enabled_features)
+                .build();
+            // Each enabled feature struct is pushed onto the front of the chain.
+            if supports_timelines {
+                timeline_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &timeline_features as *const _ as *const c_void;
+            }
+            if supports_dynamic_rendering {
+                rendering_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &rendering_features as *const _ as *const c_void;
+            }
+            if supports_synchronization2 {
+                synchronization2_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &synchronization2_features as *const _ as *const c_void;
+            }
+            if supports_device_address {
+                address_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &address_features as *const _ as *const c_void;
+            }
+            if supports_custom_border_color {
+                border_color_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &border_color_features as *const _ as *const c_void;
+            }
+            if let Some(subset_features) = &mut subset_features {
+                subset_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = subset_features as *const _ as *const c_void;
+            }
+            if supports_bindless_arrays {
+                indexing_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &indexing_features as *const _ as *const c_void;
+            }
+            if supports_mesh {
+                mesh_features.p_next = device_info.p_next as *mut c_void;
+                device_info.p_next = &mesh_features as *const _ as *const c_void;
+            }
+            #[cfg(feature = "raytracing")]
+            let mut ray_tracing_features = RayTracingFeatures::new(vk::TRUE);
+            #[cfg(feature = "raytracing")]
+            {
+                if supports_ray_tracing {
+                    device_info.p_next = ray_tracing_features.chain(device_info.p_next as *mut c_void);
+                }
+            }
+
+            match unsafe { instance.create_device(physical_device, &device_info, None) } {
+                Ok(device) => break device,
+                Err(vk::Result::ERROR_NOT_PERMITTED_EXT) => {
+                    let highest = family_priorities.iter().map(|&(_, priority)| priority).max();
+                    match highest {
+                        Some(highest) if highest > QueuePriority::Medium => {
+                            for (_, priority) in &mut family_priorities {
+                                if *priority == highest {
+                                    *priority = highest.fallback();
+                                }
+                            }
+                        }
+                        _ => return Err(vk::Result::ERROR_NOT_PERMITTED_EXT.into()),
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        let mut queue_priorities = [QueuePriority::Medium; 3];
+        for &queue_type in &queue_types {
+            let family = family_of(queue_type);
+            queue_priorities[queue_index(queue_type)] = family_priorities
+                .iter()
+                .find(|(f, _)| *f == family)
+                .map(|&(_, priority)| priority)
+                .unwrap_or_default();
+        }
+
+        let display_timing = if supports_display_timing {
+            Some(vk::GoogleDisplayTimingFn::load(|name| unsafe {
+                std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+            }))
+        } else {
+            None
+        };
+
+        let debug_utils = if supports_debug_utils {
+            Some(ext::DebugUtils::new(&entry, &instance))
+        } else {
+            None
+        };
+
+        let timeline_semaphore = if supports_timelines {
+            TimelineSemaphoreFn::load(&instance, &device, core_timelines)
+        } else {
+            None
+        };
+
+        let dynamic_rendering = if supports_dynamic_rendering {
+            DynamicRenderingFn::load(&instance, &device, core_dynamic_rendering)
+        } else {
+            None
+        };
+
+        let synchronization2 = if supports_synchronization2 {
+            Synchronization2Fn::load(&instance, &device, core_synchronization2)
+        } else {
+            None
+        };
+
+        let buffer_device_address = if supports_device_address {
+            BufferDeviceAddressFn::load(&instance, &device)
+        } else {
+            None
+        };
+
+        #[cfg(feature = "raytracing")]
+        let ray_tracing = if supports_ray_tracing {
+            RayTracingFn::load(&instance, &device)
+        } else {
+            None
+        };
+
+        let external_memory_host = if supports_host_import {
+            let mut host_properties = vk::PhysicalDeviceExternalMemoryHostPropertiesEXT::default();
+            let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut host_properties);
+            unsafe { instance.get_physical_device_properties2(physical_device, &mut properties) };
+            let fns = vk::ExtExternalMemoryHostFn::load(|name| unsafe {
+                std::mem::transmute(instance.get_device_proc_addr(device.handle(), name.as_ptr()))
+            });
+            Some((fns, host_properties.min_imported_host_pointer_alignment))
+        } else {
+            None
+        };
+
+        let allocator_context = AllocatorContext {
+            entry: &entry,
+            instance: &instance,
+            physical_device,
+            device: &device,
+            frames_in_flight: self.frames_in_flight,
+        };
+        let allocator = match self.allocator {
+            Some(ref factory) => factory(&allocator_context)?,
+            None => default_allocator(&allocator_context)?,
+        };
+
+        let (graphics_queue, compute_queue, transfer_queue) = unsafe {
+            use ash::version::DeviceV1_0;
+            (
+                device.get_device_queue(queue_families.graphics, 0),
+                device.get_device_queue(queue_families.compute, 0),
+                device.get_device_queue(queue_families.transfer, 0),
+            )
+        };
+
+        #[cfg(feature = "video")]
+        let video_queue = |family: Option<(u32, VideoCodecs)>| {
+            family.map(|(family_index, codecs)| VideoQueue {
+                queue: unsafe {
+                    use ash::version::DeviceV1_0;
+                    device.get_device_queue(family_index, 0)
+                },
+                family_index,
+                codecs,
+            })
+        };
+        #[cfg(feature = "video")]
+        let (video_decode_queue, video_encode_queue) =
+            (video_queue(video_families.decode), video_queue(video_families.encode));
+
+        let gpu_asserts = match self.gpu_assert_set {
+            Some(set) => Some(GpuAsserts::new(
+                &device,
+                &*allocator,
+                &device_properties,
+                self.frames_in_flight,
+                set,
+            )?),
+            None => None,
+        };
+
+        let timestamp_valid_bits = families[queue_families.graphics as usize].timestamp_valid_bits;
+        let gpu_profiler = if self.gpu_profiling && timestamp_valid_bits > 0 {
+            Some(GpuProfiler::new(
+                &device,
+                &device_properties,
+                timestamp_valid_bits,
+                self.frames_in_flight,
+            )?)
+        } else {
+            None
+        };
+
+        let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
+
+        let pipeline_cache = create_pipeline_cache(
+            &device,
+            &device_properties,
+            self.pipeline_cache_path.as_deref(),
+        )?;
+
+        let surface_loader = khr::Surface::new(&entry, &instance);
+        let swapchain_loader = khr::Swapchain::new(&instance, &device);
+
+        let mut hot_device = Device {
+            entry,
+            instance,
+            #[cfg(feature = "raw-window-handle")]
+            instance_extensions: enabled_instance_extensions,
+            physical_device,
+            device,
+            allocator,
+
+            graphics_queue,
+            graphics_queue_family_index: queue_families.graphics,
+            compute_queue,
+            compute_queue_family_index: queue_families.compute,
+            transfer_queue,
+            transfer_queue_family_index: queue_families.transfer,
+            multiple_queue_families: queue_families.graphics != queue_families.compute
+                || queue_families.graphics != queue_families.transfer,
+            queue_priorities,
+            queue_locks: Default::default(),
+
+            api_version,
+            memory_properties,
+            device_properties,
+            enabled_features,
+
+            pipeline_cache,
+            pipeline_cache_path: self.pipeline_cache_path,
+            pipelines: RwLock::new(PipelineCache::default()),
+            render_passes: RwLock::new(RenderPassCache::default()),
+            samplers: RwLock::new(SamplerCache::default()),
+
+            surface_loader,
+            swapchain_loader,
+            swapchain: Mutex::new(None),
+            suspended_swapchain: Mutex::new(None),
+            display_timing,
+            debug_utils,
+            external_memory_host,
+            timeline_semaphore,
+            dynamic_rendering,
+            synchronization2,
+            buffer_device_address,
+            #[cfg(feature = "raytracing")]
+            ray_tracing,
+            #[cfg(feature = "video")]
+            video_decode_queue,
+            #[cfg(feature = "video")]
+            video_encode_queue,
+            custom_border_color: supports_custom_border_color,
+            shader_viewport_index_layer: supports_viewport_index_layer,
+            memory_budget: supports_memory_budget,
+            portability_subset,
+            tier_report,
+            headless: self.headless,
+
+            id: next_device_id(),
+
+            resources: RwLock::new(ResourceSet::default()),
+            blocks: RwLock::new(None),
+
+            per_frame: Vec::with_capacity(self.frames_in_flight),
+            current_frame_index: AtomicUsize::new(0),
+            frame_active: AtomicBool::new(false),
+            vbo_upload_queue: RwLock::new(Vec::new()),
+            ibo_upload_queue: RwLock::new(Vec::new()),
+            ubo_upload_queue: RwLock::new(Vec::new()),
+            indirect_upload_queue: RwLock::new(Vec::new()),
+            pending_submits: Default::default(),
+            pending_upload_semaphores: Default::default(),
+            destruction_queue: Arc::default(),
+            descriptor_writes: Mutex::new(DescriptorWriteBatch::default()),
+            semaphore_pool: Mutex::new(Vec::new()),
+            fence_pool: Mutex::new(Vec::new()),
+            scratch_images: Mutex::new(HashMap::with_hasher(ReplayHasher::new(self.deterministic))),
+            conversion_pipelines: Mutex::new(HashMap::new()),
+            render_scale: Mutex::new(RenderScaleState::default()),
+            resource_event_sink: RwLock::new(None),
+            next_submission_id: AtomicU64::new(0),
+            memory_watermarks: Mutex::new(MemoryWatermarks::default()),
+            memory_pools: Mutex::new(HashMap::with_hasher(ReplayHasher::new(self.deterministic))),
+            block_trim_frames: self.block_trim_frames,
+            gpu_asserts,
+            gpu_assert_handler: RwLock::new(None),
+            gpu_profiler,
+            gpu_timings: Mutex::new(None),
+            cache_counters: CacheCounters::default(),
+            last_cache_stats: Mutex::new(CacheStats::default()),
+            set_layout_counts: Mutex::new(HashMap::new()),
+            screenshots: Mutex::new(ScreenshotQueue::default()),
+            uploads: Mutex::new(UploadQueue::default()),
+            deterministic_thread: self.deterministic.then(|| std::thread::current().id()),
+
+            invariant_policy: RwLock::new(InvariantPolicy::default()),
+        };
+
+        for _ in 0..self.frames_in_flight {
+            hot_device.per_frame.push(RwLock::new(PerFrame::default()));
+        }
+
+        let device = Arc::new(hot_device);
+
+        // The block pools hold a reference to the Device, so they can only be created
+        // once it is behind an `Arc`.
+        let block_device_addresses = self.block_device_addresses && device.supports_buffer_device_address();
+        let blocks = BufferBlockSet {
+            vbo_pool: BufferBlockPool::new(
+                device.clone(),
+                DEFAULT_VBO_BLOCK_SIZE,
+                vk::BufferUsageFlags::VERTEX_BUFFER,
+                true,
+                block_device_addresses,
+            )?,
+            ibo_pool: BufferBlockPool::new(
+                device.clone(),
+                DEFAULT_IBO_BLOCK_SIZE,
+                vk::BufferUsageFlags::INDEX_BUFFER,
+                true,
+                block_device_addresses,
+            )?,
+            ubo_pool: BufferBlockPool::new(
+                device.clone(),
+                DEFAULT_UBO_BLOCK_SIZE,
+                vk::BufferUsageFlags::UNIFORM_BUFFER,
+                true,
+                block_device_addresses,
+            )?,
+            indirect_pool: BufferBlockPool::new(
+                device.clone(),
+                DEFAULT_INDIRECT_BLOCK_SIZE,
+                vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+                true,
+                block_device_addresses,
+            )?,
+            staging_pool: BufferBlockPool::new(
+                device.clone(),
+                DEFAULT_STAGING_BLOCK_SIZE,
+                vk::BufferUsageFlags::TRANSFER_SRC,
+                false,
+                false,
+            )?,
+        };
+        *device.blocks.write() = Some(blocks);
+
+        // Later frames reset their query pools in `begin_frame`.
+        device.reset_gpu_timestamps()?;
+
+        Ok(device)
+    }
+
+    /// Pick the first discrete GPU, or failing that the first physical device with a
+    /// graphics queue.
+    fn pick_physical_device(
+        instance: &ash::Instance,
+        headless: bool,
+    ) -> Result<vk::PhysicalDevice, DeviceCreationError> {
+        let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+
+        // Devices which can't present are only usable when headless.
+        let supports_swapchain = |pd| {
+            unsafe { instance.enumerate_device_extension_properties(pd) }
+                .unwrap_or_default()
+                .iter()
+                .any(|ext| unsafe { CStr::from_ptr(ext.extension_name.as_ptr()) } == khr::Swapchain::name())
+        };
+        let usable = physical_devices
+            .into_iter()
+            .filter(|&pd| {
+                let families = unsafe { instance.get_physical_device_queue_family_properties(pd) };
+                QueueFamilies::find(&families).is_some() && (headless || supports_swapchain(pd))
+            })
+            .collect::<Vec<_>>();
+
+        usable
+            .iter()
+            .copied()
+            .find(|&pd| {
+                let properties = unsafe { instance.get_physical_device_properties(pd) };
+                properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
+            })
+            .or_else(|| usable.first().copied())
+            .ok_or(DeviceCreationError::NoSuitablePhysicalDevice)
+    }
+}
+
+/// Strip the patch number from a version, so that it compares against the versions hot
+/// negotiates by major and minor version only.
+fn api_version_of(version: u32) -> u32 {
+    ash::vk_make_version!(ash::vk_version_major!(version), ash::vk_version_minor!(version), 0)
+}
+
+/// The queue family indices chosen for each queue type.
+struct QueueFamilies {
+    graphics: u32,
+    compute: u32,
+    transfer: u32,
+}
+
+impl QueueFamilies {
+    /// Find a graphics family, preferring dedicated families for compute and transfer and
+    /// falling back to the graphics family when they don't exist.
+    fn find(families: &[vk::QueueFamilyProperties]) -> Option<Self> {
+        let find_family = |required: vk::QueueFlags, excluded: vk::QueueFlags| {
+            families
+                .iter()
+                .position(|family| {
+                    family.queue_count > 0
+                        && family.queue_flags.contains(required)
+                        && !family.queue_flags.intersects(excluded)
+                })
+                .map(|idx| idx as u32)
+        };
+
+        let graphics = find_family(
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+            vk::QueueFlags::empty(),
+        )?;
+
+        let compute = find_family(vk::QueueFlags::COMPUTE, vk::QueueFlags::GRAPHICS)
+            .unwrap_or(graphics);
+
+        let transfer = find_family(
+            vk::QueueFlags::TRANSFER,
+            vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE,
+        )
+        .unwrap_or(compute);
+
+        Some(Self {
+            graphics,
+            compute,
+            transfer,
+        })
+    }
+}
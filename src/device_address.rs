@@ -0,0 +1,179 @@
+use ash::version::{DeviceV1_0, InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_buffer_device_address`, so the parts of it hot uses
+// are declared here.
+
+/// The name of the `VK_KHR_buffer_device_address` extension.
+pub(crate) fn buffer_device_address_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_buffer_device_address\0").unwrap()
+}
+
+/// The usage buffers need to have a device address, `VK_BUFFER_USAGE_SHADER_DEVICE_ADDRESS_BIT`.
+pub(crate) fn shader_device_address_usage() -> vk::BufferUsageFlags {
+    vk::BufferUsageFlags::from_raw(0x0002_0000)
+}
+
+/// `VK_MEMORY_ALLOCATE_DEVICE_ADDRESS_BIT`, which the memory of addressable buffers must be
+/// allocated with.
+const MEMORY_ALLOCATE_DEVICE_ADDRESS: vk::Flags = 0x2;
+
+#[repr(C)]
+pub(crate) struct PhysicalDeviceBufferDeviceAddressFeatures {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    pub(crate) buffer_device_address: vk::Bool32,
+    pub(crate) buffer_device_address_capture_replay: vk::Bool32,
+    pub(crate) buffer_device_address_multi_device: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceBufferDeviceAddressFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1_000_257_000),
+            p_next: std::ptr::null_mut(),
+            buffer_device_address: vk::FALSE,
+            buffer_device_address_capture_replay: vk::FALSE,
+            buffer_device_address_multi_device: vk::FALSE,
+        }
+    }
+}
+
+type VoidFunction = unsafe extern "system" fn() -> c_void;
+type GetBufferDeviceAddress =
+    unsafe extern "system" fn(vk::Device, *const vk::BufferDeviceAddressInfoEXT) -> vk::DeviceAddress;
+
+/// The device functions of `VK_KHR_buffer_device_address`.
+#[derive(Clone)]
+pub(crate) struct BufferDeviceAddressFn {
+    get_buffer_device_address: GetBufferDeviceAddress,
+}
+
+impl BufferDeviceAddressFn {
+    /// Load the functions, returning `None` if any of them is missing.
+    pub(crate) fn load(instance: &ash::Instance, device: &ash::Device) -> Option<Self> {
+        let load = |name: &[u8]| unsafe {
+            instance.get_device_proc_addr(device.handle(), CStr::from_bytes_with_nul(name).unwrap().as_ptr())
+        };
+        unsafe {
+            Some(Self {
+                get_buffer_device_address: std::mem::transmute::<VoidFunction, GetBufferDeviceAddress>(
+                    load(b"vkGetBufferDeviceAddressKHR\0")?,
+                ),
+            })
+        }
+    }
+}
+
+/// Get whether a physical device supports the `bufferDeviceAddress` feature. The instance must
+/// be at least version 1.1.
+pub(crate) fn supports_buffer_device_address(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut address_features = PhysicalDeviceBufferDeviceAddressFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut address_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    address_features.buffer_device_address == vk::TRUE
+}
+
+impl Device {
+    /// Whether buffers can be created with `BufferCreateInfo::wants_device_address`, i.e.
+    /// whether `VK_KHR_buffer_device_address` is enabled.
+    pub fn supports_buffer_device_address(&self) -> bool {
+        self.buffer_device_address.is_some()
+    }
+
+    /// Get the device address of a raw buffer created with `shader_device_address_usage`.
+    pub(crate) fn raw_buffer_device_address(&self, buffer: vk::Buffer) -> Option<vk::DeviceAddress> {
+        let fns = self.buffer_device_address.as_ref()?;
+        let info = vk::BufferDeviceAddressInfoEXT::builder().buffer(buffer);
+        Some(unsafe { (fns.get_buffer_device_address)(self.device.handle(), &*info) })
+    }
+
+    /// Create a buffer and the memory backing it, through the Device's allocator unless
+    /// `create_info.wants_device_address` is set.
+    ///
+    /// The allocator can't allocate memory with `VK_MEMORY_ALLOCATE_DEVICE_ADDRESS_BIT`, so the
+    /// memory of addressable buffers is a dedicated allocation instead, from a memory type
+    /// satisfying `alloc_desc` which is also host coherent if it's host visible.
+    pub(crate) fn create_raw_buffer(
+        &self,
+        create_info: BufferCreateInfo,
+        buffer_info: &vk::BufferCreateInfo,
+        alloc_desc: &AllocationDesc,
+    ) -> Result<(vk::Buffer, Allocation), AllocatorError> {
+        if !create_info.wants_device_address {
+            return self.allocator.create_buffer(buffer_info, alloc_desc);
+        }
+        if !self.supports_buffer_device_address() {
+            return Err(AllocatorError::DeviceAddressUnsupported);
+        }
+
+        let buffer = unsafe { self.device.create_buffer(buffer_info, None)? };
+        let allocate = || -> Result<Allocation, AllocatorError> {
+            let requirements = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+            let mut alloc_desc = *alloc_desc;
+            if alloc_desc.required_flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE) {
+                alloc_desc.required_flags |= vk::MemoryPropertyFlags::HOST_COHERENT;
+            }
+            let mut memory_type_bits = requirements.memory_type_bits;
+            if alloc_desc.memory_type_bits != 0 {
+                memory_type_bits &= alloc_desc.memory_type_bits;
+            }
+            // Memory types picked by index may be host visible without it being required.
+            memory_type_bits &= (0..self.memory_properties.memory_type_count)
+                .filter(|&i| {
+                    let flags = self.memory_properties.memory_types[i as usize].property_flags;
+                    !flags.contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+                        || flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT)
+                })
+                .fold(0, |bits, i| bits | 1 << i);
+            let memory_type = self.allocator.find_memory_type_index(memory_type_bits, &alloc_desc)?;
+
+            let mut flags_info = vk::MemoryAllocateFlagsInfo {
+                flags: vk::MemoryAllocateFlags::from_raw(MEMORY_ALLOCATE_DEVICE_ADDRESS),
+                ..Default::default()
+            };
+            let alloc_info = vk::MemoryAllocateInfo::builder()
+                .allocation_size(requirements.size)
+                .memory_type_index(memory_type)
+                .push_next(&mut flags_info);
+            let memory = unsafe { self.device.allocate_memory(&alloc_info, None)? };
+
+            let bound = unsafe { self.device.bind_buffer_memory(buffer, memory, 0) };
+            let mapped = bound.and_then(|()| {
+                if !alloc_desc.mapped || !self.is_memory_type_host_visible(memory_type) {
+                    return Ok(None);
+                }
+                let ptr = unsafe {
+                    self.device
+                        .map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty())?
+                };
+                Ok(std::ptr::NonNull::new(ptr as *mut u8))
+            });
+            match mapped {
+                Ok(mapped) => Ok(Allocation::dedicated(memory, requirements.size, memory_type, mapped)),
+                Err(e) => {
+                    unsafe { self.device.free_memory(memory, None) };
+                    Err(e.into())
+                }
+            }
+        };
+        match allocate() {
+            Ok(allocation) => Ok((buffer, allocation)),
+            Err(e) => {
+                unsafe { self.device.destroy_buffer(buffer, None) };
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use ash::version::{InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::*;
+use crate::format::{format_has_depth_or_stencil_aspect, format_to_aspect_mask};
+
+// The version of ash in use predates `VK_KHR_dynamic_rendering` and Vulkan 1.3, which promoted
+// it, so the parts of it hot uses are declared here.
+
+fn structure_type(offset: i32) -> vk::StructureType {
+    vk::StructureType::from_raw(1_000_044_000 + offset)
+}
+
+/// The name of the `VK_KHR_dynamic_rendering` extension.
+pub(crate) fn dynamic_rendering_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_dynamic_rendering\0").unwrap()
+}
+
+#[repr(C)]
+pub(crate) struct PhysicalDeviceDynamicRenderingFeatures {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    pub(crate) dynamic_rendering: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceDynamicRenderingFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: structure_type(3),
+            p_next: std::ptr::null_mut(),
+            dynamic_rendering: vk::FALSE,
+        }
+    }
+}
+
+#[repr(C)]
+struct RenderingAttachmentInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    image_view: vk::ImageView,
+    image_layout: vk::ImageLayout,
+    resolve_mode: vk::ResolveModeFlagsKHR,
+    resolve_image_view: vk::ImageView,
+    resolve_image_layout: vk::ImageLayout,
+    load_op: vk::AttachmentLoadOp,
+    store_op: vk::AttachmentStoreOp,
+    clear_value: vk::ClearValue,
+}
+
+#[repr(C)]
+struct RawRenderingInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    flags: vk::Flags,
+    render_area: vk::Rect2D,
+    layer_count: u32,
+    view_mask: u32,
+    color_attachment_count: u32,
+    p_color_attachments: *const RenderingAttachmentInfo,
+    p_depth_attachment: *const RenderingAttachmentInfo,
+    p_stencil_attachment: *const RenderingAttachmentInfo,
+}
+
+#[repr(C)]
+pub(crate) struct PipelineRenderingCreateInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    view_mask: u32,
+    color_attachment_count: u32,
+    p_color_attachment_formats: *const vk::Format,
+    depth_attachment_format: vk::Format,
+    stencil_attachment_format: vk::Format,
+}
+
+impl PipelineRenderingCreateInfo {
+    /// Describe the attachment formats of a pipeline used with dynamic rendering. The color
+    /// formats must outlive the returned struct.
+    pub(crate) fn new(color_formats: &[vk::Format], depth_stencil_format: Option<vk::Format>) -> Self {
+        let (depth, stencil) = depth_stencil_format
+            .map(|format| {
+                let aspects = format_to_aspect_mask(format);
+                let has = |aspect| if aspects.contains(aspect) { format } else { vk::Format::UNDEFINED };
+                (has(vk::ImageAspectFlags::DEPTH), has(vk::ImageAspectFlags::STENCIL))
+            })
+            .unwrap_or((vk::Format::UNDEFINED, vk::Format::UNDEFINED));
+        Self {
+            s_type: structure_type(2),
+            p_next: std::ptr::null(),
+            view_mask: 0,
+            color_attachment_count: color_formats.len() as u32,
+            p_color_attachment_formats: color_formats.as_ptr(),
+            depth_attachment_format: depth,
+            stencil_attachment_format: stencil,
+        }
+    }
+}
+
+type VoidFunction = unsafe extern "system" fn() -> c_void;
+type CmdBeginRendering = unsafe extern "system" fn(vk::CommandBuffer, *const RawRenderingInfo);
+type CmdEndRendering = unsafe extern "system" fn(vk::CommandBuffer);
+
+/// The device functions of `VK_KHR_dynamic_rendering`, or of Vulkan 1.3 core.
+#[derive(Clone)]
+pub(crate) struct DynamicRenderingFn {
+    cmd_begin_rendering: CmdBeginRendering,
+    cmd_end_rendering: CmdEndRendering,
+}
+
+impl DynamicRenderingFn {
+    /// Load the functions, by their core names if `core` or by the extension's otherwise,
+    /// returning `None` if any of them is missing.
+    pub(crate) fn load(instance: &ash::Instance, device: &ash::Device, core: bool) -> Option<Self> {
+        let load = |core_name: &[u8], name: &[u8]| unsafe {
+            let name = if core { core_name } else { name };
+            instance.get_device_proc_addr(device.handle(), CStr::from_bytes_with_nul(name).unwrap().as_ptr())
+        };
+        unsafe {
+            Some(Self {
+                cmd_begin_rendering: std::mem::transmute::<VoidFunction, CmdBeginRendering>(load(
+                    b"vkCmdBeginRendering\0",
+                    b"vkCmdBeginRenderingKHR\0",
+                )?),
+                cmd_end_rendering: std::mem::transmute::<VoidFunction, CmdEndRendering>(load(
+                    b"vkCmdEndRendering\0",
+                    b"vkCmdEndRenderingKHR\0",
+                )?),
+            })
+        }
+    }
+}
+
+/// Get whether a physical device supports the `dynamicRendering` feature. The instance must be
+/// at least version 1.1.
+pub(crate) fn supports_dynamic_rendering(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut rendering_features = PhysicalDeviceDynamicRenderingFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut rendering_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    rendering_features.dynamic_rendering == vk::TRUE
+}
+
+/// An attachment rendered to by `CommandBuffer::begin_rendering`.
+#[derive(Clone, Copy)]
+pub struct RenderingAttachment {
+    /// The view rendered to.
+    pub view: ImageViewHandle,
+    /// What happens to the contents of the view at the start of rendering.
+    pub load_op: vk::AttachmentLoadOp,
+    /// What happens to the contents of the view at the end of rendering.
+    pub store_op: vk::AttachmentStoreOp,
+    /// The value the view is cleared to if `load_op` is `CLEAR`.
+    pub clear_value: vk::ClearValue,
+}
+
+impl std::fmt::Debug for RenderingAttachment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // `vk::ClearValue` is a union, so can't be printed.
+        f.debug_struct("RenderingAttachment")
+            .field("view", &self.view)
+            .field("load_op", &self.load_op)
+            .field("store_op", &self.store_op)
+            .finish()
+    }
+}
+
+/// The attachments and area rendered to by `CommandBuffer::begin_rendering`.
+#[derive(Clone, Debug)]
+pub struct RenderingInfo {
+    /// The area rendered to.
+    pub render_area: vk::Rect2D,
+    /// The number of layers rendered to.
+    pub layer_count: u32,
+    /// The color attachments, in the order of the fragment shader's outputs.
+    pub color_attachments: Vec<RenderingAttachment>,
+    /// The depth stencil attachment, which is used for both the depth and stencil aspects of its
+    /// format.
+    pub depth_stencil_attachment: Option<RenderingAttachment>,
+}
+
+/// An attachment's view resolved for `begin_rendering`.
+struct Target {
+    image: ImageHandle,
+    range: vk::ImageSubresourceRange,
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    view: vk::ImageView,
+}
+
+impl RenderingInfo {
+    fn attachments(&self) -> impl Iterator<Item = &RenderingAttachment> {
+        self.color_attachments.iter().chain(self.depth_stencil_attachment.iter())
+    }
+}
+
+impl Device {
+    /// Get whether dynamic rendering is enabled, through Vulkan 1.3 or `VK_KHR_dynamic_rendering`,
+    /// i.e. whether `CommandBuffer::begin_rendering` renders without render pass and framebuffer
+    /// objects.
+    pub fn has_dynamic_rendering(&self) -> bool {
+        self.dynamic_rendering.is_some()
+    }
+}
+
+impl CommandBuffer {
+    /// Begin rendering to the attachments of `info`, which are transitioned to their attachment
+    /// layouts first. End it with `end_rendering`.
+    ///
+    /// With `VK_KHR_dynamic_rendering` the views are rendered to directly. Without it, a render
+    /// pass and framebuffer matching the attachments are requested from the Device's caches
+    /// instead, so the same code works on older drivers. Either way, pipelines used within must be
+    /// created with `GraphicsPipelineCreateInfo::color_formats` and `depth_stencil_format`
+    /// matching the attachments and no `render_pass`.
+    pub fn begin_rendering(&mut self, info: &RenderingInfo) -> Result<(), FramebufferError> {
+        assert!(!self.in_render_pass, "render passes cannot be nested");
+
+        let targets = {
+            let resources = self.device().resources();
+            info.attachments()
+                .map(|attachment| {
+                    let view = resources
+                        .get_image_view(attachment.view)
+                        .ok_or(FramebufferError::InvalidImageView)?;
+                    let view_info = view.create_info();
+                    let samples = resources
+                        .get_image(view_info.image)
+                        .ok_or(FramebufferError::InvalidImageView)?
+                        .create_info()
+                        .sample_count;
+                    let range = vk::ImageSubresourceRange {
+                        aspect_mask: format_to_aspect_mask(view_info.format),
+                        base_mip_level: view_info.base_mip_level as u32,
+                        level_count: 1,
+                        base_array_layer: view_info.base_array_layer as u32,
+                        layer_count: view_info.array_layers as u32,
+                    };
+                    Ok(Target {
+                        image: view_info.image,
+                        range,
+                        format: view_info.format,
+                        samples,
+                        view: view.raw(),
+                    })
+                })
+                .collect::<Result<Vec<_>, FramebufferError>>()?
+        };
+
+        for target in &targets {
+            let (layout, stages, access) = if format_has_depth_or_stencil_aspect(target.format) {
+                (
+                    vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+                    vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                        | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                    vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                )
+            } else {
+                (
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                )
+            };
+            self.transition_image_subresources(target.image, target.range, layout, stages, access);
+        }
+
+        let device = self.device().clone();
+        match device.dynamic_rendering {
+            Some(ref fns) => {
+                let attachment_info = |attachment: &RenderingAttachment, target: &Target, layout| {
+                    RenderingAttachmentInfo {
+                        s_type: structure_type(1),
+                        p_next: std::ptr::null(),
+                        image_view: target.view,
+                        image_layout: layout,
+                        resolve_mode: vk::ResolveModeFlagsKHR::NONE,
+                        resolve_image_view: vk::ImageView::null(),
+                        resolve_image_layout: vk::ImageLayout::UNDEFINED,
+                        load_op: attachment.load_op,
+                        store_op: attachment.store_op,
+                        clear_value: attachment.clear_value,
+                    }
+                };
+                let color_attachments = info
+                    .color_attachments
+                    .iter()
+                    .zip(&targets)
+                    .map(|(attachment, target)| {
+                        attachment_info(attachment, target, vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    })
+                    .collect::<Vec<_>>();
+                let depth_stencil_attachment = info.depth_stencil_attachment.as_ref().map(|attachment| {
+                    let target = targets.last().unwrap();
+                    let layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
+                    (attachment_info(attachment, target, layout), format_to_aspect_mask(target.format))
+                });
+                let aspect_attachment = |aspect| match depth_stencil_attachment {
+                    Some((ref attachment, aspects)) if aspects.contains(aspect) => attachment as *const _,
+                    _ => std::ptr::null(),
+                };
+
+                let rendering_info = RawRenderingInfo {
+                    s_type: structure_type(0),
+                    p_next: std::ptr::null(),
+                    flags: 0,
+                    render_area: info.render_area,
+                    layer_count: info.layer_count,
+                    view_mask: 0,
+                    color_attachment_count: color_attachments.len() as u32,
+                    p_color_attachments: color_attachments.as_ptr(),
+                    p_depth_attachment: aspect_attachment(vk::ImageAspectFlags::DEPTH),
+                    p_stencil_attachment: aspect_attachment(vk::ImageAspectFlags::STENCIL),
+                };
+                unsafe { (fns.cmd_begin_rendering)(self.raw(), &rendering_info) };
+                self.in_render_pass = true;
+                self.in_dynamic_rendering = true;
+            }
+            None => {
+                let attachment = |attachment: &RenderingAttachment, format, samples| {
+                    let layout = if format_has_depth_or_stencil_aspect(format) {
+                        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+                    } else {
+                        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+                    };
+                    RenderPassAttachment {
+                        format,
+                        samples,
+                        load_op: attachment.load_op,
+                        store_op: attachment.store_op,
+                        stencil_load_op: attachment.load_op,
+                        stencil_store_op: attachment.store_op,
+                        initial_layout: layout,
+                        final_layout: layout,
+                        format_promotion: true,
+                    }
+                };
+                let mut attachments = info
+                    .attachments()
+                    .zip(&targets)
+                    .map(|(info, target)| attachment(info, target.format, target.samples))
+                    .collect::<Vec<_>>();
+                let depth_stencil_attachment = if info.depth_stencil_attachment.is_some() {
+                    attachments.pop()
+                } else {
+                    None
+                };
+                let render_pass = device.request_render_pass(&RenderPassInfo {
+                    color_attachments: attachments,
+                    depth_stencil_attachment,
+                    resolve_attachments: Vec::new(),
+                })?;
+                let views = info.attachments().map(|attachment| attachment.view).collect::<Vec<_>>();
+                let framebuffer = device.request_framebuffer(render_pass, &views)?;
+                let clear_values = info
+                    .attachments()
+                    .map(|attachment| attachment.clear_value)
+                    .collect::<Vec<_>>();
+                self.begin_render_pass(render_pass, framebuffer, info.render_area, &clear_values);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// End rendering begun with `begin_rendering`.
+    pub fn end_rendering(&mut self) {
+        if self.in_dynamic_rendering {
+            let device = self.device().clone();
+            let fns = device.dynamic_rendering.as_ref().unwrap();
+            unsafe { (fns.cmd_end_rendering)(self.raw()) };
+            self.in_render_pass = false;
+            self.in_dynamic_rendering = false;
+        } else {
+            self.end_render_pass();
+        }
+    }
+}
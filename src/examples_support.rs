@@ -0,0 +1,246 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::*;
+
+/// The result type of the examples, which report any error by returning it from `main`.
+pub type ExampleResult<T = ()> = Result<T, Box<dyn std::error::Error>>;
+
+/// Create the Device used by an example, with validation enabled in debug builds.
+pub fn create_device(app_name: &str) -> Result<Arc<Device>, DeviceCreationError> {
+    DeviceBuilder::new()
+        .app_name(app_name)
+        .validation(cfg!(debug_assertions))
+        .build()
+}
+
+/// Get the path of a file an example writes its output to, in the crate's `target` directory.
+pub fn output_path(file_name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("target").join(file_name)
+}
+
+/// Load `examples/shaders/<name>.spv`, which is compiled from the GLSL source
+/// `examples/shaders/<name>` with `glslc examples/shaders/<name> -o examples/shaders/<name>.spv`.
+pub fn load_shader(device: &Device, name: &str) -> ExampleResult<Shader> {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("examples/shaders")
+        .join(format!("{}.spv", name));
+    let bytes = std::fs::read(&path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    if bytes.len() % 4 != 0 {
+        return Err(format!("{} is not SPIR-V", path.display()).into());
+    }
+
+    let code = bytes
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        .collect::<Vec<_>>();
+    Ok(device.create_shader(&code)?)
+}
+
+/// Run `frames` frames, calling `frame` with the `Frame` and index of each between
+/// `Device::begin_frame` and `Device::end_frame`, then wait for the GPU to finish them.
+pub fn run_frames<F>(device: &Arc<Device>, frames: u64, mut frame: F) -> ExampleResult
+where
+    F: FnMut(&Frame, u64) -> ExampleResult,
+{
+    for index in 0..frames {
+        let current = device.begin_frame()?;
+        frame(&current, index)?;
+        device.end_frame(current)?;
+    }
+    unsafe { device.raw_device().device_wait_idle()? };
+    Ok(())
+}
+
+/// A descriptor pool which descriptor sets of any layout an example uses can be allocated from.
+/// The sets are freed along with the pool when it is dropped.
+pub struct DescriptorAllocator {
+    device: Arc<Device>,
+    pool: vk::DescriptorPool,
+}
+
+impl DescriptorAllocator {
+    /// Create a pool with room for `max_sets` sets of up to 4 descriptors of each type.
+    pub fn new(device: &Arc<Device>, max_sets: u32) -> Result<Self, vk::Result> {
+        let pool_sizes = [
+            vk::DescriptorType::SAMPLER,
+            vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            vk::DescriptorType::STORAGE_IMAGE,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            vk::DescriptorType::STORAGE_BUFFER,
+        ]
+        .iter()
+        .map(|&ty| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count: max_sets * 4,
+        })
+        .collect::<Vec<_>>();
+        let create_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(max_sets)
+            .pool_sizes(&pool_sizes);
+        let pool = unsafe { device.raw_device().create_descriptor_pool(&create_info, None)? };
+
+        Ok(Self {
+            device: device.clone(),
+            pool,
+        })
+    }
+
+    /// Allocate a descriptor set of `layout`.
+    pub fn allocate(&self, layout: vk::DescriptorSetLayout) -> Result<vk::DescriptorSet, vk::Result> {
+        let layouts = [layout];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.pool)
+            .set_layouts(&layouts);
+        let sets = unsafe { self.device.raw_device().allocate_descriptor_sets(&allocate_info)? };
+        Ok(sets[0])
+    }
+}
+
+impl Drop for DescriptorAllocator {
+    fn drop(&mut self) {
+        unsafe { self.device.raw_device().destroy_descriptor_pool(self.pool, None) };
+    }
+}
+
+/// A color image to render to in place of a window, with a single subpass render pass and a
+/// framebuffer for it, whose contents can be saved to a file to look at.
+pub struct OffscreenTarget {
+    device: Arc<Device>,
+    image: ImageHandle,
+    extent: vk::Extent2D,
+    render_pass: vk::RenderPass,
+    framebuffer: vk::Framebuffer,
+}
+
+impl OffscreenTarget {
+    /// The format of the image.
+    pub const FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+    /// Create a target of `width` by `height` pixels, which is cleared at the start of its
+    /// render pass.
+    pub fn new(device: &Arc<Device>, width: u32, height: u32) -> ExampleResult<Self> {
+        let mut create_info =
+            ImageCreateInfo::render_target(width as usize, height as usize, Self::FORMAT, false);
+        create_info.usage |= vk::ImageUsageFlags::SAMPLED;
+        create_info.initial_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+        let image = device
+            .clone()
+            .create_image(create_info, None, Some(Tag::Static("offscreen target")))?;
+        let view = device
+            .resources()
+            .get_image(image)
+            .and_then(|image| image.view().map(ImageView::raw))
+            .expect("OffscreenTarget: image has no view");
+
+        let attachment = vk::AttachmentDescription::builder()
+            .format(Self::FORMAT)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .load_op(vk::AttachmentLoadOp::CLEAR)
+            .store_op(vk::AttachmentStoreOp::STORE)
+            .initial_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+            .build();
+        let color_reference = vk::AttachmentReference {
+            attachment: 0,
+            layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        };
+        let subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(std::slice::from_ref(&color_reference))
+            .build();
+        let render_pass_info = vk::RenderPassCreateInfo::builder()
+            .attachments(std::slice::from_ref(&attachment))
+            .subpasses(std::slice::from_ref(&subpass));
+        let render_pass = unsafe { device.raw_device().create_render_pass(&render_pass_info, None)? };
+
+        let views = [view];
+        let framebuffer_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&views)
+            .width(width)
+            .height(height)
+            .layers(1);
+        let framebuffer = match unsafe { device.raw_device().create_framebuffer(&framebuffer_info, None) } {
+            Ok(framebuffer) => framebuffer,
+            Err(e) => {
+                unsafe { device.raw_device().destroy_render_pass(render_pass, None) };
+                device.destroy_image(image);
+                return Err(e.into());
+            }
+        };
+
+        Ok(Self {
+            device: device.clone(),
+            image,
+            extent: vk::Extent2D { width, height },
+            render_pass,
+            framebuffer,
+        })
+    }
+
+    /// The image rendered to.
+    pub fn image(&self) -> ImageHandle {
+        self.image
+    }
+
+    /// The render pass pipelines drawing to the target must be compatible with.
+    pub fn render_pass(&self) -> vk::RenderPass {
+        self.render_pass
+    }
+
+    /// Begin the target's render pass, clearing it to `clear_color`, and set the viewport and
+    /// scissor to cover it.
+    pub fn begin(&self, cmd: &mut CommandBuffer, clear_color: [f32; 4]) {
+        cmd.transition_image(
+            self.image,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        );
+        let clear = vk::ClearValue {
+            color: vk::ClearColorValue { float32: clear_color },
+        };
+        cmd.begin_render_pass(
+            self.render_pass,
+            self.framebuffer,
+            vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: self.extent,
+            },
+            &[clear],
+        );
+        cmd.set_viewport_for(self.image, false);
+    }
+
+    /// Read the target back and save it as a binary PPM image at `path`, waiting for all work
+    /// submitted so far to render to it.
+    pub fn save_ppm<P: AsRef<Path>>(&self, path: P) -> ExampleResult {
+        let capture = self.device.capture_image(self.image)?;
+
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        write!(file, "P6\n{} {}\n255\n", capture.width, capture.height)?;
+        for texel in capture.pixels.chunks_exact(4) {
+            file.write_all(&texel[..3])?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for OffscreenTarget {
+    fn drop(&mut self) {
+        unsafe {
+            // The target may still be in use by frames in flight.
+            let _ = self.device.raw_device().device_wait_idle();
+            self.device.raw_device().destroy_framebuffer(self.framebuffer, None);
+            self.device.raw_device().destroy_render_pass(self.render_pass, None);
+        }
+        self.device.destroy_image(self.image);
+    }
+}
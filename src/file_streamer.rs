@@ -0,0 +1,387 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use generational_arena as ga;
+
+use thiserror::Error;
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::*;
+use crate::command_buffer::subresource_layers_range;
+
+/// Regions smaller than this are always streamed through a staging copy, since importing host
+/// memory has a fixed cost which only pays off for larger transfers.
+pub const FILE_STREAM_IMPORT_THRESHOLD: vk::DeviceSize = 256 * 1024;
+
+/// An error that could occur while streaming a file region.
+#[derive(Error, Debug)]
+pub enum FileStreamError {
+    /// The file has been closed.
+    #[error("the file is not open")]
+    InvalidFile,
+    /// The destination buffer does not exist.
+    #[error("the destination buffer does not exist")]
+    InvalidBuffer,
+    /// The destination image does not exist.
+    #[error("the destination image does not exist")]
+    InvalidImage,
+    /// The region extends past the end of the file.
+    #[error("region of {size} bytes at offset {offset} is out of bounds of a {len} byte file")]
+    OutOfBounds {
+        /// The offset of the region in bytes.
+        offset: u64,
+        /// The size of the region in bytes.
+        size: u64,
+        /// The length of the file in bytes.
+        len: u64,
+    },
+    /// Opening, mapping or reading the file failed.
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    /// Importing the mapped file failed.
+    #[error("host import error: {0}")]
+    Import(#[from] HostImportError),
+    /// Allocating staging memory failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A handle to a file opened by a `FileStreamer`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct StreamFileHandle {
+    idx: ga::Index,
+}
+
+/// Streams regions of asset pack files into GPU resources on the async transfer queue.
+///
+/// On unix files are memory-mapped. Where the device supports `VK_EXT_external_memory_host`,
+/// large regions are imported directly from the mapping and copied by the GPU, so the data is
+/// never copied on the CPU; otherwise (and on other platforms) regions are copied through a
+/// staging block. Uploads are complete before the next graphics submission runs, as with
+/// `Device::create_buffer`.
+pub struct FileStreamer {
+    device: Arc<Device>,
+    files: ga::Arena<MappedFile>,
+    /// Whether the GPU may still be reading mappings, which must then not be unmapped until
+    /// the device is idle.
+    imported: bool,
+}
+
+impl FileStreamer {
+    /// Create a new FileStreamer with no open files.
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            files: ga::Arena::new(),
+            imported: false,
+        }
+    }
+
+    /// Open (and on unix, map) the file at `path`.
+    pub fn open<P: AsRef<Path>>(&mut self, path: P) -> Result<StreamFileHandle, FileStreamError> {
+        let file = MappedFile::open(File::open(path)?)?;
+        Ok(StreamFileHandle {
+            idx: self.files.insert(file),
+        })
+    }
+
+    /// Close a file opened with `open`.
+    ///
+    /// If regions have been imported from any file, this waits for the device to be idle so
+    /// that the mapping is not in use by the GPU.
+    pub fn close(&mut self, file: StreamFileHandle) {
+        if let Some(file) = self.files.remove(file.idx) {
+            if self.imported {
+                let _ = unsafe { self.device.device_wait_idle() };
+                self.imported = false;
+            }
+            drop(file);
+        }
+    }
+
+    /// Get the length in bytes of an open file.
+    pub fn len(&self, file: StreamFileHandle) -> Option<u64> {
+        self.files.get(file.idx).map(|file| file.len)
+    }
+
+    /// Stream `size` bytes of `file` at `offset` into `dst` at `dst_offset`.
+    pub fn stream_to_buffer(
+        &mut self,
+        file: StreamFileHandle,
+        offset: u64,
+        size: u64,
+        dst: BufferHandle,
+        dst_offset: vk::DeviceSize,
+    ) -> Result<(), FileStreamError> {
+        if self.device.resources().get_buffer(dst).is_none() {
+            return Err(FileStreamError::InvalidBuffer);
+        }
+
+        self.stream(file, offset, size, move |cmd, src, src_offset| {
+            cmd.transition_buffer(dst, vk::PipelineStageFlags::TRANSFER, vk::AccessFlags::TRANSFER_WRITE);
+            let dst = cmd
+                .device()
+                .resources()
+                .get_buffer(dst)
+                .expect("stream_to_buffer: buffer was destroyed during upload")
+                .raw();
+            let region = vk::BufferCopy {
+                src_offset,
+                dst_offset,
+                size,
+            };
+            unsafe { cmd.device().cmd_copy_buffer(cmd.raw(), src, dst, &[region]) };
+        })
+    }
+
+    /// Stream `size` bytes of `file` at `offset` into `dst`, copying `regions`. The
+    /// `buffer_offset` of each region is relative to `offset`.
+    ///
+    /// The copied subresources are left in `TRANSFER_DST_OPTIMAL` (or `GENERAL`) and are
+    /// transitioned as usual when next used.
+    pub fn stream_to_image(
+        &mut self,
+        file: StreamFileHandle,
+        offset: u64,
+        size: u64,
+        dst: ImageHandle,
+        regions: &[vk::BufferImageCopy],
+    ) -> Result<(), FileStreamError> {
+        if self.device.resources().get_image(dst).is_none() {
+            return Err(FileStreamError::InvalidImage);
+        }
+
+        self.stream(file, offset, size, move |cmd, src, src_offset| {
+            for region in regions {
+                cmd.transition_image_subresources(
+                    dst,
+                    subresource_layers_range(region.image_subresource),
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                );
+            }
+            let (dst, dst_layout) = {
+                let resources = cmd.device().resources();
+                let image = resources
+                    .get_image(dst)
+                    .expect("stream_to_image: image was destroyed during upload");
+                (image.raw(), image.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL))
+            };
+            let regions = regions
+                .iter()
+                .map(|&region| vk::BufferImageCopy {
+                    buffer_offset: src_offset + region.buffer_offset,
+                    ..region
+                })
+                .collect::<Vec<_>>();
+            unsafe {
+                cmd.device()
+                    .cmd_copy_buffer_to_image(cmd.raw(), src, dst, dst_layout, &regions)
+            };
+        })
+    }
+
+    /// Record a copy out of `size` bytes of `file` at `offset` with `record`, which is given
+    /// the source buffer and the offset of the region within it.
+    fn stream<F>(&mut self, file: StreamFileHandle, offset: u64, size: u64, record: F) -> Result<(), FileStreamError>
+    where
+        F: FnOnce(&mut CommandBuffer, vk::Buffer, vk::DeviceSize),
+    {
+        let file = self.files.get(file.idx).ok_or(FileStreamError::InvalidFile)?;
+        if offset.checked_add(size).map_or(true, |end| end > file.len) {
+            return Err(FileStreamError::OutOfBounds {
+                offset,
+                size,
+                len: file.len,
+            });
+        }
+        if size == 0 {
+            return Ok(());
+        }
+
+        file.will_need(offset, size);
+
+        if size >= FILE_STREAM_IMPORT_THRESHOLD {
+            let window = self
+                .device
+                .host_import_alignment()
+                .and_then(|alignment| file.import_window(offset, size, alignment));
+            if let Some((ptr, window_size, src_offset)) = window {
+                let buffer = unsafe {
+                    self.device.clone().import_host_buffer(
+                        ptr,
+                        window_size,
+                        vk::BufferUsageFlags::TRANSFER_SRC,
+                        Some(Tag::Static("FileStreamer import")),
+                    )?
+                };
+                self.imported = true;
+
+                let result = self.device.submit_upload(|cmd| {
+                    let src = cmd
+                        .device()
+                        .resources()
+                        .get_buffer(buffer)
+                        .expect("FileStreamer: imported buffer was destroyed")
+                        .raw();
+                    record(cmd, src, src_offset)
+                });
+                // Destruction is deferred until the frame's submissions have completed.
+                self.device.destroy_buffer(buffer);
+                return Ok(result?);
+            }
+        }
+
+        let data = file.read(offset, size)?;
+        self.device.upload_via_staging(&data, Some(Tag::Static("FileStreamer staging")), record)?;
+        Ok(())
+    }
+}
+
+impl Drop for FileStreamer {
+    fn drop(&mut self) {
+        if self.imported {
+            // Imported mappings may still be read by uploads in flight.
+            let _ = unsafe { self.device.device_wait_idle() };
+        }
+    }
+}
+
+/// An open file, memory-mapped where the platform supports it.
+struct MappedFile {
+    #[cfg(unix)]
+    mapping: Option<std::ptr::NonNull<u8>>,
+    #[cfg(unix)]
+    page_size: u64,
+    #[cfg(not(unix))]
+    file: File,
+    len: u64,
+}
+
+#[cfg(unix)]
+impl MappedFile {
+    fn open(file: File) -> io::Result<Self> {
+        use std::os::unix::io::AsRawFd;
+
+        let len = file.metadata()?.len();
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+        // A zero length file cannot be mapped, but has no regions to stream either.
+        let mapping = if len == 0 {
+            None
+        } else {
+            // Mapped writable (though private, so nothing is ever written back) since some
+            // implementations cannot import read-only mappings.
+            let ptr = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len as usize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+            std::ptr::NonNull::new(ptr as *mut u8)
+        };
+
+        Ok(Self {
+            mapping,
+            page_size,
+            len,
+        })
+    }
+
+    /// The length of the mapping, which extends to the end of the last page of the file.
+    fn mapped_len(&self) -> u64 {
+        self.len.div_ceil(self.page_size) * self.page_size
+    }
+
+    /// Hint that the pages of a region will be read soon, so that the kernel starts reading
+    /// them in before the region is copied or imported.
+    fn will_need(&self, offset: u64, size: u64) {
+        if let Some(mapping) = self.mapping {
+            let start = offset / self.page_size * self.page_size;
+            unsafe {
+                libc::madvise(
+                    mapping.as_ptr().add(start as usize) as *mut _,
+                    (offset + size - start) as usize,
+                    libc::MADV_WILLNEED,
+                );
+            }
+        }
+    }
+
+    /// Get the smallest `alignment` aligned window of the mapping containing a region, as its
+    /// pointer, its size and the offset of the region within it, if it lies within the mapping.
+    fn import_window(&self, offset: u64, size: u64, alignment: u64) -> Option<(std::ptr::NonNull<u8>, u64, u64)> {
+        let mapping = self.mapping?;
+        let alignment = alignment.max(self.page_size);
+
+        let base = mapping.as_ptr() as u64;
+        let start = (base + offset) / alignment * alignment;
+        let end = (base + offset + size).div_ceil(alignment) * alignment;
+        if start < base || end > base + self.mapped_len() {
+            return None;
+        }
+
+        let ptr = unsafe { mapping.as_ptr().add((start - base) as usize) };
+        Some((std::ptr::NonNull::new(ptr)?, end - start, base + offset - start))
+    }
+
+    fn read(&self, offset: u64, size: u64) -> io::Result<Cow<'_, [u8]>> {
+        let mapping = self.mapping.expect("read a region of an empty file");
+        Ok(Cow::Borrowed(unsafe {
+            std::slice::from_raw_parts(mapping.as_ptr().add(offset as usize), size as usize)
+        }))
+    }
+}
+
+#[cfg(not(unix))]
+impl MappedFile {
+    fn open(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+
+    fn will_need(&self, _offset: u64, _size: u64) {}
+
+    fn import_window(&self, _offset: u64, _size: u64, _alignment: u64) -> Option<(std::ptr::NonNull<u8>, u64, u64)> {
+        None
+    }
+
+    fn read(&self, offset: u64, size: u64) -> io::Result<Cow<'_, [u8]>> {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let mut data = vec![0; size as usize];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Cow::Owned(data))
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        if let Some(mapping) = self.mapping {
+            unsafe { libc::munmap(mapping.as_ptr() as *mut _, self.len as usize) };
+        }
+    }
+}
+
+// The mapping is only read through `&self`, and is never written.
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
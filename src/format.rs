@@ -1,41 +1,41 @@
-use ash::vk::Format;
+use ash::vk::{Format, ImageAspectFlags};
 
 /// Get whether a format is SRGB or not.
 pub fn format_is_srgb(format: Format) -> bool {
-    match format {
+    matches!(
+        format,
         Format::A8B8G8R8_SRGB_PACK32
-        | Format::R8G8B8A8_SRGB
-        | Format::B8G8R8A8_SRGB
-        | Format::R8_SRGB
-        | Format::R8G8_SRGB
-        | Format::R8G8B8_SRGB
-        | Format::B8G8R8_SRGB => true,
-        _ => false,
-    }
+            | Format::R8G8B8A8_SRGB
+            | Format::B8G8R8A8_SRGB
+            | Format::R8_SRGB
+            | Format::R8G8_SRGB
+            | Format::R8G8B8_SRGB
+            | Format::B8G8R8_SRGB
+    )
 }
 
 /// Get whether a format has a depth aspect.
 pub fn format_has_depth_aspect(format: Format) -> bool {
-    match format {
+    matches!(
+        format,
         Format::D16_UNORM
-        | Format::D16_UNORM_S8_UINT
-        | Format::D24_UNORM_S8_UINT
-        | Format::D32_SFLOAT
-        | Format::X8_D24_UNORM_PACK32
-        | Format::D32_SFLOAT_S8_UINT => true,
-        _ => false,
-    }
+            | Format::D16_UNORM_S8_UINT
+            | Format::D24_UNORM_S8_UINT
+            | Format::D32_SFLOAT
+            | Format::X8_D24_UNORM_PACK32
+            | Format::D32_SFLOAT_S8_UINT
+    )
 }
 
 /// Get whether a format has a stencil aspect.
 pub fn format_has_stencil_aspect(format: Format) -> bool {
-    match format {
+    matches!(
+        format,
         Format::D16_UNORM_S8_UINT
-        | Format::D24_UNORM_S8_UINT
-        | Format::D32_SFLOAT_S8_UINT
-        | Format::S8_UINT => true,
-        _ => false,
-    }
+            | Format::D24_UNORM_S8_UINT
+            | Format::D32_SFLOAT_S8_UINT
+            | Format::S8_UINT
+    )
 }
 
 /// Get whether a format has a depth or stencil aspect.
@@ -43,56 +43,140 @@ pub fn format_has_depth_or_stencil_aspect(format: Format) -> bool {
     format_has_depth_aspect(format) || format_has_stencil_aspect(format)
 }
 
-/*
-static inline VkImageAspectFlags format_to_aspect_mask(VkFormat format)
-{
-    switch (format)
-    {
-    case VK_FORMAT_UNDEFINED:
-        return 0;
-
-    case VK_FORMAT_S8_UINT:
-        return VK_IMAGE_ASPECT_STENCIL_BIT;
-
-    case VK_FORMAT_D16_UNORM_S8_UINT:
-    case VK_FORMAT_D24_UNORM_S8_UINT:
-    case VK_FORMAT_D32_SFLOAT_S8_UINT:
-        return VK_IMAGE_ASPECT_STENCIL_BIT | VK_IMAGE_ASPECT_DEPTH_BIT;
+/// Get the `vk::ImageAspectFlags` covering all aspects of a format.
+pub fn format_to_aspect_mask(format: Format) -> ImageAspectFlags {
+    match format {
+        Format::UNDEFINED => ImageAspectFlags::empty(),
+        Format::S8_UINT => ImageAspectFlags::STENCIL,
+        Format::D16_UNORM_S8_UINT | Format::D24_UNORM_S8_UINT | Format::D32_SFLOAT_S8_UINT => {
+            ImageAspectFlags::STENCIL | ImageAspectFlags::DEPTH
+        }
+        Format::D16_UNORM | Format::D32_SFLOAT | Format::X8_D24_UNORM_PACK32 => ImageAspectFlags::DEPTH,
+        _ => ImageAspectFlags::COLOR,
+    }
+}
 
-    case VK_FORMAT_D16_UNORM:
-    case VK_FORMAT_D32_SFLOAT:
-    case VK_FORMAT_X8_D24_UNORM_PACK32:
-        return VK_IMAGE_ASPECT_DEPTH_BIT;
+/// Get the dimensions in texels of a block of a format. This is `(1, 1)` for uncompressed
+/// formats.
+pub fn format_block_dim(format: Format) -> (u32, u32) {
+    const ASTC_BLOCK_DIMS: [(u32, u32); 14] = [
+        (4, 4), (5, 4), (5, 5), (6, 5), (6, 6), (8, 5), (8, 6),
+        (8, 8), (10, 5), (10, 6), (10, 8), (10, 10), (12, 10), (12, 12),
+    ];
 
-    default:
-        return VK_IMAGE_ASPECT_COLOR_BIT;
+    match format.as_raw() {
+        // BC1_RGB_UNORM_BLOCK ..= EAC_R11G11_SNORM_BLOCK
+        131..=156 => (4, 4),
+        // ASTC_4X4_UNORM_BLOCK ..= ASTC_12X12_SRGB_BLOCK, in UNORM and SRGB pairs.
+        157..=184 => ASTC_BLOCK_DIMS[(format.as_raw() - 157) as usize / 2],
+        _ => (1, 1),
     }
 }
 
-static inline void format_align_dim(VkFormat format, uint32_t &width, uint32_t &height)
-{
-    uint32_t align_width, align_height;
-    TextureFormatLayout::format_block_dim(format, align_width, align_height);
-    width = ((width + align_width - 1) / align_width) * align_width;
-    height = ((height + align_height - 1) / align_height) * align_height;
+/// Get the size in bytes of a block of a format, or `None` if the format is unknown or is a
+/// combined depth stencil format, whose aspects must be copied separately.
+pub fn format_block_size(format: Format) -> Option<u32> {
+    let size = match format.as_raw() {
+        // R4G4_UNORM_PACK8
+        1 => 1,
+        // R4G4B4A4_UNORM_PACK16 ..= A1R5G5B5_UNORM_PACK16
+        2..=8 => 2,
+        // R8_UNORM ..= R8_SRGB
+        9..=15 => 1,
+        // R8G8_UNORM ..= R8G8_SRGB
+        16..=22 => 2,
+        // R8G8B8_UNORM ..= B8G8R8_SRGB
+        23..=36 => 3,
+        // R8G8B8A8_UNORM ..= A2B10G10R10_SINT_PACK32
+        37..=69 => 4,
+        // R16_UNORM ..= R16_SFLOAT
+        70..=76 => 2,
+        // R16G16_UNORM ..= R16G16_SFLOAT
+        77..=83 => 4,
+        // R16G16B16_UNORM ..= R16G16B16_SFLOAT
+        84..=90 => 6,
+        // R16G16B16A16_UNORM ..= R16G16B16A16_SFLOAT
+        91..=97 => 8,
+        // R32_UINT ..= R32_SFLOAT
+        98..=100 => 4,
+        // R32G32_UINT ..= R32G32_SFLOAT
+        101..=103 => 8,
+        // R32G32B32_UINT ..= R32G32B32_SFLOAT
+        104..=106 => 12,
+        // R32G32B32A32_UINT ..= R32G32B32A32_SFLOAT
+        107..=109 => 16,
+        // R64_UINT ..= R64G64B64A64_SFLOAT
+        110..=121 => 8 * ((format.as_raw() - 110) as u32 / 3 + 1),
+        // B10G11R11_UFLOAT_PACK32, E5B9G9R9_UFLOAT_PACK32
+        122..=123 => 4,
+        // D16_UNORM
+        124 => 2,
+        // X8_D24_UNORM_PACK32, D32_SFLOAT
+        125..=126 => 4,
+        // S8_UINT
+        127 => 1,
+        // BC1_RGB_UNORM_BLOCK ..= BC1_RGBA_SRGB_BLOCK
+        131..=134 => 8,
+        // BC2_UNORM_BLOCK ..= BC3_SRGB_BLOCK
+        135..=138 => 16,
+        // BC4_UNORM_BLOCK, BC4_SNORM_BLOCK
+        139..=140 => 8,
+        // BC5_UNORM_BLOCK ..= BC7_SRGB_BLOCK
+        141..=146 => 16,
+        // ETC2_R8G8B8_UNORM_BLOCK ..= ETC2_R8G8B8A1_SRGB_BLOCK
+        147..=150 => 8,
+        // ETC2_R8G8B8A8_UNORM_BLOCK, ETC2_R8G8B8A8_SRGB_BLOCK
+        151..=152 => 16,
+        // EAC_R11_UNORM_BLOCK, EAC_R11_SNORM_BLOCK
+        153..=154 => 8,
+        // EAC_R11G11_UNORM_BLOCK, EAC_R11G11_SNORM_BLOCK, and every ASTC format
+        155..=184 => 16,
+        _ => return None,
+    };
+    Some(size)
 }
 
-static inline void format_num_blocks(VkFormat format, uint32_t &width, uint32_t &height)
-{
-    uint32_t align_width, align_height;
-    TextureFormatLayout::format_block_dim(format, align_width, align_height);
-    width = (width + align_width - 1) / align_width;
-    height = (height + align_height - 1) / align_height;
+/// Get the size in bytes of one layer of an image of a format with the given dimensions in
+/// texels, or `None` if the block size of the format is unknown.
+pub fn format_layer_size(format: Format, width: u32, height: u32, depth: u32) -> Option<u64> {
+    let (block_width, block_height) = format_block_dim(format);
+    let blocks_x = width.div_ceil(block_width) as u64;
+    let blocks_y = height.div_ceil(block_height) as u64;
+    Some(format_block_size(format)? as u64 * depth as u64 * blocks_x * blocks_y)
 }
 
-static inline VkDeviceSize format_get_layer_size(VkFormat format, unsigned width, unsigned height, unsigned depth)
-{
-    uint32_t blocks_x = width;
-    uint32_t blocks_y = height;
-    format_num_blocks(format, blocks_x, blocks_y);
-    format_align_dim(format, width, height);
+/// Get the size in bytes of a texel of a single aspect of a format as laid out in a buffer
+/// by buffer-image copies, or `None` if the format does not have exactly that aspect or its
+/// block size is unknown.
+///
+/// Unlike `format_block_size`, this covers the depth and stencil aspects of combined depth
+/// stencil formats, which must be copied separately.
+pub fn format_aspect_block_size(format: Format, aspect: ImageAspectFlags) -> Option<u32> {
+    match (format, aspect) {
+        (Format::D16_UNORM_S8_UINT, ImageAspectFlags::DEPTH) => Some(2),
+        // The depth aspect of D24_UNORM_S8_UINT is copied as X8_D24_UNORM_PACK32.
+        (Format::D24_UNORM_S8_UINT, ImageAspectFlags::DEPTH) => Some(4),
+        (Format::D32_SFLOAT_S8_UINT, ImageAspectFlags::DEPTH) => Some(4),
+        (Format::D16_UNORM_S8_UINT, ImageAspectFlags::STENCIL)
+        | (Format::D24_UNORM_S8_UINT, ImageAspectFlags::STENCIL)
+        | (Format::D32_SFLOAT_S8_UINT, ImageAspectFlags::STENCIL) => Some(1),
+        _ if aspect == format_to_aspect_mask(format) => format_block_size(format),
+        _ => None,
+    }
+}
 
-    VkDeviceSize size = TextureFormatLayout::format_block_size(format) * depth * blocks_x * blocks_y;
-    return size;
+/// Get the size in bytes of one layer of a single aspect of an image of a format with the
+/// given dimensions in texels, as laid out in a buffer by buffer-image copies. See
+/// `format_aspect_block_size`.
+pub fn format_aspect_layer_size(
+    format: Format,
+    aspect: ImageAspectFlags,
+    width: u32,
+    height: u32,
+    depth: u32,
+) -> Option<u64> {
+    let (block_width, block_height) = format_block_dim(format);
+    let blocks_x = width.div_ceil(block_width) as u64;
+    let blocks_y = height.div_ceil(block_height) as u64;
+    Some(format_aspect_block_size(format, aspect)? as u64 * depth as u64 * blocks_x * blocks_y)
 }
-*/
@@ -0,0 +1,263 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use parking_lot::*;
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::*;
+
+/// A token for the frame being recorded, returned by `Device::begin_frame` and consumed by
+/// `Device::end_frame`.
+///
+/// Frame-scoped operations, i.e. requesting buffer blocks, command buffers and scratch images
+/// which are recycled once the frame completes, take a `&Frame`, so they can't be used outside
+/// of a frame. A `Frame` which is dropped without being ended leaves its frame active, so the
+/// next `begin_frame` panics.
+#[must_use = "a Frame must be ended with `Device::end_frame`"]
+#[derive(Debug)]
+pub struct Frame {
+    device_id: u64,
+    index: usize,
+}
+
+impl Frame {
+    /// Get the index of the frame's slot, in `0..frames_in_flight`.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+/// The id of the next Device to be built.
+static NEXT_DEVICE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Get an id for a new Device, unique within the process, which its `Frame`s are checked against.
+pub(crate) fn next_device_id() -> u64 {
+    NEXT_DEVICE_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Device {
+    /// Get the index of the frame currently being recorded, in `0..frames_in_flight`.
+    pub fn current_frame_index(&self) -> usize {
+        self.current_frame_index.load(Ordering::Acquire)
+    }
+
+    /// Get the number of frames which may be in flight on the GPU at once.
+    pub fn frames_in_flight(&self) -> usize {
+        self.per_frame.len()
+    }
+
+    /// Check that `frame` is the active frame of this Device, rather than of another one.
+    pub(crate) fn check_frame(&self, frame: &Frame) {
+        assert_eq!(frame.device_id, self.id, "the Frame was begun by another Device");
+        assert_eq!(
+            frame.index,
+            self.current_frame_index(),
+            "the Frame is not the current frame of this Device"
+        );
+    }
+
+    /// Get the per-frame data of the frame currently being recorded.
+    pub(crate) fn current_frame(&self) -> &RwLock<PerFrame> {
+        &self.per_frame[self.current_frame_index()]
+    }
+
+    /// Begin a new frame.
+    ///
+    /// Advances to the next frame slot and, in order:
+    ///
+    /// 1. Waits until the GPU has finished the frame last recorded in the slot.
+    /// 2. Resets the slot's command pools, frees the resources destroyed during it and recycles the
+    ///    scratch images it used.
+    /// 3. Reads back the completed frame's zone timings for `collect_gpu_timings`, if GPU profiling
+    ///    is enabled.
+    /// 4. Recycles the buffer blocks the slot used, and frees recycled blocks which have gone
+    ///    unused for `DeviceBuilder::block_trim_frames` frames.
+    /// 5. Makes the render scale set with `set_render_scale` take effect.
+    /// 6. Latches the cache hits and misses counted since the last `begin_frame` for `cache_stats`.
+    /// 7. Sends the screenshots whose captures have completed to be encoded.
+    /// 8. Records the uploads queued with `queue_buffer_upload` and `queue_image_upload`, up to the
+    ///    upload budget.
+    /// 9. Checks the memory usage against the watermarks added with `add_memory_watermark`.
+    /// 10. Reports the GPU assertions which failed during the completed frame.
+    ///
+    /// Command buffers and blocks requested from then on belong to the new frame.
+    ///
+    /// The returned `Frame` must be passed to `end_frame` once all of the frame's work has been
+    /// submitted.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the previous frame's `Frame` has not been ended.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn begin_frame(self: &Arc<Self>) -> Result<Frame, vk::Result> {
+        self.check_deterministic_thread("begin_frame");
+        assert!(
+            !self.frame_active.load(Ordering::Acquire),
+            "begin_frame called while a frame is active; end it with end_frame first"
+        );
+        let frame_index = (self.current_frame_index() + 1) % self.per_frame.len();
+
+        // Resources dropped since the last frame began may have been used by the frame which
+        // just ended, so they are destroyed along with its other resources.
+        self.collect_dropped_resources(&mut self.current_frame().write());
+
+        let (vbo_blocks, ibo_blocks, ubo_blocks, indirect_blocks, staging_blocks, gpu_assert_failures) = {
+            let mut frame = self.per_frame[frame_index].write();
+
+            if frame.submitted_fences > 0 {
+                let fences = &frame.fences[..frame.submitted_fences];
+                unsafe {
+                    self.device
+                        .wait_for_fences(fences, true, u64::MAX)
+                        .and_then(|_| self.device.reset_fences(fences))
+                        .inspect_err(|&e| self.check_vk_result(e))?;
+                }
+                frame.submitted_fences = 0;
+            }
+
+            self.recycle_sync_objects(&mut frame)?;
+
+            let PerFrame {
+                graphics_cmd_pools,
+                compute_cmd_pools,
+                transfer_cmd_pools,
+                secondary_cmd_pools,
+                ..
+            } = &mut *frame;
+
+            for pool in graphics_cmd_pools
+                .values_mut()
+                .chain(compute_cmd_pools.values_mut())
+                .chain(transfer_cmd_pools.values_mut())
+                .chain(secondary_cmd_pools.iter_mut().flatten())
+            {
+                unsafe { pool.reset(self)? };
+            }
+
+            #[cfg(feature = "raytracing")]
+            self.free_acceleration_structures(&mut frame);
+            self.flush_destroyed_resources(&mut frame);
+            self.destroy_dropped_resources(&mut frame);
+            self.free_sparse_pages(&mut frame);
+            self.recycle_scratch_images(&mut frame);
+            self.reset_conversion_descriptor_pools(&mut frame)?;
+            let gpu_assert_failures = self.take_gpu_assert_failures(frame_index, &mut frame);
+            self.resolve_gpu_zones(frame_index, &mut frame);
+
+            (
+                std::mem::take(&mut frame.used_vbo_blocks),
+                std::mem::take(&mut frame.used_ibo_blocks),
+                std::mem::take(&mut frame.used_ubo_blocks),
+                std::mem::take(&mut frame.used_indirect_blocks),
+                std::mem::take(&mut frame.used_staging_blocks),
+                gpu_assert_failures,
+            )
+        };
+
+        // Blocks which are being recycled must not be uploaded later on.
+        self.vbo_upload_queue.write().retain(|block| !vbo_blocks.contains(block));
+        self.ibo_upload_queue.write().retain(|block| !ibo_blocks.contains(block));
+        self.ubo_upload_queue.write().retain(|block| !ubo_blocks.contains(block));
+        self.indirect_upload_queue.write().retain(|block| !indirect_blocks.contains(block));
+
+        {
+            let mut blocks = self.buffer_blocks_mut();
+            self.release_blocks(&mut blocks.vbo_pool, vbo_blocks);
+            self.release_blocks(&mut blocks.ibo_pool, ibo_blocks);
+            self.release_blocks(&mut blocks.ubo_pool, ubo_blocks);
+            self.release_blocks(&mut blocks.indirect_pool, indirect_blocks);
+            self.release_blocks(&mut blocks.staging_pool, staging_blocks);
+
+            let kinds = [
+                PoolKind::Vertex,
+                PoolKind::Index,
+                PoolKind::Uniform,
+                PoolKind::Indirect,
+                PoolKind::Staging,
+            ];
+            for &kind in &kinds {
+                let pool = blocks.pool_mut(kind);
+                pool.advance_frame();
+                if let Some(keep_frames) = self.block_trim_frames {
+                    pool.trim(keep_frames);
+                }
+            }
+        }
+
+        self.current_frame_index.store(frame_index, Ordering::Release);
+        self.latch_render_scale();
+        self.latch_cache_stats();
+        self.reset_gpu_timestamps()?;
+        self.poll_screenshots();
+        self.record_queued_uploads()?;
+
+        self.check_memory_watermarks();
+        self.report_gpu_assert_failures(gpu_assert_failures);
+
+        self.frame_active.store(true, Ordering::Release);
+        Ok(Frame {
+            device_id: self.id,
+            index: frame_index,
+        })
+    }
+
+    /// End the current frame.
+    ///
+    /// All work for the frame must have been submitted or enqueued with `Device::submit` before
+    /// calling this. The pending batches of each queue are flushed, along with a fence which the
+    /// next `begin_frame` for this frame slot waits on before reusing the frame's resources.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn end_frame(&self, frame: Frame) -> Result<(), vk::Result> {
+        self.check_frame(&frame);
+        self.frame_active.store(false, Ordering::Release);
+
+        // Flushing a queue with no pending batches still submits a fence, which is signaled once
+        // all previously submitted work on the queue has completed.
+        self.flush_all_queues()
+    }
+
+    /// Free the resources which were destroyed during a frame which has completed.
+    fn flush_destroyed_resources(&self, frame: &mut PerFrame) {
+        frame.orphaned_buffers.clear();
+
+        if frame.destroyed_buffers.is_empty()
+            && frame.destroyed_buffer_views.is_empty()
+            && frame.destroyed_images.is_empty()
+            && frame.destroyed_image_views.is_empty()
+        {
+            return;
+        }
+
+        let mut resources = self.resources_mut();
+
+        // Views are freed before the resources they view.
+        for buffer_view in frame.destroyed_buffer_views.drain(..) {
+            resources.buffer_views.remove(buffer_view.idx);
+        }
+        for image_view in frame.destroyed_image_views.drain(..) {
+            for framebuffer in self.render_passes.write().evict_view(image_view) {
+                unsafe { self.device.destroy_framebuffer(framebuffer, None) };
+            }
+            if let Some(view) = resources.image_views.remove(image_view.idx) {
+                unsafe { view.destroy(&self.device) };
+            }
+        }
+        for buffer in frame.destroyed_buffers.drain(..) {
+            resources.buffers.remove(buffer.idx);
+        }
+        for image in frame.destroyed_images.drain(..) {
+            resources.images.remove(image.idx);
+        }
+    }
+
+    /// Return the blocks used by a finished frame to `pool`.
+    fn release_blocks(&self, pool: &mut BufferBlockPool, blocks: Vec<BufferBlockHandle>) {
+        for block in blocks {
+            if let Err(e) = pool.release_block(block) {
+                self.invariant_failed(None, format!("failed to release frame BufferBlock: {}", e));
+            }
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//! Shaders report failed assertions by writing a nonzero code into the slot of the buffer bound
+//! at the set given to `DeviceBuilder::gpu_asserts`, which could be declared in GLSL as:
+//!
+//! ```glsl
+//! layout(set = 3, binding = 0) buffer HotAssert {
+//!     uint code;
+//!     uint data[3];
+//! } hot_assert;
+//!
+//! #define HOT_ASSERT(condition, assert_code, a, b, c) \
+//!     if (!(condition) && atomicCompSwap(hot_assert.code, 0u, assert_code) == 0u) { \
+//!         hot_assert.data[0] = a; hot_assert.data[1] = b; hot_assert.data[2] = c; \
+//!     }
+//! ```
+//!
+//! Each draw or dispatch gets its own slot with `CommandBuffer::bind_gpu_asserts`, so failures
+//! are reported with the label it was given.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::fmt;
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use crate::*;
+
+/// The number of slots of each frame's assertion buffer. The last one is shared by every draw
+/// past the limit.
+const SLOTS_PER_FRAME: usize = 4096;
+
+/// The number of `u32`s written by shaders in each slot: the code and three words of data.
+const SLOT_WORDS: usize = 4;
+
+/// A failed assertion of a shader, reported after the frame it was recorded in completes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GpuAssertFailure {
+    /// The label given to `CommandBuffer::bind_gpu_asserts` for the failing draw.
+    pub label: String,
+    /// The nonzero code written by the shader.
+    pub code: u32,
+    /// The data written along with the code.
+    pub data: [u32; 3],
+}
+
+impl fmt::Display for GpuAssertFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] gpu assertion {} failed with {:?}", self.label, self.code, self.data)
+    }
+}
+
+/// A function failed GPU assertions are passed to, installed with
+/// `Device::set_gpu_assert_handler`.
+pub type GpuAssertHandler = Arc<dyn Fn(&GpuAssertFailure) + Send + Sync>;
+
+/// The mapped assertion buffer of a frame slot and the descriptor set pointing to it.
+struct GpuAssertFrame {
+    mapped: *mut u8,
+    descriptor_set: vk::DescriptorSet,
+}
+
+/// The reserved descriptor set used for GPU assertions and its buffers, which live as long as
+/// the Device.
+pub(crate) struct GpuAsserts {
+    pub(crate) set: u32,
+    pub(crate) set_layout: vk::DescriptorSetLayout,
+    pub(crate) binding: vk::DescriptorSetLayoutBinding,
+    slot_size: usize,
+    frames: Vec<GpuAssertFrame>,
+}
+
+// The mapped pointers point into memory owned by the allocations, which live as long as the
+// Device, and are only written through while the frame they belong to is locked.
+unsafe impl Send for GpuAsserts {}
+unsafe impl Sync for GpuAsserts {}
+
+impl GpuAsserts {
+    /// Create the set layout, and a buffer and descriptor set for each of `frames_in_flight`.
+    pub(crate) fn new(
+        device: &ash::Device,
+        allocator: &dyn MemoryAllocator,
+        properties: &vk::PhysicalDeviceProperties,
+        frames_in_flight: usize,
+        set: u32,
+    ) -> Result<Self, DeviceCreationError> {
+        let slot_size = properties
+            .limits
+            .min_storage_buffer_offset_alignment
+            .max((SLOT_WORDS * 4) as vk::DeviceSize) as usize;
+
+        let binding = vk::DescriptorSetLayoutBinding::builder()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::ALL)
+            .build();
+        let layout_info =
+            vk::DescriptorSetLayoutCreateInfo::builder().bindings(std::slice::from_ref(&binding));
+        let set_layout = unsafe { device.create_descriptor_set_layout(&layout_info, None)? };
+
+        let pool_size = vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER_DYNAMIC,
+            descriptor_count: frames_in_flight as u32,
+        };
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(frames_in_flight as u32)
+            .pool_sizes(std::slice::from_ref(&pool_size));
+        let pool = unsafe { device.create_descriptor_pool(&pool_info, None)? };
+        let layouts = vec![set_layout; frames_in_flight];
+        let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let descriptor_sets = unsafe { device.allocate_descriptor_sets(&allocate_info)? };
+
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size((slot_size * SLOTS_PER_FRAME) as vk::DeviceSize)
+            .usage(vk::BufferUsageFlags::STORAGE_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        // Coherent memory lets failures be read and slots be cleared without flushing.
+        let alloc_info = AllocationDesc {
+            mapped: true,
+            required_flags: vk::MemoryPropertyFlags::HOST_VISIBLE
+                | vk::MemoryPropertyFlags::HOST_COHERENT,
+            ..Default::default()
+        };
+
+        let mut frames = Vec::with_capacity(frames_in_flight);
+        for descriptor_set in descriptor_sets {
+            let (buffer, allocation) = allocator.create_buffer(&buffer_info, &alloc_info)?;
+            let mapped = allocation.mapped_data().map_or(std::ptr::null_mut(), NonNull::as_ptr);
+            unsafe { std::ptr::write_bytes(mapped, 0, slot_size * SLOTS_PER_FRAME) };
+
+            let buffer_infos = [vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: (SLOT_WORDS * 4) as vk::DeviceSize,
+            }];
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER_DYNAMIC)
+                .buffer_info(&buffer_infos);
+            unsafe { device.update_descriptor_sets(&[write.build()], &[]) };
+
+            frames.push(GpuAssertFrame {
+                mapped,
+                descriptor_set,
+            });
+        }
+
+        Ok(Self {
+            set,
+            set_layout,
+            binding,
+            slot_size,
+            frames,
+        })
+    }
+
+    /// Read the failures recorded in the slots of frame `frame_index` used by the draws labelled
+    /// `labels`, clearing the slots for reuse.
+    ///
+    /// The frame must have completed on the GPU.
+    fn take_failures(&self, frame_index: usize, labels: &[String]) -> Vec<GpuAssertFailure> {
+        let frame = &self.frames[frame_index];
+        let mut failures = Vec::new();
+        for (slot, label) in labels.iter().enumerate() {
+            let words = unsafe {
+                let slot = frame.mapped.add(slot * self.slot_size) as *mut u32;
+                std::slice::from_raw_parts_mut(slot, SLOT_WORDS)
+            };
+            if words[0] != 0 {
+                failures.push(GpuAssertFailure {
+                    label: label.clone(),
+                    code: words[0],
+                    data: [words[1], words[2], words[3]],
+                });
+                words.iter_mut().for_each(|word| *word = 0);
+            }
+        }
+        failures
+    }
+}
+
+impl Device {
+    /// Get the descriptor set reserved for GPU assertions, if they were enabled with
+    /// `DeviceBuilder::gpu_asserts`.
+    pub fn gpu_assert_set(&self) -> Option<u32> {
+        self.gpu_asserts.as_ref().map(|asserts| asserts.set)
+    }
+
+    /// Install the function failed GPU assertions are passed to, or go back to logging them at
+    /// error level through the `log` crate with `None`.
+    ///
+    /// It is called from `begin_frame` once the frame which recorded the failures completes.
+    pub fn set_gpu_assert_handler(&self, handler: Option<GpuAssertHandler>) {
+        *self.gpu_assert_handler.write() = handler;
+    }
+
+    /// Collect the failures of the completed frame `frame_index`, which is locked as `frame`.
+    pub(crate) fn take_gpu_assert_failures(
+        &self,
+        frame_index: usize,
+        frame: &mut PerFrame,
+    ) -> Vec<GpuAssertFailure> {
+        let labels = std::mem::take(&mut frame.gpu_assert_labels);
+        match self.gpu_asserts {
+            Some(ref asserts) => asserts.take_failures(frame_index, &labels),
+            None => Vec::new(),
+        }
+    }
+
+    /// Pass failed GPU assertions to the installed handler.
+    pub(crate) fn report_gpu_assert_failures(&self, failures: Vec<GpuAssertFailure>) {
+        if failures.is_empty() {
+            return;
+        }
+        // Clone the handler out so that it is free to replace itself.
+        let handler = self.gpu_assert_handler.read().clone();
+        for failure in &failures {
+            match handler {
+                Some(ref handler) => handler(failure),
+                None => log::error!("hot: {}", failure),
+            }
+        }
+    }
+}
+
+impl CommandBuffer {
+    /// Bind a fresh slot of the GPU assertion buffer at the reserved set, so that assertions
+    /// failed by the next draw or dispatch are reported with `label`. Does nothing unless
+    /// assertions were enabled with `DeviceBuilder::gpu_asserts`.
+    ///
+    /// `layout` must include the reserved set, as those made by `Device::create_shader_layout`
+    /// do, and be compatible with the pipeline bound to `bind_point` up to it.
+    pub fn bind_gpu_asserts(
+        &mut self,
+        bind_point: vk::PipelineBindPoint,
+        layout: vk::PipelineLayout,
+        label: &str,
+    ) {
+        let device = self.device().clone();
+        let asserts = match device.gpu_asserts {
+            Some(ref asserts) => asserts,
+            None => return,
+        };
+
+        let frame_index = device.current_frame_index();
+        let slot = {
+            let mut frame = device.per_frame[frame_index].write();
+            let labels = &mut frame.gpu_assert_labels;
+            if labels.len() < SLOTS_PER_FRAME {
+                labels.push(label.to_owned());
+                labels.len() - 1
+            } else {
+                labels[SLOTS_PER_FRAME - 1] = format!("a draw past the first {}", SLOTS_PER_FRAME - 1);
+                SLOTS_PER_FRAME - 1
+            }
+        };
+
+        unsafe {
+            device.device.cmd_bind_descriptor_sets(
+                self.raw(),
+                bind_point,
+                layout,
+                asserts.set,
+                &[asserts.frames[frame_index].descriptor_set],
+                &[(slot * asserts.slot_size) as u32],
+            )
+        };
+    }
+}
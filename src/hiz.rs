@@ -0,0 +1,635 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::format::format_has_depth_aspect;
+use crate::*;
+
+/// The format of the depth pyramid built by `HiZBuilder`.
+pub const HIZ_PYRAMID_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+/// The work group size of the pyramid reduction in each dimension.
+const HIZ_REDUCE_GROUP_SIZE: u32 = 8;
+
+/// The work group size of the culling pass.
+const HIZ_CULL_GROUP_SIZE: u32 = 64;
+
+/// The number of culling descriptor sets allocated from each descriptor pool.
+const HIZ_CULL_SETS_PER_POOL: u32 = 16;
+
+/// The screen space bounds of an object tested by `HiZBuilder::cull`, laid out as the culling
+/// shader expects.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct HiZCullBounds {
+    /// The minimum corner of the object's bounding rectangle, in normalized `[0, 1]` screen
+    /// coordinates.
+    pub min: [f32; 2],
+    /// The maximum corner of the object's bounding rectangle, in normalized `[0, 1]` screen
+    /// coordinates.
+    pub max: [f32; 2],
+    /// The depth of the point of the object nearest to the camera.
+    pub depth: f32,
+    padding: [f32; 3],
+}
+
+impl HiZCullBounds {
+    /// Create the bounds of an object from its bounding rectangle and nearest depth.
+    pub fn new(min: [f32; 2], max: [f32; 2], depth: f32) -> Self {
+        HiZCullBounds {
+            min,
+            max,
+            depth,
+            padding: [0.0; 3],
+        }
+    }
+}
+
+/// An error that could occur while creating or using a `HiZBuilder`.
+#[derive(Error, Debug)]
+pub enum HiZError {
+    /// The depth image does not exist.
+    #[error("the depth image does not exist")]
+    InvalidImage,
+    /// A buffer passed to `HiZBuilder::cull` does not exist.
+    #[error("the buffer does not exist")]
+    InvalidBuffer,
+    /// The depth image is not a single layer, single sample 2D image.
+    #[error("the depth image must be a single layer, single sample 2D image")]
+    UnsupportedShape,
+    /// The depth image does not have a depth aspect.
+    #[error("unsupported depth format {0:?}")]
+    UnsupportedFormat(vk::Format),
+    /// The depth image was not created with `SAMPLED` usage.
+    #[error("the depth image must have SAMPLED usage")]
+    MissingUsage,
+    /// Creating the pyramid failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// Reflecting a built-in shader failed.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Builds a hierarchical depth (HiZ) pyramid from a depth attachment, and tests object bounds
+/// against it for occlusion culling.
+///
+/// Each level of the pyramid stores the farthest depth of the 2x2 texels below it, so an object
+/// whose nearest depth is nearer than the pyramid's depth anywhere under its bounds may be
+/// visible. With `reversed_z` the nearest depth is the largest, and the pyramid stores minimums
+/// instead. Level 0 is half the size of the depth attachment, rounded up.
+///
+/// The pyramid is built with the same per-level dispatches and barriers as `MipChainPasses`,
+/// but reduces with `max` (or `min`) instead of averaging. It can be bound by other shaders
+/// through `descriptor_image_info`, with a nearest filtering sampler.
+///
+/// The views and descriptor sets are created up front for one depth image, so create a
+/// `HiZBuilder` once per depth attachment and call `build` every frame after depth is written.
+pub struct HiZBuilder {
+    device: Arc<Device>,
+    depth: ImageHandle,
+    pyramid: ImageHandle,
+    reversed_z: bool,
+    extents: Vec<(u32, u32)>,
+    reduce_shader: Option<Shader>,
+    reduce_layout: Option<ShaderLayout>,
+    reduce_pipeline: Option<ComputePipeline>,
+    cull_shader: Option<Shader>,
+    cull_layout: Option<ShaderLayout>,
+    cull_pipeline: Option<ComputePipeline>,
+    sampler: vk::Sampler,
+    depth_view: vk::ImageView,
+    /// The default view of the pyramid, which is owned by the pyramid image.
+    pyramid_view: vk::ImageView,
+    level_views: Vec<vk::ImageView>,
+    descriptor_pool: vk::DescriptorPool,
+    /// `reduce_sets[0]` reads the depth image and writes level 0, and `reduce_sets[i]` reads
+    /// level `i - 1` and writes level `i`.
+    reduce_sets: Vec<vk::DescriptorSet>,
+    cull_pools: Vec<vk::DescriptorPool>,
+    /// Culling descriptor sets, by the bounds and visibility buffers they were written with.
+    cull_sets: HashMap<(BufferHandle, BufferHandle), vk::DescriptorSet>,
+}
+
+impl HiZBuilder {
+    /// Create a builder for the pyramid of `depth`, which must be a single sampled 2D depth
+    /// image with `SAMPLED` usage.
+    ///
+    /// `reversed_z` should be set if nearer depths are larger.
+    pub fn new(
+        device: &Arc<Device>,
+        depth: ImageHandle,
+        reversed_z: bool,
+    ) -> Result<Self, HiZError> {
+        let (raw_depth, depth_info, depth_layout) = {
+            let resources = device.resources();
+            let image = resources.get_image(depth).ok_or(HiZError::InvalidImage)?;
+            (
+                image.raw(),
+                image.create_info(),
+                image.layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            )
+        };
+
+        if depth_info.image_type != vk::ImageType::TYPE_2D
+            || depth_info.layers != 1
+            || depth_info.sample_count != vk::SampleCountFlags::TYPE_1
+        {
+            return Err(HiZError::UnsupportedShape);
+        }
+        if !format_has_depth_aspect(depth_info.format) {
+            return Err(HiZError::UnsupportedFormat(depth_info.format));
+        }
+        if !depth_info.usage.contains(vk::ImageUsageFlags::SAMPLED) {
+            return Err(HiZError::MissingUsage);
+        }
+
+        let mut extents = Vec::new();
+        let (mut width, mut height) = (depth_info.width as u32, depth_info.height as u32);
+        while extents.is_empty() || width > 1 || height > 1 {
+            width = width.div_ceil(2).max(1);
+            height = height.div_ceil(2).max(1);
+            extents.push((width, height));
+        }
+        let levels = extents.len() as u32;
+
+        let pyramid_info = ImageCreateInfo {
+            width: extents[0].0 as usize,
+            height: extents[0].1 as usize,
+            depth: 1,
+            levels: levels as usize,
+            format: HIZ_PYRAMID_FORMAT,
+            image_type: vk::ImageType::TYPE_2D,
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE,
+            initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..Default::default()
+        };
+        let pyramid = device
+            .clone()
+            .create_image(pyramid_info, None, Some(Tag::Static("HiZ pyramid")))?;
+        let (raw_pyramid, pyramid_view) = {
+            let resources = device.resources();
+            let image = resources.get_image(pyramid).unwrap();
+            (image.raw(), image.view().unwrap().raw())
+        };
+
+        let mut builder = HiZBuilder {
+            device: device.clone(),
+            depth,
+            pyramid,
+            reversed_z,
+            extents,
+            reduce_shader: None,
+            reduce_layout: None,
+            reduce_pipeline: None,
+            cull_shader: None,
+            cull_layout: None,
+            cull_pipeline: None,
+            sampler: vk::Sampler::null(),
+            depth_view: vk::ImageView::null(),
+            pyramid_view,
+            level_views: Vec::with_capacity(levels as usize),
+            descriptor_pool: vk::DescriptorPool::null(),
+            reduce_sets: Vec::new(),
+            cull_pools: Vec::new(),
+            cull_sets: HashMap::new(),
+        };
+
+        // Anything created so far is cleaned up by Drop if a later step fails.
+        builder.reduce_shader = Some(device.create_shader(shaders::HIZ_REDUCE)?);
+        builder.reduce_layout =
+            Some(device.create_shader_layout(&[builder.reduce_shader.as_ref().unwrap()])?);
+        builder.reduce_pipeline = Some(device.create_compute_pipeline(
+            builder.reduce_shader.as_ref().unwrap().raw(),
+            builder.reduce_layout.as_ref().unwrap().raw(),
+        )?);
+        builder.cull_shader = Some(device.create_shader(shaders::HIZ_CULL)?);
+        builder.cull_layout =
+            Some(device.create_shader_layout(&[builder.cull_shader.as_ref().unwrap()])?);
+        builder.cull_pipeline = Some(device.create_compute_pipeline(
+            builder.cull_shader.as_ref().unwrap().raw(),
+            builder.cull_layout.as_ref().unwrap().raw(),
+        )?);
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(levels as f32);
+        builder.sampler = unsafe { device.device.create_sampler(&sampler_info, None)? };
+
+        let view_info = |image, format, aspect_mask, base_mip_level, level_count| {
+            vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level,
+                    level_count,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .build()
+        };
+        unsafe {
+            builder.depth_view = device.device.create_image_view(
+                &view_info(
+                    raw_depth,
+                    depth_info.format,
+                    vk::ImageAspectFlags::DEPTH,
+                    0,
+                    1,
+                ),
+                None,
+            )?;
+            for level in 0..levels {
+                let info = view_info(
+                    raw_pyramid,
+                    HIZ_PYRAMID_FORMAT,
+                    vk::ImageAspectFlags::COLOR,
+                    level,
+                    1,
+                );
+                builder
+                    .level_views
+                    .push(device.device.create_image_view(&info, None)?);
+            }
+        }
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: levels,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: levels,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(levels)
+            .pool_sizes(&pool_sizes);
+        builder.descriptor_pool =
+            unsafe { device.device.create_descriptor_pool(&pool_info, None)? };
+
+        let set_layouts =
+            vec![builder.reduce_layout.as_ref().unwrap().set_layouts()[0]; levels as usize];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(builder.descriptor_pool)
+            .set_layouts(&set_layouts);
+        builder.reduce_sets = unsafe { device.device.allocate_descriptor_sets(&alloc_info)? };
+
+        let image_infos = (0..levels as usize)
+            .map(|level| {
+                let (src_view, src_layout) = match level {
+                    0 => (builder.depth_view, depth_layout),
+                    _ => (
+                        builder.level_views[level - 1],
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ),
+                };
+                [
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: src_view,
+                        image_layout: src_layout,
+                    },
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: builder.level_views[level],
+                        image_layout: vk::ImageLayout::GENERAL,
+                    },
+                ]
+            })
+            .collect::<Vec<_>>();
+
+        let mut writes = Vec::with_capacity(image_infos.len() * 2);
+        for (&set, infos) in builder.reduce_sets.iter().zip(&image_infos) {
+            writes.push(
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&infos[..1])
+                    .build(),
+            );
+            writes.push(
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&infos[1..])
+                    .build(),
+            );
+        }
+        unsafe { device.device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(builder)
+    }
+
+    /// The depth image the pyramid is built from.
+    pub fn depth(&self) -> ImageHandle {
+        self.depth
+    }
+
+    /// The depth pyramid image, which has the format `HIZ_PYRAMID_FORMAT`.
+    pub fn pyramid(&self) -> ImageHandle {
+        self.pyramid
+    }
+
+    /// The size of level 0 of the pyramid.
+    pub fn pyramid_extent(&self) -> (u32, u32) {
+        self.extents[0]
+    }
+
+    /// The number of mip levels in the pyramid.
+    pub fn pyramid_levels(&self) -> u32 {
+        self.extents.len() as u32
+    }
+
+    /// A view of every mip level of the pyramid.
+    pub fn pyramid_view(&self) -> vk::ImageView {
+        self.pyramid_view
+    }
+
+    /// The nearest filtering sampler used to sample the pyramid.
+    pub fn sampler(&self) -> vk::Sampler {
+        self.sampler
+    }
+
+    /// The image info to write the pyramid into a `COMBINED_IMAGE_SAMPLER` descriptor, such as
+    /// a slot of a bindless texture array. It is valid once `build` has been recorded.
+    pub fn descriptor_image_info(&self) -> vk::DescriptorImageInfo {
+        vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: self.pyramid_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+
+    /// Build the pyramid from the current contents of the depth image.
+    ///
+    /// Afterwards the whole pyramid is ready to be sampled by compute and fragment shaders.
+    pub fn build(&self, cmd: &mut CommandBuffer) {
+        cmd.transition_image(
+            self.depth,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+
+        let reversed_z = self.reversed_z as u32;
+        for (level, &(width, height)) in self.extents.iter().enumerate() {
+            if level > 0 {
+                cmd.transition_image_subresources(
+                    self.pyramid,
+                    level_range(level - 1),
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::AccessFlags::SHADER_READ,
+                );
+            }
+            cmd.transition_image_subresources(
+                self.pyramid,
+                level_range(level),
+                vk::ImageLayout::GENERAL,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_WRITE,
+            );
+
+            cmd.dispatch_with(
+                self.reduce_pipeline.as_ref().unwrap(),
+                &[self.reduce_sets[level]],
+                &reversed_z.to_ne_bytes(),
+                &[],
+                [
+                    width.div_ceil(HIZ_REDUCE_GROUP_SIZE),
+                    height.div_ceil(HIZ_REDUCE_GROUP_SIZE),
+                    1,
+                ],
+            );
+        }
+
+        cmd.transition_image(
+            self.pyramid,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+    }
+
+    /// Record the reference culling pass, which tests the first `count` `HiZCullBounds` in
+    /// `bounds` against the pyramid and writes a `u32` to the same index of `visibility`: 1 if
+    /// the object may be visible, or 0 if it is occluded.
+    ///
+    /// Both buffers need `STORAGE_BUFFER` usage. The pyramid should have been built this frame,
+    /// from the depth of the previous frame's visible objects or this frame's occluders. A
+    /// descriptor set is created and cached for each distinct pair of buffers, so reuse the
+    /// same buffers every frame.
+    pub fn cull(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        bounds: BufferHandle,
+        visibility: BufferHandle,
+        count: u32,
+    ) -> Result<(), HiZError> {
+        let set = match self.cull_sets.get(&(bounds, visibility)) {
+            Some(&set) => set,
+            None => {
+                let set = self.create_cull_set(bounds, visibility)?;
+                self.cull_sets.insert((bounds, visibility), set);
+                set
+            }
+        };
+
+        let (width, height) = self.extents[0];
+        let mut push_constants = Vec::with_capacity(16);
+        push_constants.extend_from_slice(&(width as f32).to_ne_bytes());
+        push_constants.extend_from_slice(&(height as f32).to_ne_bytes());
+        push_constants.extend_from_slice(&count.to_ne_bytes());
+        push_constants.extend_from_slice(&(self.reversed_z as u32).to_ne_bytes());
+
+        cmd.dispatch_with(
+            self.cull_pipeline.as_ref().unwrap(),
+            &[set],
+            &push_constants,
+            &[
+                DispatchResource::SampledImage(self.pyramid),
+                DispatchResource::StorageBuffer(bounds, ShaderAccess::Read),
+                DispatchResource::StorageBuffer(visibility, ShaderAccess::Write),
+            ],
+            [count.div_ceil(HIZ_CULL_GROUP_SIZE), 1, 1],
+        );
+
+        Ok(())
+    }
+
+    fn create_cull_set(
+        &mut self,
+        bounds: BufferHandle,
+        visibility: BufferHandle,
+    ) -> Result<vk::DescriptorSet, HiZError> {
+        let (bounds, visibility) = {
+            let resources = self.device.resources();
+            let raw = |buffer| {
+                resources
+                    .get_buffer(buffer)
+                    .map(Buffer::raw)
+                    .ok_or(HiZError::InvalidBuffer)
+            };
+            (raw(bounds)?, raw(visibility)?)
+        };
+
+        let set_layouts = [self.cull_layout.as_ref().unwrap().set_layouts()[0]];
+        let set = match self.cull_pools.last() {
+            Some(&pool) => {
+                let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(&set_layouts);
+                unsafe { self.device.device.allocate_descriptor_sets(&alloc_info) }.ok()
+            }
+            None => None,
+        };
+        let set = match set {
+            Some(sets) => sets[0],
+            None => {
+                let pool_sizes = [
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::SAMPLED_IMAGE,
+                        descriptor_count: HIZ_CULL_SETS_PER_POOL,
+                    },
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::SAMPLER,
+                        descriptor_count: HIZ_CULL_SETS_PER_POOL,
+                    },
+                    vk::DescriptorPoolSize {
+                        ty: vk::DescriptorType::STORAGE_BUFFER,
+                        descriptor_count: 2 * HIZ_CULL_SETS_PER_POOL,
+                    },
+                ];
+                let pool_info = vk::DescriptorPoolCreateInfo::builder()
+                    .max_sets(HIZ_CULL_SETS_PER_POOL)
+                    .pool_sizes(&pool_sizes);
+                let pool = unsafe {
+                    self.device
+                        .device
+                        .create_descriptor_pool(&pool_info, None)?
+                };
+                self.cull_pools.push(pool);
+
+                let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(&set_layouts);
+                let sets = unsafe { self.device.device.allocate_descriptor_sets(&alloc_info)? };
+                sets[0]
+            }
+        };
+
+        let image_info = [self.descriptor_image_info()];
+        let buffer_infos = [bounds, visibility]
+            .iter()
+            .map(|&buffer| vk::DescriptorBufferInfo {
+                buffer,
+                offset: 0,
+                range: vk::WHOLE_SIZE,
+            })
+            .collect::<Vec<_>>();
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&buffer_infos[..1])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(2)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&buffer_infos[1..])
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&image_info)
+                .build(),
+        ];
+        unsafe { self.device.device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(set)
+    }
+}
+
+/// The subresource range of a single level of the pyramid.
+fn level_range(level: usize) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: level as u32,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+impl Drop for HiZBuilder {
+    fn drop(&mut self) {
+        unsafe {
+            // The passes may still be in use by frames in flight.
+            let _ = self.device.device_wait_idle();
+
+            for pool in self.cull_pools.drain(..) {
+                self.device.device.destroy_descriptor_pool(pool, None);
+            }
+            if self.descriptor_pool != vk::DescriptorPool::null() {
+                self.device
+                    .device
+                    .destroy_descriptor_pool(self.descriptor_pool, None);
+            }
+            for view in self.level_views.drain(..) {
+                self.device.device.destroy_image_view(view, None);
+            }
+            if self.depth_view != vk::ImageView::null() {
+                self.device.device.destroy_image_view(self.depth_view, None);
+            }
+            if self.sampler != vk::Sampler::null() {
+                self.device.device.destroy_sampler(self.sampler, None);
+            }
+            for pipeline in [self.reduce_pipeline.take(), self.cull_pipeline.take()]
+                .iter()
+                .flatten()
+            {
+                self.device.destroy_compute_pipeline(*pipeline);
+            }
+            for layout in vec![self.reduce_layout.take(), self.cull_layout.take()]
+                .into_iter()
+                .flatten()
+            {
+                self.device.destroy_shader_layout(layout);
+            }
+            for shader in vec![self.reduce_shader.take(), self.cull_shader.take()]
+                .into_iter()
+                .flatten()
+            {
+                self.device.destroy_shader(shader);
+            }
+        }
+        self.device.destroy_image(self.pyramid);
+    }
+}
@@ -0,0 +1,149 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::ptr::NonNull;
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while importing host memory into a `Buffer`.
+#[derive(Error, Debug)]
+pub enum HostImportError {
+    /// The device does not support `VK_EXT_external_memory_host`.
+    #[error("importing host memory is not supported by this device")]
+    Unsupported,
+    /// The pointer or size is not a multiple of `Device::host_import_alignment`.
+    #[error("host pointer and size must be aligned to {alignment} bytes")]
+    Misaligned {
+        /// The required alignment in bytes.
+        alignment: vk::DeviceSize,
+    },
+    /// None of the memory types the host memory may be imported as can back a buffer with
+    /// the requested usage, or the buffer needs more memory than was provided.
+    #[error("the host memory cannot back a buffer with this usage")]
+    IncompatibleMemory,
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+impl Device {
+    /// Get the alignment which pointers and sizes passed to `import_host_buffer` must have
+    /// (`minImportedHostPointerAlignment`), or `None` if importing host memory is not supported.
+    pub fn host_import_alignment(&self) -> Option<vk::DeviceSize> {
+        self.external_memory_host.as_ref().map(|&(_, alignment)| alignment)
+    }
+
+    /// Create a `Host` domain Buffer backed directly by `size` bytes of existing host memory
+    /// at `ptr`, rather than by a new allocation, so that data produced by a decoder or read
+    /// from a memory-mapped file can be copied to the GPU without an intermediate copy.
+    ///
+    /// `ptr` and `size` must both be multiples of `host_import_alignment`; a page-aligned
+    /// allocation generally is. The buffer's `mapped_data` is `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// The `size` bytes at `ptr` must stay valid, and must not be unmapped or freed, until the
+    /// buffer has been destroyed and the GPU has finished using it. Memory-mapped files must be
+    /// mapped writable, since some implementations cannot import read-only mappings.
+    pub unsafe fn import_host_buffer(
+        self: Arc<Self>,
+        ptr: NonNull<u8>,
+        size: vk::DeviceSize,
+        usage: vk::BufferUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<BufferHandle, HostImportError> {
+        let (external_memory_host, alignment) = match self.external_memory_host {
+            Some((ref fns, alignment)) => (fns, alignment),
+            None => return Err(HostImportError::Unsupported),
+        };
+        if (ptr.as_ptr() as vk::DeviceSize) % alignment != 0 || size % alignment != 0 {
+            return Err(HostImportError::Misaligned { alignment });
+        }
+
+        let handle_type = vk::ExternalMemoryHandleTypeFlags::EXTERNAL_MEMORY_HANDLE_TYPE_HOST_ALLOCATION;
+
+        let mut host_properties = vk::MemoryHostPointerPropertiesEXT::default();
+        let result = external_memory_host.get_memory_host_pointer_properties_ext(
+            self.device.handle(),
+            handle_type,
+            ptr.as_ptr() as *const _,
+            &mut host_properties,
+        );
+        if result != vk::Result::SUCCESS {
+            return Err(result.into());
+        }
+
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Host,
+            size,
+            usage,
+            wants_device_address: false,
+        };
+        let mut queue_family_indices = [0u32; 3];
+        let mut external_info = vk::ExternalMemoryBufferCreateInfo::builder().handle_types(handle_type);
+        let buffer_info = self
+            .raw_buffer_create_info(create_info, &mut queue_family_indices)
+            .push_next(&mut external_info);
+        let buffer = self.device.create_buffer(&buffer_info, None)?;
+
+        let requirements = self.device.get_buffer_memory_requirements(buffer);
+        let memory_type_bits = requirements.memory_type_bits & host_properties.memory_type_bits;
+        let memory_type = self.host_import_memory_type(memory_type_bits);
+        let memory_type = match memory_type {
+            Some(memory_type) if requirements.size <= size => memory_type,
+            _ => {
+                self.device.destroy_buffer(buffer, None);
+                return Err(HostImportError::IncompatibleMemory);
+            }
+        };
+
+        let mut import_info = vk::ImportMemoryHostPointerInfoEXT::builder()
+            .handle_type(handle_type)
+            .host_pointer(ptr.as_ptr() as *mut _);
+        let alloc_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type)
+            .push_next(&mut import_info);
+        let memory = match self.device.allocate_memory(&alloc_info, None) {
+            Ok(memory) => memory,
+            Err(e) => {
+                self.device.destroy_buffer(buffer, None);
+                return Err(e.into());
+            }
+        };
+        if let Err(e) = self.device.bind_buffer_memory(buffer, memory, 0) {
+            self.device.destroy_buffer(buffer, None);
+            self.device.free_memory(memory, None);
+            return Err(e.into());
+        }
+
+        Ok(BufferHandle {
+            idx: self.resources.write().buffers.insert(Buffer::new_imported(
+                self.clone(),
+                buffer,
+                memory,
+                create_info,
+                Some(ptr),
+                tag,
+            )),
+        })
+    }
+
+    /// Pick the memory type to import host memory as out of `memory_type_bits`, preferring
+    /// coherent memory so that host writes need not be flushed.
+    fn host_import_memory_type(&self, memory_type_bits: u32) -> Option<u32> {
+        let types = &self.memory_properties.memory_types[..self.memory_properties.memory_type_count as usize];
+        let allowed = |&(index, _): &(usize, &vk::MemoryType)| memory_type_bits & (1 << index) != 0;
+
+        types
+            .iter()
+            .enumerate()
+            .filter(allowed)
+            .find(|(_, ty)| ty.property_flags.contains(vk::MemoryPropertyFlags::HOST_COHERENT))
+            .or_else(|| types.iter().enumerate().find(allowed))
+            .map(|(index, _)| index as u32)
+    }
+}
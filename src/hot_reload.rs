@@ -0,0 +1,143 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+/// Tracks which shader source files include which other files, so that when any file changes
+/// every root shader which (transitively) depends on it can be rebuilt, not just the file itself.
+///
+/// Dependencies can either be discovered by parsing `#include` directives with
+/// `ShaderDependencies::scan`, or provided directly (for example from a compiler's depfile
+/// output) with `ShaderDependencies::set_dependencies`.
+#[derive(Debug, Default, Clone)]
+pub struct ShaderDependencies {
+    include_dirs: Vec<PathBuf>,
+    roots: HashSet<PathBuf>,
+    /// Map from a file to the files it directly includes.
+    includes: HashMap<PathBuf, Vec<PathBuf>>,
+}
+
+impl ShaderDependencies {
+    /// Create a new, empty set of shader dependencies.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a directory which is searched for `#include <...>` directives and for quoted
+    /// includes which are not found relative to the including file.
+    pub fn add_include_dir<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.include_dirs.push(dir.into());
+    }
+
+    /// Parse the `#include` directives of the root shader at `path` and, recursively, of all the
+    /// files it includes, replacing any dependencies previously recorded for those files.
+    ///
+    /// Includes which cannot be found are ignored, since the shader compiler will report them.
+    pub fn scan<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()> {
+        let path = path.as_ref().to_path_buf();
+        self.roots.insert(path.clone());
+
+        let mut to_scan = vec![path];
+        let mut scanned = HashSet::new();
+
+        while let Some(file) = to_scan.pop() {
+            if !scanned.insert(file.clone()) {
+                continue;
+            }
+
+            let source = std::fs::read_to_string(&file)?;
+            let includes = parse_includes(&source)
+                .into_iter()
+                .filter_map(|(include, quoted)| self.resolve_include(&file, &include, quoted))
+                .collect::<Vec<_>>();
+
+            to_scan.extend(includes.iter().cloned());
+            self.includes.insert(file, includes);
+        }
+
+        Ok(())
+    }
+
+    /// Set the dependencies of the root shader at `path` directly, for example from the list
+    /// reported by the shader compiler. `dependencies` should contain every file the shader
+    /// transitively includes.
+    pub fn set_dependencies<P, I>(&mut self, path: P, dependencies: I)
+    where
+        P: Into<PathBuf>,
+        I: IntoIterator<Item = PathBuf>,
+    {
+        let path = path.into();
+        self.roots.insert(path.clone());
+        self.includes.insert(path, dependencies.into_iter().collect());
+    }
+
+    /// Stop tracking the root shader at `path`.
+    pub fn remove(&mut self, path: &Path) {
+        self.roots.remove(path);
+        self.includes.remove(path);
+    }
+
+    /// Get every tracked root shader which needs to be rebuilt because `changed` was modified,
+    /// including `changed` itself if it is a root.
+    pub fn dependents_of(&self, changed: &Path) -> Vec<PathBuf> {
+        self.roots
+            .iter()
+            .filter(|root| self.depends_on(root, changed))
+            .cloned()
+            .collect()
+    }
+
+    /// Get whether `file` is `dependency` or transitively includes it.
+    fn depends_on(&self, file: &Path, dependency: &Path) -> bool {
+        let mut to_visit = vec![file];
+        let mut visited = HashSet::new();
+
+        while let Some(file) = to_visit.pop() {
+            if file == dependency {
+                return true;
+            }
+            if !visited.insert(file) {
+                continue;
+            }
+            if let Some(includes) = self.includes.get(file) {
+                to_visit.extend(includes.iter().map(PathBuf::as_path));
+            }
+        }
+
+        false
+    }
+
+    /// Find the file referred to by an include directive in `including_file`.
+    fn resolve_include(&self, including_file: &Path, include: &str, quoted: bool) -> Option<PathBuf> {
+        let relative = if quoted {
+            including_file.parent().map(|dir| dir.join(include))
+        } else {
+            None
+        };
+
+        relative
+            .into_iter()
+            .chain(self.include_dirs.iter().map(|dir| dir.join(include)))
+            .find(|candidate| candidate.is_file())
+    }
+}
+
+/// Parse the `#include "file"` and `#include <file>` directives in a shader source, returning
+/// the included paths along with whether they were quoted.
+fn parse_includes(source: &str) -> Vec<(String, bool)> {
+    source
+        .lines()
+        .filter_map(|line| {
+            let line = line.trim_start();
+            let rest = line.strip_prefix('#')?.trim_start().strip_prefix("include")?.trim();
+
+            let (close, quoted) = match rest.chars().next()? {
+                '"' => ('"', true),
+                '<' => ('>', false),
+                _ => return None,
+            };
+
+            let rest = &rest[1..];
+            let end = rest.find(close)?;
+            Some((rest[..end].to_string(), quoted))
+        })
+        .collect()
+}
@@ -1,22 +1,55 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
 use ash::vk;
 use bitflags::bitflags;
 use derivative::Derivative;
+use parking_lot::Mutex;
+use thiserror::Error;
 
 use crate::*;
-use crate::format::format_has_depth_or_stencil_aspect;
+use crate::destruction::DestructionSender;
+use crate::format::{format_has_depth_or_stencil_aspect, format_to_aspect_mask};
 
+use std::collections::HashMap;
+use std::ops::Range;
 use std::sync::Arc;
 
 /// Initial data for an Image.
 pub struct InitialImageData<'a> {
-    /// The raw data.
+    /// The raw data, with each layer following the last. Ignored if `layers` isn't empty.
     pub data: &'a [u8],
-    /// Length of a row in pixels.
+    /// The raw data of each layer separately, e.g. the faces of a cubemap in the order +X, -X,
+    /// +Y, -Y, +Z, -Z, or empty to take every layer from `data`.
+    pub layers: &'a [&'a [u8]],
+    /// Length of a row in pixels, or 0 if rows are tightly packed.
     pub row_length: usize,
-    /// Height of the image in pixels.
+    /// Height of the image in pixels, i.e. the number of rows per layer or depth slice, or 0 if
+    /// they are tightly packed.
     pub image_height: usize,
 }
 
+impl<'a> InitialImageData<'a> {
+    /// Tightly packed data of every layer, with each layer following the last.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            layers: &[],
+            row_length: 0,
+            image_height: 0,
+        }
+    }
+
+    /// Tightly packed data of each layer separately.
+    pub fn layers(layers: &'a [&'a [u8]]) -> Self {
+        Self {
+            data: &[],
+            layers,
+            row_length: 0,
+            image_height: 0,
+        }
+    }
+}
+
 /// The general memory 'domain' an image should be placed in.
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub enum ImageUsageDomain {
@@ -62,7 +95,22 @@ pub struct ImageViewCreateInfo {
     pub swizzle: vk::ComponentMapping,
 }
 
-/// An owned ImageView and associated data. Must be manually destroyed and not be dropped.
+/// An error that could occur while creating an ImageView with `Device::create_image_view`.
+#[derive(Error, Debug)]
+pub enum ImageViewCreationError {
+    /// The image to be viewed has been destroyed.
+    #[error("the image to be viewed has been destroyed")]
+    InvalidImage,
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// An owned ImageView and associated data.
+///
+/// ImageViews are destroyed through `Device::destroy_image_view`, or along with the image they
+/// were made for. One which is dropped instead, for example while unwinding, queues its raw views
+/// to be destroyed by the Device once the current frame has completed.
 #[derive(Debug)]
 pub struct ImageView {
     view: vk::ImageView,
@@ -72,11 +120,204 @@ pub struct ImageView {
     unorm_view: vk::ImageView,
     srgb_view: vk::ImageView,
     create_info: ImageViewCreateInfo,
+    destruction: DestructionSender,
 }
 
 impl Drop for ImageView {
     fn drop(&mut self) {
-        panic!("OwnedImage dropped: {:?}", self);
+        let views = self.raw_views().collect::<Vec<_>>();
+        if !self.destruction.destroy_image_views(views) {
+            // The Device is gone, so the views can no longer be destroyed. Panicking while
+            // unwinding would abort, so only report the leak then.
+            if std::thread::panicking() || !cfg!(debug_assertions) {
+                log::warn!("hot: leaked an ImageView which outlived its Device: {:?}", self.create_info);
+            } else {
+                panic!("ImageView outlived its Device: {:?}", self.create_info);
+            }
+        }
+    }
+}
+
+impl ImageView {
+    /// Create the views of an image described by `create_info`.
+    ///
+    /// Besides the main view, which covers every aspect, a depth stencil image gets a view of
+    /// each aspect so they can be sampled separately, a layered attachment gets a view of each
+    /// layer to render to, and a `MUTABLE_FORMAT` 8-bit RGBA image gets UNORM and SRGB views.
+    ///
+    /// # Safety
+    ///
+    /// `image` must be the raw image created from `image_info` on `device`.
+    pub(crate) unsafe fn new(
+        device: &ash::Device,
+        destruction: DestructionSender,
+        image: vk::Image,
+        image_info: &ImageCreateInfo,
+        create_info: ImageViewCreateInfo,
+    ) -> VkResult<Self> {
+        let mut view = ImageView {
+            view: vk::ImageView::null(),
+            render_target_views: Vec::new(),
+            depth_view: vk::ImageView::null(),
+            stencil_view: vk::ImageView::null(),
+            unorm_view: vk::ImageView::null(),
+            srgb_view: vk::ImageView::null(),
+            create_info,
+            destruction,
+        };
+
+        // Views created so far are destroyed if a later one fails.
+        match view.create_views(device, image, image_info) {
+            Ok(()) => Ok(view),
+            Err(e) => {
+                view.destroy(device);
+                Err(e)
+            }
+        }
+    }
+
+    unsafe fn create_views(
+        &mut self,
+        device: &ash::Device,
+        image: vk::Image,
+        image_info: &ImageCreateInfo,
+    ) -> VkResult<()> {
+        let info = self.create_info;
+        let range = vk::ImageSubresourceRange {
+            aspect_mask: format_to_aspect_mask(info.format),
+            base_mip_level: info.base_mip_level as u32,
+            level_count: info.mip_levels as u32,
+            base_array_layer: info.base_array_layer as u32,
+            layer_count: info.array_layers as u32,
+        };
+        let create = |format, view_type, range| {
+            let create_info = vk::ImageViewCreateInfo::builder()
+                .image(image)
+                .view_type(view_type)
+                .format(format)
+                .components(info.swizzle)
+                .subresource_range(range);
+            device.create_image_view(&create_info, None)
+        };
+
+        self.view = create(info.format, info.view_type, range)?;
+
+        if range.aspect_mask == vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL {
+            let depth_range = vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::DEPTH,
+                ..range
+            };
+            self.depth_view = create(info.format, info.view_type, depth_range)?;
+            let stencil_range = vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::STENCIL,
+                ..range
+            };
+            self.stencil_view = create(info.format, info.view_type, stencil_range)?;
+        }
+
+        let attachment_usage =
+            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT;
+        if image_info.usage.intersects(attachment_usage) && range.layer_count > 1 {
+            for layer in 0..range.layer_count {
+                let layer_range = vk::ImageSubresourceRange {
+                    level_count: 1,
+                    base_array_layer: range.base_array_layer + layer,
+                    layer_count: 1,
+                    ..range
+                };
+                let view = create(info.format, vk::ImageViewType::TYPE_2D, layer_range)?;
+                self.render_target_views.push(view);
+            }
+        }
+
+        if image_info.create_flags.contains(vk::ImageCreateFlags::MUTABLE_FORMAT) {
+            if let Some((unorm, srgb)) = unorm_and_srgb_formats(info.format) {
+                self.unorm_view = create(unorm, info.view_type, range)?;
+                self.srgb_view = create(srgb, info.view_type, range)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Destroy every raw view.
+    ///
+    /// # Safety
+    ///
+    /// `device` must be the device the views were created on, and they must not be in use by the GPU.
+    pub(crate) unsafe fn destroy(mut self, device: &ash::Device) {
+        for view in self.raw_views() {
+            device.destroy_image_view(view, None);
+        }
+        // Free the Vec and sender, since forgetting self would leak them.
+        std::mem::take(&mut self.render_target_views);
+        std::mem::take(&mut self.destruction);
+        std::mem::forget(self);
+    }
+
+    /// Every raw view this ImageView owns.
+    pub(crate) fn raw_views(&self) -> impl Iterator<Item = vk::ImageView> + '_ {
+        let others = [self.view, self.depth_view, self.stencil_view, self.unorm_view, self.srgb_view];
+        self.render_target_views
+            .iter()
+            .copied()
+            .chain(others)
+            .filter(|&view| view != vk::ImageView::null())
+    }
+
+    /// The raw `vk::ImageView` of every aspect of the viewed subresources.
+    pub fn raw(&self) -> vk::ImageView {
+        self.view
+    }
+
+    /// The view of only the depth aspect, if the image has both depth and stencil aspects.
+    pub fn depth_view(&self) -> Option<vk::ImageView> {
+        non_null(self.depth_view)
+    }
+
+    /// The view of only the stencil aspect, if the image has both depth and stencil aspects.
+    pub fn stencil_view(&self) -> Option<vk::ImageView> {
+        non_null(self.stencil_view)
+    }
+
+    /// The view of a single layer to render to, if the image is a layered attachment.
+    pub fn render_target_view(&self, layer: usize) -> Option<vk::ImageView> {
+        self.render_target_views.get(layer).copied()
+    }
+
+    /// The view with the UNORM variant of the format, if the image has `MUTABLE_FORMAT`.
+    pub fn unorm_view(&self) -> Option<vk::ImageView> {
+        non_null(self.unorm_view)
+    }
+
+    /// The view with the SRGB variant of the format, if the image has `MUTABLE_FORMAT`.
+    pub fn srgb_view(&self) -> Option<vk::ImageView> {
+        non_null(self.srgb_view)
+    }
+
+    /// Get the ImageViewCreateInfo used to create this view.
+    pub fn create_info(&self) -> ImageViewCreateInfo {
+        self.create_info
+    }
+}
+
+fn non_null(view: vk::ImageView) -> Option<vk::ImageView> {
+    Some(view).filter(|&view| view != vk::ImageView::null())
+}
+
+/// Get the UNORM and SRGB variants of an 8-bit RGBA format.
+pub(crate) fn unorm_and_srgb_formats(format: vk::Format) -> Option<(vk::Format, vk::Format)> {
+    match format {
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R8G8B8A8_SRGB => {
+            Some((vk::Format::R8G8B8A8_UNORM, vk::Format::R8G8B8A8_SRGB))
+        }
+        vk::Format::B8G8R8A8_UNORM | vk::Format::B8G8R8A8_SRGB => {
+            Some((vk::Format::B8G8R8A8_UNORM, vk::Format::B8G8R8A8_SRGB))
+        }
+        vk::Format::A8B8G8R8_UNORM_PACK32 | vk::Format::A8B8G8R8_SRGB_PACK32 => {
+            Some((vk::Format::A8B8G8R8_UNORM_PACK32, vk::Format::A8B8G8R8_SRGB_PACK32))
+        }
+        _ => None,
     }
 }
 
@@ -107,7 +348,9 @@ pub struct ImageCreateInfo {
     pub create_flags: vk::ImageCreateFlags,
     /// Miscelaneous options for the image.
     pub misc_flags: MiscImageFlags,
-    /// The initial layout that the image should be created with.
+    /// The layout the image is in once created. Images are always created in `UNDEFINED`, and
+    /// `Device::create_image` records a transition into this layout before the image's first use.
+    /// `UNDEFINED` skips the transition, leaving the image to be transitioned by its first use.
     pub initial_layout: vk::ImageLayout,
     /// The component swizzle.
     pub swizzle: vk::ComponentMapping,
@@ -179,6 +422,27 @@ impl ImageCreateInfo {
         info
     }
 
+    /// Make an ImageCreateInfo suitable for an immutable cubemap with faces of `size` by `size`
+    /// pixels, whose default view is a `CUBE` view. Its six faces are its layers, in the order
+    /// +X, -X, +Y, -Y, +Z, -Z, and may be uploaded with `InitialImageData::layers`.
+    pub fn cubemap(size: usize, format: vk::Format) -> Self {
+        Self {
+            layers: 6,
+            create_flags: vk::ImageCreateFlags::CUBE_COMPATIBLE,
+            ..Self::immutable_2d_image(size, size, format, false)
+        }
+    }
+
+    /// Make an ImageCreateInfo suitable for an immutable 2d texture array of `layers` layers,
+    /// whose default view is a `TYPE_2D_ARRAY` view if it has more than one layer. Each layer
+    /// may be uploaded separately with `InitialImageData::layers`.
+    pub fn texture_array(width: usize, height: usize, layers: usize, format: vk::Format) -> Self {
+        Self {
+            layers,
+            ..Self::immutable_2d_image(width, height, format, false)
+        }
+    }
+
     /// Make an ImageCreateInfo suitable for a render target using sensible defaults.
     pub fn render_target(width: usize, height: usize, format: vk::Format, transient: bool) -> Self {
         let mut usage = vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST;
@@ -206,6 +470,33 @@ impl ImageCreateInfo {
             ..Default::default()
         }
     }
+
+    /// Make an ImageCreateInfo suitable for a multisampled render target with `samples`
+    /// samples, which is rendered to and then resolved, so its contents don't need to outlive
+    /// the render pass. The paired single sampled image is made by
+    /// `Device::create_multisampled_render_target`, or resolved to with
+    /// `CommandBuffer::resolve_image`.
+    pub fn multisampled_render_target(
+        width: usize,
+        height: usize,
+        format: vk::Format,
+        samples: vk::SampleCountFlags,
+    ) -> Self {
+        Self {
+            sample_count: samples,
+            ..Self::render_target(width, height, format, true)
+        }
+    }
+}
+
+/// A multisampled render target paired with the single sampled image it is resolved to, made by
+/// `Device::create_multisampled_render_target`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MultisampledRenderTarget {
+    /// The multisampled image which is rendered to.
+    pub image: ImageHandle,
+    /// The single sampled image `image` is resolved to, which may be sampled.
+    pub resolve: ImageHandle,
 }
 
 /// The type of layout that this image is in. Can either be the optimal
@@ -231,6 +522,22 @@ impl ImageLayoutType {
     }
 }
 
+/// The subresources, format and type of a view requested with `Image::get_view`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ViewKey {
+    /// The mip levels the view covers.
+    pub mips: Range<u32>,
+    /// The array layers the view covers.
+    pub layers: Range<u32>,
+    /// The format to view the image as, or `vk::Format::UNDEFINED` for the image's own format.
+    /// A different format requires the image to have been created with `MUTABLE_FORMAT`.
+    pub format: vk::Format,
+    /// The type of the view.
+    pub view_type: vk::ImageViewType,
+    /// The aspects the view covers, or empty for every aspect of the format.
+    pub aspect_mask: vk::ImageAspectFlags,
+}
+
 /// An owned Image and associated data.
 ///
 /// Will be automatically destroyed on Drop. Will also destroy associated ImageView(s) that were
@@ -239,29 +546,67 @@ impl ImageLayoutType {
 #[derivative(Debug)]
 pub struct Image {
     image: vk::Image,
-    allocation: vk_mem::Allocation,
-    allocation_info: vk_mem::AllocationInfo,
+    allocation: Option<Allocation>,
     create_info: ImageCreateInfo,
     view: Option<ImageView>,
+    /// Views created on demand by `get_view`.
+    view_cache: Mutex<HashMap<ViewKey, vk::ImageView>>,
     layout_type: ImageLayoutType,
-    stage_flags: vk::PipelineStageFlags,
-    access_flags: vk::AccessFlags,
+    current_layout: vk::ImageLayout,
+    /// The accesses to the image since it was last written.
+    history: AccessHistory,
+    /// The state of each subresource, indexed by `level * layers + layer`, while they are not all
+    /// in the same state. `None` when the whole image is in the state given by the fields above.
+    subresource_states: Option<Vec<TrackedState>>,
     swapchain_layout: vk::ImageLayout,
     tag: Option<Tag>,
+    /// The pages of a sparse image and the memory bound to them. A sparse image has no
+    /// `allocation`, but is owned and destroyed along with this memory.
+    #[derivative(Debug = "ignore")]
+    sparse: Option<SparseResidency>,
+    /// The memory of an image made by `create_aliased_images`, shared with the rest of its group.
+    /// The image has no `allocation`, but is owned, and the memory is freed with the last image.
+    #[derivative(Debug = "ignore")]
+    aliased: Option<Arc<AliasedMemory>>,
     #[derivative(Debug = "ignore")]
     device: Arc<Device>,
 }
 
 impl Drop for Image {
     fn drop(&mut self) {
-        // Destroy the image view(s) first by dropping the owned ImageView struct.
-        let _ = self.view.take();
+        // Destroy the image view(s) first.
+        if let Some(view) = self.view.take() {
+            unsafe { view.destroy(self.device.raw_device()) };
+        }
+        for (_, view) in self.view_cache.get_mut().drain() {
+            unsafe { self.device.raw_device().destroy_image_view(view, None) };
+        }
 
-        if let Err(e) = self.device.raw_allocator().destroy_image(self.image, &self.allocation) {
-            if let Some(ref tag) = self.tag {
-                panic!("OwnedBuffer with tag {} errored on destruction: {:#?}", tag, e);
-            } else {
-                panic!("Generic (untagged) Buffer errored on destruction: {:#?}", e);
+        if let Some(sparse) = self.sparse.take() {
+            sparse.free(&self.device, self.tag.as_ref());
+            unsafe { self.device.raw_device().destroy_image(self.image, None) };
+        }
+
+        if let Some(aliased) = self.aliased.take() {
+            unsafe { self.device.raw_device().destroy_image(self.image, None) };
+            if let Some(memory) = Arc::into_inner(aliased) {
+                memory.free(&self.device, self.tag.as_ref());
+            }
+        }
+
+        // Other images without an allocation, such as swapchain images, are not owned by us.
+        if let Some(ref allocation) = self.allocation {
+            self.device.track_allocation(
+                MemoryCategory::Images,
+                self.tag.as_ref(),
+                allocation.size(),
+                false,
+            );
+            if let Err(e) = self.device.allocator().destroy_image(self.image, allocation) {
+                self.device.invariant_failed(
+                    self.tag.as_ref(),
+                    format!("Image errored on destruction: {:#?}", e),
+                );
             }
         }
     }
@@ -273,11 +618,11 @@ impl Image {
     /// # Safety
     ///
     /// `device` must be the Device that this Image was allocated from.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) unsafe fn new(
         device: Arc<Device>,
         image: vk::Image,
-        allocation: vk_mem::Allocation,
-        allocation_info: vk_mem::AllocationInfo,
+        allocation: Option<Allocation>,
         create_info: ImageCreateInfo,
         view: Option<ImageView>,
         layout_type: ImageLayoutType,
@@ -286,21 +631,129 @@ impl Image {
         swapchain_layout: vk::ImageLayout,
         tag: Option<Tag>,
     ) -> Self {
+        device.set_tag_name(image, tag.as_ref());
+        if let Some(ref allocation) = allocation {
+            device.track_allocation(MemoryCategory::Images, tag.as_ref(), allocation.size(), true);
+        }
+        if let Some(ref view) = view {
+            for raw in view.raw_views() {
+                device.set_tag_name(raw, tag.as_ref());
+            }
+        }
         Self {
             image,
             allocation,
-            allocation_info,
             create_info,
             view,
+            view_cache: Mutex::new(HashMap::new()),
             layout_type,
-            stage_flags,
-            access_flags,
+            current_layout: vk::ImageLayout::UNDEFINED,
+            history: AccessHistory::last_accessed(stage_flags, access_flags),
+            subresource_states: None,
             swapchain_layout,
             tag,
+            sparse: None,
+            aliased: None,
             device: device.clone(),
         }
     }
 
+    /// The raw `vk::Image`.
+    pub fn raw(&self) -> vk::Image {
+        self.image
+    }
+
+    /// The tag the image was created with.
+    pub fn tag(&self) -> Option<&Tag> {
+        self.tag.as_ref()
+    }
+
+    /// The memory allocation backing this image, if it is owned by `hot`. Swapchain images have
+    /// no allocation.
+    pub fn allocation(&self) -> Option<&Allocation> {
+        self.allocation.as_ref()
+    }
+
+    /// The views of this image created by `Device::create_image`, if any.
+    pub fn view(&self) -> Option<&ImageView> {
+        self.view.as_ref()
+    }
+
+    /// Get a view of the subresources, format and type described by `key`, creating it the
+    /// first time it is requested. Views are cached per image and destroyed with it, so passes
+    /// which request the same view share it.
+    pub fn get_view(&self, key: ViewKey) -> VkResult<vk::ImageView> {
+        let mut cache = self.view_cache.lock();
+        if let Some(&view) = cache.get(&key) {
+            return Ok(view);
+        }
+
+        let format = if key.format == vk::Format::UNDEFINED {
+            self.create_info.format
+        } else {
+            key.format
+        };
+        let aspect_mask = if key.aspect_mask.is_empty() {
+            format_to_aspect_mask(format)
+        } else {
+            key.aspect_mask
+        };
+        let info = vk::ImageViewCreateInfo::builder()
+            .image(self.image)
+            .view_type(key.view_type)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask,
+                base_mip_level: key.mips.start,
+                level_count: key.mips.end - key.mips.start,
+                base_array_layer: key.layers.start,
+                layer_count: key.layers.end - key.layers.start,
+            });
+        let view = unsafe { self.device.raw_device().create_image_view(&info, None)? };
+        self.device.set_tag_name(view, self.tag.as_ref());
+
+        cache.insert(key, view);
+        Ok(view)
+    }
+
+    /// Give this image the views it owns, destroying any it had before.
+    pub(crate) fn set_view(&mut self, view: ImageView) {
+        for raw in view.raw_views() {
+            self.device.set_tag_name(raw, self.tag.as_ref());
+        }
+        if let Some(old) = self.view.replace(view) {
+            unsafe { old.destroy(self.device.raw_device()) };
+        }
+    }
+
+    /// The pages of this image and the memory bound to them, if it is a sparse image.
+    pub(crate) fn sparse(&self) -> Option<&SparseResidency> {
+        self.sparse.as_ref()
+    }
+
+    pub(crate) fn sparse_mut(&mut self) -> Option<&mut SparseResidency> {
+        self.sparse.as_mut()
+    }
+
+    pub(crate) fn set_sparse(&mut self, sparse: SparseResidency) {
+        self.sparse = Some(sparse);
+    }
+
+    /// The memory shared by this image's group, if it was made by `create_aliased_images`.
+    pub(crate) fn aliased(&self) -> Option<&Arc<AliasedMemory>> {
+        self.aliased.as_ref()
+    }
+
+    pub(crate) fn set_aliased(&mut self, memory: Arc<AliasedMemory>) {
+        self.aliased = Some(memory);
+    }
+
+    /// Get the layout this image must be in to be presented, if it is a swapchain image.
+    /// Otherwise, `vk::ImageLayout::UNDEFINED`.
+    pub fn swapchain_layout(&self) -> vk::ImageLayout {
+        self.swapchain_layout
+    }
+
     /// Get the width of this image.
     pub fn width(&self) -> usize {
         self.create_info.width
@@ -343,6 +796,218 @@ impl Image {
     pub fn layout(&self, optimal_layout: vk::ImageLayout) -> vk::ImageLayout {
         self.layout_type.layout(optimal_layout)
     }
+
+    /// Get the full subresource range of this image.
+    pub fn full_subresource_range(&self) -> vk::ImageSubresourceRange {
+        vk::ImageSubresourceRange {
+            aspect_mask: format_to_aspect_mask(self.create_info.format),
+            base_mip_level: 0,
+            level_count: vk::REMAINING_MIP_LEVELS,
+            base_array_layer: 0,
+            layer_count: vk::REMAINING_ARRAY_LAYERS,
+        }
+    }
+
+    /// Get the tracked synchronization state of this image.
+    ///
+    /// If the image's subresources are currently in different states, this is the state of the
+    /// first subresource; see `subresource_state`.
+    pub fn state(&self) -> ImageState {
+        match self.subresource_states {
+            Some(ref states) => states[0].state(),
+            None => self.uniform_state().state(),
+        }
+    }
+
+    /// Get the tracked synchronization state of the subresource at mip `level` and array `layer`.
+    pub fn subresource_state(&self, level: u32, layer: u32) -> ImageState {
+        assert!(
+            (level as usize) < self.create_info.levels && (layer as usize) < self.create_info.layers,
+            "subresource out of range"
+        );
+        match self.subresource_states {
+            Some(ref states) => states[self.subresource_index(level, layer)].state(),
+            None => self.uniform_state().state(),
+        }
+    }
+
+    /// Get whether the subresources of this image are currently in different states.
+    pub fn has_divergent_subresources(&self) -> bool {
+        self.subresource_states.is_some()
+    }
+
+    /// Overwrite the tracked synchronization state of this image without recording a barrier,
+    /// for example when its memory has been aliased by another image.
+    pub(crate) fn set_state(&mut self, state: ImageState) {
+        self.set_tracked_state(TrackedState {
+            layout: state.layout,
+            history: AccessHistory::last_accessed(state.stages, state.access),
+        });
+    }
+
+    fn set_tracked_state(&mut self, state: TrackedState) {
+        self.current_layout = state.layout;
+        self.history = state.history;
+        self.subresource_states = None;
+    }
+
+    /// Compute the barriers needed to move this whole image into the `new` state, and record
+    /// `new` as the image's current state. The returned barriers must be recorded before the
+    /// image is used in the new state.
+    ///
+    /// If the image's subresources were in different states, one barrier is returned per group
+    /// of subresources which shared a state, and the image is merged back into a single state.
+    pub fn transition_to(&mut self, new: ImageState) -> Vec<ImageTransition> {
+        self.transition_subresources_to(self.full_subresource_range(), new)
+    }
+
+    /// Compute the barriers needed to move the subresources in `range` into the `new` state, and
+    /// record `new` as their current state, leaving the state of the other subresources as it
+    /// was. The returned barriers must be recorded before the subresources are used in the new
+    /// state.
+    ///
+    /// This allows e.g. one mip level to be in `TRANSFER_SRC_OPTIMAL` while the next is in
+    /// `TRANSFER_DST_OPTIMAL` while generating mips. Once every subresource is in the same state
+    /// again, the image goes back to being tracked as a whole.
+    pub fn transition_subresources_to(
+        &mut self,
+        range: vk::ImageSubresourceRange,
+        new: ImageState,
+    ) -> Vec<ImageTransition> {
+        let levels = self.create_info.levels as u32;
+        let layers = self.create_info.layers as u32;
+
+        let base_level = range.base_mip_level;
+        let level_count = if range.level_count == vk::REMAINING_MIP_LEVELS {
+            levels - base_level
+        } else {
+            range.level_count
+        };
+        let base_layer = range.base_array_layer;
+        let layer_count = if range.layer_count == vk::REMAINING_ARRAY_LAYERS {
+            layers - base_layer
+        } else {
+            range.layer_count
+        };
+        assert!(
+            base_level + level_count <= levels && base_layer + layer_count <= layers,
+            "subresource range out of bounds"
+        );
+
+        let whole_image = level_count == levels && layer_count == layers;
+
+        if whole_image && self.subresource_states.is_none() {
+            let range = self.full_subresource_range();
+            let old_layout = self.current_layout;
+            let transition =
+                tracked_image_transition(self.image, range, old_layout, &mut self.history, new);
+            self.current_layout = new.layout;
+            return transition.into_iter().collect();
+        }
+
+        let uniform = self.uniform_state();
+        let mut states = self
+            .subresource_states
+            .take()
+            .unwrap_or_else(|| vec![uniform; (levels * layers) as usize]);
+
+        // Group the subresources which need a barrier into runs of consecutive layers of a level
+        // which share an old state, so that as few barriers as possible are needed.
+        let mut runs: Vec<(u32, u32, u32, u32, TrackedState)> = Vec::new();
+        for level in base_level..base_level + level_count {
+            let mut level_runs: Vec<(u32, u32, TrackedState)> = Vec::new();
+            for layer in base_layer..base_layer + layer_count {
+                let index = (level * layers + layer) as usize;
+                let old = states[index];
+                let needs_barrier = states[index].transition(self.image, range, new).is_some();
+
+                if !needs_barrier {
+                    continue;
+                }
+                match level_runs.last_mut() {
+                    Some((start, count, state)) if *start + *count == layer && *state == old => {
+                        *count += 1
+                    }
+                    _ => level_runs.push((layer, 1, old)),
+                }
+            }
+
+            // Merge with the previous level if it had exactly the same runs.
+            for (layer_start, layer_count, old) in level_runs {
+                let merged = runs.iter_mut().rev().find(|run| {
+                    run.0 + run.1 == level && run.2 == layer_start && run.3 == layer_count && run.4 == old
+                });
+                match merged {
+                    Some(run) => run.1 += 1,
+                    None => runs.push((level, 1, layer_start, layer_count, old)),
+                }
+            }
+        }
+
+        let aspect_mask = range.aspect_mask;
+        let transitions = runs
+            .into_iter()
+            .filter_map(|(level_start, level_count, layer_start, layer_count, mut old)| {
+                let range = vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: level_start,
+                    level_count,
+                    base_array_layer: layer_start,
+                    layer_count,
+                };
+                old.transition(self.image, range, new)
+            })
+            .collect();
+
+        if states.iter().all(|&state| state == states[0]) {
+            self.set_tracked_state(states[0]);
+        } else {
+            self.subresource_states = Some(states);
+        }
+
+        transitions
+    }
+
+    fn uniform_state(&self) -> TrackedState {
+        TrackedState {
+            layout: self.current_layout,
+            history: self.history,
+        }
+    }
+
+    fn subresource_index(&self, level: u32, layer: u32) -> usize {
+        level as usize * self.create_info.layers + layer as usize
+    }
+}
+
+/// The layout of an image or subresource and the accesses to it since it was last written.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct TrackedState {
+    layout: vk::ImageLayout,
+    history: AccessHistory,
+}
+
+impl TrackedState {
+    fn state(&self) -> ImageState {
+        ImageState {
+            layout: self.layout,
+            stages: self.history.stages(),
+            access: self.history.access(),
+        }
+    }
+
+    /// Compute the barrier needed to move the subresources in `range` into the `new` state, and
+    /// record the move.
+    fn transition(
+        &mut self,
+        image: vk::Image,
+        range: vk::ImageSubresourceRange,
+        new: ImageState,
+    ) -> Option<ImageTransition> {
+        let transition = tracked_image_transition(image, range, self.layout, &mut self.history, new);
+        self.layout = new.layout;
+        transition
+    }
 }
 
 /// Get the number of possible mip levels for an image given its extent.
@@ -420,7 +1085,7 @@ pub fn image_usage_to_possible_stages(usage: vk::ImageUsageFlags) -> vk::Pipelin
         flags &= possible;
     }
 
-    return flags;
+    flags
 }
 
 /// Get all possible vk::AccessFlags from a given vk::ImageLayout
@@ -0,0 +1,447 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::{format_layer_size, format_to_aspect_mask};
+
+/// The alignment of each region within the staging data, which satisfies the buffer offset
+/// alignment of copies to images of every format.
+pub(crate) const REGION_ALIGNMENT: usize = 16;
+
+/// An error that could occur while loading images into an array image.
+#[derive(Error, Debug)]
+pub enum ImageArrayError {
+    /// No images were given.
+    #[error("an image array needs at least one layer")]
+    Empty,
+    /// An image file could not be read.
+    #[error("could not read image file: {0}")]
+    Io(#[from] std::io::Error),
+    /// An image is not a KTX2 or DDS file, or uses features of one which are not supported.
+    #[error("layer {layer}: {reason}")]
+    Unsupported {
+        /// The layer of the image.
+        layer: usize,
+        /// What is not supported.
+        reason: &'static str,
+    },
+    /// An image is truncated, or its data is smaller than its dimensions need.
+    #[error("layer {layer}: image data is truncated")]
+    Truncated {
+        /// The layer of the image.
+        layer: usize,
+    },
+    /// An image's dimensions, format or mip level count differ from the first image's.
+    #[error("layer {layer} does not match the dimensions, format and levels of layer 0")]
+    Mismatched {
+        /// The layer of the image.
+        layer: usize,
+    },
+    /// The image could not be created or uploaded.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+/// The data of one layer of an image array created with `Device::create_image_array`.
+#[derive(Clone, Copy, Debug)]
+pub enum ImagePayload<'a> {
+    /// Tightly packed texels of mip level 0 of a 2D image.
+    Raw {
+        /// The texels.
+        data: &'a [u8],
+        /// Width of the image in pixels.
+        width: u32,
+        /// Height of the image in pixels.
+        height: u32,
+        /// The format of the texels.
+        format: vk::Format,
+    },
+    /// The contents of a KTX2 file holding a single 2D image without supercompression. Every
+    /// mip level in the file is uploaded.
+    Ktx2(&'a [u8]),
+    /// The contents of a DDS file holding a single 2D image. Every mip level in the file is
+    /// uploaded.
+    Dds(&'a [u8]),
+}
+
+/// A 2D image parsed out of an `ImagePayload`.
+struct ParsedImage<'a> {
+    width: u32,
+    height: u32,
+    format: vk::Format,
+    /// The data of each mip level, largest first.
+    levels: Vec<&'a [u8]>,
+}
+
+impl ParsedImage<'_> {
+    fn matches(&self, other: &ParsedImage<'_>) -> bool {
+        self.width == other.width
+            && self.height == other.height
+            && self.format == other.format
+            && self.levels.len() == other.levels.len()
+    }
+}
+
+impl Device {
+    /// Read each file in `paths`, which must be KTX2 or DDS files, and load them into the
+    /// layers of a single 2D array image. See `create_image_array`.
+    pub fn create_image_array_from_files<P: AsRef<Path>>(
+        self: &Arc<Self>,
+        paths: &[P],
+        usage: vk::ImageUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, ImageArrayError> {
+        let files = paths
+            .iter()
+            .map(std::fs::read)
+            .collect::<Result<Vec<_>, _>>()?;
+        let payloads = files
+            .iter()
+            .enumerate()
+            .map(|(layer, file)| {
+                if file.starts_with(&KTX2_IDENTIFIER) {
+                    Ok(ImagePayload::Ktx2(file))
+                } else if file.starts_with(DDS_MAGIC) {
+                    Ok(ImagePayload::Dds(file))
+                } else {
+                    Err(ImageArrayError::Unsupported {
+                        layer,
+                        reason: "not a KTX2 or DDS file",
+                    })
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.create_image_array(&payloads, usage, tag)
+    }
+
+    /// Create a 2D array image with one layer per payload, which must all have the same
+    /// dimensions, format and number of mip levels.
+    ///
+    /// Every layer is uploaded through a single staging allocation in one submission on the
+    /// async transfer queue, which the next graphics submission waits on, rather than one per
+    /// image. The image is in `SHADER_READ_ONLY_OPTIMAL` afterwards, and is created with
+    /// `usage` as well as `SAMPLED` and `TRANSFER_DST`.
+    pub fn create_image_array(
+        self: &Arc<Self>,
+        payloads: &[ImagePayload<'_>],
+        usage: vk::ImageUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, ImageArrayError> {
+        let images = payloads
+            .iter()
+            .enumerate()
+            .map(|(layer, payload)| parse_payload(layer, payload))
+            .collect::<Result<Vec<_>, _>>()?;
+        let first = images.first().ok_or(ImageArrayError::Empty)?;
+        if let Some(layer) = images.iter().position(|image| !image.matches(first)) {
+            return Err(ImageArrayError::Mismatched { layer });
+        }
+
+        // Regions are ordered by level and then layer, so each level is one copy.
+        let level_count = first.levels.len();
+        let mut data = Vec::new();
+        let mut level_offsets = Vec::with_capacity(level_count);
+        for level in 0..level_count {
+            data.resize(data.len().next_multiple_of(REGION_ALIGNMENT), 0);
+            level_offsets.push(data.len() as vk::DeviceSize);
+            for image in &images {
+                data.extend_from_slice(image.levels[level]);
+            }
+        }
+
+        let create_info = ImageCreateInfo {
+            width: first.width as usize,
+            height: first.height as usize,
+            depth: 1,
+            levels: level_count,
+            layers: images.len(),
+            format: first.format,
+            usage: usage | vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..Default::default()
+        };
+        let handle = self.clone().create_image(create_info, None, tag.clone())?;
+        let image = self
+            .resources()
+            .get_image(handle)
+            .expect("create_image_array: image was destroyed during creation")
+            .raw();
+
+        let aspect_mask = format_to_aspect_mask(first.format);
+        let layer_count = images.len() as u32;
+        let (width, height) = (first.width, first.height);
+        let upload = self.upload_via_staging(&data, tag, |cmd, src, src_offset| {
+            cmd.transition_image(
+                handle,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let regions = level_offsets
+                .iter()
+                .enumerate()
+                .map(|(level, &offset)| vk::BufferImageCopy {
+                    buffer_offset: src_offset + offset,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask,
+                        mip_level: level as u32,
+                        base_array_layer: 0,
+                        layer_count,
+                    },
+                    image_offset: vk::Offset3D::default(),
+                    image_extent: vk::Extent3D {
+                        width: (width >> level).max(1),
+                        height: (height >> level).max(1),
+                        depth: 1,
+                    },
+                })
+                .collect::<Vec<_>>();
+            unsafe {
+                cmd.device().cmd_copy_buffer_to_image(
+                    cmd.raw(),
+                    src,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &regions,
+                )
+            };
+
+            // As in `create_image`, the transfer queue can't wait on later stages.
+            cmd.transition_image(
+                handle,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::PipelineStageFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+        });
+        if let Err(e) = upload {
+            self.destroy_image(handle);
+            return Err(e.into());
+        }
+
+        Ok(handle)
+    }
+}
+
+fn parse_payload<'a>(layer: usize, payload: &ImagePayload<'a>) -> Result<ParsedImage<'a>, ImageArrayError> {
+    let image = match *payload {
+        ImagePayload::Raw {
+            data,
+            width,
+            height,
+            format,
+        } => ParsedImage {
+            width,
+            height,
+            format,
+            levels: vec![data],
+        },
+        ImagePayload::Ktx2(file) => parse_ktx2(layer, file)?,
+        ImagePayload::Dds(file) => parse_dds(layer, file)?,
+    };
+
+    // Every level must hold at least as much data as its dimensions need, and any padding after
+    // it is left out of the upload.
+    let levels = image
+        .levels
+        .iter()
+        .enumerate()
+        .map(|(level, data)| {
+            let (width, height) = ((image.width >> level).max(1), (image.height >> level).max(1));
+            let size = format_layer_size(image.format, width, height, 1).ok_or(ImageArrayError::Unsupported {
+                layer,
+                reason: "the format has no known texel size",
+            })?;
+            data.get(..size as usize).ok_or(ImageArrayError::Truncated { layer })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(ParsedImage { levels, ..image })
+}
+
+pub(crate) fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+pub(crate) fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(read_u32(data, offset)? as u64 | (read_u32(data, offset + 4)? as u64) << 32)
+}
+
+pub(crate) const KTX2_IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+
+/// Parse a KTX2 file. The header is followed by a level index, which gives the offset and
+/// length of each level, largest first.
+fn parse_ktx2(layer: usize, file: &[u8]) -> Result<ParsedImage<'_>, ImageArrayError> {
+    let unsupported = |reason| ImageArrayError::Unsupported { layer, reason };
+    let truncated = ImageArrayError::Truncated { layer };
+
+    if !file.starts_with(&KTX2_IDENTIFIER) {
+        return Err(unsupported("not a KTX2 file"));
+    }
+    let header = |index: usize| read_u32(file, 12 + index * 4).ok_or(ImageArrayError::Truncated { layer });
+    let format = vk::Format::from_raw(header(0)? as i32);
+    let (width, height, depth) = (header(2)?, header(3)?, header(4)?);
+    let (layer_count, face_count, level_count, supercompression) =
+        (header(5)?, header(6)?, header(7)?, header(8)?);
+
+    if format == vk::Format::UNDEFINED {
+        return Err(unsupported("KTX2 files of Basis Universal data are not supported"));
+    }
+    if supercompression != 0 {
+        return Err(unsupported("supercompressed KTX2 files are not supported"));
+    }
+    if height == 0 || depth > 1 || layer_count > 1 || face_count != 1 {
+        return Err(unsupported("only single 2D images are supported"));
+    }
+
+    // A level count of 0 asks for mips to be generated, which only level 0 is given for.
+    const LEVEL_INDEX: usize = 80;
+    let levels = (0..level_count.max(1) as usize)
+        .map(|level| {
+            let entry = LEVEL_INDEX + level * 24;
+            let offset = read_u64(file, entry)? as usize;
+            let length = read_u64(file, entry + 8)? as usize;
+            file.get(offset..offset.checked_add(length)?)
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(truncated)?;
+
+    Ok(ParsedImage {
+        width,
+        height,
+        format,
+        levels,
+    })
+}
+
+pub(crate) const DDS_MAGIC: &[u8] = b"DDS ";
+
+/// Parse a DDS file. The data of each level, largest first, directly follows the header.
+fn parse_dds(layer: usize, file: &[u8]) -> Result<ParsedImage<'_>, ImageArrayError> {
+    let unsupported = |reason| ImageArrayError::Unsupported { layer, reason };
+    let field = |offset: usize| read_u32(file, offset).ok_or(ImageArrayError::Truncated { layer });
+
+    if !file.starts_with(DDS_MAGIC) {
+        return Err(unsupported("not a DDS file"));
+    }
+    const DDSD_DEPTH: u32 = 0x80_0000;
+    const DDSCAPS2_CUBEMAP: u32 = 0x200;
+    let (flags, height, width, level_count) = (field(8)?, field(12)?, field(16)?, field(28)?);
+    if flags & DDSD_DEPTH != 0 || field(112)? & DDSCAPS2_CUBEMAP != 0 {
+        return Err(unsupported("only single 2D images are supported"));
+    }
+
+    if field(84)? == u32::from_le_bytes(*b"DX10") && field(140)? > 1 {
+        return Err(unsupported("only single 2D images are supported"));
+    }
+    let (format, data_offset) = dds_format(file).map_err(|e| match e {
+        DdsFormatError::Truncated => ImageArrayError::Truncated { layer },
+        DdsFormatError::Unsupported(reason) => unsupported(reason),
+    })?;
+
+    let mut offset = data_offset;
+    let levels = (0..level_count.max(1))
+        .map(|level| {
+            let size = format_layer_size(format, (width >> level).max(1), (height >> level).max(1), 1)?;
+            let data = file.get(offset..offset + size as usize)?;
+            offset += size as usize;
+            Some(data)
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(ImageArrayError::Truncated { layer })?;
+
+    Ok(ParsedImage {
+        width,
+        height,
+        format,
+        levels,
+    })
+}
+
+/// Why the pixel format of a DDS file could not be read.
+pub(crate) enum DdsFormatError {
+    /// The header is truncated.
+    Truncated,
+    /// The pixel format is not supported.
+    Unsupported(&'static str),
+}
+
+/// Get the format of a DDS file and the offset of its data, which follows the DX10 header if
+/// there is one.
+pub(crate) fn dds_format(file: &[u8]) -> Result<(vk::Format, usize), DdsFormatError> {
+    let field = |offset: usize| read_u32(file, offset).ok_or(DdsFormatError::Truncated);
+    let unsupported = DdsFormatError::Unsupported;
+
+    const DDPF_FOURCC: u32 = 0x4;
+    let (pixel_flags, four_cc) = (field(80)?, field(84)?);
+    let (format, data_offset) = if pixel_flags & DDPF_FOURCC != 0 && four_cc == u32::from_le_bytes(*b"DX10") {
+        let format = dxgi_format(field(128)?).ok_or(unsupported("unsupported DXGI format"))?;
+        (format, 148)
+    } else if pixel_flags & DDPF_FOURCC != 0 {
+        let format = match &four_cc.to_le_bytes() {
+            b"DXT1" => vk::Format::BC1_RGBA_UNORM_BLOCK,
+            b"DXT2" | b"DXT3" => vk::Format::BC2_UNORM_BLOCK,
+            b"DXT4" | b"DXT5" => vk::Format::BC3_UNORM_BLOCK,
+            b"ATI1" | b"BC4U" => vk::Format::BC4_UNORM_BLOCK,
+            b"BC4S" => vk::Format::BC4_SNORM_BLOCK,
+            b"ATI2" | b"BC5U" => vk::Format::BC5_UNORM_BLOCK,
+            b"BC5S" => vk::Format::BC5_SNORM_BLOCK,
+            _ => return Err(unsupported("unsupported DDS FourCC")),
+        };
+        (format, 128)
+    } else {
+        // Uncompressed 32-bit formats are told apart by their channel masks.
+        let masks = (field(88)?, field(92)?, field(96)?, field(100)?);
+        let format = match masks {
+            (32, 0xff, 0xff00, 0xff_0000) => vk::Format::R8G8B8A8_UNORM,
+            (32, 0xff_0000, 0xff00, 0xff) => vk::Format::B8G8R8A8_UNORM,
+            _ => return Err(unsupported("unsupported DDS pixel format")),
+        };
+        (format, 128)
+    };
+    Ok((format, data_offset))
+}
+
+/// Get the `vk::Format` of a `DXGI_FORMAT`, for the formats DDS files commonly use.
+pub(crate) fn dxgi_format(format: u32) -> Option<vk::Format> {
+    let format = match format {
+        2 => vk::Format::R32G32B32A32_SFLOAT,
+        10 => vk::Format::R16G16B16A16_SFLOAT,
+        24 => vk::Format::A2B10G10R10_UNORM_PACK32,
+        26 => vk::Format::B10G11R11_UFLOAT_PACK32,
+        28 => vk::Format::R8G8B8A8_UNORM,
+        29 => vk::Format::R8G8B8A8_SRGB,
+        34 => vk::Format::R16G16_SFLOAT,
+        41 => vk::Format::R32_SFLOAT,
+        49 => vk::Format::R8G8_UNORM,
+        54 => vk::Format::R16_SFLOAT,
+        61 => vk::Format::R8_UNORM,
+        71 => vk::Format::BC1_RGBA_UNORM_BLOCK,
+        72 => vk::Format::BC1_RGBA_SRGB_BLOCK,
+        74 => vk::Format::BC2_UNORM_BLOCK,
+        75 => vk::Format::BC2_SRGB_BLOCK,
+        77 => vk::Format::BC3_UNORM_BLOCK,
+        78 => vk::Format::BC3_SRGB_BLOCK,
+        80 => vk::Format::BC4_UNORM_BLOCK,
+        81 => vk::Format::BC4_SNORM_BLOCK,
+        83 => vk::Format::BC5_UNORM_BLOCK,
+        84 => vk::Format::BC5_SNORM_BLOCK,
+        87 => vk::Format::B8G8R8A8_UNORM,
+        91 => vk::Format::B8G8R8A8_SRGB,
+        95 => vk::Format::BC6H_UFLOAT_BLOCK,
+        96 => vk::Format::BC6H_SFLOAT_BLOCK,
+        98 => vk::Format::BC7_UNORM_BLOCK,
+        99 => vk::Format::BC7_SRGB_BLOCK,
+        _ => return None,
+    };
+    Some(format)
+}
@@ -0,0 +1,521 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use crate::*;
+use crate::format::{format_is_srgb, format_to_aspect_mask};
+use crate::image::unorm_and_srgb_formats;
+
+/// How `CommandBuffer::convert_image` treats the sRGB encoding of the images' formats.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum SrgbConversion {
+    /// Preserve the colors: texels of an sRGB source are decoded and texels written to an sRGB
+    /// destination are encoded, as a blit does.
+    Preserve,
+    /// Copy the stored values unchanged, without decoding or encoding either format, e.g. to
+    /// move already encoded data from a UNORM image into an sRGB one.
+    Reinterpret,
+    /// Treat the stored values of the source as linear and store them sRGB encoded, whatever
+    /// the formats say, e.g. to encode a UNORM image into another UNORM image.
+    Encode,
+    /// Treat the stored values of the source as sRGB encoded and store them decoded, whatever
+    /// the formats say.
+    Decode,
+}
+
+/// How `CommandBuffer::convert_image` converted an image.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ConversionMethod {
+    /// The texels were copied, since the stored values don't change.
+    Copy,
+    /// The image was blitted, since both formats support it and it converts as requested.
+    Blit,
+    /// A compute shader converted the texels.
+    Compute,
+}
+
+/// An error that could occur while converting an image.
+#[derive(Error, Debug)]
+pub enum ConvertImageError {
+    /// One of the images does not exist.
+    #[error("one of the images does not exist")]
+    InvalidImage,
+    /// The images' first mip levels differ in size.
+    #[error("the images must have the same extent")]
+    MismatchedExtent,
+    /// The conversion needs a compute shader, but one of the images is not a single sample 2D
+    /// image.
+    #[error("compute conversion needs single sample 2D images")]
+    UnsupportedShape,
+    /// The conversion needs a compute shader, which can't read `src` or write `dst`.
+    #[error("can't convert from {src:?} to {dst:?}")]
+    UnsupportedFormats {
+        /// The format the source would be read as.
+        src: vk::Format,
+        /// The format the destination would be written as.
+        dst: vk::Format,
+    },
+    /// The conversion needs to view an image as its UNORM variant, but it was not created with
+    /// `MUTABLE_FORMAT`.
+    #[error("the image must be created with MUTABLE_FORMAT to be viewed as {0:?}")]
+    NeedsMutableFormat(vk::Format),
+    /// The conversion needs a compute shader, but the source doesn't have `SAMPLED` usage or
+    /// the destination doesn't have `STORAGE` usage.
+    #[error("compute conversion needs a SAMPLED source and a STORAGE destination")]
+    MissingUsage,
+    /// Reflecting the built-in shader failed.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// The work group size of the conversion shader in x and y.
+const CONVERT_GROUP_SIZE: u32 = 8;
+
+/// What the conversion shader does to the color channels, as passed in its push constants.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ShaderMode {
+    Passthrough = 0,
+    Encode = 1,
+    Decode = 2,
+}
+
+/// The formats a compute conversion views the images as and what it does to their texels.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct ComputePlan {
+    read_format: vk::Format,
+    write_format: vk::Format,
+    mode: ShaderMode,
+}
+
+/// Get the UNORM variant of an sRGB format, or the format itself.
+fn linear_format(format: vk::Format) -> vk::Format {
+    match unorm_and_srgb_formats(format) {
+        Some((unorm, _)) => unorm,
+        None => format,
+    }
+}
+
+/// Plan a conversion which reads `src` raw, applies `mode`, and writes `dst` raw.
+fn raw_plan(src: vk::Format, dst: vk::Format, mode: ShaderMode) -> ComputePlan {
+    ComputePlan {
+        read_format: linear_format(src),
+        write_format: linear_format(dst),
+        mode,
+    }
+}
+
+/// The compute pipeline converting into images viewed as one storage format. It and its layout
+/// live as long as the Device.
+pub(crate) struct ConversionPipeline {
+    pipeline: ComputePipeline,
+    set_layout: vk::DescriptorSetLayout,
+}
+
+impl Device {
+    /// Get the pipeline writing `write_format`, creating it the first time it is needed.
+    fn conversion_pipeline(
+        &self,
+        write_format: vk::Format,
+        variant: usize,
+    ) -> Result<(ComputePipeline, vk::DescriptorSetLayout), ConvertImageError> {
+        let mut pipelines = self.conversion_pipelines.lock();
+        if let Some(existing) = pipelines.get(&write_format) {
+            return Ok((existing.pipeline, existing.set_layout));
+        }
+
+        let shader = self.create_shader(shaders::IMAGE_CONVERT[variant])?;
+        let layout = match self.create_shader_layout(&[&shader]) {
+            Ok(layout) => layout,
+            Err(e) => {
+                unsafe { self.destroy_shader(shader) };
+                return Err(e.into());
+            }
+        };
+        let pipeline = self.create_compute_pipeline(shader.raw(), layout.raw());
+        // The module is no longer needed once the pipeline has been created from it.
+        unsafe { self.destroy_shader(shader) };
+        let pipeline = match pipeline {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe { self.destroy_shader_layout(layout) };
+                return Err(e.into());
+            }
+        };
+
+        let set_layout = layout.set_layouts()[0];
+        pipelines.insert(write_format, ConversionPipeline { pipeline, set_layout });
+        Ok((pipeline, set_layout))
+    }
+
+    /// Allocate a descriptor set of `layout` which lives until the current frame completes.
+    ///
+    /// Also used for the sets of `CommandBuffer::upscale_to_swapchain`.
+    pub(crate) fn allocate_conversion_set(&self, layout: vk::DescriptorSetLayout) -> VkResult<vk::DescriptorSet> {
+        let mut frame = self.current_frame().write();
+        let layouts = [layout];
+
+        for &pool in &frame.conversion_descriptor_pools {
+            let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            match unsafe { self.device.allocate_descriptor_sets(&alloc_info) } {
+                Ok(sets) => return Ok(sets[0]),
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        const SETS_PER_POOL: u32 = 32;
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: SETS_PER_POOL,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: SETS_PER_POOL,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: SETS_PER_POOL,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(SETS_PER_POOL)
+            .pool_sizes(&pool_sizes);
+        let pool = unsafe { self.device.create_descriptor_pool(&pool_info, None)? };
+        frame.conversion_descriptor_pools.push(pool);
+
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        Ok(unsafe { self.device.allocate_descriptor_sets(&alloc_info)? }[0])
+    }
+
+    /// Free the descriptor sets of the conversions recorded during a frame which has completed.
+    pub(crate) fn reset_conversion_descriptor_pools(&self, frame: &mut PerFrame) -> VkResult<()> {
+        for &pool in &frame.conversion_descriptor_pools {
+            unsafe {
+                self.device
+                    .reset_descriptor_pool(pool, vk::DescriptorPoolResetFlags::empty())?
+            };
+        }
+        Ok(())
+    }
+
+    /// Get the optimal tiling features of `format`.
+    pub(crate) fn format_features(&self, format: vk::Format) -> vk::FormatFeatureFlags {
+        self.format_properties(format).optimal_tiling_features
+    }
+}
+
+impl CommandBuffer {
+    /// Convert the contents of `src` into `dst`, which has a different but compatible format,
+    /// treating sRGB encoding as `srgb` says rather than as the formats of whichever views
+    /// happen to be used.
+    ///
+    /// Every mip level and array layer the images have in common is converted, and their first
+    /// levels must have the same extent. The texels are copied when their stored values don't
+    /// change, blitted when the formats support blitting and a blit converts as requested, and
+    /// converted by a compute shader otherwise. The compute path needs single sample 2D images,
+    /// a float or normalized `src` with `SAMPLED` usage and a `dst` with `STORAGE` usage whose
+    /// format `mip_chain_supports_format`; sRGB images need `MUTABLE_FORMAT` to be viewed as
+    /// UNORM when the conversion must bypass the hardware's encoding.
+    ///
+    /// Returns the method used.
+    pub fn convert_image(
+        &mut self,
+        dst: ImageHandle,
+        src: ImageHandle,
+        srgb: SrgbConversion,
+    ) -> Result<ConversionMethod, ConvertImageError> {
+        let device = self.device().clone();
+        let (src_info, dst_info) = {
+            let resources = device.resources();
+            let src = resources.get_image(src).ok_or(ConvertImageError::InvalidImage)?;
+            let dst = resources.get_image(dst).ok_or(ConvertImageError::InvalidImage)?;
+            (src.create_info(), dst.create_info())
+        };
+        let extent = |info: &ImageCreateInfo| (info.width, info.height, info.depth);
+        if extent(&src_info) != extent(&dst_info) {
+            return Err(ConvertImageError::MismatchedExtent);
+        }
+        let levels = src_info.levels.min(dst_info.levels) as u32;
+        let layers = src_info.layers.min(dst_info.layers) as u32;
+
+        let (src_format, dst_format) = (src_info.format, dst_info.format);
+        let (src_srgb, dst_srgb) = (format_is_srgb(src_format), format_is_srgb(dst_format));
+        let preserves = match srgb {
+            SrgbConversion::Preserve => true,
+            SrgbConversion::Reinterpret => !src_srgb && !dst_srgb,
+            SrgbConversion::Encode => !src_srgb && dst_srgb,
+            SrgbConversion::Decode => src_srgb && !dst_srgb,
+        };
+
+        let reinterprets =
+            srgb == SrgbConversion::Reinterpret && linear_format(src_format) == linear_format(dst_format);
+        if (preserves && src_format == dst_format) || reinterprets {
+            self.convert_by_copy(dst, src, levels, layers, &src_info);
+            return Ok(ConversionMethod::Copy);
+        }
+
+        if preserves
+            && device.format_features(src_format).contains(vk::FormatFeatureFlags::BLIT_SRC)
+            && device.format_features(dst_format).contains(vk::FormatFeatureFlags::BLIT_DST)
+        {
+            self.convert_by_blit(dst, src, levels, layers, &src_info);
+            return Ok(ConversionMethod::Blit);
+        }
+
+        let plan = match srgb {
+            // Sample through the source's own format so the hardware decodes it.
+            _ if preserves => ComputePlan {
+                read_format: src_format,
+                write_format: linear_format(dst_format),
+                mode: if dst_srgb { ShaderMode::Encode } else { ShaderMode::Passthrough },
+            },
+            SrgbConversion::Encode => raw_plan(src_format, dst_format, ShaderMode::Encode),
+            SrgbConversion::Decode => raw_plan(src_format, dst_format, ShaderMode::Decode),
+            _ => raw_plan(src_format, dst_format, ShaderMode::Passthrough),
+        };
+        self.convert_by_compute(dst, src, levels, layers, &src_info, &dst_info, plan)?;
+        Ok(ConversionMethod::Compute)
+    }
+
+    fn convert_by_copy(
+        &mut self,
+        dst: ImageHandle,
+        src: ImageHandle,
+        levels: u32,
+        layers: u32,
+        src_info: &ImageCreateInfo,
+    ) {
+        let aspect_mask = format_to_aspect_mask(src_info.format);
+        let range = vk::ImageSubresourceRange {
+            aspect_mask,
+            base_mip_level: 0,
+            level_count: levels,
+            base_array_layer: 0,
+            layer_count: layers,
+        };
+        self.transition_image_subresources(
+            src,
+            range,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_READ,
+        );
+        self.transition_image_subresources(
+            dst,
+            range,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+
+        let regions = (0..levels)
+            .map(|level| {
+                let subresource = vk::ImageSubresourceLayers {
+                    aspect_mask,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: layers,
+                };
+                vk::ImageCopy {
+                    src_subresource: subresource,
+                    src_offset: vk::Offset3D::default(),
+                    dst_subresource: subresource,
+                    dst_offset: vk::Offset3D::default(),
+                    extent: level_extent(src_info, level),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let device = self.device().clone();
+        let resources = device.resources();
+        let src = resources.get_image(src).unwrap();
+        let dst = resources.get_image(dst).unwrap();
+        unsafe {
+            device.device.cmd_copy_image(
+                self.raw(),
+                src.raw(),
+                src.layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                dst.raw(),
+                dst.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+                &regions,
+            )
+        };
+    }
+
+    fn convert_by_blit(
+        &mut self,
+        dst: ImageHandle,
+        src: ImageHandle,
+        levels: u32,
+        layers: u32,
+        src_info: &ImageCreateInfo,
+    ) {
+        let aspect_mask = format_to_aspect_mask(src_info.format);
+        let regions = (0..levels)
+            .map(|level| {
+                let subresource = vk::ImageSubresourceLayers {
+                    aspect_mask,
+                    mip_level: level,
+                    base_array_layer: 0,
+                    layer_count: layers,
+                };
+                let extent = level_extent(src_info, level);
+                let offsets = [
+                    vk::Offset3D::default(),
+                    vk::Offset3D {
+                        x: extent.width as i32,
+                        y: extent.height as i32,
+                        z: extent.depth as i32,
+                    },
+                ];
+                vk::ImageBlit {
+                    src_subresource: subresource,
+                    src_offsets: offsets,
+                    dst_subresource: subresource,
+                    dst_offsets: offsets,
+                }
+            })
+            .collect::<Vec<_>>();
+        self.blit_image(dst, src, &regions, vk::Filter::NEAREST);
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn convert_by_compute(
+        &mut self,
+        dst: ImageHandle,
+        src: ImageHandle,
+        levels: u32,
+        layers: u32,
+        src_info: &ImageCreateInfo,
+        dst_info: &ImageCreateInfo,
+        plan: ComputePlan,
+    ) -> Result<(), ConvertImageError> {
+        for info in &[src_info, dst_info] {
+            if info.image_type != vk::ImageType::TYPE_2D
+                || info.sample_count != vk::SampleCountFlags::TYPE_1
+            {
+                return Err(ConvertImageError::UnsupportedShape);
+            }
+        }
+        if !src_info.usage.contains(vk::ImageUsageFlags::SAMPLED)
+            || !dst_info.usage.contains(vk::ImageUsageFlags::STORAGE)
+        {
+            return Err(ConvertImageError::MissingUsage);
+        }
+        for &(info, format) in &[(src_info, plan.read_format), (dst_info, plan.write_format)] {
+            if format != info.format && !info.create_flags.contains(vk::ImageCreateFlags::MUTABLE_FORMAT) {
+                return Err(ConvertImageError::NeedsMutableFormat(format));
+            }
+        }
+
+        let device = self.device().clone();
+        let unsupported = ConvertImageError::UnsupportedFormats {
+            src: plan.read_format,
+            dst: plan.write_format,
+        };
+        let variant = match shaders::storage_format_variant(plan.write_format) {
+            Some(variant) => variant,
+            None => return Err(unsupported),
+        };
+        if !device.format_features(plan.read_format).contains(vk::FormatFeatureFlags::SAMPLED_IMAGE)
+            || !device.format_features(plan.write_format).contains(vk::FormatFeatureFlags::STORAGE_IMAGE)
+        {
+            return Err(unsupported);
+        }
+
+        let (pipeline, set_layout) = device.conversion_pipeline(plan.write_format, variant)?;
+
+        let resources = [
+            DispatchResource::SampledImage(src),
+            DispatchResource::StorageImage(dst, ShaderAccess::Write),
+        ];
+        for level in 0..levels {
+            let view_key = |format| ViewKey {
+                mips: level..level + 1,
+                layers: 0..layers,
+                format,
+                view_type: vk::ImageViewType::TYPE_2D_ARRAY,
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+            };
+            let (src_view, src_layout, dst_view) = {
+                let resources = device.resources();
+                let src = resources.get_image(src).ok_or(ConvertImageError::InvalidImage)?;
+                let dst = resources.get_image(dst).ok_or(ConvertImageError::InvalidImage)?;
+                (
+                    src.get_view(view_key(plan.read_format))?,
+                    src.layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+                    dst.get_view(view_key(plan.write_format))?,
+                )
+            };
+
+            let set = device.allocate_conversion_set(set_layout)?;
+            let image_infos = [
+                vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: src_view,
+                    image_layout: src_layout,
+                },
+                vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: dst_view,
+                    image_layout: vk::ImageLayout::GENERAL,
+                },
+            ];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&image_infos[..1])
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&image_infos[1..])
+                    .build(),
+            ];
+            unsafe { device.device.update_descriptor_sets(&writes, &[]) };
+
+            let extent = level_extent(src_info, level);
+            let params = [extent.width, extent.height, plan.mode as u32];
+            let push_constants = params
+                .iter()
+                .flat_map(|value| value.to_ne_bytes().to_vec())
+                .collect::<Vec<_>>();
+
+            self.dispatch_with(
+                &pipeline,
+                &[set],
+                &push_constants,
+                &resources,
+                [
+                    extent.width.div_ceil(CONVERT_GROUP_SIZE),
+                    extent.height.div_ceil(CONVERT_GROUP_SIZE),
+                    layers,
+                ],
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// The extent of mip `level` of an image.
+fn level_extent(info: &ImageCreateInfo, level: u32) -> vk::Extent3D {
+    vk::Extent3D {
+        width: (info.width as u32 >> level).max(1),
+        height: (info.height as u32 >> level).max(1),
+        depth: (info.depth as u32 >> level).max(1),
+    }
+}
@@ -0,0 +1,434 @@
+//! Drawing Dear ImGui user interfaces, through the `imgui` crate, into the current render pass.
+//!
+//! Like `DebugDraw`, the vertices and indices are written to per-frame blocks and drawn with a
+//! cached pipeline created from built-in shaders. Textures, including the font atlas, are bound
+//! through descriptor sets allocated when they're registered, which ImGui refers to by their
+//! `imgui::TextureId`.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use bytemuck::{Pod, Zeroable};
+
+use imgui::internal::RawWrapper;
+use imgui::{DrawCmd, DrawCmdParams, TextureId};
+
+use thiserror::Error;
+
+use std::mem;
+use std::sync::Arc;
+
+use crate::*;
+
+/// The number of textures, including the font atlas, which can be registered with an
+/// `ImguiRenderer`.
+pub const IMGUI_MAX_TEXTURES: u32 = 64;
+
+/// An error that could occur while creating or rendering with an `ImguiRenderer`.
+#[derive(Error, Debug)]
+pub enum ImguiRendererError {
+    /// Reflecting a built-in shader failed.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+    /// The font atlas could not be created or uploaded.
+    #[error("font atlas error: {0}")]
+    FontAtlas(#[from] ImageArrayError),
+    /// The vertex or index block could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// The vertices or indices did not fit in their block.
+    #[error("block allocation error: {0}")]
+    BlockAllocation(#[from] BlockAllocationError),
+    /// The display transform could not be pushed.
+    #[error("push constant error: {0}")]
+    PushConstant(#[from] PushConstantError),
+    /// `IMGUI_MAX_TEXTURES` textures are already registered.
+    #[error("at most {} textures can be registered", IMGUI_MAX_TEXTURES)]
+    TooManyTextures,
+    /// A draw command uses a texture which was not registered with `register_texture`.
+    #[error("texture {0} was not registered")]
+    UnknownTexture(usize),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A vertex of an ImGui draw list, laid out as `imgui::DrawVert`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Pod, Zeroable)]
+pub struct ImguiVertex {
+    /// The position in display coordinates.
+    pub position: [f32; 2],
+    /// The texture coordinates.
+    pub uv: [f32; 2],
+    /// The RGBA color, which is multiplied with the texture.
+    pub color: [u8; 4],
+}
+
+impl Vertex for ImguiVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout::new(mem::size_of::<ImguiVertex>() as u32)
+            .attribute(vk::Format::R32G32_SFLOAT, 0)
+            .attribute(vk::Format::R32G32_SFLOAT, mem::size_of::<[f32; 2]>() as u32)
+            .attribute(
+                vk::Format::R8G8B8A8_UNORM,
+                mem::size_of::<[f32; 4]>() as u32,
+            )
+    }
+}
+
+/// A renderer of ImGui draw data.
+///
+/// ImGui's colors are in sRGB, so they look as intended when drawn into a `UNORM` attachment
+/// rather than an `SRGB` one.
+pub struct ImguiRenderer {
+    device: Arc<Device>,
+    vertex_shader: Option<Shader>,
+    fragment_shader: Option<Shader>,
+    layout: Option<ShaderLayout>,
+    push_constants: Option<PushConstants<[[f32; 2]; 2]>>,
+    sampler: vk::Sampler,
+    descriptor_pool: vk::DescriptorPool,
+    font_atlas: Option<ImageHandle>,
+    textures: Vec<vk::DescriptorSet>,
+}
+
+impl ImguiRenderer {
+    /// Create a renderer for `context`, creating its built-in shaders and uploading the font
+    /// atlas, which is registered as the first texture and given to the atlas as its `tex_id`.
+    pub fn new(
+        device: &Arc<Device>,
+        context: &mut imgui::Context,
+    ) -> Result<Self, ImguiRendererError> {
+        let mut renderer = ImguiRenderer {
+            device: device.clone(),
+            vertex_shader: None,
+            fragment_shader: None,
+            layout: None,
+            push_constants: None,
+            sampler: vk::Sampler::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            font_atlas: None,
+            textures: Vec::new(),
+        };
+
+        // Anything created so far is cleaned up by Drop if a later step fails.
+        renderer.vertex_shader = Some(device.create_shader(shaders::IMGUI_VERT)?);
+        renderer.fragment_shader = Some(device.create_shader(shaders::IMGUI_FRAG)?);
+        let layout = device.create_shader_layout(&[
+            renderer.vertex_shader.as_ref().unwrap(),
+            renderer.fragment_shader.as_ref().unwrap(),
+        ])?;
+        renderer.push_constants = Some(PushConstants::new(&layout));
+        renderer.layout = Some(layout);
+
+        // The sampler is owned by the Device's cache.
+        renderer.sampler = device.request_sampler(&SamplerInfo {
+            address_modes: [vk::SamplerAddressMode::CLAMP_TO_EDGE; 3],
+            ..Default::default()
+        })?;
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: IMGUI_MAX_TEXTURES,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: IMGUI_MAX_TEXTURES,
+            },
+        ];
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(IMGUI_MAX_TEXTURES)
+            .pool_sizes(&pool_sizes);
+        renderer.descriptor_pool =
+            unsafe { device.device.create_descriptor_pool(&pool_info, None)? };
+
+        let font_atlas = {
+            let atlas = context.fonts();
+            let texture = atlas.build_rgba32_texture();
+            let payload = ImagePayload::Raw {
+                data: texture.data,
+                width: texture.width,
+                height: texture.height,
+                format: vk::Format::R8G8B8A8_UNORM,
+            };
+            device.create_image_array(
+                &[payload],
+                vk::ImageUsageFlags::empty(),
+                Some(Tag::Static("imgui font atlas")),
+            )?
+        };
+        renderer.font_atlas = Some(font_atlas);
+        let font_view = device
+            .resources()
+            .get_image(font_atlas)
+            .and_then(|image| image.view().map(|view| view.raw()))
+            .expect("imgui font atlas was destroyed during creation");
+        context.fonts().tex_id = renderer.register_texture(font_view)?;
+
+        Ok(renderer)
+    }
+
+    /// Register `view` as a texture ImGui can draw with, returning the `imgui::TextureId` to
+    /// draw it with, e.g. with `imgui::Image`.
+    ///
+    /// The view must be of a 2D color image which stays in `SHADER_READ_ONLY_OPTIMAL` whenever
+    /// it's drawn, and must outlive the renderer.
+    pub fn register_texture(
+        &mut self,
+        view: vk::ImageView,
+    ) -> Result<TextureId, ImguiRendererError> {
+        if self.textures.len() >= IMGUI_MAX_TEXTURES as usize {
+            return Err(ImguiRendererError::TooManyTextures);
+        }
+
+        let set_layouts = [self.layout.as_ref().unwrap().set_layouts()[0]];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&set_layouts);
+        let set = unsafe { self.device.device.allocate_descriptor_sets(&alloc_info)?[0] };
+
+        let image_info = [vk::DescriptorImageInfo {
+            sampler: self.sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&image_info)
+                .build(),
+        ];
+        unsafe { self.device.device.update_descriptor_sets(&writes, &[]) };
+
+        self.textures.push(set);
+        Ok(TextureId::new(self.textures.len() - 1))
+    }
+
+    /// Draw `draw_data` into `target`, whose depth testing is ignored.
+    ///
+    /// `cmd` must be inside the render pass or rendering described by `target`, with the
+    /// viewport covering the display set, e.g. with `CommandBuffer::set_viewport_for`. The
+    /// scissor is left set to the clip rectangle of the last draw. The vertices and indices are
+    /// written to blocks of the current frame, so they're only valid for this frame.
+    pub fn render(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        target: &DebugDrawTarget,
+        draw_data: &imgui::DrawData,
+    ) -> Result<(), ImguiRendererError> {
+        let framebuffer_size = [
+            draw_data.display_size[0] * draw_data.framebuffer_scale[0],
+            draw_data.display_size[1] * draw_data.framebuffer_scale[1],
+        ];
+        if framebuffer_size[0] <= 0.0
+            || framebuffer_size[1] <= 0.0
+            || draw_data.total_idx_count <= 0
+        {
+            return Ok(());
+        }
+
+        let mut vertices = Vec::with_capacity(draw_data.total_vtx_count as usize);
+        let mut indices = Vec::with_capacity(draw_data.total_idx_count as usize);
+        for draw_list in draw_data.draw_lists() {
+            vertices.extend_from_slice(unsafe { draw_list.transmute_vtx_buffer::<ImguiVertex>() });
+            indices.extend_from_slice(draw_list.idx_buffer());
+        }
+
+        let vertex_block = self.device.request_internal_block(
+            PoolKind::Vertex,
+            mem::size_of_val(vertices.as_slice()),
+            Some(Tag::Static("imgui vertices")),
+        )?;
+        let index_block = self.device.request_internal_block(
+            PoolKind::Index,
+            mem::size_of_val(indices.as_slice()),
+            Some(Tag::Static("imgui indices")),
+        )?;
+        let (vertex_buffer, index_allocation) = {
+            let blocks = self.device.buffer_blocks();
+            let vertex_buffer = blocks
+                .get_vertex_block(vertex_block)
+                .expect("imgui vertex block was recycled")
+                .allocate_vertices(&vertices)?;
+            let index_allocation = blocks
+                .get_index_block(index_block)
+                .expect("imgui index block was recycled")
+                .allocate_indices(&indices)?;
+            (vertex_buffer, index_allocation)
+        };
+
+        let pipeline = self
+            .device
+            .request_graphics_pipeline(&self.pipeline_info(target))?;
+        let layout = self.layout.as_ref().unwrap().raw();
+        let scale = [
+            2.0 / draw_data.display_size[0],
+            2.0 / draw_data.display_size[1],
+        ];
+        let transform = [
+            scale,
+            [
+                -1.0 - draw_data.display_pos[0] * scale[0],
+                -1.0 - draw_data.display_pos[1] * scale[1],
+            ],
+        ];
+        let bind_state = |cmd: &mut CommandBuffer| -> Result<(), ImguiRendererError> {
+            unsafe {
+                self.device.device.cmd_bind_pipeline(
+                    cmd.raw(),
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pipeline,
+                )
+            };
+            cmd.bind_vertex_block(0, vertex_buffer);
+            cmd.bind_index_block(index_allocation);
+            cmd.push_constants(self.push_constants.as_ref().unwrap(), &transform)?;
+            Ok(())
+        };
+        bind_state(cmd)?;
+
+        let clip_offset = draw_data.display_pos;
+        let clip_scale = draw_data.framebuffer_scale;
+        let mut bound_set = None;
+        let (mut vertex_base, mut index_base) = (0, 0);
+        for draw_list in draw_data.draw_lists() {
+            for command in draw_list.commands() {
+                match command {
+                    DrawCmd::Elements {
+                        count,
+                        cmd_params:
+                            DrawCmdParams {
+                                clip_rect,
+                                texture_id,
+                                vtx_offset,
+                                idx_offset,
+                            },
+                    } => {
+                        // The clip rectangle, in framebuffer coordinates and within it.
+                        let min = [
+                            ((clip_rect[0] - clip_offset[0]) * clip_scale[0]).max(0.0),
+                            ((clip_rect[1] - clip_offset[1]) * clip_scale[1]).max(0.0),
+                        ];
+                        let max = [
+                            ((clip_rect[2] - clip_offset[0]) * clip_scale[0])
+                                .min(framebuffer_size[0]),
+                            ((clip_rect[3] - clip_offset[1]) * clip_scale[1])
+                                .min(framebuffer_size[1]),
+                        ];
+                        if max[0] <= min[0] || max[1] <= min[1] {
+                            continue;
+                        }
+
+                        let set = *self
+                            .textures
+                            .get(texture_id.id())
+                            .ok_or_else(|| ImguiRendererError::UnknownTexture(texture_id.id()))?;
+                        let scissor = vk::Rect2D {
+                            offset: vk::Offset2D {
+                                x: min[0] as i32,
+                                y: min[1] as i32,
+                            },
+                            extent: vk::Extent2D {
+                                width: (max[0] - min[0]) as u32,
+                                height: (max[1] - min[1]) as u32,
+                            },
+                        };
+                        unsafe {
+                            let device = &self.device.device;
+                            if bound_set != Some(set) {
+                                device.cmd_bind_descriptor_sets(
+                                    cmd.raw(),
+                                    vk::PipelineBindPoint::GRAPHICS,
+                                    layout,
+                                    0,
+                                    &[set],
+                                    &[],
+                                );
+                                bound_set = Some(set);
+                            }
+                            device.cmd_set_scissor(cmd.raw(), 0, &[scissor]);
+                            device.cmd_draw_indexed(
+                                cmd.raw(),
+                                count as u32,
+                                1,
+                                (index_base + idx_offset) as u32,
+                                (vertex_base + vtx_offset) as i32,
+                                0,
+                            );
+                        }
+                    }
+                    DrawCmd::ResetRenderState => {
+                        bind_state(cmd)?;
+                        bound_set = None;
+                    }
+                    DrawCmd::RawCallback { callback, raw_cmd } => unsafe {
+                        callback(draw_list.raw(), raw_cmd)
+                    },
+                }
+            }
+            vertex_base += draw_list.vtx_buffer().len();
+            index_base += draw_list.idx_buffer().len();
+        }
+
+        Ok(())
+    }
+
+    fn pipeline_info(&self, target: &DebugDrawTarget) -> GraphicsPipelineCreateInfo {
+        GraphicsPipelineCreateInfo {
+            vertex_shader: self.vertex_shader.as_ref().unwrap().raw(),
+            fragment_shader: Some(self.fragment_shader.as_ref().unwrap().raw()),
+            layout: self.layout.as_ref().unwrap().raw(),
+            render_pass: target.render_pass,
+            subpass: target.subpass,
+            vertex_bindings: vec![ImguiVertex::layout().binding(0)],
+            vertex_attributes: ImguiVertex::layout().vertex_attributes(0, 0),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            cull_mode: vk::CullModeFlags::NONE,
+            samples: target.samples,
+            depth_test: false,
+            depth_write: false,
+            blend_states: vec![BlendState::AlphaBlend],
+            color_formats: target.color_format.into_iter().collect(),
+            depth_stencil_format: target.depth_stencil_format,
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for ImguiRenderer {
+    fn drop(&mut self) {
+        unsafe {
+            // The pipeline and descriptor sets may still be in use by frames in flight.
+            // Destroying the shaders also destroys the cached pipeline created from them.
+            let _ = self.device.device_wait_idle();
+
+            if self.descriptor_pool != vk::DescriptorPool::null() {
+                self.device
+                    .device
+                    .destroy_descriptor_pool(self.descriptor_pool, None);
+            }
+            if let Some(font_atlas) = self.font_atlas.take() {
+                self.device.destroy_image(font_atlas);
+            }
+            if let Some(layout) = self.layout.take() {
+                self.device.destroy_shader_layout(layout);
+            }
+            for shader in vec![self.vertex_shader.take(), self.fragment_shader.take()]
+                .into_iter()
+                .flatten()
+            {
+                self.device.destroy_shader(shader);
+            }
+        }
+    }
+}
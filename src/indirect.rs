@@ -0,0 +1,194 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use bytemuck::{Pod, Zeroable};
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while allocating indirect commands.
+#[derive(Error, Debug)]
+pub enum IndirectError {
+    /// The indirect block could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// The commands did not fit in the indirect block.
+    #[error("block allocation error: {0}")]
+    BlockAllocation(#[from] BlockAllocationError),
+}
+
+/// The group counts of an indirect dispatch, laid out as a `VkDispatchIndirectCommand`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash, Pod, Zeroable)]
+pub struct DispatchIndirectCommand {
+    /// The number of work groups in the X dimension.
+    pub x: u32,
+    /// The number of work groups in the Y dimension.
+    pub y: u32,
+    /// The number of work groups in the Z dimension.
+    pub z: u32,
+}
+
+/// Consecutive `DispatchIndirectCommand`s in an indirect block of the current frame, made by a
+/// `DispatchIndirectBuilder` and dispatched with `CommandBuffer::dispatch_indirect`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct DispatchIndirectSlice {
+    buffer: TransientBufferHandle,
+    count: u32,
+    /// Whether the commands are written by a compute pass rather than the host, so that
+    /// dispatching them must wait for that pass's writes.
+    gpu_written: bool,
+}
+
+impl DispatchIndirectSlice {
+    /// The range of the indirect block holding the commands.
+    pub fn buffer(&self) -> TransientBufferHandle {
+        self.buffer
+    }
+
+    /// The number of commands.
+    pub fn len(&self) -> u32 {
+        self.count
+    }
+
+    /// Whether there are no commands.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Get the info to write a `STORAGE_BUFFER` descriptor for the commands with, so that a
+    /// compute pass can write them.
+    pub fn storage_descriptor_info(&self) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo {
+            buffer: self.buffer.raw(),
+            offset: self.buffer.offset(),
+            range: self.buffer.size(),
+        }
+    }
+}
+
+/// Writes `DispatchIndirectCommand`s into an indirect block of the current frame, for compute
+/// work whose size is only known to the GPU, or which is decided once and dispatched by many
+/// command buffers.
+///
+/// Commands pushed from the host are written by `build`. Alternatively, `reserve` makes room
+/// for commands which a prior compute pass writes through `storage_descriptor_info`, in which
+/// case `CommandBuffer::dispatch_indirect` records a barrier to wait for those writes.
+pub struct DispatchIndirectBuilder<'a> {
+    device: &'a Arc<Device>,
+    frame: &'a Frame,
+    commands: Vec<DispatchIndirectCommand>,
+    tag: Option<Tag>,
+}
+
+impl<'a> DispatchIndirectBuilder<'a> {
+    /// Start building commands for `frame`.
+    pub fn new(device: &'a Arc<Device>, frame: &'a Frame) -> Self {
+        Self {
+            device,
+            frame,
+            commands: Vec::new(),
+            tag: None,
+        }
+    }
+
+    /// Tag the indirect block the commands are written to.
+    pub fn tag(mut self, tag: Tag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Add a dispatch of `group_counts` work groups.
+    pub fn push(mut self, group_counts: [u32; 3]) -> Self {
+        self.commands.push(DispatchIndirectCommand {
+            x: group_counts[0],
+            y: group_counts[1],
+            z: group_counts[2],
+        });
+        self
+    }
+
+    /// Write the pushed commands to an indirect block.
+    pub fn build(self) -> Result<DispatchIndirectSlice, IndirectError> {
+        let buffer = self.allocate(self.commands.len())?;
+        let blocks = self.device.buffer_blocks();
+        let ptr = blocks
+            .get_indirect_block(buffer.block())
+            .and_then(|block| block.mapped_data(&buffer))
+            .expect("DispatchIndirectBuilder: indirect block is not mapped")
+            .as_ptr() as *mut DispatchIndirectCommand;
+        unsafe { ptr.copy_from_nonoverlapping(self.commands.as_ptr(), self.commands.len()) };
+
+        Ok(DispatchIndirectSlice {
+            buffer,
+            count: self.commands.len() as u32,
+            gpu_written: false,
+        })
+    }
+
+    /// Make room for `count` commands in an indirect block, to be written by a compute pass
+    /// recorded before they are dispatched, instead of writing the pushed commands.
+    pub fn reserve(self, count: usize) -> Result<DispatchIndirectSlice, IndirectError> {
+        let buffer = self.allocate(count)?;
+        Ok(DispatchIndirectSlice {
+            buffer,
+            count: count as u32,
+            gpu_written: true,
+        })
+    }
+
+    fn allocate(&self, count: usize) -> Result<TransientBufferHandle, IndirectError> {
+        let size = count * std::mem::size_of::<DispatchIndirectCommand>();
+        let block = self.device.request_indirect_block(self.frame, size, self.tag.clone())?;
+        let blocks = self.device.buffer_blocks();
+        let block = blocks
+            .get_indirect_block(block)
+            .expect("DispatchIndirectBuilder: indirect block was recycled");
+        Ok(block.allocate_buffer(size)?)
+    }
+}
+
+impl CommandBuffer {
+    /// Dispatch every command of `commands` in order with the currently bound compute pipeline.
+    ///
+    /// If the commands are written by a compute pass (see `DispatchIndirectBuilder::reserve`),
+    /// a barrier is recorded first so that the dispatches wait for its writes.
+    pub fn dispatch_indirect(&mut self, commands: DispatchIndirectSlice) {
+        assert!(!self.in_render_pass, "dispatch_indirect is not allowed inside a render pass");
+        let buffer = commands.buffer;
+
+        if commands.gpu_written {
+            let barrier = vk::BufferMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .buffer(buffer.raw())
+                .offset(buffer.offset())
+                .size(buffer.size())
+                .build();
+            unsafe {
+                self.device().cmd_pipeline_barrier(
+                    self.raw(),
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::DRAW_INDIRECT,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[barrier],
+                    &[],
+                )
+            };
+        }
+
+        let stride = std::mem::size_of::<DispatchIndirectCommand>() as vk::DeviceSize;
+        for index in 0..commands.count as vk::DeviceSize {
+            unsafe {
+                self.device()
+                    .cmd_dispatch_indirect(self.raw(), buffer.raw(), buffer.offset() + index * stride)
+            };
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::sync::Arc;
+
+use crate::Tag;
+
+/// A description of an internal invariant of `hot` that was found to be violated, but which
+/// `hot` is able to recover from, for example a resource which errored on destruction.
+#[derive(Debug, Clone)]
+pub struct InvariantFailure {
+    /// The tag of the resource involved in the failure, if it had one.
+    pub tag: Option<Tag>,
+    /// A human readable description of what went wrong.
+    pub message: String,
+}
+
+impl InvariantFailure {
+    /// Create a new InvariantFailure.
+    pub fn new<S: Into<String>>(tag: Option<Tag>, message: S) -> Self {
+        Self {
+            tag,
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for InvariantFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.tag {
+            Some(ref tag) => write!(f, "[{}] {}", tag, self.message),
+            None => write!(f, "[untagged] {}", self.message),
+        }
+    }
+}
+
+/// What `hot` should do when it detects a recoverable `InvariantFailure`.
+///
+/// The default is `Panic`, which is what you want during development. Shipping builds
+/// may prefer to log the failure or forward it to their own crash reporting and keep running.
+#[derive(Clone, Default)]
+pub enum InvariantPolicy {
+    /// Panic with the failure's message.
+    #[default]
+    Panic,
+    /// Log the failure at error level through the `log` crate and continue.
+    LogAndContinue,
+    /// Call the provided function with the failure and continue.
+    Callback(Arc<dyn Fn(&InvariantFailure) + Send + Sync>),
+}
+
+impl InvariantPolicy {
+    /// Handle a failure according to this policy.
+    pub fn handle(&self, failure: InvariantFailure) {
+        match self {
+            InvariantPolicy::Panic => panic!("hot invariant failure: {}", failure),
+            InvariantPolicy::LogAndContinue => log::error!("hot invariant failure: {}", failure),
+            InvariantPolicy::Callback(callback) => callback(&failure),
+        }
+    }
+}
+
+impl std::fmt::Debug for InvariantPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InvariantPolicy::Panic => write!(f, "InvariantPolicy::Panic"),
+            InvariantPolicy::LogAndContinue => write!(f, "InvariantPolicy::LogAndContinue"),
+            InvariantPolicy::Callback(_) => write!(f, "InvariantPolicy::Callback(..)"),
+        }
+    }
+}
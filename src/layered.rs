@@ -0,0 +1,126 @@
+//! Rendering into several layers or viewports with a single draw, e.g. the six faces of a cube
+//! map or every cascade of a shadow map, by having shaders write `gl_Layer` or
+//! `gl_ViewportIndex`.
+//!
+//! With `VK_EXT_shader_viewport_index_layer` both can be written from the vertex shader, which
+//! typically selects the layer or viewport from the instance index. Otherwise they can only be
+//! written from a geometry shader, given by `GraphicsPipelineCreateInfo::geometry_shader`.
+//! `Device::layer_output` tells which is available.
+//!
+//! Layers are rendered to by rendering into a view of several layers, as `begin_rendering` and
+//! `RenderTargetScope` do for a view with more than one layer. Viewports are selected among
+//! those of a pipeline with `GraphicsPipelineCreateInfo::viewport_count` greater than one, set
+//! with `CommandBuffer::set_viewports` or `set_viewport_grid_for`.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::*;
+
+/// The shader stage which can write the layer and viewport index.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum LayerOutput {
+    /// The vertex shader, through `VK_EXT_shader_viewport_index_layer`. Geometry shaders can too
+    /// if they are supported.
+    Vertex,
+    /// Only a geometry shader.
+    Geometry,
+}
+
+impl Device {
+    /// Get the earliest shader stage which can write the layer and viewport index, or `None`
+    /// if the device supports neither `VK_EXT_shader_viewport_index_layer` nor geometry shaders.
+    ///
+    /// Writing the viewport index also needs `max_viewports` to be above one.
+    pub fn layer_output(&self) -> Option<LayerOutput> {
+        if self.shader_viewport_index_layer {
+            Some(LayerOutput::Vertex)
+        } else if self.enabled_features.geometry_shader == vk::TRUE {
+            Some(LayerOutput::Geometry)
+        } else {
+            None
+        }
+    }
+
+    /// Get the greatest `GraphicsPipelineCreateInfo::viewport_count`, which is one unless the
+    /// device supports multiple viewports.
+    pub fn max_viewports(&self) -> u32 {
+        if self.enabled_features.multi_viewport == vk::TRUE {
+            self.device_properties.limits.max_viewports
+        } else {
+            1
+        }
+    }
+}
+
+impl CommandBuffer {
+    /// Set the viewports starting at index `first`, along with scissors covering each of them.
+    pub fn set_viewports(&mut self, first: u32, viewports: &[vk::Viewport]) {
+        let scissors = viewports
+            .iter()
+            .map(|viewport| {
+                // A flipped viewport has a negative height, extending up from its y.
+                let top = viewport.y.min(viewport.y + viewport.height);
+                vk::Rect2D {
+                    offset: vk::Offset2D {
+                        x: viewport.x.max(0.0) as i32,
+                        y: top.max(0.0) as i32,
+                    },
+                    extent: vk::Extent2D {
+                        width: viewport.width as u32,
+                        height: viewport.height.abs() as u32,
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            self.device().cmd_set_viewport(self.raw(), first, viewports);
+            self.device().cmd_set_scissor(self.raw(), first, &scissors);
+        }
+        self.inherited.set_viewports(first, viewports, &scissors);
+    }
+
+    /// Split `target` into a grid of `columns` by `rows` cells, and set one viewport and scissor
+    /// to cover each cell. The viewport index of a cell is `row * columns + column`, counting
+    /// from the top left, so that e.g. four shadow cascades can be rendered into the quarters of
+    /// an atlas with one draw.
+    ///
+    /// `flip_y` flips each viewport vertically as in `set_viewport_for`, which is otherwise the
+    /// same as a one by one grid. Pipelines used with the grid need a `viewport_count` of at
+    /// least `columns * rows`.
+    pub fn set_viewport_grid_for<T: Into<ViewportTarget>>(
+        &mut self,
+        target: T,
+        columns: u32,
+        rows: u32,
+        flip_y: bool,
+    ) -> vk::SurfaceTransformFlagsKHR {
+        assert!(columns > 0 && rows > 0, "set_viewport_grid_for: empty grid");
+        let (extent, transform) = self.target_extent(target.into());
+
+        let (width, height) = (extent.width / columns, extent.height / rows);
+        let mut viewports = Vec::with_capacity((columns * rows) as usize);
+        for row in 0..rows {
+            for column in 0..columns {
+                let (x, y) = ((column * width) as f32, (row * height) as f32);
+                let (y, height) = if flip_y {
+                    (y + height as f32, -(height as f32))
+                } else {
+                    (y, height as f32)
+                };
+                viewports.push(vk::Viewport {
+                    x,
+                    y,
+                    width: width as f32,
+                    height,
+                    min_depth: 0.0,
+                    max_depth: 1.0,
+                });
+            }
+        }
+        self.set_viewports(0, &viewports);
+
+        transform
+    }
+}
@@ -3,38 +3,309 @@
 #![deny(missing_docs)]
 
 pub use ash;
+pub use bytemuck;
 
 /// CommandPool abstraction.
 pub mod command_pool;
 pub use command_pool::*;
 
+/// CommandBuffer recording.
+pub mod command_buffer;
+pub use command_buffer::*;
+
+/// Recording secondary command buffers on worker threads within a render pass.
+pub mod secondary;
+pub use secondary::*;
+
+/// Device memory allocation, through a pluggable allocator backend.
+pub mod allocator;
+pub use allocator::*;
+
 /// Buffers and BufferViews.
 pub mod buffer;
 pub use buffer::*;
 
+/// Renaming buffers with fresh memory while their old contents are still in flight.
+pub mod orphan;
+pub use orphan::*;
+
+/// Device addresses of buffers through `VK_KHR_buffer_device_address`.
+pub mod device_address;
+
+/// Buffers backed by imported host memory.
+pub mod host_import;
+pub use host_import::*;
+
+/// Streaming of memory-mapped asset files into GPU resources.
+pub mod file_streamer;
+pub use file_streamer::*;
+
+/// Uploads spread over several frames by a per-frame byte budget.
+pub mod upload_queue;
+pub use upload_queue::*;
+
 /// A group of Buffers.
 pub mod buffer_block;
 pub use buffer_block::*;
 
+/// Per-draw uniforms suballocated from uniform blocks and bound with dynamic offsets.
+pub mod uniform_ring;
+pub use uniform_ring::*;
+
+/// Indirect dispatch commands written to indirect blocks by the host or by compute passes.
+pub mod indirect;
+pub use indirect::*;
+
 /// Images and ImageViews.
 pub mod image;
 pub use image::*;
 
+/// Loading several images into the layers of one array image.
+pub mod image_array;
+pub use image_array::*;
+
+/// Loading textures with all of their levels and layers from KTX2 and DDS files.
+#[cfg(feature = "texture_io")]
+pub mod texture_io;
+#[cfg(feature = "texture_io")]
+pub use texture_io::*;
+
+/// Ray tracing acceleration structures, through `VK_KHR_acceleration_structure`.
+#[cfg(feature = "raytracing")]
+pub mod raytracing;
+#[cfg(feature = "raytracing")]
+pub use raytracing::*;
+
+/// Video decode and encode queues, through `VK_KHR_video_queue`.
+#[cfg(feature = "video")]
+pub mod video;
+#[cfg(feature = "video")]
+pub use video::*;
+
+/// Sparse images, whose pages are bound to memory individually.
+pub mod sparse_image;
+pub use sparse_image::*;
+
+/// Images which share one allocation, for intermediates which are never used at once.
+pub mod aliasing;
+pub use aliasing::*;
+
+/// Reading buffers and images back to the host.
+pub mod readback;
+pub use readback::*;
+
+/// Buffers and images mirrored from one Device to another through host memory.
+pub mod mirror;
+pub use mirror::*;
+
+/// Screenshots of presented frames, encoded and saved in the background.
+pub mod screenshot;
+pub use screenshot::*;
+
+/// Per-frame pools of scratch storage images.
+pub mod scratch;
+pub use scratch::*;
+
+/// Lifetime scopes for transient resources outside of the render graph.
+pub mod scope;
+pub use scope::*;
+
+/// Pipeline barrier and image layout transition tracking.
+pub mod barrier;
+pub use barrier::*;
+
+/// Pipeline barriers with their own stages through Vulkan 1.3 or `VK_KHR_synchronization2`.
+pub mod synchronization2;
+
+/// Cached render passes and the framebuffers used with them.
+pub mod render_pass;
+pub use render_pass::*;
+
+/// Rendering without render pass objects through Vulkan 1.3 or `VK_KHR_dynamic_rendering`.
+pub mod dynamic_rendering;
+pub use dynamic_rendering::*;
+
+/// Scopes which render into an image view and leave it ready to be sampled.
+pub mod render_to;
+pub use render_to::*;
+
+/// Offscreen render targets, for rendering without a swapchain.
+pub mod offscreen;
+pub use offscreen::*;
+
+/// Selecting the layer or viewport rendered to from shaders.
+pub mod layered;
+pub use layered::*;
+
+/// Render graphs, which schedule passes and their barriers and alias transient attachments.
+pub mod render_graph;
+pub use render_graph::*;
+
+/// Built-in compute passes which downsample and upsample an image's mip chain.
+pub mod mip_chain;
+pub use mip_chain::*;
+
+/// Conversions between images of different formats, with explicit handling of sRGB encoding.
+pub mod image_convert;
+pub use image_convert::*;
+
+/// A hierarchical depth pyramid builder and reference occlusion culling pass.
+pub mod hiz;
+pub use hiz::*;
+
+/// Packing the shadow maps of many lights into one depth atlas.
+pub mod shadow_atlas;
+pub use shadow_atlas::*;
+
+/// Dynamic resolution scaling, upscaling an internal render target to the swapchain.
+pub mod render_scale;
+pub use render_scale::*;
+
+/// Immediate-mode debug lines and shapes, drawn with hot's own pipelines.
+pub mod debug_draw;
+pub use debug_draw::*;
+
+/// Drawing Dear ImGui user interfaces with hot's own pipelines.
+#[cfg(feature = "imgui")]
+pub mod imgui_renderer;
+#[cfg(feature = "imgui")]
+pub use imgui_renderer::*;
+
 /// Resource management.
 pub mod resource;
 pub use resource::*;
 
+/// Shader dependency tracking for hot reloading.
+pub mod hot_reload;
+pub use hot_reload::*;
+
+/// Batched descriptor set writes.
+pub mod descriptor;
+
+/// Shader modules and reflection of their interfaces.
+pub mod shader;
+pub use shader::*;
+
+/// Compiling GLSL and WGSL shaders to SPIR-V at runtime.
+#[cfg(feature = "shader-compiler")]
+pub mod shader_compiler;
+#[cfg(feature = "shader-compiler")]
+pub use shader_compiler::*;
+
+/// Graphics pipeline state and caching.
+pub mod pipeline;
+pub use pipeline::*;
+
+/// Typed vertex buffer layouts shared by vertex blocks and graphics pipelines.
+pub mod vertex;
+pub use vertex::*;
+/// Derives `Vertex` for a `#[repr(C)]` struct with named fields.
+#[cfg(feature = "derive")]
+pub use hot_derive::Vertex;
+
+/// Cached samplers, with custom border colors through `VK_EXT_custom_border_color`.
+pub mod sampler;
+pub use sampler::*;
+
+/// Typed push constants, checked against the ranges of a pipeline layout.
+pub mod push_constants;
+pub use push_constants::*;
+
+/// Persistence of the Vulkan pipeline cache.
+pub mod pipeline_cache;
+pub use pipeline_cache::*;
+
+/// Statistics and introspection of the pipeline, render pass, sampler and descriptor layout
+/// caches.
+pub mod cache_stats;
+pub use cache_stats::*;
+
 /// Utilities for working with Vulkan Formats.
 pub mod format;
 
+/// Swapchain management and presentation.
+pub mod swapchain;
+pub use swapchain::*;
+
+/// Creating surfaces for windows through `raw-window-handle`.
+#[cfg(feature = "raw-window-handle")]
+pub mod surface;
+#[cfg(feature = "raw-window-handle")]
+pub use surface::*;
+
+/// Batched queue submission with semaphore chaining.
+pub mod submit;
+pub use submit::*;
+
+/// Pooled fences and semaphores.
+pub mod sync;
+pub use sync::*;
+
+/// Debug names and labels through `VK_EXT_debug_utils`.
+pub mod debug_utils;
+
+/// Events describing the resource accesses of each submission, for external tools.
+pub mod resource_events;
+pub use resource_events::*;
+
+/// Timeline semaphores.
+pub mod timeline;
+pub use timeline::*;
+
+/// Support for portability implementations such as MoltenVK through `VK_KHR_portability_subset`.
+pub mod portability;
+pub use portability::*;
+
+/// Memory usage statistics and warnings about nearing the memory budget.
+pub mod memory_stats;
+pub use memory_stats::*;
+
+/// Device-side assertions, which shaders report failures of through a reserved descriptor set.
+pub mod gpu_assert;
+pub use gpu_assert::*;
+
+/// GPU timings of zones of command buffers, through timestamp queries.
+pub mod profiling;
+pub use profiling::*;
+
+/// The frame lifecycle and per-frame resource recycling.
+pub mod frame;
+pub use frame::*;
+
+/// Scaffolding shared by the examples, such as offscreen render targets to use in place of a
+/// window.
+#[cfg(feature = "examples_support")]
+pub mod examples_support;
+
+/// Diagnostic self-test of a Device.
+pub mod self_test;
+pub use self_test::*;
+
 /// A Device wrapper, the central type which creates, owns, and manages other resources.
 pub mod device;
 pub use device::*;
 
+/// Coarse capability tiers of a Device, for scaling content by tier.
+pub mod tier;
+pub use tier::*;
+
 #[allow(unused_macros)]
 #[allow(unused_imports)]
 mod util;
 
-/// A type that panics on Drop and requires manual destruction.
+/// Policies for handling recoverable internal invariant failures.
+pub mod invariant;
+pub use invariant::*;
+
+/// A deterministic mode which makes handle assignment reproducible across runs.
+pub mod deterministic;
+
+/// Deferred destruction of resources which are dropped rather than destroyed.
+mod destruction;
+
+/// The SPIR-V of hot's built-in shaders.
+mod shaders;
+
+/// A type that must be destroyed manually rather than dropped.
 pub mod nodrop;
 pub use nodrop::*;
\ No newline at end of file
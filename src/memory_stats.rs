@@ -0,0 +1,445 @@
+use ash::version::InstanceV1_1;
+use ash::vk;
+
+use std::sync::Arc;
+
+use crate::*;
+use crate::deterministic::ReplayHashMap;
+
+/// Bytes allocated for each category of resource the Device owns.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MemoryCategoryUsage {
+    /// Bytes allocated for images created through the Device.
+    pub images: vk::DeviceSize,
+    /// Bytes allocated for buffers created through the Device, other than those of blocks.
+    pub buffers: vk::DeviceSize,
+    /// Bytes allocated for the buffers of the `BufferBlockSet`'s blocks, both in use and
+    /// recycled.
+    pub buffer_blocks: vk::DeviceSize,
+}
+
+impl MemoryCategoryUsage {
+    /// The total of every category.
+    pub fn total(&self) -> vk::DeviceSize {
+        self.images + self.buffers + self.buffer_blocks
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.images += other.images;
+        self.buffers += other.buffers;
+        self.buffer_blocks += other.buffer_blocks;
+    }
+}
+
+/// The memory usage of one memory heap.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct HeapStats {
+    /// The flags of the heap.
+    pub flags: vk::MemoryHeapFlags,
+    /// The size of the heap.
+    pub size: vk::DeviceSize,
+    /// How many bytes of the heap the process can use before allocations may fail or
+    /// performance suffers. Reported by the driver with `VK_EXT_memory_budget`, otherwise
+    /// estimated as 80% of the heap's size.
+    pub budget: vk::DeviceSize,
+    /// How many bytes of the heap the process uses. Reported by the driver with
+    /// `VK_EXT_memory_budget`, so it includes memory not allocated through the Device,
+    /// otherwise the size of the allocator's memory blocks.
+    pub usage: vk::DeviceSize,
+    /// The bytes of memory blocks the allocator has allocated from the heap.
+    pub block_bytes: vk::DeviceSize,
+    /// The bytes of the allocator's memory blocks used by allocations.
+    pub allocated_bytes: vk::DeviceSize,
+    /// The allocations made from the heap, by category.
+    pub categories: MemoryCategoryUsage,
+}
+
+impl HeapStats {
+    /// The fraction of the budget in use, which may be above 1.
+    pub fn usage_fraction(&self) -> f32 {
+        if self.budget == 0 {
+            0.0
+        } else {
+            self.usage as f32 / self.budget as f32
+        }
+    }
+}
+
+/// The memory usage of the Device, returned by `Device::memory_stats`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct MemoryStats {
+    /// The stats of each memory heap, indexed like the `memory_heaps` of
+    /// `Device::memory_properties`.
+    pub heaps: Vec<HeapStats>,
+    /// Whether `budget` and `usage` were reported by `VK_EXT_memory_budget` rather than
+    /// estimated.
+    pub has_budget: bool,
+}
+
+impl MemoryStats {
+    /// The allocations made from every heap, by category.
+    pub fn categories(&self) -> MemoryCategoryUsage {
+        let mut total = MemoryCategoryUsage::default();
+        for heap in &self.heaps {
+            total.add(&heap.categories);
+        }
+        total
+    }
+
+    /// The highest fraction of its budget any heap uses.
+    pub fn highest_usage_fraction(&self) -> f32 {
+        self.heaps.iter().map(HeapStats::usage_fraction).fold(0.0, f32::max)
+    }
+}
+
+/// The kind of resource an allocation was made for.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum MemoryCategory {
+    /// Images created through the Device.
+    Images,
+    /// Buffers created through the Device, other than those of blocks.
+    Buffers,
+    /// The buffers of the `BufferBlockSet`'s blocks.
+    BufferBlocks,
+}
+
+/// How the allocations of one category and tag changed over the last frame, reported by a
+/// `MemoryWatermarkAlert`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MemoryPoolGrowth {
+    /// The kind of resource the allocations were made for.
+    pub category: MemoryCategory,
+    /// The tag the resources were created with, if any.
+    pub tag: Option<String>,
+    /// The bytes currently allocated.
+    pub bytes: vk::DeviceSize,
+    /// How many bytes were allocated since the previous frame, net of those freed.
+    pub growth: i64,
+}
+
+/// A fraction of the memory budget to be alerted about reaching, registered with
+/// `Device::add_memory_watermark`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MemoryWatermark {
+    /// The fraction of its budget a heap must use to reach the watermark, e.g. 0.8.
+    pub threshold: f32,
+    /// Only heaps with all of these flags are checked, so `DEVICE_LOCAL` watches video memory.
+    /// Every heap is checked if empty.
+    pub heap_flags: vk::MemoryHeapFlags,
+}
+
+impl MemoryWatermark {
+    /// A watermark at `threshold` of the budget of the device-local heaps.
+    pub fn device_local(threshold: f32) -> Self {
+        Self {
+            threshold,
+            heap_flags: vk::MemoryHeapFlags::DEVICE_LOCAL,
+        }
+    }
+
+    /// A watermark at `threshold` of the budget of any heap.
+    pub fn any_heap(threshold: f32) -> Self {
+        Self {
+            threshold,
+            heap_flags: vk::MemoryHeapFlags::empty(),
+        }
+    }
+
+    /// The index and usage fraction of the fullest heap of `stats` this watermark checks.
+    fn fullest_heap(&self, stats: &MemoryStats) -> Option<(usize, f32)> {
+        stats
+            .heaps
+            .iter()
+            .enumerate()
+            .filter(|(_, heap)| heap.flags.contains(self.heap_flags))
+            .map(|(i, heap)| (i, heap.usage_fraction()))
+            .fold(None, |fullest, (i, fraction)| match fullest {
+                Some((_, most)) if most >= fraction => fullest,
+                _ => Some((i, fraction)),
+            })
+    }
+}
+
+/// Passed to the callback of a `MemoryWatermark` when it is reached.
+#[derive(Clone, Debug)]
+pub struct MemoryWatermarkAlert {
+    /// The watermark which was reached.
+    pub watermark: MemoryWatermark,
+    /// The index of the heap which reached it, the fullest if several did.
+    pub heap_index: usize,
+    /// The memory usage of the Device, as returned by `Device::memory_stats`.
+    pub stats: MemoryStats,
+    /// The categories and tags whose allocations grew over the last frame, the largest growth
+    /// first.
+    pub growth: Vec<MemoryPoolGrowth>,
+}
+
+/// The callback of a `MemoryWatermark`.
+pub type MemoryWatermarkCallback = Arc<dyn Fn(&MemoryWatermarkAlert) + Send + Sync>;
+
+/// Identifies a watermark added with `Device::add_memory_watermark`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MemoryWatermarkId(u64);
+
+/// A registered watermark and its callback.
+struct WatermarkEntry {
+    id: MemoryWatermarkId,
+    watermark: MemoryWatermark,
+    callback: MemoryWatermarkCallback,
+    /// Whether the watermark was reached when last checked, so the callback is only called
+    /// once each time it is crossed.
+    exceeded: bool,
+}
+
+/// The allocations made for each category and tag, keyed like `MemoryPoolGrowth`.
+pub(crate) type MemoryPoolKey = (MemoryCategory, Option<String>);
+
+/// The watermarks registered with the Device.
+#[derive(Default)]
+pub(crate) struct MemoryWatermarks {
+    entries: Vec<WatermarkEntry>,
+    next_id: u64,
+    /// The watermark standing in for `set_memory_warning_threshold`, if one is set.
+    warning: Option<MemoryWatermarkId>,
+    /// The allocations of each pool when the watermarks were last checked.
+    previous_pools: Option<ReplayHashMap<MemoryPoolKey, vk::DeviceSize>>,
+}
+
+impl Device {
+    /// Get the memory usage and budget of each memory heap, with a breakdown of the allocations
+    /// made from them by category.
+    ///
+    /// This walks every resource and the allocator's internal state, so is too slow to call
+    /// every frame; use `add_memory_watermark` to watch the budget instead.
+    pub fn memory_stats(&self) -> Result<MemoryStats, AllocatorError> {
+        let mut stats = self.heap_stats()?;
+
+        let heap_of = |memory_type: u32| {
+            self.memory_properties.memory_types[memory_type as usize].heap_index as usize
+        };
+        {
+            let resources = self.resources();
+            for (_, buffer) in resources.buffers.iter() {
+                if let Some(allocation) = buffer.allocation() {
+                    let heap = &mut stats.heaps[heap_of(allocation.memory_type())];
+                    heap.categories.buffers += allocation.size();
+                }
+            }
+            for (_, image) in resources.images.iter() {
+                if let Some(allocation) = image.allocation() {
+                    let heap = &mut stats.heaps[heap_of(allocation.memory_type())];
+                    heap.categories.images += allocation.size();
+                }
+            }
+        }
+        if let Some(ref blocks) = *self.blocks.read() {
+            let kinds = [
+                PoolKind::Vertex,
+                PoolKind::Index,
+                PoolKind::Uniform,
+                PoolKind::Indirect,
+                PoolKind::Staging,
+            ];
+            for &kind in &kinds {
+                for buffer in blocks.pool(kind).buffers() {
+                    if let Some(allocation) = buffer.allocation() {
+                        let heap = &mut stats.heaps[heap_of(allocation.memory_type())];
+                        heap.categories.buffer_blocks += allocation.size();
+                    }
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+
+    /// Call `callback` with the Device's memory stats when any heap's usage first reaches
+    /// `threshold` of its budget, e.g. 0.9, so that an engine can free memory or lower its
+    /// quality settings before allocations start failing. It is called again only after usage
+    /// has dropped back below the threshold and reached it once more.
+    ///
+    /// The usage is checked in `begin_frame`, from the thread calling it. This is a shorthand
+    /// for a single `MemoryWatermark::any_heap`; see `add_memory_watermark` for several.
+    pub fn set_memory_warning_threshold<F>(&self, threshold: f32, callback: F)
+    where
+        F: Fn(&MemoryStats) + Send + Sync + 'static,
+    {
+        self.clear_memory_warning_threshold();
+        let id = self.add_memory_watermark(MemoryWatermark::any_heap(threshold), move |alert| {
+            callback(&alert.stats)
+        });
+        self.memory_watermarks.lock().warning = Some(id);
+    }
+
+    /// Stop checking the memory usage against the threshold set with
+    /// `set_memory_warning_threshold`.
+    pub fn clear_memory_warning_threshold(&self) {
+        let warning = self.memory_watermarks.lock().warning.take();
+        if let Some(id) = warning {
+            self.remove_memory_watermark(id);
+        }
+    }
+
+    /// Call `callback` when the usage of a heap checked by `watermark` first reaches its
+    /// threshold, e.g. with one watermark at 80% of the device-local budget to flush caches and
+    /// another at 95% to stop streaming. It is called again only after usage has dropped back
+    /// below the threshold and reached it once more.
+    ///
+    /// The usage is checked in `begin_frame`, from the thread calling it. The alert says which
+    /// categories and tags of resources grew the most over the previous frame.
+    pub fn add_memory_watermark<F>(&self, watermark: MemoryWatermark, callback: F) -> MemoryWatermarkId
+    where
+        F: Fn(&MemoryWatermarkAlert) + Send + Sync + 'static,
+    {
+        let mut watermarks = self.memory_watermarks.lock();
+        let id = MemoryWatermarkId(watermarks.next_id);
+        watermarks.next_id += 1;
+        watermarks.entries.push(WatermarkEntry {
+            id,
+            watermark,
+            callback: Arc::new(callback),
+            exceeded: false,
+        });
+        id
+    }
+
+    /// Stop checking a watermark added with `add_memory_watermark`.
+    pub fn remove_memory_watermark(&self, id: MemoryWatermarkId) {
+        let mut watermarks = self.memory_watermarks.lock();
+        watermarks.entries.retain(|entry| entry.id != id);
+        if watermarks.entries.is_empty() {
+            watermarks.previous_pools = None;
+        }
+    }
+
+    /// Record that `size` bytes were allocated for, or freed from if not `allocated`, a
+    /// resource of `category` created with `tag`.
+    pub(crate) fn track_allocation(
+        &self,
+        category: MemoryCategory,
+        tag: Option<&Tag>,
+        size: vk::DeviceSize,
+        allocated: bool,
+    ) {
+        let key = (category, tag.map(Tag::to_string));
+        let mut pools = self.memory_pools.lock();
+        if allocated {
+            *pools.entry(key).or_default() += size;
+        } else if let Some(bytes) = pools.get_mut(&key) {
+            *bytes = bytes.saturating_sub(size);
+            if *bytes == 0 {
+                pools.remove(&key);
+            }
+        }
+    }
+
+    /// Call the callbacks of the watermarks the usage of a heap has just reached.
+    pub(crate) fn check_memory_watermarks(&self) {
+        if self.memory_watermarks.lock().entries.is_empty() {
+            return;
+        }
+
+        // The check is skipped if the allocator can't report its usage.
+        let heap_stats = match self.heap_stats() {
+            Ok(stats) => stats,
+            Err(_) => return,
+        };
+        let pools = self.memory_pools.lock().clone();
+
+        let (alerts, previous_pools) = {
+            let mut watermarks = self.memory_watermarks.lock();
+            let previous_pools = watermarks.previous_pools.replace(pools.clone());
+
+            let mut alerts = Vec::new();
+            for entry in &mut watermarks.entries {
+                let fullest = entry
+                    .watermark
+                    .fullest_heap(&heap_stats)
+                    .filter(|&(_, fraction)| fraction >= entry.watermark.threshold);
+                let newly_exceeded = fullest.is_some() && !entry.exceeded;
+                entry.exceeded = fullest.is_some();
+                if let (true, Some((heap_index, _))) = (newly_exceeded, fullest) {
+                    alerts.push((entry.watermark, heap_index, entry.callback.clone()));
+                }
+            }
+            (alerts, previous_pools)
+        };
+        if alerts.is_empty() {
+            return;
+        }
+
+        // The lock is released so that the callbacks may add or remove watermarks.
+        let stats = match self.memory_stats() {
+            Ok(stats) => stats,
+            Err(_) => return,
+        };
+        let growth = pool_growth(&pools, previous_pools.as_ref().unwrap_or(&pools));
+        for (watermark, heap_index, callback) in alerts {
+            callback(&MemoryWatermarkAlert {
+                watermark,
+                heap_index,
+                stats: stats.clone(),
+                growth: growth.clone(),
+            });
+        }
+    }
+
+    /// Get the stats of each heap, without the breakdown by category.
+    fn heap_stats(&self) -> Result<MemoryStats, AllocatorError> {
+        let heap_usage = self.allocator.heap_usage()?;
+        let heap_count = self.memory_properties.memory_heap_count as usize;
+
+        let mut heaps = self.memory_properties.memory_heaps[..heap_count]
+            .iter()
+            .zip(heap_usage.iter())
+            .map(|(heap, allocated)| HeapStats {
+                flags: heap.flags,
+                size: heap.size,
+                budget: heap.size / 10 * 8,
+                usage: allocated.block_bytes,
+                block_bytes: allocated.block_bytes,
+                allocated_bytes: allocated.allocated_bytes,
+                categories: MemoryCategoryUsage::default(),
+            })
+            .collect::<Vec<_>>();
+
+        if self.memory_budget {
+            let mut budget = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+            let mut properties = vk::PhysicalDeviceMemoryProperties2::builder().push_next(&mut budget);
+            unsafe {
+                self.instance
+                    .get_physical_device_memory_properties2(self.physical_device, &mut properties)
+            };
+            for (i, heap) in heaps.iter_mut().enumerate() {
+                heap.budget = budget.heap_budget[i];
+                heap.usage = budget.heap_usage[i];
+            }
+        }
+
+        Ok(MemoryStats {
+            heaps,
+            has_budget: self.memory_budget,
+        })
+    }
+}
+
+/// The pools which grew between `previous` and `current`, the largest growth first.
+fn pool_growth(
+    current: &ReplayHashMap<MemoryPoolKey, vk::DeviceSize>,
+    previous: &ReplayHashMap<MemoryPoolKey, vk::DeviceSize>,
+) -> Vec<MemoryPoolGrowth> {
+    let mut growth = current
+        .iter()
+        .filter_map(|(key, &bytes)| {
+            let growth = bytes as i64 - previous.get(key).copied().unwrap_or(0) as i64;
+            (growth > 0).then(|| MemoryPoolGrowth {
+                category: key.0,
+                tag: key.1.clone(),
+                bytes,
+                growth,
+            })
+        })
+        .collect::<Vec<_>>();
+    growth.sort_by_key(|pool| std::cmp::Reverse(pool.growth));
+    growth
+}
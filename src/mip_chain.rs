@@ -0,0 +1,574 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// The format the mip chain passes are usually used with. Other formats supported are listed by
+/// `mip_chain_supports_format`.
+pub const MIP_CHAIN_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+
+/// Get whether `MipChainPasses` can operate on images of `format`. The device must also
+/// support the format as a storage image.
+pub fn mip_chain_supports_format(format: vk::Format) -> bool {
+    shaders::storage_format_variant(format).is_some()
+}
+
+/// The work group size of the mip chain passes in each dimension.
+const MIP_CHAIN_GROUP_SIZE: u32 = 8;
+
+/// The size of the tile of level 0 each work group of the single pass downsample reduces, in
+/// each dimension.
+const SPD_TILE_SIZE: u32 = 64;
+
+/// The largest image the single pass downsample handles in each dimension, whose level 6 is a
+/// single tile reduced by the last work group.
+pub const SPD_MAX_EXTENT: u32 = SPD_TILE_SIZE * SPD_TILE_SIZE;
+
+/// The number of levels after level 0 the single pass downsample can write.
+const SPD_MAX_MIPS: u32 = 12;
+
+/// The size of the single pass downsample's buffer: the work group counter, then four channels
+/// for each of the 64x64 texels of level 6.
+const SPD_BUFFER_SIZE: vk::DeviceSize = 4 + 4 * 4 * (SPD_TILE_SIZE * SPD_TILE_SIZE) as vk::DeviceSize;
+
+/// An error that could occur while creating `MipChainPasses`.
+#[derive(Error, Debug)]
+pub enum MipChainError {
+    /// The image does not exist.
+    #[error("the image does not exist")]
+    InvalidImage,
+    /// The image is not a single layer 2D image with at least two mip levels.
+    #[error("the image must be a single layer 2D image with at least two mip levels")]
+    UnsupportedShape,
+    /// The image's format is not supported; see `mip_chain_supports_format`.
+    #[error("unsupported image format {0:?}")]
+    UnsupportedFormat(vk::Format),
+    /// The image was not created with both `SAMPLED` and `STORAGE` usage.
+    #[error("the image must have SAMPLED and STORAGE usage")]
+    MissingUsage,
+    /// Reflecting the built-in shader failed.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+    /// The single pass downsample's buffer could not be created.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Compute passes which downsample an image into its mip chain and upsample the chain back up,
+/// which are the building blocks of bloom, HiZ and SSAO style effects.
+///
+/// Each pass runs one dispatch per mip level, with barriers between levels recorded through the
+/// image's per-subresource state tracking, so only the two levels involved are transitioned.
+/// The image must have a format supported by `mip_chain_supports_format`, usually
+/// `MIP_CHAIN_FORMAT`, and both `SAMPLED` and `STORAGE` usage.
+///
+/// Images no larger than `SPD_MAX_EXTENT` can also be downsampled in a single dispatch with
+/// `downsample_single_pass`, which saves the barriers and mostly idle dispatches of the small
+/// levels.
+///
+/// The per-mip views and descriptor sets are created up front for one image, so create a
+/// `MipChainPasses` once per image and reuse it every frame.
+pub struct MipChainPasses {
+    device: Arc<Device>,
+    image: ImageHandle,
+    extents: Vec<(u32, u32)>,
+    shader: Option<Shader>,
+    layout: Option<ShaderLayout>,
+    pipeline: Option<ComputePipeline>,
+    sampler: vk::Sampler,
+    views: Vec<vk::ImageView>,
+    descriptor_pool: vk::DescriptorPool,
+    /// `downsample_sets[i]` reads level `i` and writes level `i + 1`.
+    downsample_sets: Vec<vk::DescriptorSet>,
+    /// `upsample_sets[i]` reads level `i + 1` and writes level `i`.
+    upsample_sets: Vec<vk::DescriptorSet>,
+    /// The single pass downsample, if the image is small enough for it.
+    single_pass: Option<SinglePass>,
+}
+
+/// The pipeline and resources of `MipChainPasses::downsample_single_pass`.
+struct SinglePass {
+    shader: Option<Shader>,
+    layout: Option<ShaderLayout>,
+    pipeline: Option<ComputePipeline>,
+    /// Holds the count of work groups which have finished, which the last resets to zero, and
+    /// the level 6 texels they hand over to it.
+    buffer: Option<BufferHandle>,
+    /// Reads level 0 at binding 0, writes levels 1 to 12 at bindings 1 to 12, and binds
+    /// `buffer` at binding 13.
+    set: vk::DescriptorSet,
+}
+
+impl MipChainPasses {
+    /// Create the passes for every mip level of `image`.
+    pub fn new(device: &Arc<Device>, image: ImageHandle) -> Result<Self, MipChainError> {
+        let (raw_image, create_info, sampled_layout) = {
+            let resources = device.resources();
+            let image = resources.get_image(image).ok_or(MipChainError::InvalidImage)?;
+            (
+                image.raw(),
+                image.create_info(),
+                image.layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            )
+        };
+
+        if create_info.image_type != vk::ImageType::TYPE_2D
+            || create_info.layers != 1
+            || create_info.levels < 2
+        {
+            return Err(MipChainError::UnsupportedShape);
+        }
+        let variant = shaders::storage_format_variant(create_info.format)
+            .ok_or(MipChainError::UnsupportedFormat(create_info.format))?;
+        if !create_info
+            .usage
+            .contains(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::STORAGE)
+        {
+            return Err(MipChainError::MissingUsage);
+        }
+
+        let levels = create_info.levels as u32;
+        let mut passes = MipChainPasses {
+            device: device.clone(),
+            image,
+            extents: (0..levels)
+                .map(|level| {
+                    (
+                        (create_info.width as u32 >> level).max(1),
+                        (create_info.height as u32 >> level).max(1),
+                    )
+                })
+                .collect(),
+            shader: None,
+            layout: None,
+            pipeline: None,
+            sampler: vk::Sampler::null(),
+            views: Vec::with_capacity(levels as usize),
+            descriptor_pool: vk::DescriptorPool::null(),
+            downsample_sets: Vec::new(),
+            upsample_sets: Vec::new(),
+            single_pass: None,
+        };
+        let single_pass = create_info.width as u32 <= SPD_MAX_EXTENT
+            && create_info.height as u32 <= SPD_MAX_EXTENT;
+
+        // Anything created so far is cleaned up by Drop if a later step fails.
+        let shader = device.create_shader(shaders::MIP_CHAIN[variant])?;
+        let layout = device.create_shader_layout(&[&shader])?;
+        passes.shader = Some(shader);
+        let pipeline = device.create_compute_pipeline(passes.shader.as_ref().unwrap().raw(), layout.raw())?;
+        passes.layout = Some(layout);
+        passes.pipeline = Some(pipeline);
+
+        let sampler_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(0.0);
+        passes.sampler = unsafe { device.device.create_sampler(&sampler_info, None)? };
+
+        for level in 0..levels {
+            let view_info = vk::ImageViewCreateInfo::builder()
+                .image(raw_image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(create_info.format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: level,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                });
+            passes
+                .views
+                .push(unsafe { device.device.create_image_view(&view_info, None)? });
+        }
+
+        let set_count = 2 * (levels - 1);
+        let single_pass_sets = single_pass as u32;
+        let mut pool_sizes = vec![
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: set_count + single_pass_sets,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_IMAGE,
+                descriptor_count: set_count + single_pass_sets * SPD_MAX_MIPS,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: set_count,
+            },
+        ];
+        if single_pass {
+            pool_sizes.push(vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::STORAGE_BUFFER,
+                descriptor_count: 1,
+            });
+        }
+        let pool_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(set_count + single_pass_sets)
+            .pool_sizes(&pool_sizes);
+        passes.descriptor_pool = unsafe { device.device.create_descriptor_pool(&pool_info, None)? };
+
+        let set_layouts = vec![passes.layout.as_ref().unwrap().set_layouts()[0]; set_count as usize];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(passes.descriptor_pool)
+            .set_layouts(&set_layouts);
+        let mut sets = unsafe { device.device.allocate_descriptor_sets(&alloc_info)? };
+        passes.upsample_sets = sets.split_off((levels - 1) as usize);
+        passes.downsample_sets = sets;
+
+        // Each set samples one level at binding 0 with the sampler at binding 2, and writes
+        // another at binding 1.
+        let mut image_infos = Vec::with_capacity(set_count as usize * 3);
+        for level in 0..levels - 1 {
+            let (low, high) = (level as usize, level as usize + 1);
+            for &(src, dst) in &[(low, high), (high, low)] {
+                image_infos.push(vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: passes.views[src],
+                    image_layout: sampled_layout,
+                });
+                image_infos.push(vk::DescriptorImageInfo {
+                    sampler: vk::Sampler::null(),
+                    image_view: passes.views[dst],
+                    image_layout: vk::ImageLayout::GENERAL,
+                });
+                image_infos.push(vk::DescriptorImageInfo {
+                    sampler: passes.sampler,
+                    image_view: vk::ImageView::null(),
+                    image_layout: vk::ImageLayout::UNDEFINED,
+                });
+            }
+        }
+
+        let mut writes = Vec::with_capacity(image_infos.len());
+        for level in 0..(levels - 1) as usize {
+            let sets = [passes.downsample_sets[level], passes.upsample_sets[level]];
+            for (i, &set) in sets.iter().enumerate() {
+                let infos = &image_infos[(level * 2 + i) * 3..];
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                        .image_info(&infos[..1])
+                        .build(),
+                );
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(1)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .image_info(&infos[1..2])
+                        .build(),
+                );
+                writes.push(
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(2)
+                        .descriptor_type(vk::DescriptorType::SAMPLER)
+                        .image_info(&infos[2..3])
+                        .build(),
+                );
+            }
+        }
+        unsafe { device.device.update_descriptor_sets(&writes, &[]) };
+
+        if single_pass {
+            passes.create_single_pass(variant, sampled_layout)?;
+        }
+
+        Ok(passes)
+    }
+
+    /// Create the pipeline, buffer and descriptor set of the single pass downsample, from the
+    /// variant of the shader for the image's format.
+    fn create_single_pass(
+        &mut self,
+        variant: usize,
+        sampled_layout: vk::ImageLayout,
+    ) -> Result<(), MipChainError> {
+        let device = self.device.clone();
+        // As in `new`, anything created so far is cleaned up by Drop if a later step fails.
+        let single_pass = self.single_pass.insert(SinglePass {
+            shader: None,
+            layout: None,
+            pipeline: None,
+            buffer: None,
+            set: vk::DescriptorSet::null(),
+        });
+
+        let shader = device.create_shader(shaders::SPD_DOWNSAMPLE[variant])?;
+        single_pass.shader = Some(shader);
+        let layout = device.create_shader_layout(&[single_pass.shader.as_ref().unwrap()])?;
+        let pipeline =
+            device.create_compute_pipeline(single_pass.shader.as_ref().unwrap().raw(), layout.raw());
+        single_pass.layout = Some(layout);
+        single_pass.pipeline = Some(pipeline?);
+
+        // Only the counter needs to start at zero; the level 6 texels are written before use.
+        let buffer_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: SPD_BUFFER_SIZE,
+            usage: vk::BufferUsageFlags::STORAGE_BUFFER,
+            wants_device_address: false,
+        };
+        let buffer = device.clone().create_buffer(buffer_info, None, Some(0u32))?;
+        single_pass.buffer = Some(buffer);
+        let raw_buffer = device
+            .resources()
+            .get_buffer(buffer)
+            .expect("create_single_pass: buffer was destroyed")
+            .raw();
+
+        let set_layouts = [single_pass.layout.as_ref().unwrap().set_layouts()[0]];
+        let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(self.descriptor_pool)
+            .set_layouts(&set_layouts);
+        single_pass.set = unsafe { device.device.allocate_descriptor_sets(&alloc_info)?[0] };
+
+        // Levels past the last are never written, but their bindings still need a view.
+        let views = &self.views;
+        let last_level = views.len() - 1;
+        let src_info = [vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view: views[0],
+            image_layout: sampled_layout,
+        }];
+        let dst_infos = (1..=SPD_MAX_MIPS as usize)
+            .map(|level| vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: views[level.min(last_level)],
+                image_layout: vk::ImageLayout::GENERAL,
+            })
+            .collect::<Vec<_>>();
+        let buffer_info = [vk::DescriptorBufferInfo {
+            buffer: raw_buffer,
+            offset: 0,
+            range: vk::WHOLE_SIZE,
+        }];
+        let writes = [
+            vk::WriteDescriptorSet::builder()
+                .dst_set(single_pass.set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&src_info)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(single_pass.set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .image_info(&dst_infos)
+                .build(),
+            vk::WriteDescriptorSet::builder()
+                .dst_set(single_pass.set)
+                .dst_binding(SPD_MAX_MIPS + 1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(&buffer_info)
+                .build(),
+        ];
+        unsafe { device.device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(())
+    }
+
+    /// The image these passes operate on.
+    pub fn image(&self) -> ImageHandle {
+        self.image
+    }
+
+    /// The view of a single mip level of the image.
+    pub fn level_view(&self, level: u32) -> vk::ImageView {
+        self.views[level as usize]
+    }
+
+    /// Fill every mip level below the first by successively downsampling with a 2x2 box filter.
+    ///
+    /// Afterwards, all but the last level are ready to be sampled by compute shaders, and the
+    /// last level is in `GENERAL` layout.
+    pub fn downsample(&self, cmd: &mut CommandBuffer) {
+        for level in 0..self.extents.len() - 1 {
+            self.dispatch(cmd, level, level + 1, self.downsample_sets[level], 1.0, 0.0);
+        }
+    }
+
+    /// Fill every mip level below the first like `downsample`, but in a single dispatch, in the
+    /// manner of AMD's FidelityFX SPD: each work group reduces a 64x64 tile of the first level
+    /// down to level 6, and the last group to finish, found with a counter in a storage buffer,
+    /// reduces level 6 to the end of the chain. Falls back to `downsample` for images larger
+    /// than `SPD_MAX_EXTENT`.
+    ///
+    /// Each level is a 2x2 box filter of the one above, so for levels with an odd size the
+    /// results differ slightly from `downsample`, whose bilinear samples straddle three texels.
+    /// Afterwards, the first level is ready to be sampled by compute shaders, and the others are
+    /// in `GENERAL` layout.
+    pub fn downsample_single_pass(&self, cmd: &mut CommandBuffer) {
+        let single_pass = match self.single_pass {
+            Some(ref single_pass) => single_pass,
+            None => return self.downsample(cmd),
+        };
+
+        let levels = self.extents.len() as u32;
+        cmd.transition_image_subresources(
+            self.image,
+            vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        cmd.transition_image_subresources(
+            self.image,
+            vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 1,
+                level_count: levels - 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            },
+            vk::ImageLayout::GENERAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_WRITE,
+        );
+
+        let (width, height) = self.extents[0];
+        let group_counts = [width.div_ceil(SPD_TILE_SIZE), height.div_ceil(SPD_TILE_SIZE), 1];
+        let params = [levels - 1, group_counts[0] * group_counts[1]];
+        let push_constants = params
+            .iter()
+            .flat_map(|value| value.to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        // The buffer is read and written by the previous dispatch, if any, so the barrier also
+        // orders the counter's reset before this dispatch.
+        cmd.dispatch_with(
+            single_pass.pipeline.as_ref().unwrap(),
+            &[single_pass.set],
+            &push_constants,
+            &[DispatchResource::StorageBuffer(
+                single_pass.buffer.unwrap(),
+                ShaderAccess::ReadWrite,
+            )],
+            group_counts,
+        );
+    }
+
+    /// Walk back up the mip chain from the smallest level, adding each level's bilinearly
+    /// upsampled contents, scaled by `weight`, to the level above it. Running this after
+    /// `downsample` produces the blurred chain used for bloom.
+    pub fn upsample(&self, cmd: &mut CommandBuffer, weight: f32) {
+        for level in (0..self.extents.len() - 1).rev() {
+            self.dispatch(cmd, level + 1, level, self.upsample_sets[level], weight, 1.0);
+        }
+    }
+
+    fn dispatch(
+        &self,
+        cmd: &mut CommandBuffer,
+        src_level: usize,
+        dst_level: usize,
+        set: vk::DescriptorSet,
+        src_weight: f32,
+        dst_weight: f32,
+    ) {
+        let level_range = |level: usize| vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: level as u32,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        cmd.transition_image_subresources(
+            self.image,
+            level_range(src_level),
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        cmd.transition_image_subresources(
+            self.image,
+            level_range(dst_level),
+            vk::ImageLayout::GENERAL,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        );
+
+        let (width, height) = self.extents[dst_level];
+        let params = [1.0 / width as f32, 1.0 / height as f32, src_weight, dst_weight];
+        let push_constants = params
+            .iter()
+            .flat_map(|value| value.to_ne_bytes().to_vec())
+            .collect::<Vec<_>>();
+
+        cmd.dispatch_with(
+            self.pipeline.as_ref().unwrap(),
+            &[set],
+            &push_constants,
+            &[],
+            [
+                width.div_ceil(MIP_CHAIN_GROUP_SIZE),
+                height.div_ceil(MIP_CHAIN_GROUP_SIZE),
+                1,
+            ],
+        );
+    }
+}
+
+impl Drop for MipChainPasses {
+    fn drop(&mut self) {
+        unsafe {
+            // The passes may still be in use by frames in flight.
+            let _ = self.device.device_wait_idle();
+
+            if let Some(mut single_pass) = self.single_pass.take() {
+                if let Some(buffer) = single_pass.buffer.take() {
+                    self.device.destroy_buffer(buffer);
+                }
+                if let Some(pipeline) = single_pass.pipeline.take() {
+                    self.device.destroy_compute_pipeline(pipeline);
+                }
+                if let Some(layout) = single_pass.layout.take() {
+                    self.device.destroy_shader_layout(layout);
+                }
+                if let Some(shader) = single_pass.shader.take() {
+                    self.device.destroy_shader(shader);
+                }
+            }
+            if self.descriptor_pool != vk::DescriptorPool::null() {
+                self.device.device.destroy_descriptor_pool(self.descriptor_pool, None);
+            }
+            for view in self.views.drain(..) {
+                self.device.device.destroy_image_view(view, None);
+            }
+            if self.sampler != vk::Sampler::null() {
+                self.device.device.destroy_sampler(self.sampler, None);
+            }
+            if let Some(pipeline) = self.pipeline.take() {
+                self.device.destroy_compute_pipeline(pipeline);
+            }
+            if let Some(layout) = self.layout.take() {
+                self.device.destroy_shader_layout(layout);
+            }
+            if let Some(shader) = self.shader.take() {
+                self.device.destroy_shader(shader);
+            }
+        }
+    }
+}
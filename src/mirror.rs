@@ -0,0 +1,335 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::{format_has_depth_or_stencil_aspect, format_to_aspect_mask};
+
+/// An error that could occur while mirroring a resource from one Device to another.
+#[derive(Error, Debug)]
+pub enum MirrorError {
+    /// The resource to mirror has been destroyed.
+    #[error("the resource to mirror has been destroyed")]
+    InvalidResource,
+    /// Depth stencil images can't be mirrored, since they can't be read back.
+    #[error("images of format {0:?} cannot be mirrored")]
+    UnsupportedFormat(vk::Format),
+    /// Reading the source resource back to the host failed.
+    #[error("readback error: {0}")]
+    Readback(#[from] ReadbackError),
+    /// Creating the mirror or staging its contents failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+/// A buffer or image mirrored by a `MirroredResource`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum MirroredHandle {
+    /// A buffer.
+    Buffer(BufferHandle),
+    /// An image.
+    Image(ImageHandle),
+}
+
+/// A readback of the source resource which hasn't been copied to the mirror yet.
+enum InFlight {
+    Buffer(ReadbackFuture<Vec<u8>>),
+    Image(ReadbackFuture<ImageData>),
+}
+
+/// A copy of a buffer or image from one Device kept on another, for setups with more than one
+/// adapter, such as rendering on a discrete GPU and encoding or presenting on an integrated one.
+///
+/// Devices can't share memory directly, so each sync reads the source back into host-visible
+/// memory on the source Device and copies it through a staging block on the destination Device.
+/// A sync is started with `begin_sync` and completed by `poll` once the readback has finished,
+/// so the copy can overlap with the next frame's rendering. The source must have
+/// `TRANSFER_SRC` usage.
+///
+/// The mirror is created and owned by the MirroredResource, and destroyed when it is dropped.
+pub struct MirroredResource {
+    src_device: Arc<Device>,
+    dst_device: Arc<Device>,
+    src: MirroredHandle,
+    dst: MirroredHandle,
+    in_flight: Option<InFlight>,
+    tag: Option<Tag>,
+}
+
+impl MirroredResource {
+    /// Mirror `src`, a buffer of `src_device`, with a buffer of the same size on `dst_device`.
+    pub fn buffer(
+        src_device: &Arc<Device>,
+        src: BufferHandle,
+        dst_device: &Arc<Device>,
+        tag: Option<Tag>,
+    ) -> Result<Self, MirrorError> {
+        let src_info = match src_device.resources().get_buffer(src) {
+            Some(buffer) => buffer.create_info(),
+            None => return Err(MirrorError::InvalidResource),
+        };
+
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: src_info.size,
+            usage: src_info.usage | vk::BufferUsageFlags::TRANSFER_DST,
+            wants_device_address: false,
+        };
+        let dst = dst_device.clone().create_buffer::<()>(create_info, tag.clone(), None)?;
+
+        Ok(Self::new(src_device, MirroredHandle::Buffer(src), dst_device, MirroredHandle::Buffer(dst), tag))
+    }
+
+    /// Mirror the first mip level of every layer of `src`, an image of `src_device`, with an
+    /// image of the same extent and format on `dst_device`. After each sync, the mirror is left
+    /// in the source's `initial_layout`.
+    pub fn image(
+        src_device: &Arc<Device>,
+        src: ImageHandle,
+        dst_device: &Arc<Device>,
+        tag: Option<Tag>,
+    ) -> Result<Self, MirrorError> {
+        let src_info = match src_device.resources().get_image(src) {
+            Some(image) => image.create_info(),
+            None => return Err(MirrorError::InvalidResource),
+        };
+        if format_has_depth_or_stencil_aspect(src_info.format) {
+            return Err(MirrorError::UnsupportedFormat(src_info.format));
+        }
+
+        let create_info = ImageCreateInfo {
+            width: src_info.width,
+            height: src_info.height,
+            depth: src_info.depth,
+            layers: src_info.layers,
+            format: src_info.format,
+            image_type: src_info.image_type,
+            usage: (src_info.usage | vk::ImageUsageFlags::TRANSFER_DST)
+                & !(vk::ImageUsageFlags::TRANSIENT_ATTACHMENT | vk::ImageUsageFlags::INPUT_ATTACHMENT),
+            initial_layout: src_info.initial_layout,
+            swizzle: src_info.swizzle,
+            ..Default::default()
+        };
+        let dst = dst_device.clone().create_image(create_info, None, tag.clone())?;
+
+        Ok(Self::new(src_device, MirroredHandle::Image(src), dst_device, MirroredHandle::Image(dst), tag))
+    }
+
+    fn new(
+        src_device: &Arc<Device>,
+        src: MirroredHandle,
+        dst_device: &Arc<Device>,
+        dst: MirroredHandle,
+        tag: Option<Tag>,
+    ) -> Self {
+        Self {
+            src_device: src_device.clone(),
+            dst_device: dst_device.clone(),
+            src,
+            dst,
+            in_flight: None,
+            tag,
+        }
+    }
+
+    /// The Device the source resource belongs to.
+    pub fn src_device(&self) -> &Arc<Device> {
+        &self.src_device
+    }
+
+    /// The Device the mirror belongs to.
+    pub fn dst_device(&self) -> &Arc<Device> {
+        &self.dst_device
+    }
+
+    /// The resource being mirrored.
+    pub fn src(&self) -> MirroredHandle {
+        self.src
+    }
+
+    /// The mirror, which is owned by the MirroredResource.
+    pub fn dst(&self) -> MirroredHandle {
+        self.dst
+    }
+
+    /// Whether a sync has been started which `poll` hasn't completed yet.
+    pub fn is_syncing(&self) -> bool {
+        self.in_flight.is_some()
+    }
+
+    /// Start copying the current contents of the source to the mirror, by reading it back on
+    /// the source Device. The readback is submitted to the source's graphics queue immediately,
+    /// so it sees all graphics work submitted before it.
+    ///
+    /// Returns `false` without starting a new sync if the previous one hasn't completed yet.
+    pub fn begin_sync(&mut self) -> Result<bool, MirrorError> {
+        if self.in_flight.is_some() {
+            return Ok(false);
+        }
+
+        self.in_flight = Some(match self.src {
+            MirroredHandle::Buffer(buffer) => {
+                let size = match self.src_device.resources().get_buffer(buffer) {
+                    Some(buffer) => buffer.create_info().size,
+                    None => return Err(MirrorError::InvalidResource),
+                };
+                InFlight::Buffer(self.src_device.read_buffer(buffer, 0..size)?)
+            }
+            MirroredHandle::Image(image) => {
+                let create_info = match self.src_device.resources().get_image(image) {
+                    Some(image) => image.create_info(),
+                    None => return Err(MirrorError::InvalidResource),
+                };
+                InFlight::Image(self.src_device.read_image(image, ImageRegion::mip_level(&create_info, 0))?)
+            }
+        });
+        Ok(true)
+    }
+
+    /// Complete the sync started by `begin_sync` if its readback has finished, enqueueing the
+    /// copy into the mirror on the destination Device's async transfer queue, which the next
+    /// graphics submission on that Device waits for. Returns whether the mirror was updated.
+    pub fn poll(&mut self) -> Result<bool, MirrorError> {
+        self.wait(0)
+    }
+
+    /// Like `poll`, but waits up to `timeout` nanoseconds for the readback to finish.
+    pub fn wait(&mut self, timeout: u64) -> Result<bool, MirrorError> {
+        match self.in_flight.take() {
+            Some(InFlight::Buffer(future)) => match future.wait(timeout)? {
+                Ok(data) => self.upload_buffer(&data).map(|_| true),
+                Err(future) => {
+                    self.in_flight = Some(InFlight::Buffer(future));
+                    Ok(false)
+                }
+            },
+            Some(InFlight::Image(future)) => match future.wait(timeout)? {
+                Ok(data) => self.upload_image(&data).map(|_| true),
+                Err(future) => {
+                    self.in_flight = Some(InFlight::Image(future));
+                    Ok(false)
+                }
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Start a sync and wait for it to complete, for when the mirror is needed immediately. If
+    /// a sync is already in flight, waits for that one instead.
+    pub fn sync_blocking(&mut self) -> Result<(), MirrorError> {
+        self.begin_sync()?;
+        self.wait(u64::MAX)?;
+        Ok(())
+    }
+
+    /// Copy the bytes read back from the source buffer into the mirror.
+    fn upload_buffer(&self, data: &[u8]) -> Result<(), MirrorError> {
+        let handle = match self.dst {
+            MirroredHandle::Buffer(buffer) => buffer,
+            MirroredHandle::Image(_) => unreachable!("buffer readback for an image mirror"),
+        };
+
+        self.dst_device.upload_via_staging(data, self.tag.clone(), |cmd, src, src_offset| {
+            cmd.transition_buffer(
+                handle,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let dst = cmd
+                .device()
+                .resources()
+                .get_buffer(handle)
+                .expect("MirroredResource: mirror buffer was destroyed")
+                .raw();
+            let region = vk::BufferCopy {
+                src_offset,
+                dst_offset: 0,
+                size: data.len() as vk::DeviceSize,
+            };
+            unsafe { cmd.device().cmd_copy_buffer(cmd.raw(), src, dst, &[region]) };
+        })?;
+        Ok(())
+    }
+
+    /// Copy the texels read back from the source image into the mirror.
+    fn upload_image(&self, data: &ImageData) -> Result<(), MirrorError> {
+        let handle = match self.dst {
+            MirroredHandle::Image(image) => image,
+            MirroredHandle::Buffer(_) => unreachable!("image readback for a buffer mirror"),
+        };
+        let (image, final_layout) = {
+            let resources = self.dst_device.resources();
+            let image = resources
+                .get_image(handle)
+                .expect("MirroredResource: mirror image was destroyed");
+            (image.raw(), image.create_info().initial_layout)
+        };
+
+        let aspect_mask = format_to_aspect_mask(data.format);
+        self.dst_device.upload_via_staging(&data.data, self.tag.clone(), |cmd, src, src_offset| {
+            cmd.transition_image(
+                handle,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let region = vk::BufferImageCopy {
+                buffer_offset: src_offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: data.layers,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: data.extent,
+            };
+            unsafe {
+                cmd.device().cmd_copy_buffer_to_image(
+                    cmd.raw(),
+                    src,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[region],
+                )
+            };
+
+            // As in `Device::create_image`, the transfer queue can't wait on later stages.
+            if final_layout != vk::ImageLayout::UNDEFINED {
+                cmd.transition_image(
+                    handle,
+                    final_layout,
+                    vk::PipelineStageFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+        })?;
+        Ok(())
+    }
+}
+
+impl Drop for MirroredResource {
+    fn drop(&mut self) {
+        // An in-flight readback cleans up after itself, and destruction is deferred until the
+        // current frame has completed, so a pending copy into the mirror finishes first.
+        match self.dst {
+            MirroredHandle::Buffer(buffer) => self.dst_device.destroy_buffer(buffer),
+            MirroredHandle::Image(image) => self.dst_device.destroy_image(image),
+        }
+    }
+}
+
+impl std::fmt::Debug for MirroredResource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MirroredResource")
+            .field("src", &self.src)
+            .field("dst", &self.dst)
+            .field("syncing", &self.is_syncing())
+            .finish()
+    }
+}
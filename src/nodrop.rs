@@ -11,15 +11,19 @@ pub enum Tag {
 
 impl std::fmt::Display for Tag {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-         match self {
-            &Tag::Allocated(ref tag) => write!(f, "{}", tag),
-            &Tag::Static(ref tag) => write!(f, "{}", tag),
+        match self {
+            Tag::Allocated(tag) => write!(f, "{}", tag),
+            Tag::Static(tag) => write!(f, "{}", tag),
         }
     }
 }
 
 /// This type, and structs containing this type, must explicitly be destroyed
-/// rather than simply being Dropped. Being Dropped will cause a panic.
+/// rather than simply being Dropped.
+///
+/// Being Dropped will cause a panic, unless the thread is already panicking, since panicking
+/// while unwinding would abort. The leak is only logged then. Unlike `ImageView`, which queues
+/// itself for destruction through its Device, a NoDrop has no way to free what it guards.
 #[derive(Debug)]
 pub struct NoDrop(ManuallyDrop<Tag>);
 
@@ -35,6 +39,7 @@ impl NoDrop {
     }
 
     /// Create a new NoDrop from an `&'static str`
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(tag: &'static str) -> Self {
         Self(ManuallyDrop::new(Tag::Static(tag)))
     }
@@ -54,6 +59,11 @@ impl Default for NoDrop {
 
 impl Drop for NoDrop {
     fn drop(&mut self) {
-        panic!("NoDrop item with tag {} was dropped!", *self.0);
+        let tag = unsafe { ManuallyDrop::take(&mut self.0) };
+        if std::thread::panicking() {
+            log::warn!("hot: NoDrop item with tag {} was dropped", tag);
+        } else {
+            panic!("NoDrop item with tag {} was dropped!", tag);
+        }
     }
 }
\ No newline at end of file
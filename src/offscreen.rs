@@ -0,0 +1,159 @@
+//! Offscreen render targets, which stand in for the swapchain when rendering without a window,
+//! such as on a `headless` Device in CI: render into one with `OffscreenRenderTarget::render`
+//! and read the result back with `OffscreenRenderTarget::capture`.
+
+use ash::vk;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::format_has_depth_or_stencil_aspect;
+
+/// An error that could occur while creating an `OffscreenRenderTarget`.
+#[derive(Error, Debug)]
+pub enum OffscreenTargetError {
+    /// An image could not be created.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A view of an image could not be created.
+    #[error("image view error: {0}")]
+    ImageView(#[from] ImageViewCreationError),
+}
+
+/// A color image, and optionally a depth image, to render into in place of the swapchain.
+///
+/// Create one with `Device::create_offscreen_target`. It must be destroyed with `destroy`.
+#[derive(Clone, Copy, Debug)]
+pub struct OffscreenRenderTarget {
+    color: ImageHandle,
+    color_view: ImageViewHandle,
+    depth: Option<(ImageHandle, ImageViewHandle)>,
+    extent: vk::Extent2D,
+}
+
+impl OffscreenRenderTarget {
+    /// The color image.
+    pub fn color(&self) -> ImageHandle {
+        self.color
+    }
+
+    /// The view of the color image to render into.
+    pub fn color_view(&self) -> ImageViewHandle {
+        self.color_view
+    }
+
+    /// The view of the depth image, if the target has one.
+    pub fn depth_view(&self) -> Option<ImageViewHandle> {
+        self.depth.map(|(_, view)| view)
+    }
+
+    /// The extent of the target's images.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// Get a `RenderTargetScope` rendering into the target, to configure before rendering.
+    pub fn scope(&self) -> RenderTargetScope {
+        let scope = RenderTargetScope::new(self.color_view);
+        match self.depth {
+            Some((_, view)) => scope.depth(view),
+            None => scope,
+        }
+    }
+
+    /// Render into the target with `f` in a `RenderTargetScope` with the default settings. See
+    /// `RenderTargetScope::render`.
+    pub fn render<R, F>(&self, device: &Arc<Device>, frame: &Frame, f: F) -> Result<R, RenderTargetError>
+    where
+        F: FnOnce(&mut CommandBuffer) -> R,
+    {
+        self.scope().render(device, frame, f)
+    }
+
+    /// Capture the color image as 8-bit RGBA pixels, waiting for the copy. The copy is submitted
+    /// along with the work already enqueued on the graphics queue, such as that of `render`.
+    /// See `Device::capture_image`.
+    pub fn capture(&self, device: &Arc<Device>) -> Result<ScreenCapture, ReadbackError> {
+        device.capture_image(self.color)
+    }
+
+    /// Destroy the target's images and views.
+    pub fn destroy(self, device: &Device) {
+        device.destroy_image_view(self.color_view);
+        device.destroy_image(self.color);
+        if let Some((image, view)) = self.depth {
+            device.destroy_image_view(view);
+            device.destroy_image(image);
+        }
+    }
+}
+
+impl Device {
+    /// Create an `OffscreenRenderTarget` of `width` by `height` with a color image of `format`, which
+    /// can be sampled and read back, and a depth image of `depth_format` if given.
+    pub fn create_offscreen_target(
+        self: &Arc<Self>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        depth_format: Option<vk::Format>,
+    ) -> Result<OffscreenRenderTarget, OffscreenTargetError> {
+        let color = self.create_offscreen_image(width, height, format, "offscreen color")?;
+        let depth = match depth_format {
+            Some(depth_format) => {
+                match self.create_offscreen_image(width, height, depth_format, "offscreen depth") {
+                    Ok(depth) => Some(depth),
+                    Err(e) => {
+                        self.destroy_image_view(color.1);
+                        self.destroy_image(color.0);
+                        return Err(e);
+                    }
+                }
+            }
+            None => None,
+        };
+
+        Ok(OffscreenRenderTarget {
+            color: color.0,
+            color_view: color.1,
+            depth,
+            extent: vk::Extent2D { width, height },
+        })
+    }
+
+    /// Create an image of a single level and layer to render into, and a view of it.
+    fn create_offscreen_image(
+        self: &Arc<Self>,
+        width: u32,
+        height: u32,
+        format: vk::Format,
+        tag: &'static str,
+    ) -> Result<(ImageHandle, ImageViewHandle), OffscreenTargetError> {
+        let mut create_info = ImageCreateInfo::render_target(width as usize, height as usize, format, false);
+        create_info.levels = 1;
+        if !format_has_depth_or_stencil_aspect(format) {
+            create_info.usage |= vk::ImageUsageFlags::SAMPLED;
+        }
+        let image = self.clone().create_image(create_info, None, Some(Tag::Static(tag)))?;
+
+        let view_info = ImageViewCreateInfo {
+            image,
+            format,
+            base_mip_level: 0,
+            mip_levels: 1,
+            base_array_layer: 0,
+            array_layers: 1,
+            view_type: vk::ImageViewType::TYPE_2D,
+            swizzle: vk::ComponentMapping::default(),
+        };
+        match self.create_image_view(view_info, Some(Tag::Static(tag))) {
+            Ok(view) => Ok((image, view)),
+            Err(e) => {
+                self.destroy_image(image);
+                Err(e.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while orphaning a buffer's memory with `Device::orphan_buffer`.
+#[derive(Error, Debug)]
+pub enum OrphanError {
+    /// The buffer has been destroyed.
+    #[error("invalid buffer handle")]
+    InvalidBuffer,
+    /// The buffer is backed by imported host memory, which can't be replaced.
+    #[error("buffers backed by imported host memory cannot be orphaned")]
+    Imported,
+    /// The new memory could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+impl Device {
+    /// Give `buffer` a fresh `vk::Buffer` and memory with the same `BufferCreateInfo`, so it
+    /// can be rewritten right away while work submitted earlier still reads its old contents.
+    /// This is the "buffer renaming" pattern, for dynamic data which is rewritten every frame
+    /// but doesn't fit the block pools, e.g. because it must outlive a frame.
+    ///
+    /// The handle stays valid and refers to the new buffer, whose contents are undefined. The
+    /// old buffer is destroyed once the current frame has completed, like with `destroy_buffer`.
+    /// Since `Buffer::raw` changes, descriptors and buffer views of the old buffer must be
+    /// written or created again.
+    pub fn orphan_buffer(self: &Arc<Self>, buffer: BufferHandle) -> Result<(), OrphanError> {
+        self.check_deterministic_thread("orphan_buffer");
+        let (create_info, category, tag) = match self.resources().get_buffer(buffer) {
+            Some(old) if old.is_imported() => return Err(OrphanError::Imported),
+            Some(old) => (old.create_info, old.category, old.tag.clone()),
+            None => return Err(OrphanError::InvalidBuffer),
+        };
+
+        let mut queue_family_indices = [0u32; 3];
+        let buffer_info = self.raw_buffer_create_info(create_info, &mut queue_family_indices);
+        let alloc_desc = self.allocation_desc_from_buffer_create_info(create_info);
+        let (raw, allocation) = self.create_raw_buffer(create_info, &buffer_info, &alloc_desc)?;
+        let new = unsafe { Buffer::new(self.clone(), raw, allocation, create_info, category, tag) };
+
+        let old = match self.resources_mut().buffers.get_mut(buffer.idx) {
+            Some(slot) => std::mem::replace(slot, new),
+            // Destroyed in the meantime, so the new buffer is simply dropped.
+            None => return Err(OrphanError::InvalidBuffer),
+        };
+        self.current_frame().write().orphaned_buffers.push(old);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,491 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::c_void;
+
+use crate::*;
+use crate::cache_stats::CacheKind;
+use crate::dynamic_rendering::PipelineRenderingCreateInfo;
+
+/// A vertex buffer binding of a graphics pipeline.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct VertexBinding {
+    /// The binding number.
+    pub binding: u32,
+    /// The distance in bytes between consecutive elements.
+    pub stride: u32,
+    /// Whether the binding advances per vertex or per instance.
+    pub input_rate: vk::VertexInputRate,
+}
+
+/// A vertex attribute of a graphics pipeline.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct VertexAttribute {
+    /// The shader input location.
+    pub location: u32,
+    /// The binding the attribute is read from.
+    pub binding: u32,
+    /// The format of the attribute.
+    pub format: vk::Format,
+    /// The offset of the attribute within an element of the binding.
+    pub offset: u32,
+}
+
+/// A commonly used color blend state for a color attachment.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum BlendState {
+    /// No blending, the source color replaces the destination.
+    Opaque,
+    /// Standard alpha blending: `src * src_alpha + dst * (1 - src_alpha)`.
+    AlphaBlend,
+    /// Blending for colors with premultiplied alpha: `src + dst * (1 - src_alpha)`.
+    PremultipliedAlpha,
+    /// Additive blending: `src * src_alpha + dst`.
+    Additive,
+}
+
+impl BlendState {
+    /// Get the raw attachment blend state.
+    pub fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let (src_color, dst_color) = match self {
+            BlendState::Opaque => (vk::BlendFactor::ONE, vk::BlendFactor::ZERO),
+            BlendState::AlphaBlend => (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            BlendState::PremultipliedAlpha => (vk::BlendFactor::ONE, vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            BlendState::Additive => (vk::BlendFactor::SRC_ALPHA, vk::BlendFactor::ONE),
+        };
+
+        vk::PipelineColorBlendAttachmentState::builder()
+            .blend_enable(self != BlendState::Opaque)
+            .src_color_blend_factor(src_color)
+            .dst_color_blend_factor(dst_color)
+            .color_blend_op(vk::BlendOp::ADD)
+            .src_alpha_blend_factor(vk::BlendFactor::ONE)
+            .dst_alpha_blend_factor(dst_color)
+            .alpha_blend_op(vk::BlendOp::ADD)
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .build()
+    }
+}
+
+/// The state needed to create a graphics pipeline.
+///
+/// The defaults describe a pipeline drawing opaque triangle lists into a single color attachment
+/// with no depth testing, with the viewport and scissor set dynamically (see
+/// `CommandBuffer::set_viewport_for`).
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct GraphicsPipelineCreateInfo {
+    /// The vertex shader.
+    pub vertex_shader: vk::ShaderModule,
+    /// The fragment shader, if any.
+    pub fragment_shader: Option<vk::ShaderModule>,
+    /// The geometry shader, if any, e.g. to select the layer or viewport on devices which can't
+    /// from the vertex shader. See `Device::layer_output`.
+    pub geometry_shader: Option<vk::ShaderModule>,
+    /// The name of the entry point of every shader.
+    pub entry_point: String,
+    /// The pipeline layout.
+    pub layout: vk::PipelineLayout,
+    /// The render pass the pipeline will be used in, or null for a pipeline used with
+    /// `CommandBuffer::begin_rendering`, whose attachments are given by `color_formats` and
+    /// `depth_stencil_format` instead.
+    pub render_pass: vk::RenderPass,
+    /// The subpass of `render_pass` the pipeline will be used in.
+    pub subpass: u32,
+    /// The vertex buffer bindings.
+    pub vertex_bindings: Vec<VertexBinding>,
+    /// The vertex attributes.
+    pub vertex_attributes: Vec<VertexAttribute>,
+    /// The primitive topology.
+    pub topology: vk::PrimitiveTopology,
+    /// How polygons are rasterized.
+    pub polygon_mode: vk::PolygonMode,
+    /// Which faces are culled.
+    pub cull_mode: vk::CullModeFlags,
+    /// Which winding order is front facing.
+    pub front_face: vk::FrontFace,
+    /// The number of samples of the render pass's attachments.
+    pub samples: vk::SampleCountFlags,
+    /// Whether fragments are tested against the depth attachment.
+    pub depth_test: bool,
+    /// Whether fragments write to the depth attachment.
+    pub depth_write: bool,
+    /// The comparison used for depth testing.
+    pub depth_compare_op: vk::CompareOp,
+    /// The blend state of each color attachment.
+    pub blend_states: Vec<BlendState>,
+    /// The states which are set dynamically when recording.
+    pub dynamic_states: Vec<vk::DynamicState>,
+    /// The number of viewports and scissors, which shaders select between by writing the
+    /// viewport index. More than one needs `Device::max_viewports`.
+    pub viewport_count: u32,
+    /// The formats of the color attachments, if `render_pass` is null.
+    pub color_formats: Vec<vk::Format>,
+    /// The format of the depth stencil attachment, if `render_pass` is null.
+    pub depth_stencil_format: Option<vk::Format>,
+}
+
+impl Default for GraphicsPipelineCreateInfo {
+    fn default() -> Self {
+        GraphicsPipelineCreateInfo {
+            vertex_shader: vk::ShaderModule::null(),
+            fragment_shader: None,
+            geometry_shader: None,
+            entry_point: String::from("main"),
+            layout: vk::PipelineLayout::null(),
+            render_pass: vk::RenderPass::null(),
+            subpass: 0,
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::NONE,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            samples: vk::SampleCountFlags::TYPE_1,
+            depth_test: false,
+            depth_write: false,
+            depth_compare_op: vk::CompareOp::LESS_OR_EQUAL,
+            blend_states: vec![BlendState::Opaque],
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+            viewport_count: 1,
+            color_formats: Vec::new(),
+            depth_stencil_format: None,
+        }
+    }
+}
+
+/// A cache of graphics pipelines, keyed by the full state used to create them, so that each
+/// distinct pipeline is only created once.
+///
+/// The Device owns one, which is used through `Device::request_graphics_pipeline`. Pipelines are
+/// created through the Device's `vk::PipelineCache`, which can be persisted to disk between runs
+/// with `Device::save_pipeline_cache`.
+#[derive(Debug, Default)]
+pub struct PipelineCache {
+    graphics: HashMap<GraphicsPipelineCreateInfo, vk::Pipeline>,
+}
+
+impl PipelineCache {
+    /// Get a previously created pipeline with the given state.
+    pub fn get_graphics(&self, create_info: &GraphicsPipelineCreateInfo) -> Option<vk::Pipeline> {
+        self.graphics.get(create_info).copied()
+    }
+
+    /// Get the number of cached pipelines.
+    pub fn len(&self) -> usize {
+        self.graphics.len()
+    }
+
+    /// Get whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.graphics.is_empty()
+    }
+
+    /// Iterate over the create infos of the cached pipelines.
+    pub(crate) fn graphics_keys(&self) -> impl Iterator<Item = &GraphicsPipelineCreateInfo> {
+        self.graphics.keys()
+    }
+
+    /// Remove every pipeline which uses `shader`, returning them so they can be destroyed.
+    fn evict_shader(&mut self, shader: vk::ShaderModule) -> Vec<vk::Pipeline> {
+        let mut evicted = Vec::new();
+        self.graphics.retain(|create_info, &mut pipeline| {
+            let uses = create_info.vertex_shader == shader
+                || create_info.fragment_shader == Some(shader)
+                || create_info.geometry_shader == Some(shader);
+            if uses {
+                evicted.push(pipeline);
+            }
+            !uses
+        });
+        evicted
+    }
+}
+
+impl Device {
+    /// Create a shader module from SPIR-V code.
+    pub fn create_shader_module(&self, code: &[u32]) -> VkResult<vk::ShaderModule> {
+        let create_info = vk::ShaderModuleCreateInfo::builder().code(code);
+        unsafe { self.device.create_shader_module(&create_info, None) }
+    }
+
+    /// Destroy a shader module created with `create_shader_module`, along with every cached
+    /// pipeline which uses it.
+    ///
+    /// # Safety
+    ///
+    /// The pipelines using the shader must not be in use by the GPU.
+    pub unsafe fn destroy_shader_module(&self, shader: vk::ShaderModule) {
+        for pipeline in self.pipelines.write().evict_shader(shader) {
+            self.device.destroy_pipeline(pipeline, None);
+        }
+        self.device.destroy_shader_module(shader, None);
+    }
+
+    /// Get a read-only handle to this Device's cache of pipelines.
+    pub fn pipelines(&self) -> parking_lot::RwLockReadGuard<'_, PipelineCache> {
+        self.pipelines.read()
+    }
+
+    /// Get a graphics pipeline with the given state, creating it if an identical one has not
+    /// been requested before.
+    pub fn request_graphics_pipeline(
+        &self,
+        create_info: &GraphicsPipelineCreateInfo,
+    ) -> VkResult<vk::Pipeline> {
+        if let Some(pipeline) = self.pipelines.read().get_graphics(create_info) {
+            self.cache_counters.count(CacheKind::Pipeline, true);
+            return Ok(pipeline);
+        }
+        self.cache_counters.count(CacheKind::Pipeline, false);
+
+        let pipeline = self.create_graphics_pipeline(create_info)?;
+
+        let mut pipelines = self.pipelines.write();
+        match pipelines.graphics.get(create_info) {
+            // Another thread created the same pipeline in the meantime.
+            Some(&existing) => {
+                unsafe { self.device.destroy_pipeline(pipeline, None) };
+                Ok(existing)
+            }
+            None => {
+                pipelines.graphics.insert(create_info.clone(), pipeline);
+                Ok(pipeline)
+            }
+        }
+    }
+
+    /// Destroy every cached pipeline.
+    ///
+    /// # Safety
+    ///
+    /// None of the pipelines may be in use by the GPU.
+    pub unsafe fn clear_pipelines(&self) {
+        for (_, pipeline) in self.pipelines.write().graphics.drain() {
+            self.device.destroy_pipeline(pipeline, None);
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    fn create_graphics_pipeline(&self, info: &GraphicsPipelineCreateInfo) -> VkResult<vk::Pipeline> {
+        assert!(
+            info.viewport_count >= 1 && info.viewport_count <= self.max_viewports(),
+            "the device supports between 1 and {} viewports",
+            self.max_viewports()
+        );
+        assert!(
+            info.geometry_shader.is_none() || self.enabled_features.geometry_shader == vk::TRUE,
+            "the device does not support geometry shaders, see Device::layer_output"
+        );
+        if let Some(subset) = self.portability_subset() {
+            assert!(
+                info.topology != vk::PrimitiveTopology::TRIANGLE_FAN || subset.triangle_fans,
+                "the device's portability subset does not support triangle fans"
+            );
+            assert!(
+                info.polygon_mode != vk::PolygonMode::POINT || subset.point_polygons,
+                "the device's portability subset does not support point polygons"
+            );
+        }
+
+        let entry_point = CString::new(info.entry_point.as_str())
+            .expect("shader entry point contains a nul byte");
+
+        let mut stages = vec![vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::VERTEX)
+            .module(info.vertex_shader)
+            .name(&entry_point)
+            .build()];
+        if let Some(geometry_shader) = info.geometry_shader {
+            stages.push(
+                vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(vk::ShaderStageFlags::GEOMETRY)
+                    .module(geometry_shader)
+                    .name(&entry_point)
+                    .build(),
+            );
+        }
+        if let Some(fragment_shader) = info.fragment_shader {
+            stages.push(
+                vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(vk::ShaderStageFlags::FRAGMENT)
+                    .module(fragment_shader)
+                    .name(&entry_point)
+                    .build(),
+            );
+        }
+
+        let bindings = info
+            .vertex_bindings
+            .iter()
+            .map(|binding| vk::VertexInputBindingDescription {
+                binding: binding.binding,
+                stride: binding.stride,
+                input_rate: binding.input_rate,
+            })
+            .collect::<Vec<_>>();
+        let attributes = info
+            .vertex_attributes
+            .iter()
+            .map(|attribute| vk::VertexInputAttributeDescription {
+                location: attribute.location,
+                binding: attribute.binding,
+                format: attribute.format,
+                offset: attribute.offset,
+            })
+            .collect::<Vec<_>>();
+        let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&bindings)
+            .vertex_attribute_descriptions(&attributes);
+
+        let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .topology(info.topology);
+
+        // The viewport and scissor are normally dynamic, in which case only their counts matter.
+        let viewports = vec![vk::Viewport::default(); info.viewport_count as usize];
+        let scissors = vec![vk::Rect2D::default(); info.viewport_count as usize];
+        let viewport = vk::PipelineViewportStateCreateInfo::builder()
+            .viewports(&viewports)
+            .scissors(&scissors);
+
+        let rasterization = vk::PipelineRasterizationStateCreateInfo::builder()
+            .polygon_mode(info.polygon_mode)
+            .cull_mode(info.cull_mode)
+            .front_face(info.front_face)
+            .line_width(1.0);
+
+        let multisample = vk::PipelineMultisampleStateCreateInfo::builder()
+            .rasterization_samples(info.samples);
+
+        let depth_stencil = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(info.depth_test)
+            .depth_write_enable(info.depth_write)
+            .depth_compare_op(info.depth_compare_op);
+
+        let attachments = info
+            .blend_states
+            .iter()
+            .map(|blend| blend.attachment_state())
+            .collect::<Vec<_>>();
+        let color_blend = vk::PipelineColorBlendStateCreateInfo::builder().attachments(&attachments);
+
+        let dynamic = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&info.dynamic_states);
+
+        // A pipeline for `begin_rendering` either describes its attachments directly, or without
+        // dynamic rendering uses a render pass compatible with those `begin_rendering` falls back
+        // to.
+        let rendering_info = PipelineRenderingCreateInfo::new(&info.color_formats, info.depth_stencil_format);
+        let render_pass = if info.render_pass != vk::RenderPass::null() || self.has_dynamic_rendering() {
+            info.render_pass
+        } else {
+            let attachment = |format| RenderPassAttachment {
+                samples: info.samples,
+                ..RenderPassAttachment::clear_store(format)
+            };
+            self.request_render_pass(
+                &RenderPassInfo {
+                    color_attachments: info.color_formats.iter().copied().map(attachment).collect(),
+                    depth_stencil_attachment: info.depth_stencil_format.map(attachment),
+                    resolve_attachments: Vec::new(),
+                }
+                .compatible(),
+            )?
+        };
+
+        let mut create_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input)
+            .input_assembly_state(&input_assembly)
+            .viewport_state(&viewport)
+            .rasterization_state(&rasterization)
+            .multisample_state(&multisample)
+            .depth_stencil_state(&depth_stencil)
+            .color_blend_state(&color_blend)
+            .dynamic_state(&dynamic)
+            .layout(info.layout)
+            .render_pass(render_pass)
+            .subpass(info.subpass)
+            .build();
+        if render_pass == vk::RenderPass::null() {
+            create_info.p_next = &rendering_info as *const _ as *const c_void;
+        }
+
+        let pipelines = unsafe {
+            self.device
+                .create_graphics_pipelines(self.pipeline_cache, &[create_info], None)
+        }
+        .map_err(|(_, e)| {
+            self.check_vk_result(e);
+            e
+        })?;
+
+        Ok(pipelines[0])
+    }
+}
+
+/// A compute pipeline together with the layout it was created with.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ComputePipeline {
+    pub(crate) raw: vk::Pipeline,
+    pub(crate) layout: vk::PipelineLayout,
+}
+
+impl ComputePipeline {
+    /// The raw `vk::Pipeline`.
+    pub fn raw(&self) -> vk::Pipeline {
+        self.raw
+    }
+
+    /// The layout the pipeline was created with.
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+}
+
+impl Device {
+    /// Create a compute pipeline from a shader with a `main` entry point.
+    ///
+    /// Unlike graphics pipelines, compute pipelines are not cached by the Device and must be
+    /// destroyed with `destroy_compute_pipeline`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn create_compute_pipeline(
+        &self,
+        shader: vk::ShaderModule,
+        layout: vk::PipelineLayout,
+    ) -> VkResult<ComputePipeline> {
+        let entry_point = CString::new("main").unwrap();
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(shader)
+            .name(&entry_point);
+        let create_info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage.build())
+            .layout(layout);
+
+        let pipelines = unsafe {
+            self.device
+                .create_compute_pipelines(self.pipeline_cache, &[create_info.build()], None)
+        }
+        .map_err(|(_, e)| {
+            self.check_vk_result(e);
+            e
+        })?;
+
+        Ok(ComputePipeline {
+            raw: pipelines[0],
+            layout,
+        })
+    }
+
+    /// Destroy a compute pipeline created with `create_compute_pipeline`. The layout is not
+    /// destroyed.
+    ///
+    /// # Safety
+    ///
+    /// The pipeline must not be in use by the GPU.
+    pub unsafe fn destroy_compute_pipeline(&self, pipeline: ComputePipeline) {
+        self.device.destroy_pipeline(pipeline.raw, None);
+    }
+}
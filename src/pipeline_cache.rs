@@ -0,0 +1,142 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::path::Path;
+use std::sync::mpsc;
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::*;
+
+/// Size of the `VkPipelineCacheHeaderVersionOne` header at the start of a pipeline cache blob.
+const PIPELINE_CACHE_HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// Get whether `data` is a pipeline cache blob which was created by the device described by
+/// `properties`. Blobs from other devices or driver versions are discarded rather than handed
+/// to the driver.
+pub(crate) fn pipeline_cache_data_matches(
+    data: &[u8],
+    properties: &vk::PhysicalDeviceProperties,
+) -> bool {
+    if data.len() < PIPELINE_CACHE_HEADER_SIZE {
+        return false;
+    }
+
+    let read_u32 = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+
+    read_u32(4) == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && read_u32(8) == properties.vendor_id
+        && read_u32(12) == properties.device_id
+        && data[16..PIPELINE_CACHE_HEADER_SIZE] == properties.pipeline_cache_uuid[..]
+}
+
+/// Create a `vk::PipelineCache`, seeded from the file at `path` if it exists and was created by
+/// the same device and driver.
+pub(crate) fn create_pipeline_cache(
+    device: &ash::Device,
+    properties: &vk::PhysicalDeviceProperties,
+    path: Option<&Path>,
+) -> Result<vk::PipelineCache, vk::Result> {
+    let initial_data = path
+        .and_then(|path| std::fs::read(path).ok())
+        .filter(|data| pipeline_cache_data_matches(data, properties))
+        .unwrap_or_default();
+
+    let create_info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
+
+    unsafe { device.create_pipeline_cache(&create_info, None) }
+}
+
+/// A handle to a background thread which periodically saves the Device's pipeline cache.
+///
+/// Dropping the handle stops the thread, saving the cache one last time.
+pub struct PipelineCacheAutosave {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for PipelineCacheAutosave {
+    fn drop(&mut self) {
+        // Dropping the sender wakes the thread up and tells it to stop.
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Device {
+    /// Get the raw `vk::PipelineCache` used for all pipelines created by this Device.
+    pub fn raw_pipeline_cache(&self) -> vk::PipelineCache {
+        self.pipeline_cache
+    }
+
+    /// Save the pipeline cache to the path set with `DeviceBuilder::pipeline_cache_path`, if any.
+    ///
+    /// The cache is written to a temporary file which then replaces the old one, so a crash
+    /// during the save never leaves a truncated cache behind.
+    pub fn save_pipeline_cache(&self) -> std::io::Result<()> {
+        let path = match self.pipeline_cache_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+
+        let data = unsafe { self.device.get_pipeline_cache_data(self.pipeline_cache) }
+            .map_err(|e| std::io::Error::other(format!("could not get pipeline cache data: {}", e)))?;
+
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, &data)?;
+        std::fs::rename(&tmp_path, path)
+    }
+
+    /// Start saving the pipeline cache every `interval` on a background thread, so that a crash
+    /// during a long session doesn't lose the warmed cache. The thread stops when the returned
+    /// handle is dropped or when the Device is destroyed.
+    pub fn autosave_pipeline_cache(self: Arc<Self>, interval: Duration) -> PipelineCacheAutosave {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let device: Weak<Device> = Arc::downgrade(&self);
+
+        let thread = std::thread::Builder::new()
+            .name(String::from("hot pipeline cache autosave"))
+            .spawn(move || loop {
+                let stop = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                );
+
+                let device = match device.upgrade() {
+                    Some(device) => device,
+                    None => return,
+                };
+
+                if let Err(e) = device.save_pipeline_cache() {
+                    log::warn!("hot: failed to autosave pipeline cache: {}", e);
+                }
+
+                if stop {
+                    return;
+                }
+            })
+            .expect("failed to spawn pipeline cache autosave thread");
+
+        PipelineCacheAutosave {
+            stop: Some(stop),
+            thread: Some(thread),
+        }
+    }
+
+    /// Inspect the result of a Vulkan call made by `hot`. If the device was lost, the pipeline
+    /// cache is saved immediately, since the process is likely about to go down.
+    pub(crate) fn check_vk_result(&self, result: vk::Result) {
+        if result == vk::Result::ERROR_DEVICE_LOST {
+            if let Err(e) = self.save_pipeline_cache() {
+                log::warn!("hot: failed to save pipeline cache after device loss: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+use ash::version::{DeviceV1_0, InstanceV1_1};
+use ash::vk;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_portability_subset` and
+// `VK_KHR_portability_enumeration`, so the parts of them hot uses are declared here.
+
+/// The name of the `VK_KHR_portability_enumeration` instance extension.
+pub(crate) fn portability_enumeration_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_portability_enumeration\0").unwrap()
+}
+
+/// The name of the `VK_KHR_portability_subset` device extension.
+pub(crate) fn portability_subset_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_portability_subset\0").unwrap()
+}
+
+/// `VK_INSTANCE_CREATE_ENUMERATE_PORTABILITY_BIT_KHR`, without which loaders don't list
+/// portability implementations among the physical devices.
+pub(crate) fn instance_create_enumerate_portability() -> vk::InstanceCreateFlags {
+    vk::InstanceCreateFlags::from_raw(0x1)
+}
+
+#[repr(C)]
+pub(crate) struct PhysicalDevicePortabilitySubsetFeatures {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    constant_alpha_color_blend_factors: vk::Bool32,
+    events: vk::Bool32,
+    image_view_format_reinterpretation: vk::Bool32,
+    image_view_format_swizzle: vk::Bool32,
+    image_view2_d_on3_d_image: vk::Bool32,
+    multisample_array_image: vk::Bool32,
+    mutable_comparison_samplers: vk::Bool32,
+    point_polygons: vk::Bool32,
+    sampler_mip_lod_bias: vk::Bool32,
+    separate_stencil_mask_ref: vk::Bool32,
+    shader_sample_rate_interpolation_functions: vk::Bool32,
+    tessellation_isolines: vk::Bool32,
+    tessellation_point_mode: vk::Bool32,
+    triangle_fans: vk::Bool32,
+    vertex_attribute_access_beyond_stride: vk::Bool32,
+}
+
+impl Default for PhysicalDevicePortabilitySubsetFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1_000_163_000),
+            p_next: std::ptr::null_mut(),
+            constant_alpha_color_blend_factors: vk::FALSE,
+            events: vk::FALSE,
+            image_view_format_reinterpretation: vk::FALSE,
+            image_view_format_swizzle: vk::FALSE,
+            image_view2_d_on3_d_image: vk::FALSE,
+            multisample_array_image: vk::FALSE,
+            mutable_comparison_samplers: vk::FALSE,
+            point_polygons: vk::FALSE,
+            sampler_mip_lod_bias: vk::FALSE,
+            separate_stencil_mask_ref: vk::FALSE,
+            shader_sample_rate_interpolation_functions: vk::FALSE,
+            tessellation_isolines: vk::FALSE,
+            tessellation_point_mode: vk::FALSE,
+            triangle_fans: vk::FALSE,
+            vertex_attribute_access_beyond_stride: vk::FALSE,
+        }
+    }
+}
+
+/// The features of full Vulkan which a portability implementation, such as MoltenVK on macOS
+/// and iOS, may lack, as reported by `VK_KHR_portability_subset`. Each is true if it is
+/// supported.
+///
+/// Core features such as geometry shaders, wide lines and anisotropic filtering are reported
+/// as usual, and those hot uses are enabled whenever supported.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct PortabilitySubset {
+    /// Whether blend factors may use the constant alpha as a color.
+    pub constant_alpha_color_blend_factors: bool,
+    /// Whether events are supported.
+    pub events: bool,
+    /// Whether image views may reinterpret the image's format with a different texel layout.
+    pub image_view_format_reinterpretation: bool,
+    /// Whether image views may swizzle components.
+    pub image_view_format_swizzle: bool,
+    /// Whether 2D image views may be created of 3D images.
+    pub image_view_2d_on_3d_image: bool,
+    /// Whether multisampled images may have more than one layer.
+    pub multisample_array_image: bool,
+    /// Whether descriptors with comparison samplers may be updated.
+    pub mutable_comparison_samplers: bool,
+    /// Whether polygons may be rasterized as points.
+    pub point_polygons: bool,
+    /// Whether samplers may have a mip LOD bias.
+    pub sampler_mip_lod_bias: bool,
+    /// Whether front and back faces may have different stencil masks and references.
+    pub separate_stencil_mask_ref: bool,
+    /// Whether shaders may use the `InterpolationFunction` capability.
+    pub shader_sample_rate_interpolation_functions: bool,
+    /// Whether tessellation may produce isolines.
+    pub tessellation_isolines: bool,
+    /// Whether tessellation may produce points.
+    pub tessellation_point_mode: bool,
+    /// Whether the `TRIANGLE_FAN` topology is supported.
+    pub triangle_fans: bool,
+    /// Whether vertex attributes may be read beyond the stride of their binding.
+    pub vertex_attribute_access_beyond_stride: bool,
+}
+
+impl PortabilitySubset {
+    fn from_raw(raw: &PhysicalDevicePortabilitySubsetFeatures) -> Self {
+        Self {
+            constant_alpha_color_blend_factors: raw.constant_alpha_color_blend_factors == vk::TRUE,
+            events: raw.events == vk::TRUE,
+            image_view_format_reinterpretation: raw.image_view_format_reinterpretation == vk::TRUE,
+            image_view_format_swizzle: raw.image_view_format_swizzle == vk::TRUE,
+            image_view_2d_on_3d_image: raw.image_view2_d_on3_d_image == vk::TRUE,
+            multisample_array_image: raw.multisample_array_image == vk::TRUE,
+            mutable_comparison_samplers: raw.mutable_comparison_samplers == vk::TRUE,
+            point_polygons: raw.point_polygons == vk::TRUE,
+            sampler_mip_lod_bias: raw.sampler_mip_lod_bias == vk::TRUE,
+            separate_stencil_mask_ref: raw.separate_stencil_mask_ref == vk::TRUE,
+            shader_sample_rate_interpolation_functions: raw.shader_sample_rate_interpolation_functions
+                == vk::TRUE,
+            tessellation_isolines: raw.tessellation_isolines == vk::TRUE,
+            tessellation_point_mode: raw.tessellation_point_mode == vk::TRUE,
+            triangle_fans: raw.triangle_fans == vk::TRUE,
+            vertex_attribute_access_beyond_stride: raw.vertex_attribute_access_beyond_stride == vk::TRUE,
+        }
+    }
+
+    /// Get the features struct which enables every supported feature.
+    pub(crate) fn features(&self) -> PhysicalDevicePortabilitySubsetFeatures {
+        let bool32 = |supported: bool| if supported { vk::TRUE } else { vk::FALSE };
+        PhysicalDevicePortabilitySubsetFeatures {
+            constant_alpha_color_blend_factors: bool32(self.constant_alpha_color_blend_factors),
+            events: bool32(self.events),
+            image_view_format_reinterpretation: bool32(self.image_view_format_reinterpretation),
+            image_view_format_swizzle: bool32(self.image_view_format_swizzle),
+            image_view2_d_on3_d_image: bool32(self.image_view_2d_on_3d_image),
+            multisample_array_image: bool32(self.multisample_array_image),
+            mutable_comparison_samplers: bool32(self.mutable_comparison_samplers),
+            point_polygons: bool32(self.point_polygons),
+            sampler_mip_lod_bias: bool32(self.sampler_mip_lod_bias),
+            separate_stencil_mask_ref: bool32(self.separate_stencil_mask_ref),
+            shader_sample_rate_interpolation_functions: bool32(
+                self.shader_sample_rate_interpolation_functions,
+            ),
+            tessellation_isolines: bool32(self.tessellation_isolines),
+            tessellation_point_mode: bool32(self.tessellation_point_mode),
+            triangle_fans: bool32(self.triangle_fans),
+            vertex_attribute_access_beyond_stride: bool32(self.vertex_attribute_access_beyond_stride),
+            ..Default::default()
+        }
+    }
+}
+
+/// Get the portability subset features of a physical device. The instance must be at least
+/// version 1.1.
+pub(crate) fn query_portability_subset(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> PortabilitySubset {
+    let mut subset_features = PhysicalDevicePortabilitySubsetFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut subset_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    PortabilitySubset::from_raw(&subset_features)
+}
+
+impl Device {
+    /// Get the features of `VK_KHR_portability_subset` if the Device is a portability
+    /// implementation, such as MoltenVK, or `None` if it implements full Vulkan.
+    pub fn portability_subset(&self) -> Option<&PortabilitySubset> {
+        self.portability_subset.as_ref()
+    }
+
+    /// Get the widest line `CommandBuffer::set_line_width` can set, which is one unless the
+    /// device supports wide lines.
+    pub fn max_line_width(&self) -> f32 {
+        if self.enabled_features.wide_lines == vk::TRUE {
+            self.device_properties.limits.line_width_range[1]
+        } else {
+            1.0
+        }
+    }
+
+    /// Get the greatest `SamplerInfo::max_anisotropy` samplers use, which is one, i.e.
+    /// anisotropic filtering is off, unless the device supports it.
+    pub fn max_sampler_anisotropy(&self) -> f32 {
+        if self.enabled_features.sampler_anisotropy == vk::TRUE {
+            self.device_properties.limits.max_sampler_anisotropy
+        } else {
+            1.0
+        }
+    }
+}
+
+impl CommandBuffer {
+    /// Set the width of rasterized lines, for pipelines with the `LINE_WIDTH` dynamic state.
+    ///
+    /// The width is clamped to the range the device supports, so lines are one pixel wide on
+    /// devices without wide lines, such as MoltenVK. See `Device::max_line_width`.
+    pub fn set_line_width(&mut self, width: f32) {
+        let min = self.device().device_properties.limits.line_width_range[0].min(1.0);
+        let width = width.max(min).min(self.device().max_line_width());
+        unsafe { self.device().cmd_set_line_width(self.raw(), width) };
+        self.inherited.line_width = Some(width);
+    }
+}
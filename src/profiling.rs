@@ -0,0 +1,239 @@
+//! Zones of graphics command buffers, such as a shadow pass, are timed by recording them between
+//! `CommandBuffer::begin_gpu_zone` and `end_gpu_zone`, once profiling is enabled with
+//! `DeviceBuilder::gpu_profiling`.
+//!
+//! Each frame slot has a pool of timestamp queries, which `begin_frame` resets with a batch
+//! enqueued on the graphics queue ahead of the frame's work. Once the frame's fence has
+//! signaled, the next `begin_frame` for its slot reads the timestamps back and
+//! `Device::collect_gpu_timings` returns them.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use crate::*;
+
+/// The number of zones which can be timed in each frame. Zones begun past the limit are not
+/// timed.
+pub const GPU_ZONES_PER_FRAME: usize = 512;
+
+/// The GPU time taken by a zone of a completed frame.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GpuZoneTiming {
+    /// The name given to `CommandBuffer::begin_gpu_zone`.
+    pub name: String,
+    /// How many zones of the same command buffer enclose this one.
+    pub depth: usize,
+    /// The milliseconds from the start of the earliest zone of the frame to the start of this one.
+    pub start_ms: f64,
+    /// The milliseconds between the start and end of the zone.
+    pub duration_ms: f64,
+}
+
+/// A zone begun during a frame, whose begin and end timestamps are queries `2 * i` and
+/// `2 * i + 1` of the frame's pool, where `i` is its index in `PerFrame::gpu_zones`.
+pub(crate) struct GpuZone {
+    name: String,
+    depth: usize,
+}
+
+/// The timestamp query pools of each frame slot, which live as long as the Device.
+pub(crate) struct GpuProfiler {
+    pools: Vec<vk::QueryPool>,
+    /// The mask of the valid bits of the graphics queue's timestamps.
+    timestamp_mask: u64,
+    /// The nanoseconds per timestamp tick.
+    timestamp_period: f64,
+}
+
+impl GpuProfiler {
+    /// Create a query pool for each of `frames_in_flight`, for a graphics queue whose timestamps
+    /// have `timestamp_valid_bits` valid bits.
+    pub(crate) fn new(
+        device: &ash::Device,
+        properties: &vk::PhysicalDeviceProperties,
+        timestamp_valid_bits: u32,
+        frames_in_flight: usize,
+    ) -> Result<Self, DeviceCreationError> {
+        let pool_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count((GPU_ZONES_PER_FRAME * 2) as u32);
+        let mut pools = Vec::with_capacity(frames_in_flight);
+        for _ in 0..frames_in_flight {
+            pools.push(unsafe { device.create_query_pool(&pool_info, None)? });
+        }
+
+        let timestamp_mask = match timestamp_valid_bits {
+            64 => u64::MAX,
+            bits => (1 << bits) - 1,
+        };
+
+        Ok(Self {
+            pools,
+            timestamp_mask,
+            timestamp_period: properties.limits.timestamp_period as f64,
+        })
+    }
+
+    /// Read the timestamps of `zones` from the pool of frame `frame_index`, skipping the zones
+    /// which were never ended or whose command buffers were never submitted.
+    ///
+    /// The frame must have completed on the GPU.
+    fn resolve(&self, device: &ash::Device, frame_index: usize, zones: &[GpuZone]) -> Vec<GpuZoneTiming> {
+        if zones.is_empty() {
+            return Vec::new();
+        }
+
+        // Each query is read as its timestamp followed by whether it is available.
+        let mut results = vec![[0u64; 2]; zones.len() * 2];
+        let result = unsafe {
+            device.fp_v1_0().get_query_pool_results(
+                device.handle(),
+                self.pools[frame_index],
+                0,
+                results.len() as u32,
+                std::mem::size_of_val(&results[..]),
+                results.as_mut_ptr() as *mut _,
+                std::mem::size_of::<[u64; 2]>() as vk::DeviceSize,
+                vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WITH_AVAILABILITY,
+            )
+        };
+        // `NOT_READY` only means that some zones are unavailable.
+        if result != vk::Result::SUCCESS && result != vk::Result::NOT_READY {
+            log::warn!("hot: failed to read GPU zone timestamps: {}", result);
+            return Vec::new();
+        }
+
+        let timed = zones
+            .iter()
+            .zip(results.chunks_exact(2))
+            .filter(|(_, queries)| queries[0][1] != 0 && queries[1][1] != 0)
+            .map(|(zone, queries)| (zone, queries[0][0], queries[1][0]))
+            .collect::<Vec<_>>();
+        let origin = match timed.iter().map(|&(_, begin, _)| begin).min() {
+            Some(origin) => origin,
+            None => return Vec::new(),
+        };
+
+        let to_ms = |ticks: u64| (ticks & self.timestamp_mask) as f64 * self.timestamp_period / 1_000_000.0;
+        timed
+            .into_iter()
+            .map(|(zone, begin, end)| GpuZoneTiming {
+                name: zone.name.clone(),
+                depth: zone.depth,
+                start_ms: to_ms(begin.wrapping_sub(origin)),
+                duration_ms: to_ms(end.wrapping_sub(begin)),
+            })
+            .collect()
+    }
+}
+
+impl Device {
+    /// Whether zones are timed, i.e. whether profiling was enabled with
+    /// `DeviceBuilder::gpu_profiling` and the graphics queue supports timestamps.
+    pub fn gpu_profiling_enabled(&self) -> bool {
+        self.gpu_profiler.is_some()
+    }
+
+    /// Take the timings of the zones of the most recently completed frame, or `None` if no
+    /// frame has completed since the last call.
+    ///
+    /// Frames are read back by `begin_frame` once their fence has signaled, so the timings lag
+    /// `frames_in_flight` frames behind the frame being recorded.
+    pub fn collect_gpu_timings(&self) -> Option<Vec<GpuZoneTiming>> {
+        self.gpu_timings.lock().take()
+    }
+
+    /// Read back the zones of the completed frame `frame_index`, which is locked as `frame`.
+    pub(crate) fn resolve_gpu_zones(&self, frame_index: usize, frame: &mut PerFrame) {
+        let zones = std::mem::take(&mut frame.gpu_zones);
+        if let Some(ref profiler) = self.gpu_profiler {
+            *self.gpu_timings.lock() = Some(profiler.resolve(&self.device, frame_index, &zones));
+        }
+    }
+
+    /// Enqueue a batch on the graphics queue which resets the query pool of the current frame,
+    /// ahead of any work of the frame which writes timestamps to it.
+    pub(crate) fn reset_gpu_timestamps(&self) -> Result<(), vk::Result> {
+        let profiler = match self.gpu_profiler {
+            Some(ref profiler) => profiler,
+            None => return Ok(()),
+        };
+
+        let cmd = self.request_raw_command_buffer(QueueType::Graphics)?;
+        unsafe {
+            let pool = profiler.pools[self.current_frame_index()];
+            self.device.cmd_reset_query_pool(cmd, pool, 0, (GPU_ZONES_PER_FRAME * 2) as u32);
+            self.device.end_command_buffer(cmd)?;
+        }
+        self.enqueue_raw_command_buffer(QueueType::Graphics, cmd);
+        Ok(())
+    }
+}
+
+impl CommandBuffer {
+    /// Begin a zone named `name`, which is timed from when the GPU starts the commands recorded
+    /// after this until it finishes those recorded before the matching `end_gpu_zone`. Zones may
+    /// nest.
+    ///
+    /// Zones are only timed on `Graphics` command buffers, and only when profiling was enabled
+    /// with `DeviceBuilder::gpu_profiling`. Otherwise this does nothing, besides requiring an
+    /// `end_gpu_zone` all the same.
+    pub fn begin_gpu_zone(&mut self, name: &str) {
+        let device = self.device().clone();
+        let profiler = match device.gpu_profiler {
+            Some(ref profiler) if self.queue_type() == QueueType::Graphics => profiler,
+            _ => {
+                self.gpu_zones.push(None);
+                return;
+            }
+        };
+
+        let frame_index = device.current_frame_index();
+        let zone = {
+            let mut frame = device.per_frame[frame_index].write();
+            if frame.gpu_zones.len() < GPU_ZONES_PER_FRAME {
+                frame.gpu_zones.push(GpuZone {
+                    name: name.to_owned(),
+                    depth: self.gpu_zones.len(),
+                });
+                Some(frame.gpu_zones.len() - 1)
+            } else {
+                None
+            }
+        };
+
+        if let Some(zone) = zone {
+            unsafe {
+                device.device.cmd_write_timestamp(
+                    self.raw(),
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    profiler.pools[frame_index],
+                    (zone * 2) as u32,
+                )
+            };
+        }
+        self.gpu_zones.push(zone.map(|zone| (frame_index, zone)));
+    }
+
+    /// End the innermost zone begun with `begin_gpu_zone`.
+    ///
+    /// # Panics
+    /// Panics if there is no zone to end.
+    pub fn end_gpu_zone(&mut self) {
+        let zone = self
+            .gpu_zones
+            .pop()
+            .expect("end_gpu_zone called without a matching begin_gpu_zone");
+
+        if let (Some((frame_index, zone)), Some(ref profiler)) = (zone, &self.device().gpu_profiler) {
+            unsafe {
+                self.device().device.cmd_write_timestamp(
+                    self.raw(),
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    profiler.pools[frame_index],
+                    (zone * 2 + 1) as u32,
+                )
+            };
+        }
+    }
+}
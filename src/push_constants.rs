@@ -0,0 +1,170 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use bytemuck::Pod;
+
+use thiserror::Error;
+
+use std::marker::PhantomData;
+
+use crate::*;
+
+/// An error that could occur while pushing constants with `CommandBuffer::push_constants`.
+#[derive(Error, Debug)]
+pub enum PushConstantError {
+    /// The offset or size of the constants is not a multiple of 4.
+    #[error("push constants at offset {offset} of {size} bytes are not 4 byte aligned")]
+    Misaligned {
+        /// The offset of the constants in bytes.
+        offset: u32,
+        /// The size of the constants in bytes.
+        size: u32,
+    },
+    /// No push constant range of the layout covers the constants.
+    #[error("no push constant range covers {size} bytes at offset {offset}")]
+    NotCovered {
+        /// The offset of the constants in bytes.
+        offset: u32,
+        /// The size of the constants in bytes.
+        size: u32,
+    },
+    /// A stage the constants are pushed for has a range which only partly covers them.
+    #[error("the push constant range of {stage:?} doesn't cover {size} bytes at offset {offset}")]
+    PartlyCovered {
+        /// The stage whose range falls short.
+        stage: vk::ShaderStageFlags,
+        /// The offset of the constants in bytes.
+        offset: u32,
+        /// The size of the constants in bytes.
+        size: u32,
+    },
+}
+
+/// Push constants of type `T` at an offset of a pipeline layout, pushed for every stage whose
+/// push constant range overlaps them.
+///
+/// Created once from the layout's `ShaderLayout` and used with `CommandBuffer::push_constants`,
+/// which checks `T` against the layout's ranges before recording.
+pub struct PushConstants<T: Pod> {
+    layout: vk::PipelineLayout,
+    ranges: Vec<vk::PushConstantRange>,
+    offset: u32,
+    _marker: PhantomData<fn(&T)>,
+}
+
+impl<T: Pod> Clone for PushConstants<T> {
+    fn clone(&self) -> Self {
+        Self {
+            layout: self.layout,
+            ranges: self.ranges.clone(),
+            offset: self.offset,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Pod> std::fmt::Debug for PushConstants<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PushConstants")
+            .field("layout", &self.layout)
+            .field("offset", &self.offset)
+            .field("size", &Self::size())
+            .field("stages", &self.stages())
+            .finish()
+    }
+}
+
+impl<T: Pod> PushConstants<T> {
+    /// Push constants at the start of the push constant block of `layout`.
+    pub fn new(layout: &ShaderLayout) -> Self {
+        Self::at_offset(layout, 0)
+    }
+
+    /// Push constants `offset` bytes into the push constant block of `layout`.
+    pub fn at_offset(layout: &ShaderLayout, offset: u32) -> Self {
+        Self {
+            layout: layout.raw(),
+            ranges: layout.push_constant_ranges().to_vec(),
+            offset,
+            _marker: PhantomData,
+        }
+    }
+
+    /// The raw layout the constants are pushed to.
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    /// The offset of the constants in bytes.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// The size of the constants in bytes.
+    pub fn size() -> u32 {
+        std::mem::size_of::<T>() as u32
+    }
+
+    /// The stages the constants are pushed for: those with a range overlapping them.
+    pub fn stages(&self) -> vk::ShaderStageFlags {
+        let (start, end) = (self.offset, self.offset + Self::size());
+        self.ranges
+            .iter()
+            .filter(|range| range.offset < end && start < range.offset + range.size)
+            .fold(vk::ShaderStageFlags::empty(), |stages, range| stages | range.stage_flags)
+    }
+
+    /// Check that the constants lie wholly within the range of every stage they are pushed for,
+    /// as `vkCmdPushConstants` requires.
+    pub fn validate(&self) -> Result<(), PushConstantError> {
+        let (offset, size) = (self.offset, Self::size());
+        if offset % 4 != 0 || size % 4 != 0 || size == 0 {
+            return Err(PushConstantError::Misaligned { offset, size });
+        }
+
+        let stages = self.stages();
+        if stages.is_empty() {
+            return Err(PushConstantError::NotCovered { offset, size });
+        }
+        let end = offset + size;
+        for range in &self.ranges {
+            let overlaps = range.offset < end && offset < range.offset + range.size;
+            if overlaps && (range.offset > offset || range.offset + range.size < end) {
+                return Err(PushConstantError::PartlyCovered {
+                    stage: range.stage_flags,
+                    offset,
+                    size,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CommandBuffer {
+    /// Push `data` as the constants described by `push_constants`, for every stage whose range
+    /// of the layout they overlap.
+    ///
+    /// The constants are validated against the layout's ranges first, so a type which doesn't
+    /// match the shaders' push constant blocks is reported instead of recording an invalid
+    /// command.
+    pub fn push_constants<T: Pod>(
+        &mut self,
+        push_constants: &PushConstants<T>,
+        data: &T,
+    ) -> Result<(), PushConstantError> {
+        push_constants.validate()?;
+
+        let bytes = bytemuck::bytes_of(data);
+        unsafe {
+            self.device().device.cmd_push_constants(
+                self.raw(),
+                push_constants.layout,
+                push_constants.stages(),
+                push_constants.offset,
+                bytes,
+            )
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,1154 @@
+use ash::version::{DeviceV1_0, InstanceV1_0, InstanceV1_1};
+use ash::vk;
+use bitflags::bitflags;
+use derivative::Derivative;
+use thiserror::Error;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::Arc;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_acceleration_structure` and
+// `VK_KHR_ray_tracing_pipeline`, so the parts of them hot uses are declared here.
+
+fn structure_type(offset: i32) -> vk::StructureType {
+    vk::StructureType::from_raw(1_000_150_000 + offset)
+}
+
+fn extension_name(name: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(name).unwrap()
+}
+
+/// The names of the extensions enabled for ray tracing: the two it targets, and those they
+/// build on which aren't core in 1.1, other than `VK_KHR_buffer_device_address` which is enabled
+/// whenever it's supported.
+pub(crate) fn ray_tracing_extension_names() -> [&'static CStr; 6] {
+    [
+        extension_name(b"VK_KHR_acceleration_structure\0"),
+        extension_name(b"VK_KHR_ray_tracing_pipeline\0"),
+        extension_name(b"VK_KHR_deferred_host_operations\0"),
+        extension_name(b"VK_EXT_descriptor_indexing\0"),
+        extension_name(b"VK_KHR_spirv_1_4\0"),
+        extension_name(b"VK_KHR_shader_float_controls\0"),
+    ]
+}
+
+fn acceleration_structure_storage_usage() -> vk::BufferUsageFlags {
+    vk::BufferUsageFlags::from_raw(0x0010_0000)
+}
+
+fn build_input_usage() -> vk::BufferUsageFlags {
+    vk::BufferUsageFlags::from_raw(0x0008_0000)
+}
+
+fn compacted_size_query() -> vk::QueryType {
+    vk::QueryType::from_raw(1_000_150_000)
+}
+
+const BUILD_TYPE_DEVICE: i32 = 1;
+const BUILD_MODE_BUILD: i32 = 0;
+const GEOMETRY_TYPE_TRIANGLES: i32 = 0;
+const GEOMETRY_TYPE_AABBS: i32 = 1;
+const GEOMETRY_TYPE_INSTANCES: i32 = 2;
+const COPY_MODE_COMPACT: i32 = 1;
+
+#[repr(C)]
+struct PhysicalDeviceAccelerationStructureFeatures {
+    s_type: vk::StructureType,
+    p_next: *mut c_void,
+    acceleration_structure: vk::Bool32,
+    acceleration_structure_capture_replay: vk::Bool32,
+    acceleration_structure_indirect_build: vk::Bool32,
+    acceleration_structure_host_commands: vk::Bool32,
+    descriptor_binding_acceleration_structure_update_after_bind: vk::Bool32,
+}
+
+#[repr(C)]
+struct PhysicalDeviceRayTracingPipelineFeatures {
+    s_type: vk::StructureType,
+    p_next: *mut c_void,
+    ray_tracing_pipeline: vk::Bool32,
+    ray_tracing_pipeline_shader_group_handle_capture_replay: vk::Bool32,
+    ray_tracing_pipeline_shader_group_handle_capture_replay_mixed: vk::Bool32,
+    ray_tracing_pipeline_trace_rays_indirect: vk::Bool32,
+    ray_traversal_primitive_culling: vk::Bool32,
+}
+
+/// The features ray tracing needs besides `bufferDeviceAddress`, linked together to be queried
+/// or enabled at once.
+pub(crate) struct RayTracingFeatures {
+    acceleration_structure: PhysicalDeviceAccelerationStructureFeatures,
+    ray_tracing_pipeline: PhysicalDeviceRayTracingPipelineFeatures,
+}
+
+impl RayTracingFeatures {
+    /// The features, with each of those needed set to `needed`.
+    pub(crate) fn new(needed: vk::Bool32) -> Self {
+        Self {
+            acceleration_structure: PhysicalDeviceAccelerationStructureFeatures {
+                s_type: structure_type(13),
+                p_next: std::ptr::null_mut(),
+                acceleration_structure: needed,
+                acceleration_structure_capture_replay: vk::FALSE,
+                acceleration_structure_indirect_build: vk::FALSE,
+                acceleration_structure_host_commands: vk::FALSE,
+                descriptor_binding_acceleration_structure_update_after_bind: vk::FALSE,
+            },
+            ray_tracing_pipeline: PhysicalDeviceRayTracingPipelineFeatures {
+                s_type: vk::StructureType::from_raw(1_000_347_000),
+                p_next: std::ptr::null_mut(),
+                ray_tracing_pipeline: needed,
+                ray_tracing_pipeline_shader_group_handle_capture_replay: vk::FALSE,
+                ray_tracing_pipeline_shader_group_handle_capture_replay_mixed: vk::FALSE,
+                ray_tracing_pipeline_trace_rays_indirect: vk::FALSE,
+                ray_traversal_primitive_culling: vk::FALSE,
+            },
+        }
+    }
+
+    /// Link the features in front of `next`, returning the head of the chain. The features
+    /// must not move while the chain is in use.
+    pub(crate) fn chain(&mut self, next: *mut c_void) -> *mut c_void {
+        self.acceleration_structure.p_next = next;
+        self.ray_tracing_pipeline.p_next = &mut self.acceleration_structure as *mut _ as *mut c_void;
+        &mut self.ray_tracing_pipeline as *mut _ as *mut c_void
+    }
+}
+
+/// Get whether a physical device supports the features ray tracing needs besides
+/// `bufferDeviceAddress`. The instance must be at least version 1.1.
+pub(crate) fn supports_ray_tracing(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    let mut supported = RayTracingFeatures::new(vk::FALSE);
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: supported.chain(std::ptr::null_mut()),
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    supported.acceleration_structure.acceleration_structure == vk::TRUE
+        && supported.ray_tracing_pipeline.ray_tracing_pipeline == vk::TRUE
+}
+
+/// A raw `VkAccelerationStructureKHR` handle.
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct RawAccelerationStructure(u64);
+
+impl RawAccelerationStructure {
+    /// The raw value of the handle.
+    pub fn as_raw(self) -> u64 {
+        self.0
+    }
+}
+
+#[repr(C)]
+struct AccelerationStructureCreateInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    create_flags: vk::Flags,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    ty: i32,
+    device_address: vk::DeviceAddress,
+}
+
+// `VkDeviceOrHostAddressConstKHR` is a union of a device address and a host pointer. Only
+// device builds are made, so the fields of that type are declared as device addresses.
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GeometryTrianglesData {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    vertex_format: vk::Format,
+    vertex_data: vk::DeviceAddress,
+    vertex_stride: vk::DeviceSize,
+    max_vertex: u32,
+    index_type: vk::IndexType,
+    index_data: vk::DeviceAddress,
+    transform_data: vk::DeviceAddress,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GeometryAabbsData {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    data: vk::DeviceAddress,
+    stride: vk::DeviceSize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GeometryInstancesData {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    array_of_pointers: vk::Bool32,
+    data: vk::DeviceAddress,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union GeometryData {
+    triangles: GeometryTrianglesData,
+    aabbs: GeometryAabbsData,
+    instances: GeometryInstancesData,
+}
+
+#[repr(C)]
+struct Geometry {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    geometry_type: i32,
+    geometry: GeometryData,
+    flags: vk::Flags,
+}
+
+#[repr(C)]
+struct BuildGeometryInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    ty: i32,
+    flags: vk::Flags,
+    mode: i32,
+    src: RawAccelerationStructure,
+    dst: RawAccelerationStructure,
+    geometry_count: u32,
+    p_geometries: *const Geometry,
+    pp_geometries: *const *const Geometry,
+    scratch_data: vk::DeviceAddress,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct BuildRangeInfo {
+    primitive_count: u32,
+    primitive_offset: u32,
+    first_vertex: u32,
+    transform_offset: u32,
+}
+
+#[repr(C)]
+struct BuildSizesInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    acceleration_structure_size: vk::DeviceSize,
+    update_scratch_size: vk::DeviceSize,
+    build_scratch_size: vk::DeviceSize,
+}
+
+#[repr(C)]
+struct DeviceAddressInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    acceleration_structure: RawAccelerationStructure,
+}
+
+#[repr(C)]
+struct CopyInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    src: RawAccelerationStructure,
+    dst: RawAccelerationStructure,
+    mode: i32,
+}
+
+/// `VkAccelerationStructureInstanceKHR`, as read by the GPU from an instance buffer.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawInstance {
+    transform: [[f32; 4]; 3],
+    custom_index_and_mask: u32,
+    binding_table_offset_and_flags: u32,
+    acceleration_structure_reference: u64,
+}
+
+type VoidFunction = unsafe extern "system" fn() -> c_void;
+type CreateAccelerationStructure = unsafe extern "system" fn(
+    vk::Device,
+    *const AccelerationStructureCreateInfo,
+    *const vk::AllocationCallbacks,
+    *mut RawAccelerationStructure,
+) -> vk::Result;
+type DestroyAccelerationStructure =
+    unsafe extern "system" fn(vk::Device, RawAccelerationStructure, *const vk::AllocationCallbacks);
+type GetBuildSizes =
+    unsafe extern "system" fn(vk::Device, i32, *const BuildGeometryInfo, *const u32, *mut BuildSizesInfo);
+type CmdBuild = unsafe extern "system" fn(
+    vk::CommandBuffer,
+    u32,
+    *const BuildGeometryInfo,
+    *const *const BuildRangeInfo,
+);
+type GetAccelerationStructureAddress =
+    unsafe extern "system" fn(vk::Device, *const DeviceAddressInfo) -> vk::DeviceAddress;
+type CmdWriteProperties = unsafe extern "system" fn(
+    vk::CommandBuffer,
+    u32,
+    *const RawAccelerationStructure,
+    vk::QueryType,
+    vk::QueryPool,
+    u32,
+);
+type CmdCopy = unsafe extern "system" fn(vk::CommandBuffer, *const CopyInfo);
+
+/// The device functions of `VK_KHR_acceleration_structure`.
+#[derive(Clone)]
+pub(crate) struct RayTracingFn {
+    create_acceleration_structure: CreateAccelerationStructure,
+    destroy_acceleration_structure: DestroyAccelerationStructure,
+    get_build_sizes: GetBuildSizes,
+    cmd_build: CmdBuild,
+    get_acceleration_structure_address: GetAccelerationStructureAddress,
+    cmd_write_properties: CmdWriteProperties,
+    cmd_copy: CmdCopy,
+}
+
+impl RayTracingFn {
+    /// Load the functions, returning `None` if any of them is missing.
+    pub(crate) fn load(instance: &ash::Instance, device: &ash::Device) -> Option<Self> {
+        let load = |name: &[u8]| unsafe {
+            instance.get_device_proc_addr(device.handle(), CStr::from_bytes_with_nul(name).unwrap().as_ptr())
+        };
+        unsafe {
+            Some(Self {
+                create_acceleration_structure: std::mem::transmute::<
+                    VoidFunction,
+                    CreateAccelerationStructure,
+                >(load(b"vkCreateAccelerationStructureKHR\0")?),
+                destroy_acceleration_structure: std::mem::transmute::<
+                    VoidFunction,
+                    DestroyAccelerationStructure,
+                >(load(b"vkDestroyAccelerationStructureKHR\0")?),
+                get_build_sizes: std::mem::transmute::<VoidFunction, GetBuildSizes>(load(
+                    b"vkGetAccelerationStructureBuildSizesKHR\0",
+                )?),
+                cmd_build: std::mem::transmute::<VoidFunction, CmdBuild>(load(
+                    b"vkCmdBuildAccelerationStructuresKHR\0",
+                )?),
+                get_acceleration_structure_address: std::mem::transmute::<
+                    VoidFunction,
+                    GetAccelerationStructureAddress,
+                >(load(b"vkGetAccelerationStructureDeviceAddressKHR\0")?),
+                cmd_write_properties: std::mem::transmute::<VoidFunction, CmdWriteProperties>(load(
+                    b"vkCmdWriteAccelerationStructuresPropertiesKHR\0",
+                )?),
+                cmd_copy: std::mem::transmute::<VoidFunction, CmdCopy>(load(
+                    b"vkCmdCopyAccelerationStructureKHR\0",
+                )?),
+            })
+        }
+    }
+}
+
+/// An error that could occur while building or compacting an acceleration structure.
+#[derive(Error, Debug)]
+pub enum RayTracingError {
+    /// The device does not support `VK_KHR_acceleration_structure` and
+    /// `VK_KHR_ray_tracing_pipeline`, which `Tier::Tier2` guarantees.
+    #[error("{0}")]
+    UnsupportedOnTier(#[from] UnsupportedOnTier),
+    /// A buffer handle given as build input is not valid.
+    #[error("invalid buffer handle")]
+    InvalidBuffer,
+    /// A buffer given as build input has no device address, i.e. it wasn't created with
+    /// `Device::create_acceleration_structure_input`.
+    #[error("build input buffer has no device address")]
+    NoDeviceAddress,
+    /// An acceleration structure handle is not valid, or an instance of a top level
+    /// acceleration structure refers to another top level one.
+    #[error("invalid acceleration structure handle")]
+    InvalidAccelerationStructure,
+    /// A bottom level acceleration structure was given both triangle and AABB geometry, which
+    /// can't be mixed.
+    #[error("triangle and AABB geometry can't be mixed in one acceleration structure")]
+    MixedGeometry,
+    /// Compaction was requested of an acceleration structure not built with
+    /// `BuildAccelerationStructureFlags::ALLOW_COMPACTION`.
+    #[error("acceleration structure was not built to allow compaction")]
+    CompactionNotAllowed,
+    /// The compacted size of an acceleration structure isn't known yet, since its build hasn't
+    /// completed on the GPU.
+    #[error("acceleration structure build has not completed")]
+    NotReady,
+    /// Allocating memory failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+bitflags! {
+    /// Options for building an acceleration structure.
+    #[derive(Default)]
+    pub struct BuildAccelerationStructureFlags: u32 {
+        /// Allow the acceleration structure to be compacted with
+        /// `CommandBuffer::compact_acceleration_structure` once it has been built.
+        const ALLOW_COMPACTION = 0b0_0010;
+        /// Prefer a structure which is faster to trace over a faster build.
+        const PREFER_FAST_TRACE = 0b0_0100;
+        /// Prefer a faster build over a structure which is faster to trace.
+        const PREFER_FAST_BUILD = 0b0_1000;
+        /// Prefer using less memory over faster builds and traces.
+        const LOW_MEMORY = 0b1_0000;
+    }
+}
+
+bitflags! {
+    /// Options for the geometry of a bottom level acceleration structure.
+    pub struct GeometryFlags: u32 {
+        /// The geometry doesn't invoke any-hit shaders.
+        const OPAQUE = 0b01;
+        /// The any-hit shader is invoked at most once per primitive of the geometry.
+        const NO_DUPLICATE_ANY_HIT_INVOCATION = 0b10;
+    }
+}
+
+bitflags! {
+    /// Options for an instance in a top level acceleration structure.
+    pub struct GeometryInstanceFlags: u8 {
+        /// Disable face culling for the instance's triangles.
+        const TRIANGLE_FACING_CULL_DISABLE = 0b0001;
+        /// Flip which side of the instance's triangles is the front.
+        const TRIANGLE_FLIP_FACING = 0b0010;
+        /// Treat all of the instance's geometry as opaque.
+        const FORCE_OPAQUE = 0b0100;
+        /// Treat none of the instance's geometry as opaque.
+        const FORCE_NO_OPAQUE = 0b1000;
+    }
+}
+
+/// Whether an acceleration structure contains geometry or instances of other acceleration
+/// structures.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum AccelerationStructureType {
+    /// A top level acceleration structure, containing instances of bottom level ones.
+    TopLevel,
+    /// A bottom level acceleration structure, containing triangles or AABBs.
+    BottomLevel,
+}
+
+impl AccelerationStructureType {
+    fn raw(self) -> i32 {
+        match self {
+            AccelerationStructureType::TopLevel => 0,
+            AccelerationStructureType::BottomLevel => 1,
+        }
+    }
+}
+
+/// An acceleration structure, along with the buffer it is stored in.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct AccelerationStructure {
+    pub(crate) raw: RawAccelerationStructure,
+    pub(crate) ty: AccelerationStructureType,
+    pub(crate) device_address: vk::DeviceAddress,
+    /// The query pool the compacted size is written to, if the structure allows compaction.
+    pub(crate) compaction_query: vk::QueryPool,
+    pub(crate) buffer: Buffer,
+    pub(crate) tag: Option<Tag>,
+    #[derivative(Debug = "ignore")]
+    pub(crate) device: Arc<Device>,
+}
+
+impl AccelerationStructure {
+    /// The raw `VkAccelerationStructureKHR`, for writing descriptors.
+    pub fn raw(&self) -> RawAccelerationStructure {
+        self.raw
+    }
+
+    /// Whether this is a top or bottom level acceleration structure.
+    pub fn ty(&self) -> AccelerationStructureType {
+        self.ty
+    }
+
+    /// The device address of the acceleration structure.
+    pub fn device_address(&self) -> vk::DeviceAddress {
+        self.device_address
+    }
+
+    /// The size of the buffer the acceleration structure is stored in.
+    pub fn size(&self) -> vk::DeviceSize {
+        self.buffer.create_info().size
+    }
+
+    /// The tag the acceleration structure was built with.
+    pub fn tag(&self) -> Option<&Tag> {
+        self.tag.as_ref()
+    }
+}
+
+impl Drop for AccelerationStructure {
+    fn drop(&mut self) {
+        // The functions are always loaded if an acceleration structure could be created.
+        if let Some(ref fns) = self.device.ray_tracing {
+            let device = self.device.raw_device().handle();
+            unsafe { (fns.destroy_acceleration_structure)(device, self.raw, std::ptr::null()) };
+        }
+        if self.compaction_query != vk::QueryPool::null() {
+            unsafe { self.device.raw_device().destroy_query_pool(self.compaction_query, None) };
+        }
+    }
+}
+
+/// Triangle geometry of a bottom level acceleration structure.
+///
+/// The buffers must have been created with `Device::create_acceleration_structure_input`.
+#[derive(Clone, Copy, Debug)]
+pub struct TriangleGeometry {
+    /// The buffer the vertex positions are read from.
+    pub vertex_buffer: BufferHandle,
+    /// The offset in bytes of the first vertex in `vertex_buffer`.
+    pub vertex_offset: vk::DeviceSize,
+    /// The format of the vertex positions, e.g. `R32G32B32_SFLOAT`.
+    pub vertex_format: vk::Format,
+    /// The distance in bytes between consecutive vertices.
+    pub vertex_stride: vk::DeviceSize,
+    /// The highest index of a vertex the triangles may use.
+    pub max_vertex: u32,
+    /// The index buffer, the offset in bytes of the first index in it and the index type, or
+    /// `None` if each three consecutive vertices are a triangle.
+    pub indices: Option<(BufferHandle, vk::DeviceSize, vk::IndexType)>,
+    /// The number of triangles.
+    pub triangle_count: u32,
+    /// Options for the geometry.
+    pub flags: GeometryFlags,
+}
+
+/// Axis-aligned bounding box geometry of a bottom level acceleration structure, for procedural
+/// primitives intersected by an intersection shader.
+///
+/// Each AABB is six floats, the minimum then the maximum corner. The buffer must have been
+/// created with `Device::create_acceleration_structure_input`.
+#[derive(Clone, Copy, Debug)]
+pub struct AabbGeometry {
+    /// The buffer the AABBs are read from.
+    pub buffer: BufferHandle,
+    /// The offset in bytes of the first AABB in `buffer`.
+    pub offset: vk::DeviceSize,
+    /// The distance in bytes between consecutive AABBs, a multiple of 8.
+    pub stride: vk::DeviceSize,
+    /// The number of AABBs.
+    pub count: u32,
+    /// Options for the geometry.
+    pub flags: GeometryFlags,
+}
+
+/// An instance of a bottom level acceleration structure within a top level one.
+#[derive(Clone, Copy, Debug)]
+pub struct AccelerationStructureInstance {
+    /// The bottom level acceleration structure instanced.
+    pub acceleration_structure: AccelerationStructureHandle,
+    /// The transform from the instance's object space to world space, a row-major 3x4 matrix.
+    pub transform: [[f32; 4]; 3],
+    /// The value of `gl_InstanceCustomIndexEXT` for the instance. Only the low 24 bits are used.
+    pub custom_index: u32,
+    /// The mask which a ray's cull mask must share a bit with for the instance to be hit.
+    pub mask: u8,
+    /// The offset of the instance's hit groups in the shader binding table. Only the low 24
+    /// bits are used.
+    pub binding_table_offset: u32,
+    /// Options for the instance.
+    pub flags: GeometryInstanceFlags,
+}
+
+impl AccelerationStructureInstance {
+    /// An instance of `acceleration_structure` with an identity transform, which any ray can
+    /// hit.
+    pub fn new(acceleration_structure: AccelerationStructureHandle) -> Self {
+        Self {
+            acceleration_structure,
+            transform: [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0], [0.0, 0.0, 1.0, 0.0]],
+            custom_index: 0,
+            mask: 0xff,
+            binding_table_offset: 0,
+            flags: GeometryInstanceFlags::empty(),
+        }
+    }
+}
+
+/// Builds a bottom level acceleration structure (BLAS) from triangle or AABB geometry.
+#[derive(Clone, Debug, Default)]
+pub struct BlasBuilder {
+    triangles: Vec<TriangleGeometry>,
+    aabbs: Vec<AabbGeometry>,
+    flags: BuildAccelerationStructureFlags,
+    tag: Option<Tag>,
+}
+
+impl BlasBuilder {
+    /// Create a builder with no geometry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add triangle geometry. A BLAS can't contain both triangles and AABBs.
+    pub fn triangles(mut self, geometry: TriangleGeometry) -> Self {
+        self.triangles.push(geometry);
+        self
+    }
+
+    /// Add AABB geometry. A BLAS can't contain both triangles and AABBs.
+    pub fn aabbs(mut self, geometry: AabbGeometry) -> Self {
+        self.aabbs.push(geometry);
+        self
+    }
+
+    /// Set the options for the build.
+    pub fn flags(mut self, flags: BuildAccelerationStructureFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the tag of the acceleration structure and its buffer.
+    pub fn tag(mut self, tag: Tag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Record the build into `cmd`, returning the new acceleration structure.
+    ///
+    /// The scratch memory of the build belongs to the current frame, so `cmd` must be submitted
+    /// before the frame ends.
+    pub fn build(self, cmd: &mut CommandBuffer) -> Result<AccelerationStructureHandle, RayTracingError> {
+        if !self.triangles.is_empty() && !self.aabbs.is_empty() {
+            return Err(RayTracingError::MixedGeometry);
+        }
+        let device = cmd.device().clone();
+
+        let mut geometries = Vec::with_capacity(self.triangles.len() + self.aabbs.len());
+        let mut ranges = Vec::with_capacity(geometries.capacity());
+        for geometry in &self.triangles {
+            let (index_type, index_data) = match geometry.indices {
+                Some((buffer, offset, index_type)) => {
+                    cmd.transition_buffer(buffer, build_stage(), vk::AccessFlags::SHADER_READ);
+                    (index_type, device.build_input_address(buffer)? + offset)
+                }
+                None => (vk::IndexType::NONE_NV, 0),
+            };
+            cmd.transition_buffer(geometry.vertex_buffer, build_stage(), vk::AccessFlags::SHADER_READ);
+            let triangles = GeometryTrianglesData {
+                s_type: structure_type(5),
+                p_next: std::ptr::null(),
+                vertex_format: geometry.vertex_format,
+                vertex_data: device.build_input_address(geometry.vertex_buffer)? + geometry.vertex_offset,
+                vertex_stride: geometry.vertex_stride,
+                max_vertex: geometry.max_vertex,
+                index_type,
+                index_data,
+                transform_data: 0,
+            };
+            geometries.push(Geometry {
+                s_type: structure_type(6),
+                p_next: std::ptr::null(),
+                geometry_type: GEOMETRY_TYPE_TRIANGLES,
+                geometry: GeometryData { triangles },
+                flags: geometry.flags.bits(),
+            });
+            ranges.push(BuildRangeInfo {
+                primitive_count: geometry.triangle_count,
+                ..Default::default()
+            });
+        }
+        for geometry in &self.aabbs {
+            cmd.transition_buffer(geometry.buffer, build_stage(), vk::AccessFlags::SHADER_READ);
+            let aabbs = GeometryAabbsData {
+                s_type: structure_type(3),
+                p_next: std::ptr::null(),
+                data: device.build_input_address(geometry.buffer)? + geometry.offset,
+                stride: geometry.stride,
+            };
+            geometries.push(Geometry {
+                s_type: structure_type(6),
+                p_next: std::ptr::null(),
+                geometry_type: GEOMETRY_TYPE_AABBS,
+                geometry: GeometryData { aabbs },
+                flags: geometry.flags.bits(),
+            });
+            ranges.push(BuildRangeInfo {
+                primitive_count: geometry.count,
+                ..Default::default()
+            });
+        }
+
+        device.build_acceleration_structure(
+            cmd,
+            AccelerationStructureType::BottomLevel,
+            &geometries,
+            &ranges,
+            self.flags,
+            self.tag,
+        )
+    }
+}
+
+/// Builds a top level acceleration structure (TLAS) from instances of bottom level ones.
+#[derive(Clone, Debug, Default)]
+pub struct TlasBuilder {
+    instances: Vec<AccelerationStructureInstance>,
+    flags: BuildAccelerationStructureFlags,
+    tag: Option<Tag>,
+}
+
+impl TlasBuilder {
+    /// Create a builder with no instances.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an instance of a bottom level acceleration structure.
+    pub fn instance(mut self, instance: AccelerationStructureInstance) -> Self {
+        self.instances.push(instance);
+        self
+    }
+
+    /// Add several instances of bottom level acceleration structures.
+    pub fn instances<I: IntoIterator<Item = AccelerationStructureInstance>>(mut self, instances: I) -> Self {
+        self.instances.extend(instances);
+        self
+    }
+
+    /// Set the options for the build.
+    pub fn flags(mut self, flags: BuildAccelerationStructureFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Set the tag of the acceleration structure and its buffer.
+    pub fn tag(mut self, tag: Tag) -> Self {
+        self.tag = Some(tag);
+        self
+    }
+
+    /// Record the build into `cmd`, returning the new acceleration structure. The bottom level
+    /// acceleration structures instanced must have been built before, in `cmd` or work
+    /// submitted earlier.
+    ///
+    /// The instances are written to a buffer which, like the scratch memory of the build,
+    /// belongs to the current frame, so `cmd` must be submitted before the frame ends.
+    pub fn build(self, cmd: &mut CommandBuffer) -> Result<AccelerationStructureHandle, RayTracingError> {
+        let device = cmd.device().clone();
+
+        let raw_instances = {
+            let resources = device.resources();
+            self.instances
+                .iter()
+                .map(|instance| {
+                    let blas = resources
+                        .get_acceleration_structure(instance.acceleration_structure)
+                        .filter(|blas| blas.ty == AccelerationStructureType::BottomLevel)
+                        .ok_or(RayTracingError::InvalidAccelerationStructure)?;
+                    Ok(RawInstance {
+                        transform: instance.transform,
+                        custom_index_and_mask: (instance.custom_index & 0xff_ffff)
+                            | (u32::from(instance.mask) << 24),
+                        binding_table_offset_and_flags: (instance.binding_table_offset & 0xff_ffff)
+                            | (u32::from(instance.flags.bits()) << 24),
+                        acceleration_structure_reference: blas.device_address,
+                    })
+                })
+                .collect::<Result<Vec<_>, RayTracingError>>()?
+        };
+
+        let size = (raw_instances.len().max(1) * std::mem::size_of::<RawInstance>()) as vk::DeviceSize;
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Host,
+            size,
+            usage: build_input_usage(),
+            wants_device_address: true,
+        };
+        let tag = Tag::Static("acceleration structure instances");
+        let (instance_buffer, instance_data) = device.create_build_buffer(create_info, Some(tag))?;
+        device.write_build_buffer(instance_buffer, &raw_instances);
+        device.destroy_buffer(instance_buffer);
+
+        let instances = GeometryInstancesData {
+            s_type: structure_type(4),
+            p_next: std::ptr::null(),
+            array_of_pointers: vk::FALSE,
+            data: instance_data,
+        };
+        let geometry = Geometry {
+            s_type: structure_type(6),
+            p_next: std::ptr::null(),
+            geometry_type: GEOMETRY_TYPE_INSTANCES,
+            geometry: GeometryData { instances },
+            flags: 0,
+        };
+        let range = BuildRangeInfo {
+            primitive_count: raw_instances.len() as u32,
+            ..Default::default()
+        };
+
+        device.build_acceleration_structure(
+            cmd,
+            AccelerationStructureType::TopLevel,
+            &[geometry],
+            &[range],
+            self.flags,
+            self.tag,
+        )
+    }
+}
+
+/// The stage acceleration structures are built in, whose value is shared by the NV and KHR
+/// extensions.
+fn build_stage() -> vk::PipelineStageFlags {
+    vk::PipelineStageFlags::ACCELERATION_STRUCTURE_BUILD_NV
+}
+
+impl Device {
+    /// Whether acceleration structures can be built, i.e. whether `VK_KHR_acceleration_structure`
+    /// and `VK_KHR_ray_tracing_pipeline` are enabled.
+    pub fn supports_ray_tracing(&self) -> bool {
+        self.ray_tracing.is_some()
+    }
+
+    /// Create a `Host` domain buffer holding `data`, which can be used as the vertex, index or
+    /// AABB buffer of a `TriangleGeometry` or `AabbGeometry`.
+    ///
+    /// The buffer has a device address, which the acceleration structure build reads through.
+    /// `usage` is added to the usage the build needs, e.g. so that shaders can also read the
+    /// vertices.
+    pub fn create_acceleration_structure_input(
+        self: &Arc<Self>,
+        data: &[u8],
+        usage: vk::BufferUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<BufferHandle, RayTracingError> {
+        self.check_deterministic_thread("create_acceleration_structure_input");
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Host,
+            size: data.len().max(1) as vk::DeviceSize,
+            usage: usage | build_input_usage(),
+            wants_device_address: true,
+        };
+        let (buffer, _) = self.create_build_buffer(create_info, tag)?;
+        self.write_build_buffer(buffer, data);
+        Ok(buffer)
+    }
+
+    /// Destroy the acceleration structure referred to by `acceleration_structure`.
+    ///
+    /// As with `destroy_buffer`, it is freed once the current frame has completed.
+    pub fn destroy_acceleration_structure(&self, acceleration_structure: AccelerationStructureHandle) {
+        self.current_frame()
+            .write()
+            .destroyed_acceleration_structures
+            .push(acceleration_structure);
+    }
+
+    /// Get the size `acceleration_structure` compacts to, or `None` if its build hasn't
+    /// completed on the GPU yet.
+    pub fn compacted_size(
+        &self,
+        acceleration_structure: AccelerationStructureHandle,
+    ) -> Result<Option<vk::DeviceSize>, RayTracingError> {
+        let query_pool = self
+            .resources()
+            .get_acceleration_structure(acceleration_structure)
+            .ok_or(RayTracingError::InvalidAccelerationStructure)?
+            .compaction_query;
+        if query_pool == vk::QueryPool::null() {
+            return Err(RayTracingError::CompactionNotAllowed);
+        }
+
+        let mut size = [0u64];
+        let result = unsafe {
+            self.device
+                .get_query_pool_results(query_pool, 0, 1, &mut size, vk::QueryResultFlags::TYPE_64)
+        };
+        match result {
+            Ok(()) => Ok(Some(size[0])),
+            Err(vk::Result::NOT_READY) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Free the acceleration structures destroyed during a frame which has completed.
+    pub(crate) fn free_acceleration_structures(&self, frame: &mut PerFrame) {
+        if frame.destroyed_acceleration_structures.is_empty() {
+            return;
+        }
+
+        let mut resources = self.resources_mut();
+        for acceleration_structure in frame.destroyed_acceleration_structures.drain(..) {
+            resources.acceleration_structures.remove(acceleration_structure.idx);
+        }
+    }
+
+    fn ray_tracing_fns(&self) -> Result<&RayTracingFn, RayTracingError> {
+        self.ray_tracing
+            .as_ref()
+            .ok_or_else(|| self.unsupported_on_tier("ray tracing", Tier::Tier2).into())
+    }
+
+    /// Get the device address of a buffer used as build input.
+    fn build_input_address(&self, buffer: BufferHandle) -> Result<vk::DeviceAddress, RayTracingError> {
+        let resources = self.resources();
+        let buffer = resources.get_buffer(buffer).ok_or(RayTracingError::InvalidBuffer)?;
+        buffer.device_address().ok_or(RayTracingError::NoDeviceAddress)
+    }
+
+    /// Create a buffer the build reads or writes through its device address, and get the
+    /// address.
+    fn create_build_buffer(
+        self: &Arc<Self>,
+        create_info: BufferCreateInfo,
+        tag: Option<Tag>,
+    ) -> Result<(BufferHandle, vk::DeviceAddress), RayTracingError> {
+        self.ray_tracing_fns()?;
+        let buffer = self.clone().create_buffer::<()>(create_info, tag, None)?;
+        match self.build_input_address(buffer) {
+            Ok(device_address) => Ok((buffer, device_address)),
+            Err(e) => {
+                self.destroy_buffer(buffer);
+                Err(e)
+            }
+        }
+    }
+
+    /// Copy `data` into the mapped memory of a `Host` domain build buffer, which is host
+    /// coherent since its memory is a dedicated allocation.
+    fn write_build_buffer<T: Copy>(&self, buffer: BufferHandle, data: &[T]) {
+        let mut resources = self.resources_mut();
+        let mapped = resources
+            .get_buffer_mut(buffer)
+            .and_then(Buffer::mapped_data)
+            .expect("build buffer is not mapped");
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), mapped.as_ptr() as *mut T, data.len()) };
+    }
+
+    /// Create an acceleration structure and record its build from `geometries` into `cmd`.
+    fn build_acceleration_structure(
+        self: &Arc<Self>,
+        cmd: &mut CommandBuffer,
+        ty: AccelerationStructureType,
+        geometries: &[Geometry],
+        ranges: &[BuildRangeInfo],
+        flags: BuildAccelerationStructureFlags,
+        tag: Option<Tag>,
+    ) -> Result<AccelerationStructureHandle, RayTracingError> {
+        self.check_deterministic_thread("build_acceleration_structure");
+        let fns = self.ray_tracing_fns()?;
+
+        let mut build_info = BuildGeometryInfo {
+            s_type: structure_type(0),
+            p_next: std::ptr::null(),
+            ty: ty.raw(),
+            flags: flags.bits(),
+            mode: BUILD_MODE_BUILD,
+            src: RawAccelerationStructure::default(),
+            dst: RawAccelerationStructure::default(),
+            geometry_count: geometries.len() as u32,
+            p_geometries: geometries.as_ptr(),
+            pp_geometries: std::ptr::null(),
+            scratch_data: 0,
+        };
+        let primitive_counts = ranges.iter().map(|range| range.primitive_count).collect::<Vec<_>>();
+        let mut sizes = BuildSizesInfo {
+            s_type: structure_type(20),
+            p_next: std::ptr::null(),
+            acceleration_structure_size: 0,
+            update_scratch_size: 0,
+            build_scratch_size: 0,
+        };
+        unsafe {
+            (fns.get_build_sizes)(
+                self.device.handle(),
+                BUILD_TYPE_DEVICE,
+                &build_info,
+                primitive_counts.as_ptr(),
+                &mut sizes,
+            )
+        };
+
+        let handle = self.create_acceleration_structure(
+            ty,
+            sizes.acceleration_structure_size,
+            flags.contains(BuildAccelerationStructureFlags::ALLOW_COMPACTION),
+            tag,
+        )?;
+        let (raw, compaction_query) = {
+            let resources = self.resources();
+            let acceleration_structure = resources.get_acceleration_structure(handle).unwrap();
+            (acceleration_structure.raw, acceleration_structure.compaction_query)
+        };
+
+        let scratch_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size: sizes.build_scratch_size.max(1),
+            usage: vk::BufferUsageFlags::STORAGE_BUFFER,
+            wants_device_address: true,
+        };
+        let scratch_tag = Tag::Static("acceleration structure scratch");
+        let (scratch, scratch_address) = match self.create_build_buffer(scratch_info, Some(scratch_tag)) {
+            Ok(scratch) => scratch,
+            Err(e) => {
+                self.destroy_acceleration_structure(handle);
+                return Err(e);
+            }
+        };
+        build_info.dst = raw;
+        build_info.scratch_data = scratch_address;
+        self.destroy_buffer(scratch);
+
+        let p_ranges = ranges.as_ptr();
+        unsafe {
+            if compaction_query != vk::QueryPool::null() {
+                self.device.cmd_reset_query_pool(cmd.raw(), compaction_query, 0, 1);
+            }
+            (fns.cmd_build)(cmd.raw(), 1, &build_info, &p_ranges);
+
+            // The compacted size query reads the structure too.
+            self.cmd_acceleration_structure_barrier(cmd.raw());
+
+            if compaction_query != vk::QueryPool::null() {
+                (fns.cmd_write_properties)(cmd.raw(), 1, &raw, compacted_size_query(), compaction_query, 0);
+            }
+        }
+
+        Ok(handle)
+    }
+
+    /// Record a barrier making acceleration structure writes visible to later builds and to
+    /// shaders tracing rays.
+    unsafe fn cmd_acceleration_structure_barrier(&self, cmd: vk::CommandBuffer) {
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_WRITE_NV)
+            .dst_access_mask(vk::AccessFlags::ACCELERATION_STRUCTURE_READ_NV);
+        self.device.cmd_pipeline_barrier(
+            cmd,
+            build_stage(),
+            build_stage()
+                | vk::PipelineStageFlags::RAY_TRACING_SHADER_NV
+                | vk::PipelineStageFlags::COMPUTE_SHADER
+                | vk::PipelineStageFlags::VERTEX_SHADER
+                | vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::DependencyFlags::empty(),
+            &[*barrier],
+            &[],
+            &[],
+        );
+    }
+
+    /// Create an acceleration structure of `size` bytes, with a buffer to store it in.
+    fn create_acceleration_structure(
+        self: &Arc<Self>,
+        ty: AccelerationStructureType,
+        size: vk::DeviceSize,
+        allow_compaction: bool,
+        tag: Option<Tag>,
+    ) -> Result<AccelerationStructureHandle, RayTracingError> {
+        let fns = self.ray_tracing_fns()?;
+
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Device,
+            size,
+            usage: acceleration_structure_storage_usage(),
+            wants_device_address: false,
+        };
+        let mut queue_family_indices = [0u32; 3];
+        let buffer_info = self.raw_buffer_create_info(create_info, &mut queue_family_indices);
+        let alloc_desc = self.allocation_desc_from_buffer_create_info(create_info);
+        let (buffer, allocation) = self.allocator.create_buffer(&buffer_info, &alloc_desc)?;
+        let buffer = unsafe {
+            Buffer::new(self.clone(), buffer, allocation, create_info, MemoryCategory::Buffers, tag.clone())
+        };
+
+        let info = AccelerationStructureCreateInfo {
+            s_type: structure_type(17),
+            p_next: std::ptr::null(),
+            create_flags: 0,
+            buffer: buffer.raw(),
+            offset: 0,
+            size,
+            ty: ty.raw(),
+            device_address: 0,
+        };
+        let mut raw = RawAccelerationStructure::default();
+        let result = unsafe {
+            (fns.create_acceleration_structure)(self.device.handle(), &info, std::ptr::null(), &mut raw)
+        };
+        if result != vk::Result::SUCCESS {
+            return Err(result.into());
+        }
+
+        let address_info = DeviceAddressInfo {
+            s_type: structure_type(2),
+            p_next: std::ptr::null(),
+            acceleration_structure: raw,
+        };
+        let device_address =
+            unsafe { (fns.get_acceleration_structure_address)(self.device.handle(), &address_info) };
+
+        let mut acceleration_structure = AccelerationStructure {
+            raw,
+            ty,
+            device_address,
+            compaction_query: vk::QueryPool::null(),
+            buffer,
+            tag,
+            device: self.clone(),
+        };
+        if allow_compaction {
+            let query_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(compacted_size_query())
+                .query_count(1);
+            let query_pool = unsafe { self.device.create_query_pool(&query_info, None)? };
+            acceleration_structure.compaction_query = query_pool;
+        }
+
+        Ok(AccelerationStructureHandle::new(
+            self.resources.write().acceleration_structures.insert(acceleration_structure),
+        ))
+    }
+}
+
+impl CommandBuffer {
+    /// Record a copy of `acceleration_structure` into a new acceleration structure of its
+    /// compacted size, returning the new one. The original is destroyed once the current frame
+    /// has completed, so `cmd` must be submitted before the frame ends.
+    ///
+    /// It must have been built with `BuildAccelerationStructureFlags::ALLOW_COMPACTION`, and
+    /// the build must have completed on the GPU, i.e. `Device::compacted_size` returns `Some`;
+    /// if it hasn't, `RayTracingError::NotReady` is returned.
+    pub fn compact_acceleration_structure(
+        &mut self,
+        acceleration_structure: AccelerationStructureHandle,
+    ) -> Result<AccelerationStructureHandle, RayTracingError> {
+        let device = self.device().clone();
+        let size = device
+            .compacted_size(acceleration_structure)?
+            .ok_or(RayTracingError::NotReady)?;
+        let (src, ty, tag) = {
+            let resources = device.resources();
+            let src = resources
+                .get_acceleration_structure(acceleration_structure)
+                .ok_or(RayTracingError::InvalidAccelerationStructure)?;
+            (src.raw, src.ty, src.tag.clone())
+        };
+
+        let compacted = device.create_acceleration_structure(ty, size, false, tag)?;
+        let dst = device.resources().get_acceleration_structure(compacted).unwrap().raw;
+
+        let copy_info = CopyInfo {
+            s_type: structure_type(10),
+            p_next: std::ptr::null(),
+            src,
+            dst,
+            mode: COPY_MODE_COMPACT,
+        };
+        unsafe {
+            (device.ray_tracing_fns()?.cmd_copy)(self.raw(), &copy_info);
+            device.cmd_acceleration_structure_barrier(self.raw());
+        }
+
+        device.destroy_acceleration_structure(acceleration_structure);
+        Ok(compacted)
+    }
+}
@@ -0,0 +1,457 @@
+use ash::vk;
+
+use thiserror::Error;
+
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::{
+    format_has_depth_or_stencil_aspect, format_is_srgb, format_layer_size, format_to_aspect_mask,
+};
+
+/// An error that could occur while reading a resource back to the host.
+#[derive(Error, Debug)]
+pub enum ReadbackError {
+    /// The resource to read has been destroyed.
+    #[error("the resource to read back has been destroyed")]
+    InvalidResource,
+    /// The range or region to read is not within the resource.
+    #[error("the range to read back is out of bounds")]
+    OutOfBounds,
+    /// The image's format has no known texel size, or is a combined depth stencil format.
+    #[error("images of format {0:?} cannot be read back")]
+    UnsupportedFormat(vk::Format),
+    /// The readback buffer could not be allocated or its memory could not be invalidated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A region of an image to read back with `Device::read_image`.
+#[derive(Clone, Copy, Debug)]
+pub struct ImageRegion {
+    /// The mip level to read.
+    pub mip_level: u32,
+    /// The first array layer to read.
+    pub base_array_layer: u32,
+    /// The number of array layers to read.
+    pub layer_count: u32,
+    /// The offset of the region within the mip level, in texels.
+    pub offset: vk::Offset3D,
+    /// The size of the region, in texels.
+    pub extent: vk::Extent3D,
+}
+
+impl ImageRegion {
+    /// The whole of every layer of a mip level of an image created with `create_info`.
+    pub fn mip_level(create_info: &ImageCreateInfo, mip_level: u32) -> Self {
+        let extent = |size: usize| (size as u32 >> mip_level).max(1);
+        Self {
+            mip_level,
+            base_array_layer: 0,
+            layer_count: create_info.layers as u32,
+            offset: vk::Offset3D::default(),
+            extent: vk::Extent3D {
+                width: extent(create_info.width),
+                height: extent(create_info.height),
+                depth: extent(create_info.depth.max(1)),
+            },
+        }
+    }
+}
+
+/// Texels read back from an image by `Device::read_image`.
+#[derive(Clone, Debug)]
+pub struct ImageData {
+    /// The format of the texels.
+    pub format: vk::Format,
+    /// The size of the region that was read, in texels.
+    pub extent: vk::Extent3D,
+    /// The number of layers that were read.
+    pub layers: u32,
+    /// The texels, with rows, depth slices and then layers tightly packed.
+    pub data: Vec<u8>,
+}
+
+/// The pixels of an image captured by `Device::capture_image`, e.g. for a screenshot or to
+/// compare against a golden image.
+#[derive(Clone, Debug)]
+pub struct ScreenCapture {
+    /// The width of the image in pixels.
+    pub width: u32,
+    /// The height of the image in pixels.
+    pub height: u32,
+    /// The format of the pixels: `R8G8B8A8_SRGB` if the image was sRGB encoded, or
+    /// `R8G8B8A8_UNORM` otherwise.
+    pub format: vk::Format,
+    /// The pixels, four bytes each, with rows tightly packed from the top of the image.
+    pub pixels: Vec<u8>,
+}
+
+/// The result of a readback, which becomes available once the GPU has finished the copy.
+///
+/// Dropping the ReadbackFuture before it has resolved discards the result.
+pub struct ReadbackFuture<T> {
+    fence: Fence,
+    buffer: Option<BufferHandle>,
+    size: usize,
+    finish: Option<Box<dyn FnOnce(Vec<u8>) -> T + Send + Sync>>,
+    device: Arc<Device>,
+}
+
+impl<T> ReadbackFuture<T> {
+    /// Get whether the copy has completed, i.e. whether `try_take` will return the result.
+    pub fn is_ready(&self) -> Result<bool, vk::Result> {
+        self.fence.is_signaled()
+    }
+
+    /// Take the result if the copy has completed, or give the future back if it hasn't.
+    pub fn try_take(self) -> Result<Result<T, Self>, ReadbackError> {
+        if self.is_ready()? {
+            self.take().map(Ok)
+        } else {
+            Ok(Err(self))
+        }
+    }
+
+    /// Wait up to `timeout` nanoseconds for the copy to complete, then take the result, or give
+    /// the future back if the copy still hasn't completed.
+    pub fn wait(self, timeout: u64) -> Result<Result<T, Self>, ReadbackError> {
+        if self.fence.wait(timeout)? {
+            self.take().map(Ok)
+        } else {
+            Ok(Err(self))
+        }
+    }
+
+    /// Copy the result out of the readback buffer. The copy must have completed.
+    fn take(mut self) -> Result<T, ReadbackError> {
+        let handle = self.buffer.take().expect("ReadbackFuture taken twice");
+        let data = {
+            let resources = self.device.resources();
+            let buffer = resources
+                .get_buffer(handle)
+                .expect("ReadbackFuture: readback buffer was destroyed");
+            let allocation = buffer.allocation().expect("ReadbackFuture: readback buffer has no allocation");
+            // A no-op for coherent memory.
+            self.device.allocator().invalidate_allocation(allocation, 0, self.size)?;
+            let mapped = buffer
+                .mapped_data
+                .expect("ReadbackFuture: readback buffer is not mapped");
+            unsafe { std::slice::from_raw_parts(mapped.as_ptr(), self.size) }.to_vec()
+        };
+        self.device.destroy_buffer(handle);
+
+        let finish = self.finish.take().expect("ReadbackFuture taken twice");
+        Ok(finish(data))
+    }
+}
+
+impl<T> Drop for ReadbackFuture<T> {
+    fn drop(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            // The copy may still be in flight, which the deferred destruction waits for.
+            self.device.destroy_buffer(buffer);
+        }
+    }
+}
+
+impl<T> std::fmt::Debug for ReadbackFuture<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReadbackFuture")
+            .field("fence", &self.fence)
+            .field("buffer", &self.buffer)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+/// The semaphores synchronizing the capture of a swapchain image with presenting it.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PresentCapture {
+    /// The semaphore signaled once the image has been rendered.
+    pub(crate) wait: vk::Semaphore,
+    /// The semaphore presentation waits on instead, signaled once the image has been copied.
+    pub(crate) signal: vk::Semaphore,
+}
+
+impl Device {
+    /// Read `range` bytes of `buffer` back to the host.
+    ///
+    /// The copy is submitted to the graphics queue immediately, along with any other pending
+    /// graphics batches, so it sees the results of all graphics work submitted before it.
+    pub fn read_buffer(
+        self: &Arc<Self>,
+        buffer: BufferHandle,
+        range: Range<vk::DeviceSize>,
+    ) -> Result<ReadbackFuture<Vec<u8>>, ReadbackError> {
+        let buffer_size = match self.resources().get_buffer(buffer) {
+            Some(buffer) => buffer.create_info().size,
+            None => return Err(ReadbackError::InvalidResource),
+        };
+        if range.start > range.end || range.end > buffer_size {
+            return Err(ReadbackError::OutOfBounds);
+        }
+
+        let size = range.end - range.start;
+        self.readback(size, Box::new(|data| data), None, |cmd, dst| {
+            cmd.copy_buffer(dst, 0, buffer, range.start, size);
+        })
+    }
+
+    /// Read a region of `image` back to the host, with its texels tightly packed.
+    ///
+    /// The image is transitioned to `TRANSFER_SRC_OPTIMAL` for the copy. As with `read_buffer`,
+    /// the copy is submitted to the graphics queue immediately. Depth stencil images can't be
+    /// read, since each aspect would need a separate copy.
+    pub fn read_image(
+        self: &Arc<Self>,
+        image: ImageHandle,
+        region: ImageRegion,
+    ) -> Result<ReadbackFuture<ImageData>, ReadbackError> {
+        let create_info = match self.resources().get_image(image) {
+            Some(image) => image.create_info(),
+            None => return Err(ReadbackError::InvalidResource),
+        };
+
+        let format = create_info.format;
+        let aspect_mask = format_to_aspect_mask(format);
+        if aspect_mask == vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL {
+            return Err(ReadbackError::UnsupportedFormat(format));
+        }
+        let layer_size = format_layer_size(
+            format,
+            region.extent.width,
+            region.extent.height,
+            region.extent.depth,
+        )
+        .ok_or(ReadbackError::UnsupportedFormat(format))?;
+
+        let level = ImageRegion::mip_level(&create_info, region.mip_level);
+        let fits = |offset: i32, size: u32, level_size: u32| {
+            offset >= 0 && offset as u64 + size as u64 <= level_size as u64
+        };
+        if region.mip_level as usize >= create_info.levels
+            || region.base_array_layer as u64 + region.layer_count as u64 > create_info.layers as u64
+            || !fits(region.offset.x, region.extent.width, level.extent.width)
+            || !fits(region.offset.y, region.extent.height, level.extent.height)
+            || !fits(region.offset.z, region.extent.depth, level.extent.depth)
+        {
+            return Err(ReadbackError::OutOfBounds);
+        }
+
+        let size = layer_size * region.layer_count as u64;
+        let (extent, layers) = (region.extent, region.layer_count);
+        let finish = Box::new(move |data| ImageData {
+            format,
+            extent,
+            layers,
+            data,
+        });
+        self.readback(size, finish, None, |cmd, dst| {
+            let copy = vk::BufferImageCopy {
+                buffer_offset: 0,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask,
+                    mip_level: region.mip_level,
+                    base_array_layer: region.base_array_layer,
+                    layer_count: region.layer_count,
+                },
+                image_offset: region.offset,
+                image_extent: region.extent,
+            };
+            cmd.copy_image_to_buffer(dst, image, &[copy]);
+        })
+    }
+
+    /// Capture the first mip level and layer of `image` as 8-bit RGBA pixels, waiting for the
+    /// copy to complete.
+    ///
+    /// Images of other color formats, such as a `B8G8R8A8` swapchain image or an HDR target, are
+    /// converted by blitting into a temporary RGBA image, which needs their format to support
+    /// `BLIT_SRC`. sRGB images are captured as `R8G8B8A8_SRGB`, keeping their encoded values.
+    /// As with `read_image`, the copy is submitted to the graphics queue, and the image must
+    /// have `TRANSFER_SRC` usage.
+    pub fn capture_image(self: &Arc<Self>, image: ImageHandle) -> Result<ScreenCapture, ReadbackError> {
+        let (future, converted) = self.capture_image_async(image, None)?;
+        let capture = wait_for_capture(future);
+        if let Some(converted) = converted {
+            self.destroy_image(converted);
+        }
+        capture
+    }
+
+    /// Capture `image` as `capture_image` does, without waiting for the copy. If `present` is
+    /// given, the copy is synchronized with presenting the image, which is transitioned back to
+    /// `PRESENT_SRC_KHR` afterwards.
+    ///
+    /// Also returns the temporary image the capture was converted through, if any, which must be
+    /// destroyed once the capture has resolved.
+    pub(crate) fn capture_image_async(
+        self: &Arc<Self>,
+        image: ImageHandle,
+        present: Option<PresentCapture>,
+    ) -> Result<(ReadbackFuture<ScreenCapture>, Option<ImageHandle>), ReadbackError> {
+        let create_info = match self.resources().get_image(image) {
+            Some(image) => image.create_info(),
+            None => return Err(ReadbackError::InvalidResource),
+        };
+        let (width, height) = (create_info.width as u32, create_info.height as u32);
+        let src_format = create_info.format;
+        let format = if format_is_srgb(src_format) {
+            vk::Format::R8G8B8A8_SRGB
+        } else {
+            vk::Format::R8G8B8A8_UNORM
+        };
+        let size = width as vk::DeviceSize * height as vk::DeviceSize * 4;
+        let finish = move |pixels| ScreenCapture {
+            width,
+            height,
+            format,
+            pixels,
+        };
+
+        let subresource = vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: 0,
+            layer_count: 1,
+        };
+        let copy = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: subresource,
+            image_offset: vk::Offset3D::default(),
+            image_extent: vk::Extent3D { width, height, depth: 1 },
+        };
+        let restore_layout = |cmd: &mut CommandBuffer| {
+            if present.is_some() {
+                cmd.transition_image(
+                    image,
+                    vk::ImageLayout::PRESENT_SRC_KHR,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::AccessFlags::empty(),
+                );
+            }
+        };
+
+        if src_format == format {
+            let future = self.readback(size, Box::new(finish), present, |cmd, dst| {
+                cmd.copy_image_to_buffer(dst, image, &[copy]);
+                restore_layout(cmd);
+            })?;
+            return Ok((future, None));
+        }
+        if format_has_depth_or_stencil_aspect(src_format)
+            || !self
+                .format_features(src_format)
+                .contains(vk::FormatFeatureFlags::BLIT_SRC)
+        {
+            return Err(ReadbackError::UnsupportedFormat(src_format));
+        }
+
+        let converted_info = ImageCreateInfo {
+            width: width as usize,
+            height: height as usize,
+            depth: 1,
+            format,
+            image_type: vk::ImageType::TYPE_2D,
+            usage: vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST,
+            initial_layout: vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            ..Default::default()
+        };
+        let converted = self
+            .clone()
+            .create_image(converted_info, None, Some(Tag::Static("capture conversion image")))?;
+
+        let future = self.readback(size, Box::new(finish), present, |cmd, dst| {
+            let corner = vk::Offset3D {
+                x: width as i32,
+                y: height as i32,
+                z: 1,
+            };
+            let blit = vk::ImageBlit {
+                src_subresource: subresource,
+                src_offsets: [vk::Offset3D::default(), corner],
+                dst_subresource: subresource,
+                dst_offsets: [vk::Offset3D::default(), corner],
+            };
+            cmd.blit_image(converted, image, &[blit], vk::Filter::NEAREST);
+            cmd.copy_image_to_buffer(dst, converted, &[copy]);
+            restore_layout(cmd);
+        });
+        match future {
+            Ok(future) => Ok((future, Some(converted))),
+            Err(e) => {
+                self.destroy_image(converted);
+                Err(e)
+            }
+        }
+    }
+
+    /// Allocate a readback buffer of `size` bytes, record a copy into it with `record` and
+    /// submit it to the graphics queue, synchronized with presentation if `present` is given.
+    fn readback<T>(
+        self: &Arc<Self>,
+        size: vk::DeviceSize,
+        finish: Box<dyn FnOnce(Vec<u8>) -> T + Send + Sync>,
+        present: Option<PresentCapture>,
+        record: impl FnOnce(&mut CommandBuffer, BufferHandle),
+    ) -> Result<ReadbackFuture<T>, ReadbackError> {
+        let create_info = BufferCreateInfo {
+            domain: BufferUsageDomain::Readback,
+            size: size.max(1),
+            usage: vk::BufferUsageFlags::TRANSFER_DST,
+            wants_device_address: false,
+        };
+        let buffer = self
+            .clone()
+            .create_buffer::<()>(create_info, Some(Tag::Static("readback buffer")), None)?;
+
+        let submitted = (|| {
+            let fence = self.request_fence()?;
+            let mut cmd = self.clone().request_internal_command_buffer(QueueType::Graphics)?;
+            record(&mut cmd, buffer);
+            cmd.transition_buffer(buffer, vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ);
+            cmd.end()?;
+            let submit = self.submit(QueueType::Graphics, &[cmd]);
+            let submit = match present {
+                Some(present) => submit
+                    .wait(present.wait, vk::PipelineStageFlags::TRANSFER)
+                    .signal(present.signal),
+                None => submit,
+            };
+            submit.flush_with_fence(&fence)?;
+            Ok(fence)
+        })();
+        let fence = match submitted {
+            Ok(fence) => fence,
+            Err(e) => {
+                self.destroy_buffer(buffer);
+                return Err(ReadbackError::Vulkan(e));
+            }
+        };
+
+        Ok(ReadbackFuture {
+            fence,
+            buffer: Some(buffer),
+            size: size as usize,
+            finish: Some(finish),
+            device: self.clone(),
+        })
+    }
+}
+
+/// Wait for the readback of a capture to complete.
+fn wait_for_capture<T>(future: ReadbackFuture<T>) -> Result<T, ReadbackError> {
+    match future.wait(u64::MAX)? {
+        Ok(result) => Ok(result),
+        Err(_) => Err(ReadbackError::Vulkan(vk::Result::TIMEOUT)),
+    }
+}
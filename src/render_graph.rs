@@ -0,0 +1,1041 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::{format_has_depth_or_stencil_aspect, format_to_aspect_mask};
+
+/// An error that could occur while compiling a `RenderGraph`.
+#[derive(Error, Debug)]
+pub enum RenderGraphError {
+    /// A pass reads a resource which no earlier pass writes.
+    #[error("pass `{pass}` reads `{resource}` before any pass writes it")]
+    ReadBeforeWrite {
+        /// The name of the pass.
+        pass: String,
+        /// The name of the resource.
+        resource: String,
+    },
+    /// A pass both reads and writes the same resource.
+    #[error("pass `{pass}` both reads and writes `{resource}`")]
+    Feedback {
+        /// The name of the pass.
+        pass: String,
+        /// The name of the resource.
+        resource: String,
+    },
+    /// A pass has no attachments to render to.
+    #[error("pass `{0}` has no outputs")]
+    NoOutputs(String),
+    /// The attachments of a pass have different extents.
+    #[error("the outputs of pass `{0}` have different extents")]
+    MismatchedExtents(String),
+    /// An attachment is sized relative to the swapchain, but the Device has no swapchain.
+    #[error("attachment `{0}` is sized relative to the swapchain, but there is no swapchain")]
+    NoSwapchain(String),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+    /// Allocating memory for an attachment failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+/// A resource declared in a `RenderGraph`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct RenderGraphResource(usize);
+
+/// The size of an attachment created by a `RenderGraph`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AttachmentSize {
+    /// A fixed size in pixels.
+    Absolute {
+        /// Width in pixels.
+        width: u32,
+        /// Height in pixels.
+        height: u32,
+    },
+    /// A multiple of the swapchain's extent at the time the graph is compiled.
+    SwapchainRelative {
+        /// The scale applied to the swapchain's width and height.
+        scale: f32,
+    },
+}
+
+/// Describes an attachment created and owned by a `RenderGraph`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AttachmentInfo {
+    /// The format of the attachment. Depth and/or stencil formats create depth-stencil attachments.
+    pub format: vk::Format,
+    /// The size of the attachment.
+    pub size: AttachmentSize,
+    /// The number of samples.
+    pub samples: vk::SampleCountFlags,
+}
+
+impl AttachmentInfo {
+    /// An attachment with the same extent as the swapchain.
+    pub fn swapchain_sized(format: vk::Format) -> Self {
+        Self {
+            format,
+            size: AttachmentSize::SwapchainRelative { scale: 1.0 },
+            samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+}
+
+/// The function which records a pass's commands. It is called inside the pass's render pass.
+type ExecuteFn = Box<dyn FnMut(&mut CommandBuffer, &RenderGraphImages) + Send>;
+
+enum ResourceKind {
+    Attachment(AttachmentInfo),
+    Imported(ImageHandle),
+}
+
+struct ResourceDecl {
+    name: String,
+    kind: ResourceKind,
+}
+
+struct Output {
+    resource: RenderGraphResource,
+    clear: Option<vk::ClearValue>,
+}
+
+struct PassDecl {
+    name: String,
+    color_outputs: Vec<Output>,
+    depth_stencil_output: Option<Output>,
+    sampled_inputs: Vec<RenderGraphResource>,
+    transitions: Vec<(RenderGraphResource, TransitionPreset)>,
+    execute: Option<ExecuteFn>,
+}
+
+impl PassDecl {
+    fn outputs(&self) -> impl Iterator<Item = &Output> {
+        self.color_outputs.iter().chain(self.depth_stencil_output.iter())
+    }
+}
+
+/// A description of the passes which make up a frame and the resources they read and write.
+///
+/// Passes run in the order they are added. Compiling the graph creates its attachments, a render
+/// pass for each pass, and works out which attachments' memory can be shared. When executed, the
+/// compiled graph records all the barriers and layout transitions needed between passes.
+///
+/// Attachments whose lifetimes within the frame don't overlap are placed in the same memory.
+/// Attachments which are only used by a single pass are created as transient attachments in
+/// lazily allocated memory (`ImageUsageDomain::Transient`), which on tiled GPUs means they may
+/// never be backed by real memory at all.
+#[derive(Default)]
+pub struct RenderGraph {
+    resources: Vec<ResourceDecl>,
+    passes: Vec<PassDecl>,
+}
+
+/// Declares the resources used by a pass. Created with `RenderGraph::add_pass`.
+pub struct PassBuilder<'a> {
+    pass: &'a mut PassDecl,
+}
+
+impl PassBuilder<'_> {
+    /// Render to `resource` as a color attachment, clearing it to `clear` first if given.
+    /// Otherwise the contents written by earlier passes are preserved.
+    pub fn color_output(&mut self, resource: RenderGraphResource, clear: Option<vk::ClearValue>) -> &mut Self {
+        self.pass.color_outputs.push(Output { resource, clear });
+        self
+    }
+
+    /// Use `resource` as the depth-stencil attachment, clearing it to `clear` first if given.
+    /// Otherwise the contents written by earlier passes are preserved.
+    pub fn depth_stencil_output(&mut self, resource: RenderGraphResource, clear: Option<vk::ClearValue>) -> &mut Self {
+        self.pass.depth_stencil_output = Some(Output { resource, clear });
+        self
+    }
+
+    /// Sample `resource` from the fragment shader. It must have been written by an earlier pass.
+    pub fn sampled_input(&mut self, resource: RenderGraphResource) -> &mut Self {
+        self.pass.sampled_inputs.push(resource);
+        self
+    }
+
+    /// Transition `resource` into the destination state of `preset` before the pass, ahead of the
+    /// transitions of its inputs and outputs. This is mostly useful for imported images which are
+    /// also used outside the graph, such as one copied from after the frame.
+    pub fn transition(&mut self, resource: RenderGraphResource, preset: TransitionPreset) -> &mut Self {
+        self.pass.transitions.push((resource, preset));
+        self
+    }
+
+    /// Set the function which records the pass's draw calls.
+    pub fn execute<F>(&mut self, execute: F) -> &mut Self
+    where
+        F: FnMut(&mut CommandBuffer, &RenderGraphImages) + Send + 'static,
+    {
+        self.pass.execute = Some(Box::new(execute));
+        self
+    }
+}
+
+impl RenderGraph {
+    /// Create an empty RenderGraph.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare an attachment which is created and owned by the graph.
+    pub fn create_attachment<S: Into<String>>(&mut self, name: S, info: AttachmentInfo) -> RenderGraphResource {
+        self.add_resource(name.into(), ResourceKind::Attachment(info))
+    }
+
+    /// Use an existing image, such as a swapchain image, in the graph. The image to use can be
+    /// changed after compilation with `CompiledRenderGraph::set_imported_image`.
+    ///
+    /// Imported images are never aliased, and their contents are preserved between frames.
+    pub fn import_image<S: Into<String>>(&mut self, name: S, image: ImageHandle) -> RenderGraphResource {
+        self.add_resource(name.into(), ResourceKind::Imported(image))
+    }
+
+    fn add_resource(&mut self, name: String, kind: ResourceKind) -> RenderGraphResource {
+        self.resources.push(ResourceDecl { name, kind });
+        RenderGraphResource(self.resources.len() - 1)
+    }
+
+    /// Add a pass, which runs after all previously added passes.
+    pub fn add_pass<S: Into<String>>(&mut self, name: S) -> PassBuilder<'_> {
+        self.passes.push(PassDecl {
+            name: name.into(),
+            color_outputs: Vec::new(),
+            depth_stencil_output: None,
+            sampled_inputs: Vec::new(),
+            transitions: Vec::new(),
+            execute: None,
+        });
+        PassBuilder {
+            pass: self.passes.last_mut().unwrap(),
+        }
+    }
+
+    /// Validate the graph and create the attachments, memory and render passes needed to
+    /// execute it.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn compile(self, device: &Arc<Device>) -> Result<CompiledRenderGraph, RenderGraphError> {
+        let usage = self.validate()?;
+        let extents = self.attachment_extents(device)?;
+
+        for pass in &self.passes {
+            let mut pass_extents = pass
+                .outputs()
+                .filter_map(|output| extents[output.resource.0])
+                .map(|extent| (extent.width, extent.height));
+            if let Some(first) = pass_extents.next() {
+                if pass_extents.any(|extent| extent != first) {
+                    return Err(RenderGraphError::MismatchedExtents(pass.name.clone()));
+                }
+            }
+        }
+
+        let mut graph = CompiledRenderGraph {
+            device: device.clone(),
+            resources: Vec::with_capacity(self.resources.len()),
+            passes: Vec::with_capacity(self.passes.len()),
+            slots: Vec::new(),
+            images: RenderGraphImages {
+                images: Vec::new(),
+                views: Vec::new(),
+            },
+            imported_views: HashMap::new(),
+            framebuffers: HashMap::new(),
+        };
+
+        graph.create_attachments(&self.resources, &usage, &extents)?;
+        graph.create_passes(self.passes, &usage)?;
+
+        Ok(graph)
+    }
+
+    /// Check that every read has an earlier write, and find how each resource is used.
+    fn validate(&self) -> Result<Vec<ResourceUsage>, RenderGraphError> {
+        let mut usage = vec![ResourceUsage::default(); self.resources.len()];
+
+        for (pass_index, pass) in self.passes.iter().enumerate() {
+            if pass.color_outputs.is_empty() && pass.depth_stencil_output.is_none() {
+                return Err(RenderGraphError::NoOutputs(pass.name.clone()));
+            }
+
+            for &input in &pass.sampled_inputs {
+                let resource = &self.resources[input.0];
+
+                if pass.outputs().any(|output| output.resource == input) {
+                    return Err(RenderGraphError::Feedback {
+                        pass: pass.name.clone(),
+                        resource: resource.name.clone(),
+                    });
+                }
+
+                let written = matches!(resource.kind, ResourceKind::Imported(_))
+                    || usage[input.0].first_write.is_some();
+                if !written {
+                    return Err(RenderGraphError::ReadBeforeWrite {
+                        pass: pass.name.clone(),
+                        resource: resource.name.clone(),
+                    });
+                }
+
+                usage[input.0].sampled = true;
+                usage[input.0].use_pass(pass_index);
+            }
+
+            for &(resource, preset) in &pass.transitions {
+                let usage = &mut usage[resource.0];
+                usage.extra_usage |= preset_image_usage(preset);
+                usage.use_pass(pass_index);
+            }
+
+            for output in pass.outputs() {
+                let usage = &mut usage[output.resource.0];
+                usage.first_write.get_or_insert(pass_index);
+                usage.use_pass(pass_index);
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Resolve the extent of every attachment owned by the graph.
+    fn attachment_extents(&self, device: &Device) -> Result<Vec<Option<vk::Extent2D>>, RenderGraphError> {
+        let swapchain_extent = device.swapchain().map(|swapchain| swapchain.extent());
+
+        self.resources
+            .iter()
+            .map(|resource| match resource.kind {
+                ResourceKind::Imported(_) => Ok(None),
+                ResourceKind::Attachment(info) => match info.size {
+                    AttachmentSize::Absolute { width, height } => Ok(Some(vk::Extent2D { width, height })),
+                    AttachmentSize::SwapchainRelative { scale } => {
+                        let extent = swapchain_extent
+                            .ok_or_else(|| RenderGraphError::NoSwapchain(resource.name.clone()))?;
+                        Ok(Some(vk::Extent2D {
+                            width: ((extent.width as f32 * scale) as u32).max(1),
+                            height: ((extent.height as f32 * scale) as u32).max(1),
+                        }))
+                    }
+                },
+            })
+            .collect()
+    }
+}
+
+/// How a resource is used over the course of a frame.
+#[derive(Clone, Copy, Debug, Default)]
+struct ResourceUsage {
+    first_write: Option<usize>,
+    first_use: Option<usize>,
+    last_use: usize,
+    pass_count: usize,
+    sampled: bool,
+    /// Usage flags needed by transitions declared with `PassBuilder::transition`.
+    extra_usage: vk::ImageUsageFlags,
+}
+
+impl ResourceUsage {
+    fn use_pass(&mut self, pass_index: usize) {
+        if self.last_use != pass_index || self.first_use.is_none() {
+            self.pass_count += 1;
+        }
+        self.first_use.get_or_insert(pass_index);
+        self.last_use = pass_index;
+    }
+
+    /// Whether the resource is only used within a single pass, so can be a transient attachment.
+    fn is_transient(&self) -> bool {
+        self.pass_count <= 1 && !self.sampled && self.extra_usage.is_empty()
+    }
+
+    fn overlaps(&self, other: &ResourceUsage) -> bool {
+        let (a, b) = (self.first_use.unwrap_or(0), other.first_use.unwrap_or(0));
+        a <= other.last_use && b <= self.last_use
+    }
+}
+
+/// The memory requirements of a slot, which fit every attachment placed in it, whether it
+/// holds a transient attachment, and the indices of its attachments.
+type SlotPlacement = (vk::MemoryRequirements, bool, Vec<usize>);
+
+/// Place attachments, as their index, memory requirements and whether they're transient, into
+/// memory slots.
+///
+/// The largest attachments are placed first, each into the first slot none of whose attachments
+/// are alive at the same time. Transient attachments get their own lazily allocated memory
+/// rather than sharing.
+fn place_in_slots(
+    mut attachments: Vec<(usize, vk::MemoryRequirements, bool)>,
+    usage: &[ResourceUsage],
+) -> Vec<SlotPlacement> {
+    attachments.sort_by_key(|&(_, requirements, _)| std::cmp::Reverse(requirements.size));
+
+    let mut slots: Vec<SlotPlacement> = Vec::new();
+    for &(index, requirements, transient) in &attachments {
+        let slot = slots.iter().position(|(slot_requirements, slot_transient, users)| {
+            !transient
+                && !slot_transient
+                && slot_requirements.memory_type_bits & requirements.memory_type_bits != 0
+                && users.iter().all(|&user| !usage[user].overlaps(&usage[index]))
+        });
+
+        match slot {
+            Some(slot) => {
+                let (slot_requirements, _, users) = &mut slots[slot];
+                slot_requirements.size = slot_requirements.size.max(requirements.size);
+                slot_requirements.alignment = slot_requirements.alignment.max(requirements.alignment);
+                slot_requirements.memory_type_bits &= requirements.memory_type_bits;
+                users.push(index);
+            }
+            None => slots.push((requirements, transient, vec![index])),
+        }
+    }
+    slots
+}
+
+/// The usage flags an image needs to be transitioned into the destination state of `preset`.
+fn preset_image_usage(preset: TransitionPreset) -> vk::ImageUsageFlags {
+    match preset.dst().layout {
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL => vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::ImageLayout::TRANSFER_DST_OPTIMAL => vk::ImageUsageFlags::TRANSFER_DST,
+        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL | vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL => {
+            vk::ImageUsageFlags::SAMPLED
+        }
+        _ => vk::ImageUsageFlags::empty(),
+    }
+}
+
+/// The images and views of the resources in a compiled `RenderGraph`, passed to each pass's
+/// execute function so that it can bind the resources it samples.
+pub struct RenderGraphImages {
+    images: Vec<ImageHandle>,
+    views: Vec<vk::ImageView>,
+}
+
+impl RenderGraphImages {
+    /// Get the image currently used for `resource`.
+    pub fn image(&self, resource: RenderGraphResource) -> ImageHandle {
+        self.images[resource.0]
+    }
+
+    /// Get a view of the whole of the image currently used for `resource`, with all of its
+    /// aspects.
+    pub fn view(&self, resource: RenderGraphResource) -> vk::ImageView {
+        self.views[resource.0]
+    }
+}
+
+/// An attachment created by a compiled graph.
+struct CompiledResource {
+    /// The raw image, if it was created by the graph rather than imported.
+    owned_image: Option<vk::Image>,
+    /// The memory slot the attachment is bound to, if it was created by the graph.
+    slot: Option<usize>,
+    first_use: Option<usize>,
+    format: vk::Format,
+}
+
+/// A block of memory shared by attachments whose lifetimes don't overlap.
+struct MemorySlot {
+    allocation: Allocation,
+    /// The image which most recently used the memory, whose accesses must complete before the
+    /// next image may use it.
+    last_user: Option<ImageHandle>,
+}
+
+struct CompiledPass {
+    name: String,
+    render_pass: vk::RenderPass,
+    color_outputs: Vec<RenderGraphResource>,
+    depth_stencil_output: Option<RenderGraphResource>,
+    sampled_inputs: Vec<RenderGraphResource>,
+    transitions: Vec<(RenderGraphResource, TransitionPreset)>,
+    clear_values: Vec<vk::ClearValue>,
+    execute: Option<ExecuteFn>,
+}
+
+impl CompiledPass {
+    fn outputs(&self) -> impl Iterator<Item = RenderGraphResource> + '_ {
+        self.color_outputs.iter().chain(self.depth_stencil_output.iter()).copied()
+    }
+}
+
+/// A `RenderGraph` which is ready to be executed.
+///
+/// Owns the graph's attachments, their memory and the render passes and framebuffers used to
+/// render to them. Dropping it waits for the Device to be idle, so recompile graphs sparingly,
+/// for example when the swapchain is resized.
+pub struct CompiledRenderGraph {
+    device: Arc<Device>,
+    resources: Vec<CompiledResource>,
+    passes: Vec<CompiledPass>,
+    slots: Vec<MemorySlot>,
+    images: RenderGraphImages,
+    imported_views: HashMap<ImageHandle, vk::ImageView>,
+    framebuffers: HashMap<(usize, Vec<vk::ImageView>), vk::Framebuffer>,
+}
+
+impl CompiledRenderGraph {
+    /// Get the image currently used for `resource`.
+    pub fn image(&self, resource: RenderGraphResource) -> ImageHandle {
+        self.images.image(resource)
+    }
+
+    /// Get the render pass of the pass named `pass`, which pipelines used in it must be
+    /// compatible with.
+    pub fn render_pass(&self, pass: &str) -> Option<vk::RenderPass> {
+        self.passes
+            .iter()
+            .find(|compiled| compiled.name == pass)
+            .map(|compiled| compiled.render_pass)
+    }
+
+    /// Change the image used for an imported resource, for example to the swapchain image
+    /// acquired for this frame.
+    pub fn set_imported_image(
+        &mut self,
+        resource: RenderGraphResource,
+        image: ImageHandle,
+    ) -> Result<(), vk::Result> {
+        assert!(
+            self.resources[resource.0].owned_image.is_none(),
+            "set_imported_image: resource was not imported"
+        );
+
+        let view = self.imported_view(image)?;
+        self.images.images[resource.0] = image;
+        self.images.views[resource.0] = view;
+        Ok(())
+    }
+
+    /// Record all of the graph's passes into `cmd`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn execute(&mut self, cmd: &mut CommandBuffer) -> Result<(), vk::Result> {
+        for pass_index in 0..self.passes.len() {
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!("pass", name = %self.passes[pass_index].name).entered();
+            self.prepare_pass_resources(cmd, pass_index);
+
+            let extent = {
+                let first_output = self.passes[pass_index]
+                    .outputs()
+                    .next()
+                    .expect("render graph pass has no outputs");
+                let resources = self.device.resources();
+                let image = resources
+                    .get_image(self.images.image(first_output))
+                    .expect("render graph image was destroyed");
+                vk::Extent2D {
+                    width: image.width() as u32,
+                    height: image.height() as u32,
+                }
+            };
+
+            let framebuffer = self.framebuffer(pass_index, extent)?;
+
+            let pass = &mut self.passes[pass_index];
+            cmd.begin_render_pass(
+                pass.render_pass,
+                framebuffer,
+                vk::Rect2D {
+                    offset: vk::Offset2D { x: 0, y: 0 },
+                    extent,
+                },
+                &pass.clear_values,
+            );
+
+            if let Some(first_output) = pass.outputs().next() {
+                cmd.set_viewport_for(self.images.image(first_output), false);
+            }
+
+            if let Some(ref mut execute) = pass.execute {
+                execute(cmd, &self.images);
+            }
+
+            cmd.end_render_pass();
+        }
+
+        Ok(())
+    }
+
+    /// Record the barriers needed before a pass: its declared transitions, transitions of its
+    /// sampled inputs to a shader readable layout, and of its outputs to attachment layouts.
+    fn prepare_pass_resources(&mut self, cmd: &mut CommandBuffer, pass_index: usize) {
+        let pass = &self.passes[pass_index];
+
+        for &(resource, preset) in &pass.transitions {
+            cmd.transition_image_preset(self.images.image(resource), preset);
+        }
+
+        for &input in &pass.sampled_inputs {
+            let preset = if format_has_depth_or_stencil_aspect(self.resources[input.0].format) {
+                TransitionPreset::DepthStencilAttachmentToShaderRead
+            } else {
+                TransitionPreset::ColorAttachmentToShaderRead
+            };
+            cmd.transition_image_preset(self.images.image(input), preset);
+        }
+
+        let outputs = pass.outputs().collect::<Vec<_>>();
+        for output in outputs {
+            let (owned, slot, first_use, format) = {
+                let resource = &self.resources[output.0];
+                (resource.owned_image.is_some(), resource.slot, resource.first_use, resource.format)
+            };
+            let image = self.images.image(output);
+
+            // The contents of graph-owned attachments don't survive between frames, and their
+            // memory may have been used by another attachment since they were last used. Either
+            // way the old contents are discarded, after waiting for the last user to finish.
+            if owned && first_use == Some(pass_index) {
+                let last_user = slot
+                    .and_then(|slot| self.slots[slot].last_user.replace(image))
+                    .unwrap_or(image);
+
+                let mut resources = self.device.resources_mut();
+                let last_state = resources
+                    .get_image(last_user)
+                    .map(Image::state)
+                    .unwrap_or_else(ImageState::undefined);
+                if let Some(image) = resources.get_image_mut(image) {
+                    image.set_state(ImageState {
+                        layout: vk::ImageLayout::UNDEFINED,
+                        ..last_state
+                    });
+                }
+            }
+
+            let preset = if format_has_depth_or_stencil_aspect(format) {
+                TransitionPreset::UndefinedToDepthStencilAttachment
+            } else {
+                TransitionPreset::UndefinedToColorAttachment
+            };
+            cmd.transition_image_preset(image, preset);
+        }
+    }
+
+    /// Get the framebuffer for a pass with the currently used images, creating it if needed.
+    fn framebuffer(&mut self, pass_index: usize, extent: vk::Extent2D) -> Result<vk::Framebuffer, vk::Result> {
+        let pass = &self.passes[pass_index];
+        let views = pass
+            .outputs()
+            .map(|output| self.images.view(output))
+            .collect::<Vec<_>>();
+
+        let key = (pass_index, views);
+        if let Some(&framebuffer) = self.framebuffers.get(&key) {
+            return Ok(framebuffer);
+        }
+
+        let create_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(pass.render_pass)
+            .attachments(&key.1)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = unsafe { self.device.create_framebuffer(&create_info, None)? };
+
+        self.framebuffers.insert(key, framebuffer);
+        Ok(framebuffer)
+    }
+
+    /// Get the view used to render to an imported image, creating it if needed.
+    fn imported_view(&mut self, image: ImageHandle) -> Result<vk::ImageView, vk::Result> {
+        if let Some(&view) = self.imported_views.get(&image) {
+            return Ok(view);
+        }
+
+        let (raw, format) = {
+            let resources = self.device.resources();
+            let image = resources.get_image(image).expect("imported image was destroyed");
+            (image.raw(), image.create_info().format)
+        };
+
+        let view = create_attachment_view(&self.device, raw, format)?;
+        self.imported_views.insert(image, view);
+        Ok(view)
+    }
+
+    /// Create the graph's attachments, placing those whose lifetimes don't overlap in the same
+    /// memory.
+    fn create_attachments(
+        &mut self,
+        resources: &[ResourceDecl],
+        usage: &[ResourceUsage],
+        extents: &[Option<vk::Extent2D>],
+    ) -> Result<(), RenderGraphError> {
+        let device = self.device.clone();
+
+        // Attachments which need memory, with their memory requirements.
+        let mut aliased = Vec::new();
+
+        for (index, resource) in resources.iter().enumerate() {
+            let info = match resource.kind {
+                ResourceKind::Imported(image) => {
+                    self.resources.push(CompiledResource {
+                        owned_image: None,
+                        slot: None,
+                        first_use: None,
+                        format: device.resources().get_image(image).map_or(
+                            vk::Format::UNDEFINED,
+                            |image| image.create_info().format,
+                        ),
+                    });
+                    let view = self.imported_view(image)?;
+                    self.images.images.push(image);
+                    self.images.views.push(view);
+                    continue;
+                }
+                ResourceKind::Attachment(info) => info,
+            };
+
+            let usage = usage[index];
+            let extent = extents[index].expect("attachment has no extent");
+            let transient = usage.is_transient();
+
+            let mut create_info = ImageCreateInfo::render_target(
+                extent.width as usize,
+                extent.height as usize,
+                info.format,
+                transient,
+            );
+            create_info.sample_count = info.samples;
+            create_info.initial_layout = vk::ImageLayout::UNDEFINED;
+            if transient {
+                create_info.usage = (create_info.usage
+                    & (vk::ImageUsageFlags::COLOR_ATTACHMENT
+                        | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT))
+                    | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT;
+            }
+            if usage.sampled {
+                create_info.usage |= vk::ImageUsageFlags::SAMPLED;
+            }
+            create_info.usage |= usage.extra_usage;
+
+            let image_info = vk::ImageCreateInfo::builder()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(info.format)
+                .extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(info.samples)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(create_info.usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+
+            let raw = unsafe { device.device.create_image(&image_info, None)? };
+            let requirements = unsafe { device.get_image_memory_requirements(raw) };
+
+            let tag = Tag::Allocated(format!("render graph: {}", resource.name));
+            let handle = ImageHandle::new(device.resources_mut().images.insert(unsafe {
+                Image::new(
+                    device.clone(),
+                    raw,
+                    None,
+                    create_info,
+                    None,
+                    ImageLayoutType::Optimal,
+                    vk::PipelineStageFlags::empty(),
+                    vk::AccessFlags::empty(),
+                    vk::ImageLayout::UNDEFINED,
+                    Some(tag),
+                )
+            }));
+
+            self.resources.push(CompiledResource {
+                owned_image: Some(raw),
+                slot: None,
+                first_use: usage.first_use,
+                format: info.format,
+            });
+            self.images.images.push(handle);
+            self.images.views.push(vk::ImageView::null());
+
+            aliased.push((index, requirements, transient));
+        }
+
+        for (requirements, transient, users) in place_in_slots(aliased, usage) {
+            let alloc_info = AllocationDesc {
+                usage: MemoryUsage::GpuOnly,
+                preferred_flags: if transient {
+                    vk::MemoryPropertyFlags::LAZILY_ALLOCATED
+                } else {
+                    vk::MemoryPropertyFlags::empty()
+                },
+                ..Default::default()
+            };
+            let allocation = device.allocator().allocate_memory(&requirements, &alloc_info)?;
+
+            let slot = self.slots.len();
+            self.slots.push(MemorySlot {
+                allocation,
+                last_user: None,
+            });
+
+            for index in users {
+                let resource = &mut self.resources[index];
+                resource.slot = Some(slot);
+
+                let raw = resource.owned_image.unwrap();
+                device.allocator().bind_image_memory(raw, &self.slots[slot].allocation)?;
+                self.images.views[index] = create_attachment_view(&device, raw, resource.format)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create a render pass for each of the graph's passes.
+    fn create_passes(&mut self, passes: Vec<PassDecl>, usage: &[ResourceUsage]) -> Result<(), RenderGraphError> {
+        for (pass_index, pass) in passes.into_iter().enumerate() {
+            let mut attachments = Vec::new();
+            let mut clear_values = Vec::new();
+
+            for output in pass.outputs() {
+                let resource = &self.resources[output.resource.0];
+                let imported = resource.owned_image.is_none();
+                let usage = usage[output.resource.0];
+
+                let load_op = if output.clear.is_some() {
+                    vk::AttachmentLoadOp::CLEAR
+                } else if imported || usage.first_write.is_some_and(|first| first < pass_index) {
+                    vk::AttachmentLoadOp::LOAD
+                } else {
+                    vk::AttachmentLoadOp::DONT_CARE
+                };
+                let store_op = if imported || usage.last_use > pass_index {
+                    vk::AttachmentStoreOp::STORE
+                } else {
+                    vk::AttachmentStoreOp::DONT_CARE
+                };
+
+                let depth_stencil = format_has_depth_or_stencil_aspect(resource.format);
+                let has_stencil = format_to_aspect_mask(resource.format).contains(vk::ImageAspectFlags::STENCIL);
+                let layout = if depth_stencil {
+                    vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+                } else {
+                    vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+                };
+
+                let samples = {
+                    let resources = self.device.resources();
+                    resources
+                        .get_image(self.images.image(output.resource))
+                        .map_or(vk::SampleCountFlags::TYPE_1, |image| image.create_info().sample_count)
+                };
+
+                attachments.push(
+                    vk::AttachmentDescription::builder()
+                        .format(resource.format)
+                        .samples(samples)
+                        .load_op(load_op)
+                        .store_op(store_op)
+                        .stencil_load_op(if has_stencil { load_op } else { vk::AttachmentLoadOp::DONT_CARE })
+                        .stencil_store_op(if has_stencil { store_op } else { vk::AttachmentStoreOp::DONT_CARE })
+                        .initial_layout(layout)
+                        .final_layout(layout)
+                        .build(),
+                );
+                clear_values.push(output.clear.unwrap_or_default());
+            }
+
+            let color_refs = (0..pass.color_outputs.len())
+                .map(|i| vk::AttachmentReference {
+                    attachment: i as u32,
+                    layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                })
+                .collect::<Vec<_>>();
+            let depth_ref = vk::AttachmentReference {
+                attachment: pass.color_outputs.len() as u32,
+                layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            };
+
+            let mut subpass = vk::SubpassDescription::builder()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&color_refs);
+            if pass.depth_stencil_output.is_some() {
+                subpass = subpass.depth_stencil_attachment(&depth_ref);
+            }
+            let subpasses = [subpass.build()];
+
+            let create_info = vk::RenderPassCreateInfo::builder()
+                .attachments(&attachments)
+                .subpasses(&subpasses);
+            let render_pass = unsafe { self.device.create_render_pass(&create_info, None)? };
+
+            self.passes.push(CompiledPass {
+                name: pass.name,
+                render_pass,
+                color_outputs: pass.color_outputs.iter().map(|output| output.resource).collect(),
+                depth_stencil_output: pass.depth_stencil_output.as_ref().map(|output| output.resource),
+                sampled_inputs: pass.sampled_inputs,
+                transitions: pass.transitions,
+                clear_values,
+                execute: pass.execute,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for CompiledRenderGraph {
+    fn drop(&mut self) {
+        unsafe {
+            // The attachments may still be in use by frames in flight.
+            let _ = self.device.device_wait_idle();
+
+            for (_, framebuffer) in self.framebuffers.drain() {
+                self.device.destroy_framebuffer(framebuffer, None);
+            }
+            for pass in &self.passes {
+                self.device.destroy_render_pass(pass.render_pass, None);
+            }
+            for (_, view) in self.imported_views.drain() {
+                self.device.device.destroy_image_view(view, None);
+            }
+
+            for (index, resource) in self.resources.iter().enumerate() {
+                if let Some(raw) = resource.owned_image {
+                    if self.images.views[index] != vk::ImageView::null() {
+                        self.device.device.destroy_image_view(self.images.views[index], None);
+                    }
+                    // The Image doesn't own its memory, so this only forgets about it.
+                    self.device.resources_mut().images.remove(self.images.images[index].idx);
+                    self.device.device.destroy_image(raw, None);
+                }
+            }
+
+            for slot in &self.slots {
+                if let Err(e) = self.device.allocator().free_memory(&slot.allocation) {
+                    self.device.invariant_failed(
+                        None,
+                        format!("render graph memory errored on destruction: {:#?}", e),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Create a view of all of an image's aspects, for use as a framebuffer attachment.
+fn create_attachment_view(device: &Device, image: vk::Image, format: vk::Format) -> Result<vk::ImageView, vk::Result> {
+    let create_info = vk::ImageViewCreateInfo::builder()
+        .image(image)
+        .view_type(vk::ImageViewType::TYPE_2D)
+        .format(format)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: format_to_aspect_mask(format),
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        });
+
+    unsafe { device.device.create_image_view(&create_info, None) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The usage of a resource used in each of `passes`, in order.
+    fn used_in(passes: &[usize]) -> ResourceUsage {
+        let mut usage = ResourceUsage::default();
+        for &pass in passes {
+            usage.use_pass(pass);
+        }
+        usage
+    }
+
+    fn requirements(size: vk::DeviceSize, memory_type_bits: u32) -> vk::MemoryRequirements {
+        vk::MemoryRequirements {
+            size,
+            alignment: 256,
+            memory_type_bits,
+        }
+    }
+
+    #[test]
+    fn transient_only_within_one_pass() {
+        // Used twice by the same pass, e.g. written and then transitioned.
+        assert!(used_in(&[2, 2]).is_transient());
+        assert!(!used_in(&[0, 1]).is_transient());
+
+        let mut sampled = used_in(&[0]);
+        sampled.sampled = true;
+        assert!(!sampled.is_transient());
+
+        let mut transitioned = used_in(&[0]);
+        transitioned.extra_usage = vk::ImageUsageFlags::TRANSFER_SRC;
+        assert!(!transitioned.is_transient());
+    }
+
+    #[test]
+    fn overlapping_pass_ranges() {
+        assert!(!used_in(&[0, 1]).overlaps(&used_in(&[2, 3])));
+        assert!(!used_in(&[2, 3]).overlaps(&used_in(&[0, 1])));
+        // Sharing a single pass is enough to overlap.
+        assert!(used_in(&[0, 2]).overlaps(&used_in(&[2, 3])));
+        // As is one range containing the other.
+        assert!(used_in(&[0, 4]).overlaps(&used_in(&[1, 3])));
+        assert!(used_in(&[1, 3]).overlaps(&used_in(&[0, 4])));
+    }
+
+    #[test]
+    fn disjoint_attachments_share_a_slot() {
+        let usage = [used_in(&[0, 1]), used_in(&[2, 3]), used_in(&[1, 2])];
+        let slots = place_in_slots(
+            vec![
+                (0, requirements(1024, 1), false),
+                (1, requirements(2048, 1), false),
+                (2, requirements(512, 1), false),
+            ],
+            &usage,
+        );
+
+        assert_eq!(slots.len(), 2);
+        let (shared, _, users) = &slots[0];
+        assert_eq!(users, &vec![1, 0]);
+        assert_eq!(shared.size, 2048);
+        assert_eq!(slots[1].2, vec![2]);
+    }
+
+    #[test]
+    fn overlapping_attachments_do_not_share_a_slot() {
+        let usage = [used_in(&[0, 2]), used_in(&[2, 3])];
+        let slots = place_in_slots(
+            vec![(0, requirements(1024, 1), false), (1, requirements(1024, 1), false)],
+            &usage,
+        );
+        assert_eq!(slots.len(), 2);
+    }
+
+    #[test]
+    fn transient_and_incompatible_attachments_do_not_share_a_slot() {
+        let usage = [used_in(&[0]), used_in(&[1]), used_in(&[2])];
+        let slots = place_in_slots(
+            vec![
+                (0, requirements(1024, 0b01), false),
+                (1, requirements(1024, 0b01), true),
+                (2, requirements(1024, 0b10), false),
+            ],
+            &usage,
+        );
+        assert_eq!(slots.len(), 3);
+        assert!(slots.iter().any(|&(_, transient, ref users)| transient && users == &vec![1]));
+    }
+}
@@ -0,0 +1,432 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::HashMap;
+
+use crate::*;
+use crate::cache_stats::CacheKind;
+use crate::format::format_has_depth_or_stencil_aspect;
+use crate::image::unorm_and_srgb_formats;
+
+/// An attachment of a render pass described by a `RenderPassInfo`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct RenderPassAttachment {
+    /// The format of the attachment.
+    pub format: vk::Format,
+    /// The number of samples of the attachment.
+    pub samples: vk::SampleCountFlags,
+    /// What happens to the contents of the attachment (or its depth aspect) at the start of the
+    /// render pass.
+    pub load_op: vk::AttachmentLoadOp,
+    /// What happens to the contents of the attachment (or its depth aspect) at the end of the
+    /// render pass.
+    pub store_op: vk::AttachmentStoreOp,
+    /// What happens to the stencil aspect at the start of the render pass.
+    pub stencil_load_op: vk::AttachmentLoadOp,
+    /// What happens to the stencil aspect at the end of the render pass.
+    pub stencil_store_op: vk::AttachmentStoreOp,
+    /// The layout the attachment is in when the render pass begins.
+    pub initial_layout: vk::ImageLayout,
+    /// The layout the attachment is transitioned to when the render pass ends.
+    pub final_layout: vk::ImageLayout,
+    /// Whether a framebuffer may render to an image whose view has the other of the UNORM and
+    /// SRGB variants of `format`, through the view's alias of `format` (see
+    /// `ImageView::unorm_view`), rather than failing with `FramebufferError::FormatMismatch`.
+    /// Set by `clear_store`.
+    pub format_promotion: bool,
+}
+
+impl RenderPassAttachment {
+    /// An attachment which is cleared at the start of the render pass and stored at the end,
+    /// staying in its attachment layout throughout.
+    pub fn clear_store(format: vk::Format) -> Self {
+        let layout = attachment_layout(format);
+        Self {
+            format,
+            samples: vk::SampleCountFlags::TYPE_1,
+            load_op: vk::AttachmentLoadOp::CLEAR,
+            store_op: vk::AttachmentStoreOp::STORE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: layout,
+            final_layout: layout,
+            format_promotion: true,
+        }
+    }
+
+    /// Get the attachment with the state which doesn't affect render pass compatibility reset.
+    fn compatible(self) -> Self {
+        Self {
+            load_op: vk::AttachmentLoadOp::DONT_CARE,
+            store_op: vk::AttachmentStoreOp::DONT_CARE,
+            stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+            stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            final_layout: attachment_layout(self.format),
+            format_promotion: true,
+            ..self
+        }
+    }
+}
+
+/// The optimal layout for an attachment of `format`.
+fn attachment_layout(format: vk::Format) -> vk::ImageLayout {
+    if format_has_depth_or_stencil_aspect(format) {
+        vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
+    } else {
+        vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+    }
+}
+
+/// Describes a render pass with a single subpass, which renders to every color attachment and
+/// the depth stencil attachment, if any, and resolves multisampled color attachments into the
+/// resolve attachments, if any.
+///
+/// The attachments of a framebuffer of the render pass are the color attachments, then the
+/// depth stencil attachment, then the resolve attachments.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct RenderPassInfo {
+    /// The color attachments, in the order of the fragment shader's outputs.
+    pub color_attachments: Vec<RenderPassAttachment>,
+    /// The depth stencil attachment.
+    pub depth_stencil_attachment: Option<RenderPassAttachment>,
+    /// The single sampled attachments the color attachments are resolved into at the end of the
+    /// subpass, one per color attachment, or empty to resolve none of them.
+    pub resolve_attachments: Vec<RenderPassAttachment>,
+}
+
+impl RenderPassInfo {
+    /// Get the info of a render pass which is compatible with this one, and identical for all
+    /// render passes compatible with it, regardless of their load and store ops and layouts.
+    pub fn compatible(&self) -> Self {
+        Self {
+            color_attachments: self
+                .color_attachments
+                .iter()
+                .map(|attachment| attachment.compatible())
+                .collect(),
+            depth_stencil_attachment: self.depth_stencil_attachment.map(RenderPassAttachment::compatible),
+            resolve_attachments: self
+                .resolve_attachments
+                .iter()
+                .map(|attachment| attachment.compatible())
+                .collect(),
+        }
+    }
+
+    fn attachments(&self) -> impl Iterator<Item = &RenderPassAttachment> {
+        self.color_attachments
+            .iter()
+            .chain(self.depth_stencil_attachment.iter())
+            .chain(self.resolve_attachments.iter())
+    }
+}
+
+/// An error that could occur while requesting a framebuffer.
+#[derive(Error, Debug)]
+pub enum FramebufferError {
+    /// An attachment's view, or the image it views, has been destroyed.
+    #[error("a framebuffer attachment has been destroyed")]
+    InvalidImageView,
+    /// No attachments were given.
+    #[error("a framebuffer needs at least one attachment")]
+    NoAttachments,
+    /// An attachment's view has a different format than the render pass expects, which is not
+    /// its UNORM or SRGB variant, or which the attachment disallows promoting to.
+    #[error("attachment {index} is viewed as {view:?}, but the render pass expects {attachment:?}")]
+    FormatMismatch {
+        /// The index of the attachment.
+        index: usize,
+        /// The format of the attachment's view.
+        view: vk::Format,
+        /// The format of the render pass attachment.
+        attachment: vk::Format,
+    },
+    /// An attachment would be rendered to through its UNORM or SRGB alias view, but its image
+    /// was not created with `MUTABLE_FORMAT`.
+    #[error("attachment {index} must be created with MUTABLE_FORMAT to be rendered to as {format:?}")]
+    NotMutableFormat {
+        /// The index of the attachment.
+        index: usize,
+        /// The format of the render pass attachment.
+        format: vk::Format,
+    },
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A cache of render passes, keyed by their `RenderPassInfo`, and of framebuffers, keyed by their
+/// render pass and attachments.
+///
+/// The Device owns one, which is used through `Device::request_render_pass` and
+/// `Device::request_framebuffer`. Framebuffers are destroyed along with any of the views they
+/// are made of.
+#[derive(Debug, Default)]
+pub struct RenderPassCache {
+    render_passes: HashMap<RenderPassInfo, vk::RenderPass>,
+    framebuffers: HashMap<(vk::RenderPass, Vec<ImageViewHandle>), vk::Framebuffer>,
+}
+
+impl RenderPassCache {
+    /// Get a previously created render pass with the given info.
+    pub fn get_render_pass(&self, info: &RenderPassInfo) -> Option<vk::RenderPass> {
+        self.render_passes.get(info).copied()
+    }
+
+    /// Get a previously created framebuffer of `render_pass` with the given attachments.
+    pub fn get_framebuffer(
+        &self,
+        render_pass: vk::RenderPass,
+        attachments: &[ImageViewHandle],
+    ) -> Option<vk::Framebuffer> {
+        // Tuples of borrowed keys can't be looked up without building an owned key.
+        self.framebuffers.get(&(render_pass, attachments.to_vec())).copied()
+    }
+
+    /// Get the info a cached render pass was created with.
+    pub fn render_pass_info(&self, render_pass: vk::RenderPass) -> Option<&RenderPassInfo> {
+        self.render_passes
+            .iter()
+            .find(|&(_, &cached)| cached == render_pass)
+            .map(|(info, _)| info)
+    }
+
+    /// Get the number of cached render passes.
+    pub fn render_pass_count(&self) -> usize {
+        self.render_passes.len()
+    }
+
+    /// Get the number of cached framebuffers.
+    pub fn framebuffer_count(&self) -> usize {
+        self.framebuffers.len()
+    }
+
+    /// Iterate over the infos of the cached render passes.
+    pub(crate) fn render_pass_keys(&self) -> impl Iterator<Item = &RenderPassInfo> {
+        self.render_passes.keys()
+    }
+
+    /// Iterate over the render passes and attachments of the cached framebuffers.
+    pub(crate) fn framebuffer_keys(&self) -> impl Iterator<Item = &(vk::RenderPass, Vec<ImageViewHandle>)> {
+        self.framebuffers.keys()
+    }
+
+    /// Remove every framebuffer which uses `view`, returning them so they can be destroyed.
+    pub(crate) fn evict_view(&mut self, view: ImageViewHandle) -> Vec<vk::Framebuffer> {
+        let mut evicted = Vec::new();
+        self.framebuffers.retain(|(_, attachments), &mut framebuffer| {
+            let uses = attachments.contains(&view);
+            if uses {
+                evicted.push(framebuffer);
+            }
+            !uses
+        });
+        evicted
+    }
+}
+
+impl Device {
+    /// Get a read-only handle to this Device's cache of render passes and framebuffers.
+    pub fn render_passes(&self) -> parking_lot::RwLockReadGuard<'_, RenderPassCache> {
+        self.render_passes.read()
+    }
+
+    /// Get a render pass described by `info`, creating it if an identical one has not been
+    /// requested before.
+    ///
+    /// Pipelines only need a compatible render pass, so should be created with the one for
+    /// `info.compatible()`, which lets them be used with every render pass differing only in
+    /// load and store ops and layouts.
+    pub fn request_render_pass(&self, info: &RenderPassInfo) -> VkResult<vk::RenderPass> {
+        if let Some(render_pass) = self.render_passes.read().get_render_pass(info) {
+            self.cache_counters.count(CacheKind::RenderPass, true);
+            return Ok(render_pass);
+        }
+        self.cache_counters.count(CacheKind::RenderPass, false);
+
+        let attachments = info
+            .attachments()
+            .map(|attachment| {
+                vk::AttachmentDescription::builder()
+                    .format(attachment.format)
+                    .samples(attachment.samples)
+                    .load_op(attachment.load_op)
+                    .store_op(attachment.store_op)
+                    .stencil_load_op(attachment.stencil_load_op)
+                    .stencil_store_op(attachment.stencil_store_op)
+                    .initial_layout(attachment.initial_layout)
+                    .final_layout(attachment.final_layout)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+        let color_refs = (0..info.color_attachments.len() as u32)
+            .map(|attachment| vk::AttachmentReference {
+                attachment,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            })
+            .collect::<Vec<_>>();
+        let depth_ref = vk::AttachmentReference {
+            attachment: info.color_attachments.len() as u32,
+            layout: vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+        };
+        assert!(
+            info.resolve_attachments.is_empty()
+                || info.resolve_attachments.len() == info.color_attachments.len(),
+            "a render pass needs a resolve attachment for every color attachment, or none"
+        );
+        let first_resolve = (info.color_attachments.len() + info.depth_stencil_attachment.iter().count()) as u32;
+        let resolve_refs = (0..info.resolve_attachments.len() as u32)
+            .map(|resolve| vk::AttachmentReference {
+                attachment: first_resolve + resolve,
+                layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            })
+            .collect::<Vec<_>>();
+        let mut subpass = vk::SubpassDescription::builder()
+            .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+            .color_attachments(&color_refs);
+        if info.depth_stencil_attachment.is_some() {
+            subpass = subpass.depth_stencil_attachment(&depth_ref);
+        }
+        if !info.resolve_attachments.is_empty() {
+            subpass = subpass.resolve_attachments(&resolve_refs);
+        }
+        let subpasses = [subpass.build()];
+        let create_info = vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses);
+        let render_pass = unsafe { self.device.create_render_pass(&create_info, None)? };
+
+        let mut cache = self.render_passes.write();
+        match cache.render_passes.get(info) {
+            // Another thread created the same render pass in the meantime.
+            Some(&existing) => {
+                unsafe { self.device.destroy_render_pass(render_pass, None) };
+                Ok(existing)
+            }
+            None => {
+                cache.render_passes.insert(info.clone(), render_pass);
+                Ok(render_pass)
+            }
+        }
+    }
+
+    /// Get a framebuffer of `render_pass` made of `attachments`, creating it if the same one has
+    /// not been requested before. Its extent is that of the first attachment's base mip level.
+    ///
+    /// If `render_pass` was requested from this Device, an attachment viewed with the UNORM
+    /// variant of an SRGB render pass attachment's format, or the other way around, is rendered
+    /// to through its alias view of that format, so linear values can be written to sRGB images
+    /// without a separate view, unless the attachment's `format_promotion` is unset.
+    ///
+    /// The framebuffer is destroyed once any of its attachments is destroyed with
+    /// `destroy_image_view`, so it must only be used while they all are alive.
+    pub fn request_framebuffer(
+        &self,
+        render_pass: vk::RenderPass,
+        attachments: &[ImageViewHandle],
+    ) -> Result<vk::Framebuffer, FramebufferError> {
+        if let Some(framebuffer) = self.render_passes.read().get_framebuffer(render_pass, attachments) {
+            self.cache_counters.count(CacheKind::Framebuffer, true);
+            return Ok(framebuffer);
+        }
+        self.cache_counters.count(CacheKind::Framebuffer, false);
+
+        let (views, extent, layers) = {
+            let info = self.render_passes.read().render_pass_info(render_pass).cloned();
+            let resources = self.resources();
+            let views = attachments
+                .iter()
+                .map(|&handle| resources.get_image_view(handle))
+                .collect::<Option<Vec<_>>>()
+                .ok_or(FramebufferError::InvalidImageView)?;
+            let first = views.first().ok_or(FramebufferError::NoAttachments)?.create_info();
+            let image = resources
+                .get_image(first.image)
+                .ok_or(FramebufferError::InvalidImageView)?;
+            let extent = vk::Extent2D {
+                width: image.width_lod(first.base_mip_level) as u32,
+                height: image.height_lod(first.base_mip_level) as u32,
+            };
+            let views = match info {
+                Some(ref info) => views
+                    .iter()
+                    .zip(info.attachments())
+                    .enumerate()
+                    .map(|(index, (view, attachment))| attachment_view(index, view, attachment))
+                    .collect::<Result<Vec<_>, _>>()?,
+                None => views.iter().map(|view| view.raw()).collect::<Vec<_>>(),
+            };
+            (views, extent, first.array_layers as u32)
+        };
+
+        let create_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(render_pass)
+            .attachments(&views)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(layers.max(1));
+        let framebuffer = unsafe { self.device.create_framebuffer(&create_info, None)? };
+
+        let mut cache = self.render_passes.write();
+        let key = (render_pass, attachments.to_vec());
+        match cache.framebuffers.get(&key) {
+            Some(&existing) => {
+                unsafe { self.device.destroy_framebuffer(framebuffer, None) };
+                Ok(existing)
+            }
+            None => {
+                cache.framebuffers.insert(key, framebuffer);
+                Ok(framebuffer)
+            }
+        }
+    }
+
+    /// Destroy every cached render pass and framebuffer.
+    ///
+    /// # Safety
+    ///
+    /// None of the render passes or framebuffers may be in use by the GPU, and no pipelines
+    /// created with the render passes may be used afterwards.
+    pub unsafe fn clear_render_passes(&self) {
+        let mut cache = self.render_passes.write();
+        for (_, framebuffer) in cache.framebuffers.drain() {
+            self.device.destroy_framebuffer(framebuffer, None);
+        }
+        for (_, render_pass) in cache.render_passes.drain() {
+            self.device.destroy_render_pass(render_pass, None);
+        }
+    }
+}
+
+/// Get the raw view to use for the `index`th attachment of a framebuffer, promoting it to its
+/// UNORM or SRGB alias view if `attachment` expects the other variant of its format.
+fn attachment_view(
+    index: usize,
+    view: &ImageView,
+    attachment: &RenderPassAttachment,
+) -> Result<vk::ImageView, FramebufferError> {
+    let format = view.create_info().format;
+    if format == attachment.format {
+        return Ok(view.raw());
+    }
+
+    let mismatch = FramebufferError::FormatMismatch {
+        index,
+        view: format,
+        attachment: attachment.format,
+    };
+    let alias = match unorm_and_srgb_formats(format) {
+        _ if !attachment.format_promotion => return Err(mismatch),
+        Some((unorm, _)) if attachment.format == unorm => view.unorm_view(),
+        Some((_, srgb)) if attachment.format == srgb => view.srgb_view(),
+        _ => return Err(mismatch),
+    };
+    alias.ok_or(FramebufferError::NotMutableFormat {
+        index,
+        format: attachment.format,
+    })
+}
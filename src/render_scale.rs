@@ -0,0 +1,380 @@
+//! Dynamic resolution scaling: the frame is rendered into an internal render target whose
+//! extent is the swapchain's scaled by a factor set with `Device::set_render_scale`, and then
+//! upscaled into the acquired swapchain image with `CommandBuffer::upscale_to_swapchain`.
+//!
+//! The upscale is a bilinear blit unless a compute pass of the application's own is installed
+//! with `Device::set_upscale_filter`. hot ships no upscaling shaders, such as FSR1's EASU and
+//! RCAS. A pass must use this interface at set 0:
+//!
+//! ```glsl
+//! layout(set = 0, binding = 0) uniform sampler2D src;
+//! layout(set = 0, binding = 1, rgba16f) uniform writeonly image2D dst;
+//! layout(push_constant) uniform Params { uvec2 src_size; uvec2 dst_size; };
+//! ```
+//!
+//! `src` is the render target, sampled with a bilinear clamp-to-edge sampler, and `dst` is an
+//! intermediate of the swapchain's extent which is blitted into the swapchain image afterwards,
+//! since swapchain images usually can't be storage images.
+
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// The smallest factor accepted by `Device::set_render_scale`.
+pub const MIN_RENDER_SCALE: f32 = 0.25;
+
+/// An error from dynamic resolution scaling.
+#[derive(Error, Debug)]
+pub enum RenderScaleError {
+    /// The swapchain has not been initialized.
+    #[error("the swapchain is not initialized")]
+    NoSwapchain,
+    /// The format of the render target or of the upscale's intermediate doesn't support the
+    /// features needed to upscale it.
+    #[error("format {0:?} does not support upscaling")]
+    UnsupportedFormat(vk::Format),
+    /// The render target could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A compute pass which upscales the render target into an intermediate of the swapchain's
+/// extent. See the module documentation for the interface it must use.
+#[derive(Clone, Copy, Debug)]
+pub struct UpscalePass {
+    /// The pipeline of the pass, which must outlive its use as the upscale filter.
+    pub pipeline: ComputePipeline,
+    /// The layout of set 0 of the pipeline.
+    pub set_layout: vk::DescriptorSetLayout,
+    /// The pixels of the output covered by each work group in each dimension.
+    pub group_size: [u32; 2],
+    /// The format of the intermediate the pass writes, which must support storage image usage
+    /// and blitting.
+    pub format: vk::Format,
+}
+
+impl UpscalePass {
+    /// Describe a pass writing a `R16G16B16A16_SFLOAT` intermediate with 16x16 pixel work
+    /// groups.
+    pub fn new(pipeline: ComputePipeline, set_layout: vk::DescriptorSetLayout) -> Self {
+        Self {
+            pipeline,
+            set_layout,
+            group_size: [16, 16],
+            format: vk::Format::R16G16B16A16_SFLOAT,
+        }
+    }
+}
+
+/// How the render target is upscaled into the swapchain image.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum UpscaleFilter {
+    /// A bilinear blit.
+    #[default]
+    Bilinear,
+    /// A compute pass supplied by the application, followed by a 1:1 blit.
+    Compute(UpscalePass),
+}
+
+/// The internal render target of the current frame.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderTarget {
+    /// The image to render the frame into, which has the swapchain's format and
+    /// `COLOR_ATTACHMENT`, `SAMPLED` and `TRANSFER_SRC` usage.
+    pub image: ImageHandle,
+    /// The extent of the image.
+    pub extent: vk::Extent2D,
+    /// The render scale the extent was computed with.
+    pub scale: f32,
+}
+
+/// The state of dynamic resolution scaling, owned by the Device.
+pub(crate) struct RenderScaleState {
+    /// The scale set with `set_render_scale`, which takes effect at the next `begin_frame`.
+    requested: f32,
+    /// The scale of the current frame.
+    scale: f32,
+    filter: UpscaleFilter,
+    /// The render target, if one has been requested, along with the extent of the swapchain it
+    /// was created for.
+    target: Option<(RenderTarget, vk::Extent2D, vk::Format)>,
+    /// The sampler compute upscales read the render target with, created on first use.
+    sampler: vk::Sampler,
+}
+
+impl Default for RenderScaleState {
+    fn default() -> Self {
+        Self {
+            requested: 1.0,
+            scale: 1.0,
+            filter: UpscaleFilter::Bilinear,
+            target: None,
+            sampler: vk::Sampler::null(),
+        }
+    }
+}
+
+/// Scale `extent` by `scale`, keeping at least one pixel in each dimension.
+fn scale_extent(extent: vk::Extent2D, scale: f32) -> vk::Extent2D {
+    vk::Extent2D {
+        width: ((extent.width as f32 * scale).round() as u32).max(1),
+        height: ((extent.height as f32 * scale).round() as u32).max(1),
+    }
+}
+
+fn full_rect_offsets(extent: vk::Extent2D) -> [vk::Offset3D; 2] {
+    [
+        vk::Offset3D { x: 0, y: 0, z: 0 },
+        vk::Offset3D {
+            x: extent.width as i32,
+            y: extent.height as i32,
+            z: 1,
+        },
+    ]
+}
+
+fn color_layers() -> vk::ImageSubresourceLayers {
+    vk::ImageSubresourceLayers {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        mip_level: 0,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+impl Device {
+    /// Set the factor the extent of the render target is scaled by relative to the swapchain,
+    /// clamped to `MIN_RENDER_SCALE..=1.0`. It takes effect at the next `begin_frame`, so every
+    /// pass of a frame sees the same extent.
+    pub fn set_render_scale(&self, scale: f32) {
+        let scale = if scale.is_nan() { 1.0 } else { scale };
+        self.render_scale.lock().requested = scale.clamp(MIN_RENDER_SCALE, 1.0);
+    }
+
+    /// Get the render scale of the current frame.
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale.lock().scale
+    }
+
+    /// Set how the render target is upscaled by `CommandBuffer::upscale_to_swapchain`.
+    pub fn set_upscale_filter(&self, filter: UpscaleFilter) {
+        self.render_scale.lock().filter = filter;
+    }
+
+    /// Get the render target of the current frame, (re)creating it if the swapchain or the
+    /// render scale changed since it was last requested.
+    ///
+    /// Render passes should target it instead of the swapchain image, for example with
+    /// `CommandBuffer::set_viewport_for(target.image, flip_y)`. A replaced render target is
+    /// destroyed once the frames in flight have completed.
+    pub fn render_target(self: &Arc<Self>) -> Result<RenderTarget, RenderScaleError> {
+        let (swapchain_extent, format) = {
+            let swapchain = self.swapchain().ok_or(RenderScaleError::NoSwapchain)?;
+            (swapchain.extent(), swapchain.format().format)
+        };
+
+        let mut state = self.render_scale.lock();
+        let scale = state.scale;
+        if let Some((target, extent, target_format)) = state.target {
+            let same_extent =
+                (extent.width, extent.height) == (swapchain_extent.width, swapchain_extent.height);
+            if target.scale == scale && same_extent && target_format == format {
+                return Ok(target);
+            }
+        }
+
+        let required = vk::FormatFeatureFlags::COLOR_ATTACHMENT
+            | vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR
+            | vk::FormatFeatureFlags::BLIT_SRC;
+        if !self.format_features(format).contains(required) {
+            return Err(RenderScaleError::UnsupportedFormat(format));
+        }
+
+        let extent = scale_extent(swapchain_extent, scale);
+        let create_info = ImageCreateInfo {
+            width: extent.width as usize,
+            height: extent.height as usize,
+            depth: 1,
+            format,
+            usage: vk::ImageUsageFlags::COLOR_ATTACHMENT
+                | vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::TRANSFER_SRC,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            ..Default::default()
+        };
+        let image = self
+            .clone()
+            .create_image(create_info, None, Some(Tag::Static("render target")))?;
+
+        let target = RenderTarget { image, extent, scale };
+        if let Some((old, _, _)) = state.target.replace((target, swapchain_extent, format)) {
+            self.destroy_image(old.image);
+        }
+        Ok(target)
+    }
+
+    /// Free the render target, for example because the swapchain it was sized for was torn down.
+    /// It is created again by the next `render_target`.
+    pub(crate) fn release_render_target(&self) {
+        if let Some((old, _, _)) = self.render_scale.lock().target.take() {
+            self.destroy_image(old.image);
+        }
+    }
+
+    /// Apply the render scale requested with `set_render_scale` to the frame being begun.
+    pub(crate) fn latch_render_scale(&self) {
+        let mut state = self.render_scale.lock();
+        state.scale = state.requested;
+    }
+
+    /// Get the sampler compute upscales read the render target with, creating it if needed.
+    fn upscale_sampler(&self) -> VkResult<vk::Sampler> {
+        let mut state = self.render_scale.lock();
+        if state.sampler == vk::Sampler::null() {
+            let sampler_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE);
+            state.sampler = unsafe { self.device.create_sampler(&sampler_info, None)? };
+        }
+        Ok(state.sampler)
+    }
+}
+
+impl CommandBuffer {
+    /// Upscale the current frame's render target into the swapchain image of `frame` with the
+    /// filter set by `Device::set_upscale_filter`, leaving the swapchain image ready to be
+    /// presented.
+    ///
+    /// The render target must have been rendered to this frame and must not be in a render
+    /// pass. Nothing else should be drawn to the swapchain image afterwards.
+    pub fn upscale_to_swapchain(&mut self, frame: &SwapchainFrame) -> Result<(), RenderScaleError> {
+        let device = self.device().clone();
+        let target = device.render_target()?;
+        let (swapchain_extent, swapchain_format) = {
+            let swapchain = device.swapchain().ok_or(RenderScaleError::NoSwapchain)?;
+            (swapchain.extent(), swapchain.format().format)
+        };
+        if !device
+            .format_features(swapchain_format)
+            .contains(vk::FormatFeatureFlags::BLIT_DST)
+        {
+            return Err(RenderScaleError::UnsupportedFormat(swapchain_format));
+        }
+
+        let filter = device.render_scale.lock().filter;
+        match filter {
+            UpscaleFilter::Bilinear => {
+                let region = vk::ImageBlit {
+                    src_subresource: color_layers(),
+                    src_offsets: full_rect_offsets(target.extent),
+                    dst_subresource: color_layers(),
+                    dst_offsets: full_rect_offsets(swapchain_extent),
+                };
+                self.blit_image(frame.image, target.image, &[region], vk::Filter::LINEAR);
+            }
+            UpscaleFilter::Compute(pass) => {
+                let required = vk::FormatFeatureFlags::STORAGE_IMAGE | vk::FormatFeatureFlags::BLIT_SRC;
+                if !device.format_features(pass.format).contains(required) {
+                    return Err(RenderScaleError::UnsupportedFormat(pass.format));
+                }
+
+                let mut desc = ScratchImageDesc::new(
+                    swapchain_extent.width as usize,
+                    swapchain_extent.height as usize,
+                    pass.format,
+                );
+                desc.usage = vk::ImageUsageFlags::TRANSFER_SRC;
+                let output = device.request_internal_scratch_image(desc)?;
+
+                let sampler = device.upscale_sampler()?;
+                let set = device.allocate_conversion_set(pass.set_layout)?;
+                let (src_view, dst_view) = {
+                    let resources = device.resources();
+                    let view = |image| resources.get_image(image).unwrap().view().unwrap().raw();
+                    (view(target.image), view(output))
+                };
+                let image_infos = [
+                    vk::DescriptorImageInfo {
+                        sampler,
+                        image_view: src_view,
+                        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    },
+                    vk::DescriptorImageInfo {
+                        sampler: vk::Sampler::null(),
+                        image_view: dst_view,
+                        image_layout: vk::ImageLayout::GENERAL,
+                    },
+                ];
+                let writes = [
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(0)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .image_info(&image_infos[..1])
+                        .build(),
+                    vk::WriteDescriptorSet::builder()
+                        .dst_set(set)
+                        .dst_binding(1)
+                        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                        .image_info(&image_infos[1..])
+                        .build(),
+                ];
+                unsafe { device.device.update_descriptor_sets(&writes, &[]) };
+
+                let sizes = [
+                    target.extent.width,
+                    target.extent.height,
+                    swapchain_extent.width,
+                    swapchain_extent.height,
+                ];
+                let mut push_constants = Vec::with_capacity(16);
+                for size in &sizes {
+                    push_constants.extend_from_slice(&size.to_ne_bytes());
+                }
+                self.dispatch_with(
+                    &pass.pipeline,
+                    &[set],
+                    &push_constants,
+                    &[
+                        DispatchResource::SampledImage(target.image),
+                        DispatchResource::StorageImage(output, ShaderAccess::Write),
+                    ],
+                    [
+                        swapchain_extent.width.div_ceil(pass.group_size[0]),
+                        swapchain_extent.height.div_ceil(pass.group_size[1]),
+                        1,
+                    ],
+                );
+
+                let region = vk::ImageBlit {
+                    src_subresource: color_layers(),
+                    src_offsets: full_rect_offsets(swapchain_extent),
+                    dst_subresource: color_layers(),
+                    dst_offsets: full_rect_offsets(swapchain_extent),
+                };
+                self.blit_image(frame.image, output, &[region], vk::Filter::NEAREST);
+            }
+        }
+
+        self.transition_image(
+            frame.image,
+            vk::ImageLayout::PRESENT_SRC_KHR,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            vk::AccessFlags::empty(),
+        );
+        Ok(())
+    }
+}
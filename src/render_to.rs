@@ -0,0 +1,206 @@
+//! Scopes which render into an image view on a command buffer of their own, for render to
+//! texture work such as planar reflections, reflection probes, impostors and UI render targets.
+//!
+//! `Device::render_to(&frame, view, |cmd| ...)` begins rendering into `view` with `begin_rendering`,
+//! sets the viewport and scissor to cover it, runs the closure, and then transitions the view to
+//! `SHADER_READ_ONLY_OPTIMAL` and enqueues the command buffer on the graphics queue, so the image
+//! can be sampled by anything recorded afterwards.
+//!
+//! A view of several layers is rendered to as a whole, for shaders which select the layer they
+//! write to, and the viewport can be split into a grid for shaders which select the viewport.
+//! See the `layered` module.
+//!
+//! Scopes nest: since each has its own command buffer, a scope begun within another's closure is
+//! enqueued first, so the outer scope can sample what the inner one rendered. The image of the
+//! inner scope must not be used by the outer scope before the inner scope has ended.
+
+use ash::vk;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while rendering in a `RenderTargetScope`.
+#[derive(Error, Debug)]
+pub enum RenderTargetError {
+    /// The color or depth view does not exist.
+    #[error("the image view does not exist")]
+    InvalidImageView,
+    /// A render pass or framebuffer for the views could not be created.
+    #[error("framebuffer error: {0}")]
+    Framebuffer(#[from] FramebufferError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A scope rendering into a color view, and optionally a depth view. See the module
+/// documentation.
+///
+/// By default the color view is cleared to transparent black, the depth view to 1.0, and the
+/// viewport is neither flipped nor split.
+#[derive(Clone, Copy, Debug)]
+pub struct RenderTargetScope {
+    color: ImageViewHandle,
+    depth: Option<ImageViewHandle>,
+    clear_color: Option<[f32; 4]>,
+    clear_depth: f32,
+    flip_y: bool,
+    viewport_grid: (u32, u32),
+}
+
+impl RenderTargetScope {
+    /// Create a scope rendering into the first mip level of `color`.
+    pub fn new(color: ImageViewHandle) -> Self {
+        Self {
+            color,
+            depth: None,
+            clear_color: Some([0.0; 4]),
+            clear_depth: 1.0,
+            flip_y: false,
+            viewport_grid: (1, 1),
+        }
+    }
+
+    /// Depth test against `depth`, which must have the same extent as the color view. It is
+    /// left in its attachment layout.
+    pub fn depth(mut self, depth: ImageViewHandle) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Clear the color view to `clear_color` at the start of the scope, or keep its contents
+    /// if `None`.
+    pub fn clear_color(mut self, clear_color: Option<[f32; 4]>) -> Self {
+        self.clear_color = clear_color;
+        self
+    }
+
+    /// Clear the depth view to `clear_depth` at the start of the scope.
+    pub fn clear_depth(mut self, clear_depth: f32) -> Self {
+        self.clear_depth = clear_depth;
+        self
+    }
+
+    /// Flip the viewport vertically, as `CommandBuffer::set_viewport_for` does.
+    pub fn flip_y(mut self, flip_y: bool) -> Self {
+        self.flip_y = flip_y;
+        self
+    }
+
+    /// Split the viewport into a grid of `columns` by `rows` viewports, as
+    /// `CommandBuffer::set_viewport_grid_for` does.
+    pub fn viewport_grid(mut self, columns: u32, rows: u32) -> Self {
+        self.viewport_grid = (columns, rows);
+        self
+    }
+
+    /// Record `f` into a new graphics command buffer while rendering into the scope's views,
+    /// then make the color view ready to be sampled and enqueue the command buffer, returning
+    /// what `f` returned.
+    ///
+    /// Pipelines used by `f` must be created for dynamic rendering, with `color_formats` and
+    /// `depth_stencil_format` matching the views.
+    pub fn render<R, F>(self, device: &Arc<Device>, frame: &Frame, f: F) -> Result<R, RenderTargetError>
+    where
+        F: FnOnce(&mut CommandBuffer) -> R,
+    {
+        let (image, range, extent) = {
+            let resources = device.resources();
+            let view_info = resources
+                .get_image_view(self.color)
+                .ok_or(RenderTargetError::InvalidImageView)?
+                .create_info();
+            let image = resources
+                .get_image(view_info.image)
+                .ok_or(RenderTargetError::InvalidImageView)?;
+            let level = view_info.base_mip_level;
+            let range = vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: level as u32,
+                level_count: 1,
+                base_array_layer: view_info.base_array_layer as u32,
+                layer_count: view_info.array_layers as u32,
+            };
+            let extent = vk::Extent2D {
+                width: image.width_lod(level) as u32,
+                height: image.height_lod(level) as u32,
+            };
+            (view_info.image, range, extent)
+        };
+        if let Some(depth) = self.depth {
+            if device.resources().get_image_view(depth).is_none() {
+                return Err(RenderTargetError::InvalidImageView);
+            }
+        }
+
+        let (load_op, clear_color) = match self.clear_color {
+            Some(color) => (vk::AttachmentLoadOp::CLEAR, color),
+            None => (vk::AttachmentLoadOp::LOAD, [0.0; 4]),
+        };
+        let info = RenderingInfo {
+            render_area: vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            },
+            layer_count: range.layer_count,
+            color_attachments: vec![RenderingAttachment {
+                view: self.color,
+                load_op,
+                store_op: vk::AttachmentStoreOp::STORE,
+                clear_value: vk::ClearValue {
+                    color: vk::ClearColorValue { float32: clear_color },
+                },
+            }],
+            depth_stencil_attachment: self.depth.map(|view| RenderingAttachment {
+                view,
+                load_op: vk::AttachmentLoadOp::CLEAR,
+                store_op: vk::AttachmentStoreOp::STORE,
+                clear_value: vk::ClearValue {
+                    depth_stencil: vk::ClearDepthStencilValue {
+                        depth: self.clear_depth,
+                        stencil: 0,
+                    },
+                },
+            }),
+        };
+
+        let mut cmd = device.clone().request_command_buffer(frame, QueueType::Graphics)?;
+        cmd.begin_rendering(&info)?;
+        let (columns, rows) = self.viewport_grid;
+        let target = ViewportTarget::Image(image, range.base_mip_level as usize);
+        cmd.set_viewport_grid_for(target, columns, rows, self.flip_y);
+        let result = f(&mut cmd);
+        cmd.end_rendering();
+
+        cmd.transition_image_subresources(
+            image,
+            range,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        cmd.end()?;
+        device.submit(QueueType::Graphics, &[cmd]).enqueue();
+
+        Ok(result)
+    }
+}
+
+impl Device {
+    /// Render into `view` with `f` in a `RenderTargetScope` with the default settings, leaving
+    /// the view ready to be sampled. See the `render_to` module documentation.
+    pub fn render_to<R, F>(
+        self: &Arc<Self>,
+        frame: &Frame,
+        view: ImageViewHandle,
+        f: F,
+    ) -> Result<R, RenderTargetError>
+    where
+        F: FnOnce(&mut CommandBuffer) -> R,
+    {
+        RenderTargetScope::new(view).render(self, frame, f)
+    }
+}
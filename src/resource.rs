@@ -3,11 +3,14 @@ use generational_arena as ga;
 use crate::*;
 
 /// A set of persistent GPU resources.
+#[derive(Default)]
 pub struct ResourceSet {
     pub(crate) buffers: ga::Arena<Buffer>,
     pub(crate) buffer_views: ga::Arena<BufferView>,
     pub(crate) images: ga::Arena<Image>,
     pub(crate) image_views: ga::Arena<ImageView>,
+    #[cfg(feature = "raytracing")]
+    pub(crate) acceleration_structures: ga::Arena<AccelerationStructure>,
 }
 
 impl ResourceSet {
@@ -46,10 +49,100 @@ impl ResourceSet {
     pub fn get_image_mut(&mut self, image: ImageHandle) -> Option<&mut Image> {
         self.images.get_mut(image.idx)
     }
+
+    /// Get a shared reference to the owned image view behind a given handle, if
+    /// it still exists.
+    pub fn get_image_view(&self, image_view: ImageViewHandle) -> Option<&ImageView> {
+        self.image_views.get(image_view.idx)
+    }
+
+    /// Get an exclusive reference to the owned image view behind a given handle, if
+    /// it still exists.
+    pub fn get_image_view_mut(&mut self, image_view: ImageViewHandle) -> Option<&mut ImageView> {
+        self.image_views.get_mut(image_view.idx)
+    }
+
+    /// Get a shared reference to the owned acceleration structure behind a given handle, if
+    /// it still exists.
+    #[cfg(feature = "raytracing")]
+    pub fn get_acceleration_structure(
+        &self,
+        acceleration_structure: AccelerationStructureHandle,
+    ) -> Option<&AccelerationStructure> {
+        self.acceleration_structures.get(acceleration_structure.idx)
+    }
+
+    /// Get the generation of the resource currently occupying the slot of a given handle, or
+    /// `None` if the slot is empty.
+    ///
+    /// Generations increase monotonically whenever a slot is freed and reused, so a cache keyed
+    /// on a handle can store `handle.generation()` alongside its entry and consider the entry
+    /// stale once this no longer matches. Since destruction is deferred until the frame has
+    /// completed, the generation changes when the resource is actually freed rather than when
+    /// it is destroyed.
+    pub fn generation<H: ResourceHandle>(&self, handle: H) -> Option<u64> {
+        H::current_generation(self, handle.slot())
+    }
+
+    /// Get whether a handle still refers to a live resource, rather than one which has been
+    /// freed or whose slot has since been reused.
+    pub fn is_current<H: ResourceHandle>(&self, handle: H) -> bool {
+        self.generation(handle) == Some(handle.generation())
+    }
 }
 
+impl Drop for ResourceSet {
+    fn drop(&mut self) {
+        // The set is only dropped along with its Device, which can no longer destroy the
+        // ImageViews still alive, so they are leaked rather than queued for destruction.
+        for (_, view) in self.image_views.drain() {
+            std::mem::forget(view);
+        }
+    }
+}
+
+/// A handle to a resource in a ResourceSet.
+pub trait ResourceHandle: Copy {
+    /// Get the slot of the resource within its ResourceSet.
+    fn slot(&self) -> usize;
+
+    /// Get the generation of the resource this handle was created for.
+    fn generation(&self) -> u64;
+
+    /// Get the generation of the resource of this type currently occupying `slot`, if any.
+    fn current_generation(resources: &ResourceSet, slot: usize) -> Option<u64>;
+}
+
+macro_rules! impl_resource_handle {
+    ($handle:ty, $arena:ident) => {
+        impl ResourceHandle for $handle {
+            fn slot(&self) -> usize {
+                self.idx.into_raw_parts().0
+            }
+
+            fn generation(&self) -> u64 {
+                self.idx.into_raw_parts().1
+            }
+
+            fn current_generation(resources: &ResourceSet, slot: usize) -> Option<u64> {
+                resources
+                    .$arena
+                    .get_unknown_gen(slot)
+                    .map(|(_, idx)| idx.into_raw_parts().1)
+            }
+        }
+    };
+}
+
+impl_resource_handle!(BufferHandle, buffers);
+impl_resource_handle!(BufferViewHandle, buffer_views);
+impl_resource_handle!(ImageHandle, images);
+impl_resource_handle!(ImageViewHandle, image_views);
+#[cfg(feature = "raytracing")]
+impl_resource_handle!(AccelerationStructureHandle, acceleration_structures);
+
 /// Handle to a GPU buffer.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct BufferHandle {
     pub(crate) idx: ga::Index,
 }
@@ -84,15 +177,64 @@ impl ImageHandle {
     }
 }
 
+/// Handle to a GPU image view.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ImageViewHandle {
+    pub(crate) idx: ga::Index,
+}
+
+impl ImageViewHandle {
+    pub(crate) fn new(idx: ga::Index) -> Self {
+        ImageViewHandle { idx }
+    }
+}
+
+/// Handle to a ray tracing acceleration structure.
+#[cfg(feature = "raytracing")]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AccelerationStructureHandle {
+    pub(crate) idx: ga::Index,
+}
+
+#[cfg(feature = "raytracing")]
+impl AccelerationStructureHandle {
+    pub(crate) fn new(idx: ga::Index) -> Self {
+        AccelerationStructureHandle { idx }
+    }
+}
+
 /// A set of BufferBlockPools, for different usages.
 pub struct BufferBlockSet {
     pub(crate) vbo_pool: BufferBlockPool,
     pub(crate) ibo_pool: BufferBlockPool,
     pub(crate) ubo_pool: BufferBlockPool,
+    pub(crate) indirect_pool: BufferBlockPool,
     pub(crate) staging_pool: BufferBlockPool,
 }
 
 impl BufferBlockSet {
+    /// Get the pool handing out blocks of `kind`.
+    pub fn pool(&self, kind: PoolKind) -> &BufferBlockPool {
+        match kind {
+            PoolKind::Vertex => &self.vbo_pool,
+            PoolKind::Index => &self.ibo_pool,
+            PoolKind::Uniform => &self.ubo_pool,
+            PoolKind::Indirect => &self.indirect_pool,
+            PoolKind::Staging => &self.staging_pool,
+        }
+    }
+
+    /// Get the pool handing out blocks of `kind` mutably.
+    pub(crate) fn pool_mut(&mut self, kind: PoolKind) -> &mut BufferBlockPool {
+        match kind {
+            PoolKind::Vertex => &mut self.vbo_pool,
+            PoolKind::Index => &mut self.ibo_pool,
+            PoolKind::Uniform => &mut self.ubo_pool,
+            PoolKind::Indirect => &mut self.indirect_pool,
+            PoolKind::Staging => &mut self.staging_pool,
+        }
+    }
+
     /// Get a reference to a vertex buffer block, if it exists.
     pub fn get_vertex_block(&self, block: BufferBlockHandle) -> Option<&BufferBlock> {
         self.vbo_pool.get_block(block)
@@ -123,6 +265,16 @@ impl BufferBlockSet {
         self.ibo_pool.get_block_mut(block)
     }
 
+    /// Get a reference to an indirect buffer block, if it exists.
+    pub fn get_indirect_block(&self, block: BufferBlockHandle) -> Option<&BufferBlock> {
+        self.indirect_pool.get_block(block)
+    }
+
+    /// Get a reference to an indirect buffer block, if it exists.
+    pub fn get_indirect_block_mut(&mut self, block: BufferBlockHandle) -> Option<&mut BufferBlock> {
+        self.indirect_pool.get_block_mut(block)
+    }
+
     /// Get a reference to a staging buffer block, if it exists.
     pub fn get_staging_block(&self, block: BufferBlockHandle) -> Option<&BufferBlock> {
         self.staging_pool.get_block(block)
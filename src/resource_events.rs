@@ -0,0 +1,132 @@
+use ash::vk;
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::*;
+
+/// A resource accessed by a submission.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ResourceId {
+    /// A buffer.
+    Buffer(BufferHandle),
+    /// An image.
+    Image(ImageHandle),
+}
+
+/// Whether an access reads a resource, writes it, or both.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum AccessKind {
+    /// The resource is only read.
+    Read,
+    /// The resource is only written.
+    Write,
+    /// The resource is both read and written.
+    ReadWrite,
+}
+
+impl AccessKind {
+    /// Classify a set of access flags. Access flags which neither read nor write (i.e. none)
+    /// count as a read.
+    pub fn from_flags(access: vk::AccessFlags) -> Self {
+        let writes = access.intersects(write_access());
+        let reads = !(access & !write_access()).is_empty();
+        match (reads, writes) {
+            (true, true) => AccessKind::ReadWrite,
+            (false, true) => AccessKind::Write,
+            _ => AccessKind::Read,
+        }
+    }
+}
+
+/// Every access flag which writes.
+fn write_access() -> vk::AccessFlags {
+    vk::AccessFlags::SHADER_WRITE
+        | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+        | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE
+        | vk::AccessFlags::TRANSFER_WRITE
+        | vk::AccessFlags::HOST_WRITE
+        | vk::AccessFlags::MEMORY_WRITE
+}
+
+/// An access to a resource made by a submitted command buffer, passed to the installed
+/// `ResourceEventSink` once the submission containing it has been made.
+#[derive(Clone, Debug)]
+pub struct ResourceAccessEvent {
+    /// The resource accessed.
+    pub resource: ResourceId,
+    /// The resource's tag, if it had one when the event was emitted.
+    pub tag: Option<Tag>,
+    /// The queue the access was submitted to.
+    pub queue: QueueType,
+    /// The id of the submission, unique for the Device. Ids increase in the order batches are
+    /// submitted in, across all queues.
+    pub submission: u64,
+    /// The pipeline stages which access the resource.
+    pub stages: vk::PipelineStageFlags,
+    /// How the resource is accessed.
+    pub access: vk::AccessFlags,
+    /// Whether the access reads or writes the resource.
+    pub kind: AccessKind,
+}
+
+/// Receives `ResourceAccessEvent`s, e.g. to draw a per-resource timeline of which queues
+/// touched which resources when diagnosing synchronization bugs. Install one with
+/// `Device::set_resource_event_sink`.
+///
+/// Events are emitted from whichever thread submits, while the Device's resources are locked
+/// for reading, so a sink should do little more than record them and must not call back into
+/// the Device.
+pub trait ResourceEventSink: Send + Sync {
+    /// Called for every resource access in a batch, right after it has been submitted.
+    fn on_access(&self, event: &ResourceAccessEvent);
+}
+
+/// A resource access recorded into a command buffer.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RecordedAccess {
+    pub(crate) resource: ResourceId,
+    pub(crate) stages: vk::PipelineStageFlags,
+    pub(crate) access: vk::AccessFlags,
+}
+
+impl Device {
+    /// Install the sink resource access events are passed to, or remove it with `None`.
+    ///
+    /// Only accesses `hot` tracks are reported, i.e. those of `CommandBuffer` methods which take
+    /// resource handles, and only those recorded while a sink is installed.
+    pub fn set_resource_event_sink(&self, sink: Option<Arc<dyn ResourceEventSink>>) {
+        *self.resource_event_sink.write() = sink;
+    }
+
+    /// Get whether a resource event sink is installed, i.e. whether accesses must be recorded.
+    pub(crate) fn records_resource_events(&self) -> bool {
+        self.resource_event_sink.read().is_some()
+    }
+
+    /// Report the accesses of a batch submitted to `queue`, giving it the next submission id.
+    pub(crate) fn emit_resource_events(&self, queue: QueueType, accesses: &[RecordedAccess]) {
+        let sink = match *self.resource_event_sink.read() {
+            Some(ref sink) => sink.clone(),
+            None => return,
+        };
+        let submission = self.next_submission_id.fetch_add(1, Ordering::Relaxed);
+
+        let resources = self.resources();
+        for access in accesses {
+            let tag = match access.resource {
+                ResourceId::Buffer(buffer) => resources.get_buffer(buffer).and_then(|b| b.tag.clone()),
+                ResourceId::Image(image) => resources.get_image(image).and_then(|i| i.tag().cloned()),
+            };
+            sink.on_access(&ResourceAccessEvent {
+                resource: access.resource,
+                tag,
+                queue,
+                submission,
+                stages: access.stages,
+                access: access.access,
+                kind: AccessKind::from_flags(access.access),
+            });
+        }
+    }
+}
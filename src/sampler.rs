@@ -0,0 +1,319 @@
+use ash::prelude::VkResult;
+use ash::version::{DeviceV1_0, InstanceV1_1};
+use ash::vk;
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::hash::{Hash, Hasher};
+use std::os::raw::c_void;
+
+use crate::*;
+use crate::cache_stats::CacheKind;
+
+// The version of ash in use predates `VK_EXT_custom_border_color`, so the parts of it hot uses
+// are declared here.
+
+/// The name of the `VK_EXT_custom_border_color` extension.
+pub(crate) fn custom_border_color_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_EXT_custom_border_color\0").unwrap()
+}
+
+const BORDER_COLOR_FLOAT_CUSTOM: i32 = 1_000_287_003;
+const BORDER_COLOR_INT_CUSTOM: i32 = 1_000_287_004;
+
+#[repr(C)]
+pub(crate) struct PhysicalDeviceCustomBorderColorFeatures {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    pub(crate) custom_border_colors: vk::Bool32,
+    pub(crate) custom_border_color_without_format: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceCustomBorderColorFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1_000_287_002),
+            p_next: std::ptr::null_mut(),
+            custom_border_colors: vk::FALSE,
+            custom_border_color_without_format: vk::FALSE,
+        }
+    }
+}
+
+#[repr(C)]
+struct SamplerCustomBorderColorCreateInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    custom_border_color: vk::ClearColorValue,
+    format: vk::Format,
+}
+
+/// Get whether a physical device supports custom border colors without giving the format of
+/// the sampled image. The instance must be at least version 1.1.
+pub(crate) fn supports_custom_border_color(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut border_features = PhysicalDeviceCustomBorderColorFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut border_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    border_features.custom_border_colors == vk::TRUE
+        && border_features.custom_border_color_without_format == vk::TRUE
+}
+
+/// The color read when sampling outside of an image with a `CLAMP_TO_BORDER` address mode.
+#[derive(Clone, Copy, Debug)]
+pub enum SamplerBorderColor {
+    /// One of the standard border colors.
+    Standard(vk::BorderColor),
+    /// An arbitrary color for images with float or normalized formats.
+    Float([f32; 4]),
+    /// An arbitrary color for images with integer formats.
+    Int([i32; 4]),
+}
+
+impl SamplerBorderColor {
+    /// Get the standard border color closest to this one, which is used in its place when
+    /// custom border colors are unsupported.
+    pub fn nearest_standard(self) -> vk::BorderColor {
+        let distance = |color: [f32; 4], standard: [f32; 4]| {
+            color.iter().zip(&standard).map(|(a, b)| (a - b) * (a - b)).sum::<f32>()
+        };
+        let nearest = |color: [f32; 4]| {
+            [[0.0, 0.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0], [1.0, 1.0, 1.0, 1.0]]
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| distance(color, **a).total_cmp(&distance(color, **b)))
+                .map_or(0, |(i, _)| i)
+        };
+        match self {
+            SamplerBorderColor::Standard(border_color) => border_color,
+            SamplerBorderColor::Float(color) => [
+                vk::BorderColor::FLOAT_TRANSPARENT_BLACK,
+                vk::BorderColor::FLOAT_OPAQUE_BLACK,
+                vk::BorderColor::FLOAT_OPAQUE_WHITE,
+            ][nearest(color)],
+            SamplerBorderColor::Int(color) => [
+                vk::BorderColor::INT_TRANSPARENT_BLACK,
+                vk::BorderColor::INT_OPAQUE_BLACK,
+                vk::BorderColor::INT_OPAQUE_WHITE,
+            ][nearest(color.map(|component| component as f32))],
+        }
+    }
+}
+
+// Custom colors are compared and hashed by their bits, so that every color is a distinct key.
+impl PartialEq for SamplerBorderColor {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SamplerBorderColor::Standard(a), SamplerBorderColor::Standard(b)) => a == b,
+            (SamplerBorderColor::Float(a), SamplerBorderColor::Float(b)) => {
+                a.map(f32::to_bits) == b.map(f32::to_bits)
+            }
+            (SamplerBorderColor::Int(a), SamplerBorderColor::Int(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for SamplerBorderColor {}
+
+impl Hash for SamplerBorderColor {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            SamplerBorderColor::Standard(border_color) => border_color.hash(state),
+            SamplerBorderColor::Float(color) => color.map(f32::to_bits).hash(state),
+            SamplerBorderColor::Int(color) => color.hash(state),
+        }
+    }
+}
+
+/// The state needed to create a sampler.
+///
+/// The defaults describe a trilinear sampler which repeats the image, with an opaque black
+/// border.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct SamplerInfo {
+    /// The filter used when magnifying.
+    pub mag_filter: vk::Filter,
+    /// The filter used when minifying.
+    pub min_filter: vk::Filter,
+    /// How mip levels are selected and blended.
+    pub mipmap_mode: vk::SamplerMipmapMode,
+    /// The address modes of the u, v and w coordinates.
+    pub address_modes: [vk::SamplerAddressMode; 3],
+    /// The comparison made against the reference value of a depth comparison sampler, if any.
+    pub compare_op: Option<vk::CompareOp>,
+    /// The color read outside of the image with `CLAMP_TO_BORDER`.
+    pub border_color: SamplerBorderColor,
+    /// The maximum anisotropy of anisotropic filtering, if it is used. It is clamped to
+    /// `Device::max_sampler_anisotropy`, so is ignored on devices without anisotropic filtering.
+    pub max_anisotropy: Option<u32>,
+}
+
+impl Default for SamplerInfo {
+    fn default() -> Self {
+        SamplerInfo {
+            mag_filter: vk::Filter::LINEAR,
+            min_filter: vk::Filter::LINEAR,
+            mipmap_mode: vk::SamplerMipmapMode::LINEAR,
+            address_modes: [vk::SamplerAddressMode::REPEAT; 3],
+            compare_op: None,
+            border_color: SamplerBorderColor::Standard(vk::BorderColor::FLOAT_OPAQUE_BLACK),
+            max_anisotropy: None,
+        }
+    }
+}
+
+impl SamplerInfo {
+    /// A linear depth comparison sampler for shadow maps, which reads `border_color` outside of
+    /// the map.
+    pub fn shadow(compare_op: vk::CompareOp, border_color: SamplerBorderColor) -> Self {
+        SamplerInfo {
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_modes: [vk::SamplerAddressMode::CLAMP_TO_BORDER; 3],
+            compare_op: Some(compare_op),
+            border_color,
+            ..Default::default()
+        }
+    }
+
+    /// A nearest sampler for lookup tables, which reads `border_color` outside of the table.
+    pub fn lookup(border_color: SamplerBorderColor) -> Self {
+        SamplerInfo {
+            mag_filter: vk::Filter::NEAREST,
+            min_filter: vk::Filter::NEAREST,
+            mipmap_mode: vk::SamplerMipmapMode::NEAREST,
+            address_modes: [vk::SamplerAddressMode::CLAMP_TO_BORDER; 3],
+            border_color,
+            ..Default::default()
+        }
+    }
+}
+
+/// A cache of samplers, keyed by their `SamplerInfo`.
+///
+/// The Device owns one, which is used through `Device::request_sampler`.
+#[derive(Debug, Default)]
+pub struct SamplerCache {
+    samplers: HashMap<SamplerInfo, vk::Sampler>,
+}
+
+impl SamplerCache {
+    /// Get a previously created sampler with the given info.
+    pub fn get(&self, info: &SamplerInfo) -> Option<vk::Sampler> {
+        self.samplers.get(info).copied()
+    }
+
+    /// Get the number of cached samplers.
+    pub fn len(&self) -> usize {
+        self.samplers.len()
+    }
+
+    /// Get whether the cache is empty.
+    pub fn is_empty(&self) -> bool {
+        self.samplers.is_empty()
+    }
+
+    /// Iterate over the infos of the cached samplers.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &SamplerInfo> {
+        self.samplers.keys()
+    }
+}
+
+impl Device {
+    /// Get whether `VK_EXT_custom_border_color` is enabled, i.e. whether samplers use custom
+    /// border colors rather than the nearest standard one.
+    pub fn supports_custom_border_color(&self) -> bool {
+        self.custom_border_color
+    }
+
+    /// Get a read-only handle to this Device's cache of samplers.
+    pub fn samplers(&self) -> parking_lot::RwLockReadGuard<'_, SamplerCache> {
+        self.samplers.read()
+    }
+
+    /// Get a sampler described by `info`, creating it if an identical one has not been
+    /// requested before.
+    ///
+    /// Custom border colors fall back to `SamplerBorderColor::nearest_standard` unless the
+    /// Device `supports_custom_border_color`.
+    pub fn request_sampler(&self, info: &SamplerInfo) -> VkResult<vk::Sampler> {
+        if let Some(sampler) = self.samplers.read().get(info) {
+            self.cache_counters.count(CacheKind::Sampler, true);
+            return Ok(sampler);
+        }
+        self.cache_counters.count(CacheKind::Sampler, false);
+
+        let (border_color, custom_color) = match info.border_color {
+            SamplerBorderColor::Float(color) if self.custom_border_color => (
+                vk::BorderColor::from_raw(BORDER_COLOR_FLOAT_CUSTOM),
+                Some(vk::ClearColorValue { float32: color }),
+            ),
+            SamplerBorderColor::Int(color) if self.custom_border_color => (
+                vk::BorderColor::from_raw(BORDER_COLOR_INT_CUSTOM),
+                Some(vk::ClearColorValue { int32: color }),
+            ),
+            border_color => (border_color.nearest_standard(), None),
+        };
+        let custom_info = custom_color.map(|custom_border_color| SamplerCustomBorderColorCreateInfo {
+            s_type: vk::StructureType::from_raw(1_000_287_000),
+            p_next: std::ptr::null(),
+            custom_border_color,
+            format: vk::Format::UNDEFINED,
+        });
+
+        let max_anisotropy = info
+            .max_anisotropy
+            .map_or(1.0, |max_anisotropy| (max_anisotropy as f32).min(self.max_sampler_anisotropy()));
+
+        let [address_mode_u, address_mode_v, address_mode_w] = info.address_modes;
+        let mut create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(info.mag_filter)
+            .min_filter(info.min_filter)
+            .mipmap_mode(info.mipmap_mode)
+            .address_mode_u(address_mode_u)
+            .address_mode_v(address_mode_v)
+            .address_mode_w(address_mode_w)
+            .compare_enable(info.compare_op.is_some())
+            .compare_op(info.compare_op.unwrap_or(vk::CompareOp::NEVER))
+            .anisotropy_enable(max_anisotropy > 1.0)
+            .max_anisotropy(max_anisotropy)
+            .max_lod(vk::LOD_CLAMP_NONE)
+            .border_color(border_color)
+            .build();
+        if let Some(ref custom_info) = custom_info {
+            create_info.p_next = custom_info as *const _ as *const c_void;
+        }
+        let sampler = unsafe { self.device.create_sampler(&create_info, None)? };
+
+        let mut cache = self.samplers.write();
+        match cache.samplers.get(info) {
+            // Another thread created the same sampler in the meantime.
+            Some(&existing) => {
+                unsafe { self.device.destroy_sampler(sampler, None) };
+                Ok(existing)
+            }
+            None => {
+                cache.samplers.insert(*info, sampler);
+                Ok(sampler)
+            }
+        }
+    }
+
+    /// Destroy every cached sampler.
+    ///
+    /// # Safety
+    ///
+    /// None of the samplers may be in use by the GPU, or be written to descriptor sets which
+    /// are used afterwards.
+    pub unsafe fn clear_samplers(&self) {
+        for (_, sampler) in self.samplers.write().samplers.drain() {
+            self.device.destroy_sampler(sampler, None);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! Explicit lifetime scopes for transient resources, a middle ground between managing every
+//! resource by hand and building a render graph.
+//!
+//! `Device::scope` runs a closure with a `Scope`, from which the resources a pass needs only
+//! for its own duration, such as a shadow map's depth buffer, are created. Once the closure
+//! returns, every resource created from the scope is destroyed, which is deferred until the
+//! current frame has completed, so the work recorded with them in the closure may be submitted
+//! any time during the frame.
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// Transient resources created during a call to `Device::scope`, which are destroyed once it
+/// returns.
+pub struct Scope<'a> {
+    device: &'a Arc<Device>,
+    name: &'a str,
+    images: Vec<ImageHandle>,
+    buffers: Vec<BufferHandle>,
+}
+
+impl<'a> Scope<'a> {
+    /// The name the scope was created with.
+    pub fn name(&self) -> &str {
+        self.name
+    }
+
+    /// The Device the scope's resources are created on.
+    pub fn device(&self) -> &Arc<Device> {
+        self.device
+    }
+
+    /// Create an image which is destroyed along with the scope. It is tagged with the scope's
+    /// name.
+    pub fn transient_image(&mut self, create_info: ImageCreateInfo) -> Result<ImageHandle, AllocatorError> {
+        let image = self.device.clone().create_image(create_info, None, self.tag())?;
+        self.images.push(image);
+        Ok(image)
+    }
+
+    /// Create a buffer which is destroyed along with the scope. It is tagged with the scope's
+    /// name.
+    pub fn transient_buffer(&mut self, create_info: BufferCreateInfo) -> Result<BufferHandle, AllocatorError> {
+        let buffer = self.device.clone().create_buffer::<()>(create_info, self.tag(), None)?;
+        self.buffers.push(buffer);
+        Ok(buffer)
+    }
+
+    /// Get a scratch storage image from the Device's pool, like
+    /// `Device::request_scratch_storage_image`. Unlike the scope's other resources, it is
+    /// returned to the pool once the current frame has completed, to be reused by later frames.
+    pub fn scratch_image(&mut self, desc: ScratchImageDesc) -> Result<ImageHandle, AllocatorError> {
+        self.device.request_internal_scratch_image(desc)
+    }
+
+    fn tag(&self) -> Option<Tag> {
+        Some(Tag::Allocated(self.name.to_owned()))
+    }
+}
+
+impl<'a> Drop for Scope<'a> {
+    fn drop(&mut self) {
+        for image in self.images.drain(..) {
+            self.device.destroy_image(image);
+        }
+        for buffer in self.buffers.drain(..) {
+            self.device.destroy_buffer(buffer);
+        }
+    }
+}
+
+impl<'a> std::fmt::Debug for Scope<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Scope")
+            .field("name", &self.name)
+            .field("images", &self.images)
+            .field("buffers", &self.buffers)
+            .finish()
+    }
+}
+
+impl Device {
+    /// Run `f` with a `Scope` named `name`, destroying every transient resource created from
+    /// it once `f` returns (or unwinds). The resources are freed once the current frame has
+    /// completed, so they may be used by any work submitted during the frame, but not later.
+    pub fn scope<R, F>(self: &Arc<Self>, name: &str, f: F) -> R
+    where
+        F: FnOnce(&mut Scope<'_>) -> R,
+    {
+        let mut scope = Scope {
+            device: self,
+            name,
+            images: Vec::new(),
+            buffers: Vec::new(),
+        };
+        f(&mut scope)
+    }
+}
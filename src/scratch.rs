@@ -0,0 +1,124 @@
+use ash::vk;
+
+use std::sync::Arc;
+
+use crate::*;
+use crate::format::format_has_depth_or_stencil_aspect;
+
+/// Describes a scratch storage image requested with `Device::request_scratch_storage_image`.
+/// Scratch images are pooled by their description, so a request reuses any idle image created
+/// for an equal one.
+///
+/// Depth formats can't be used for storage images, so a scratch image with a depth or stencil
+/// format is created as a depth stencil attachment instead.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ScratchImageDesc {
+    /// Width of the image in pixels.
+    pub width: usize,
+    /// Height of the image in pixels.
+    pub height: usize,
+    /// The format of the image, which must support storage image usage unless it is a depth or
+    /// stencil format.
+    pub format: vk::Format,
+    /// Number of mip levels for the image.
+    pub levels: usize,
+    /// Number of image layers.
+    pub layers: usize,
+    /// Usage besides `STORAGE` (or `DEPTH_STENCIL_ATTACHMENT`), e.g. `SAMPLED` for an
+    /// intermediate read by a later pass.
+    pub usage: vk::ImageUsageFlags,
+}
+
+impl ScratchImageDesc {
+    /// Describe a single level, single layer 2d scratch image.
+    pub fn new(width: usize, height: usize, format: vk::Format) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            levels: 1,
+            layers: 1,
+            usage: vk::ImageUsageFlags::empty(),
+        }
+    }
+
+    fn create_info(self) -> ImageCreateInfo {
+        let base_usage = if format_has_depth_or_stencil_aspect(self.format) {
+            vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT
+        } else {
+            vk::ImageUsageFlags::STORAGE
+        };
+        ImageCreateInfo {
+            width: self.width,
+            height: self.height,
+            depth: 1,
+            levels: self.levels,
+            layers: self.layers,
+            format: self.format,
+            usage: base_usage | self.usage,
+            initial_layout: vk::ImageLayout::GENERAL,
+            ..Default::default()
+        }
+    }
+}
+
+impl Device {
+    /// Get a storage image matching `desc` to use as an intermediate within the current frame,
+    /// e.g. between the passes of a post-processing chain.
+    ///
+    /// The image is always in `vk::ImageLayout::GENERAL` and its contents are undefined. It
+    /// belongs to the current frame: it must not be used by work submitted after the frame has
+    /// ended, since it is then returned to the pool once the frame has completed and may be
+    /// handed out again. It must not be destroyed by the caller.
+    pub fn request_scratch_storage_image(
+        self: &Arc<Self>,
+        frame: &Frame,
+        desc: ScratchImageDesc,
+    ) -> Result<ImageHandle, AllocatorError> {
+        self.check_frame(frame);
+        self.request_internal_scratch_image(desc)
+    }
+
+    /// Get a scratch storage image for the current frame without a `Frame`, for passes hot
+    /// records into a command buffer, which can only have been requested during a frame.
+    pub(crate) fn request_internal_scratch_image(
+        self: &Arc<Self>,
+        desc: ScratchImageDesc,
+    ) -> Result<ImageHandle, AllocatorError> {
+        let pooled = self
+            .scratch_images
+            .lock()
+            .get_mut(&desc)
+            .and_then(|images| images.pop());
+        let image = match pooled {
+            Some(image) => image,
+            None => self
+                .clone()
+                .create_image(desc.create_info(), None, Some(Tag::Static("scratch storage image")))?,
+        };
+
+        self.current_frame().write().used_scratch_images.push((desc, image));
+        Ok(image)
+    }
+
+    /// Destroy every pooled scratch image not in use by a frame, e.g. after a resolution change
+    /// which means the old sizes won't be requested again.
+    pub fn trim_scratch_images(&self) {
+        let pooled = std::mem::replace(&mut *self.scratch_images.lock(), self.replay_hash_map());
+        for image in pooled.into_values().flatten() {
+            self.destroy_image(image);
+        }
+    }
+
+    /// Return the scratch images used during a frame which has completed to the pool.
+    pub(crate) fn recycle_scratch_images(&self, frame: &mut PerFrame) {
+        if frame.used_scratch_images.is_empty() {
+            return;
+        }
+
+        let mut pool = self.scratch_images.lock();
+        for (desc, image) in frame.used_scratch_images.drain(..) {
+            pool.entry(desc).or_default().push(image);
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! Screenshots requested with `Device::queue_screenshot` are captured from the next frame
+//! passed to `Device::present`, by a copy which presentation waits on. The capture resolves
+//! without stalling once the copy has completed, and is encoded and written to its path on a
+//! background thread, so the render loop never waits for either.
+//!
+//! The format is chosen by the path's extension: `.png` with the `png` feature, encoded by the
+//! `png` crate, or `.exr` with the `exr` feature, encoded by the `exr` crate. Failures are logged at error level through the `log` crate.
+
+use ash::vk;
+
+use thiserror::Error;
+
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+
+use crate::*;
+use crate::readback::PresentCapture;
+
+/// An error that could occur while encoding or writing a screenshot.
+#[derive(Error, Debug)]
+pub enum ScreenshotError {
+    /// The path's extension is not that of a supported format.
+    #[error("unknown screenshot format for {0}")]
+    UnknownFormat(PathBuf),
+    /// The path's format needs a feature which hot was built without.
+    #[error("hot was built without the `{0}` feature")]
+    FeatureDisabled(&'static str),
+    /// The file could not be written.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The capture could not be encoded as a PNG file.
+    #[cfg(feature = "png")]
+    #[error("png encoding error: {0}")]
+    Png(#[from] png::EncodingError),
+    /// The capture could not be encoded as an OpenEXR file.
+    #[cfg(feature = "exr")]
+    #[error("exr encoding error: {0}")]
+    Exr(#[from] exr::error::Error),
+}
+
+/// A capture in flight, and the paths to write it to once it resolves.
+struct PendingCapture {
+    paths: Vec<PathBuf>,
+    future: ReadbackFuture<ScreenCapture>,
+    /// The temporary image the capture was converted through, if any.
+    converted: Option<ImageHandle>,
+    /// The semaphore presentation waited on, signaled by the capture.
+    semaphore: vk::Semaphore,
+}
+
+/// The screenshots requested, the captures in flight and the thread encoding them.
+#[derive(Default)]
+pub(crate) struct ScreenshotQueue {
+    requested: Vec<PathBuf>,
+    pending: Vec<PendingCapture>,
+    encoder: Option<mpsc::Sender<(PathBuf, ScreenCapture)>>,
+}
+
+impl ScreenshotQueue {
+    /// Send a resolved capture to the encoding thread, starting it if needed.
+    fn encode(&mut self, path: PathBuf, capture: ScreenCapture) {
+        let encoder = self.encoder.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel::<(PathBuf, ScreenCapture)>();
+            // The thread stops once the Device, and with it the sender, is dropped.
+            std::thread::Builder::new()
+                .name(String::from("hot screenshot encoder"))
+                .spawn(move || {
+                    for (path, capture) in receiver {
+                        match write_screenshot(&path, &capture) {
+                            Ok(()) => log::info!("hot: saved screenshot {}", path.display()),
+                            Err(e) => log::error!("hot: failed to save screenshot {}: {}", path.display(), e),
+                        }
+                    }
+                })
+                .expect("failed to spawn screenshot encoder thread");
+            sender
+        });
+        if encoder.send((path, capture)).is_err() {
+            log::error!("hot: the screenshot encoder thread has stopped");
+        }
+    }
+}
+
+/// Encode `capture` in the format of `path`'s extension and write it to `path`.
+#[cfg_attr(not(any(feature = "png", feature = "exr")), allow(unused_variables))]
+fn write_screenshot(path: &Path, capture: &ScreenCapture) -> Result<(), ScreenshotError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    let encoded: Vec<u8> = match extension.as_deref() {
+        #[cfg(feature = "png")]
+        Some("png") => capture.to_png(),
+        #[cfg(not(feature = "png"))]
+        Some("png") => Err(ScreenshotError::FeatureDisabled("png")),
+        #[cfg(feature = "exr")]
+        Some("exr") => capture.to_exr(),
+        #[cfg(not(feature = "exr"))]
+        Some("exr") => Err(ScreenshotError::FeatureDisabled("exr")),
+        _ => Err(ScreenshotError::UnknownFormat(path.to_owned())),
+    }?;
+    std::fs::write(path, encoded)?;
+    Ok(())
+}
+
+impl Device {
+    /// Capture the next frame passed to `present` and save it to `path` in the background. See
+    /// the `screenshot` module.
+    pub fn queue_screenshot<P: Into<PathBuf>>(&self, path: P) {
+        self.screenshots.lock().requested.push(path.into());
+    }
+
+    /// Start capturing `image` for the requested screenshots, if any, returning the semaphore
+    /// presentation must wait on instead of `present_semaphore`.
+    ///
+    /// Failures are logged rather than returned, so that they never prevent presentation.
+    pub(crate) fn capture_screenshots(
+        self: &Arc<Self>,
+        image: ImageHandle,
+        present_semaphore: vk::Semaphore,
+    ) -> Option<vk::Semaphore> {
+        let paths = std::mem::take(&mut self.screenshots.lock().requested);
+        if paths.is_empty() {
+            return None;
+        }
+
+        let semaphore = match self.request_raw_semaphore() {
+            Ok(semaphore) => semaphore,
+            Err(e) => {
+                log::error!("hot: failed to capture screenshot: {}", e);
+                return None;
+            }
+        };
+        let present = PresentCapture {
+            wait: present_semaphore,
+            signal: semaphore,
+        };
+        match self.capture_image_async(image, Some(present)) {
+            Ok((future, converted)) => {
+                self.screenshots.lock().pending.push(PendingCapture {
+                    paths,
+                    future,
+                    converted,
+                    semaphore,
+                });
+                Some(semaphore)
+            }
+            Err(e) => {
+                log::error!("hot: failed to capture screenshot: {}", e);
+                self.semaphore_pool.lock().push(semaphore);
+                None
+            }
+        }
+    }
+
+    /// Send the captures which have resolved to the encoding thread.
+    pub(crate) fn poll_screenshots(&self) {
+        let mut screenshots = self.screenshots.lock();
+        let pending = std::mem::take(&mut screenshots.pending);
+        for capture in pending {
+            let PendingCapture {
+                paths,
+                future,
+                converted,
+                semaphore,
+            } = capture;
+            let result = match future.try_take() {
+                Ok(Err(future)) => {
+                    screenshots.pending.push(PendingCapture {
+                        paths,
+                        future,
+                        converted,
+                        semaphore,
+                    });
+                    continue;
+                }
+                Ok(Ok(result)) => Ok(result),
+                Err(e) => Err(e),
+            };
+
+            if let Some(converted) = converted {
+                self.destroy_image(converted);
+            }
+            // Presentation may still be waiting on the semaphore, so it is only reused once the
+            // current frame has completed too.
+            self.current_frame().write().used_semaphores.push(semaphore);
+
+            match result {
+                Ok(capture) => {
+                    let (last, rest) = paths.split_last().expect("a capture without paths");
+                    for path in rest {
+                        screenshots.encode(path.clone(), capture.clone());
+                    }
+                    screenshots.encode(last.clone(), capture);
+                }
+                Err(e) => log::error!("hot: failed to capture screenshot: {}", e),
+            }
+        }
+    }
+}
+
+impl ScreenCapture {
+    /// Encode the capture as a PNG file.
+    #[cfg(feature = "png")]
+    pub fn to_png(&self) -> Result<Vec<u8>, ScreenshotError> {
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+
+    /// Encode the capture as an OpenEXR file of linear 32-bit float channels, decoding sRGB
+    /// captures.
+    #[cfg(feature = "exr")]
+    pub fn to_exr(&self) -> Result<Vec<u8>, ScreenshotError> {
+        use exr::prelude::*;
+
+        let width = self.width as usize;
+        let srgb = self.format == vk::Format::R8G8B8A8_SRGB;
+        let linear = |value: u8| {
+            let value = value as f32 / 255.0;
+            if !srgb {
+                value
+            } else if value <= 0.04045 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        };
+
+        let channels = SpecificChannels::rgba(|position: Vec2<usize>| {
+            let index = (position.y() * width + position.x()) * 4;
+            let pixel = &self.pixels[index..index + 4];
+            (linear(pixel[0]), linear(pixel[1]), linear(pixel[2]), pixel[3] as f32 / 255.0)
+        });
+        let mut exr = std::io::Cursor::new(Vec::new());
+        Image::from_channels((width, self.height as usize), channels)
+            .write()
+            .to_buffered(&mut exr)?;
+        Ok(exr.into_inner())
+    }
+}
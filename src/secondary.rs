@@ -0,0 +1,256 @@
+use ash::prelude::VkResult;
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use std::sync::Arc;
+
+use crate::*;
+use crate::submit::queue_index;
+
+/// Descriptor sets bound to consecutive set numbers by one call, as recorded in an
+/// `InheritedState`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DescriptorSetBinding {
+    /// The pipeline layout the sets were bound with.
+    pub layout: vk::PipelineLayout,
+    /// The set number of the first set.
+    pub first_set: u32,
+    /// The sets, bound from `first_set` onwards.
+    pub sets: Vec<vk::DescriptorSet>,
+    /// The dynamic offsets of the sets' dynamic buffers, in order.
+    pub dynamic_offsets: Vec<u32>,
+}
+
+/// The graphics state bound in a command buffer which `record_secondary_parallel` re-applies at
+/// the start of each secondary command buffer, since secondaries inherit none of it from their
+/// primary.
+///
+/// State set through `bind_graphics_pipeline`, `bind_graphics_descriptor_sets`,
+/// `set_viewport_for`, `set_viewports` and `set_line_width` is recorded automatically. State set
+/// with raw `ash` calls can be recorded by hand through `inherited_state_mut`.
+#[derive(Clone, Debug, Default)]
+pub struct InheritedState {
+    /// The bound graphics pipeline.
+    pub pipeline: Option<vk::Pipeline>,
+    /// The descriptor set bindings, replayed in order.
+    pub descriptor_sets: Vec<DescriptorSetBinding>,
+    /// The viewports, from viewport 0.
+    pub viewports: Vec<vk::Viewport>,
+    /// The scissors, from scissor 0.
+    pub scissors: Vec<vk::Rect2D>,
+    /// The line width.
+    pub line_width: Option<f32>,
+}
+
+impl InheritedState {
+    /// Record that descriptor sets were bound, replacing earlier bindings to the same or fewer
+    /// sets.
+    pub fn bind_descriptor_sets(&mut self, binding: DescriptorSetBinding) {
+        let end = binding.first_set + binding.sets.len() as u32;
+        self.descriptor_sets.retain(|earlier| {
+            earlier.first_set < binding.first_set
+                || earlier.first_set + earlier.sets.len() as u32 > end
+        });
+        self.descriptor_sets.push(binding);
+    }
+
+    /// Record that `viewports` and `scissors` were set from `first` onwards.
+    pub fn set_viewports(&mut self, first: u32, viewports: &[vk::Viewport], scissors: &[vk::Rect2D]) {
+        fn set<T: Copy + Default>(slots: &mut Vec<T>, first: usize, values: &[T]) {
+            if slots.len() < first + values.len() {
+                slots.resize(first + values.len(), T::default());
+            }
+            slots[first..first + values.len()].copy_from_slice(values);
+        }
+        set(&mut self.viewports, first as usize, viewports);
+        set(&mut self.scissors, first as usize, scissors);
+    }
+}
+
+impl CommandBuffer {
+    /// Bind `pipeline` as the graphics pipeline, recording it in the inherited state.
+    pub fn bind_graphics_pipeline(&mut self, pipeline: vk::Pipeline) {
+        unsafe {
+            self.device()
+                .cmd_bind_pipeline(self.raw(), vk::PipelineBindPoint::GRAPHICS, pipeline)
+        };
+        self.inherited.pipeline = Some(pipeline);
+    }
+
+    /// Bind `sets` from `first_set` onwards for graphics pipelines with `layout`, recording them
+    /// in the inherited state.
+    pub fn bind_graphics_descriptor_sets(
+        &mut self,
+        layout: vk::PipelineLayout,
+        first_set: u32,
+        sets: &[vk::DescriptorSet],
+        dynamic_offsets: &[u32],
+    ) {
+        // Sets must not be updated after being bound, so batched writes are made now.
+        self.device().flush_descriptor_writes();
+        unsafe {
+            self.device().cmd_bind_descriptor_sets(
+                self.raw(),
+                vk::PipelineBindPoint::GRAPHICS,
+                layout,
+                first_set,
+                sets,
+                dynamic_offsets,
+            )
+        };
+        self.inherited.bind_descriptor_sets(DescriptorSetBinding {
+            layout,
+            first_set,
+            sets: sets.to_vec(),
+            dynamic_offsets: dynamic_offsets.to_vec(),
+        });
+    }
+
+    /// Get the graphics state which secondary command buffers recorded from this one start with.
+    pub fn inherited_state(&self) -> &InheritedState {
+        &self.inherited
+    }
+
+    /// Get the graphics state which secondary command buffers recorded from this one start with,
+    /// to record state bound with raw `ash` calls or to leave state out of the secondaries.
+    pub fn inherited_state_mut(&mut self) -> &mut InheritedState {
+        &mut self.inherited
+    }
+
+    /// Record the commands which bind `state`, and make it this command buffer's state.
+    fn apply_inherited_state(&mut self, state: &InheritedState) {
+        let (device, raw) = (self.device().clone(), self.raw());
+        unsafe {
+            if let Some(pipeline) = state.pipeline {
+                device.cmd_bind_pipeline(raw, vk::PipelineBindPoint::GRAPHICS, pipeline);
+            }
+            for binding in &state.descriptor_sets {
+                device.cmd_bind_descriptor_sets(
+                    raw,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    binding.layout,
+                    binding.first_set,
+                    &binding.sets,
+                    &binding.dynamic_offsets,
+                );
+            }
+            if !state.viewports.is_empty() {
+                device.cmd_set_viewport(raw, 0, &state.viewports);
+            }
+            if !state.scissors.is_empty() {
+                device.cmd_set_scissor(raw, 0, &state.scissors);
+            }
+            if let Some(width) = state.line_width {
+                device.cmd_set_line_width(raw, width);
+            }
+        }
+        self.inherited = state.clone();
+    }
+
+    /// Record each of `jobs` into its own secondary command buffer on its own worker thread,
+    /// then execute them in order within `subpass` of the current render pass.
+    ///
+    /// The render pass must have been begun with `begin_render_pass_with_contents` and
+    /// `vk::SubpassContents::SECONDARY_COMMAND_BUFFERS`. Each job is given a command buffer
+    /// which continues the render pass, in which it may bind pipelines, descriptor sets and
+    /// vertex blocks, set dynamic state and draw, but can't record barriers or end the render
+    /// pass. Each starts with this command buffer's `inherited_state` already bound, but
+    /// inherits no other state. Resource events are not recorded for the jobs.
+    ///
+    /// The secondary command buffers are allocated from command pools of the current frame,
+    /// one per job, which later frames in the same slot reuse.
+    pub fn record_secondary_parallel<F>(&mut self, subpass: u32, jobs: Vec<F>) -> VkResult<()>
+    where
+        F: FnOnce(&mut CommandBuffer) + Send,
+    {
+        assert!(!self.secondary, "secondary command buffers cannot record secondaries");
+        let (render_pass, framebuffer, contents) = self
+            .render_pass
+            .expect("record_secondary_parallel: no render pass begun with begin_render_pass_with_contents");
+        assert!(
+            contents == vk::SubpassContents::SECONDARY_COMMAND_BUFFERS,
+            "record_secondary_parallel: the render pass was not begun with SECONDARY_COMMAND_BUFFERS contents"
+        );
+        if jobs.is_empty() {
+            return Ok(());
+        }
+
+        // The pools are taken out of the frame while the jobs record, so that other threads
+        // calling this meanwhile use pools of their own.
+        let device = self.device().clone();
+        let index = queue_index(self.queue_type());
+        let mut pools = std::mem::take(&mut device.current_frame().write().secondary_cmd_pools[index]);
+        if !self.inherited.descriptor_sets.is_empty() {
+            // Sets recorded by hand may have batched writes pending.
+            device.flush_descriptor_writes();
+        }
+        let recorded = record_secondaries(
+            &device,
+            self.queue_type(),
+            &mut pools,
+            jobs,
+            (render_pass, subpass, framebuffer),
+            &self.inherited,
+        );
+        device.current_frame().write().secondary_cmd_pools[index].extend(pools);
+
+        let secondaries = recorded?;
+        unsafe { device.cmd_execute_commands(self.raw(), &secondaries) };
+        Ok(())
+    }
+}
+
+/// Record each job into a secondary command buffer from its own pool, creating pools as needed,
+/// after binding `inherited`, and return the recorded command buffers in the order of the jobs.
+fn record_secondaries<F>(
+    device: &Arc<Device>,
+    queue_type: QueueType,
+    pools: &mut Vec<CommandPool>,
+    jobs: Vec<F>,
+    (render_pass, subpass, framebuffer): (vk::RenderPass, u32, vk::Framebuffer),
+    inherited: &InheritedState,
+) -> VkResult<Vec<vk::CommandBuffer>>
+where
+    F: FnOnce(&mut CommandBuffer) + Send,
+{
+    while pools.len() < jobs.len() {
+        pools.push(unsafe { CommandPool::new(device, device.queue_family_index(queue_type))? });
+    }
+
+    let inheritance = vk::CommandBufferInheritanceInfo::builder()
+        .render_pass(render_pass)
+        .subpass(subpass)
+        .framebuffer(framebuffer);
+    let begin_info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT | vk::CommandBufferUsageFlags::RENDER_PASS_CONTINUE)
+        .inheritance_info(&inheritance);
+
+    let mut secondaries = Vec::with_capacity(jobs.len());
+    for pool in &mut pools[..jobs.len()] {
+        let raw = unsafe { pool.request_secondary_command_buffer(device)? };
+        unsafe { device.begin_command_buffer(raw, &begin_info)? };
+        let mut cmd = CommandBuffer::new(device.clone(), raw, queue_type);
+        cmd.in_render_pass = true;
+        cmd.secondary = true;
+        cmd.apply_inherited_state(inherited);
+        secondaries.push(cmd);
+    }
+
+    std::thread::scope(|scope| {
+        let workers = secondaries
+            .iter_mut()
+            .zip(jobs)
+            .map(|(cmd, job)| {
+                scope.spawn(move || {
+                    job(cmd);
+                    cmd.end()
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+    })?;
+
+    Ok(secondaries.iter().map(CommandBuffer::raw).collect())
+}
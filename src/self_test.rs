@@ -0,0 +1,763 @@
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+
+use std::ffi::CStr;
+use std::ptr::NonNull;
+use std::time::{Duration, Instant};
+
+use crate::*;
+
+/// How long a single self-test submission may take before it is considered hung.
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The number of invocations run by the compute self-test.
+const COMPUTE_INVOCATIONS: u32 = 256;
+
+/// The outcome of a single self-test.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SelfTestOutcome {
+    /// The test ran and produced the expected results.
+    Passed,
+    /// The test ran but failed, with a description of what went wrong.
+    Failed(String),
+    /// The test could not be run on this device, with the reason why.
+    Skipped(String),
+}
+
+/// The result of a single self-test.
+#[derive(Clone, Debug)]
+pub struct SelfTestResult {
+    /// The name of the test.
+    pub name: &'static str,
+    /// Whether the test passed.
+    pub outcome: SelfTestOutcome,
+    /// Extra information gathered by the test, such as a measured GPU time.
+    pub detail: Option<String>,
+    /// How long the test took on the CPU, including waiting for the GPU.
+    pub duration: Duration,
+}
+
+/// A report produced by `Device::run_self_test`, describing the device and the outcome of each test.
+///
+/// The `Display` implementation produces a plain text report suitable for attaching to bug reports.
+#[derive(Clone, Debug)]
+pub struct SelfTestReport {
+    /// The name of the physical device.
+    pub device_name: String,
+    /// The vendor ID of the physical device.
+    pub vendor_id: u32,
+    /// The device ID of the physical device.
+    pub device_id: u32,
+    /// The vendor-specific driver version.
+    pub driver_version: u32,
+    /// The Vulkan version supported by the device.
+    pub api_version: u32,
+    /// The results of each test, in the order they were run.
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    /// Get whether no test failed. Skipped tests do not count as failures.
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|result| !matches!(result.outcome, SelfTestOutcome::Failed(_)))
+    }
+}
+
+impl std::fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} (vendor {:#06x}, device {:#06x}, driver {:#x}, vulkan {}.{}.{})",
+            self.device_name,
+            self.vendor_id,
+            self.device_id,
+            self.driver_version,
+            ash::vk_version_major!(self.api_version),
+            ash::vk_version_minor!(self.api_version),
+            ash::vk_version_patch!(self.api_version),
+        )?;
+
+        for result in &self.results {
+            let outcome = match result.outcome {
+                SelfTestOutcome::Passed => String::from("passed"),
+                SelfTestOutcome::Failed(ref reason) => format!("FAILED: {}", reason),
+                SelfTestOutcome::Skipped(ref reason) => format!("skipped: {}", reason),
+            };
+            write!(f, "  {}: {} ({:?})", result.name, outcome, result.duration)?;
+            if let Some(ref detail) = result.detail {
+                write!(f, " [{}]", detail)?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A test failure, converted into a `SelfTestOutcome::Failed`.
+struct TestFailure(String);
+
+impl From<vk::Result> for TestFailure {
+    fn from(result: vk::Result) -> Self {
+        TestFailure(format!("vulkan error: {}", result))
+    }
+}
+
+impl From<AllocatorError> for TestFailure {
+    fn from(error: AllocatorError) -> Self {
+        TestFailure(format!("allocation error: {}", error))
+    }
+}
+
+type TestResult = Result<Option<String>, TestFailure>;
+
+/// Raw Vulkan objects created by a single test, destroyed when the test finishes whether or not
+/// it succeeded.
+///
+/// The self-test deliberately goes around the `ResourceSet` and frame machinery, so that it
+/// exercises the driver directly and can run at any time without disturbing the application's
+/// frames.
+struct TestContext<'a> {
+    device: &'a Device,
+    command_pool: vk::CommandPool,
+    fence: vk::Fence,
+    buffers: Vec<(vk::Buffer, Allocation)>,
+    images: Vec<(vk::Image, Allocation)>,
+    shader_modules: Vec<vk::ShaderModule>,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    descriptor_pools: Vec<vk::DescriptorPool>,
+    pipeline_layouts: Vec<vk::PipelineLayout>,
+    pipelines: Vec<vk::Pipeline>,
+    query_pools: Vec<vk::QueryPool>,
+}
+
+impl<'a> TestContext<'a> {
+    fn new(device: &'a Device) -> Result<Self, TestFailure> {
+        let pool_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(device.graphics_queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let command_pool = unsafe { device.device.create_command_pool(&pool_info, None)? };
+
+        let mut context = Self {
+            device,
+            command_pool,
+            fence: vk::Fence::null(),
+            buffers: Vec::new(),
+            images: Vec::new(),
+            shader_modules: Vec::new(),
+            descriptor_set_layouts: Vec::new(),
+            descriptor_pools: Vec::new(),
+            pipeline_layouts: Vec::new(),
+            pipelines: Vec::new(),
+            query_pools: Vec::new(),
+        };
+        context.fence = unsafe { device.device.create_fence(&vk::FenceCreateInfo::default(), None)? };
+
+        Ok(context)
+    }
+
+    /// Create a mapped buffer. Returns the buffer, its index in `self.buffers`, and its mapped
+    /// pointer (which is null unless `memory_usage` is host visible).
+    fn create_buffer(
+        &mut self,
+        size: usize,
+        usage: vk::BufferUsageFlags,
+        memory_usage: MemoryUsage,
+    ) -> Result<(vk::Buffer, usize, *mut u8), TestFailure> {
+        let buffer_info = vk::BufferCreateInfo::builder()
+            .size(size as vk::DeviceSize)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let alloc_info = AllocationDesc {
+            usage: memory_usage,
+            mapped: true,
+            ..Default::default()
+        };
+
+        let (buffer, allocation) = self.device.allocator.create_buffer(&buffer_info, &alloc_info)?;
+        let mapped = allocation.mapped_data().map_or(std::ptr::null_mut(), NonNull::as_ptr);
+
+        self.buffers.push((buffer, allocation));
+        Ok((buffer, self.buffers.len() - 1, mapped))
+    }
+
+    /// Create a host visible buffer containing `data`.
+    fn create_upload_buffer(&mut self, data: &[u8]) -> Result<vk::Buffer, TestFailure> {
+        let (buffer, index, mapped) = self.create_buffer(
+            data.len(),
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            MemoryUsage::CpuOnly,
+        )?;
+
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), mapped, data.len()) };
+        self.device
+            .allocator
+            .flush_allocation(&self.buffers[index].1, 0, vk::WHOLE_SIZE as usize)?;
+
+        Ok(buffer)
+    }
+
+    /// Read back the contents of a host visible buffer created by `create_buffer`.
+    fn read_buffer(&self, index: usize, mapped: *mut u8, size: usize) -> Result<Vec<u8>, TestFailure> {
+        self.device
+            .allocator
+            .invalidate_allocation(&self.buffers[index].1, 0, vk::WHOLE_SIZE as usize)?;
+
+        let mut data = vec![0u8; size];
+        unsafe { std::ptr::copy_nonoverlapping(mapped, data.as_mut_ptr(), size) };
+        Ok(data)
+    }
+
+    /// Record a command buffer with `record`, submit it to the graphics queue and wait for it
+    /// to complete.
+    fn submit<F: FnOnce(vk::CommandBuffer)>(&mut self, record: F) -> Result<(), TestFailure> {
+        let device = &self.device.device;
+
+        let alloc_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(self.command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let cmd = unsafe { device.allocate_command_buffers(&alloc_info)?[0] };
+
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+
+        unsafe {
+            device.begin_command_buffer(cmd, &begin_info)?;
+            record(cmd);
+            device.end_command_buffer(cmd)?;
+
+            let command_buffers = [cmd];
+            let submit_info = vk::SubmitInfo::builder().command_buffers(&command_buffers);
+            device.reset_fences(&[self.fence])?;
+            {
+                let _queue = self.device.lock_queue(QueueType::Graphics);
+                device.queue_submit(self.device.graphics_queue, &[submit_info.build()], self.fence)?;
+            }
+
+            match device.wait_for_fences(&[self.fence], true, SELF_TEST_TIMEOUT.as_nanos() as u64) {
+                Ok(()) => Ok(()),
+                Err(vk::Result::TIMEOUT) => Err(TestFailure(format!(
+                    "submission did not complete within {:?}",
+                    SELF_TEST_TIMEOUT
+                ))),
+                Err(e) => Err(e.into()),
+            }
+        }
+    }
+}
+
+impl Drop for TestContext<'_> {
+    fn drop(&mut self) {
+        let device = &self.device.device;
+        unsafe {
+            // If a submission timed out, it may still be executing.
+            let _ = {
+                let _queue = self.device.lock_queue(QueueType::Graphics);
+                device.queue_wait_idle(self.device.graphics_queue)
+            };
+
+            for &query_pool in &self.query_pools {
+                device.destroy_query_pool(query_pool, None);
+            }
+            for &pipeline in &self.pipelines {
+                device.destroy_pipeline(pipeline, None);
+            }
+            for &pipeline_layout in &self.pipeline_layouts {
+                device.destroy_pipeline_layout(pipeline_layout, None);
+            }
+            for &descriptor_pool in &self.descriptor_pools {
+                device.destroy_descriptor_pool(descriptor_pool, None);
+            }
+            for &descriptor_set_layout in &self.descriptor_set_layouts {
+                device.destroy_descriptor_set_layout(descriptor_set_layout, None);
+            }
+            for &shader_module in &self.shader_modules {
+                device.destroy_shader_module(shader_module, None);
+            }
+            for (image, allocation) in self.images.drain(..) {
+                let _ = self.device.allocator.destroy_image(image, &allocation);
+            }
+            for (buffer, allocation) in self.buffers.drain(..) {
+                let _ = self.device.allocator.destroy_buffer(buffer, &allocation);
+            }
+            device.destroy_fence(self.fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
+
+/// Generate `len` bytes of a recognizable, non-repeating test pattern.
+fn test_pattern(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i.wrapping_mul(31) ^ (i >> 8)) as u8).collect()
+}
+
+/// Describe the first mismatch between two byte slices, if any.
+fn compare(expected: &[u8], actual: &[u8]) -> Result<(), TestFailure> {
+    match expected.iter().zip(actual).position(|(a, b)| a != b) {
+        Some(i) => Err(TestFailure(format!(
+            "data mismatch at byte {}: expected {:#04x}, got {:#04x}",
+            i, expected[i], actual[i]
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Upload a pattern to a device local buffer through a staging buffer and read it back.
+fn buffer_roundtrip(device: &Device) -> TestResult {
+    const SIZE: usize = 64 * 1024;
+
+    let mut context = TestContext::new(device)?;
+    let pattern = test_pattern(SIZE);
+
+    let upload = context.create_upload_buffer(&pattern)?;
+    let (gpu, _, _) = context.create_buffer(
+        SIZE,
+        vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryUsage::GpuOnly,
+    )?;
+    let (readback, readback_index, readback_mapped) = context.create_buffer(
+        SIZE,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryUsage::GpuToCpu,
+    )?;
+
+    let region = vk::BufferCopy {
+        src_offset: 0,
+        dst_offset: 0,
+        size: SIZE as vk::DeviceSize,
+    };
+
+    context.submit(|cmd| unsafe {
+        device.cmd_copy_buffer(cmd, upload, gpu, &[region]);
+
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            .build();
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+
+        device.cmd_copy_buffer(cmd, gpu, readback, &[region]);
+    })?;
+
+    compare(&pattern, &context.read_buffer(readback_index, readback_mapped, SIZE)?)?;
+
+    Ok(None)
+}
+
+/// Upload a pattern to an optimally tiled image, blit it into a second image (going through the
+/// texture sampling hardware) and read the result back.
+fn image_roundtrip(device: &Device) -> TestResult {
+    const EXTENT: u32 = 64;
+    const FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+    const SIZE: usize = (EXTENT * EXTENT * 4) as usize;
+
+    let features = device.format_properties(FORMAT).optimal_tiling_features;
+    let required = vk::FormatFeatureFlags::BLIT_SRC | vk::FormatFeatureFlags::BLIT_DST;
+    if !features.contains(required) {
+        return Err(TestFailure(format!("{:?} does not support blits", FORMAT)));
+    }
+
+    let mut context = TestContext::new(device)?;
+    let pattern = test_pattern(SIZE);
+
+    let upload = context.create_upload_buffer(&pattern)?;
+    let (readback, readback_index, readback_mapped) = context.create_buffer(
+        SIZE,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        MemoryUsage::GpuToCpu,
+    )?;
+
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(FORMAT)
+        .extent(vk::Extent3D {
+            width: EXTENT,
+            height: EXTENT,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE)
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+    let alloc_info = AllocationDesc {
+        usage: MemoryUsage::GpuOnly,
+        ..Default::default()
+    };
+
+    let mut images = [vk::Image::null(); 2];
+    for image in images.iter_mut() {
+        let (raw, allocation) = device.allocator.create_image(&image_info, &alloc_info)?;
+        context.images.push((raw, allocation));
+        *image = raw;
+    }
+    let [src, dst] = images;
+
+    let subresource_range = vk::ImageSubresourceRange {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+    let subresource_layers = vk::ImageSubresourceLayers {
+        aspect_mask: vk::ImageAspectFlags::COLOR,
+        mip_level: 0,
+        base_array_layer: 0,
+        layer_count: 1,
+    };
+    let copy = vk::BufferImageCopy {
+        buffer_offset: 0,
+        buffer_row_length: 0,
+        buffer_image_height: 0,
+        image_subresource: subresource_layers,
+        image_offset: vk::Offset3D { x: 0, y: 0, z: 0 },
+        image_extent: image_info.extent,
+    };
+    let corner = vk::Offset3D {
+        x: EXTENT as i32,
+        y: EXTENT as i32,
+        z: 1,
+    };
+    let blit = vk::ImageBlit {
+        src_subresource: subresource_layers,
+        src_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, corner],
+        dst_subresource: subresource_layers,
+        dst_offsets: [vk::Offset3D { x: 0, y: 0, z: 0 }, corner],
+    };
+
+    let barrier = |image, old_layout, new_layout, src_access_mask, dst_access_mask| {
+        vk::ImageMemoryBarrier::builder()
+            .image(image)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .subresource_range(subresource_range)
+            .build()
+    };
+
+    let transfer_barrier = |cmd, barriers: &[vk::ImageMemoryBarrier]| unsafe {
+        device.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::PipelineStageFlags::TRANSFER,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            barriers,
+        );
+    };
+
+    context.submit(|cmd| unsafe {
+        transfer_barrier(cmd, &[
+            barrier(
+                src,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+            ),
+            barrier(
+                dst,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::AccessFlags::empty(),
+                vk::AccessFlags::TRANSFER_WRITE,
+            ),
+        ]);
+
+        device.cmd_copy_buffer_to_image(
+            cmd,
+            upload,
+            src,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[copy],
+        );
+
+        transfer_barrier(cmd, &[barrier(
+            src,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+        )]);
+
+        device.cmd_blit_image(
+            cmd,
+            src,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            dst,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[blit],
+            vk::Filter::NEAREST,
+        );
+
+        transfer_barrier(cmd, &[barrier(
+            dst,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::AccessFlags::TRANSFER_READ,
+        )]);
+
+        device.cmd_copy_image_to_buffer(
+            cmd,
+            dst,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            readback,
+            &[copy],
+        );
+    })?;
+
+    compare(&pattern, &context.read_buffer(readback_index, readback_mapped, SIZE)?)?;
+
+    Ok(None)
+}
+
+/// Run a compute shader which writes its invocation index into a storage buffer, timing it with
+/// timestamp queries if the graphics queue supports them.
+fn compute_dispatch(device: &Device, with_timestamps: bool) -> TestResult {
+    const SIZE: usize = COMPUTE_INVOCATIONS as usize * 4;
+
+    let mut context = TestContext::new(device)?;
+    let raw = &device.device;
+
+    let (storage, storage_index, storage_mapped) = context.create_buffer(
+        SIZE,
+        vk::BufferUsageFlags::STORAGE_BUFFER,
+        MemoryUsage::GpuToCpu,
+    )?;
+
+    let shader_info = vk::ShaderModuleCreateInfo::builder().code(shaders::SELF_TEST);
+    let shader_module = unsafe { raw.create_shader_module(&shader_info, None)? };
+    context.shader_modules.push(shader_module);
+
+    let bindings = [vk::DescriptorSetLayoutBinding::builder()
+        .binding(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .descriptor_count(1)
+        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+        .build()];
+    let set_layout_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings);
+    let set_layout = unsafe { raw.create_descriptor_set_layout(&set_layout_info, None)? };
+    context.descriptor_set_layouts.push(set_layout);
+
+    let set_layouts = [set_layout];
+    let layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&set_layouts);
+    let pipeline_layout = unsafe { raw.create_pipeline_layout(&layout_info, None)? };
+    context.pipeline_layouts.push(pipeline_layout);
+
+    let entry_point = CStr::from_bytes_with_nul(b"main\0").unwrap();
+    let stage = vk::PipelineShaderStageCreateInfo::builder()
+        .stage(vk::ShaderStageFlags::COMPUTE)
+        .module(shader_module)
+        .name(entry_point)
+        .build();
+    let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+        .stage(stage)
+        .layout(pipeline_layout)
+        .build();
+    let pipeline = unsafe {
+        raw.create_compute_pipelines(device.pipeline_cache, &[pipeline_info], None)
+            .map_err(|(_, e)| e)?[0]
+    };
+    context.pipelines.push(pipeline);
+
+    let pool_sizes = [vk::DescriptorPoolSize {
+        ty: vk::DescriptorType::STORAGE_BUFFER,
+        descriptor_count: 1,
+    }];
+    let pool_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(1)
+        .pool_sizes(&pool_sizes);
+    let descriptor_pool = unsafe { raw.create_descriptor_pool(&pool_info, None)? };
+    context.descriptor_pools.push(descriptor_pool);
+
+    let alloc_info = vk::DescriptorSetAllocateInfo::builder()
+        .descriptor_pool(descriptor_pool)
+        .set_layouts(&set_layouts);
+    let descriptor_set = unsafe { raw.allocate_descriptor_sets(&alloc_info)?[0] };
+
+    let buffer_infos = [vk::DescriptorBufferInfo {
+        buffer: storage,
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&buffer_infos)
+        .build();
+    unsafe { raw.update_descriptor_sets(&[write], &[]) };
+
+    let query_pool = if with_timestamps {
+        let query_info = vk::QueryPoolCreateInfo::builder()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(2);
+        let query_pool = unsafe { raw.create_query_pool(&query_info, None)? };
+        context.query_pools.push(query_pool);
+        Some(query_pool)
+    } else {
+        None
+    };
+
+    context.submit(|cmd| unsafe {
+        if let Some(query_pool) = query_pool {
+            raw.cmd_reset_query_pool(cmd, query_pool, 0, 2);
+            raw.cmd_write_timestamp(cmd, vk::PipelineStageFlags::TOP_OF_PIPE, query_pool, 0);
+        }
+
+        raw.cmd_bind_pipeline(cmd, vk::PipelineBindPoint::COMPUTE, pipeline);
+        raw.cmd_bind_descriptor_sets(
+            cmd,
+            vk::PipelineBindPoint::COMPUTE,
+            pipeline_layout,
+            0,
+            &[descriptor_set],
+            &[],
+        );
+        raw.cmd_dispatch(cmd, COMPUTE_INVOCATIONS / 64, 1, 1);
+
+        let barrier = vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ)
+            .build();
+        raw.cmd_pipeline_barrier(
+            cmd,
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::PipelineStageFlags::HOST,
+            vk::DependencyFlags::empty(),
+            &[barrier],
+            &[],
+            &[],
+        );
+
+        if let Some(query_pool) = query_pool {
+            raw.cmd_write_timestamp(cmd, vk::PipelineStageFlags::BOTTOM_OF_PIPE, query_pool, 1);
+        }
+    })?;
+
+    let data = context.read_buffer(storage_index, storage_mapped, SIZE)?;
+    for (i, value) in data.chunks_exact(4).enumerate() {
+        let value = u32::from_ne_bytes([value[0], value[1], value[2], value[3]]);
+        if value != i as u32 {
+            return Err(TestFailure(format!(
+                "invocation {} wrote {}, expected {}",
+                i, value, i
+            )));
+        }
+    }
+
+    match query_pool {
+        Some(query_pool) => {
+            let mut timestamps = [0u64; 2];
+            unsafe {
+                raw.get_query_pool_results(
+                    query_pool,
+                    0,
+                    2,
+                    &mut timestamps,
+                    vk::QueryResultFlags::TYPE_64 | vk::QueryResultFlags::WAIT,
+                )?
+            };
+
+            if timestamps[1] < timestamps[0] {
+                return Err(TestFailure(format!(
+                    "timestamps went backwards: {} then {}",
+                    timestamps[0], timestamps[1]
+                )));
+            }
+
+            let period = device.device_properties.limits.timestamp_period as f64;
+            let nanos = (timestamps[1] - timestamps[0]) as f64 * period;
+            Ok(Some(format!("gpu time {:.3} us", nanos / 1000.0)))
+        }
+        None => Ok(None),
+    }
+}
+
+impl Device {
+    /// Run a battery of end-to-end operations on the device and report the results, for triaging
+    /// driver and platform issues on user machines.
+    ///
+    /// The tests are:
+    ///
+    /// * `buffer_roundtrip`: upload data to a device local buffer and read it back.
+    /// * `image_roundtrip`: upload data to an image, blit it to another image and read it back.
+    /// * `compute_dispatch`: run a small compute shader and check what it wrote.
+    /// * `timestamp_query`: time a compute dispatch with timestamp queries.
+    ///
+    /// All work is submitted to the graphics queue and waited on before returning, independently
+    /// of the frame lifecycle. The graphics queue must not be used from other threads while the
+    /// self-test runs.
+    pub fn run_self_test(&self) -> SelfTestReport {
+        let properties = &self.device_properties;
+        let device_name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let timestamp_valid_bits = unsafe {
+            self.instance
+                .get_physical_device_queue_family_properties(self.physical_device)
+        }[self.graphics_queue_family_index as usize]
+            .timestamp_valid_bits;
+
+        let mut results = Vec::new();
+        let mut run = |name: &'static str, skip: Option<&str>, test: &dyn Fn() -> TestResult| {
+            let start = Instant::now();
+            let (outcome, detail) = match skip {
+                Some(reason) => (SelfTestOutcome::Skipped(reason.to_string()), None),
+                None => match test() {
+                    Ok(detail) => (SelfTestOutcome::Passed, detail),
+                    Err(TestFailure(reason)) => (SelfTestOutcome::Failed(reason), None),
+                },
+            };
+            results.push(SelfTestResult {
+                name,
+                outcome,
+                detail,
+                duration: start.elapsed(),
+            });
+        };
+
+        run("buffer_roundtrip", None, &|| buffer_roundtrip(self));
+        run("image_roundtrip", None, &|| image_roundtrip(self));
+        run("compute_dispatch", None, &|| compute_dispatch(self, false));
+        run(
+            "timestamp_query",
+            if timestamp_valid_bits == 0 {
+                Some("the graphics queue does not support timestamps")
+            } else {
+                None
+            },
+            &|| compute_dispatch(self, true),
+        );
+
+        SelfTestReport {
+            device_name,
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            driver_version: properties.driver_version,
+            api_version: properties.api_version,
+            results,
+        }
+    }
+}
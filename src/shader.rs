@@ -0,0 +1,735 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::*;
+
+/// An error that could occur while reflecting a shader or creating layouts from it.
+#[derive(Error, Debug)]
+pub enum ShaderError {
+    /// The code is not valid SPIR-V.
+    #[error("invalid SPIR-V: {0}")]
+    InvalidSpirv(&'static str),
+    /// The SPIR-V module contains no entry point.
+    #[error("the SPIR-V module has no entry point")]
+    NoEntryPoint,
+    /// Two shaders use the same descriptor binding with different descriptor types or counts.
+    #[error("descriptor set {set} binding {binding} is declared differently by two shaders")]
+    MismatchedBinding {
+        /// The descriptor set.
+        set: u32,
+        /// The binding within the set.
+        binding: u32,
+    },
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A descriptor binding used by a shader.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ShaderDescriptorBinding {
+    /// The descriptor set.
+    pub set: u32,
+    /// The binding within the set.
+    pub binding: u32,
+    /// The type of descriptor.
+    pub descriptor_type: vk::DescriptorType,
+    /// The number of descriptors, which is greater than 1 for arrays of descriptors. Runtime
+    /// sized arrays have a count of 1.
+    pub count: u32,
+}
+
+/// A vertex input of a vertex shader.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ShaderVertexInput {
+    /// The input location.
+    pub location: u32,
+    /// The format matching the type of the input, or `vk::Format::UNDEFINED` if it has no
+    /// corresponding format.
+    pub format: vk::Format,
+}
+
+/// The interface of a shader, as reflected from its SPIR-V.
+#[derive(Clone, Debug)]
+pub struct ShaderReflection {
+    /// The stage of the shader's entry point.
+    pub stage: vk::ShaderStageFlags,
+    /// The name of the shader's entry point.
+    pub entry_point: String,
+    /// The descriptor bindings used by the shader, sorted by set and binding.
+    pub descriptor_bindings: Vec<ShaderDescriptorBinding>,
+    /// The push constant range used by the shader, if any.
+    pub push_constant_range: Option<vk::PushConstantRange>,
+    /// The vertex inputs of the shader, sorted by location. Empty unless it is a vertex shader.
+    pub vertex_inputs: Vec<ShaderVertexInput>,
+    /// The local work group size, if it is a compute shader which declares it as a literal.
+    pub local_size: Option<[u32; 3]>,
+}
+
+/// A shader module along with its reflected interface.
+#[derive(Clone, Debug)]
+pub struct Shader {
+    module: vk::ShaderModule,
+    reflection: ShaderReflection,
+}
+
+impl Shader {
+    /// The raw `vk::ShaderModule`.
+    pub fn raw(&self) -> vk::ShaderModule {
+        self.module
+    }
+
+    /// The reflected interface of the shader.
+    pub fn reflection(&self) -> &ShaderReflection {
+        &self.reflection
+    }
+
+    /// The stage of the shader's entry point.
+    pub fn stage(&self) -> vk::ShaderStageFlags {
+        self.reflection.stage
+    }
+}
+
+/// A pipeline layout and the descriptor set layouts it was created with, built from the
+/// reflected interfaces of a set of shaders.
+#[derive(Clone, Debug)]
+pub struct ShaderLayout {
+    raw: vk::PipelineLayout,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    bindings: Vec<Vec<vk::DescriptorSetLayoutBinding>>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+}
+
+impl ShaderLayout {
+    /// The raw `vk::PipelineLayout`.
+    pub fn raw(&self) -> vk::PipelineLayout {
+        self.raw
+    }
+
+    /// The descriptor set layouts, indexed by set number. Sets which no shader uses have an
+    /// empty layout.
+    pub fn set_layouts(&self) -> &[vk::DescriptorSetLayout] {
+        &self.set_layouts
+    }
+
+    /// The bindings of the descriptor set layout for `set`.
+    pub fn set_bindings(&self, set: u32) -> &[vk::DescriptorSetLayoutBinding] {
+        self.bindings.get(set as usize).map_or(&[], |bindings| bindings.as_slice())
+    }
+
+    /// The push constant ranges, one per shader stage which uses push constants.
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        &self.push_constant_ranges
+    }
+}
+
+impl Device {
+    /// Create a shader module from SPIR-V code and reflect its interface.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn create_shader(&self, code: &[u32]) -> Result<Shader, ShaderError> {
+        let reflection = ShaderReflection::reflect(code)?;
+        let module = self.create_shader_module(code)?;
+        Ok(Shader { module, reflection })
+    }
+
+    /// Destroy a shader created with `create_shader`, along with every cached pipeline which
+    /// uses it.
+    ///
+    /// # Safety
+    ///
+    /// The pipelines using the shader must not be in use by the GPU.
+    pub unsafe fn destroy_shader(&self, shader: Shader) {
+        self.destroy_shader_module(shader.module);
+    }
+
+    /// Create a pipeline layout, and the descriptor set layouts it uses, matching the interfaces
+    /// of `shaders`, which are the shaders of a single pipeline.
+    ///
+    /// Bindings used by several shaders are made visible to all of their stages. If GPU
+    /// assertions are enabled, the set reserved for them always gets the Device's assertion
+    /// layout, whatever the shaders declare in it.
+    pub fn create_shader_layout(&self, shaders: &[&Shader]) -> Result<ShaderLayout, ShaderError> {
+        let mut sets: BTreeMap<u32, BTreeMap<u32, vk::DescriptorSetLayoutBinding>> = BTreeMap::new();
+        let mut push_constant_ranges: Vec<vk::PushConstantRange> = Vec::new();
+
+        for shader in shaders {
+            let reflection = shader.reflection();
+            for binding in &reflection.descriptor_bindings {
+                let set = sets.entry(binding.set).or_default();
+                match set.get_mut(&binding.binding) {
+                    Some(existing) => {
+                        if existing.descriptor_type != binding.descriptor_type
+                            || existing.descriptor_count != binding.count
+                        {
+                            return Err(ShaderError::MismatchedBinding {
+                                set: binding.set,
+                                binding: binding.binding,
+                            });
+                        }
+                        existing.stage_flags |= reflection.stage;
+                    }
+                    None => {
+                        set.insert(
+                            binding.binding,
+                            vk::DescriptorSetLayoutBinding::builder()
+                                .binding(binding.binding)
+                                .descriptor_type(binding.descriptor_type)
+                                .descriptor_count(binding.count)
+                                .stage_flags(reflection.stage)
+                                .build(),
+                        );
+                    }
+                }
+            }
+
+            if let Some(range) = reflection.push_constant_range {
+                // Each stage may only appear in one range.
+                match push_constant_ranges
+                    .iter_mut()
+                    .find(|existing| existing.stage_flags == range.stage_flags)
+                {
+                    Some(existing) => {
+                        let end = (existing.offset + existing.size).max(range.offset + range.size);
+                        existing.offset = existing.offset.min(range.offset);
+                        existing.size = end - existing.offset;
+                    }
+                    None => push_constant_ranges.push(range),
+                }
+            }
+        }
+
+        let assert_set = self.gpu_asserts.as_ref().map(|asserts| asserts.set);
+        let set_count = sets
+            .keys()
+            .next_back()
+            .map_or(0, |&set| set + 1)
+            .max(assert_set.map_or(0, |set| set + 1));
+        let bindings = (0..set_count)
+            .map(|set| match self.gpu_asserts {
+                Some(ref asserts) if asserts.set == set => vec![asserts.binding],
+                _ => sets
+                    .get(&set)
+                    .map(|bindings| bindings.values().copied().collect::<Vec<_>>())
+                    .unwrap_or_default(),
+            })
+            .collect::<Vec<_>>();
+
+        let mut set_layouts = Vec::with_capacity(bindings.len());
+        for (set, set_bindings) in bindings.iter().enumerate() {
+            if let Some(ref asserts) = self.gpu_asserts {
+                if asserts.set == set as u32 {
+                    set_layouts.push(asserts.set_layout);
+                    continue;
+                }
+            }
+            let create_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(set_bindings);
+            match unsafe { self.device.create_descriptor_set_layout(&create_info, None) } {
+                Ok(layout) => set_layouts.push(layout),
+                Err(e) => {
+                    unsafe { self.destroy_set_layouts(set_layouts) };
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let create_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let raw = match unsafe { self.device.create_pipeline_layout(&create_info, None) } {
+            Ok(raw) => raw,
+            Err(e) => {
+                unsafe { self.destroy_set_layouts(set_layouts) };
+                return Err(e.into());
+            }
+        };
+
+        for (set, set_bindings) in bindings.iter().enumerate() {
+            if assert_set != Some(set as u32) {
+                self.track_set_layout(set_bindings, true);
+            }
+        }
+
+        Ok(ShaderLayout {
+            raw,
+            set_layouts,
+            bindings,
+            push_constant_ranges,
+        })
+    }
+
+    /// Destroy a layout created with `create_shader_layout`.
+    ///
+    /// # Safety
+    ///
+    /// No pipelines or descriptor sets created with the layout may be in use by the GPU.
+    pub unsafe fn destroy_shader_layout(&self, layout: ShaderLayout) {
+        self.device.destroy_pipeline_layout(layout.raw, None);
+        let assert_set = self.gpu_asserts.as_ref().map(|asserts| asserts.set);
+        for (set, set_bindings) in layout.bindings.iter().enumerate() {
+            if assert_set != Some(set as u32) {
+                self.track_set_layout(set_bindings, false);
+            }
+        }
+        self.destroy_set_layouts(layout.set_layouts);
+    }
+
+    /// Destroy the set layouts of a shader layout, except the GPU assertion layout, which is
+    /// shared by all of them.
+    unsafe fn destroy_set_layouts(&self, set_layouts: Vec<vk::DescriptorSetLayout>) {
+        let assert_layout = self.gpu_asserts.as_ref().map(|asserts| asserts.set_layout);
+        for set_layout in set_layouts {
+            if Some(set_layout) != assert_layout {
+                self.device.destroy_descriptor_set_layout(set_layout, None);
+            }
+        }
+    }
+}
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+// Opcodes.
+const OP_ENTRY_POINT: u32 = 15;
+const OP_EXECUTION_MODE: u32 = 16;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// Decorations.
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// Storage classes.
+const STORAGE_CLASS_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_CLASS_INPUT: u32 = 1;
+const STORAGE_CLASS_UNIFORM: u32 = 2;
+const STORAGE_CLASS_PUSH_CONSTANT: u32 = 9;
+const STORAGE_CLASS_STORAGE_BUFFER: u32 = 12;
+
+// Image dimensionalities.
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+
+/// A type declared by a SPIR-V module, with its ids left unresolved.
+#[derive(Clone, Debug)]
+enum SpirvType {
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { storage_class: u32, pointee: u32 },
+}
+
+/// The parts of a SPIR-V module needed for reflection.
+#[derive(Default)]
+struct SpirvModule {
+    entry_point: Option<(u32, u32, String)>,
+    local_size: Option<[u32; 3]>,
+    types: HashMap<u32, SpirvType>,
+    constants: HashMap<u32, u32>,
+    /// `(pointer type, id, storage class)` of each global variable.
+    variables: Vec<(u32, u32, u32)>,
+    decorations: HashMap<(u32, u32), u32>,
+    member_decorations: HashMap<(u32, u32, u32), u32>,
+}
+
+impl SpirvModule {
+    fn parse(code: &[u32]) -> Result<Self, ShaderError> {
+        if code.len() < 5 {
+            return Err(ShaderError::InvalidSpirv("module is too short"));
+        }
+        if code[0] != SPIRV_MAGIC {
+            return Err(ShaderError::InvalidSpirv("bad magic number"));
+        }
+
+        let mut module = SpirvModule::default();
+        let mut words = &code[5..];
+        while !words.is_empty() {
+            let word_count = (words[0] >> 16) as usize;
+            let opcode = words[0] & 0xffff;
+            if word_count == 0 || word_count > words.len() {
+                return Err(ShaderError::InvalidSpirv("bad instruction length"));
+            }
+            let operands = &words[1..word_count];
+            words = &words[word_count..];
+
+            let operand = |i: usize| {
+                operands
+                    .get(i)
+                    .copied()
+                    .ok_or(ShaderError::InvalidSpirv("missing instruction operand"))
+            };
+
+            match opcode {
+                OP_ENTRY_POINT if module.entry_point.is_none() => {
+                    let name = parse_string(&operands[2..]);
+                    module.entry_point = Some((operand(0)?, operand(1)?, name));
+                }
+                OP_EXECUTION_MODE if operand(1)? == EXECUTION_MODE_LOCAL_SIZE => {
+                    let is_entry_point = module
+                        .entry_point
+                        .as_ref()
+                        .is_some_and(|&(_, id, _)| id == operands[0]);
+                    if is_entry_point {
+                        module.local_size = Some([operand(2)?, operand(3)?, operand(4)?]);
+                    }
+                }
+                OP_TYPE_INT => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Int {
+                            width: operand(1)?,
+                            signed: operand(2)? != 0,
+                        },
+                    );
+                }
+                OP_TYPE_FLOAT => {
+                    module.types.insert(operand(0)?, SpirvType::Float { width: operand(1)? });
+                }
+                OP_TYPE_VECTOR => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Vector {
+                            component: operand(1)?,
+                            count: operand(2)?,
+                        },
+                    );
+                }
+                OP_TYPE_MATRIX => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Matrix {
+                            column: operand(1)?,
+                            count: operand(2)?,
+                        },
+                    );
+                }
+                OP_TYPE_IMAGE => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Image {
+                            dim: operand(2)?,
+                            sampled: operand(6)?,
+                        },
+                    );
+                }
+                OP_TYPE_SAMPLER => {
+                    module.types.insert(operand(0)?, SpirvType::Sampler);
+                }
+                OP_TYPE_SAMPLED_IMAGE => {
+                    module.types.insert(operand(0)?, SpirvType::SampledImage);
+                }
+                OP_TYPE_ARRAY => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Array {
+                            element: operand(1)?,
+                            length: operand(2)?,
+                        },
+                    );
+                }
+                OP_TYPE_RUNTIME_ARRAY => {
+                    module
+                        .types
+                        .insert(operand(0)?, SpirvType::RuntimeArray { element: operand(1)? });
+                }
+                OP_TYPE_STRUCT => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Struct {
+                            members: operands[1..].to_vec(),
+                        },
+                    );
+                }
+                OP_TYPE_POINTER => {
+                    module.types.insert(
+                        operand(0)?,
+                        SpirvType::Pointer {
+                            storage_class: operand(1)?,
+                            pointee: operand(2)?,
+                        },
+                    );
+                }
+                OP_CONSTANT => {
+                    module.constants.insert(operand(1)?, operand(2)?);
+                }
+                OP_VARIABLE => {
+                    module.variables.push((operand(0)?, operand(1)?, operand(2)?));
+                }
+                OP_DECORATE => {
+                    module
+                        .decorations
+                        .insert((operand(0)?, operand(1)?), operands.get(2).copied().unwrap_or(0));
+                }
+                OP_MEMBER_DECORATE => {
+                    module.member_decorations.insert(
+                        (operand(0)?, operand(1)?, operand(2)?),
+                        operands.get(3).copied().unwrap_or(0),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        Ok(module)
+    }
+
+    fn ty(&self, id: u32) -> Result<&SpirvType, ShaderError> {
+        self.types
+            .get(&id)
+            .ok_or(ShaderError::InvalidSpirv("reference to an undeclared type"))
+    }
+
+    fn decoration(&self, id: u32, decoration: u32) -> Option<u32> {
+        self.decorations.get(&(id, decoration)).copied()
+    }
+
+    /// Get the descriptor type and count of a resource variable's type.
+    fn descriptor_type(
+        &self,
+        storage_class: u32,
+        ty: u32,
+    ) -> Result<Option<(vk::DescriptorType, u32)>, ShaderError> {
+        let (ty, count) = match self.ty(ty)? {
+            SpirvType::Array { element, length } => (*element, self.constants.get(length).copied().unwrap_or(1)),
+            SpirvType::RuntimeArray { element } => (*element, 1),
+            _ => (ty, 1),
+        };
+
+        let descriptor_type = match (storage_class, self.ty(ty)?) {
+            (_, SpirvType::Sampler) => vk::DescriptorType::SAMPLER,
+            (_, SpirvType::SampledImage) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (_, &SpirvType::Image { dim, sampled }) => match (dim, sampled) {
+                (DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            (STORAGE_CLASS_STORAGE_BUFFER, SpirvType::Struct { .. }) => vk::DescriptorType::STORAGE_BUFFER,
+            (STORAGE_CLASS_UNIFORM, SpirvType::Struct { .. }) => {
+                if self.decoration(ty, DECORATION_BUFFER_BLOCK).is_some() {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::UNIFORM_BUFFER
+                }
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some((descriptor_type, count)))
+    }
+
+    /// Get the size in bytes of a type laid out with explicit offsets and strides.
+    fn size_of(&self, ty: u32, matrix_stride: Option<u32>) -> Result<u32, ShaderError> {
+        Ok(match self.ty(ty)? {
+            SpirvType::Int { width, .. } | SpirvType::Float { width } => width / 8,
+            &SpirvType::Vector { component, count } => self.size_of(component, None)? * count,
+            &SpirvType::Matrix { column, count } => match matrix_stride {
+                Some(stride) => stride * count,
+                None => self.size_of(column, None)? * count,
+            },
+            &SpirvType::Array { element, length } => {
+                let length = self.constants.get(&length).copied().unwrap_or(1);
+                match self.decoration(ty, DECORATION_ARRAY_STRIDE) {
+                    Some(stride) => stride * length,
+                    None => self.size_of(element, matrix_stride)? * length,
+                }
+            }
+            SpirvType::RuntimeArray { .. } => 0,
+            SpirvType::Struct { members } => {
+                let mut size = 0;
+                for (i, &member) in members.iter().enumerate() {
+                    let offset = self
+                        .member_decorations
+                        .get(&(ty, i as u32, DECORATION_OFFSET))
+                        .copied()
+                        .unwrap_or(size);
+                    let stride = self
+                        .member_decorations
+                        .get(&(ty, i as u32, DECORATION_MATRIX_STRIDE))
+                        .copied();
+                    size = size.max(offset + self.size_of(member, stride)?);
+                }
+                size
+            }
+            _ => 0,
+        })
+    }
+
+    /// Get the offset of the first member of a struct, or 0 for other types.
+    fn first_member_offset(&self, ty: u32) -> Result<u32, ShaderError> {
+        Ok(match self.ty(ty)? {
+            SpirvType::Struct { members } => (0..members.len() as u32)
+                .filter_map(|i| self.member_decorations.get(&(ty, i, DECORATION_OFFSET)).copied())
+                .min()
+                .unwrap_or(0),
+            _ => 0,
+        })
+    }
+
+    /// Get the vertex attribute format corresponding to a type.
+    fn vertex_format(&self, ty: u32) -> Result<vk::Format, ShaderError> {
+        let (component, count) = match *self.ty(ty)? {
+            SpirvType::Vector { component, count } => (component, count),
+            _ => (ty, 1),
+        };
+
+        let formats = match *self.ty(component)? {
+            SpirvType::Float { width: 32 } => [
+                vk::Format::R32_SFLOAT,
+                vk::Format::R32G32_SFLOAT,
+                vk::Format::R32G32B32_SFLOAT,
+                vk::Format::R32G32B32A32_SFLOAT,
+            ],
+            SpirvType::Float { width: 64 } => [
+                vk::Format::R64_SFLOAT,
+                vk::Format::R64G64_SFLOAT,
+                vk::Format::R64G64B64_SFLOAT,
+                vk::Format::R64G64B64A64_SFLOAT,
+            ],
+            SpirvType::Int { width: 32, signed: true } => [
+                vk::Format::R32_SINT,
+                vk::Format::R32G32_SINT,
+                vk::Format::R32G32B32_SINT,
+                vk::Format::R32G32B32A32_SINT,
+            ],
+            SpirvType::Int { width: 32, signed: false } => [
+                vk::Format::R32_UINT,
+                vk::Format::R32G32_UINT,
+                vk::Format::R32G32B32_UINT,
+                vk::Format::R32G32B32A32_UINT,
+            ],
+            _ => return Ok(vk::Format::UNDEFINED),
+        };
+
+        Ok(formats
+            .get(count as usize - 1)
+            .copied()
+            .unwrap_or(vk::Format::UNDEFINED))
+    }
+}
+
+/// Parse a nul-terminated string packed into words.
+fn parse_string(words: &[u32]) -> String {
+    let bytes = words
+        .iter()
+        .flat_map(|word| word.to_le_bytes().to_vec())
+        .take_while(|&byte| byte != 0)
+        .collect::<Vec<_>>();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+impl ShaderReflection {
+    /// Reflect the interface of the first entry point of a SPIR-V module.
+    pub fn reflect(code: &[u32]) -> Result<Self, ShaderError> {
+        let module = SpirvModule::parse(code)?;
+
+        let (execution_model, _, entry_point) = module
+            .entry_point
+            .clone()
+            .ok_or(ShaderError::NoEntryPoint)?;
+        let stage = match execution_model {
+            0 => vk::ShaderStageFlags::VERTEX,
+            1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+            2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+            3 => vk::ShaderStageFlags::GEOMETRY,
+            4 => vk::ShaderStageFlags::FRAGMENT,
+            5 => vk::ShaderStageFlags::COMPUTE,
+            _ => return Err(ShaderError::InvalidSpirv("unsupported execution model")),
+        };
+
+        let mut descriptor_bindings = Vec::new();
+        let mut push_constant_range = None;
+        let mut vertex_inputs = Vec::new();
+
+        for &(pointer, id, storage_class) in &module.variables {
+            let pointee = match *module.ty(pointer)? {
+                SpirvType::Pointer { pointee, .. } => pointee,
+                _ => return Err(ShaderError::InvalidSpirv("variable is not a pointer")),
+            };
+
+            match storage_class {
+                STORAGE_CLASS_UNIFORM_CONSTANT | STORAGE_CLASS_UNIFORM | STORAGE_CLASS_STORAGE_BUFFER => {
+                    let (set, binding) = match (
+                        module.decoration(id, DECORATION_DESCRIPTOR_SET),
+                        module.decoration(id, DECORATION_BINDING),
+                    ) {
+                        (Some(set), Some(binding)) => (set, binding),
+                        _ => continue,
+                    };
+                    if let Some((descriptor_type, count)) = module.descriptor_type(storage_class, pointee)? {
+                        descriptor_bindings.push(ShaderDescriptorBinding {
+                            set,
+                            binding,
+                            descriptor_type,
+                            count,
+                        });
+                    }
+                }
+                STORAGE_CLASS_PUSH_CONSTANT => {
+                    let offset = module.first_member_offset(pointee)?;
+                    let size = module.size_of(pointee, None)?;
+                    push_constant_range = Some(vk::PushConstantRange {
+                        stage_flags: stage,
+                        offset,
+                        size: size - offset,
+                    });
+                }
+                STORAGE_CLASS_INPUT if stage == vk::ShaderStageFlags::VERTEX => {
+                    if module.decoration(id, DECORATION_BUILT_IN).is_some() {
+                        continue;
+                    }
+                    if let Some(location) = module.decoration(id, DECORATION_LOCATION) {
+                        vertex_inputs.push(ShaderVertexInput {
+                            location,
+                            format: module.vertex_format(pointee)?,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        descriptor_bindings.sort_by_key(|binding| (binding.set, binding.binding));
+        vertex_inputs.sort_by_key(|input| input.location);
+
+        Ok(ShaderReflection {
+            stage,
+            entry_point,
+            descriptor_bindings,
+            push_constant_range,
+            vertex_inputs,
+            local_size: module.local_size,
+        })
+    }
+}
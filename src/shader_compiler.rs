@@ -0,0 +1,118 @@
+//! Runtime compilation of GLSL and WGSL to SPIR-V with naga, the compiler hot's built-in shaders
+//! are built with, so that shaders can be edited and reloaded without an external compiler.
+//!
+//! naga's GLSL frontend supports neither `#include` nor combined image samplers, so GLSL sources
+//! sample through separate `texture2D` and `sampler` bindings, e.g. with
+//! `texture(sampler2D(tex, tex_sampler), uv)`. Includes tracked with `ShaderDependencies` have to
+//! be resolved by the caller before compiling.
+
+use ash::vk;
+
+use naga::back::spv;
+use naga::front::{glsl, wgsl};
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+use thiserror::Error;
+
+use crate::*;
+
+/// An error that could occur while compiling a shader at runtime.
+#[derive(Error, Debug)]
+pub enum ShaderCompileError {
+    /// The stage of a GLSL shader is not a vertex, fragment or compute stage.
+    #[error("unsupported GLSL shader stage {0:?}")]
+    UnsupportedStage(vk::ShaderStageFlags),
+    /// The source could not be parsed.
+    #[error("failed to parse shader:\n{0}")]
+    Parse(String),
+    /// The parsed shader is not valid.
+    #[error("invalid shader:\n{0}")]
+    Validation(String),
+    /// The shader could not be written as SPIR-V.
+    #[error("failed to write SPIR-V: {0}")]
+    Spirv(#[from] spv::Error),
+    /// The compiled shader could not be reflected or created.
+    #[error("shader error: {0}")]
+    Shader(#[from] ShaderError),
+}
+
+/// The source of a shader compiled with `compile_shader`.
+#[derive(Clone, Copy, Debug)]
+pub enum ShaderSource<'a> {
+    /// A GLSL shader with a `main` entry point.
+    Glsl {
+        /// The source.
+        source: &'a str,
+        /// The stage, one of `VERTEX`, `FRAGMENT` or `COMPUTE`.
+        stage: vk::ShaderStageFlags,
+        /// Preprocessor definitions, as pairs of name and value.
+        defines: &'a [(&'a str, &'a str)],
+    },
+    /// A WGSL shader. As with SPIR-V modules holding several entry points, `Device::create_shader`
+    /// reflects the first of them.
+    Wgsl(&'a str),
+}
+
+/// Compile `source` to SPIR-V, to be passed to `Device::create_shader`.
+///
+/// Shaders are compiled as the built-in ones are: written for Vulkan, so without any change of
+/// coordinate space, and without zeroing workgroup memory.
+pub fn compile_shader(source: &ShaderSource<'_>) -> Result<Vec<u32>, ShaderCompileError> {
+    let (module, text) = match *source {
+        ShaderSource::Glsl {
+            source,
+            stage,
+            defines,
+        } => {
+            let naga_stage = match stage {
+                vk::ShaderStageFlags::VERTEX => naga::ShaderStage::Vertex,
+                vk::ShaderStageFlags::FRAGMENT => naga::ShaderStage::Fragment,
+                vk::ShaderStageFlags::COMPUTE => naga::ShaderStage::Compute,
+                _ => return Err(ShaderCompileError::UnsupportedStage(stage)),
+            };
+            let mut options = glsl::Options::from(naga_stage);
+            for &(name, value) in defines {
+                options
+                    .defines
+                    .insert(String::from(name), String::from(value));
+            }
+            let module = glsl::Frontend::default()
+                .parse(&options, source)
+                .map_err(|errors| {
+                    let messages = errors
+                        .iter()
+                        .map(|error| error.to_string())
+                        .collect::<Vec<_>>();
+                    ShaderCompileError::Parse(messages.join("\n"))
+                })?;
+            (module, source)
+        }
+        ShaderSource::Wgsl(source) => {
+            let module = wgsl::parse_str(source)
+                .map_err(|error| ShaderCompileError::Parse(error.emit_to_string(source)))?;
+            (module, source)
+        }
+    };
+
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|error| ShaderCompileError::Validation(error.emit_to_string(text)))?;
+
+    let options = spv::Options {
+        flags: spv::WriterFlags::empty(),
+        zero_initialize_workgroup_memory: spv::ZeroInitializeWorkgroupMemoryMode::None,
+        ..Default::default()
+    };
+    Ok(spv::write_vec(&module, &info, &options, None)?)
+}
+
+impl Device {
+    /// Compile `source` with `compile_shader` and create a shader module from it.
+    pub fn create_shader_from_source(
+        &self,
+        source: &ShaderSource<'_>,
+    ) -> Result<Shader, ShaderCompileError> {
+        let code = compile_shader(source)?;
+        Ok(self.create_shader(&code)?)
+    }
+}
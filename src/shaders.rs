@@ -0,0 +1,11 @@
+use ash::vk;
+
+// The SPIR-V of the built-in shaders, compiled by `build.rs` from the GLSL and WGSL in
+// `src/shaders`.
+include!(concat!(env!("OUT_DIR"), "/shaders.rs"));
+
+/// Get the index of the variant of a shader writing storage images of `format`, if it was
+/// compiled for the format.
+pub(crate) fn storage_format_variant(format: vk::Format) -> Option<usize> {
+    STORAGE_FORMATS.iter().position(|&variant| variant == format)
+}
@@ -0,0 +1,15 @@
+#version 450
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+
+layout(push_constant) uniform Params {
+    mat4 view_proj;
+};
+
+void main() {
+    gl_Position = view_proj * vec4(position, 1.0);
+    out_color = color;
+}
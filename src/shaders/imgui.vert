@@ -0,0 +1,20 @@
+#version 450
+
+layout(location = 0) in vec2 position;
+layout(location = 1) in vec2 uv;
+layout(location = 2) in vec4 color;
+
+layout(location = 0) out vec4 out_color;
+layout(location = 1) out vec2 out_uv;
+
+// Maps ImGui's display coordinates to clip space.
+layout(push_constant) uniform Params {
+    vec2 scale;
+    vec2 translate;
+};
+
+void main() {
+    gl_Position = vec4(position * scale + translate, 0.0, 1.0);
+    out_color = color;
+    out_uv = uv;
+}
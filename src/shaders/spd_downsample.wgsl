@@ -0,0 +1,166 @@
+// Downsamples level 0 into up to 12 mip levels in a single dispatch, in the manner of AMD's
+// FidelityFX SPD. Each work group reduces a 64x64 tile of level 0 into its texels of levels 1 to
+// 6, and hands its level 6 texel over through `global`. The last group to finish, found with the
+// atomic counter, reduces level 6 into levels 7 to 12 and resets the counter.
+//
+// Every level is a 2x2 box filter of the one above it. Written in WGSL rather than GLSL as naga's
+// GLSL frontend has no atomics, and `DST_FORMAT` is replaced with each storage format supported.
+
+struct Params {
+    // The number of levels to write after level 0.
+    mips: u32,
+    // The number of work groups dispatched.
+    groups: u32,
+}
+
+struct Global {
+    // The number of groups which have written their level 6 texel.
+    counter: atomic<u32>,
+    // The bits of the four channels of each level 6 texel, in rows of 64 texels.
+    level6: array<atomic<u32>>,
+}
+
+@group(0) @binding(0) var src: texture_2d<f32>;
+@group(0) @binding(1) var mip1: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(2) var mip2: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(3) var mip3: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(4) var mip4: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(5) var mip5: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(6) var mip6: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(7) var mip7: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(8) var mip8: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(9) var mip9: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(10) var mip10: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(11) var mip11: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(12) var mip12: texture_storage_2d<DST_FORMAT, write>;
+@group(0) @binding(13) var<storage, read_write> global: Global;
+
+var<push_constant> params: Params;
+
+// The 16x16 texels of the group's first level reduced in registers, reduced in place further.
+var<workgroup> tile: array<array<vec4<f32>, 16>, 16>;
+var<workgroup> is_last: u32;
+
+fn level_size(mip: u32) -> vec2<u32> {
+    return max(textureDimensions(src) >> vec2<u32>(mip), vec2<u32>(1u));
+}
+
+fn store(mip: u32, coord: vec2<u32>, value: vec4<f32>) {
+    if (mip > params.mips || any(coord >= level_size(mip))) {
+        return;
+    }
+    let texel = vec2<i32>(coord);
+    switch mip {
+        case 1u: { textureStore(mip1, texel, value); }
+        case 2u: { textureStore(mip2, texel, value); }
+        case 3u: { textureStore(mip3, texel, value); }
+        case 4u: { textureStore(mip4, texel, value); }
+        case 5u: { textureStore(mip5, texel, value); }
+        case 6u: { textureStore(mip6, texel, value); }
+        case 7u: { textureStore(mip7, texel, value); }
+        case 8u: { textureStore(mip8, texel, value); }
+        case 9u: { textureStore(mip9, texel, value); }
+        case 10u: { textureStore(mip10, texel, value); }
+        case 11u: { textureStore(mip11, texel, value); }
+        case 12u: { textureStore(mip12, texel, value); }
+        default: {}
+    }
+}
+
+fn level6_index(coord: vec2<u32>) -> u32 {
+    return (coord.y * 64u + coord.x) * 4u;
+}
+
+// Load a texel of level 0, or of level 6 once handed over, clamped to the level.
+fn load(mip: u32, coord: vec2<u32>) -> vec4<f32> {
+    if (mip == 0u) {
+        return textureLoad(src, vec2<i32>(min(coord, textureDimensions(src) - 1u)), 0);
+    }
+    let i = level6_index(min(coord, level_size(6u) - 1u));
+    return vec4<f32>(
+        bitcast<f32>(atomicLoad(&global.level6[i])),
+        bitcast<f32>(atomicLoad(&global.level6[i + 1u])),
+        bitcast<f32>(atomicLoad(&global.level6[i + 2u])),
+        bitcast<f32>(atomicLoad(&global.level6[i + 3u])),
+    );
+}
+
+// Reduce the 64x64 texels of level `first - 1` at `group` into the 32x32 of level `first`, each
+// invocation writing 2x2 of them, and those into the 16x16 of level `first + 1` in `tile`.
+fn reduce_registers(first: u32, group: vec2<u32>, local: vec2<u32>) {
+    var sum = vec4<f32>(0.0);
+    for (var i = 0u; i < 4u; i = i + 1u) {
+        let coord = group * 32u + local * 2u + vec2<u32>(i & 1u, i >> 1u);
+        let src_coord = coord * 2u;
+        let value = (load(first - 1u, src_coord)
+            + load(first - 1u, src_coord + vec2<u32>(1u, 0u))
+            + load(first - 1u, src_coord + vec2<u32>(0u, 1u))
+            + load(first - 1u, src_coord + vec2<u32>(1u, 1u))) * 0.25;
+        store(first, coord, value);
+        sum = sum + value;
+    }
+    tile[local.y][local.x] = sum * 0.25;
+    store(first + 1u, group * 16u + local, sum * 0.25);
+}
+
+// Reduce the 16x16 texels in `tile` into the 8x8 of level `first` at `group`, and so on down to
+// the single texel of level `first + 3`, which is left in `tile[0][0]`.
+fn reduce_tile(first: u32, group: vec2<u32>, index: u32) {
+    for (var level = 0u; level < 4u; level = level + 1u) {
+        let size = 8u >> level;
+        let local = vec2<u32>(index % size, index / size);
+        let in_level = index < size * size;
+        var value = vec4<f32>(0.0);
+        if (in_level) {
+            let c = local * 2u;
+            value = (tile[c.y][c.x] + tile[c.y][c.x + 1u] + tile[c.y + 1u][c.x]
+                + tile[c.y + 1u][c.x + 1u]) * 0.25;
+        }
+        workgroupBarrier();
+        if (in_level) {
+            tile[local.y][local.x] = value;
+            store(first + level, group * size + local, value);
+        }
+        workgroupBarrier();
+    }
+}
+
+@compute @workgroup_size(256)
+fn main(
+    @builtin(workgroup_id) group_id: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+) {
+    let local = vec2<u32>(index % 16u, index / 16u);
+    reduce_registers(1u, group_id.xy, local);
+    workgroupBarrier();
+    reduce_tile(3u, group_id.xy, index);
+    if (params.mips <= 6u) {
+        return;
+    }
+
+    if (index == 0u) {
+        let value = tile[0][0];
+        let i = level6_index(group_id.xy);
+        atomicStore(&global.level6[i], bitcast<u32>(value.x));
+        atomicStore(&global.level6[i + 1u], bitcast<u32>(value.y));
+        atomicStore(&global.level6[i + 2u], bitcast<u32>(value.z));
+        atomicStore(&global.level6[i + 3u], bitcast<u32>(value.w));
+    }
+    // Make the level 6 texel visible to the other groups before counting this one as finished.
+    storageBarrier();
+    if (index == 0u) {
+        is_last = u32(atomicAdd(&global.counter, 1u) == params.groups - 1u);
+    }
+    if (workgroupUniformLoad(&is_last) == 0u) {
+        return;
+    }
+    // Make every group's level 6 texel visible to this one.
+    storageBarrier();
+    if (index == 0u) {
+        atomicStore(&global.counter, 0u);
+    }
+
+    reduce_registers(7u, vec2<u32>(0u), local);
+    workgroupBarrier();
+    reduce_tile(9u, vec2<u32>(0u), index);
+}
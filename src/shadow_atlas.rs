@@ -0,0 +1,524 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use generational_arena as ga;
+
+use thiserror::Error;
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
+use crate::format::{format_has_depth_aspect, format_has_stencil_aspect};
+use crate::*;
+
+/// The default smallest tile a `ShadowAtlasManager` hands out, in texels.
+pub const DEFAULT_SHADOW_TILE_MIN_SIZE: u32 = 64;
+
+/// An error that could occur while creating or using a `ShadowAtlasManager`.
+#[derive(Error, Debug)]
+pub enum ShadowAtlasError {
+    /// The atlas or minimum tile size is zero or not a power of two, or the minimum tile size
+    /// is larger than the atlas.
+    #[error("invalid shadow atlas size {size} with minimum tile size {min_tile_size}")]
+    InvalidSize {
+        /// The size of the atlas.
+        size: u32,
+        /// The minimum tile size.
+        min_tile_size: u32,
+    },
+    /// The format is not a depth-only format.
+    #[error("unsupported shadow atlas format {0:?}")]
+    UnsupportedFormat(vk::Format),
+    /// No free region of the atlas is large enough for a tile of the requested resolution.
+    #[error("no room in the shadow atlas for a {0}x{0} tile")]
+    OutOfSpace(u32),
+    /// The atlas image has been destroyed.
+    #[error("the shadow atlas image does not exist")]
+    InvalidImage,
+    /// Creating the atlas image failed.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Where the depth image of a `ShadowAtlasManager` comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum ShadowAtlasStorage {
+    /// The manager owns one image for its whole lifetime, so tiles keep their contents between
+    /// frames and the shadows of static lights only need to be rendered once.
+    Persistent,
+    /// The image is requested from the Device's per-frame scratch image pool each time the
+    /// atlas render pass begins, and is shared with other users of equal scratch images once the
+    /// frame has completed. Every tile must be rendered every frame.
+    Transient,
+}
+
+/// Describes the depth image of a `ShadowAtlasManager` and how it is divided.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShadowAtlasDesc {
+    /// The width and height of the atlas in texels, which must be a power of two.
+    pub size: u32,
+    /// The depth format of the atlas, which must have no stencil aspect.
+    pub format: vk::Format,
+    /// The smallest tile handed out, which must be a power of two. Smaller requests are
+    /// rounded up to it.
+    pub min_tile_size: u32,
+    /// The depth tiles are cleared to: 1.0, or 0.0 with a reversed depth range.
+    pub clear_depth: f32,
+    /// Where the atlas image comes from.
+    pub storage: ShadowAtlasStorage,
+}
+
+impl ShadowAtlasDesc {
+    /// Describe a persistent atlas of `size` by `size` texels, with the default minimum tile
+    /// size and cleared to a depth of 1.0.
+    pub fn new(size: u32, format: vk::Format) -> Self {
+        Self {
+            size,
+            format,
+            min_tile_size: DEFAULT_SHADOW_TILE_MIN_SIZE.min(size),
+            clear_depth: 1.0,
+            storage: ShadowAtlasStorage::Persistent,
+        }
+    }
+}
+
+/// A handle to a tile of a `ShadowAtlasManager`, typically owned by one light or one cascade.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ShadowTileHandle(ga::Index);
+
+/// The region of the atlas a tile occupies.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ShadowTile {
+    /// The left edge of the tile in texels.
+    pub x: u32,
+    /// The top edge of the tile in texels.
+    pub y: u32,
+    /// The width and height of the tile in texels.
+    pub size: u32,
+    /// The width and height of the whole atlas in texels.
+    pub atlas_size: u32,
+    level: u32,
+}
+
+impl ShadowTile {
+    /// The viewport to render the tile's shadow map with.
+    pub fn viewport(&self) -> vk::Viewport {
+        vk::Viewport {
+            x: self.x as f32,
+            y: self.y as f32,
+            width: self.size as f32,
+            height: self.size as f32,
+            min_depth: 0.0,
+            max_depth: 1.0,
+        }
+    }
+
+    /// The scissor which keeps rendering within the tile.
+    pub fn scissor(&self) -> vk::Rect2D {
+        vk::Rect2D {
+            offset: vk::Offset2D {
+                x: self.x as i32,
+                y: self.y as i32,
+            },
+            extent: vk::Extent2D {
+                width: self.size,
+                height: self.size,
+            },
+        }
+    }
+
+    /// The transform from the `[0, 1]` texture coordinates of the light's own shadow map to
+    /// coordinates within the atlas, as `[scale_x, scale_y, offset_x, offset_y]`, i.e.
+    /// `atlas_uv = uv * scale + offset`.
+    ///
+    /// Filtering near the edge of a tile reads from its neighbours, so shaders sampling with a
+    /// filter footprint should clamp `uv` half a footprint away from the edges.
+    pub fn uv_transform(&self) -> [f32; 4] {
+        let atlas = self.atlas_size as f32;
+        let scale = self.size as f32 / atlas;
+        [scale, scale, self.x as f32 / atlas, self.y as f32 / atlas]
+    }
+}
+
+/// Packs the shadow maps of many lights into one depth image, dividing it with a quadtree.
+///
+/// Each tile is a power of two sized square node of the quadtree: allocating a tile splits the
+/// smallest free node large enough into quarters until it fits, and freeing one merges it with
+/// its siblings once they are all free again. Tiles are rendered within a single render pass
+/// over the whole atlas, by setting each tile's viewport and scissor in turn with `set_tile`,
+/// and sampled with their `uv_transform`.
+///
+/// Each frame, call `begin_render_pass`, then `set_tile` before drawing each light's shadow
+/// casters, and finally `end_render_pass` before sampling the atlas.
+pub struct ShadowAtlasManager {
+    device: Arc<Device>,
+    desc: ShadowAtlasDesc,
+    /// The persistent image, or the transient image of the current render pass.
+    image: Option<ImageHandle>,
+    render_pass: vk::RenderPass,
+    /// Framebuffers by the image they render to. With transient storage there is one for each
+    /// scratch image the atlas has been given.
+    framebuffers: HashMap<ImageHandle, vk::Framebuffer>,
+    tiles: ga::Arena<ShadowTile>,
+    /// The free nodes of each level of the quadtree, by their position in units of the level's
+    /// node size. Level 0 is the whole atlas.
+    free: Vec<BTreeSet<(u32, u32)>>,
+}
+
+impl ShadowAtlasManager {
+    /// Create an atlas described by `desc`. With persistent storage the image is created here,
+    /// in the `SHADER_READ_ONLY_OPTIMAL` layout.
+    pub fn new(device: &Arc<Device>, desc: ShadowAtlasDesc) -> Result<Self, ShadowAtlasError> {
+        if !desc.size.is_power_of_two()
+            || !desc.min_tile_size.is_power_of_two()
+            || desc.min_tile_size > desc.size
+        {
+            return Err(ShadowAtlasError::InvalidSize {
+                size: desc.size,
+                min_tile_size: desc.min_tile_size,
+            });
+        }
+        // Tiles are sampled through the image's default view, which covers every aspect.
+        if !format_has_depth_aspect(desc.format) || format_has_stencil_aspect(desc.format) {
+            return Err(ShadowAtlasError::UnsupportedFormat(desc.format));
+        }
+
+        let image = match desc.storage {
+            ShadowAtlasStorage::Persistent => {
+                let create_info = ImageCreateInfo {
+                    width: desc.size as usize,
+                    height: desc.size as usize,
+                    depth: 1,
+                    format: desc.format,
+                    image_type: vk::ImageType::TYPE_2D,
+                    usage: vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+                    initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    ..Default::default()
+                };
+                Some(device.clone().create_image(create_info, None, Some(Tag::Static("shadow atlas")))?)
+            }
+            ShadowAtlasStorage::Transient => None,
+        };
+
+        let render_pass = device.request_render_pass(&RenderPassInfo {
+            color_attachments: Vec::new(),
+            depth_stencil_attachment: Some(RenderPassAttachment::clear_store(desc.format)),
+            resolve_attachments: Vec::new(),
+        }
+        .compatible())?;
+
+        let levels = (desc.size / desc.min_tile_size).trailing_zeros() as usize + 1;
+        let mut free = vec![BTreeSet::new(); levels];
+        free[0].insert((0, 0));
+
+        Ok(Self {
+            device: device.clone(),
+            desc,
+            image,
+            render_pass,
+            framebuffers: HashMap::new(),
+            tiles: ga::Arena::new(),
+            free,
+        })
+    }
+
+    /// The description the atlas was created with.
+    pub fn desc(&self) -> &ShadowAtlasDesc {
+        &self.desc
+    }
+
+    /// The atlas image. With transient storage this is the image of the last render pass begun
+    /// with `begin_render_pass`, which is only valid until the end of that frame.
+    pub fn image(&self) -> Option<ImageHandle> {
+        self.image
+    }
+
+    /// A render pass compatible with that of the atlas, to create shadow pipelines with.
+    pub fn render_pass(&self) -> vk::RenderPass {
+        self.render_pass
+    }
+
+    /// Allocate a tile of at least `resolution` by `resolution` texels. The resolution is
+    /// rounded up to a power of two no smaller than the minimum tile size.
+    pub fn allocate(&mut self, resolution: u32) -> Result<ShadowTileHandle, ShadowAtlasError> {
+        if resolution > self.desc.size {
+            return Err(ShadowAtlasError::OutOfSpace(resolution));
+        }
+        let size = resolution.max(self.desc.min_tile_size).next_power_of_two();
+        let level = (self.desc.size / size).trailing_zeros();
+
+        // Split the smallest free node large enough down to the requested level.
+        let (mut node_level, (mut x, mut y)) = (0..=level)
+            .rev()
+            .find_map(|l| self.free[l as usize].iter().next().map(|&node| (l, node)))
+            .ok_or(ShadowAtlasError::OutOfSpace(resolution))?;
+        self.free[node_level as usize].remove(&(x, y));
+        while node_level < level {
+            node_level += 1;
+            x *= 2;
+            y *= 2;
+            let children = &mut self.free[node_level as usize];
+            children.insert((x + 1, y));
+            children.insert((x, y + 1));
+            children.insert((x + 1, y + 1));
+        }
+
+        Ok(ShadowTileHandle(self.tiles.insert(ShadowTile {
+            x: x * size,
+            y: y * size,
+            size,
+            atlas_size: self.desc.size,
+            level,
+        })))
+    }
+
+    /// Free a tile, so that its region can be handed out again. Freeing a tile which has
+    /// already been freed does nothing.
+    pub fn free(&mut self, tile: ShadowTileHandle) {
+        let tile = match self.tiles.remove(tile.0) {
+            Some(tile) => tile,
+            None => return,
+        };
+
+        // Merge the node with its siblings for as long as they are all free.
+        let (mut level, mut x, mut y) = (tile.level as usize, tile.x / tile.size, tile.y / tile.size);
+        while level > 0 {
+            let (parent_x, parent_y) = (x / 2, y / 2);
+            let siblings = [0, 1, 2, 3]
+                .iter()
+                .map(|i| (parent_x * 2 + i % 2, parent_y * 2 + i / 2))
+                .filter(|&node| node != (x, y))
+                .collect::<Vec<_>>();
+            if !siblings.iter().all(|node| self.free[level].contains(node)) {
+                break;
+            }
+            for node in &siblings {
+                self.free[level].remove(node);
+            }
+            level -= 1;
+            x = parent_x;
+            y = parent_y;
+        }
+        self.free[level].insert((x, y));
+    }
+
+    /// Free every tile.
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+        for level in &mut self.free {
+            level.clear();
+        }
+        self.free[0].insert((0, 0));
+    }
+
+    /// Get the region of a tile, or `None` if it has been freed.
+    pub fn tile(&self, tile: ShadowTileHandle) -> Option<ShadowTile> {
+        self.tiles.get(tile.0).copied()
+    }
+
+    /// The number of allocated tiles.
+    pub fn tile_count(&self) -> usize {
+        self.tiles.len()
+    }
+
+    /// The number of texels not covered by any tile.
+    pub fn free_texels(&self) -> u64 {
+        self.free
+            .iter()
+            .enumerate()
+            .map(|(level, nodes)| {
+                let size = (self.desc.size >> level) as u64;
+                nodes.len() as u64 * size * size
+            })
+            .sum()
+    }
+
+    /// Begin a render pass over the whole atlas. With `clear_all` the atlas is cleared first;
+    /// otherwise tiles keep their previous contents, and those being rendered again should be
+    /// cleared by `set_tile`.
+    ///
+    /// With transient storage the atlas image is requested from the scratch image pool here,
+    /// so this must be called once per frame, and the atlas is always cleared since the image's
+    /// previous contents are undefined.
+    pub fn begin_render_pass(
+        &mut self,
+        cmd: &mut CommandBuffer,
+        clear_all: bool,
+    ) -> Result<(), ShadowAtlasError> {
+        let clear_all = match self.desc.storage {
+            ShadowAtlasStorage::Persistent => clear_all,
+            ShadowAtlasStorage::Transient => {
+                let mut desc = ScratchImageDesc::new(
+                    self.desc.size as usize,
+                    self.desc.size as usize,
+                    self.desc.format,
+                );
+                desc.usage = vk::ImageUsageFlags::SAMPLED;
+                self.image = Some(self.device.request_internal_scratch_image(desc)?);
+                true
+            }
+        };
+        let image = self.image.ok_or(ShadowAtlasError::InvalidImage)?;
+
+        cmd.transition_image(
+            image,
+            vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+        );
+        let (view, layout) = {
+            let resources = self.device.resources();
+            let image = resources.get_image(image).ok_or(ShadowAtlasError::InvalidImage)?;
+            let view = image.view().ok_or(ShadowAtlasError::InvalidImage)?.raw();
+            (view, image.layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL))
+        };
+
+        let mut attachment = RenderPassAttachment::clear_store(self.desc.format);
+        if !clear_all {
+            attachment.load_op = vk::AttachmentLoadOp::LOAD;
+        }
+        attachment.initial_layout = layout;
+        attachment.final_layout = layout;
+        let render_pass = self.device.request_render_pass(&RenderPassInfo {
+            color_attachments: Vec::new(),
+            depth_stencil_attachment: Some(attachment),
+            resolve_attachments: Vec::new(),
+        })?;
+        let framebuffer = self.framebuffer(image, view)?;
+
+        cmd.begin_render_pass(
+            render_pass,
+            framebuffer,
+            vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent: vk::Extent2D {
+                    width: self.desc.size,
+                    height: self.desc.size,
+                },
+            },
+            &[self.clear_value()],
+        );
+        Ok(())
+    }
+
+    /// Set the viewport and scissor to those of `tile`, to render its shadow map, clearing it
+    /// first if `clear` is set. Does nothing if the tile has been freed.
+    ///
+    /// Must be called within the atlas render pass.
+    pub fn set_tile(&self, cmd: &mut CommandBuffer, tile: ShadowTileHandle, clear: bool) {
+        let tile = match self.tile(tile) {
+            Some(tile) => tile,
+            None => return,
+        };
+
+        let device = &cmd.device().device;
+        unsafe {
+            device.cmd_set_viewport(cmd.raw(), 0, &[tile.viewport()]);
+            device.cmd_set_scissor(cmd.raw(), 0, &[tile.scissor()]);
+            if clear {
+                let attachment = vk::ClearAttachment {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    color_attachment: 0,
+                    clear_value: self.clear_value(),
+                };
+                let rect = vk::ClearRect {
+                    rect: tile.scissor(),
+                    base_array_layer: 0,
+                    layer_count: 1,
+                };
+                device.cmd_clear_attachments(cmd.raw(), &[attachment], &[rect]);
+            }
+        }
+    }
+
+    /// End the atlas render pass, and transition the atlas so that fragment and compute shaders
+    /// can sample it.
+    pub fn end_render_pass(&self, cmd: &mut CommandBuffer) {
+        cmd.end_render_pass();
+        if let Some(image) = self.image {
+            cmd.transition_image(
+                image,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_READ,
+            );
+        }
+    }
+
+    /// The image info to write the atlas into a `COMBINED_IMAGE_SAMPLER` descriptor with
+    /// `sampler`, e.g. a comparison sampler. Valid once `end_render_pass` has been recorded.
+    pub fn descriptor_image_info(&self, sampler: vk::Sampler) -> Option<vk::DescriptorImageInfo> {
+        let resources = self.device.resources();
+        let image = resources.get_image(self.image?)?;
+        Some(vk::DescriptorImageInfo {
+            sampler,
+            image_view: image.view()?.raw(),
+            image_layout: image.layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+        })
+    }
+
+    fn clear_value(&self) -> vk::ClearValue {
+        vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: self.desc.clear_depth,
+                stencil: 0,
+            },
+        }
+    }
+
+    /// Get the framebuffer rendering to `image` through `view`, creating it if needed.
+    fn framebuffer(
+        &mut self,
+        image: ImageHandle,
+        view: vk::ImageView,
+    ) -> Result<vk::Framebuffer, vk::Result> {
+        if let Some(&framebuffer) = self.framebuffers.get(&image) {
+            return Ok(framebuffer);
+        }
+
+        // Scratch images destroyed by `Device::trim_scratch_images` leave their framebuffers
+        // behind. Those are no longer used once their image's frame has completed, which it has
+        // by the time the image was destroyed.
+        let device = self.device.clone();
+        let resources = device.resources();
+        self.framebuffers.retain(|&image, &mut framebuffer| {
+            let alive = resources.get_image(image).is_some();
+            if !alive {
+                unsafe { device.device.destroy_framebuffer(framebuffer, None) };
+            }
+            alive
+        });
+        drop(resources);
+
+        let views = [view];
+        let create_info = vk::FramebufferCreateInfo::builder()
+            .render_pass(self.render_pass)
+            .attachments(&views)
+            .width(self.desc.size)
+            .height(self.desc.size)
+            .layers(1);
+        let framebuffer = unsafe { self.device.device.create_framebuffer(&create_info, None)? };
+        self.framebuffers.insert(image, framebuffer);
+        Ok(framebuffer)
+    }
+}
+
+impl Drop for ShadowAtlasManager {
+    fn drop(&mut self) {
+        unsafe {
+            // The atlas may still be in use by frames in flight.
+            let _ = self.device.device_wait_idle();
+            for (_, framebuffer) in self.framebuffers.drain() {
+                self.device.device.destroy_framebuffer(framebuffer, None);
+            }
+        }
+        if self.desc.storage == ShadowAtlasStorage::Persistent {
+            if let Some(image) = self.image.take() {
+                self.device.destroy_image(image);
+            }
+        }
+    }
+}
@@ -0,0 +1,624 @@
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error creating a sparse image or binding its pages.
+#[derive(Error, Debug)]
+pub enum SparseImageError {
+    /// The device doesn't support sparse residency for images of the requested type and sample
+    /// count.
+    #[error("sparse residency is not supported for this image type")]
+    Unsupported,
+    /// The format can't be used for a sparse image with the requested type and usage, or needs
+    /// memory bound for metadata, which is not supported.
+    #[error("format {0:?} does not support sparse residency")]
+    UnsupportedFormat(vk::Format),
+    /// The image handle is invalid.
+    #[error("invalid image handle")]
+    InvalidImage,
+    /// The image is not a sparse image.
+    #[error("the image is not a sparse image")]
+    NotSparse,
+    /// A region is outside of the image or not aligned to its pages.
+    #[error("invalid region: {0:?}")]
+    InvalidRegion(SparseImageRegion),
+    /// The memory of a page could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A region of one mip level and array layer of a sparse image, whose pages are bound or
+/// unbound together.
+///
+/// Its offset must be a multiple of the page extent, and its extent too unless the region
+/// reaches the edge of the level. Regions of the levels in the mip tail bind or unbind the whole
+/// mip tail of their layer, whatever their offset and extent.
+#[derive(Clone, Copy, Debug)]
+pub struct SparseImageRegion {
+    /// The mip level of the region.
+    pub level: u32,
+    /// The array layer of the region.
+    pub layer: u32,
+    /// The offset of the region in texels.
+    pub offset: vk::Offset3D,
+    /// The extent of the region in texels.
+    pub extent: vk::Extent3D,
+}
+
+/// The page layout and residency of a sparse image, returned by `Device::sparse_image_info`.
+#[derive(Clone, Copy, Debug)]
+pub struct SparseImageInfo {
+    /// The extent of a page in texels.
+    pub page_extent: vk::Extent3D,
+    /// The bytes of memory bound to each page.
+    pub page_size: vk::DeviceSize,
+    /// The first mip level of the mip tail, which is bound as a whole, or the number of levels if
+    /// there is no mip tail.
+    pub mip_tail_first_level: u32,
+    /// The number of pages with memory bound, counting each bound mip tail as one.
+    pub resident_pages: usize,
+}
+
+/// A page of a sparse image, by level, layer and its index in pages within the level.
+type PageKey = (u32, u32, [u32; 3]);
+
+/// The pages of a sparse image and the memory bound to them, owned by its `Image`.
+pub(crate) struct SparseResidency {
+    aspect_mask: vk::ImageAspectFlags,
+    granularity: vk::Extent3D,
+    page_size: vk::DeviceSize,
+    memory_type_bits: u32,
+    extent: [u32; 3],
+    levels: u32,
+    layers: u32,
+    mip_tail_first_level: u32,
+    mip_tail_size: vk::DeviceSize,
+    mip_tail_offset: vk::DeviceSize,
+    mip_tail_stride: vk::DeviceSize,
+    /// Whether all layers share a single mip tail.
+    single_mip_tail: bool,
+    pages: HashMap<PageKey, Allocation>,
+    /// The memory bound to the mip tail of each layer, or of layer 0 for a single mip tail.
+    mip_tails: HashMap<u32, Allocation>,
+}
+
+impl SparseResidency {
+    /// Free the memory bound to every page, once the image no longer uses it.
+    pub(crate) fn free(self, device: &Device, tag: Option<&Tag>) {
+        for allocation in self.pages.into_values().chain(self.mip_tails.into_values()) {
+            device.free_sparse_page(&allocation, tag);
+        }
+    }
+
+    fn level_extent(&self, level: u32) -> [u32; 3] {
+        let [width, height, depth] = self.extent;
+        [
+            (width >> level).max(1),
+            (height >> level).max(1),
+            (depth >> level).max(1),
+        ]
+    }
+
+    /// The layer whose mip tail a region of the mip tail at `layer` binds.
+    fn mip_tail_layer(&self, layer: u32) -> u32 {
+        if self.single_mip_tail {
+            0
+        } else {
+            layer
+        }
+    }
+
+    /// The opaque bind of the mip tail of `layer` to `memory`.
+    fn mip_tail_bind(
+        &self,
+        layer: u32,
+        memory: vk::DeviceMemory,
+        memory_offset: vk::DeviceSize,
+    ) -> vk::SparseMemoryBind {
+        vk::SparseMemoryBind {
+            resource_offset: self.mip_tail_offset + layer as vk::DeviceSize * self.mip_tail_stride,
+            size: self.mip_tail_size,
+            memory,
+            memory_offset,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        }
+    }
+
+    /// The bind of the page `page` of `level` and `layer` to `memory`.
+    fn page_bind(
+        &self,
+        (level, layer, page): PageKey,
+        memory: vk::DeviceMemory,
+        memory_offset: vk::DeviceSize,
+    ) -> vk::SparseImageMemoryBind {
+        let granularity = [self.granularity.width, self.granularity.height, self.granularity.depth];
+        let level_extent = self.level_extent(level);
+        let offset = [0, 1, 2].map(|i| page[i] * granularity[i]);
+        let extent = [0, 1, 2].map(|i| granularity[i].min(level_extent[i] - offset[i]));
+        vk::SparseImageMemoryBind {
+            subresource: vk::ImageSubresource {
+                aspect_mask: self.aspect_mask,
+                mip_level: level,
+                array_layer: layer,
+            },
+            offset: vk::Offset3D {
+                x: offset[0] as i32,
+                y: offset[1] as i32,
+                z: offset[2] as i32,
+            },
+            extent: vk::Extent3D {
+                width: extent[0],
+                height: extent[1],
+                depth: extent[2],
+            },
+            memory,
+            memory_offset,
+            flags: vk::SparseMemoryBindFlags::empty(),
+        }
+    }
+
+    /// The pages covered by `region`, or the layer of the mip tail it covers.
+    fn region_pages(&self, region: SparseImageRegion) -> Result<RegionPages, SparseImageError> {
+        let invalid = || SparseImageError::InvalidRegion(region);
+        if region.level >= self.levels || region.layer >= self.layers {
+            return Err(invalid());
+        }
+        if region.level >= self.mip_tail_first_level {
+            return Ok(RegionPages::MipTail(self.mip_tail_layer(region.layer)));
+        }
+
+        let granularity = [self.granularity.width, self.granularity.height, self.granularity.depth];
+        let level_extent = self.level_extent(region.level);
+        let offset = [region.offset.x, region.offset.y, region.offset.z];
+        let extent = [region.extent.width, region.extent.height, region.extent.depth];
+
+        let mut first = [0; 3];
+        let mut end = [0; 3];
+        for i in 0..3 {
+            if offset[i] < 0 || extent[i] == 0 {
+                return Err(invalid());
+            }
+            let (offset, extent) = (offset[i] as u32, extent[i]);
+            let region_end = offset.checked_add(extent).ok_or_else(invalid)?;
+            if offset % granularity[i] != 0
+                || region_end > level_extent[i]
+                || (extent % granularity[i] != 0 && region_end != level_extent[i])
+            {
+                return Err(invalid());
+            }
+            first[i] = offset / granularity[i];
+            end[i] = region_end.div_ceil(granularity[i]);
+        }
+
+        let mut pages = Vec::new();
+        for z in first[2]..end[2] {
+            for y in first[1]..end[1] {
+                for x in first[0]..end[0] {
+                    pages.push((region.level, region.layer, [x, y, z]));
+                }
+            }
+        }
+        Ok(RegionPages::Pages(pages))
+    }
+}
+
+/// What a `SparseImageRegion` covers.
+enum RegionPages {
+    Pages(Vec<PageKey>),
+    MipTail(u32),
+}
+
+impl Device {
+    /// Whether sparse images of `image_type` can be created with `create_sparse_image`.
+    pub fn supports_sparse_residency(&self, image_type: vk::ImageType) -> bool {
+        let features = &self.enabled_features;
+        match image_type {
+            vk::ImageType::TYPE_2D => features.sparse_residency_image2_d == vk::TRUE,
+            vk::ImageType::TYPE_3D => features.sparse_residency_image3_d == vk::TRUE,
+            _ => false,
+        }
+    }
+
+    /// Create a sparse image, with no memory bound to any of its pages, for example for virtual
+    /// texturing. Memory is bound to pages with `bind_image_pages`, and reading or writing pages
+    /// without memory has undefined results.
+    ///
+    /// The image is created with `SPARSE_BINDING` and `SPARSE_RESIDENCY` in addition to
+    /// `create_info.create_flags`, and must be single sampled. A `levels` of 0 creates a full
+    /// mip chain. Initial data and `MiscImageFlags` are not supported. The image is destroyed
+    /// like any other with `destroy_image`, which also frees the memory bound to its pages.
+    pub fn create_sparse_image(
+        self: &Arc<Self>,
+        mut create_info: ImageCreateInfo,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, SparseImageError> {
+        if !self.supports_sparse_residency(create_info.image_type)
+            || create_info.sample_count != vk::SampleCountFlags::TYPE_1
+        {
+            return Err(SparseImageError::Unsupported);
+        }
+
+        create_info.depth = create_info.depth.max(1);
+        create_info.create_flags |=
+            vk::ImageCreateFlags::SPARSE_BINDING | vk::ImageCreateFlags::SPARSE_RESIDENCY;
+        let extent = vk::Extent3D {
+            width: create_info.width as u32,
+            height: create_info.height as u32,
+            depth: create_info.depth as u32,
+        };
+        if create_info.levels == 0 {
+            create_info.levels = mip_levels_from_extent(extent) as usize;
+        }
+
+        let format_property_count = unsafe {
+            let fp = self.instance.fp_v1_0();
+            let mut count = 0;
+            fp.get_physical_device_sparse_image_format_properties(
+                self.physical_device,
+                create_info.format,
+                create_info.image_type,
+                create_info.sample_count,
+                create_info.usage,
+                vk::ImageTiling::OPTIMAL,
+                &mut count,
+                std::ptr::null_mut(),
+            );
+            count
+        };
+        if format_property_count == 0 {
+            return Err(SparseImageError::UnsupportedFormat(create_info.format));
+        }
+
+        let mut queue_family_indices = [0u32; 3];
+        let (sharing_mode, queue_family_index_count) = self.sharing_mode(&mut queue_family_indices);
+        let image_info = vk::ImageCreateInfo::builder()
+            .flags(create_info.create_flags)
+            .image_type(create_info.image_type)
+            .format(create_info.format)
+            .extent(extent)
+            .mip_levels(create_info.levels as u32)
+            .array_layers(create_info.layers as u32)
+            .samples(create_info.sample_count)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(create_info.usage)
+            .sharing_mode(sharing_mode)
+            .queue_family_indices(&queue_family_indices[0..queue_family_index_count])
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let image = unsafe { self.device.create_image(&image_info, None)? };
+
+        let requirements = unsafe { self.device.get_image_memory_requirements(image) };
+        let sparse_requirements = unsafe {
+            let fp = self.device.fp_v1_0();
+            let mut count = 0;
+            fp.get_image_sparse_memory_requirements(
+                self.device.handle(),
+                image,
+                &mut count,
+                std::ptr::null_mut(),
+            );
+            let mut sparse_requirements =
+                vec![vk::SparseImageMemoryRequirements::default(); count as usize];
+            fp.get_image_sparse_memory_requirements(
+                self.device.handle(),
+                image,
+                &mut count,
+                sparse_requirements.as_mut_ptr(),
+            );
+            sparse_requirements.truncate(count as usize);
+            sparse_requirements
+        };
+        // Binding metadata, or each aspect of a depth stencil format separately, isn't supported.
+        let needs_metadata = sparse_requirements
+            .iter()
+            .any(|req| req.format_properties.aspect_mask.contains(vk::ImageAspectFlags::METADATA));
+        let sparse_requirements = match sparse_requirements.as_slice() {
+            [requirements] if !needs_metadata => *requirements,
+            _ => {
+                unsafe { self.device.destroy_image(image, None) };
+                return Err(SparseImageError::UnsupportedFormat(create_info.format));
+            }
+        };
+
+        let format_properties = sparse_requirements.format_properties;
+        let sparse = SparseResidency {
+            aspect_mask: format_properties.aspect_mask,
+            granularity: format_properties.image_granularity,
+            page_size: requirements.alignment,
+            memory_type_bits: requirements.memory_type_bits,
+            extent: [extent.width, extent.height, extent.depth],
+            levels: create_info.levels as u32,
+            layers: create_info.layers as u32,
+            mip_tail_first_level: sparse_requirements
+                .image_mip_tail_first_lod
+                .min(create_info.levels as u32),
+            mip_tail_size: sparse_requirements.image_mip_tail_size,
+            mip_tail_offset: sparse_requirements.image_mip_tail_offset,
+            mip_tail_stride: sparse_requirements.image_mip_tail_stride,
+            single_mip_tail: format_properties
+                .flags
+                .contains(vk::SparseImageFormatFlags::SINGLE_MIPTAIL),
+            pages: HashMap::new(),
+            mip_tails: HashMap::new(),
+        };
+
+        let layout_type = if create_info.initial_layout == vk::ImageLayout::GENERAL {
+            ImageLayoutType::General
+        } else {
+            ImageLayoutType::Optimal
+        };
+        let mut sparse_image = unsafe {
+            Image::new(
+                self.clone(),
+                image,
+                None,
+                create_info,
+                None,
+                layout_type,
+                vk::PipelineStageFlags::empty(),
+                vk::AccessFlags::empty(),
+                vk::ImageLayout::UNDEFINED,
+                tag,
+            )
+        };
+        sparse_image.set_sparse(sparse);
+        let handle = ImageHandle::new(self.resources.write().images.insert(sparse_image));
+
+        if let Err(e) = self.create_default_view(handle, image, &create_info) {
+            self.resources.write().images.remove(handle.idx);
+            return Err(e.into());
+        }
+
+        Ok(handle)
+    }
+
+    /// Get the page layout and residency of `image`, if it is a sparse image.
+    pub fn sparse_image_info(&self, image: ImageHandle) -> Option<SparseImageInfo> {
+        let resources = self.resources();
+        let sparse = resources.get_image(image)?.sparse()?;
+        Some(SparseImageInfo {
+            page_extent: sparse.granularity,
+            page_size: sparse.page_size,
+            mip_tail_first_level: sparse.mip_tail_first_level,
+            resident_pages: sparse.pages.len() + sparse.mip_tails.len(),
+        })
+    }
+
+    /// Bind memory to the pages of the sparse image `image` covered by `regions`, with
+    /// `vkQueueBindSparse` on the graphics queue. Pages which already have memory bound are left
+    /// as they are. The next graphics submission waits for the binding to complete.
+    ///
+    /// The contents of newly bound pages are undefined until they are written.
+    pub fn bind_image_pages(
+        &self,
+        image: ImageHandle,
+        regions: &[SparseImageRegion],
+    ) -> Result<(), SparseImageError> {
+        let mut resources = self.resources_mut();
+        let image = resources.get_image_mut(image).ok_or(SparseImageError::InvalidImage)?;
+        let raw_image = image.raw();
+        let tag = image.tag().cloned();
+        let sparse = image.sparse_mut().ok_or(SparseImageError::NotSparse)?;
+
+        let mut pages = Vec::new();
+        let mut mip_tails = Vec::new();
+        for &region in regions {
+            match sparse.region_pages(region)? {
+                RegionPages::Pages(region_pages) => pages.extend(
+                    region_pages
+                        .into_iter()
+                        .filter(|page| !sparse.pages.contains_key(page)),
+                ),
+                RegionPages::MipTail(layer) => {
+                    if !sparse.mip_tails.contains_key(&layer) {
+                        mip_tails.push(layer);
+                    }
+                }
+            }
+        }
+        pages.sort_unstable();
+        pages.dedup();
+        mip_tails.sort_unstable();
+        mip_tails.dedup();
+        if pages.is_empty() && mip_tails.is_empty() {
+            return Ok(());
+        }
+
+        let page_requirements = vk::MemoryRequirements {
+            size: sparse.page_size,
+            alignment: sparse.page_size,
+            memory_type_bits: sparse.memory_type_bits,
+        };
+        let mip_tail_requirements = vk::MemoryRequirements {
+            size: sparse.mip_tail_size,
+            ..page_requirements
+        };
+
+        let mut page_allocations = Vec::with_capacity(pages.len());
+        let mut mip_tail_allocations = Vec::with_capacity(mip_tails.len());
+        let allocated = pages
+            .iter()
+            .try_for_each(|_| {
+                page_allocations.push(self.allocate_sparse_page(&page_requirements, tag.as_ref())?);
+                Ok(())
+            })
+            .and_then(|_| {
+                mip_tails.iter().try_for_each(|_| {
+                    mip_tail_allocations
+                        .push(self.allocate_sparse_page(&mip_tail_requirements, tag.as_ref())?);
+                    Ok(())
+                })
+            });
+
+        let result = allocated.and_then(|_| {
+            let image_binds = pages
+                .iter()
+                .zip(&page_allocations)
+                .map(|(&page, allocation)| sparse.page_bind(page, allocation.memory(), allocation.offset()))
+                .collect::<Vec<_>>();
+            let opaque_binds = mip_tails
+                .iter()
+                .zip(&mip_tail_allocations)
+                .map(|(&layer, allocation)| {
+                    sparse.mip_tail_bind(layer, allocation.memory(), allocation.offset())
+                })
+                .collect::<Vec<_>>();
+            self.queue_bind_sparse(raw_image, &image_binds, &opaque_binds)
+        });
+        if let Err(e) = result {
+            for allocation in page_allocations.iter().chain(&mip_tail_allocations) {
+                self.free_sparse_page(allocation, tag.as_ref());
+            }
+            return Err(e);
+        }
+
+        sparse.pages.extend(pages.into_iter().zip(page_allocations));
+        sparse.mip_tails.extend(mip_tails.into_iter().zip(mip_tail_allocations));
+        Ok(())
+    }
+
+    /// Unbind the memory from the pages of the sparse image `image` covered by `regions`, with
+    /// `vkQueueBindSparse` on the graphics queue. Pages without memory bound are skipped. The
+    /// memory is freed once the current frame has completed.
+    ///
+    /// The binding isn't ordered with work which has already been submitted, so pages should
+    /// only be unbound once nothing in flight reads them, e.g. when they have gone unused for as
+    /// many frames as there are frames in flight.
+    pub fn unbind_image_pages(
+        &self,
+        image: ImageHandle,
+        regions: &[SparseImageRegion],
+    ) -> Result<(), SparseImageError> {
+        let mut resources = self.resources_mut();
+        let image = resources.get_image_mut(image).ok_or(SparseImageError::InvalidImage)?;
+        let raw_image = image.raw();
+        let tag = image.tag().cloned();
+        let sparse = image.sparse_mut().ok_or(SparseImageError::NotSparse)?;
+
+        let mut pages = Vec::new();
+        let mut mip_tails = Vec::new();
+        for &region in regions {
+            match sparse.region_pages(region)? {
+                RegionPages::Pages(region_pages) => pages.extend(region_pages),
+                RegionPages::MipTail(layer) => mip_tails.push(layer),
+            }
+        }
+        pages.retain(|page| sparse.pages.contains_key(page));
+        pages.sort_unstable();
+        pages.dedup();
+        mip_tails.retain(|layer| sparse.mip_tails.contains_key(layer));
+        mip_tails.sort_unstable();
+        mip_tails.dedup();
+        if pages.is_empty() && mip_tails.is_empty() {
+            return Ok(());
+        }
+
+        let image_binds = pages
+            .iter()
+            .map(|&page| sparse.page_bind(page, vk::DeviceMemory::null(), 0))
+            .collect::<Vec<_>>();
+        let opaque_binds = mip_tails
+            .iter()
+            .map(|&layer| sparse.mip_tail_bind(layer, vk::DeviceMemory::null(), 0))
+            .collect::<Vec<_>>();
+        self.queue_bind_sparse(raw_image, &image_binds, &opaque_binds)?;
+
+        let mut freed = Vec::with_capacity(pages.len() + mip_tails.len());
+        for page in &pages {
+            freed.extend(sparse.pages.remove(page).map(|allocation| (allocation, tag.clone())));
+        }
+        for layer in &mip_tails {
+            freed.extend(sparse.mip_tails.remove(layer).map(|allocation| (allocation, tag.clone())));
+        }
+        // The frame is locked after the resources are released, as `begin_frame` locks them in
+        // the opposite order.
+        drop(resources);
+        self.current_frame().write().freed_sparse_pages.extend(freed);
+        Ok(())
+    }
+
+    /// Free the memory unbound from sparse images during a frame which has completed.
+    pub(crate) fn free_sparse_pages(&self, frame: &mut PerFrame) {
+        for (allocation, tag) in frame.freed_sparse_pages.drain(..) {
+            self.free_sparse_page(&allocation, tag.as_ref());
+        }
+    }
+
+    fn allocate_sparse_page(
+        &self,
+        requirements: &vk::MemoryRequirements,
+        tag: Option<&Tag>,
+    ) -> Result<Allocation, SparseImageError> {
+        let desc = AllocationDesc {
+            usage: MemoryUsage::GpuOnly,
+            ..Default::default()
+        };
+        let allocation = self.allocator.allocate_memory(requirements, &desc)?;
+        self.track_allocation(MemoryCategory::Images, tag, allocation.size(), true);
+        Ok(allocation)
+    }
+
+    fn free_sparse_page(&self, allocation: &Allocation, tag: Option<&Tag>) {
+        self.track_allocation(MemoryCategory::Images, tag, allocation.size(), false);
+        if let Err(e) = self.allocator.free_memory(allocation) {
+            self.invariant_failed(tag, format!("Sparse image page errored on free: {:#?}", e));
+        }
+    }
+
+    /// Bind or unbind the memory of pages of `image` on the graphics queue, such that the next
+    /// graphics submission waits for the binding to complete.
+    fn queue_bind_sparse(
+        &self,
+        image: vk::Image,
+        image_binds: &[vk::SparseImageMemoryBind],
+        opaque_binds: &[vk::SparseMemoryBind],
+    ) -> Result<(), SparseImageError> {
+        let semaphore = self.request_raw_semaphore()?;
+
+        let image_bind_infos = [vk::SparseImageMemoryBindInfo::builder()
+            .image(image)
+            .binds(image_binds)
+            .build()];
+        let opaque_bind_infos = [vk::SparseImageOpaqueMemoryBindInfo::builder()
+            .image(image)
+            .binds(opaque_binds)
+            .build()];
+        let signal_semaphores = [semaphore];
+        let mut bind_info = vk::BindSparseInfo::builder().signal_semaphores(&signal_semaphores);
+        if !image_binds.is_empty() {
+            bind_info = bind_info.image_binds(&image_bind_infos);
+        }
+        if !opaque_binds.is_empty() {
+            bind_info = bind_info.image_opaque_binds(&opaque_bind_infos);
+        }
+
+        let result = unsafe {
+            let _queue = self.lock_queue(QueueType::Graphics);
+            self.device.fp_v1_0().queue_bind_sparse(
+                self.graphics_queue,
+                1,
+                &*bind_info,
+                vk::Fence::null(),
+            )
+        };
+        if result != vk::Result::SUCCESS {
+            self.check_vk_result(result);
+            // The semaphore was never signaled, so it can be reused straight away.
+            self.semaphore_pool.lock().push(semaphore);
+            return Err(result.into());
+        }
+
+        self.pending_upload_semaphores[queue_index(QueueType::Graphics)]
+            .lock()
+            .push(semaphore);
+        Ok(())
+    }
+}
@@ -0,0 +1,518 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use parking_lot::MutexGuard;
+
+use crate::*;
+use crate::timeline::TimelineSemaphoreSubmitInfo;
+
+/// A batch of command buffers waiting to be submitted to a queue.
+#[derive(Debug, Default)]
+pub(crate) struct PendingSubmit {
+    command_buffers: Vec<vk::CommandBuffer>,
+    wait_semaphores: Vec<vk::Semaphore>,
+    wait_stages: Vec<vk::PipelineStageFlags>,
+    /// The value to wait on for each wait semaphore, ignored for binary semaphores.
+    wait_values: Vec<u64>,
+    signal_semaphores: Vec<vk::Semaphore>,
+    /// The value to signal for each signal semaphore, ignored for binary semaphores.
+    signal_values: Vec<u64>,
+    /// The resource accesses of the command buffers, if resource events are being recorded.
+    accesses: Vec<RecordedAccess>,
+}
+
+impl PendingSubmit {
+    fn uses_timelines(&self) -> bool {
+        self.wait_values.iter().chain(&self.signal_values).any(|&value| value != 0)
+    }
+}
+
+/// Builds a submission of command buffers to one of the Device's queues. Create one with
+/// `Device::submit`.
+///
+/// Semaphores allow chaining work across queues, e.g. an upload on the `AsyncTransfer` queue
+/// which `signal`s a semaphore that a `Graphics` submission `wait`s on, or which signals a
+/// `Timeline` value that the `Graphics` submission waits on.
+#[must_use = "a SubmitBuilder does nothing until it is enqueued or flushed"]
+pub struct SubmitBuilder<'a> {
+    device: &'a Device,
+    queue_type: QueueType,
+    submit: PendingSubmit,
+}
+
+impl SubmitBuilder<'_> {
+    /// Wait on `semaphore` before the submission's `stage` stages execute.
+    pub fn wait(mut self, semaphore: vk::Semaphore, stage: vk::PipelineStageFlags) -> Self {
+        self.submit.wait_semaphores.push(semaphore);
+        self.submit.wait_stages.push(stage);
+        self.submit.wait_values.push(0);
+        self
+    }
+
+    /// Signal `semaphore` once the submission has completed.
+    pub fn signal(mut self, semaphore: vk::Semaphore) -> Self {
+        self.submit.signal_semaphores.push(semaphore);
+        self.submit.signal_values.push(0);
+        self
+    }
+
+    /// Wait on `timeline` reaching `value` before the submission's `stage` stages execute.
+    pub fn wait_timeline(mut self, timeline: &Timeline, value: u64, stage: vk::PipelineStageFlags) -> Self {
+        self.submit.wait_semaphores.push(timeline.raw());
+        self.submit.wait_stages.push(stage);
+        self.submit.wait_values.push(value);
+        self
+    }
+
+    /// Signal `value` on `timeline` once the submission has completed. It must be greater than
+    /// any value signaled on the timeline before, e.g. one reserved with `Timeline::signal_value`.
+    pub fn signal_timeline(mut self, timeline: &Timeline, value: u64) -> Self {
+        self.submit.signal_semaphores.push(timeline.raw());
+        self.submit.signal_values.push(value);
+        self
+    }
+
+    /// Add the submission to its queue's pending batch, to be submitted by the next
+    /// `Device::flush_queue` or `Device::end_frame`.
+    pub fn enqueue(self) {
+        self.device.pending_submits[queue_index(self.queue_type)]
+            .lock()
+            .push(self.submit);
+    }
+
+    /// Add the submission to its queue's pending batch and submit the batch immediately.
+    ///
+    /// See `Device::flush_queue`.
+    pub fn flush(self) -> Result<vk::Fence, vk::Result> {
+        let (device, queue_type) = (self.device, self.queue_type);
+        self.enqueue();
+        device.flush_queue(queue_type)
+    }
+
+    /// Add the submission to its queue's pending batch and submit the batch immediately,
+    /// signaling `fence` once it has completed rather than a fence belonging to the current
+    /// frame, so it can be waited on for as long as needed.
+    pub fn flush_with_fence(self, fence: &Fence) -> Result<(), vk::Result> {
+        let (device, queue_type) = (self.device, self.queue_type);
+        self.enqueue();
+        device.submit_pending(queue_type, Some(fence.raw())).map(|_| ())
+    }
+}
+
+/// A copy of data written to the CPU-side buffer of a block to its GPU-side buffer.
+struct BlockUpload {
+    src: vk::Buffer,
+    dst: vk::Buffer,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+}
+
+/// The stages which read vertex, index, uniform and indirect blocks.
+fn block_read_stages() -> vk::PipelineStageFlags {
+    vk::PipelineStageFlags::DRAW_INDIRECT
+        | vk::PipelineStageFlags::VERTEX_INPUT
+        | vk::PipelineStageFlags::VERTEX_SHADER
+        | vk::PipelineStageFlags::FRAGMENT_SHADER
+        | vk::PipelineStageFlags::COMPUTE_SHADER
+}
+
+/// The accesses which read vertex, index, uniform and indirect blocks.
+fn block_read_access() -> vk::AccessFlags {
+    vk::AccessFlags::VERTEX_ATTRIBUTE_READ
+        | vk::AccessFlags::INDEX_READ
+        | vk::AccessFlags::UNIFORM_READ
+        | vk::AccessFlags::INDIRECT_COMMAND_READ
+}
+
+/// Get the index of a queue type's pending batch.
+pub(crate) fn queue_index(queue_type: QueueType) -> usize {
+    match queue_type {
+        QueueType::Graphics => 0,
+        QueueType::Compute => 1,
+        QueueType::AsyncTransfer => 2,
+    }
+}
+
+const QUEUE_TYPES: [QueueType; 3] = [QueueType::Graphics, QueueType::Compute, QueueType::AsyncTransfer];
+
+impl Device {
+    /// Get the queue used for a `QueueType`.
+    pub fn queue(&self, queue_type: QueueType) -> vk::Queue {
+        match queue_type {
+            QueueType::Graphics => self.graphics_queue,
+            QueueType::Compute => self.compute_queue,
+            QueueType::AsyncTransfer => self.transfer_queue,
+        }
+    }
+
+    /// Get whether the `a` and `b` queue types share a queue. When a device has no dedicated
+    /// compute or transfer queue family, those queue types fall back to the graphics queue.
+    /// Everything which takes a `QueueType` works the same either way, but work submitted to
+    /// queue types which share a queue runs serially rather than in parallel.
+    pub fn shares_queue(&self, a: QueueType, b: QueueType) -> bool {
+        self.queue(a) == self.queue(b)
+    }
+
+    /// Lock the queue used for `queue_type`, which must be held around any call which uses the
+    /// queue, such as `vkQueueSubmit`, since Vulkan requires access to a queue to be externally
+    /// synchronized. Queue types which share a queue share a lock.
+    pub(crate) fn lock_queue(&self, queue_type: QueueType) -> MutexGuard<'_, ()> {
+        let owner = QUEUE_TYPES
+            .iter()
+            .copied()
+            .find(|&other| self.shares_queue(other, queue_type))
+            .unwrap_or(queue_type);
+        self.queue_locks[queue_index(owner)].lock()
+    }
+
+    /// Add a raw command buffer recorded by hot, such as with `request_raw_command_buffer`, to
+    /// a queue's pending batch.
+    pub(crate) fn enqueue_raw_command_buffer(&self, queue_type: QueueType, cmd: vk::CommandBuffer) {
+        self.pending_submits[queue_index(queue_type)].lock().push(PendingSubmit {
+            command_buffers: vec![cmd],
+            ..Default::default()
+        });
+    }
+
+    /// Get the global priority the queue used for a `QueueType` was created with, which may be
+    /// lower than requested with `DeviceBuilder::queue_priority`.
+    pub fn queue_priority(&self, queue_type: QueueType) -> QueuePriority {
+        self.queue_priorities[queue_index(queue_type)]
+    }
+
+    /// Start building a submission of `command_buffers` to the queue of type `queue_type`.
+    ///
+    /// The command buffers must have been ended and requested for `queue_type`.
+    ///
+    /// The submission also waits on any uploads for this queue type which have not yet been
+    /// waited on, i.e. those started by `create_buffer` and friends (for `Graphics`) or
+    /// submitted with `submit_staging`.
+    pub fn submit(&self, queue_type: QueueType, command_buffers: &[CommandBuffer]) -> SubmitBuilder<'_> {
+        self.check_deterministic_thread("submit");
+        let accesses = command_buffers
+            .iter()
+            .flat_map(|cmd| cmd.recorded_accesses().iter().copied())
+            .collect();
+        let command_buffers = command_buffers
+            .iter()
+            .map(|cmd| {
+                assert!(cmd.is_ended(), "submitted a command buffer which has not been ended");
+                assert_eq!(
+                    cmd.queue_type(),
+                    queue_type,
+                    "submitted a command buffer to the wrong type of queue"
+                );
+                cmd.raw()
+            })
+            .collect();
+
+        let mut builder = SubmitBuilder {
+            device: self,
+            queue_type,
+            submit: PendingSubmit {
+                command_buffers,
+                accesses,
+                ..Default::default()
+            },
+        };
+
+        let uploads = std::mem::take(&mut *self.pending_upload_semaphores[queue_index(queue_type)].lock());
+        if !uploads.is_empty() {
+            for &semaphore in &uploads {
+                builder = builder.wait(semaphore, vk::PipelineStageFlags::ALL_COMMANDS);
+            }
+            self.current_frame().write().used_semaphores.extend(uploads);
+        }
+
+        builder
+    }
+
+    /// Submit `command_buffers`, which copy out of staging blocks requested this frame, to the
+    /// `AsyncTransfer` queue, such that the next submission to the `consumer` queue waits for
+    /// them to complete.
+    ///
+    /// The memory written in this frame's staging blocks is flushed first, so the copies see it
+    /// even if the staging memory is not host coherent. The submission is enqueued, so it is
+    /// submitted along with the consumer's batch (or earlier).
+    pub fn submit_staging(&self, command_buffers: &[CommandBuffer], consumer: QueueType) -> Result<(), AllocatorError> {
+        {
+            let blocks = self.buffer_blocks();
+            for &handle in &self.current_frame().read().used_staging_blocks {
+                let block = match blocks.get_staging_block(handle) {
+                    Some(block) => block,
+                    None => continue,
+                };
+                if let Some(allocation) = block.gpu.allocation() {
+                    self.allocator.flush_allocation(allocation, 0, block.used())?;
+                }
+            }
+        }
+
+        let semaphore = self.request_raw_semaphore().map_err(AllocatorError::Vulkan)?;
+        self.submit(QueueType::AsyncTransfer, command_buffers)
+            .signal(semaphore)
+            .enqueue();
+        self.pending_upload_semaphores[queue_index(consumer)]
+            .lock()
+            .push(semaphore);
+
+        Ok(())
+    }
+
+    /// Submit every pending batch for the queue of type `queue_type` in a single
+    /// `vkQueueSubmit`, returning a fence which is signaled once they have all completed.
+    ///
+    /// Batches pending on other queues which signal semaphores these batches wait on are
+    /// flushed first, since a semaphore must be signaled by an earlier submission than the one
+    /// waiting on it.
+    ///
+    /// Flushing the graphics queue first schedules the upload of data written to vertex, index
+    /// and uniform blocks since the last flush, which the batches wait for.
+    ///
+    /// The fence belongs to the current frame and is reset and reused the next time this frame
+    /// slot begins, so it must not be waited on after that.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn flush_queue(&self, queue_type: QueueType) -> Result<vk::Fence, vk::Result> {
+        self.submit_pending(queue_type, None)
+    }
+
+    /// Submit the pending batches for a queue, signaling `fence` or, if there is none, a fence
+    /// of the current frame, which is returned.
+    ///
+    /// A frame still waits for batches submitted with another fence before reusing its
+    /// resources, since `end_frame` submits the frame's fence on every queue afterwards, and a
+    /// fence is only signaled once all earlier submissions to its queue have completed.
+    fn submit_pending(
+        &self,
+        queue_type: QueueType,
+        fence: Option<vk::Fence>,
+    ) -> Result<vk::Fence, vk::Result> {
+        let mut batches = std::mem::take(&mut *self.pending_submits[queue_index(queue_type)].lock());
+        if queue_type == QueueType::Graphics {
+            self.schedule_block_uploads(&mut batches)?;
+        }
+
+        for &other in QUEUE_TYPES.iter().filter(|&&other| other != queue_type) {
+            let signals_waited_semaphore = self.pending_submits[queue_index(other)]
+                .lock()
+                .iter()
+                .flat_map(|submit| submit.signal_semaphores.iter())
+                .any(|semaphore| batches.iter().any(|batch| batch.wait_semaphores.contains(semaphore)));
+            if signals_waited_semaphore {
+                self.flush_queue(other)?;
+            }
+        }
+
+        // Kept alive until the submission, since the submit infos point into them.
+        let timeline_infos = batches
+            .iter()
+            .map(|batch| TimelineSemaphoreSubmitInfo::new(&batch.wait_values, &batch.signal_values))
+            .collect::<Vec<_>>();
+        let submit_infos = batches
+            .iter()
+            .zip(&timeline_infos)
+            .map(|(batch, timeline_info)| {
+                let mut info = vk::SubmitInfo::builder()
+                    .command_buffers(&batch.command_buffers)
+                    .wait_semaphores(&batch.wait_semaphores)
+                    .wait_dst_stage_mask(&batch.wait_stages)
+                    .signal_semaphores(&batch.signal_semaphores)
+                    .build();
+                if batch.uses_timelines() {
+                    info.p_next = timeline_info as *const _ as *const _;
+                }
+                info
+            })
+            .collect::<Vec<_>>();
+
+        let fence = match fence {
+            Some(fence) => {
+                let _queue = self.lock_queue(queue_type);
+                unsafe { self.device.queue_submit(self.queue(queue_type), &submit_infos, fence) }
+                    .inspect_err(|&e| self.check_vk_result(e))?;
+                fence
+            }
+            None => {
+                let mut frame = self.current_frame().write();
+                if frame.fences.len() == frame.submitted_fences {
+                    let fence = unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None)? };
+                    frame.fences.push(fence);
+                }
+                let fence = frame.fences[frame.submitted_fences];
+
+                let _queue = self.lock_queue(queue_type);
+                unsafe { self.device.queue_submit(self.queue(queue_type), &submit_infos, fence) }
+                    .inspect_err(|&e| self.check_vk_result(e))?;
+                frame.submitted_fences += 1;
+                fence
+            }
+        };
+
+        for batch in &batches {
+            self.emit_resource_events(queue_type, &batch.accesses);
+        }
+
+        Ok(fence)
+    }
+
+    /// Schedule the upload of the data written to vertex, index and uniform blocks since they
+    /// were last uploaded, before the graphics `batches` about to be submitted.
+    ///
+    /// The copies are recorded on the `AsyncTransfer` queue, whose batch signals a semaphore
+    /// which a batch put at the front of `batches` waits on. When the transfer and graphics
+    /// queue families differ, the uploaded ranges are released by the transfer queue family and
+    /// acquired by the graphics queue family, which owns the blocks' GPU-side buffers.
+    fn schedule_block_uploads(&self, batches: &mut Vec<PendingSubmit>) -> Result<(), vk::Result> {
+        let uploads = self.take_block_uploads();
+        if uploads.is_empty() {
+            return Ok(());
+        }
+
+        let transfer_family = self.transfer_queue_family_index;
+        let graphics_family = self.graphics_queue_family_index;
+        let ownership_barriers = |src_access, dst_access| {
+            uploads
+                .iter()
+                .map(|upload| {
+                    vk::BufferMemoryBarrier::builder()
+                        .src_access_mask(src_access)
+                        .dst_access_mask(dst_access)
+                        .src_queue_family_index(transfer_family)
+                        .dst_queue_family_index(graphics_family)
+                        .buffer(upload.dst)
+                        .offset(upload.offset)
+                        .size(upload.size)
+                        .build()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let transfer_cmd = self.request_raw_command_buffer(QueueType::AsyncTransfer)?;
+        unsafe {
+            for upload in &uploads {
+                let region = vk::BufferCopy {
+                    src_offset: upload.offset,
+                    dst_offset: upload.offset,
+                    size: upload.size,
+                };
+                self.device.cmd_copy_buffer(transfer_cmd, upload.src, upload.dst, &[region]);
+            }
+            if transfer_family != graphics_family {
+                self.device.cmd_pipeline_barrier(
+                    transfer_cmd,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &ownership_barriers(vk::AccessFlags::TRANSFER_WRITE, vk::AccessFlags::empty()),
+                    &[],
+                );
+            }
+            self.device.end_command_buffer(transfer_cmd)?;
+        }
+
+        // The semaphore makes the copies visible to the waiting stages, and the barrier extends
+        // the wait to the graphics batches submitted after this one.
+        let graphics_cmd = self.request_raw_command_buffer(QueueType::Graphics)?;
+        unsafe {
+            let (memory_barriers, buffer_barriers) = if transfer_family != graphics_family {
+                (Vec::new(), ownership_barriers(vk::AccessFlags::empty(), block_read_access()))
+            } else {
+                let barrier = vk::MemoryBarrier::builder().dst_access_mask(block_read_access()).build();
+                (vec![barrier], Vec::new())
+            };
+            self.device.cmd_pipeline_barrier(
+                graphics_cmd,
+                block_read_stages(),
+                block_read_stages(),
+                vk::DependencyFlags::empty(),
+                &memory_barriers,
+                &buffer_barriers,
+                &[],
+            );
+            self.device.end_command_buffer(graphics_cmd)?;
+        }
+
+        let semaphore = self.request_raw_semaphore()?;
+        self.pending_submits[queue_index(QueueType::AsyncTransfer)]
+            .lock()
+            .push(PendingSubmit {
+                command_buffers: vec![transfer_cmd],
+                signal_semaphores: vec![semaphore],
+                signal_values: vec![0],
+                ..Default::default()
+            });
+        batches.insert(
+            0,
+            PendingSubmit {
+                command_buffers: vec![graphics_cmd],
+                wait_semaphores: vec![semaphore],
+                wait_stages: vec![block_read_stages()],
+                wait_values: vec![0],
+                ..Default::default()
+            },
+        );
+        self.current_frame().write().used_semaphores.push(semaphore);
+
+        Ok(())
+    }
+
+    /// Collect the ranges of the queued vertex, index, uniform and indirect blocks written since
+    /// they were last uploaded, flushing them from the host, and mark them as uploaded.
+    fn take_block_uploads(&self) -> Vec<BlockUpload> {
+        // Locked before the upload queues, as when blocks are requested.
+        let mut blocks = self.blocks.write();
+        let blocks = match *blocks {
+            Some(ref mut blocks) => blocks,
+            None => return Vec::new(),
+        };
+
+        let queues = [
+            (PoolKind::Vertex, &self.vbo_upload_queue),
+            (PoolKind::Index, &self.ibo_upload_queue),
+            (PoolKind::Uniform, &self.ubo_upload_queue),
+            (PoolKind::Indirect, &self.indirect_upload_queue),
+        ];
+        let mut uploads = Vec::new();
+        for &(kind, queue) in &queues {
+            let pool = blocks.pool_mut(kind);
+            for &handle in queue.read().iter() {
+                let block = match pool.get_block_mut(handle) {
+                    Some(block) => block,
+                    None => continue,
+                };
+                let (offset, used) = (block.uploaded, block.used());
+                let cpu = match block.cpu {
+                    Some(ref cpu) if used > offset => cpu,
+                    _ => continue,
+                };
+
+                // A no-op for coherent memory.
+                if let Some(allocation) = cpu.allocation() {
+                    if let Err(e) = self.allocator.flush_allocation(allocation, offset, used - offset) {
+                        self.invariant_failed(
+                            block.tag.as_ref(),
+                            format!("failed to flush BufferBlock for upload: {}", e),
+                        );
+                    }
+                }
+                uploads.push(BlockUpload {
+                    src: cpu.raw(),
+                    dst: block.gpu.raw(),
+                    offset: offset as vk::DeviceSize,
+                    size: (used - offset) as vk::DeviceSize,
+                });
+                block.uploaded = used;
+            }
+        }
+        uploads
+    }
+
+    /// Flush the pending batches of every queue. See `flush_queue`.
+    pub fn flush_all_queues(&self) -> Result<(), vk::Result> {
+        for &queue_type in &QUEUE_TYPES {
+            self.flush_queue(queue_type)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//! Surfaces for windows of any windowing library implementing `raw-window-handle`, such as
+//! winit or sdl2, so that applications don't need platform specific surface code.
+//!
+//! The platform's surface extension must be enabled on the instance, which
+//! `DeviceBuilder::surface_extensions_for` does given the display, before
+//! `Device::create_surface` can create a surface to pass to `Device::init_swapchain`.
+//!
+//! Win32, Xlib, Xcb, Wayland and Android windows are supported, as are AppKit and UIKit views
+//! through MoltenVK's `VK_MVK_macos_surface` and `VK_MVK_ios_surface`.
+
+use ash::extensions::{khr, mvk};
+use ash::vk;
+
+use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle, RawDisplayHandle, RawWindowHandle};
+
+use thiserror::Error;
+
+use std::ffi::CStr;
+
+use crate::*;
+
+/// An error that could occur while creating a surface for a window.
+#[derive(Error, Debug)]
+pub enum SurfaceError {
+    /// The window or display is of a platform hot can't create surfaces for, or the two are of
+    /// different platforms.
+    #[error("unsupported window or display platform")]
+    UnsupportedPlatform,
+    /// The platform's surface extension was not enabled on the instance. See
+    /// `DeviceBuilder::surface_extensions_for`.
+    #[error("the instance extension {0:?} is not enabled")]
+    ExtensionNotEnabled(&'static CStr),
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Get the instance extensions needed to create surfaces for windows on `display`, besides
+/// `VK_KHR_surface`.
+pub fn surface_extensions(display: RawDisplayHandle) -> Result<Vec<&'static CStr>, SurfaceError> {
+    let name = match display {
+        RawDisplayHandle::Windows(_) => khr::Win32Surface::name(),
+        RawDisplayHandle::Xlib(_) => khr::XlibSurface::name(),
+        RawDisplayHandle::Xcb(_) => khr::XcbSurface::name(),
+        RawDisplayHandle::Wayland(_) => khr::WaylandSurface::name(),
+        RawDisplayHandle::Android(_) => khr::AndroidSurface::name(),
+        RawDisplayHandle::AppKit(_) => mvk::MacOSSurface::name(),
+        RawDisplayHandle::UiKit(_) => mvk::IOSSurface::name(),
+        _ => return Err(SurfaceError::UnsupportedPlatform),
+    };
+    Ok(vec![name])
+}
+
+impl Device {
+    /// Create a surface for `window`, to pass to `init_swapchain`.
+    ///
+    /// AppKit and UIKit views must be backed by a `CAMetalLayer`.
+    ///
+    /// # Safety
+    ///
+    /// The window must outlive the surface, i.e. the swapchain must be destroyed or given
+    /// another surface before the window is destroyed.
+    pub unsafe fn create_surface<W>(&self, window: &W) -> Result<vk::SurfaceKHR, SurfaceError>
+    where
+        W: HasRawWindowHandle + HasRawDisplayHandle,
+    {
+        let display = window.raw_display_handle();
+        for name in surface_extensions(display)? {
+            if !self.instance_extensions.contains(&name) {
+                return Err(SurfaceError::ExtensionNotEnabled(name));
+            }
+        }
+
+        let surface = match (window.raw_window_handle(), display) {
+            (RawWindowHandle::Win32(window), RawDisplayHandle::Windows(_)) => {
+                let create_info = vk::Win32SurfaceCreateInfoKHR::builder()
+                    .hinstance(window.hinstance)
+                    .hwnd(window.hwnd);
+                khr::Win32Surface::new(&self.entry, &self.instance)
+                    .create_win32_surface(&create_info, None)?
+            }
+            (RawWindowHandle::Xlib(window), RawDisplayHandle::Xlib(display)) => {
+                let create_info = vk::XlibSurfaceCreateInfoKHR::builder()
+                    .dpy(display.display as *mut vk::Display)
+                    .window(window.window);
+                khr::XlibSurface::new(&self.entry, &self.instance)
+                    .create_xlib_surface(&create_info, None)?
+            }
+            (RawWindowHandle::Xcb(window), RawDisplayHandle::Xcb(display)) => {
+                let create_info = vk::XcbSurfaceCreateInfoKHR::builder()
+                    .connection(display.connection as *mut vk::xcb_connection_t)
+                    .window(window.window);
+                khr::XcbSurface::new(&self.entry, &self.instance)
+                    .create_xcb_surface(&create_info, None)?
+            }
+            (RawWindowHandle::Wayland(window), RawDisplayHandle::Wayland(display)) => {
+                let create_info = vk::WaylandSurfaceCreateInfoKHR::builder()
+                    .display(display.display)
+                    .surface(window.surface);
+                khr::WaylandSurface::new(&self.entry, &self.instance)
+                    .create_wayland_surface(&create_info, None)?
+            }
+            (RawWindowHandle::AndroidNdk(window), RawDisplayHandle::Android(_)) => {
+                let create_info =
+                    vk::AndroidSurfaceCreateInfoKHR::builder().window(window.a_native_window);
+                khr::AndroidSurface::new(&self.entry, &self.instance)
+                    .create_android_surface(&create_info, None)?
+            }
+            (RawWindowHandle::AppKit(window), RawDisplayHandle::AppKit(_)) => {
+                let create_info = vk::MacOSSurfaceCreateInfoMVK {
+                    p_view: window.ns_view,
+                    ..Default::default()
+                };
+                mvk::MacOSSurface::new(&self.entry, &self.instance)
+                    .create_mac_os_surface_mvk(&create_info, None)?
+            }
+            (RawWindowHandle::UiKit(window), RawDisplayHandle::UiKit(_)) => {
+                let create_info = vk::IOSSurfaceCreateInfoMVK {
+                    p_view: window.ui_view,
+                    ..Default::default()
+                };
+                mvk::IOSSurface::new(&self.entry, &self.instance)
+                    .create_ios_surface_mvk(&create_info, None)?
+            }
+            _ => return Err(SurfaceError::UnsupportedPlatform),
+        };
+
+        Ok(surface)
+    }
+}
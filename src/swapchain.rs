@@ -0,0 +1,856 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use parking_lot::*;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::*;
+
+/// An error that could occur while creating or using the `Swapchain`.
+#[derive(Error, Debug)]
+pub enum SwapchainError {
+    /// `Device::init_swapchain` has not been called, or the swapchain was destroyed.
+    #[error("the swapchain has not been initialized")]
+    NotInitialized,
+    /// The graphics queue of the device is not able to present to the surface.
+    #[error("the graphics queue cannot present to this surface")]
+    PresentNotSupported,
+    /// The Device was built headless, so it can't present. See `DeviceBuilder::headless`.
+    #[error("the device is headless")]
+    Headless,
+    /// The surface currently has a zero-sized extent, for example because the window is
+    /// minimized. Try again later.
+    #[error("the surface has a zero-sized extent")]
+    ZeroExtent,
+    /// Presentation is suspended with `Device::suspend_presentation`, for example because the
+    /// app was sent to the background on Android. Skip presenting until it is resumed.
+    #[error("presentation is suspended")]
+    Suspended,
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// Information needed to create a Swapchain.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct SwapchainCreateInfo {
+    /// Width of the swapchain in pixels. Only used if the surface does not dictate its own extent.
+    pub width: u32,
+    /// Height of the swapchain in pixels. Only used if the surface does not dictate its own extent.
+    pub height: u32,
+    /// Whether an sRGB format should be preferred.
+    pub srgb: bool,
+    /// The preferred present mode. If it is not supported, `vk::PresentModeKHR::FIFO` is used.
+    pub present_mode: vk::PresentModeKHR,
+    /// The desired number of swapchain images. Will be clamped to what the surface supports.
+    pub image_count: u32,
+    /// Whether to render in the surface's native orientation rather than having the presentation
+    /// engine rotate the image, which avoids an extra pass on many mobile devices.
+    ///
+    /// If set, the swapchain's `pre_transform` may be a rotation, which the application must
+    /// apply to its rendering (its extent will already be rotated to match).
+    pub pre_rotate: bool,
+}
+
+impl Default for SwapchainCreateInfo {
+    fn default() -> Self {
+        SwapchainCreateInfo {
+            width: 0,
+            height: 0,
+            srgb: true,
+            present_mode: vk::PresentModeKHR::FIFO,
+            image_count: 3,
+            pre_rotate: false,
+        }
+    }
+}
+
+/// An image acquired from the swapchain, ready to be rendered to and then presented.
+#[derive(Clone, Copy, Debug)]
+pub struct SwapchainFrame {
+    /// The index of the image within the swapchain.
+    pub image_index: u32,
+    /// The swapchain image.
+    pub image: ImageHandle,
+    /// A semaphore which will be signaled once the image is ready to be rendered to. The first
+    /// submission which uses the image must wait on it.
+    pub acquire_semaphore: vk::Semaphore,
+    /// A semaphore which must be signaled by the last submission which renders to the image.
+    /// Presentation waits on it.
+    pub present_semaphore: vk::Semaphore,
+}
+
+/// Timing feedback about a single present, reported by the presentation engine.
+///
+/// Times are in nanoseconds, in the same clock domain as the presentation engine.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct PresentFeedback {
+    /// The id of the present, counting up from 0 for each swapchain.
+    pub present_id: u32,
+    /// The time at which the application requested the image be presented, or 0 if it did not.
+    pub desired_present_time: u64,
+    /// The time at which the image was actually presented.
+    pub actual_present_time: u64,
+    /// The earliest time at which the image could have been presented. If this is earlier than
+    /// `actual_present_time`, the frame was presented later than it needed to be.
+    pub earliest_present_time: u64,
+    /// How long before its presentation deadline the image was ready to be presented.
+    pub present_margin: u64,
+    /// Whether the image was never presented, for example because a later image replaced it.
+    /// All times are 0 for a dropped frame.
+    pub dropped: bool,
+}
+
+impl PresentFeedback {
+    fn dropped(present_id: u32) -> Self {
+        PresentFeedback {
+            present_id,
+            desired_present_time: 0,
+            actual_present_time: 0,
+            earliest_present_time: 0,
+            present_margin: 0,
+            dropped: true,
+        }
+    }
+}
+
+/// Aggregate presentation statistics of a swapchain, for example to display in a frame time HUD.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct PresentStatistics {
+    /// The number of frames which were presented.
+    pub presented: u64,
+    /// The number of frames which were dropped.
+    pub dropped: u64,
+    /// The feedback of the most recently presented frame.
+    pub last_presented: Option<PresentFeedback>,
+    /// The interval between the two most recently presented frames.
+    pub last_interval: Option<Duration>,
+}
+
+/// A swapchain and the surface it presents to. Owned by the `Device`; see `Device::init_swapchain`.
+#[derive(Debug)]
+pub struct Swapchain {
+    surface: vk::SurfaceKHR,
+    swapchain: vk::SwapchainKHR,
+    create_info: SwapchainCreateInfo,
+    format: vk::SurfaceFormatKHR,
+    present_mode: vk::PresentModeKHR,
+    extent: vk::Extent2D,
+    pre_transform: vk::SurfaceTransformFlagsKHR,
+    images: Vec<ImageHandle>,
+    acquire_semaphores: Vec<vk::Semaphore>,
+    present_semaphores: Vec<vk::Semaphore>,
+    next_acquire_semaphore: usize,
+    needs_recreate: bool,
+    refresh_duration: Option<Duration>,
+    next_present_id: u32,
+    next_feedback_id: u32,
+    statistics: PresentStatistics,
+}
+
+impl Swapchain {
+    /// The raw `vk::SwapchainKHR`.
+    pub fn raw(&self) -> vk::SwapchainKHR {
+        self.swapchain
+    }
+
+    /// The raw `vk::SurfaceKHR` this swapchain presents to.
+    pub fn surface(&self) -> vk::SurfaceKHR {
+        self.surface
+    }
+
+    /// The SwapchainCreateInfo this swapchain was created with.
+    pub fn create_info(&self) -> SwapchainCreateInfo {
+        self.create_info
+    }
+
+    /// The negotiated surface format.
+    pub fn format(&self) -> vk::SurfaceFormatKHR {
+        self.format
+    }
+
+    /// The negotiated present mode.
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+
+    /// The current extent of the swapchain images.
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// The transform applied by the presentation engine to the swapchain images. When this is a
+    /// 90 or 270 degree rotation, the application must rotate its rendering to match.
+    pub fn pre_transform(&self) -> vk::SurfaceTransformFlagsKHR {
+        self.pre_transform
+    }
+
+    /// The duration of a refresh cycle of the display, if the device supports presentation timing.
+    pub fn refresh_duration(&self) -> Option<Duration> {
+        self.refresh_duration
+    }
+
+    /// The presentation statistics gathered by `Device::poll_present_feedback` so far.
+    pub fn statistics(&self) -> PresentStatistics {
+        self.statistics
+    }
+
+    /// The swapchain images. These are only valid until the swapchain is next recreated.
+    pub fn images(&self) -> &[ImageHandle] {
+        &self.images
+    }
+
+    /// Choose the format, present mode, extent and image count for a surface and create the
+    /// raw swapchain and its images.
+    fn create_raw(
+        device: &Arc<Device>,
+        surface: vk::SurfaceKHR,
+        create_info: SwapchainCreateInfo,
+        old_swapchain: vk::SwapchainKHR,
+    ) -> Result<Self, SwapchainError> {
+        let surface_loader = &device.surface_loader;
+        let (capabilities, formats, present_modes) = unsafe {
+            (
+                surface_loader
+                    .get_physical_device_surface_capabilities(device.physical_device, surface)?,
+                surface_loader.get_physical_device_surface_formats(device.physical_device, surface)?,
+                surface_loader
+                    .get_physical_device_surface_present_modes(device.physical_device, surface)?,
+            )
+        };
+
+        let format = choose_surface_format(&formats, create_info.srgb);
+
+        let present_mode = if present_modes.contains(&create_info.present_mode) {
+            create_info.present_mode
+        } else {
+            vk::PresentModeKHR::FIFO
+        };
+
+        let pre_transform = if create_info.pre_rotate
+            || !capabilities
+                .supported_transforms
+                .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
+        {
+            capabilities.current_transform
+        } else {
+            vk::SurfaceTransformFlagsKHR::IDENTITY
+        };
+
+        let extent = if capabilities.current_extent.width != u32::MAX {
+            // The current extent is in the surface's native orientation, so if the presentation
+            // engine is going to rotate the images for us they must be in the rotated orientation.
+            if pre_transform == capabilities.current_transform
+                || !transform_is_rotated(capabilities.current_transform)
+            {
+                capabilities.current_extent
+            } else {
+                vk::Extent2D {
+                    width: capabilities.current_extent.height,
+                    height: capabilities.current_extent.width,
+                }
+            }
+        } else {
+            vk::Extent2D {
+                width: create_info.width.max(capabilities.min_image_extent.width)
+                    .min(capabilities.max_image_extent.width),
+                height: create_info.height.max(capabilities.min_image_extent.height)
+                    .min(capabilities.max_image_extent.height),
+            }
+        };
+
+        if extent.width == 0 || extent.height == 0 {
+            return Err(SwapchainError::ZeroExtent);
+        }
+
+        let mut image_count = create_info.image_count.max(capabilities.min_image_count);
+        if capabilities.max_image_count > 0 {
+            image_count = image_count.min(capabilities.max_image_count);
+        }
+
+        let swapchain_info = vk::SwapchainCreateInfoKHR::builder()
+            .surface(surface)
+            .min_image_count(image_count)
+            .image_format(format.format)
+            .image_color_space(format.color_space)
+            .image_extent(extent)
+            .image_array_layers(1)
+            .image_usage(
+                vk::ImageUsageFlags::COLOR_ATTACHMENT
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::TRANSFER_SRC,
+            )
+            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .pre_transform(pre_transform)
+            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+            .present_mode(present_mode)
+            .clipped(true)
+            .old_swapchain(old_swapchain);
+
+        let swapchain = unsafe {
+            device
+                .swapchain_loader
+                .create_swapchain(&swapchain_info, None)?
+        };
+
+        let raw_images = unsafe { device.swapchain_loader.get_swapchain_images(swapchain)? };
+
+        let image_create_info = ImageCreateInfo {
+            width: extent.width as usize,
+            height: extent.height as usize,
+            depth: 1,
+            format: format.format,
+            usage: swapchain_info.image_usage,
+            initial_layout: vk::ImageLayout::UNDEFINED,
+            ..Default::default()
+        };
+
+        let images = {
+            let mut resources = device.resources_mut();
+            raw_images
+                .iter()
+                .map(|&raw| {
+                    let image = unsafe {
+                        Image::new(
+                            device.clone(),
+                            raw,
+                            None,
+                            image_create_info,
+                            None,
+                            ImageLayoutType::Optimal,
+                            vk::PipelineStageFlags::empty(),
+                            vk::AccessFlags::empty(),
+                            vk::ImageLayout::PRESENT_SRC_KHR,
+                            Some(Tag::Static("swapchain image")),
+                        )
+                    };
+                    ImageHandle::new(resources.images.insert(image))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let semaphore_info = vk::SemaphoreCreateInfo::default();
+        let mut acquire_semaphores = Vec::with_capacity(raw_images.len() + 1);
+        let mut present_semaphores = Vec::with_capacity(raw_images.len());
+        unsafe {
+            for _ in 0..=raw_images.len() {
+                acquire_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
+            }
+            for _ in 0..raw_images.len() {
+                present_semaphores.push(device.create_semaphore(&semaphore_info, None)?);
+            }
+        }
+        if device.has_debug_utils() {
+            for &semaphore in &acquire_semaphores {
+                device.set_object_name(semaphore, "swapchain acquire semaphore");
+            }
+            for &semaphore in &present_semaphores {
+                device.set_object_name(semaphore, "swapchain present semaphore");
+            }
+        }
+
+        let refresh_duration = match device.display_timing {
+            Some(ref display_timing) => {
+                let mut properties = vk::RefreshCycleDurationGOOGLE::default();
+                let result = unsafe {
+                    display_timing.get_refresh_cycle_duration_google(
+                        device.device.handle(),
+                        swapchain,
+                        &mut properties,
+                    )
+                };
+                if result != vk::Result::SUCCESS {
+                    device.check_vk_result(result);
+                    return Err(result.into());
+                }
+                Some(Duration::from_nanos(properties.refresh_duration))
+            }
+            None => None,
+        };
+
+        Ok(Swapchain {
+            surface,
+            swapchain,
+            create_info,
+            format,
+            present_mode,
+            extent,
+            pre_transform,
+            images,
+            acquire_semaphores,
+            present_semaphores,
+            next_acquire_semaphore: 0,
+            needs_recreate: false,
+            refresh_duration,
+            next_present_id: 0,
+            next_feedback_id: 0,
+            statistics: PresentStatistics::default(),
+        })
+    }
+
+    /// Destroy the swapchain's images and semaphores and the raw swapchain, but not the surface.
+    ///
+    /// # Safety
+    ///
+    /// None of the swapchain's images or semaphores may be in use by the GPU.
+    unsafe fn destroy_raw(&mut self, device: &Device) {
+        {
+            let mut resources = device.resources_mut();
+            for image in self.images.drain(..) {
+                resources.images.remove(image.idx);
+            }
+        }
+        for semaphore in self
+            .acquire_semaphores
+            .drain(..)
+            .chain(self.present_semaphores.drain(..))
+        {
+            device.destroy_semaphore(semaphore, None);
+        }
+        device.swapchain_loader.destroy_swapchain(self.swapchain, None);
+        self.swapchain = vk::SwapchainKHR::null();
+    }
+
+    /// Recreate the swapchain, for example because it went out of date.
+    fn recreate(&mut self, device: &Arc<Device>) -> Result<(), SwapchainError> {
+        unsafe { device.device_wait_idle()? };
+
+        let mut new = Self::create_raw(device, self.surface, self.create_info, self.swapchain)?;
+        new.statistics = self.statistics;
+        let mut old = std::mem::replace(self, new);
+        unsafe { old.destroy_raw(device) };
+
+        Ok(())
+    }
+}
+
+/// Get whether a surface transform rotates the image by 90 or 270 degrees, i.e. swaps its width
+/// and height.
+pub fn transform_is_rotated(transform: vk::SurfaceTransformFlagsKHR) -> bool {
+    transform.intersects(
+        vk::SurfaceTransformFlagsKHR::ROTATE_90
+            | vk::SurfaceTransformFlagsKHR::ROTATE_270
+            | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_90
+            | vk::SurfaceTransformFlagsKHR::HORIZONTAL_MIRROR_ROTATE_270,
+    )
+}
+
+/// Pick the surface format to use, preferring 8-bit BGRA/RGBA in either sRGB or UNORM.
+fn choose_surface_format(formats: &[vk::SurfaceFormatKHR], srgb: bool) -> vk::SurfaceFormatKHR {
+    let preferred: &[vk::Format] = if srgb {
+        &[vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_SRGB]
+    } else {
+        &[vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM]
+    };
+
+    // A single UNDEFINED format means that the surface has no preference.
+    if formats.len() == 1 && formats[0].format == vk::Format::UNDEFINED {
+        return vk::SurfaceFormatKHR {
+            format: preferred[0],
+            color_space: vk::ColorSpaceKHR::SRGB_NONLINEAR,
+        };
+    }
+
+    preferred
+        .iter()
+        .find_map(|&format| {
+            formats.iter().copied().find(|candidate| {
+                candidate.format == format
+                    && candidate.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+            })
+        })
+        .unwrap_or(formats[0])
+}
+
+/// What a surface supports on this Device, for choosing a `SwapchainCreateInfo` (or offering
+/// choices in a settings menu) before creating the swapchain. Returned by
+/// `Device::surface_support_report`.
+#[derive(Clone, Debug)]
+pub struct SurfaceSupportReport {
+    /// Whether the graphics queue can present to the surface. If not, `init_swapchain` will fail.
+    pub present_supported: bool,
+    /// The supported formats and color spaces.
+    pub formats: Vec<vk::SurfaceFormatKHR>,
+    /// The supported present modes. `FIFO` is always supported.
+    pub present_modes: Vec<vk::PresentModeKHR>,
+    /// The minimum number of swapchain images.
+    pub min_image_count: u32,
+    /// The maximum number of swapchain images, or `None` if there is no limit.
+    pub max_image_count: Option<u32>,
+    /// The current extent of the surface, or `None` if it is determined by the swapchain.
+    pub current_extent: Option<vk::Extent2D>,
+    /// The smallest supported swapchain extent.
+    pub min_image_extent: vk::Extent2D,
+    /// The largest supported swapchain extent.
+    pub max_image_extent: vk::Extent2D,
+    /// The supported pre-transforms.
+    pub supported_transforms: vk::SurfaceTransformFlagsKHR,
+    /// The surface's current transform relative to its native orientation.
+    pub current_transform: vk::SurfaceTransformFlagsKHR,
+    /// The supported ways of compositing the surface's alpha channel.
+    pub supported_composite_alpha: vk::CompositeAlphaFlagsKHR,
+    /// The image usages swapchain images may have.
+    pub supported_usage: vk::ImageUsageFlags,
+}
+
+impl Device {
+    /// Query what `surface` supports without creating a swapchain for it.
+    ///
+    /// `surface` must have been created from this Device's instance. Unlike `init_swapchain`,
+    /// this does not take ownership of it.
+    pub fn surface_support_report(&self, surface: vk::SurfaceKHR) -> Result<SurfaceSupportReport, SwapchainError> {
+        if self.headless {
+            return Err(SwapchainError::Headless);
+        }
+        let surface_loader = &self.surface_loader;
+        let (present_supported, capabilities, formats, present_modes) = unsafe {
+            (
+                surface_loader.get_physical_device_surface_support(
+                    self.physical_device,
+                    self.graphics_queue_family_index,
+                    surface,
+                ),
+                surface_loader.get_physical_device_surface_capabilities(self.physical_device, surface)?,
+                surface_loader.get_physical_device_surface_formats(self.physical_device, surface)?,
+                surface_loader.get_physical_device_surface_present_modes(self.physical_device, surface)?,
+            )
+        };
+
+        Ok(SurfaceSupportReport {
+            present_supported,
+            formats,
+            present_modes,
+            min_image_count: capabilities.min_image_count,
+            max_image_count: Some(capabilities.max_image_count).filter(|&count| count != 0),
+            current_extent: Some(capabilities.current_extent).filter(|extent| extent.width != u32::MAX),
+            min_image_extent: capabilities.min_image_extent,
+            max_image_extent: capabilities.max_image_extent,
+            supported_transforms: capabilities.supported_transforms,
+            current_transform: capabilities.current_transform,
+            supported_composite_alpha: capabilities.supported_composite_alpha,
+            supported_usage: capabilities.supported_usage_flags,
+        })
+    }
+
+    /// Create the swapchain for `surface`, replacing any existing swapchain. The Device takes
+    /// ownership of `surface` and will destroy it along with the swapchain.
+    ///
+    /// `surface` must have been created from this Device's instance (see `Device::raw_instance`
+    /// and `Device::raw_entry`).
+    pub fn init_swapchain(
+        self: Arc<Self>,
+        surface: vk::SurfaceKHR,
+        create_info: SwapchainCreateInfo,
+    ) -> Result<(), SwapchainError> {
+        if self.headless {
+            return Err(SwapchainError::Headless);
+        }
+        let supported = unsafe {
+            self.surface_loader.get_physical_device_surface_support(
+                self.physical_device,
+                self.graphics_queue_family_index,
+                surface,
+            )
+        };
+        if !supported {
+            return Err(SwapchainError::PresentNotSupported);
+        }
+
+        self.destroy_swapchain();
+
+        let swapchain =
+            Swapchain::create_raw(&self, surface, create_info, vk::SwapchainKHR::null())?;
+        *self.swapchain.lock() = Some(swapchain);
+
+        Ok(())
+    }
+
+    /// Acquire a handle to the swapchain, if it has been initialized.
+    pub fn swapchain(&self) -> Option<MappedMutexGuard<'_, Swapchain>> {
+        MutexGuard::try_map(self.swapchain.lock(), |swapchain| swapchain.as_mut()).ok()
+    }
+
+    /// Change the desired extent of the swapchain, for example when the window is resized. The
+    /// swapchain will be recreated before the next frame is acquired. While presentation is
+    /// suspended, this changes the extent the swapchain is resumed with instead.
+    pub fn resize_swapchain(&self, width: u32, height: u32) -> Result<(), SwapchainError> {
+        if let Some(create_info) = self.suspended_swapchain.lock().as_mut() {
+            create_info.width = width;
+            create_info.height = height;
+            return Ok(());
+        }
+
+        let mut swapchain = self.swapchain().ok_or(SwapchainError::NotInitialized)?;
+        swapchain.create_info.width = width;
+        swapchain.create_info.height = height;
+        swapchain.needs_recreate = true;
+        Ok(())
+    }
+
+    /// Acquire the next image from the swapchain, recreating the swapchain first if it is out of
+    /// date or suboptimal.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn acquire_next_frame(self: Arc<Self>) -> Result<SwapchainFrame, SwapchainError> {
+        let mut swapchain = self.swapchain().ok_or_else(|| self.missing_swapchain_error())?;
+
+        if swapchain.needs_recreate {
+            swapchain.recreate(&self)?;
+        }
+
+        let mut retried = false;
+        loop {
+            let acquire_semaphore = swapchain.acquire_semaphores[swapchain.next_acquire_semaphore];
+
+            let result = unsafe {
+                self.swapchain_loader.acquire_next_image(
+                    swapchain.swapchain,
+                    u64::MAX,
+                    acquire_semaphore,
+                    vk::Fence::null(),
+                )
+            };
+
+            match result {
+                Ok((image_index, suboptimal)) => {
+                    swapchain.needs_recreate = suboptimal;
+                    swapchain.next_acquire_semaphore =
+                        (swapchain.next_acquire_semaphore + 1) % swapchain.acquire_semaphores.len();
+
+                    return Ok(SwapchainFrame {
+                        image_index,
+                        image: swapchain.images[image_index as usize],
+                        acquire_semaphore,
+                        present_semaphore: swapchain.present_semaphores[image_index as usize],
+                    });
+                }
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) if !retried => {
+                    retried = true;
+                    swapchain.recreate(&self)?;
+                }
+                Err(e) => {
+                    self.check_vk_result(e);
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+
+    /// Present a frame previously acquired with `acquire_next_frame`. Presentation waits on the
+    /// frame's `present_semaphore`.
+    ///
+    /// If the swapchain turns out to be out of date or suboptimal, it will be recreated.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn present(self: Arc<Self>, frame: SwapchainFrame) -> Result<(), SwapchainError> {
+        // Screenshots copy the image before it is presented, so presentation waits on the copy.
+        let present_semaphore = self
+            .capture_screenshots(frame.image, frame.present_semaphore)
+            .unwrap_or(frame.present_semaphore);
+
+        let mut swapchain = self.swapchain().ok_or_else(|| self.missing_swapchain_error())?;
+
+        let wait_semaphores = [present_semaphore];
+        let swapchains = [swapchain.swapchain];
+        let image_indices = [frame.image_index];
+        let mut present_info = vk::PresentInfoKHR::builder()
+            .wait_semaphores(&wait_semaphores)
+            .swapchains(&swapchains)
+            .image_indices(&image_indices);
+
+        let present_times = [vk::PresentTimeGOOGLE {
+            present_id: swapchain.next_present_id,
+            desired_present_time: 0,
+        }];
+        let mut present_times_info = vk::PresentTimesInfoGOOGLE::builder().times(&present_times);
+        if self.display_timing.is_some() {
+            present_info = present_info.push_next(&mut present_times_info);
+            swapchain.next_present_id = swapchain.next_present_id.wrapping_add(1);
+        }
+
+        let result = unsafe {
+            let _queue = self.lock_queue(QueueType::Graphics);
+            self.swapchain_loader
+                .queue_present(self.graphics_queue, &present_info)
+        };
+
+        match result {
+            Ok(false) => Ok(()),
+            Ok(true) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => swapchain.recreate(&self),
+            Err(e) => {
+                self.check_vk_result(e);
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Get whether the device supports presentation timing feedback (`VK_GOOGLE_display_timing`).
+    pub fn supports_present_feedback(&self) -> bool {
+        self.display_timing.is_some()
+    }
+
+    /// Collect timing feedback about frames presented since the last call, in present order,
+    /// and update the swapchain's `PresentStatistics`.
+    ///
+    /// Frames which were never presented are reported as dropped. Feedback usually arrives a few
+    /// frames after the present, and is always empty if the device does not support presentation
+    /// timing.
+    pub fn poll_present_feedback(&self) -> Result<Vec<PresentFeedback>, SwapchainError> {
+        let display_timing = match self.display_timing {
+            Some(ref display_timing) => display_timing,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut swapchain = self.swapchain().ok_or(SwapchainError::NotInitialized)?;
+
+        let mut timings = Vec::new();
+        loop {
+            let mut count = 0;
+            let result = unsafe {
+                display_timing.get_past_presentation_timing_google(
+                    self.device.handle(),
+                    swapchain.swapchain,
+                    &mut count,
+                    std::ptr::null_mut(),
+                )
+            };
+            if result != vk::Result::SUCCESS {
+                self.check_vk_result(result);
+                return Err(result.into());
+            }
+
+            timings.resize(count as usize, vk::PastPresentationTimingGOOGLE::default());
+            let result = unsafe {
+                display_timing.get_past_presentation_timing_google(
+                    self.device.handle(),
+                    swapchain.swapchain,
+                    &mut count,
+                    timings.as_mut_ptr(),
+                )
+            };
+            match result {
+                vk::Result::SUCCESS => {
+                    timings.truncate(count as usize);
+                    break;
+                }
+                // More timings became available between the two calls.
+                vk::Result::INCOMPLETE => continue,
+                e => {
+                    self.check_vk_result(e);
+                    return Err(e.into());
+                }
+            }
+        }
+
+        let mut feedback = Vec::with_capacity(timings.len());
+        for timing in timings {
+            // Timings are reported in present order, so any skipped ids were never presented.
+            while swapchain.next_feedback_id != timing.present_id {
+                feedback.push(PresentFeedback::dropped(swapchain.next_feedback_id));
+                swapchain.next_feedback_id = swapchain.next_feedback_id.wrapping_add(1);
+            }
+            feedback.push(PresentFeedback {
+                present_id: timing.present_id,
+                desired_present_time: timing.desired_present_time,
+                actual_present_time: timing.actual_present_time,
+                earliest_present_time: timing.earliest_present_time,
+                present_margin: timing.present_margin,
+                dropped: false,
+            });
+            swapchain.next_feedback_id = timing.present_id.wrapping_add(1);
+        }
+
+        let statistics = &mut swapchain.statistics;
+        for present in &feedback {
+            if present.dropped {
+                statistics.dropped += 1;
+                continue;
+            }
+
+            statistics.presented += 1;
+            if let Some(last) = statistics.last_presented {
+                statistics.last_interval = Some(Duration::from_nanos(
+                    present.actual_present_time.saturating_sub(last.actual_present_time),
+                ));
+            }
+            statistics.last_presented = Some(*present);
+        }
+
+        Ok(feedback)
+    }
+
+    /// Tear down the swapchain and its surface, keeping everything else, for when the window
+    /// they belong to is about to be destroyed, as happens to an Android app's native window
+    /// when it is sent to the background. The internal render target used for render scaling is
+    /// freed too, since it is sized for the swapchain.
+    ///
+    /// While suspended, `acquire_next_frame` and `present` return `SwapchainError::Suspended`,
+    /// so the app should skip presenting (and any rendering which only feeds presentation) until
+    /// `resume_presentation` is called. Frames can still be begun and ended, and other work
+    /// submitted as usual. Suspending while already suspended does nothing.
+    ///
+    /// Waits for the device to become idle first.
+    pub fn suspend_presentation(&self) -> Result<(), SwapchainError> {
+        let create_info = match self.swapchain() {
+            Some(swapchain) => swapchain.create_info,
+            None if self.is_presentation_suspended() => return Ok(()),
+            None => return Err(SwapchainError::NotInitialized),
+        };
+
+        *self.suspended_swapchain.lock() = Some(create_info);
+        self.destroy_swapchain();
+        self.release_render_target();
+
+        Ok(())
+    }
+
+    /// Resume presentation suspended with `suspend_presentation`, creating a swapchain for
+    /// `surface` with the same `SwapchainCreateInfo` as the one torn down, apart from any
+    /// `resize_swapchain` since. On Android, create the surface for the new native window with
+    /// `Device::create_surface` once it is available.
+    ///
+    /// As with `init_swapchain`, the Device takes ownership of `surface`. If creating the
+    /// swapchain fails, presentation stays suspended and resuming can be tried again.
+    pub fn resume_presentation(self: Arc<Self>, surface: vk::SurfaceKHR) -> Result<(), SwapchainError> {
+        let create_info = match *self.suspended_swapchain.lock() {
+            Some(create_info) => create_info,
+            None => return Err(SwapchainError::NotInitialized),
+        };
+
+        self.clone().init_swapchain(surface, create_info)?;
+        *self.suspended_swapchain.lock() = None;
+
+        Ok(())
+    }
+
+    /// Get whether presentation is suspended with `suspend_presentation`.
+    pub fn is_presentation_suspended(&self) -> bool {
+        self.suspended_swapchain.lock().is_some()
+    }
+
+    /// The error to return when presenting without a swapchain.
+    fn missing_swapchain_error(&self) -> SwapchainError {
+        if self.is_presentation_suspended() {
+            SwapchainError::Suspended
+        } else {
+            SwapchainError::NotInitialized
+        }
+    }
+
+    /// Destroy the swapchain and its surface, if they exist.
+    ///
+    /// Waits for the device to become idle first.
+    pub fn destroy_swapchain(&self) {
+        if let Some(mut swapchain) = self.swapchain.lock().take() {
+            unsafe {
+                if let Err(e) = self.device.device_wait_idle() {
+                    self.invariant_failed(None, format!("device_wait_idle failed: {}", e));
+                }
+                swapchain.destroy_raw(self);
+                self.surface_loader.destroy_surface(swapchain.surface, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use derivative::Derivative;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// A pooled `vk::Fence`, requested with `Device::request_fence`.
+///
+/// On Drop the fence is returned to the Device, which resets it and makes it available to
+/// `request_fence` again once the current frame has completed on the GPU.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Fence {
+    fence: vk::Fence,
+    #[derivative(Debug = "ignore")]
+    device: Arc<Device>,
+}
+
+impl Fence {
+    /// The raw `vk::Fence`.
+    pub fn raw(&self) -> vk::Fence {
+        self.fence
+    }
+
+    /// Get whether the fence has been signaled.
+    pub fn is_signaled(&self) -> Result<bool, vk::Result> {
+        match unsafe { self.device.device.get_fence_status(self.fence) } {
+            Ok(()) => Ok(true),
+            Err(vk::Result::NOT_READY) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Wait up to `timeout` nanoseconds for the fence to be signaled, returning whether it was.
+    pub fn wait(&self, timeout: u64) -> Result<bool, vk::Result> {
+        match unsafe { self.device.device.wait_for_fences(&[self.fence], true, timeout) } {
+            Ok(()) => Ok(true),
+            Err(vk::Result::TIMEOUT) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Drop for Fence {
+    fn drop(&mut self) {
+        self.device
+            .current_frame()
+            .write()
+            .released_fences
+            .push(self.fence);
+    }
+}
+
+/// A pooled `vk::Semaphore`, requested with `Device::request_semaphore`.
+///
+/// On Drop the semaphore is returned to the Device, which makes it available to
+/// `request_semaphore` again once the current frame has completed on the GPU. It must not be
+/// dropped while signaled, i.e. it must either never have been signaled or have had every signal
+/// waited on by a submission.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Semaphore {
+    semaphore: vk::Semaphore,
+    #[derivative(Debug = "ignore")]
+    device: Arc<Device>,
+}
+
+impl Semaphore {
+    /// The raw `vk::Semaphore`.
+    pub fn raw(&self) -> vk::Semaphore {
+        self.semaphore
+    }
+}
+
+impl Drop for Semaphore {
+    fn drop(&mut self) {
+        self.device
+            .current_frame()
+            .write()
+            .used_semaphores
+            .push(self.semaphore);
+    }
+}
+
+impl Device {
+    /// Get an unsignaled fence, reusing one released by a completed frame if possible.
+    pub fn request_fence(self: &Arc<Self>) -> Result<Fence, vk::Result> {
+        Ok(Fence {
+            fence: self.request_raw_fence()?,
+            device: self.clone(),
+        })
+    }
+
+    /// Get an unsignaled semaphore, reusing one released by a completed frame if possible.
+    pub fn request_semaphore(self: &Arc<Self>) -> Result<Semaphore, vk::Result> {
+        Ok(Semaphore {
+            semaphore: self.request_raw_semaphore()?,
+            device: self.clone(),
+        })
+    }
+
+    /// Get an unsignaled raw fence, which must be returned through `PerFrame::released_fences`.
+    pub(crate) fn request_raw_fence(&self) -> Result<vk::Fence, vk::Result> {
+        match self.fence_pool.lock().pop() {
+            Some(fence) => Ok(fence),
+            None => unsafe { self.device.create_fence(&vk::FenceCreateInfo::default(), None) },
+        }
+    }
+
+    /// Get an unsignaled raw semaphore, which must be returned through
+    /// `PerFrame::used_semaphores`.
+    pub(crate) fn request_raw_semaphore(&self) -> Result<vk::Semaphore, vk::Result> {
+        match self.semaphore_pool.lock().pop() {
+            Some(semaphore) => Ok(semaphore),
+            None => unsafe { self.device.create_semaphore(&vk::SemaphoreCreateInfo::default(), None) },
+        }
+    }
+
+    /// Return the fences and semaphores released during a frame which has completed to their
+    /// pools, and destroy the semaphores dropped during it.
+    pub(crate) fn recycle_sync_objects(&self, frame: &mut PerFrame) -> Result<(), vk::Result> {
+        let released_fences = std::mem::take(&mut frame.released_fences);
+        if !released_fences.is_empty() {
+            unsafe { self.device.reset_fences(&released_fences)? };
+            self.fence_pool.lock().extend(released_fences);
+        }
+
+        let used_semaphores = std::mem::take(&mut frame.used_semaphores);
+        self.semaphore_pool.lock().extend(used_semaphores);
+
+        for semaphore in frame.destroyed_semaphores.drain(..) {
+            unsafe { self.device.destroy_semaphore(semaphore, None) };
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use ash::version::{InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_synchronization2` and Vulkan 1.3, which promoted
+// it, so the parts of it hot uses are declared here. Its stage and access masks are 64 bits wide,
+// but keep the values of the `vk::PipelineStageFlags` and `vk::AccessFlags` bits.
+
+fn structure_type(offset: i32) -> vk::StructureType {
+    vk::StructureType::from_raw(1_000_314_000 + offset)
+}
+
+/// The name of the `VK_KHR_synchronization2` extension.
+pub(crate) fn synchronization2_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_synchronization2\0").unwrap()
+}
+
+#[repr(C)]
+pub(crate) struct PhysicalDeviceSynchronization2Features {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    pub(crate) synchronization2: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceSynchronization2Features {
+    fn default() -> Self {
+        Self {
+            s_type: structure_type(7),
+            p_next: std::ptr::null_mut(),
+            synchronization2: vk::FALSE,
+        }
+    }
+}
+
+#[repr(C)]
+struct BufferMemoryBarrier2 {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    src_stage_mask: u64,
+    src_access_mask: u64,
+    dst_stage_mask: u64,
+    dst_access_mask: u64,
+    src_queue_family_index: u32,
+    dst_queue_family_index: u32,
+    buffer: vk::Buffer,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+}
+
+impl From<&BufferTransition> for BufferMemoryBarrier2 {
+    fn from(transition: &BufferTransition) -> Self {
+        let barrier = &transition.barrier;
+        Self {
+            s_type: structure_type(1),
+            p_next: std::ptr::null(),
+            src_stage_mask: u64::from(transition.src_stages.as_raw()),
+            src_access_mask: u64::from(barrier.src_access_mask.as_raw()),
+            dst_stage_mask: u64::from(transition.dst_stages.as_raw()),
+            dst_access_mask: u64::from(barrier.dst_access_mask.as_raw()),
+            src_queue_family_index: barrier.src_queue_family_index,
+            dst_queue_family_index: barrier.dst_queue_family_index,
+            buffer: barrier.buffer,
+            offset: barrier.offset,
+            size: barrier.size,
+        }
+    }
+}
+
+#[repr(C)]
+struct ImageMemoryBarrier2 {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    src_stage_mask: u64,
+    src_access_mask: u64,
+    dst_stage_mask: u64,
+    dst_access_mask: u64,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout,
+    src_queue_family_index: u32,
+    dst_queue_family_index: u32,
+    image: vk::Image,
+    subresource_range: vk::ImageSubresourceRange,
+}
+
+impl From<&ImageTransition> for ImageMemoryBarrier2 {
+    fn from(transition: &ImageTransition) -> Self {
+        let barrier = &transition.barrier;
+        Self {
+            s_type: structure_type(2),
+            p_next: std::ptr::null(),
+            src_stage_mask: u64::from(transition.src_stages.as_raw()),
+            src_access_mask: u64::from(barrier.src_access_mask.as_raw()),
+            dst_stage_mask: u64::from(transition.dst_stages.as_raw()),
+            dst_access_mask: u64::from(barrier.dst_access_mask.as_raw()),
+            old_layout: barrier.old_layout,
+            new_layout: barrier.new_layout,
+            src_queue_family_index: barrier.src_queue_family_index,
+            dst_queue_family_index: barrier.dst_queue_family_index,
+            image: barrier.image,
+            subresource_range: barrier.subresource_range,
+        }
+    }
+}
+
+#[repr(C)]
+struct DependencyInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    dependency_flags: vk::DependencyFlags,
+    memory_barrier_count: u32,
+    p_memory_barriers: *const c_void,
+    buffer_memory_barrier_count: u32,
+    p_buffer_memory_barriers: *const BufferMemoryBarrier2,
+    image_memory_barrier_count: u32,
+    p_image_memory_barriers: *const ImageMemoryBarrier2,
+}
+
+type VoidFunction = unsafe extern "system" fn() -> c_void;
+type CmdPipelineBarrier2 = unsafe extern "system" fn(vk::CommandBuffer, *const DependencyInfo);
+
+/// The device functions of `VK_KHR_synchronization2`, or of Vulkan 1.3 core.
+#[derive(Clone)]
+pub(crate) struct Synchronization2Fn {
+    cmd_pipeline_barrier2: CmdPipelineBarrier2,
+}
+
+impl Synchronization2Fn {
+    /// Load the functions, by their core names if `core` or by the extension's otherwise,
+    /// returning `None` if any of them is missing.
+    pub(crate) fn load(instance: &ash::Instance, device: &ash::Device, core: bool) -> Option<Self> {
+        let load = |core_name: &[u8], name: &[u8]| unsafe {
+            let name = if core { core_name } else { name };
+            instance.get_device_proc_addr(device.handle(), CStr::from_bytes_with_nul(name).unwrap().as_ptr())
+        };
+        unsafe {
+            Some(Self {
+                cmd_pipeline_barrier2: std::mem::transmute::<VoidFunction, CmdPipelineBarrier2>(load(
+                    b"vkCmdPipelineBarrier2\0",
+                    b"vkCmdPipelineBarrier2KHR\0",
+                )?),
+            })
+        }
+    }
+
+    /// Record `buffer_transitions` and `image_transitions` into `cmd` as one pipeline barrier,
+    /// each with its own stages.
+    pub(crate) unsafe fn cmd_pipeline_barrier(
+        &self,
+        cmd: vk::CommandBuffer,
+        buffer_transitions: &[BufferTransition],
+        image_transitions: &[ImageTransition],
+    ) {
+        let buffer_barriers = buffer_transitions
+            .iter()
+            .map(BufferMemoryBarrier2::from)
+            .collect::<Vec<_>>();
+        let image_barriers = image_transitions
+            .iter()
+            .map(ImageMemoryBarrier2::from)
+            .collect::<Vec<_>>();
+        let dependency_info = DependencyInfo {
+            s_type: structure_type(3),
+            p_next: std::ptr::null(),
+            dependency_flags: vk::DependencyFlags::empty(),
+            memory_barrier_count: 0,
+            p_memory_barriers: std::ptr::null(),
+            buffer_memory_barrier_count: buffer_barriers.len() as u32,
+            p_buffer_memory_barriers: buffer_barriers.as_ptr(),
+            image_memory_barrier_count: image_barriers.len() as u32,
+            p_image_memory_barriers: image_barriers.as_ptr(),
+        };
+        (self.cmd_pipeline_barrier2)(cmd, &dependency_info);
+    }
+}
+
+/// Get whether a physical device supports the `synchronization2` feature. The instance must be
+/// at least version 1.1.
+pub(crate) fn supports_synchronization2(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut synchronization2_features = PhysicalDeviceSynchronization2Features::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut synchronization2_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    synchronization2_features.synchronization2 == vk::TRUE
+}
+
+impl Device {
+    /// Get whether synchronization2 is enabled, through Vulkan 1.3 or `VK_KHR_synchronization2`,
+    /// i.e. whether tracked transitions recorded together keep their own stages rather than
+    /// waiting on the union of them.
+    pub fn has_synchronization2(&self) -> bool {
+        self.synchronization2.is_some()
+    }
+}
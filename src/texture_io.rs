@@ -0,0 +1,630 @@
+//! Loading textures straight from KTX2 and DDS files, enabled with the `texture_io` feature.
+//!
+//! Unlike `Device::create_image_array`, which only takes single 2D images, every mip level,
+//! array layer and cubemap face of a file is loaded, as well as 3D images. KTX2 files holding
+//! UASTC data from Basis Universal are transcoded into the first of BC7, ASTC 4x4, ETC2 and
+//! plain RGBA8 which the Device can sample, chosen with `Device::find_supported_format`.
+//!
+//! Supercompressed KTX2 files, whether Zstandard, ZLIB or BasisLZ (ETC1S), are not supported,
+//! and loading one returns `TextureIoError::Unsupported`. Such files must be re-encoded without
+//! supercompression, e.g. with `ktx create --encode uastc` and no `--zstd`.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use basis_universal::{DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat};
+
+use thiserror::Error;
+
+use std::borrow::Cow;
+use std::sync::{Arc, Once};
+
+use crate::*;
+use crate::format::{format_layer_size, format_to_aspect_mask};
+use crate::image_array::{
+    dds_format, read_u32, read_u64, DdsFormatError, DDS_MAGIC, KTX2_IDENTIFIER, REGION_ALIGNMENT,
+};
+
+/// An error that could occur while loading a texture file.
+#[derive(Error, Debug)]
+pub enum TextureIoError {
+    /// The data is neither a KTX2 nor a DDS file.
+    #[error("not a KTX2 or DDS file")]
+    UnknownContainer,
+    /// The file is truncated, or a level is smaller than its dimensions need.
+    #[error("texture file is truncated")]
+    Truncated,
+    /// The file uses features of its container which are not supported.
+    #[error("unsupported texture file: {0}")]
+    Unsupported(&'static str),
+    /// The Device can't sample images of the file's format with optimal tiling.
+    #[error("sampling {0:?} images is not supported")]
+    FormatNotSupported(vk::Format),
+    /// The Device can sample none of the formats Basis Universal data can be transcoded into.
+    #[error("no supported format to transcode Basis Universal data into")]
+    NoTranscodeFormat,
+    /// Transcoding Basis Universal data failed.
+    #[error("failed to transcode Basis Universal data")]
+    Transcode,
+    /// The image could not be created or uploaded.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+}
+
+/// A texture parsed out of a file, ready to be created with `Device::create_texture`.
+#[derive(Clone, Debug)]
+pub struct Texture {
+    /// The info to create the image with. Its usage includes `SAMPLED` and `TRANSFER_DST`, and
+    /// it is in `SHADER_READ_ONLY_OPTIMAL` once uploaded.
+    pub create_info: ImageCreateInfo,
+    /// The tightly packed data of each mip level, largest first, with each layer following the
+    /// last, and the faces of a cubemap as consecutive layers.
+    pub levels: Vec<Vec<u8>>,
+}
+
+impl Texture {
+    /// Parse a KTX2 or DDS file, telling them apart by their magic numbers.
+    pub fn parse(device: &Device, file: &[u8]) -> Result<Self, TextureIoError> {
+        if file.starts_with(&KTX2_IDENTIFIER) {
+            Self::parse_ktx2(device, file)
+        } else if file.starts_with(DDS_MAGIC) {
+            Self::parse_dds(device, file)
+        } else {
+            Err(TextureIoError::UnknownContainer)
+        }
+    }
+
+    /// Parse a KTX2 file without supercompression. If it holds UASTC data, it is transcoded
+    /// into a format `device` supports.
+    pub fn parse_ktx2(device: &Device, file: &[u8]) -> Result<Self, TextureIoError> {
+        let contents = read_ktx2(file)?;
+        if contents.format == vk::Format::UNDEFINED {
+            return transcode_uastc(device, file, contents.shape, &contents.levels);
+        }
+        Self::from_contents(device, contents)
+    }
+
+    /// Parse a DDS file, with or without a DX10 header.
+    pub fn parse_dds(device: &Device, file: &[u8]) -> Result<Self, TextureIoError> {
+        Self::from_contents(device, read_dds(file)?)
+    }
+
+    /// Get the data of mip level `level` of every layer, e.g. to create the image with
+    /// `Device::create_image` when only the first level is needed.
+    pub fn level(&self, level: usize) -> InitialImageData<'_> {
+        InitialImageData::new(&self.levels[level])
+    }
+
+    fn from_contents(device: &Device, contents: Contents<'_>) -> Result<Self, TextureIoError> {
+        device
+            .find_supported_format(
+                &[contents.format],
+                vk::ImageTiling::OPTIMAL,
+                vk::FormatFeatureFlags::SAMPLED_IMAGE,
+            )
+            .ok_or(TextureIoError::FormatNotSupported(contents.format))?;
+
+        let levels = contents.trimmed_levels()?;
+        Ok(Self {
+            create_info: contents.shape.create_info(contents.format, levels.len()),
+            levels,
+        })
+    }
+}
+
+/// The format, shape and level data of a texture file, read without a Device.
+struct Contents<'a> {
+    /// `UNDEFINED` for Basis Universal data.
+    format: vk::Format,
+    shape: Shape,
+    levels: Vec<Cow<'a, [u8]>>,
+}
+
+impl Contents<'_> {
+    /// Get the data of each level, checking that it holds at least as much data as its
+    /// dimensions need and leaving out any padding after it.
+    fn trimmed_levels(&self) -> Result<Vec<Vec<u8>>, TextureIoError> {
+        self.levels
+            .iter()
+            .enumerate()
+            .map(|(level, data)| {
+                let size = self.shape.level_size(self.format, level).ok_or(
+                    TextureIoError::Unsupported("the format has no known texel size"),
+                )? as usize
+                    * self.shape.layers;
+                data.get(..size).map(<[u8]>::to_vec).ok_or(TextureIoError::Truncated)
+            })
+            .collect()
+    }
+}
+
+/// Read the header and level index of a KTX2 file without supercompression.
+fn read_ktx2(file: &[u8]) -> Result<Contents<'_>, TextureIoError> {
+    if !file.starts_with(&KTX2_IDENTIFIER) {
+        return Err(TextureIoError::UnknownContainer);
+    }
+    let header = |index: usize| read_u32(file, 12 + index * 4).ok_or(TextureIoError::Truncated);
+    let format = vk::Format::from_raw(header(0)? as i32);
+    let (width, height, depth) = (header(2)?, header(3)?.max(1), header(4)?.max(1));
+    let (layers, faces, level_count, supercompression) =
+        (header(5)?.max(1), header(6)?, header(7)?.max(1), header(8)?);
+
+    if supercompression != 0 {
+        return Err(TextureIoError::Unsupported("supercompressed KTX2 files are not supported"));
+    }
+    if faces != 1 && faces != 6 {
+        return Err(TextureIoError::Unsupported("KTX2 files must have 1 or 6 faces"));
+    }
+    if depth > 1 && (layers > 1 || faces > 1) {
+        return Err(TextureIoError::Unsupported("3D array and cubemap images are not supported"));
+    }
+
+    // Within each level, images are ordered by layer and then face, as Vulkan orders the
+    // layers of a cubemap array.
+    const LEVEL_INDEX: usize = 80;
+    let levels = (0..level_count as usize)
+        .map(|level| {
+            let entry = LEVEL_INDEX + level * 24;
+            let offset = read_u64(file, entry)? as usize;
+            let length = read_u64(file, entry + 8)? as usize;
+            file.get(offset..offset.checked_add(length)?).map(Cow::Borrowed)
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(TextureIoError::Truncated)?;
+
+    let shape = Shape {
+        width,
+        height,
+        depth,
+        layers: (layers * faces) as usize,
+        cube: faces == 6,
+    };
+    Ok(Contents {
+        format,
+        shape,
+        levels,
+    })
+}
+
+/// Read the header and surfaces of a DDS file, with or without a DX10 header.
+fn read_dds(file: &[u8]) -> Result<Contents<'_>, TextureIoError> {
+    if !file.starts_with(DDS_MAGIC) {
+        return Err(TextureIoError::UnknownContainer);
+    }
+    let field = |offset: usize| read_u32(file, offset).ok_or(TextureIoError::Truncated);
+    let (format, data_offset) = dds_format(file).map_err(|e| match e {
+        DdsFormatError::Truncated => TextureIoError::Truncated,
+        DdsFormatError::Unsupported(reason) => TextureIoError::Unsupported(reason),
+    })?;
+
+    const DDSD_DEPTH: u32 = 0x80_0000;
+    const DDSCAPS2_CUBEMAP: u32 = 0x200;
+    const RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+    let (flags, height, width, level_count) = (field(8)?, field(12)?, field(16)?, field(28)?.max(1));
+    let depth = if flags & DDSD_DEPTH != 0 { field(24)?.max(1) } else { 1 };
+    let (layers, cube) = if data_offset == 148 {
+        let cube = field(136)? & RESOURCE_MISC_TEXTURECUBE != 0;
+        let faces = if cube { 6 } else { 1 };
+        (field(140)?.max(1) * faces, cube)
+    } else if field(112)? & DDSCAPS2_CUBEMAP != 0 {
+        (6, true)
+    } else {
+        (1, false)
+    };
+    if depth > 1 && layers > 1 {
+        return Err(TextureIoError::Unsupported("3D array images are not supported"));
+    }
+
+    let shape = Shape {
+        width,
+        height,
+        depth,
+        layers: layers as usize,
+        cube,
+    };
+
+    // Unlike KTX2, DDS files store every level of one layer before the next layer.
+    let mut offset = data_offset;
+    let mut levels = vec![Vec::new(); level_count as usize];
+    for _ in 0..layers {
+        for (level, data) in levels.iter_mut().enumerate() {
+            let size = shape.level_size(format, level).ok_or(TextureIoError::Unsupported(
+                "the format has no known texel size",
+            ))? as usize;
+            let surface = file
+                .get(offset..offset + size)
+                .ok_or(TextureIoError::Truncated)?;
+            data.extend_from_slice(surface);
+            offset += size;
+        }
+    }
+    Ok(Contents {
+        format,
+        shape,
+        levels: levels.into_iter().map(Cow::Owned).collect(),
+    })
+}
+
+/// The dimensions and layers of a texture, which all of its levels share.
+#[derive(Clone, Copy)]
+struct Shape {
+    width: u32,
+    height: u32,
+    depth: u32,
+    layers: usize,
+    cube: bool,
+}
+
+impl Shape {
+    /// The size of one layer of mip level `level`.
+    fn level_size(&self, format: vk::Format, level: usize) -> Option<u64> {
+        format_layer_size(
+            format,
+            (self.width >> level).max(1),
+            (self.height >> level).max(1),
+            (self.depth >> level).max(1),
+        )
+    }
+
+    fn create_info(&self, format: vk::Format, levels: usize) -> ImageCreateInfo {
+        ImageCreateInfo {
+            width: self.width as usize,
+            height: self.height as usize,
+            depth: self.depth as usize,
+            levels,
+            layers: self.layers,
+            format,
+            image_type: if self.depth > 1 {
+                vk::ImageType::TYPE_3D
+            } else {
+                vk::ImageType::TYPE_2D
+            },
+            usage: vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST,
+            create_flags: if self.cube {
+                vk::ImageCreateFlags::CUBE_COMPATIBLE
+            } else {
+                vk::ImageCreateFlags::empty()
+            },
+            initial_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ..Default::default()
+        }
+    }
+}
+
+/// The formats UASTC data may be transcoded into, in order of preference, as UNORM and SRGB
+/// formats and the block format to transcode into.
+const UASTC_TARGETS: [(vk::Format, vk::Format, TranscoderBlockFormat); 4] = [
+    (vk::Format::BC7_UNORM_BLOCK, vk::Format::BC7_SRGB_BLOCK, TranscoderBlockFormat::BC7),
+    (
+        vk::Format::ASTC_4X4_UNORM_BLOCK,
+        vk::Format::ASTC_4X4_SRGB_BLOCK,
+        TranscoderBlockFormat::ASTC_4x4,
+    ),
+    (
+        vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK,
+        vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+        TranscoderBlockFormat::ETC2_RGBA,
+    ),
+    (vk::Format::R8G8B8A8_UNORM, vk::Format::R8G8B8A8_SRGB, TranscoderBlockFormat::RGBA32),
+];
+
+/// Whether the Basis Universal data of a KTX2 file is sRGB encoded and has alpha.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct UastcDescriptor {
+    srgb: bool,
+    has_alpha: bool,
+}
+
+/// Read the data format descriptor of a KTX2 file with Basis Universal data, which must be
+/// UASTC.
+fn read_uastc_descriptor(file: &[u8], shape: Shape) -> Result<UastcDescriptor, TextureIoError> {
+    // The basic data format descriptor block follows its total size, with the color model and
+    // transfer function in its third word, and the channel of its first sample in the top
+    // byte of the seventh.
+    const KHR_DF_MODEL_UASTC: u8 = 166;
+    const KHR_DF_TRANSFER_SRGB: u8 = 2;
+    const KHR_DF_CHANNEL_UASTC_RGBA: u8 = 3;
+    const KHR_DF_CHANNEL_UASTC_RRRG: u8 = 5;
+    let dfd = read_u32(file, 48).ok_or(TextureIoError::Truncated)? as usize;
+    let descriptor = file.get(dfd..dfd + 32).ok_or(TextureIoError::Truncated)?;
+    if descriptor[12] != KHR_DF_MODEL_UASTC {
+        return Err(TextureIoError::Unsupported("only UASTC Basis Universal data is supported"));
+    }
+    if shape.depth > 1 {
+        return Err(TextureIoError::Unsupported("3D Basis Universal images are not supported"));
+    }
+    let channel = descriptor[31] & 0xf;
+    Ok(UastcDescriptor {
+        srgb: descriptor[14] == KHR_DF_TRANSFER_SRGB,
+        has_alpha: channel == KHR_DF_CHANNEL_UASTC_RGBA || channel == KHR_DF_CHANNEL_UASTC_RRRG,
+    })
+}
+
+/// Transcode the UASTC data of a KTX2 file into the first of `UASTC_TARGETS` `device` can
+/// sample.
+fn transcode_uastc(
+    device: &Device,
+    file: &[u8],
+    shape: Shape,
+    levels: &[Cow<'_, [u8]>],
+) -> Result<Texture, TextureIoError> {
+    let UastcDescriptor { srgb, has_alpha } = read_uastc_descriptor(file, shape)?;
+    let candidates = UASTC_TARGETS
+        .iter()
+        .map(|&(unorm, srgb_format, _)| if srgb { srgb_format } else { unorm })
+        .collect::<Vec<_>>();
+    let format = device
+        .find_supported_format(&candidates, vk::ImageTiling::OPTIMAL, vk::FormatFeatureFlags::SAMPLED_IMAGE)
+        .ok_or(TextureIoError::NoTranscodeFormat)?;
+    let block_format = UASTC_TARGETS[candidates.iter().position(|&candidate| candidate == format).unwrap()].2;
+
+    static INIT: Once = Once::new();
+    INIT.call_once(basis_universal::transcoder_init);
+    let transcoder = LowLevelUastcTranscoder::new();
+
+    const UASTC_BLOCK_SIZE: usize = 16;
+    let levels = levels
+        .iter()
+        .enumerate()
+        .map(|(level, data)| {
+            let (width, height) = ((shape.width >> level).max(1), (shape.height >> level).max(1));
+            let (blocks_x, blocks_y) = (width.div_ceil(4), height.div_ceil(4));
+            let image_size = blocks_x as usize * blocks_y as usize * UASTC_BLOCK_SIZE;
+            let mut transcoded = Vec::new();
+            for layer in 0..shape.layers {
+                let image = data
+                    .get(layer * image_size..(layer + 1) * image_size)
+                    .ok_or(TextureIoError::Truncated)?;
+                let slice = SliceParametersUastc {
+                    num_blocks_x: blocks_x,
+                    num_blocks_y: blocks_y,
+                    has_alpha,
+                    original_width: width,
+                    original_height: height,
+                };
+                let image = transcoder
+                    .transcode_slice(image, slice, DecodeFlags::empty(), block_format)
+                    .map_err(|_| TextureIoError::Transcode)?;
+                transcoded.extend_from_slice(&image);
+            }
+            Ok::<_, TextureIoError>(transcoded)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Texture::from_contents(
+        device,
+        Contents {
+            format,
+            shape,
+            levels: levels.into_iter().map(Cow::Owned).collect(),
+        },
+    )
+}
+
+impl Device {
+    /// Parse a KTX2 or DDS file with `Texture::parse` and create an image from it with
+    /// `create_texture`.
+    pub fn load_texture(
+        self: &Arc<Self>,
+        file: &[u8],
+        usage: vk::ImageUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, TextureIoError> {
+        let texture = Texture::parse(self, file)?;
+        self.create_texture(&texture, usage, tag)
+    }
+
+    /// Create an image from `texture`, created with `usage` as well as its own, and upload
+    /// every mip level of it.
+    ///
+    /// Like `create_image_array`, the levels are uploaded through a single staging allocation
+    /// in one submission on the async transfer queue, which the next graphics submission waits
+    /// on. The image is in `SHADER_READ_ONLY_OPTIMAL` afterwards.
+    pub fn create_texture(
+        self: &Arc<Self>,
+        texture: &Texture,
+        usage: vk::ImageUsageFlags,
+        tag: Option<Tag>,
+    ) -> Result<ImageHandle, TextureIoError> {
+        let mut data = Vec::new();
+        let mut level_offsets = Vec::with_capacity(texture.levels.len());
+        for level in &texture.levels {
+            data.resize(data.len().next_multiple_of(REGION_ALIGNMENT), 0);
+            level_offsets.push(data.len() as vk::DeviceSize);
+            data.extend_from_slice(level);
+        }
+
+        let create_info = ImageCreateInfo {
+            usage: texture.create_info.usage | usage,
+            ..texture.create_info
+        };
+        let handle = self.clone().create_image(create_info, None, tag.clone())?;
+        let image = self
+            .resources()
+            .get_image(handle)
+            .expect("create_texture: image was destroyed during creation")
+            .raw();
+
+        let aspect_mask = format_to_aspect_mask(create_info.format);
+        let upload = self.upload_via_staging(&data, tag, |cmd, src, src_offset| {
+            cmd.transition_image(
+                handle,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let regions = level_offsets
+                .iter()
+                .enumerate()
+                .map(|(level, &offset)| vk::BufferImageCopy {
+                    buffer_offset: src_offset + offset,
+                    buffer_row_length: 0,
+                    buffer_image_height: 0,
+                    image_subresource: vk::ImageSubresourceLayers {
+                        aspect_mask,
+                        mip_level: level as u32,
+                        base_array_layer: 0,
+                        layer_count: create_info.layers as u32,
+                    },
+                    image_offset: vk::Offset3D::default(),
+                    image_extent: vk::Extent3D {
+                        width: (create_info.width >> level).max(1) as u32,
+                        height: (create_info.height >> level).max(1) as u32,
+                        depth: (create_info.depth >> level).max(1) as u32,
+                    },
+                })
+                .collect::<Vec<_>>();
+            unsafe {
+                cmd.device().cmd_copy_buffer_to_image(
+                    cmd.raw(),
+                    src,
+                    image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &regions,
+                )
+            };
+
+            // As in `create_image`, the transfer queue can't wait on later stages.
+            cmd.transition_image(
+                handle,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                vk::PipelineStageFlags::empty(),
+                vk::AccessFlags::empty(),
+            );
+        });
+        if let Err(e) = upload {
+            self.destroy_image(handle);
+            return Err(e.into());
+        }
+
+        Ok(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A KTX2 file of `format`, 8x4 with two levels, whose level index and data format
+    /// descriptor point past the header. The descriptor is UASTC, sRGB and RGBA.
+    fn ktx2(format: vk::Format, supercompression: u32) -> Vec<u8> {
+        const DFD_OFFSET: u32 = 128;
+        const DFD_LENGTH: u32 = 44;
+        let level0 = u64::from(DFD_OFFSET + DFD_LENGTH);
+        let level1 = level0 + 32;
+
+        let mut file = KTX2_IDENTIFIER.to_vec();
+        for field in &[format.as_raw() as u32, 1, 8, 4, 0, 0, 1, 2, supercompression] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[DFD_OFFSET, DFD_LENGTH, 0, 0] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+        for field in &[0u64, 0, level0, 32, 32, level1, 16, 16] {
+            file.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let mut descriptor = [0; DFD_LENGTH as usize];
+        descriptor[..4].copy_from_slice(&DFD_LENGTH.to_le_bytes());
+        descriptor[12] = 166;
+        descriptor[14] = 2;
+        descriptor[31] = 3;
+        file.extend_from_slice(&descriptor);
+
+        file.extend(std::iter::repeat(0xaa).take(32));
+        file.extend(std::iter::repeat(0xbb).take(16));
+        file
+    }
+
+    /// A DDS file without a DX10 header holding a 4x4 RGBA8 image with two levels.
+    fn dds() -> Vec<u8> {
+        let mut file = vec![0; 128];
+        file[..4].copy_from_slice(DDS_MAGIC);
+        let mut field = |offset: usize, value: u32| {
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes())
+        };
+        field(4, 124);
+        field(12, 4);
+        field(16, 4);
+        field(28, 2);
+        field(76, 32);
+        field(80, 0x41);
+        field(88, 32);
+        field(92, 0xff);
+        field(96, 0xff00);
+        field(100, 0xff_0000);
+        field(104, 0xff00_0000);
+        file.extend(std::iter::repeat(0xaa).take(64));
+        file.extend(std::iter::repeat(0xbb).take(16));
+        file
+    }
+
+    #[test]
+    fn ktx2_uastc_levels_and_descriptor() {
+        let file = ktx2(vk::Format::UNDEFINED, 0);
+        let contents = read_ktx2(&file).unwrap();
+        assert_eq!(contents.format, vk::Format::UNDEFINED);
+        assert_eq!((contents.shape.width, contents.shape.height, contents.shape.depth), (8, 4, 1));
+        assert_eq!((contents.shape.layers, contents.shape.cube), (1, false));
+        assert_eq!(contents.levels.len(), 2);
+        assert_eq!(&*contents.levels[0], &[0xaa; 32][..]);
+        assert_eq!(&*contents.levels[1], &[0xbb; 16][..]);
+
+        let descriptor = read_uastc_descriptor(&file, contents.shape).unwrap();
+        assert_eq!(
+            descriptor,
+            UastcDescriptor {
+                srgb: true,
+                has_alpha: true,
+            }
+        );
+    }
+
+    #[test]
+    fn ktx2_truncated() {
+        let file = ktx2(vk::Format::UNDEFINED, 0);
+        // Within the header, within the level index and within the last level's data.
+        for &len in &[40, 100, file.len() - 1] {
+            assert!(matches!(read_ktx2(&file[..len]), Err(TextureIoError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn ktx2_supercompressed() {
+        // Zstandard.
+        let file = ktx2(vk::Format::UNDEFINED, 2);
+        assert!(matches!(read_ktx2(&file), Err(TextureIoError::Unsupported(_))));
+    }
+
+    #[test]
+    fn ktx2_levels_shorter_than_their_dimensions() {
+        // 8x4 RGBA8 needs 128 bytes in the first level, but the file only has 32.
+        let file = ktx2(vk::Format::R8G8B8A8_UNORM, 0);
+        let contents = read_ktx2(&file).unwrap();
+        assert!(matches!(contents.trimmed_levels(), Err(TextureIoError::Truncated)));
+    }
+
+    #[test]
+    fn dds_levels() {
+        let file = dds();
+        let contents = read_dds(&file).unwrap();
+        assert_eq!(contents.format, vk::Format::R8G8B8A8_UNORM);
+        assert_eq!((contents.shape.width, contents.shape.height, contents.shape.layers), (4, 4, 1));
+        let levels = contents.trimmed_levels().unwrap();
+        assert_eq!(levels, vec![vec![0xaa; 64], vec![0xbb; 16]]);
+    }
+
+    #[test]
+    fn dds_truncated() {
+        let file = dds();
+        for &len in &[20, 100, file.len() - 1] {
+            assert!(matches!(read_dds(&file[..len]), Err(TextureIoError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn unknown_container() {
+        assert!(matches!(read_ktx2(b"DDS "), Err(TextureIoError::UnknownContainer)));
+        assert!(matches!(read_dds(&KTX2_IDENTIFIER), Err(TextureIoError::UnknownContainer)));
+    }
+}
@@ -0,0 +1,125 @@
+use ash::version::InstanceV1_1;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::os::raw::c_void;
+
+use crate::*;
+
+/// A coarse capability tier of a Device, so that content can be scaled by tier rather than by
+/// checking individual features. Each tier has every capability of the tiers below it.
+///
+/// The tier is computed when the Device is built, from the optional capabilities it enables
+/// whenever they are supported, which `Device::tier_report` lists individually.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Tier {
+    /// The baseline every Device meets.
+    Tier0,
+    /// Bindless descriptor arrays, through `VK_EXT_descriptor_indexing`, and indirect draws
+    /// with a count read from a buffer, through `VK_KHR_draw_indirect_count`.
+    Tier1,
+    /// Ray tracing, which needs the `raytracing` feature, and mesh and task shaders, through
+    /// `VK_NV_mesh_shader`.
+    Tier2,
+}
+
+/// The optional capabilities of a Device which its `Tier` is computed from.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct TierReport {
+    /// Whether runtime sized, partially bound descriptor arrays of a variable count can be
+    /// indexed non-uniformly by sampled images.
+    pub bindless: bool,
+    /// Whether `vkCmdDrawIndirectCountKHR` and `vkCmdDrawIndexedIndirectCountKHR` are available.
+    pub draw_indirect_count: bool,
+    /// Whether the Device `supports_ray_tracing`.
+    pub ray_tracing: bool,
+    /// Whether mesh and task shaders are available.
+    pub mesh_shaders: bool,
+}
+
+impl TierReport {
+    /// Get the highest tier all of whose capabilities are available.
+    pub fn tier(&self) -> Tier {
+        if !(self.bindless && self.draw_indirect_count) {
+            Tier::Tier0
+        } else if !(self.ray_tracing && self.mesh_shaders) {
+            Tier::Tier1
+        } else {
+            Tier::Tier2
+        }
+    }
+}
+
+/// An error returned by optional subsystems when the Device lacks a capability they need,
+/// along with the tier which guarantees it.
+#[derive(Error, Debug, Clone, Copy, Eq, PartialEq)]
+#[error("{feature} needs {required:?}, but the device is {tier:?} and does not support it")]
+pub struct UnsupportedOnTier {
+    /// The capability which is not supported.
+    pub feature: &'static str,
+    /// The lowest tier which guarantees the capability.
+    pub required: Tier,
+    /// The tier of the Device.
+    pub tier: Tier,
+}
+
+impl Device {
+    /// Get the capability tier of the Device.
+    pub fn tier(&self) -> Tier {
+        self.tier_report.tier()
+    }
+
+    /// Get the capabilities the tier of the Device is computed from.
+    pub fn tier_report(&self) -> TierReport {
+        self.tier_report
+    }
+
+    /// Make the error an optional subsystem returns when `feature`, which `required`
+    /// guarantees, is not supported.
+    pub(crate) fn unsupported_on_tier(&self, feature: &'static str, required: Tier) -> UnsupportedOnTier {
+        UnsupportedOnTier {
+            feature,
+            required,
+            tier: self.tier(),
+        }
+    }
+}
+
+/// The descriptor indexing features enabled for bindless descriptor arrays.
+pub(crate) fn bindless_features() -> vk::PhysicalDeviceDescriptorIndexingFeaturesEXT {
+    vk::PhysicalDeviceDescriptorIndexingFeaturesEXT {
+        shader_sampled_image_array_non_uniform_indexing: vk::TRUE,
+        descriptor_binding_partially_bound: vk::TRUE,
+        descriptor_binding_variable_descriptor_count: vk::TRUE,
+        runtime_descriptor_array: vk::TRUE,
+        ..Default::default()
+    }
+}
+
+/// Get whether a physical device supports every feature in `bindless_features`. The instance
+/// must be at least version 1.1.
+pub(crate) fn supports_bindless(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    let mut indexing = vk::PhysicalDeviceDescriptorIndexingFeaturesEXT::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut indexing as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    indexing.shader_sampled_image_array_non_uniform_indexing == vk::TRUE
+        && indexing.descriptor_binding_partially_bound == vk::TRUE
+        && indexing.descriptor_binding_variable_descriptor_count == vk::TRUE
+        && indexing.runtime_descriptor_array == vk::TRUE
+}
+
+/// Get whether a physical device supports mesh and task shaders. The instance must be at least
+/// version 1.1.
+pub(crate) fn supports_mesh_shaders(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> bool {
+    let mut mesh = vk::PhysicalDeviceMeshShaderFeaturesNV::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut mesh as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    mesh.task_shader == vk::TRUE && mesh.mesh_shader == vk::TRUE
+}
@@ -0,0 +1,293 @@
+use ash::version::{DeviceV1_0, InstanceV1_0, InstanceV1_1};
+use ash::vk;
+
+use derivative::Derivative;
+
+use thiserror::Error;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_timeline_semaphore` and Vulkan 1.2, which promoted
+// it, so the parts of it hot uses are declared here.
+
+fn structure_type(offset: i32) -> vk::StructureType {
+    vk::StructureType::from_raw(1_000_207_000 + offset)
+}
+
+const SEMAPHORE_TYPE_TIMELINE: i32 = 1;
+
+/// The name of the `VK_KHR_timeline_semaphore` extension.
+pub(crate) fn timeline_semaphore_extension_name() -> &'static CStr {
+    CStr::from_bytes_with_nul(b"VK_KHR_timeline_semaphore\0").unwrap()
+}
+
+#[repr(C)]
+pub(crate) struct PhysicalDeviceTimelineSemaphoreFeatures {
+    pub(crate) s_type: vk::StructureType,
+    pub(crate) p_next: *mut c_void,
+    pub(crate) timeline_semaphore: vk::Bool32,
+}
+
+impl Default for PhysicalDeviceTimelineSemaphoreFeatures {
+    fn default() -> Self {
+        Self {
+            s_type: structure_type(0),
+            p_next: std::ptr::null_mut(),
+            timeline_semaphore: vk::FALSE,
+        }
+    }
+}
+
+#[repr(C)]
+struct SemaphoreTypeCreateInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    semaphore_type: i32,
+    initial_value: u64,
+}
+
+#[repr(C)]
+pub(crate) struct TimelineSemaphoreSubmitInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    wait_semaphore_value_count: u32,
+    p_wait_semaphore_values: *const u64,
+    signal_semaphore_value_count: u32,
+    p_signal_semaphore_values: *const u64,
+}
+
+impl TimelineSemaphoreSubmitInfo {
+    /// Describe the values to wait on and signal for a submission's semaphores, which must
+    /// outlive the returned struct. Binary semaphores' values are ignored.
+    pub(crate) fn new(wait_values: &[u64], signal_values: &[u64]) -> Self {
+        Self {
+            s_type: structure_type(3),
+            p_next: std::ptr::null(),
+            wait_semaphore_value_count: wait_values.len() as u32,
+            p_wait_semaphore_values: wait_values.as_ptr(),
+            signal_semaphore_value_count: signal_values.len() as u32,
+            p_signal_semaphore_values: signal_values.as_ptr(),
+        }
+    }
+}
+
+#[repr(C)]
+struct SemaphoreWaitInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    flags: vk::Flags,
+    semaphore_count: u32,
+    p_semaphores: *const vk::Semaphore,
+    p_values: *const u64,
+}
+
+#[repr(C)]
+struct SemaphoreSignalInfo {
+    s_type: vk::StructureType,
+    p_next: *const c_void,
+    semaphore: vk::Semaphore,
+    value: u64,
+}
+
+type VoidFunction = unsafe extern "system" fn() -> c_void;
+type GetSemaphoreCounterValue = unsafe extern "system" fn(vk::Device, vk::Semaphore, *mut u64) -> vk::Result;
+type WaitSemaphores = unsafe extern "system" fn(vk::Device, *const SemaphoreWaitInfo, u64) -> vk::Result;
+type SignalSemaphore = unsafe extern "system" fn(vk::Device, *const SemaphoreSignalInfo) -> vk::Result;
+
+/// The device functions of `VK_KHR_timeline_semaphore`, or of Vulkan 1.2 core.
+#[derive(Clone)]
+pub(crate) struct TimelineSemaphoreFn {
+    get_semaphore_counter_value: GetSemaphoreCounterValue,
+    wait_semaphores: WaitSemaphores,
+    signal_semaphore: SignalSemaphore,
+}
+
+impl TimelineSemaphoreFn {
+    /// Load the functions, by their core names if `core` or by the extension's otherwise,
+    /// returning `None` if any of them is missing.
+    pub(crate) fn load(instance: &ash::Instance, device: &ash::Device, core: bool) -> Option<Self> {
+        let load = |core_name: &[u8], name: &[u8]| unsafe {
+            let name = if core { core_name } else { name };
+            instance.get_device_proc_addr(device.handle(), CStr::from_bytes_with_nul(name).unwrap().as_ptr())
+        };
+        unsafe {
+            Some(Self {
+                get_semaphore_counter_value: std::mem::transmute::<VoidFunction, GetSemaphoreCounterValue>(
+                    load(b"vkGetSemaphoreCounterValue\0", b"vkGetSemaphoreCounterValueKHR\0")?,
+                ),
+                wait_semaphores: std::mem::transmute::<VoidFunction, WaitSemaphores>(load(
+                    b"vkWaitSemaphores\0",
+                    b"vkWaitSemaphoresKHR\0",
+                )?),
+                signal_semaphore: std::mem::transmute::<VoidFunction, SignalSemaphore>(load(
+                    b"vkSignalSemaphore\0",
+                    b"vkSignalSemaphoreKHR\0",
+                )?),
+            })
+        }
+    }
+}
+
+/// Get whether a physical device supports the `timelineSemaphore` feature. The instance must
+/// be at least version 1.1.
+pub(crate) fn supports_timeline_semaphores(
+    instance: &ash::Instance,
+    physical_device: vk::PhysicalDevice,
+) -> bool {
+    let mut timeline_features = PhysicalDeviceTimelineSemaphoreFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut timeline_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.fp_v1_1().get_physical_device_features2(physical_device, &mut features) };
+    timeline_features.timeline_semaphore == vk::TRUE
+}
+
+/// An error that could occur while creating a `Timeline`.
+#[derive(Error, Debug)]
+pub enum TimelineError {
+    /// The device supports neither Vulkan 1.2 nor `VK_KHR_timeline_semaphore`.
+    #[error("timeline semaphores are not supported by this device")]
+    Unsupported,
+    /// A Vulkan call failed.
+    #[error("vulkan error: {0}")]
+    Vulkan(#[from] vk::Result),
+}
+
+/// A timeline semaphore: a semaphore with a monotonically increasing 64-bit value, which
+/// submissions (see `SubmitBuilder::wait_timeline` and `SubmitBuilder::signal_timeline`) and the
+/// host can wait on reaching a value and signal.
+///
+/// Unlike binary semaphores, one timeline can order any number of submissions across queues and
+/// be waited on any number of times, so it needs no per-dependency semaphore.
+///
+/// Will be destroyed once the current frame has completed after it is dropped.
+#[derive(Derivative)]
+#[derivative(Debug)]
+pub struct Timeline {
+    semaphore: vk::Semaphore,
+    /// The highest value handed out by `signal_value`.
+    last_value: AtomicU64,
+    #[derivative(Debug = "ignore")]
+    device: Arc<Device>,
+}
+
+impl Timeline {
+    /// The raw `vk::Semaphore`.
+    pub fn raw(&self) -> vk::Semaphore {
+        self.semaphore
+    }
+
+    /// Reserve the next value for a submission (or the host) to signal, one higher than the last
+    /// value reserved.
+    pub fn signal_value(&self) -> u64 {
+        self.last_value.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Get the last value reserved by `signal_value`, waiting on which waits for all work that
+    /// has been given a value so far.
+    pub fn wait_value(&self) -> u64 {
+        self.last_value.load(Ordering::Acquire)
+    }
+
+    /// Get the current value of the timeline's counter.
+    pub fn value(&self) -> Result<u64, vk::Result> {
+        let mut value = 0;
+        let result = unsafe {
+            (self.fns().get_semaphore_counter_value)(self.device.device.handle(), self.semaphore, &mut value)
+        };
+        match result {
+            vk::Result::SUCCESS => Ok(value),
+            e => Err(e),
+        }
+    }
+
+    /// Wait up to `timeout` nanoseconds on the host for the timeline to reach `value`,
+    /// returning whether it did.
+    pub fn wait(&self, value: u64, timeout: u64) -> Result<bool, vk::Result> {
+        let info = SemaphoreWaitInfo {
+            s_type: structure_type(4),
+            p_next: std::ptr::null(),
+            flags: 0,
+            semaphore_count: 1,
+            p_semaphores: &self.semaphore,
+            p_values: &value,
+        };
+        match unsafe { (self.fns().wait_semaphores)(self.device.device.handle(), &info, timeout) } {
+            vk::Result::SUCCESS => Ok(true),
+            vk::Result::TIMEOUT => Ok(false),
+            e => Err(e),
+        }
+    }
+
+    /// Signal `value` from the host. It must be greater than the timeline's current value and
+    /// any value a pending submission will signal.
+    pub fn signal(&self, value: u64) -> Result<(), vk::Result> {
+        let info = SemaphoreSignalInfo {
+            s_type: structure_type(5),
+            p_next: std::ptr::null(),
+            semaphore: self.semaphore,
+            value,
+        };
+        self.last_value.fetch_max(value, Ordering::AcqRel);
+        match unsafe { (self.fns().signal_semaphore)(self.device.device.handle(), &info) } {
+            vk::Result::SUCCESS => Ok(()),
+            e => Err(e),
+        }
+    }
+
+    fn fns(&self) -> &TimelineSemaphoreFn {
+        self.device
+            .timeline_semaphore
+            .as_ref()
+            .expect("Timeline exists without timeline semaphore support")
+    }
+}
+
+impl Drop for Timeline {
+    fn drop(&mut self) {
+        self.device
+            .current_frame()
+            .write()
+            .destroyed_semaphores
+            .push(self.semaphore);
+    }
+}
+
+impl Device {
+    /// Get whether timeline semaphores are supported and enabled, through Vulkan 1.2 or
+    /// `VK_KHR_timeline_semaphore`, i.e. whether `create_timeline` will succeed.
+    pub fn supports_timelines(&self) -> bool {
+        self.timeline_semaphore.is_some()
+    }
+
+    /// Create a `Timeline` with a counter starting at `initial_value`.
+    pub fn create_timeline(self: &Arc<Self>, initial_value: u64) -> Result<Timeline, TimelineError> {
+        if self.timeline_semaphore.is_none() {
+            return Err(TimelineError::Unsupported);
+        }
+
+        let type_info = SemaphoreTypeCreateInfo {
+            s_type: structure_type(2),
+            p_next: std::ptr::null(),
+            semaphore_type: SEMAPHORE_TYPE_TIMELINE,
+            initial_value,
+        };
+        let info = vk::SemaphoreCreateInfo {
+            p_next: &type_info as *const _ as *const c_void,
+            ..Default::default()
+        };
+        let semaphore = unsafe { self.device.create_semaphore(&info, None)? };
+
+        Ok(Timeline {
+            semaphore,
+            last_value: AtomicU64::new(initial_value),
+            device: self.clone(),
+        })
+    }
+}
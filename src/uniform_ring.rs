@@ -0,0 +1,164 @@
+use ash::vk;
+
+use bytemuck::Pod;
+
+use thiserror::Error;
+
+use std::sync::Arc;
+
+use crate::*;
+
+/// An error that could occur while pushing uniforms to a `UniformRing`.
+#[derive(Error, Debug)]
+pub enum UniformRingError {
+    /// The uniforms are larger than the range the ring's descriptors are written with.
+    #[error("uniforms of {size} bytes do not fit the ring's range of {range} bytes")]
+    TooLarge {
+        /// The size of the uniforms.
+        size: usize,
+        /// The ring's range.
+        range: usize,
+    },
+    /// A uniform block could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// The uniforms did not fit in the uniform block.
+    #[error("block allocation error: {0}")]
+    BlockAllocation(#[from] BlockAllocationError),
+}
+
+/// Suballocates per-draw uniforms from the current frame's uniform blocks, to be bound through
+/// a `UNIFORM_BUFFER_DYNAMIC` descriptor with a dynamic offset instead of a descriptor set each.
+///
+/// Each `push` returns the range the uniforms were written to along with its dynamic offset,
+/// which is aligned to `minUniformBufferOffsetAlignment`. The descriptor must be written with
+/// `descriptor_info` for the range's buffer, which only changes when the ring moves to a new
+/// block, i.e. on the first push of a frame and when a block fills up.
+///
+/// The blocks are requested from the Device's uniform block pool, so they are uploaded if need
+/// be and recycled once their frame has completed, and the ring starts over with a new block
+/// every frame.
+pub struct UniformRing {
+    device: Arc<Device>,
+    /// The size of the blocks requested from the pool.
+    block_size: usize,
+    /// The range descriptors are written with, i.e. the largest uniforms which may be pushed.
+    range: usize,
+    /// The block being allocated from, and the frame slot it was requested for.
+    current: Option<(BufferBlockHandle, usize)>,
+    tag: Option<Tag>,
+}
+
+impl UniformRing {
+    /// Create a ring which pushes uniforms of at most `range` bytes into blocks of at least
+    /// `block_size` bytes.
+    pub fn new(device: &Arc<Device>, range: usize, block_size: usize, tag: Option<Tag>) -> Self {
+        assert!(range > 0, "UniformRing range must not be zero");
+        Self {
+            device: device.clone(),
+            block_size: block_size.max(range),
+            range,
+            current: None,
+            tag,
+        }
+    }
+
+    /// The range the ring's descriptors must be written with.
+    pub fn range(&self) -> usize {
+        self.range
+    }
+
+    /// Get the info to write a `UNIFORM_BUFFER_DYNAMIC` descriptor for the buffer of a range
+    /// returned by `push` with.
+    pub fn descriptor_info(&self, buffer: &TransientBufferHandle) -> vk::DescriptorBufferInfo {
+        vk::DescriptorBufferInfo {
+            buffer: buffer.raw(),
+            offset: 0,
+            range: self.range as vk::DeviceSize,
+        }
+    }
+
+    /// Write `data` to the ring, returning the range it was written to and the dynamic offset
+    /// to bind it with. The range is only valid during `frame`.
+    pub fn push<T: Pod>(
+        &mut self,
+        frame: &Frame,
+        data: &T,
+    ) -> Result<(TransientBufferHandle, u32), UniformRingError> {
+        self.push_bytes(frame, bytemuck::bytes_of(data))
+    }
+
+    /// Like `push`, but writes raw bytes.
+    pub fn push_bytes(
+        &mut self,
+        frame: &Frame,
+        data: &[u8],
+    ) -> Result<(TransientBufferHandle, u32), UniformRingError> {
+        self.device.check_frame(frame);
+        if data.len() > self.range {
+            return Err(UniformRingError::TooLarge {
+                size: data.len(),
+                range: self.range,
+            });
+        }
+
+        if let Some(pushed) = self.try_push(frame, data)? {
+            return Ok(pushed);
+        }
+
+        let block = self
+            .device
+            .request_internal_block(PoolKind::Uniform, self.block_size, self.tag.clone())?;
+        self.current = Some((block, frame.index()));
+
+        // A fresh block is at least `range` bytes, so the uniforms always fit.
+        Ok(self
+            .try_push(frame, data)?
+            .expect("UniformRing: uniforms did not fit in a new block"))
+    }
+
+    /// Write `data` to the current block, returning `None` if the ring needs a new block first,
+    /// because the current one belongs to an earlier frame or is full.
+    fn try_push(
+        &self,
+        frame: &Frame,
+        data: &[u8],
+    ) -> Result<Option<(TransientBufferHandle, u32)>, UniformRingError> {
+        let block = match self.current {
+            Some((block, index)) if index == frame.index() => block,
+            _ => return Ok(None),
+        };
+
+        let blocks = self.device.buffer_blocks();
+        // A block of an earlier use of the same frame slot has been recycled.
+        let block = match blocks.get_uniform_block(block) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+
+        // Every dynamic offset must leave room for the whole range after it.
+        let buffer = match block.allocate_buffer(data.len()) {
+            Ok(buffer) if buffer.offset() as usize + self.range <= block.size => buffer,
+            Ok(_) | Err(BlockAllocationError::OutOfSpace { .. }) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let ptr = block
+            .mapped_data(&buffer)
+            .expect("UniformRing: uniform block is not mapped")
+            .as_ptr();
+        unsafe { ptr.copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+
+        Ok(Some((buffer, buffer.offset() as u32)))
+    }
+}
+
+impl std::fmt::Debug for UniformRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UniformRing")
+            .field("range", &self.range)
+            .field("block_size", &self.block_size)
+            .field("current", &self.current)
+            .finish()
+    }
+}
@@ -0,0 +1,420 @@
+//! Uploads which are spread over several frames by a per-frame byte budget, so that e.g. a
+//! level load streaming in while the game renders doesn't flood the transfer queue and hitch.
+//!
+//! Uploads queued with `Device::queue_buffer_upload` and `queue_image_upload` are staged and
+//! recorded by `Device::begin_frame`, highest `UploadPriority` first and in the order they were
+//! queued within a priority, until the bytes uploaded that frame reach the budget set with
+//! `Device::set_upload_budget`. The rest wait for later frames. As with `Device::create_buffer`,
+//! an upload is complete before any graphics submission made after it leaves the queue.
+
+use ash::version::DeviceV1_0;
+use ash::vk;
+
+use thiserror::Error;
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use crate::*;
+use crate::command_buffer::subresource_layers_range;
+use crate::format::{format_layer_size, format_to_aspect_mask};
+
+/// An error that could occur while queueing an upload.
+#[derive(Error, Debug)]
+pub enum UploadError {
+    /// The destination buffer does not exist.
+    #[error("the destination buffer does not exist")]
+    InvalidBuffer,
+    /// The destination image does not exist.
+    #[error("the destination image does not exist")]
+    InvalidImage,
+    /// The range or region to upload to is not within the destination, or the data is smaller
+    /// than the region.
+    #[error("the upload is out of bounds")]
+    OutOfBounds,
+    /// The image's format has no known texel size, or is a combined depth stencil format.
+    #[error("images of format {0:?} cannot be uploaded to")]
+    UnsupportedFormat(vk::Format),
+}
+
+/// The priority of a queued upload. Queued uploads of a higher priority are always uploaded
+/// before those of a lower one.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum UploadPriority {
+    /// Data which isn't needed soon, such as distant level geometry.
+    Low,
+    /// The default priority.
+    #[default]
+    Normal,
+    /// Data which is needed as soon as possible, such as what is about to come into view.
+    High,
+}
+
+/// Identifies a queued upload, to check whether it has left the queue with
+/// `Device::is_upload_queued`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct UploadTicket {
+    id: u64,
+}
+
+/// Where a queued upload is copied to.
+enum UploadDestination {
+    Buffer {
+        buffer: BufferHandle,
+        offset: vk::DeviceSize,
+    },
+    Image {
+        image: ImageHandle,
+        copy: vk::BufferImageCopy,
+        final_layout: vk::ImageLayout,
+    },
+}
+
+/// An upload waiting in an `UploadQueue`.
+struct QueuedUpload {
+    id: u64,
+    data: Vec<u8>,
+    dst: UploadDestination,
+}
+
+/// The uploads waiting to be recorded by `begin_frame`, and the budget they are recorded in.
+#[derive(Default)]
+pub(crate) struct UploadQueue {
+    /// The queued uploads of each priority, by `UploadPriority as usize`.
+    queues: [VecDeque<QueuedUpload>; 3],
+    queued_bytes: vk::DeviceSize,
+    next_id: u64,
+    budget: Option<vk::DeviceSize>,
+}
+
+impl UploadQueue {
+    fn push(&mut self, priority: UploadPriority, data: Vec<u8>, dst: UploadDestination) -> UploadTicket {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.queued_bytes += data.len() as vk::DeviceSize;
+        self.queues[priority as usize].push_back(QueuedUpload { id, data, dst });
+        UploadTicket { id }
+    }
+
+    /// Take the uploads to record this frame. The first is always taken, even if it is larger
+    /// than the budget, so that large uploads still make progress.
+    fn take_frame_uploads(&mut self) -> Vec<QueuedUpload> {
+        let mut uploads = Vec::new();
+        let mut bytes = 0;
+        for queue in self.queues.iter_mut().rev() {
+            while let Some(upload) = queue.front() {
+                let size = upload.data.len() as vk::DeviceSize;
+                match self.budget {
+                    Some(budget) if !uploads.is_empty() && bytes + size > budget => return uploads,
+                    _ => {}
+                }
+                bytes += size;
+                self.queued_bytes -= size;
+                uploads.extend(queue.pop_front());
+            }
+        }
+        uploads
+    }
+}
+
+impl Device {
+    /// Queue `data` to be uploaded to `buffer` at `offset` by a later `begin_frame`, within the
+    /// upload budget.
+    pub fn queue_buffer_upload(
+        &self,
+        buffer: BufferHandle,
+        offset: vk::DeviceSize,
+        data: Vec<u8>,
+        priority: UploadPriority,
+    ) -> Result<UploadTicket, UploadError> {
+        let buffer_size = match self.resources().get_buffer(buffer) {
+            Some(buffer) => buffer.create_info().size,
+            None => return Err(UploadError::InvalidBuffer),
+        };
+        if offset
+            .checked_add(data.len() as vk::DeviceSize)
+            .map_or(true, |end| end > buffer_size)
+        {
+            return Err(UploadError::OutOfBounds);
+        }
+
+        let dst = UploadDestination::Buffer { buffer, offset };
+        Ok(self.uploads.lock().push(priority, data, dst))
+    }
+
+    /// Queue `data`, the tightly packed texels of `region`, to be uploaded to `image` by a
+    /// later `begin_frame`, within the upload budget.
+    ///
+    /// The region is transitioned to `TRANSFER_DST_OPTIMAL` for the copy and then to
+    /// `final_layout`, unless it is `UNDEFINED`. Depth stencil images can't be uploaded to,
+    /// since each aspect would need a separate copy.
+    pub fn queue_image_upload(
+        &self,
+        image: ImageHandle,
+        region: ImageRegion,
+        data: Vec<u8>,
+        final_layout: vk::ImageLayout,
+        priority: UploadPriority,
+    ) -> Result<UploadTicket, UploadError> {
+        let create_info = match self.resources().get_image(image) {
+            Some(image) => image.create_info(),
+            None => return Err(UploadError::InvalidImage),
+        };
+
+        let format = create_info.format;
+        let aspect_mask = format_to_aspect_mask(format);
+        if aspect_mask == vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL {
+            return Err(UploadError::UnsupportedFormat(format));
+        }
+        let layer_size = format_layer_size(
+            format,
+            region.extent.width,
+            region.extent.height,
+            region.extent.depth,
+        )
+        .ok_or(UploadError::UnsupportedFormat(format))?;
+
+        let level = ImageRegion::mip_level(&create_info, region.mip_level);
+        let fits = |offset: i32, size: u32, level_size: u32| {
+            offset >= 0 && offset as u64 + size as u64 <= level_size as u64
+        };
+        if region.mip_level as usize >= create_info.levels
+            || region.base_array_layer as u64 + region.layer_count as u64 > create_info.layers as u64
+            || !fits(region.offset.x, region.extent.width, level.extent.width)
+            || !fits(region.offset.y, region.extent.height, level.extent.height)
+            || !fits(region.offset.z, region.extent.depth, level.extent.depth)
+            || (data.len() as u64) < layer_size * region.layer_count as u64
+        {
+            return Err(UploadError::OutOfBounds);
+        }
+
+        let copy = vk::BufferImageCopy {
+            buffer_offset: 0,
+            buffer_row_length: 0,
+            buffer_image_height: 0,
+            image_subresource: vk::ImageSubresourceLayers {
+                aspect_mask,
+                mip_level: region.mip_level,
+                base_array_layer: region.base_array_layer,
+                layer_count: region.layer_count,
+            },
+            image_offset: region.offset,
+            image_extent: region.extent,
+        };
+        let dst = UploadDestination::Image {
+            image,
+            copy,
+            final_layout,
+        };
+        Ok(self.uploads.lock().push(priority, data, dst))
+    }
+
+    /// Whether the upload of `ticket` is still queued. Once it isn't, graphics submissions see
+    /// the uploaded data.
+    pub fn is_upload_queued(&self, ticket: UploadTicket) -> bool {
+        self.uploads
+            .lock()
+            .queues
+            .iter()
+            .any(|queue| queue.iter().any(|upload| upload.id == ticket.id))
+    }
+
+    /// Get the total size in bytes of the uploads which are still queued.
+    pub fn queued_upload_bytes(&self) -> vk::DeviceSize {
+        self.uploads.lock().queued_bytes
+    }
+
+    /// Set the greatest number of bytes of queued uploads which each `begin_frame` records, or
+    /// `None` to record every queued upload at once. At least one queued upload is recorded per
+    /// frame even if it is larger than the budget.
+    ///
+    /// Defaults to `None`. Raising the budget during a loading screen, when hitches don't
+    /// matter, makes the queue drain faster.
+    pub fn set_upload_budget(&self, budget: Option<vk::DeviceSize>) {
+        self.uploads.lock().budget = budget;
+    }
+
+    /// Get the upload budget set with `set_upload_budget`.
+    pub fn upload_budget(&self) -> Option<vk::DeviceSize> {
+        self.uploads.lock().budget
+    }
+
+    /// Stage the queued uploads within this frame's budget and record them into a single
+    /// command buffer on the async transfer queue.
+    pub(crate) fn record_queued_uploads(self: &Arc<Self>) -> Result<(), vk::Result> {
+        let uploads = self.uploads.lock().take_frame_uploads();
+        if uploads.is_empty() {
+            return Ok(());
+        }
+
+        let mut staged = Vec::with_capacity(uploads.len());
+        for upload in uploads {
+            let exists = match upload.dst {
+                UploadDestination::Buffer { buffer, .. } => self.resources().get_buffer(buffer).is_some(),
+                UploadDestination::Image { image, .. } => self.resources().get_image(image).is_some(),
+            };
+            if !exists {
+                log::warn!("hot: dropping a queued upload whose destination was destroyed");
+                continue;
+            }
+
+            match self.write_staging(&upload.data, Some(Tag::Static("queued upload staging"))) {
+                Ok((src, src_offset)) => staged.push((src, src_offset, upload)),
+                Err(e) => log::error!("hot: dropping a queued upload which failed to stage: {}", e),
+            }
+        }
+        if staged.is_empty() {
+            return Ok(());
+        }
+
+        self.submit_upload(|cmd| {
+            for (src, src_offset, upload) in &staged {
+                record_upload(cmd, *src, *src_offset, upload);
+            }
+        })
+    }
+}
+
+/// Record the copy of `upload` out of the staging buffer `src`.
+fn record_upload(
+    cmd: &mut CommandBuffer,
+    src: vk::Buffer,
+    src_offset: vk::DeviceSize,
+    upload: &QueuedUpload,
+) {
+    match upload.dst {
+        UploadDestination::Buffer { buffer, offset } => {
+            cmd.transition_buffer(
+                buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let dst = cmd
+                .device()
+                .resources()
+                .get_buffer(buffer)
+                .expect("record_queued_uploads: buffer was destroyed during upload")
+                .raw();
+            let region = vk::BufferCopy {
+                src_offset,
+                dst_offset: offset,
+                size: upload.data.len() as vk::DeviceSize,
+            };
+            unsafe { cmd.device().cmd_copy_buffer(cmd.raw(), src, dst, &[region]) };
+        }
+        UploadDestination::Image {
+            image,
+            copy,
+            final_layout,
+        } => {
+            let range = subresource_layers_range(copy.image_subresource);
+            cmd.transition_image_subresources(
+                image,
+                range,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            );
+            let (dst, dst_layout) = {
+                let resources = cmd.device().resources();
+                let image = resources
+                    .get_image(image)
+                    .expect("record_queued_uploads: image was destroyed during upload");
+                (image.raw(), image.layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL))
+            };
+            let copy = vk::BufferImageCopy {
+                buffer_offset: src_offset,
+                ..copy
+            };
+            unsafe {
+                cmd.device()
+                    .cmd_copy_buffer_to_image(cmd.raw(), src, dst, dst_layout, &[copy])
+            };
+
+            // As in `create_image`, the transfer queue can't wait on later stages, so the
+            // transition only needs to complete before the upload's semaphore is signaled.
+            if final_layout != vk::ImageLayout::UNDEFINED {
+                cmd.transition_image_subresources(
+                    image,
+                    range,
+                    final_layout,
+                    vk::PipelineStageFlags::empty(),
+                    vk::AccessFlags::empty(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use generational_arena as ga;
+
+    fn queue(budget: Option<vk::DeviceSize>) -> UploadQueue {
+        UploadQueue {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    fn push(queue: &mut UploadQueue, priority: UploadPriority, size: usize) -> u64 {
+        let dst = UploadDestination::Buffer {
+            buffer: BufferHandle::new(ga::Index::from_raw_parts(0, 0)),
+            offset: 0,
+        };
+        queue.push(priority, vec![0; size], dst).id
+    }
+
+    fn take(queue: &mut UploadQueue) -> Vec<u64> {
+        queue.take_frame_uploads().iter().map(|upload| upload.id).collect()
+    }
+
+    #[test]
+    fn priority_order() {
+        let mut queue = queue(None);
+        let low = push(&mut queue, UploadPriority::Low, 1);
+        let normal = push(&mut queue, UploadPriority::Normal, 1);
+        let high = push(&mut queue, UploadPriority::High, 1);
+        let normal_later = push(&mut queue, UploadPriority::Normal, 1);
+
+        assert_eq!(take(&mut queue), vec![high, normal, normal_later, low]);
+        assert_eq!(queue.queued_bytes, 0);
+        assert!(take(&mut queue).is_empty());
+    }
+
+    #[test]
+    fn first_upload_taken_over_budget() {
+        let mut queue = queue(Some(10));
+        let large = push(&mut queue, UploadPriority::Normal, 100);
+        let small = push(&mut queue, UploadPriority::Normal, 1);
+
+        assert_eq!(take(&mut queue), vec![large]);
+        assert_eq!(queue.queued_bytes, 1);
+        assert_eq!(take(&mut queue), vec![small]);
+    }
+
+    #[test]
+    fn over_budget_upload_stops_frame() {
+        let mut queue = queue(Some(10));
+        let first = push(&mut queue, UploadPriority::High, 6);
+        let second = push(&mut queue, UploadPriority::Normal, 6);
+        // Fits in what is left of the budget, but must not overtake the upload before it.
+        let third = push(&mut queue, UploadPriority::Low, 1);
+
+        assert_eq!(take(&mut queue), vec![first]);
+        assert_eq!(queue.queued_bytes, 7);
+        assert_eq!(take(&mut queue), vec![second, third]);
+        assert_eq!(queue.queued_bytes, 0);
+    }
+
+    #[test]
+    fn uploads_filling_budget_exactly() {
+        let mut queue = queue(Some(10));
+        let first = push(&mut queue, UploadPriority::Normal, 5);
+        let second = push(&mut queue, UploadPriority::Normal, 5);
+
+        assert_eq!(take(&mut queue), vec![first, second]);
+    }
+}
@@ -0,0 +1,209 @@
+use ash::vk;
+
+use bytemuck::Pod;
+
+use thiserror::Error;
+
+use crate::*;
+
+/// An error that could occur while writing per-frame instance data.
+#[derive(Error, Debug)]
+pub enum InstanceDataError {
+    /// The vertex block could not be allocated.
+    #[error("allocator error: {0}")]
+    Allocator(#[from] AllocatorError),
+    /// The instances did not fit in the vertex block.
+    #[error("block allocation error: {0}")]
+    BlockAllocation(#[from] BlockAllocationError),
+}
+
+/// Types which may be attributes of a `Vertex`, along with the format they are read as.
+pub trait VertexFormat: Copy + 'static {
+    /// The format of an attribute of this type.
+    const FORMAT: vk::Format;
+}
+
+macro_rules! impl_vertex_format {
+    ($($ty:ty => $format:ident),* $(,)?) => {
+        $(impl VertexFormat for $ty {
+            const FORMAT: vk::Format = vk::Format::$format;
+        })*
+    };
+}
+
+impl_vertex_format!(
+    f32 => R32_SFLOAT,
+    [f32; 2] => R32G32_SFLOAT,
+    [f32; 3] => R32G32B32_SFLOAT,
+    [f32; 4] => R32G32B32A32_SFLOAT,
+    u32 => R32_UINT,
+    [u32; 2] => R32G32_UINT,
+    [u32; 3] => R32G32B32_UINT,
+    [u32; 4] => R32G32B32A32_UINT,
+    i32 => R32_SINT,
+    [i32; 2] => R32G32_SINT,
+    [i32; 3] => R32G32B32_SINT,
+    [i32; 4] => R32G32B32A32_SINT,
+    [u16; 2] => R16G16_UINT,
+    [u16; 4] => R16G16B16A16_UINT,
+    [u8; 4] => R8G8B8A8_UNORM,
+);
+
+/// An attribute of a `VertexLayout`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct VertexElement {
+    /// The format of the attribute.
+    pub format: vk::Format,
+    /// The offset of the attribute within an element, in bytes.
+    pub offset: u32,
+}
+
+/// The layout of the elements of a vertex buffer binding: their stride, and the format and
+/// offset of each of their attributes, in shader location order.
+///
+/// A single layout describes both the data written to vertex blocks with
+/// `BufferBlock::allocate_vertices` and the vertex input of graphics pipelines drawing it,
+/// through `GraphicsPipelineCreateInfo::vertex_layouts`. Typed vertices get theirs from
+/// `Vertex::layout`, which can be derived with the `derive` feature.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct VertexLayout {
+    /// The distance in bytes between consecutive elements.
+    pub stride: u32,
+    /// Whether the binding advances per vertex or per instance.
+    pub input_rate: vk::VertexInputRate,
+    /// The attributes of an element.
+    pub attributes: Vec<VertexElement>,
+}
+
+impl VertexLayout {
+    /// Create a per-vertex layout with elements `stride` bytes apart and no attributes.
+    pub fn new(stride: u32) -> Self {
+        Self {
+            stride,
+            input_rate: vk::VertexInputRate::VERTEX,
+            attributes: Vec::new(),
+        }
+    }
+
+    /// Add an attribute of `format` at `offset` bytes, read from the location after the
+    /// previous attribute's.
+    pub fn attribute(mut self, format: vk::Format, offset: u32) -> Self {
+        self.attributes.push(VertexElement { format, offset });
+        self
+    }
+
+    /// Get the pipeline's vertex binding for this layout at `binding`.
+    pub fn binding(&self, binding: u32) -> VertexBinding {
+        VertexBinding {
+            binding,
+            stride: self.stride,
+            input_rate: self.input_rate,
+        }
+    }
+
+    /// Get the pipeline's vertex attributes for this layout at `binding`, at consecutive
+    /// locations starting from `first_location`.
+    pub fn vertex_attributes(&self, binding: u32, first_location: u32) -> Vec<VertexAttribute> {
+        self.attributes
+            .iter()
+            .zip(first_location..)
+            .map(|(attribute, location)| VertexAttribute {
+                location,
+                binding,
+                format: attribute.format,
+                offset: attribute.offset,
+            })
+            .collect()
+    }
+}
+
+/// The layout of the elements of a per-instance vertex buffer binding, built like a
+/// `VertexLayout` but advancing once per instance.
+///
+/// Pass it to `GraphicsPipelineCreateInfo::vertex_layouts` after the per-vertex layout,
+/// converted with `into`, to draw with `CommandBuffer::draw_instanced`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct InstanceLayout(VertexLayout);
+
+impl InstanceLayout {
+    /// Create a per-instance layout with elements `stride` bytes apart and no attributes.
+    pub fn new(stride: u32) -> Self {
+        InstanceLayout(VertexLayout {
+            input_rate: vk::VertexInputRate::INSTANCE,
+            ..VertexLayout::new(stride)
+        })
+    }
+
+    /// Get the per-instance layout of `I`, e.g. a struct deriving `Vertex`.
+    pub fn of<I: Vertex>() -> Self {
+        InstanceLayout(VertexLayout {
+            input_rate: vk::VertexInputRate::INSTANCE,
+            ..I::layout()
+        })
+    }
+
+    /// Add an attribute of `format` at `offset` bytes, read from the location after the
+    /// previous attribute's.
+    pub fn attribute(self, format: vk::Format, offset: u32) -> Self {
+        InstanceLayout(self.0.attribute(format, offset))
+    }
+
+    /// Get the layout as a `VertexLayout` with a per-instance input rate.
+    pub fn vertex_layout(&self) -> &VertexLayout {
+        &self.0
+    }
+}
+
+impl From<InstanceLayout> for VertexLayout {
+    fn from(layout: InstanceLayout) -> Self {
+        layout.0
+    }
+}
+
+impl Device {
+    /// Write `instances` to a vertex block of the current frame, to be drawn with
+    /// `CommandBuffer::draw_instanced` during the frame. Like all vertex blocks, the block is
+    /// uploaded if need be and recycled once the frame has completed.
+    pub fn allocate_instances<I: Vertex>(
+        &self,
+        frame: &Frame,
+        instances: &[I],
+        tag: Option<Tag>,
+    ) -> Result<TransientBufferHandle, InstanceDataError> {
+        let size = std::mem::size_of_val(instances);
+        let block = self.request_vertex_block(frame, size, tag)?;
+        let blocks = self.buffer_blocks();
+        let block = blocks
+            .get_vertex_block(block)
+            .expect("allocate_instances: vertex block was recycled");
+        Ok(block.allocate_vertices(instances)?)
+    }
+}
+
+/// Types which are the elements of a vertex buffer, along with their `VertexLayout`.
+///
+/// With the `derive` feature, `#[derive(Vertex)]` implements it for a `#[repr(C)]` struct
+/// with named fields, taking each attribute's format from its field's `VertexFormat` unless
+/// overridden with `#[vertex(format = "R8G8B8A8_SRGB")]`. The struct must also implement
+/// `bytemuck::Pod`, e.g. with `#[derive(Pod, Zeroable)]`.
+pub trait Vertex: Pod {
+    /// Get the layout of the type as a vertex.
+    fn layout() -> VertexLayout;
+}
+
+impl GraphicsPipelineCreateInfo {
+    /// Set the vertex bindings and attributes from `layouts`, one binding per layout in order,
+    /// with the attributes at consecutive locations across all of them.
+    pub fn vertex_layouts(mut self, layouts: &[VertexLayout]) -> Self {
+        self.vertex_bindings.clear();
+        self.vertex_attributes.clear();
+
+        let mut location = 0;
+        for (layout, binding) in layouts.iter().zip(0..) {
+            self.vertex_bindings.push(layout.binding(binding));
+            self.vertex_attributes.extend(layout.vertex_attributes(binding, location));
+            location += layout.attributes.len() as u32;
+        }
+        self
+    }
+}
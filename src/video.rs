@@ -0,0 +1,227 @@
+use ash::version::InstanceV1_1;
+use ash::vk;
+use bitflags::bitflags;
+
+use std::ffi::CStr;
+use std::os::raw::c_void;
+
+use crate::*;
+
+// The version of ash in use predates `VK_KHR_video_queue` and the extensions built on it, so the
+// parts of them hot uses are declared here. hot only creates the queues and enables the
+// extensions: video sessions are left to applications, which load their functions through
+// `Device::raw_device`.
+
+fn extension_name(name: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(name).unwrap()
+}
+
+/// The name of the `VK_KHR_video_queue` extension.
+pub(crate) fn video_queue_extension_name() -> &'static CStr {
+    extension_name(b"VK_KHR_video_queue\0")
+}
+
+/// `VK_QUEUE_VIDEO_DECODE_BIT_KHR`.
+const QUEUE_VIDEO_DECODE: u32 = 0x0000_0020;
+/// `VK_QUEUE_VIDEO_ENCODE_BIT_KHR`.
+const QUEUE_VIDEO_ENCODE: u32 = 0x0000_0040;
+
+bitflags! {
+    /// Video codec operations, as in `VkVideoCodecOperationFlagsKHR`.
+    #[derive(Default)]
+    pub struct VideoCodecs: u32 {
+        /// H.264 decoding, through `VK_KHR_video_decode_h264`.
+        const DECODE_H264 = 0x0000_0001;
+        /// H.265 decoding, through `VK_KHR_video_decode_h265`.
+        const DECODE_H265 = 0x0000_0002;
+        /// AV1 decoding, through `VK_KHR_video_decode_av1`.
+        const DECODE_AV1 = 0x0000_0004;
+        /// H.264 encoding, through `VK_KHR_video_encode_h264`.
+        const ENCODE_H264 = 0x0001_0000;
+        /// H.265 encoding, through `VK_KHR_video_encode_h265`.
+        const ENCODE_H265 = 0x0002_0000;
+        /// AV1 encoding, through `VK_KHR_video_encode_av1`.
+        const ENCODE_AV1 = 0x0004_0000;
+    }
+}
+
+/// Each codec operation and the extension it needs.
+fn codec_extension_names() -> [(VideoCodecs, &'static CStr); 6] {
+    [
+        (
+            VideoCodecs::DECODE_H264,
+            extension_name(b"VK_KHR_video_decode_h264\0"),
+        ),
+        (
+            VideoCodecs::DECODE_H265,
+            extension_name(b"VK_KHR_video_decode_h265\0"),
+        ),
+        (
+            VideoCodecs::DECODE_AV1,
+            extension_name(b"VK_KHR_video_decode_av1\0"),
+        ),
+        (
+            VideoCodecs::ENCODE_H264,
+            extension_name(b"VK_KHR_video_encode_h264\0"),
+        ),
+        (
+            VideoCodecs::ENCODE_H265,
+            extension_name(b"VK_KHR_video_encode_h265\0"),
+        ),
+        (
+            VideoCodecs::ENCODE_AV1,
+            extension_name(b"VK_KHR_video_encode_av1\0"),
+        ),
+    ]
+}
+
+#[repr(C)]
+struct QueueFamilyVideoProperties {
+    s_type: vk::StructureType,
+    p_next: *mut c_void,
+    video_codec_operations: u32,
+}
+
+impl Default for QueueFamilyVideoProperties {
+    fn default() -> Self {
+        Self {
+            s_type: vk::StructureType::from_raw(1_000_023_012),
+            p_next: std::ptr::null_mut(),
+            video_codec_operations: 0,
+        }
+    }
+}
+
+/// The queue families a Device creates its video queues in, and the codec operations of each.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct VideoQueueFamilies {
+    pub(crate) decode: Option<(u32, VideoCodecs)>,
+    pub(crate) encode: Option<(u32, VideoCodecs)>,
+}
+
+impl VideoQueueFamilies {
+    /// Find the first decode and the first encode queue family of a physical device which isn't
+    /// in `excluded`, the families of hot's own queues. The device must support
+    /// `VK_KHR_video_queue` and the instance must be at least version 1.1.
+    pub(crate) fn find(
+        instance: &ash::Instance,
+        physical_device: vk::PhysicalDevice,
+        excluded: &[u32],
+    ) -> Self {
+        let count = unsafe {
+            let mut count = 0;
+            instance
+                .fp_v1_1()
+                .get_physical_device_queue_family_properties2(
+                    physical_device,
+                    &mut count,
+                    std::ptr::null_mut(),
+                );
+            count as usize
+        };
+        let mut video_properties = (0..count)
+            .map(|_| QueueFamilyVideoProperties::default())
+            .collect::<Vec<_>>();
+        let mut properties = video_properties
+            .iter_mut()
+            .map(|video| vk::QueueFamilyProperties2 {
+                p_next: video as *mut _ as *mut c_void,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let mut count = count as u32;
+        unsafe {
+            instance
+                .fp_v1_1()
+                .get_physical_device_queue_family_properties2(
+                    physical_device,
+                    &mut count,
+                    properties.as_mut_ptr(),
+                )
+        };
+
+        let find = |queue_flag: u32| {
+            properties
+                .iter()
+                .zip(&video_properties)
+                .enumerate()
+                .map(|(index, (family, video))| (index as u32, family, video))
+                .find(|&(index, family, _)| {
+                    family.queue_family_properties.queue_flags.as_raw() & queue_flag != 0
+                        && !excluded.contains(&index)
+                })
+                .map(|(index, _, video)| {
+                    (
+                        index,
+                        VideoCodecs::from_bits_truncate(video.video_codec_operations),
+                    )
+                })
+        };
+        Self {
+            decode: find(QUEUE_VIDEO_DECODE),
+            encode: find(QUEUE_VIDEO_ENCODE),
+        }
+    }
+
+    /// Enable the extensions of the decode and encode queues and their codecs with `enable`,
+    /// which returns whether the extension is supported. Codecs whose extension is unsupported
+    /// are dropped, as are queues left without any codec.
+    pub(crate) fn enable_extensions(&mut self, mut enable: impl FnMut(&'static CStr) -> bool) {
+        let queues = [
+            (
+                &mut self.decode,
+                extension_name(b"VK_KHR_video_decode_queue\0"),
+            ),
+            (
+                &mut self.encode,
+                extension_name(b"VK_KHR_video_encode_queue\0"),
+            ),
+        ];
+        for (queue, queue_extension) in queues {
+            let codecs = match queue {
+                Some((_, codecs)) if enable(queue_extension) => codecs,
+                _ => {
+                    *queue = None;
+                    continue;
+                }
+            };
+            for (codec, name) in codec_extension_names().iter().copied() {
+                if codecs.contains(codec) && !enable(name) {
+                    codecs.remove(codec);
+                }
+            }
+            if codecs.is_empty() {
+                *queue = None;
+            }
+        }
+    }
+}
+
+/// A queue created for video decoding or encoding.
+///
+/// hot never uses the queue itself, so access to it only needs to be synchronized by the
+/// application.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct VideoQueue {
+    /// The raw queue.
+    pub queue: vk::Queue,
+    /// The index of the queue's family.
+    pub family_index: u32,
+    /// The codec operations the queue supports with their extensions enabled.
+    pub codecs: VideoCodecs,
+}
+
+impl Device {
+    /// Get the queue created for video decoding, if the device has a decode queue family other
+    /// than those of hot's own queues, with at least one codec whose extension is supported.
+    ///
+    /// `VK_KHR_video_queue` needs synchronization2, so there are no video queues without it.
+    pub fn video_decode_queue(&self) -> Option<VideoQueue> {
+        self.video_decode_queue
+    }
+
+    /// Like `video_decode_queue`, but for the queue created for video encoding.
+    pub fn video_encode_queue(&self) -> Option<VideoQueue> {
+        self.video_encode_queue
+    }
+}